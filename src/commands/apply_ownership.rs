@@ -0,0 +1,85 @@
+use crate::core::ownership::{OwnershipFailure, OwnershipManifest, can_restore_ownership};
+use crate::output::{emit_error_with_details, emit_output, emit_warning, is_json_mode};
+use crate::utils::handle_error;
+use clap::ArgMatches;
+use console::style;
+use std::path::Path;
+
+/// Applies an ownership manifest written by `gib restore --store-ownership-manifest`.
+/// Files are resolved against `--target-path`, or the folder holding the manifest.
+pub fn apply_ownership(matches: &ArgMatches) {
+    if cfg!(not(unix)) {
+        handle_error(
+            "gib apply-ownership is only supported on Unix".to_string(),
+            None,
+        );
+    }
+
+    if !can_restore_ownership() {
+        handle_error(
+            "gib apply-ownership must run as root, since only root can change file owners (try 'sudo gib apply-ownership')".to_string(),
+            None,
+        );
+    }
+
+    let manifest_path = Path::new(matches.get_one::<String>("manifest").unwrap());
+
+    let root = match matches.get_one::<String>("target-path") {
+        Some(target_path) => Path::new(target_path).to_path_buf(),
+        None => manifest_path
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default(),
+    };
+
+    let manifest = match OwnershipManifest::read(manifest_path) {
+        Ok(manifest) => manifest,
+        Err(e) => handle_error(e, None),
+    };
+
+    let (applied, failures) = manifest.apply(&root);
+
+    if !failures.is_empty() {
+        let message = format!(
+            "Failed to apply ownership to {} files:\n{}",
+            failures.len(),
+            failures
+                .iter()
+                .map(|failure| format!("  - {}: {}", failure.path, failure.error))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+
+        if applied == 0 {
+            if is_json_mode() {
+                emit_error_with_details(&message, "apply_ownership_failed", &failures);
+            }
+            handle_error(message, None);
+        }
+
+        emit_warning(&message, "apply_ownership_partial_failure");
+    }
+
+    if is_json_mode() {
+        #[derive(serde::Serialize)]
+        struct ApplyOwnershipOutput {
+            backup: String,
+            applied: u64,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            failed_files: Vec<OwnershipFailure>,
+        }
+
+        emit_output(&ApplyOwnershipOutput {
+            backup: manifest.backup,
+            applied,
+            failed_files: failures,
+        });
+    } else {
+        println!(
+            "{} Applied ownership to {} files from backup {}",
+            style("OK").green(),
+            applied,
+            &manifest.backup[..8.min(manifest.backup.len())]
+        );
+    }
+}
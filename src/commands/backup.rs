@@ -1,951 +1,4659 @@
-use crate::commands::config::Config;
-use crate::core::crypto::get_password;
-use crate::core::crypto::read_file_maybe_decrypt;
-use crate::core::crypto::write_file_maybe_encrypt;
-use crate::core::indexes::{add_backup_summary, create_new_backup, load_chunk_indexes};
-use crate::core::metadata::PendingBackup;
-use crate::core::metadata::{Backup, BackupObject, ChunkIndex};
-use crate::core::permissions::get_file_permissions_with_path;
-use crate::fs::FS;
-use crate::output::{JsonProgress, emit_output, emit_progress_message, emit_warning, is_json_mode};
-use crate::utils::decompress_bytes;
-use crate::utils::{compress_bytes, get_fs, get_pwd_string, get_storage, handle_error};
-use bytesize::ByteSize;
-use clap::ArgMatches;
-use console::style;
-use dialoguer::{Input, Select};
-use dirs::home_dir;
-use futures::stream::{self, StreamExt};
-use indicatif::{ProgressBar, ProgressStyle};
-use parse_size::parse_size;
-use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use std::io::Read;
-use std::path::Path;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-use tokio::sync::{Mutex as TokioMutex, Semaphore};
-use tokio::task::JoinSet;
-
-pub async fn backup(matches: &ArgMatches) {
-    let (
-        key,
-        message,
-        root_path_string,
-        storage,
-        compress,
-        password,
-        chunk_size,
-        ignore_patterns,
-        received_pending_backup,
-        concurrency,
-    ) = match get_params(matches).await {
-        Ok(params) => params,
-        Err(e) => handle_error(e, None),
-    };
-
-    let received_pending_backup = Arc::new(Mutex::new(received_pending_backup));
-
-    let home_dir = match home_dir() {
-        Some(dir) => dir,
-        None => handle_error("Failed to get home directory".to_string(), None),
-    };
-
-    let config_path = home_dir.join(".gib").join("config.msgpack");
-
-    if !config_path.exists() {
-        handle_error("Seems like you didn't configure your backup tool yet. Run 'gib config' to configure your backup tool.".to_string(), None);
-    }
-
-    let config_bytes = match std::fs::read(&config_path) {
-        Ok(bytes) => bytes,
-        Err(e) => handle_error(format!("Failed to read config file: {}", e), None),
-    };
-
-    let config: Config = match rmp_serde::from_slice(&config_bytes) {
-        Ok(config) => config,
-        Err(e) => handle_error(format!("Failed to deserialize config: {}", e), None),
-    };
-
-    let pb = if is_json_mode() {
-        ProgressBar::hidden()
-    } else {
-        let pb = ProgressBar::new(100);
-        pb.enable_steady_tick(Duration::from_millis(100));
-        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
-        pb.set_message("Loading metadata from the repository key...");
-        pb
-    };
-
-    if is_json_mode() {
-        emit_progress_message("Loading metadata from the repository key...");
-    }
-
-    let storage = get_storage(&storage);
-
-    let fs = get_fs(&storage, Some(&pb));
-
-    pb.set_message("Generating new backup...");
-    if is_json_mode() {
-        emit_progress_message("Generating new backup...");
-    }
-
-    let prev_not_encrypted_but_now_yes = Arc::new(Mutex::new(false));
-
-    let (new_backup, root_files, chunk_indexes) = match load_metadata(
-        Arc::clone(&fs),
-        key.clone(),
-        message,
-        config,
-        root_path_string.clone(),
-        password.clone(),
-        Arc::clone(&prev_not_encrypted_but_now_yes),
-        ignore_patterns.clone(),
-    )
-    .await
-    {
-        Ok(result) => result,
-        Err(e) => handle_error(e, Some(&pb)),
-    };
-
-    let continue_error_message = format!(
-        "Continue from the place where the backup was interrupted by running: gib backup --continue {}",
-        new_backup.hash[..8].to_string()
-    );
-
-    let total_files = root_files.len();
-
-    pb.finish_and_clear();
-
-    if *prev_not_encrypted_but_now_yes.lock().unwrap() {
-        let warning = "The backup was not encrypted but you provided a password. Only new chunks will be encrypted; run 'gib encrypt' to encrypt existing chunks.";
-        if is_json_mode() {
-            emit_warning(warning, "unencrypted_chunks");
-        } else {
-            println!("{}", style(warning).yellow());
-        }
-    }
-
-    let json_progress = if is_json_mode() {
-        let progress = JsonProgress::new(root_files.len() as u64);
-        progress.set_message(&format!(
-            "Backing up files to {}...",
-            new_backup.hash[..8].to_string()
-        ));
-        Some(progress)
-    } else {
-        None
-    };
-
-    let pb = if is_json_mode() {
-        ProgressBar::hidden()
-    } else {
-        let pb = ProgressBar::new(root_files.len() as u64);
-        pb.enable_steady_tick(Duration::from_millis(100));
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
-            )
-            .unwrap(),
-        );
-        pb.set_message(format!(
-            "Backing up files to {}...",
-            new_backup.hash[..8].to_string()
-        ));
-        pb
-    };
-
-    let chunk_indexes: Arc<Mutex<HashMap<String, ChunkIndex>>> =
-        Arc::new(Mutex::new(chunk_indexes));
-
-    let new_backup: Arc<Mutex<Backup>> = Arc::new(Mutex::new(new_backup));
-
-    let files_set = Arc::new(TokioMutex::new(JoinSet::new()));
-    let written_bytes = Arc::new(Mutex::new(0));
-    let deduplicated_bytes = Arc::new(Mutex::new(0));
-    let semaphore = Arc::new(Semaphore::new(concurrency));
-
-    let pending_backup = Arc::new(Mutex::new(PendingBackup {
-        message: new_backup.lock().unwrap().message.clone(),
-        compress,
-        chunk_size,
-        concurrency,
-        ignore_patterns: ignore_patterns.clone(),
-        processed_chunks: Vec::new(),
-    }));
-    let pending_backup_path = Arc::new(format!(
-        "{}/indexes/pending_{}",
-        key,
-        new_backup.lock().unwrap().hash
-    ));
-
-    let pending_backup_watcher_stop = Arc::new(AtomicBool::new(false));
-
-    {
-        let fs_clone = Arc::clone(&fs);
-        let pending_backup_clone = Arc::clone(&pending_backup);
-        let pending_backup_path_clone = pending_backup_path.clone();
-        let pending_backup_watcher_stop_clone = pending_backup_watcher_stop.clone();
-        let password_clone = password.clone();
-
-        thread::spawn(move || {
-            let runtime = tokio::runtime::Runtime::new().unwrap();
-            runtime.block_on(watch_pending_backup(
-                pending_backup_clone,
-                pending_backup_path_clone,
-                fs_clone,
-                pending_backup_watcher_stop_clone,
-                password_clone,
-            ));
-        });
-    };
-
-    let files_stream = stream::iter(root_files);
-
-    files_stream
-        .for_each_concurrent(concurrency, |file_path| {
-            let pb_clone = pb.clone();
-            let chunk_indexes_clone = Arc::clone(&chunk_indexes);
-            let password_clone = password.clone();
-            let key_clone = key.clone();
-            let fs_clone = Arc::clone(&fs);
-            let new_backup_clone = Arc::clone(&new_backup);
-            let root_path_string_clone = root_path_string.clone();
-            let written_bytes_clone = Arc::clone(&written_bytes);
-            let deduplicated_bytes_clone = Arc::clone(&deduplicated_bytes);
-            let semaphore_clone = Arc::clone(&semaphore);
-            let files_set_clone = Arc::clone(&files_set);
-            let json_progress_clone = json_progress.clone();
-            let pending_backup_clone = Arc::clone(&pending_backup);
-            let received_pending_backup_clone = Arc::clone(&received_pending_backup);
-
-            async move {
-                let mut guard = files_set_clone.lock().await;
-                guard.spawn(async move {
-                    let _permit = semaphore_clone.acquire().await.expect("Semaphore closed");
-                    backup_file(
-                        file_path,
-                        pb_clone,
-                        chunk_indexes_clone,
-                        password_clone,
-                        key_clone,
-                        fs_clone,
-                        new_backup_clone,
-                        root_path_string_clone,
-                        written_bytes_clone,
-                        deduplicated_bytes_clone,
-                        chunk_size,
-                        compress,
-                        json_progress_clone,
-                        pending_backup_clone,
-                        received_pending_backup_clone,
-                    )
-                    .await
-                });
-            }
-        })
-        .await;
-
-    let mut failed_files = Vec::new();
-
-    {
-        let mut guard = files_set.lock().await;
-        while let Some(file_process_result) = guard.join_next().await {
-            match file_process_result {
-                Ok(Ok(_)) => {}
-                Ok(Err(e)) => failed_files.push(e),
-                Err(e) => failed_files.push(e.to_string()),
-            }
-        }
-    }
-
-    pending_backup_watcher_stop.store(true, Ordering::SeqCst);
-
-    if !failed_files.is_empty() {
-        handle_error(
-            format!(
-                "Failed to process {} files:\n{}\n\n{}",
-                failed_files.len(),
-                failed_files
-                    .iter()
-                    .map(|f| format!("  - {}", f))
-                    .collect::<Vec<String>>()
-                    .join("\n"),
-                &continue_error_message
-            ),
-            Some(&pb),
-        );
-    }
-
-    let chunk_indexes_bytes =
-        rmp_serde::to_vec_named(&*chunk_indexes.lock().unwrap()).unwrap_or_else(|_| Vec::new());
-
-    let compressed_chunk_indexes_bytes = compress_bytes(&chunk_indexes_bytes, compress);
-
-    let chunk_index_path = format!("{}/indexes/chunks", key);
-
-    let write_chunk_index_future = write_file_maybe_encrypt(
-        &fs,
-        &chunk_index_path,
-        &compressed_chunk_indexes_bytes,
-        password.as_deref(),
-    );
-
-    let backup_file_bytes =
-        rmp_serde::to_vec_named(&*new_backup.lock().unwrap()).unwrap_or_else(|_| Vec::new());
-
-    let compressed_backup_file_bytes = compress_bytes(&backup_file_bytes, compress);
-
-    let backup_file_path = format!("{}/backups/{}", key, new_backup.lock().unwrap().hash);
-
-    let write_backup_file_future = write_file_maybe_encrypt(
-        &fs,
-        &backup_file_path,
-        &compressed_backup_file_bytes,
-        password.as_deref(),
-    );
-
-    let (write_chunk_index_result, write_backup_file_result) =
-        tokio::join!(write_chunk_index_future, write_backup_file_future);
-
-    if write_chunk_index_result.is_err() {
-        handle_error(
-            format!(
-                "Failed to write chunk indexes\n\n{}",
-                &continue_error_message
-            ),
-            Some(&pb),
-        );
-    }
-
-    if write_backup_file_result.is_err() {
-        handle_error(
-            format!("Failed to write backup file\n\n{}", &continue_error_message),
-            Some(&pb),
-        );
-    }
-
-    let written_bytes = *written_bytes.lock().unwrap();
-    let deduplicated_bytes = *deduplicated_bytes.lock().unwrap();
-
-    {
-        let backup_guard = new_backup.lock().unwrap();
-        if let Err(e) = add_backup_summary(
-            Arc::clone(&fs),
-            key.clone(),
-            &backup_guard,
-            compress,
-            password.clone(),
-            &written_bytes,
-        )
-        .await
-        {
-            handle_error(
-                format!(
-                    "Failed to save backup summary: {}\n\n{}",
-                    &e, &continue_error_message
-                ),
-                Some(&pb),
-            );
-        }
-    }
-
-    let _ = fs.delete_file(&pending_backup_path).await;
-
-    {
-        match received_pending_backup.lock().unwrap().take() {
-            Some(pending_backup) => {
-                let _ = fs.delete_file(&pending_backup.path).await;
-            }
-            None => {}
-        };
-    }
-
-    if is_json_mode() {
-        #[derive(serde::Serialize)]
-        struct BackupOutput {
-            backup: String,
-            backup_short: String,
-            message: String,
-            author: String,
-            timestamp_unix: u64,
-            files_total: usize,
-            written_bytes: u64,
-            deduplicated_bytes: u64,
-            elapsed_ms: u64,
-        }
-
-        let backup_guard = new_backup.lock().unwrap();
-        let elapsed_ms = pb.elapsed().as_millis() as u64;
-        let payload = BackupOutput {
-            backup: backup_guard.hash.clone(),
-            backup_short: backup_guard.hash[..8.min(backup_guard.hash.len())].to_string(),
-            message: backup_guard.message.clone(),
-            author: backup_guard.author.clone(),
-            timestamp_unix: backup_guard.timestamp,
-            files_total: total_files,
-            written_bytes,
-            deduplicated_bytes,
-            elapsed_ms,
-        };
-        emit_output(&payload);
-    } else {
-        let elapsed = pb.elapsed();
-        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
-        pb.set_prefix("OK");
-        pb.finish_with_message(format!(
-            "Backed up files ({:.2?}) - {} written, {} deduplicated",
-            elapsed,
-            ByteSize(written_bytes),
-            ByteSize(deduplicated_bytes),
-        ));
-    }
-}
-
-async fn watch_pending_backup(
-    pending_backup: Arc<Mutex<PendingBackup>>,
-    pending_backup_path: Arc<String>,
-    fs: Arc<dyn FS>,
-    pending_backup_watcher_stop: Arc<AtomicBool>,
-    password: Option<String>,
-) {
-    let mut interval = tokio::time::interval(Duration::from_secs(1));
-
-    loop {
-        interval.tick().await;
-
-        if pending_backup_watcher_stop.load(Ordering::SeqCst) {
-            break;
-        }
-
-        let bytes_to_write = {
-            let pending_backup_guard = pending_backup.lock().unwrap();
-            rmp_serde::to_vec_named(&*pending_backup_guard).unwrap_or_else(|_| Vec::new())
-        };
-
-        let compressed_bytes = compress_bytes(&bytes_to_write, 3);
-
-        let _ = write_file_maybe_encrypt(
-            &fs,
-            pending_backup_path.as_str(),
-            &compressed_bytes,
-            password.as_deref(),
-        )
-        .await;
-    }
-}
-
-async fn backup_file(
-    file_path: String,
-    pb: ProgressBar,
-    chunk_indexes: Arc<Mutex<HashMap<String, ChunkIndex>>>,
-    password: Option<String>,
-    key: String,
-    fs: Arc<dyn FS>,
-    new_backup: Arc<Mutex<Backup>>,
-    root_path_string: String,
-    written_bytes: Arc<Mutex<u64>>,
-    deduplicated_bytes: Arc<Mutex<u64>>,
-    chunk_size: u64,
-    compress: i32,
-    json_progress: Option<Arc<JsonProgress>>,
-    pending_backup: Arc<Mutex<PendingBackup>>,
-    received_pending_backup: Arc<Mutex<Option<PendingBackupMatch>>>,
-) -> Result<(), String> {
-    let mut file = std::fs::File::open(file_path.clone())
-        .map_err(|e| format!("Failed to open file: {}", e))?;
-    let mut file_hasher = Sha256::new();
-    let mut file_chunks = Vec::new();
-
-    let file_metadata = file
-        .metadata()
-        .map_err(|e| format!("Failed to get file metadata: {}", e))?;
-
-    let mut buffer = vec![0u8; chunk_size as usize];
-
-    loop {
-        let bytes_read = file
-            .read(&mut buffer)
-            .map_err(|e| format!("Failed to read file: {}", e))
-            .unwrap_or(0);
-
-        if bytes_read == 0 {
-            break;
-        }
-
-        let chunk_bytes = &buffer[..bytes_read];
-
-        file_hasher.update(chunk_bytes);
-
-        let chunk_hash = format!("{:x}", Sha256::digest(chunk_bytes));
-        file_chunks.push(chunk_hash.clone());
-
-        let is_in_chunk_indexes = {
-            let mut chunk_indexes_guard = chunk_indexes.lock().unwrap();
-            let entry = chunk_indexes_guard
-                .entry(chunk_hash.clone())
-                .or_insert(ChunkIndex { refcount: 0 });
-            entry.refcount += 1;
-
-            entry.refcount > 1
-        };
-
-        if is_in_chunk_indexes {
-            let mut deduplicated_bytes_guard = deduplicated_bytes.lock().unwrap();
-            *deduplicated_bytes_guard += chunk_bytes.len() as u64;
-            continue;
-        }
-
-        {
-            let received_pending_backup_guard = received_pending_backup.lock().unwrap();
-
-            let exists = match received_pending_backup_guard.as_ref() {
-                Some(pending_backup) => {
-                    pending_backup.backup.processed_chunks.contains(&chunk_hash)
-                }
-                None => false,
-            };
-
-            if exists {
-                let mut written_bytes_guard = written_bytes.lock().unwrap();
-                *written_bytes_guard += chunk_bytes.len() as u64;
-                continue;
-            }
-        }
-
-        let compressed_chunk_bytes = compress_bytes(chunk_bytes, compress);
-
-        let (chunk_hash_prefix, chunk_hash_rest) = chunk_hash.split_at(2);
-        let chunk_path = format!("{}/chunks/{}/{}", key, chunk_hash_prefix, chunk_hash_rest);
-
-        let mut last_error = String::new();
-        let mut success = false;
-
-        for attempt in 1..=3 {
-            match write_file_maybe_encrypt(
-                &fs,
-                &chunk_path,
-                &compressed_chunk_bytes,
-                password.as_deref(),
-            )
-            .await
-            {
-                Ok(_) => {
-                    success = true;
-                    break;
-                }
-                Err(e) => {
-                    last_error = format!("Failed to write chunk (attempt {}/3): {}", attempt, e);
-                    if attempt < 3 {
-                        tokio::time::sleep(Duration::from_millis(100 * attempt as u64)).await;
-                    }
-                }
-            }
-        }
-
-        if !success {
-            return Err(last_error);
-        }
-
-        {
-            let mut written_bytes_guard = written_bytes.lock().unwrap();
-            *written_bytes_guard += chunk_bytes.len() as u64;
-        }
-
-        {
-            let mut pending_backup_guard = pending_backup.lock().unwrap();
-            pending_backup_guard
-                .processed_chunks
-                .push(chunk_hash.clone());
-        }
-    }
-
-    let file_hash = format!("{:x}", file_hasher.finalize());
-
-    let relative_path = {
-        let content = file_path
-            .strip_prefix(&root_path_string)
-            .unwrap_or(&file_path);
-
-        let mut content = content.replace('\\', "/");
-
-        if content.starts_with('/') {
-            content = content[1..].to_string();
-        }
-
-        content
-    };
-
-    let file_permissions = get_file_permissions_with_path(&file_metadata, &file_path);
-
-    {
-        let mut new_backup_guard = new_backup.lock().unwrap();
-
-        new_backup_guard.tree.insert(
-            relative_path.to_string(),
-            BackupObject {
-                hash: file_hash.clone(),
-                size: file_metadata.len(),
-                content_type: "application/octet-stream".to_string(),
-                permissions: file_permissions,
-                chunks: file_chunks,
-            },
-        );
-    }
-
-    if let Some(progress) = &json_progress {
-        progress.inc_by(1);
-    } else {
-        pb.inc(1);
-    }
-    Ok(())
-}
-
-fn list_files(path: &str, ignore_patterns: &[String]) -> Vec<String> {
-    let mut files = Vec::new();
-
-    let walker = walkdir::WalkDir::new(path)
-        .into_iter()
-        .filter_entry(|entry| {
-            if ignore_patterns.is_empty() {
-                return true;
-            }
-
-            let file_name = entry.file_name().to_string_lossy();
-
-            !ignore_patterns.iter().any(|pattern| file_name == *pattern)
-        });
-
-    for entry in walker.filter_map(|e| e.ok()).filter(|e| e.path().is_file()) {
-        files.push(entry.path().display().to_string());
-    }
-
-    files
-}
-
-async fn load_metadata(
-    fs: Arc<dyn FS>,
-    key: String,
-    message: String,
-    config: Config,
-    root_path_string: String,
-    password: Option<String>,
-    prev_not_encrypted_but_now_yes: Arc<Mutex<bool>>,
-    ignore_patterns: Vec<String>,
-) -> Result<(Backup, Vec<String>, HashMap<String, ChunkIndex>), String> {
-    let new_backup = create_new_backup(message, config.author);
-
-    let root_files_future =
-        tokio::spawn(async move { list_files(&root_path_string, &ignore_patterns) });
-
-    let chunk_indexes_future = tokio::spawn(load_chunk_indexes(
-        Arc::clone(&fs),
-        key.clone(),
-        password,
-        prev_not_encrypted_but_now_yes,
-    ));
-
-    let (root_files_result, chunk_indexes_result) =
-        tokio::join!(root_files_future, chunk_indexes_future);
-
-    let root_files = root_files_result.map_err(|e| format!("Failed to list root files: {}", e))?;
-
-    let chunk_indexes = chunk_indexes_result
-        .map_err(|e| format!("Failed to load chunk indexes: {}", e))?
-        .map_err(|e| format!("Failed to load chunk indexes: {}", e))?;
-
-    Ok((new_backup, root_files, chunk_indexes))
-}
-
-struct PendingBackupMatch {
-    backup: PendingBackup,
-    path: String,
-}
-
-async fn load_pending_backup(
-    fs: Arc<dyn FS>,
-    key: &str,
-    continue_prefix: &str,
-    password: &Option<String>,
-) -> Result<PendingBackupMatch, String> {
-    let indexes_path = format!("{}/indexes", key);
-    let files = fs
-        .list_files(&indexes_path)
-        .await
-        .map_err(|e| format!("Failed to list indexes in '{}': {}", indexes_path, e))?;
-
-    let pending_prefix = format!("{}/indexes/pending_{}", key, continue_prefix);
-    let mut matches: Vec<String> = files
-        .into_iter()
-        .filter(|path| path.starts_with(&pending_prefix))
-        .collect();
-
-    matches.sort();
-    matches.dedup();
-
-    if matches.is_empty() {
-        return Err(format!("No pending backup found for '{}'", continue_prefix));
-    }
-
-    let pending_path = matches
-        .pop()
-        .ok_or_else(|| "Pending backup match missing".to_string())?;
-
-    let pending_result = read_file_maybe_decrypt(
-        &fs,
-        &pending_path,
-        password.as_deref(),
-        "The pending backup is encrypted. Please enter the password to decrypt it.",
-    )
-    .await?;
-
-    let decompressed_bytes = decompress_bytes(&pending_result.bytes);
-
-    let pending_backup: PendingBackup =
-        rmp_serde::from_slice(&decompressed_bytes).map_err(|e| {
-            format!(
-                "Failed to deserialize pending backup '{}': {}",
-                pending_path, e
-            )
-        })?;
-
-    Ok(PendingBackupMatch {
-        backup: pending_backup,
-        path: pending_path,
-    })
-}
-
-async fn get_params(
-    matches: &ArgMatches,
-) -> Result<
-    (
-        String,
-        String,
-        String,
-        String,
-        i32,
-        Option<String>,
-        u64,
-        Vec<String>,
-        Option<PendingBackupMatch>,
-        usize,
-    ),
-    String,
-> {
-    let password: Option<String> = matches
-        .get_one::<String>("password")
-        .map(|s| s.to_string())
-        .map_or_else(
-            || get_password(false, false),
-            |password| Some(password.to_string()),
-        );
-
-    let pwd_string = get_pwd_string();
-
-    let root_path_string = matches.get_one::<String>("root-path").map_or_else(
-        || pwd_string.clone(),
-        |root_path| {
-            Path::new(&pwd_string)
-                .join(root_path)
-                .to_string_lossy()
-                .to_string()
-        },
-    );
-
-    let default_key = Path::new(&root_path_string)
-        .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .to_string();
-
-    let key = matches
-        .get_one::<String>("key")
-        .map_or_else(|| default_key, |key| key.to_string());
-
-    let home_dir = home_dir().unwrap();
-    let storage_path = home_dir.join(".gib").join("storages");
-
-    if !storage_path.exists() {
-        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
-    }
-
-    let files =
-        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
-
-    let storages_names = &files
-        .map(|file| {
-            file.map_err(|e| format!("Failed to read storage entry: {}", e))
-                .map(|file| {
-                    file.file_name()
-                        .to_string_lossy()
-                        .to_string()
-                        .split('.')
-                        .next()
-                        .unwrap()
-                        .to_string()
-                })
-        })
-        .collect::<Result<Vec<String>, String>>()?;
-
-    if storages_names.is_empty() {
-        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
-    }
-
-    let storage = match matches.get_one::<String>("storage") {
-        Some(storage) => storage.to_string(),
-        None => {
-            if is_json_mode() {
-                return Err(
-                    "Missing required argument: --storage (required in --mode json)".to_string(),
-                );
-            }
-            let selected_index = Select::new()
-                .with_prompt("Select the storage to use")
-                .items(storages_names)
-                .default(0)
-                .interact()
-                .map_err(|e| format!("{}", e))?;
-
-            storages_names[selected_index].clone()
-        }
-    };
-
-    let exists = storages_names
-        .iter()
-        .any(|storage_name| storage_name == &storage);
-
-    if !exists {
-        return Err(format!("Storage '{}' not found", storage));
-    }
-
-    let pending_backup = match matches.get_one::<String>("continue") {
-        Some(continue_prefix) => {
-            let storage_config = get_storage(&storage);
-            let fs = get_fs(&storage_config, None);
-            Some(load_pending_backup(fs, &key, continue_prefix, &password).await?)
-        }
-        None => None,
-    };
-
-    let mut reused_data = Vec::new();
-
-    if let Some(pending) = &pending_backup
-        && !pending.backup.processed_chunks.is_empty()
-    {
-        reused_data.push("uploaded chunks".to_string());
-    }
-
-    let message = match matches.get_one::<String>("message") {
-        Some(message) => message.to_string(),
-        None => {
-            if let Some(pending) = &pending_backup
-                && !pending.backup.message.is_empty()
-            {
-                reused_data.push("message".to_string());
-                pending.backup.message.clone()
-            } else {
-                if is_json_mode() {
-                    return Err(
-                        "Missing required argument: --message (required in --mode json)"
-                            .to_string(),
-                    );
-                }
-                Input::<String>::new()
-                    .with_prompt("Enter the backup message")
-                    .interact_text()
-                    .map_err(|e| format!("{}", e))?
-            }
-        }
-    };
-
-    let compress: i32 = matches.get_one::<String>("compress").map_or_else(
-        || {
-            if let Some(pending) = &pending_backup
-                && pending.backup.compress != 3
-            {
-                reused_data.push("compress".to_string());
-                pending.backup.compress
-            } else {
-                3
-            }
-        },
-        |compress| compress.parse().unwrap_or(3),
-    );
-
-    let chunk_size: u64 = matches.get_one::<String>("chunk-size").map_or_else(
-        || {
-            if let Some(pending) = &pending_backup
-                && pending.backup.chunk_size != parse_size("5 MB").unwrap()
-            {
-                reused_data.push("chunk size".to_string());
-                pending.backup.chunk_size
-            } else {
-                parse_size("5 MB").unwrap()
-            }
-        },
-        |chunk_size| parse_size(chunk_size).unwrap(),
-    );
-
-    let ignore_patterns: Vec<String> = matches
-        .get_many::<String>("ignore")
-        .map(|values| values.map(|s| s.to_string()).collect())
-        .unwrap_or_else(|| {
-            if let Some(pending) = &pending_backup
-                && !pending.backup.ignore_patterns.is_empty()
-            {
-                reused_data.push("ignored files".to_string());
-                pending.backup.ignore_patterns.clone()
-            } else {
-                Vec::new()
-            }
-        });
-
-    if !reused_data.is_empty() {
-        let pending_name = pending_backup
-            .as_ref()
-            .and_then(|pending| pending.path.rsplit('/').next())
-            .map_or("pending backup".to_string(), |pending| {
-                let hash = pending.replace("pending_", "");
-                hash[..8].to_string()
-            });
-        let warning = format!("Reusing from {}: {}", pending_name, reused_data.join(", "));
-
-        if is_json_mode() {
-            emit_warning(&warning, "pending_backup_reuse");
-        } else {
-            println!("{}", style(warning).yellow());
-        }
-    }
-
-    let default_concurrency = num_cpus::get() * 2;
-
-    let concurrency = matches.get_one::<String>("concurrency").map_or_else(
-        || {
-            if let Some(pending) = &pending_backup
-                && pending.backup.concurrency != default_concurrency
-            {
-                reused_data.push("concurrency".to_string());
-                pending.backup.concurrency
-            } else {
-                default_concurrency
-            }
-        },
-        |concurrency| concurrency.parse().unwrap_or(default_concurrency),
-    );
-
-    Ok((
-        key,
-        message,
-        root_path_string,
-        storage,
-        compress,
-        password,
-        chunk_size,
-        ignore_patterns,
-        pending_backup,
-        concurrency,
-    ))
-}
+use crate::commands::config::{Config, default_key, default_storage, read_config};
+use crate::commands::delete::{delete_orphaned_chunks, remove_backups};
+use crate::core::acl::{has_acl, read_acl};
+use crate::core::append::{FileHasher, ResumableSha256, chunk_matches, content_sample};
+use crate::core::chunker::{Chunker, MIN_CDC_SIZE};
+use crate::core::content_cache::{CachedContent, ContentCache, ContentKey};
+use crate::core::crypto::get_password;
+use crate::core::crypto::read_file_maybe_decrypt;
+use crate::core::crypto::{encrypt_for_storage, write_file_maybe_encrypt};
+use crate::core::hooks::Hooks;
+use crate::core::indexes::{
+    add_backup_summary, content_hash, create_new_backup, list_backup_summaries, load_chunk_indexes,
+    load_repository_meta, load_resolved_backup, manifest_exists, save_repository_meta,
+};
+use crate::core::layout::{
+    MAX_CHUNK_DIR_LEVELS, backup_path, chunk_index_path, chunk_path, pending_backup_path,
+};
+use crate::core::lock::acquire_lock;
+use crate::core::low_memory::{DiskChunkIndex, TreeSpool};
+use crate::core::metadata::{AclEntry, AppendState, Backup, BackupObject, ChunkIndex};
+use crate::core::metadata::{BackupSummary, PendingBackup, RepositoryMeta};
+use crate::core::ownership::get_file_owner;
+use crate::core::permissions::get_file_permissions_with_path;
+use crate::core::permits::{ConcurrencyReport, ConcurrencyTuner, PermitPool};
+use crate::core::retention::RetentionPolicy;
+use crate::core::signing::{load_signing_key, remove_signature, sign_object};
+use crate::fs::FS;
+use crate::output::{
+    JsonProgress, ProgressBytes, can_prompt, default_progress_interval, emit_error, emit_output,
+    emit_progress_message, emit_warning, is_json_mode, set_report_path, write_report,
+};
+use crate::utils::decompress_bytes;
+use crate::utils::{
+    compress_bytes, encode_chunk, get_fs, get_pwd_string, get_storage, handle_error, parse_duration,
+};
+use bytesize::ByteSize;
+use clap::ArgMatches;
+use console::style;
+use dialoguer::{Input, Select};
+use dirs::home_dir;
+use futures::stream::{self, StreamExt};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use indicatif::{ProgressBar, ProgressStyle};
+use parse_size::parse_size;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::Mutex as TokioMutex;
+use tokio::task::JoinSet;
+use tracing::{Instrument, debug, info, info_span, instrument, trace, warn};
+
+pub async fn backup(matches: &ArgMatches) {
+    // Checked before anything else, so a typo in the path does not waste a whole run.
+    if let Some(report_path) = matches.get_one::<String>("report-json") {
+        let report_path = PathBuf::from(report_path);
+        let parent = report_path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        if !parent.is_dir() {
+            handle_error(
+                format!(
+                    "Cannot write the report to {}: {} is not a directory",
+                    report_path.display(),
+                    parent.display()
+                ),
+                None,
+            );
+        }
+        set_report_path(report_path);
+    }
+
+    if matches.get_flag("store-acls") && !cfg!(target_os = "linux") {
+        handle_error("--store-acls is only supported on Linux".to_string(), None);
+    }
+
+    let params = match get_params(matches).await {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    // Hooks are not run for --dry-run. In split mode there is no single key to report.
+    let hooks = Hooks::from_matches("backup", matches)
+        .filter(|_| !matches.get_flag("dry-run"))
+        .map(|hooks| {
+            let key = if matches.get_flag("split-by-top-level") {
+                String::new()
+            } else {
+                params.key.clone()
+            };
+            hooks
+                .with_env("KEY", key)
+                .with_env("ROOT", params.root_path_string.clone())
+        });
+
+    if let Some(hooks) = &hooks {
+        hooks.run_pre_hook();
+    }
+
+    if matches.get_flag("split-by-top-level") {
+        backup_top_level_dirs(matches, params).await;
+
+        if let Some(hooks) = &hooks {
+            hooks.run_post_hook("success", &[]);
+        }
+        return;
+    }
+
+    let fs = get_fs(&get_storage(&params.storage), None);
+
+    let payload = run_backup(matches, params, fs).await;
+
+    if let Some(hooks) = &hooks {
+        let hash = payload
+            .as_ref()
+            .map(|payload| ("HASH", payload.backup.clone()));
+        hooks.run_post_hook("success", hash.as_slice());
+    }
+
+    if let Some(payload) = payload {
+        if let Err(e) = write_report(&payload) {
+            handle_error(e, None);
+        }
+
+        if is_json_mode() {
+            emit_output(&payload);
+        }
+    }
+}
+
+/// Backs up each immediate subdirectory of the root path as its own repository, named
+/// after the subdirectory. Ignore patterns and excluded paths apply inside every subtree
+/// and can also leave out whole subdirectories. All keys share one storage connection;
+/// the command stops at the first key that fails, keeping the ones already backed up.
+async fn backup_top_level_dirs(matches: &ArgMatches, params: BackupParams) {
+    let mounts = match MountRules::from_matches(
+        matches,
+        &params.root_path_string,
+        WarningPolicy::from_matches(matches),
+    ) {
+        Ok(mounts) => mounts,
+        Err(e) => handle_error(e, None),
+    };
+
+    let ignore_rules = match IgnoreRules::new(
+        &params.root_path_string,
+        &params.ignore_patterns,
+        &params.exclude_paths,
+        params.ignore_case,
+        params.exclude_vcs,
+    ) {
+        Ok(rules) => rules.with_mounts(mounts),
+        Err(e) => handle_error(e, None),
+    };
+
+    let mut dirs = Vec::new();
+    let mut loose_files = 0;
+
+    for entry in walkdir::WalkDir::new(&params.root_path_string)
+        .min_depth(1)
+        .max_depth(1)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|entry| !ignore_rules.is_ignored(entry))
+    {
+        if entry.file_type().is_dir() {
+            dirs.push(entry.path().to_string_lossy().to_string());
+        } else {
+            loose_files += 1;
+        }
+    }
+
+    if dirs.is_empty() {
+        handle_error(
+            format!(
+                "No subdirectories to back up in {}",
+                params.root_path_string
+            ),
+            None,
+        );
+    }
+
+    if loose_files > 0 {
+        emit_warning(
+            &format!(
+                "{} entries directly inside {} are not in a subdirectory and will not be backed up",
+                loose_files, params.root_path_string
+            ),
+            "split_loose_files",
+        );
+    }
+
+    let fs = get_fs(&get_storage(&params.storage), None);
+
+    #[derive(serde::Serialize)]
+    struct KeyBackupOutput {
+        key: String,
+        #[serde(flatten)]
+        backup: BackupOutput,
+    }
+
+    let mut backups = Vec::new();
+
+    for (index, dir) in dirs.iter().enumerate() {
+        let key = Path::new(dir)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| dir.clone());
+
+        let header = format!(
+            "[{}/{}] Backing up {} as '{}'",
+            index + 1,
+            dirs.len(),
+            relative_path(dir, &params.root_path_string),
+            key
+        );
+        if is_json_mode() {
+            emit_progress_message(&header);
+        } else {
+            println!("{}", style(header).bold());
+        }
+
+        let dir_params = BackupParams {
+            key: key.clone(),
+            message: params.message.clone(),
+            root_path_string: dir.clone(),
+            storage: params.storage.clone(),
+            compress: params.compress,
+            password: params.password.clone(),
+            chunker: params.chunker,
+            ignore_patterns: params.ignore_patterns.clone(),
+            exclude_paths: params.exclude_paths.clone(),
+            ignore_case: params.ignore_case,
+            exclude_vcs: params.exclude_vcs,
+            pending_backup: None,
+            concurrency: params.concurrency,
+            progress_every: params.progress_every,
+            progress_interval: params.progress_interval,
+            timestamp: params.timestamp,
+        };
+
+        if let Some(backup) = run_backup(matches, dir_params, Arc::clone(&fs)).await {
+            backups.push(KeyBackupOutput { key, backup });
+        }
+    }
+
+    #[derive(serde::Serialize)]
+    struct SplitBackupOutput {
+        backups: Vec<KeyBackupOutput>,
+    }
+
+    let payload = SplitBackupOutput { backups };
+
+    if let Err(e) = write_report(&payload) {
+        handle_error(e, None);
+    }
+
+    if is_json_mode() {
+        emit_output(&payload);
+    } else {
+        println!();
+        println!(
+            "{} {} repositories",
+            style("Backed up").bold(),
+            payload.backups.len()
+        );
+        for entry in &payload.backups {
+            println!(
+                "  {:<30} {}  {} written, {} deduplicated",
+                entry.key,
+                entry.backup.backup_short,
+                ByteSize(entry.backup.written_bytes),
+                ByteSize(entry.backup.deduplicated_bytes),
+            );
+        }
+    }
+}
+
+/// Runs one backup and returns its summary, or `None` for `--dry-run`. In interactive
+/// mode the result is printed here; in JSON mode the caller emits it.
+async fn run_backup(
+    matches: &ArgMatches,
+    params: BackupParams,
+    fs: Arc<dyn FS>,
+) -> Option<BackupOutput> {
+    let BackupParams {
+        key,
+        message,
+        root_path_string,
+        storage: _,
+        compress,
+        password,
+        chunker,
+        ignore_patterns,
+        exclude_paths,
+        ignore_case,
+        exclude_vcs,
+        pending_backup: received_pending_backup,
+        concurrency,
+        progress_every,
+        progress_interval,
+        timestamp,
+    } = params;
+
+    let show_stats = matches.get_flag("stats");
+    let follow_symlinks = matches.get_flag("follow-symlinks");
+    let warning_policy = WarningPolicy::from_matches(matches);
+    let skip_policy = SkipPolicy {
+        permission_denied: matches.get_flag("skip-permission-denied"),
+        vanished: matches.get_flag("skip-vanished"),
+    };
+
+    let max_chunk_objects_per_dir = match matches.get_one::<String>("max-chunk-objects-per-dir") {
+        Some(max) => match max.parse::<u64>() {
+            Ok(max) if max > 0 => Some(max),
+            _ => handle_error(
+                format!(
+                    "Invalid value for --max-chunk-objects-per-dir: '{}' (must be a positive number)",
+                    max
+                ),
+                None,
+            ),
+        },
+        None => None,
+    };
+
+    let content_cache = match matches.get_one::<String>("chunk-cache-size") {
+        Some(size) => match size.parse::<usize>() {
+            Ok(size) if size > 0 => Some(Arc::new(ContentCache::new(size))),
+            _ => handle_error(
+                format!(
+                    "Invalid value for --chunk-cache-size: '{}' (must be a positive number of files)",
+                    size
+                ),
+                None,
+            ),
+        },
+        None => None,
+    };
+
+    let budget = match get_budget(matches) {
+        Ok(budget) => budget.map(Arc::new),
+        Err(e) => handle_error(e, None),
+    };
+
+    let newer_than = match get_newer_than_cutoff(matches) {
+        Ok(newer_than) => newer_than.map(Arc::new),
+        Err(e) => handle_error(e, None),
+    };
+
+    let signing_key = match matches.get_one::<String>("sign-key") {
+        Some(path) => match load_signing_key(path) {
+            Ok(signing_key) => Some(signing_key),
+            Err(e) => handle_error(e, None),
+        },
+        None => None,
+    };
+
+    let lock_timeout = match matches.get_one::<String>("lock-timeout") {
+        Some(timeout) => match parse_duration(timeout, "--lock-timeout") {
+            Ok(timeout) => timeout,
+            Err(e) => handle_error(e, None),
+        },
+        None => Duration::ZERO,
+    };
+
+    let max_backups = match matches.get_one::<String>("max-backups") {
+        Some(max) => match max.parse::<usize>() {
+            Ok(max) if max > 0 => Some(max),
+            _ => handle_error(
+                format!(
+                    "Invalid value for --max-backups: '{}' (must be a positive number)",
+                    max
+                ),
+                None,
+            ),
+        },
+        None => None,
+    };
+
+    let assume_unchanged = match matches.get_many::<String>("assume-unchanged") {
+        Some(patterns) => match build_assume_unchanged_patterns(patterns) {
+            Ok(patterns) => Some(patterns),
+            Err(e) => handle_error(e, None),
+        },
+        None => None,
+    };
+
+    let mounts = match MountRules::from_matches(matches, &root_path_string, warning_policy) {
+        Ok(mounts) => mounts,
+        Err(e) => handle_error(e, None),
+    };
+
+    let ignore_rules = match IgnoreRules::new(
+        &root_path_string,
+        &ignore_patterns,
+        &exclude_paths,
+        ignore_case,
+        exclude_vcs,
+    ) {
+        Ok(rules) => rules.with_mounts(mounts.clone()),
+        Err(e) => handle_error(e, None),
+    };
+
+    if matches.get_flag("dry-run") {
+        let files = list_files(&root_path_string, &ignore_rules, follow_symlinks);
+
+        let rotation = match max_backups {
+            Some(max_backups) => match preview_rotation(
+                Arc::clone(&fs),
+                &key,
+                password.clone(),
+                &message,
+                timestamp,
+                matches.get_one::<String>("exclude-from-backup"),
+                max_backups,
+            )
+            .await
+            {
+                Ok(rotation) => Some(rotation),
+                Err(e) => handle_error(e, None),
+            },
+            None => None,
+        };
+
+        let prediction = if matches.get_flag("detailed") {
+            match predict_dedup(
+                fs,
+                &key,
+                password,
+                &root_path_string,
+                &files,
+                chunker,
+                concurrency,
+            )
+            .await
+            {
+                Ok(prediction) => Some(prediction),
+                Err(e) => handle_error(e, None),
+            }
+        } else {
+            None
+        };
+
+        dry_run(
+            &root_path_string,
+            &files,
+            &ignore_rules,
+            prediction,
+            matches.get_flag("list-ignored"),
+            rotation,
+        );
+        return None;
+    }
+
+    let received_pending_backup = Arc::new(Mutex::new(received_pending_backup));
+
+    let home_dir = match home_dir() {
+        Some(dir) => dir,
+        None => handle_error("Failed to get home directory".to_string(), None),
+    };
+
+    let config_path = home_dir.join(".gib").join("config.msgpack");
+
+    if !config_path.exists() {
+        handle_error("Seems like you didn't configure your backup tool yet. Run 'gib config' to configure your backup tool.".to_string(), None);
+    }
+
+    let config_bytes = match std::fs::read(&config_path) {
+        Ok(bytes) => bytes,
+        Err(e) => handle_error(format!("Failed to read config file: {}", e), None),
+    };
+
+    let config: Config = match rmp_serde::from_slice(&config_bytes) {
+        Ok(config) => config,
+        Err(e) => handle_error(format!("Failed to deserialize config: {}", e), None),
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb.set_message("Loading metadata from the repository key...");
+        pb
+    };
+
+    if is_json_mode() {
+        emit_progress_message("Loading metadata from the repository key...");
+    }
+
+    let lock = match acquire_lock(
+        Arc::clone(&fs),
+        &key,
+        lock_timeout,
+        |holder| {
+            let message = format!("Waiting for the repository lock held by {}...", holder);
+            pb.set_message(message.clone());
+            if is_json_mode() {
+                emit_progress_message(&message);
+            }
+        },
+        |holder, reason| {
+            let warning = format!(
+                "Taking over the stale repository lock held by {}: {}",
+                holder, reason
+            );
+            warning_policy.escalate(&warning, "stale_lock_taken_over", Some(&pb));
+            pb.suspend(|| emit_warning(&warning, "stale_lock_taken_over"));
+        },
+    )
+    .await
+    {
+        Ok(lock) => lock,
+        Err(e) => {
+            if is_json_mode() {
+                emit_error(&e, "repository_locked");
+            }
+            handle_error(e, Some(&pb));
+        }
+    };
+
+    pb.set_message("Generating new backup...");
+    if is_json_mode() {
+        emit_progress_message("Generating new backup...");
+    }
+
+    let prev_not_encrypted_but_now_yes = Arc::new(Mutex::new(false));
+
+    let (mut new_backup, mut root_files, chunk_indexes, low_memory) = match load_metadata(
+        Arc::clone(&fs),
+        key.clone(),
+        message,
+        config,
+        root_path_string.clone(),
+        password.clone(),
+        Arc::clone(&prev_not_encrypted_but_now_yes),
+        ignore_rules,
+        timestamp,
+        matches.get_flag("low-memory"),
+        follow_symlinks,
+    )
+    .instrument(info_span!("load_metadata", key = %key))
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    if root_files.is_empty() {
+        let message = format!(
+            "No files were selected for backup in {}; check --root-path and the ignore rules",
+            root_path_string
+        );
+
+        if matches.get_flag("fail-if-empty") {
+            let _ = lock.release().await;
+            if is_json_mode() {
+                emit_error(&message, "backup_empty");
+            }
+            handle_error(message, Some(&pb));
+        }
+
+        warning_policy.escalate(&message, "backup_empty", Some(&pb));
+        pb.suspend(|| emit_warning(&message, "backup_empty"));
+    }
+
+    let differential_base = match matches.get_one::<String>("exclude-from-backup") {
+        Some(base) => {
+            match load_differential_base(Arc::clone(&fs), &key, password.clone(), base).await {
+                Ok(base) => Some(base),
+                Err(e) => handle_error(e, Some(&pb)),
+            }
+        }
+        None => None,
+    };
+
+    if let Some(base) = &differential_base {
+        new_backup.base = Some(base.hash.clone());
+
+        let chain_length = base.chain_length + 1;
+        if chain_length >= DIFFERENTIAL_CHAIN_WARNING {
+            let warning = format!(
+                "This differential backup sits on a chain of {} backups; restoring it loads every one of them. Start a new chain with a full backup to keep restores fast.",
+                chain_length
+            );
+            warning_policy.escalate(&warning, "long_differential_chain", Some(&pb));
+            pb.suspend(|| emit_warning(&warning, "long_differential_chain"));
+        }
+    }
+
+    let continue_error_message = format!(
+        "Continue from the place where the backup was interrupted by running: gib backup --continue {}",
+        new_backup.hash[..8].to_string()
+    );
+
+    if let Some(budget) = &budget {
+        budget.priority_order.sort_files(&mut root_files);
+    }
+
+    let chunk_dir_levels = match resolve_chunk_dir_levels(
+        Arc::clone(&fs),
+        key.clone(),
+        max_chunk_objects_per_dir,
+        chunk_indexes.is_empty()
+            && low_memory
+                .as_ref()
+                .is_none_or(|low_memory| low_memory.chunk_index.is_empty()),
+        &root_files,
+        chunker,
+        warning_policy,
+    )
+    .await
+    {
+        Ok(levels) => levels,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let total_files = root_files.len();
+
+    info!(
+        backup = %new_backup.hash,
+        files = total_files,
+        known_chunks = chunk_indexes.len() as u64
+            + low_memory
+                .as_ref()
+                .map_or(0, |low_memory| low_memory.chunk_index.len()),
+        "loaded repository metadata"
+    );
+
+    pb.finish_and_clear();
+
+    if *prev_not_encrypted_but_now_yes.lock().unwrap() {
+        let warning = "The backup was not encrypted but you provided a password. Only new chunks will be encrypted; run 'gib encrypt' to encrypt existing chunks.";
+        warning_policy.escalate(warning, "unencrypted_chunks", None);
+        if is_json_mode() {
+            emit_warning(warning, "unencrypted_chunks");
+        } else {
+            println!("{}", style(warning).yellow());
+        }
+    }
+
+    let renames = if matches.get_flag("detect-renames") {
+        match load_rename_detector(Arc::clone(&fs), key.clone(), password.clone()).await {
+            Ok(renames) => Some(Arc::new(renames)),
+            Err(e) => {
+                let warning = format!("Rename detection is disabled for this backup: {}", e);
+                warning_policy.escalate(&warning, "rename_detection_unavailable", None);
+                emit_warning(&warning, "rename_detection_unavailable");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    // A repository without a usable previous backup still stores append states, so the
+    // next backup can resume from them.
+    let appends = if matches.get_flag("exclude-bigger-deltas") {
+        match load_previous_tree(Arc::clone(&fs), key.clone(), password.clone()).await {
+            Ok(previous_files) => Some(Arc::new(AppendDetector { previous_files })),
+            Err(e) => {
+                let warning = format!("Appended files will be read in full for this backup: {}", e);
+                warning_policy.escalate(&warning, "append_detection_unavailable", None);
+                emit_warning(&warning, "append_detection_unavailable");
+                Some(Arc::new(AppendDetector {
+                    previous_files: HashMap::new(),
+                }))
+            }
+        }
+    } else {
+        None
+    };
+
+    let assumed = match assume_unchanged {
+        Some(patterns) => {
+            match load_previous_tree(Arc::clone(&fs), key.clone(), password.clone()).await {
+                Ok(previous_files) if !previous_files.is_empty() => {
+                    Some(Arc::new(AssumeUnchanged {
+                        patterns,
+                        previous_files,
+                    }))
+                }
+                Ok(_) => {
+                    let _ = lock.release().await;
+                    handle_error(
+                        format!(
+                            "--assume-unchanged needs a previous backup of {} to take the files from; run one backup without it first",
+                            key
+                        ),
+                        None,
+                    );
+                }
+                Err(e) => {
+                    let _ = lock.release().await;
+                    handle_error(
+                        format!(
+                            "Failed to load the previous backup for --assume-unchanged: {}",
+                            e
+                        ),
+                        None,
+                    );
+                }
+            }
+        }
+        None => None,
+    };
+
+    let json_progress = if is_json_mode() {
+        let progress =
+            JsonProgress::with_throttle(root_files.len() as u64, progress_every, progress_interval);
+        if matches.get_flag("json-progress-bytes") {
+            let total_bytes = root_files
+                .iter()
+                .filter_map(|file_path| {
+                    if follow_symlinks {
+                        std::fs::metadata(file_path).ok()
+                    } else {
+                        std::fs::symlink_metadata(file_path)
+                            .ok()
+                            .filter(|file_metadata| !file_metadata.file_type().is_symlink())
+                    }
+                })
+                .map(|file_metadata| file_metadata.len())
+                .sum();
+            progress.track_bytes(total_bytes, false);
+        }
+        progress.set_message(&format!(
+            "Backing up files to {}...",
+            new_backup.hash[..8].to_string()
+        ));
+        Some(progress)
+    } else {
+        None
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(root_files.len() as u64);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap(),
+        );
+        pb.set_message(format!(
+            "Backing up files to {}...",
+            new_backup.hash[..8].to_string()
+        ));
+        pb
+    };
+
+    let chunk_indexes: Arc<Mutex<HashMap<String, ChunkIndex>>> =
+        Arc::new(Mutex::new(chunk_indexes));
+
+    let new_backup: Arc<Mutex<Backup>> = Arc::new(Mutex::new(new_backup));
+
+    let files_set = Arc::new(TokioMutex::new(JoinSet::new()));
+    let written_bytes = Arc::new(Mutex::new(0));
+    let deduplicated_bytes = Arc::new(Mutex::new(0));
+    let verify_writes = matches.get_flag("verify-chunks-after-write");
+    let store_acls = matches.get_flag("store-acls");
+    let stats = Arc::new(Mutex::new(BackupStats {
+        write_verify_mismatches: verify_writes.then_some(0),
+        appended_files: appends.is_some().then_some(0),
+        assumed_unchanged_files: assumed.is_some().then_some(0),
+        chunk_cache_hits: content_cache.is_some().then_some(0),
+        chunk_cache_misses: content_cache.is_some().then_some(0),
+        ..Default::default()
+    }));
+    let permits = Arc::new(PermitPool::new(concurrency));
+    let concurrency_tuner = matches.get_flag("concurrency-auto").then(|| {
+        let written_bytes = Arc::clone(&written_bytes);
+        let deduplicated_bytes = Arc::clone(&deduplicated_bytes);
+        ConcurrencyTuner::start(Arc::clone(&permits), move || {
+            *written_bytes.lock().unwrap() + *deduplicated_bytes.lock().unwrap()
+        })
+    });
+
+    let pending_backup = Arc::new(Mutex::new(PendingBackup {
+        message: new_backup.lock().unwrap().message.clone(),
+        compress,
+        chunk_size: chunker.target_size(),
+        concurrency,
+        ignore_patterns: ignore_patterns.clone(),
+        exclude_paths: exclude_paths.clone(),
+        ignore_case,
+        exclude_vcs,
+        processed_chunks: Vec::new(),
+        created_at: Some(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        ),
+        chunker: chunker.fixed_size().is_none().then_some(chunker),
+    }));
+    let pending_backup_path = Arc::new(pending_backup_path(&key, &new_backup.lock().unwrap().hash));
+
+    let pending_backup_watcher_stop = Arc::new(AtomicBool::new(false));
+
+    {
+        let fs_clone = Arc::clone(&fs);
+        let pending_backup_clone = Arc::clone(&pending_backup);
+        let pending_backup_path_clone = pending_backup_path.clone();
+        let pending_backup_watcher_stop_clone = pending_backup_watcher_stop.clone();
+        let password_clone = password.clone();
+
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(watch_pending_backup(
+                pending_backup_clone,
+                pending_backup_path_clone,
+                fs_clone,
+                pending_backup_watcher_stop_clone,
+                password_clone,
+            ));
+        });
+    };
+
+    let context = Arc::new(BackupContext {
+        key: key.clone(),
+        password: password.clone(),
+        fs: Arc::clone(&fs),
+        root_path_string: root_path_string.clone(),
+        pb: pb.clone(),
+        json_progress: json_progress.clone(),
+        chunk_indexes: Arc::clone(&chunk_indexes),
+        new_backup: Arc::clone(&new_backup),
+        written_bytes: Arc::clone(&written_bytes),
+        deduplicated_bytes: Arc::clone(&deduplicated_bytes),
+        stats: Arc::clone(&stats),
+        pending_backup: Arc::clone(&pending_backup),
+        received_pending_backup: Arc::clone(&received_pending_backup),
+        chunker,
+        compress,
+        store_extensions: get_store_extensions(matches),
+        chunk_dir_levels,
+        follow_symlinks,
+        verify_writes,
+        store_acls,
+        skip_policy,
+        warning_policy,
+        renames,
+        appends,
+        assumed,
+        newer_than: newer_than.clone(),
+        low_memory: low_memory.clone(),
+        content_cache,
+    });
+
+    let files_stream = stream::iter(root_files);
+
+    files_stream
+        .for_each_concurrent(concurrency, |file_path| {
+            let context = Arc::clone(&context);
+            let permits_clone = Arc::clone(&permits);
+            let files_set_clone = Arc::clone(&files_set);
+            let budget_clone = budget.clone();
+
+            async move {
+                let mut guard = files_set_clone.lock().await;
+                guard.spawn(async move {
+                    let _permit = permits_clone.acquire().await;
+
+                    if let Some(budget) = &budget_clone
+                        && *context.written_bytes.lock().unwrap() >= budget.limit
+                    {
+                        trace!(file = %file_path, "budget reached, skipping file");
+                        budget
+                            .skipped_files
+                            .lock()
+                            .unwrap()
+                            .push(relative_path(&file_path, &context.root_path_string));
+                        context.stats.lock().unwrap().skipped_files += 1;
+                        if let Some(progress) = &context.json_progress {
+                            progress.inc_by(1);
+                        } else {
+                            context.pb.inc(1);
+                        }
+                        return Ok(());
+                    }
+
+                    backup_file(file_path, context)
+                        .await
+                        .inspect_err(|_| permits_clone.record_error())
+                });
+            }
+        })
+        .await;
+
+    let mut failed_files = Vec::new();
+
+    {
+        let mut guard = files_set.lock().await;
+        while let Some(file_process_result) = guard.join_next().await {
+            match file_process_result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => failed_files.push(e),
+                Err(e) => failed_files.push(e.to_string()),
+            }
+        }
+    }
+
+    pending_backup_watcher_stop.store(true, Ordering::SeqCst);
+
+    let auto_concurrency = concurrency_tuner.map(ConcurrencyTuner::report);
+
+    if let Some(progress) = &json_progress {
+        progress.finish();
+    }
+
+    info!(
+        files = total_files,
+        failed = failed_files.len(),
+        "finished processing files"
+    );
+
+    if !failed_files.is_empty() {
+        handle_error(
+            format!(
+                "Failed to process {} files:\n{}\n\n{}",
+                failed_files.len(),
+                failed_files
+                    .iter()
+                    .map(|f| format!("  - {}", f))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+                &continue_error_message
+            ),
+            Some(&pb),
+        );
+    }
+
+    let unstored_acl_files = stats.lock().unwrap().unstored_acl_files;
+    if unstored_acl_files > 0 {
+        let warning = format!(
+            "{} files have POSIX ACLs that were not stored. Pass --store-acls to keep them.",
+            unstored_acl_files
+        );
+        warning_policy.escalate(&warning, "acls_not_stored", Some(&pb));
+        pb.suspend(|| emit_warning(&warning, "acls_not_stored"));
+    }
+
+    let differential = differential_base.as_ref().map(|base| {
+        base.reduce(
+            &mut new_backup.lock().unwrap(),
+            &mut chunk_indexes.lock().unwrap(),
+        )
+    });
+
+    let compressed_chunk_indexes_bytes = match &low_memory {
+        Some(low_memory) => low_memory.chunk_index.to_compressed(compress),
+        None => {
+            let chunk_indexes_bytes = rmp_serde::to_vec_named(&*chunk_indexes.lock().unwrap())
+                .unwrap_or_else(|_| Vec::new());
+            compress_bytes(&chunk_indexes_bytes, compress)
+                .map_err(|e| format!("Failed to compress chunk indexes: {}", e))
+        }
+    };
+    let compressed_chunk_indexes_bytes = match compressed_chunk_indexes_bytes {
+        Ok(bytes) => bytes,
+        Err(e) => handle_error(format!("{}\n\n{}", e, &continue_error_message), Some(&pb)),
+    };
+
+    let chunk_index_path = chunk_index_path(&key);
+
+    // Encrypted here rather than while writing, so a signature covers the exact bytes stored.
+    let stored_chunk_index_bytes =
+        encrypt_for_storage(&compressed_chunk_indexes_bytes, password.as_deref());
+
+    let write_chunk_index_future = async {
+        fs.write_file(&chunk_index_path, &stored_chunk_index_bytes)
+            .await
+            .map_err(|e| format!("Failed to write file {}: {}", chunk_index_path, e))
+    };
+
+    let content = if matches.get_flag("content-addressed-manifests") {
+        match content_hash(&new_backup.lock().unwrap().tree) {
+            Ok(content) => Some(content),
+            Err(e) => handle_error(e, Some(&pb)),
+        }
+    } else {
+        None
+    };
+
+    // An identical tree was already stored by an earlier backup; this one only adds a
+    // summary pointing at it.
+    let manifest_shared = match &content {
+        Some(content) => match manifest_exists(Arc::clone(&fs), key.clone(), content).await {
+            Ok(exists) => exists,
+            Err(e) => handle_error(e, Some(&pb)),
+        },
+        None => false,
+    };
+
+    let compressed_backup_file_bytes = match &low_memory {
+        Some(low_memory) => low_memory
+            .tree
+            .to_compressed_manifest(&new_backup.lock().unwrap(), compress),
+        None => {
+            let backup_file_bytes = {
+                let mut backup_guard = new_backup.lock().unwrap();
+                match &content {
+                    Some(content) => {
+                        let own_hash = std::mem::replace(&mut backup_guard.hash, content.clone());
+                        let bytes = rmp_serde::to_vec_named(&*backup_guard);
+                        backup_guard.hash = own_hash;
+                        bytes
+                    }
+                    None => rmp_serde::to_vec_named(&*backup_guard),
+                }
+                .unwrap_or_else(|_| Vec::new())
+            };
+
+            compress_bytes(&backup_file_bytes, compress)
+                .map_err(|e| format!("Failed to compress backup: {}", e))
+        }
+    };
+    let compressed_backup_file_bytes = match compressed_backup_file_bytes {
+        Ok(bytes) => bytes,
+        Err(e) => handle_error(format!("{}\n\n{}", e, &continue_error_message), Some(&pb)),
+    };
+
+    let backup_file_path = backup_path(
+        &key,
+        content
+            .as_deref()
+            .unwrap_or(&new_backup.lock().unwrap().hash),
+    );
+
+    let stored_backup_file_bytes =
+        encrypt_for_storage(&compressed_backup_file_bytes, password.as_deref());
+
+    let write_backup_file_future = async {
+        if manifest_shared {
+            return Ok(());
+        }
+
+        fs.write_file(&backup_file_path, &stored_backup_file_bytes)
+            .await
+            .map_err(|e| format!("Failed to write file {}: {}", backup_file_path, e))
+    };
+
+    let (write_chunk_index_result, write_backup_file_result) =
+        async { tokio::join!(write_chunk_index_future, write_backup_file_future) }
+            .instrument(info_span!("write_indexes"))
+            .await;
+
+    if write_chunk_index_result.is_err() {
+        handle_error(
+            format!(
+                "Failed to write chunk indexes\n\n{}",
+                &continue_error_message
+            ),
+            Some(&pb),
+        );
+    }
+
+    if write_backup_file_result.is_err() {
+        handle_error(
+            format!("Failed to write backup file\n\n{}", &continue_error_message),
+            Some(&pb),
+        );
+    }
+
+    if signing_key.is_none()
+        && let Err(e) = remove_signature(&fs, &key, &chunk_index_path).await
+    {
+        handle_error(format!("{}\n\n{}", e, &continue_error_message), Some(&pb));
+    }
+
+    if let Some(signing_key) = &signing_key {
+        let mut signed_objects = vec![(&chunk_index_path, &stored_chunk_index_bytes)];
+        if !manifest_shared {
+            signed_objects.push((&backup_file_path, &stored_backup_file_bytes));
+        }
+
+        for (object_path, stored_bytes) in signed_objects {
+            if let Err(e) = sign_object(&fs, &key, object_path, stored_bytes, signing_key).await {
+                handle_error(format!("{}\n\n{}", e, &continue_error_message), Some(&pb));
+            }
+        }
+    }
+
+    let written_bytes = *written_bytes.lock().unwrap();
+    let deduplicated_bytes = *deduplicated_bytes.lock().unwrap();
+    let skipped_files = stats.lock().unwrap().skipped_files;
+    let stats = stats
+        .lock()
+        .unwrap()
+        .to_output(written_bytes, deduplicated_bytes);
+
+    {
+        let backup_guard = new_backup.lock().unwrap();
+        if let Err(e) = add_backup_summary(
+            Arc::clone(&fs),
+            key.clone(),
+            &backup_guard,
+            content.clone(),
+            compress,
+            password.clone(),
+            &written_bytes,
+        )
+        .await
+        {
+            handle_error(
+                format!(
+                    "Failed to save backup summary: {}\n\n{}",
+                    &e, &continue_error_message
+                ),
+                Some(&pb),
+            );
+        }
+    }
+
+    let mut leftover_pending = Vec::new();
+
+    if let Err(e) = fs.delete_file(&pending_backup_path).await
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        leftover_pending.push(format!("{} ({})", pending_backup_path, e));
+    }
+
+    {
+        let received_pending_backup = received_pending_backup.lock().unwrap().take();
+        if let Some(pending_backup) = received_pending_backup
+            && let Err(e) = fs.delete_file(&pending_backup.path).await
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            leftover_pending.push(format!("{} ({})", pending_backup.path, e));
+        }
+    }
+
+    if !leftover_pending.is_empty() {
+        let warning = format!(
+            "The backup was saved but its pending file could not be removed: {}. Run 'gib storage prune --orphan-pending-only' to clean it up.",
+            leftover_pending.join(", ")
+        );
+        warning_policy.escalate(&warning, "pending_cleanup_failed", Some(&pb));
+        pb.suspend(|| emit_warning(&warning, "pending_cleanup_failed"));
+    }
+
+    // Rotation runs only now that the new backup is fully written, and still under the
+    // lock, so a failed backup never costs an old one.
+    let rotation = match max_backups {
+        Some(max_backups) => {
+            let new_hash = new_backup.lock().unwrap().hash.clone();
+            match rotate_backups(
+                &fs,
+                &key,
+                &password,
+                &new_hash,
+                max_backups,
+                chunk_dir_levels,
+                &pb,
+            )
+            .await
+            {
+                Ok(rotation) => Some(rotation),
+                Err(e) => {
+                    let _ = lock.release().await;
+                    handle_error(
+                        format!(
+                            "Backup {} was saved, but rotating out old backups failed: {}",
+                            &new_hash[..8.min(new_hash.len())],
+                            e
+                        ),
+                        Some(&pb),
+                    );
+                }
+            }
+        }
+        None => None,
+    };
+
+    if let Err(e) = lock.release().await {
+        let warning = format!(
+            "The backup was saved but its lock could not be removed ({}). It will be taken over once it goes stale.",
+            e
+        );
+        warning_policy.escalate(&warning, "lock_release_failed", Some(&pb));
+        pb.suspend(|| emit_warning(&warning, "lock_release_failed"));
+    }
+
+    if let Some(manifest_path) = matches.get_one::<String>("output-manifest") {
+        let backup_guard = new_backup.lock().unwrap();
+        if let Err(e) = write_local_manifest(
+            manifest_path,
+            &key,
+            &backup_guard,
+            written_bytes,
+            deduplicated_bytes,
+        ) {
+            handle_error(
+                format!(
+                    "Backup {} was saved, but {}",
+                    &backup_guard.hash[..8.min(backup_guard.hash.len())],
+                    e
+                ),
+                Some(&pb),
+            );
+        }
+    }
+
+    let payload = {
+        let backup_guard = new_backup.lock().unwrap();
+
+        let budget = budget.as_ref().map(|budget| {
+            let mut included_files = backup_guard.tree.keys().cloned().collect::<Vec<String>>();
+            included_files.sort();
+
+            let mut skipped_files = budget.skipped_files.lock().unwrap().clone();
+            skipped_files.sort();
+
+            BudgetOutput {
+                budget_bytes: budget.limit,
+                priority_order: budget.priority_order.as_str(),
+                included_files,
+                skipped_files,
+            }
+        });
+
+        let deferred = newer_than.as_ref().map(|newer_than| {
+            let mut files = newer_than.deferred_files.lock().unwrap().clone();
+            files.sort();
+
+            DeferredOutput {
+                cutoff_unix: newer_than.cutoff_unix(),
+                deferred_files: files.len(),
+                files,
+            }
+        });
+
+        BackupOutput {
+            backup: backup_guard.hash.clone(),
+            backup_short: backup_guard.hash[..8.min(backup_guard.hash.len())].to_string(),
+            message: backup_guard.message.clone(),
+            author: backup_guard.author.clone(),
+            timestamp_unix: backup_guard.timestamp,
+            files_total: total_files,
+            skipped_files,
+            written_bytes,
+            deduplicated_bytes,
+            elapsed_ms: pb.elapsed().as_millis() as u64,
+            stats,
+            budget,
+            deferred,
+            mounts: mounts.as_ref().map(|mounts| mounts.report()),
+            content: content.clone(),
+            manifest_shared,
+            differential,
+            auto_concurrency,
+            rotation,
+        }
+    };
+
+    if !is_json_mode() {
+        let elapsed = pb.elapsed();
+        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
+        pb.set_prefix("OK");
+        if skipped_files > 0 {
+            pb.finish_with_message(format!(
+                "Backed up files ({:.2?}) - {} written, {} deduplicated, {} skipped",
+                elapsed,
+                ByteSize(written_bytes),
+                ByteSize(deduplicated_bytes),
+                skipped_files,
+            ));
+        } else {
+            pb.finish_with_message(format!(
+                "Backed up files ({:.2?}) - {} written, {} deduplicated",
+                elapsed,
+                ByteSize(written_bytes),
+                ByteSize(deduplicated_bytes),
+            ));
+        }
+
+        if show_stats {
+            print_stats(&payload.stats);
+        } else if let Some(mismatches) = payload.stats.write_verify_mismatches
+            && mismatches > 0
+        {
+            println!(
+                "{}",
+                style(format!(
+                    "{} chunks did not read back as written and were rewritten",
+                    mismatches
+                ))
+                .yellow()
+            );
+        }
+
+        if let Some(mounts) = &payload.mounts {
+            mounts.print();
+        }
+
+        if let Some(auto_concurrency) = &payload.auto_concurrency {
+            println!(
+                "{}",
+                style(format!(
+                    "Concurrency settled at {} (started at {}, {} adjustments)",
+                    auto_concurrency.stabilized,
+                    auto_concurrency.initial,
+                    auto_concurrency.adjustments
+                ))
+                .dim()
+            );
+        }
+
+        if let Some(differential) = &payload.differential {
+            println!(
+                "{}",
+                style(format!(
+                    "Differential of {}: {} changed, {} removed, {} unchanged files left to the base",
+                    &differential.base[..8],
+                    differential.changed_files,
+                    differential.removed_files,
+                    differential.unchanged_files
+                ))
+                .dim()
+            );
+        }
+
+        if let Some(assumed_unchanged_files) = payload.stats.assumed_unchanged_files {
+            println!(
+                "{}",
+                style(format!(
+                    "{} files matching --assume-unchanged were taken from the previous backup without reading them",
+                    assumed_unchanged_files
+                ))
+                .dim()
+            );
+        }
+
+        if let Some(rotation) = &payload.rotation
+            && !rotation.deleted_backups.is_empty()
+        {
+            println!(
+                "{}",
+                style(format!(
+                    "Rotated out {} old backups to keep at most {} ({} chunks deleted)",
+                    rotation.deleted_backups.len(),
+                    rotation.max_backups,
+                    rotation.deleted_chunks.unwrap_or(0)
+                ))
+                .dim()
+            );
+        }
+
+        if let Some(content) = &payload.content
+            && manifest_shared
+        {
+            println!(
+                "{}",
+                style(format!(
+                    "Same content as an earlier backup, sharing its manifest ({})",
+                    &content[..8]
+                ))
+                .dim()
+            );
+        }
+
+        if let Some(deferred) = &payload.deferred
+            && deferred.deferred_files > 0
+        {
+            println!(
+                "{}",
+                style(format!(
+                    "{} files were deferred to the next backup because they were modified after the cutoff ({})",
+                    deferred.deferred_files, deferred.cutoff_unix
+                ))
+                .yellow()
+            );
+        }
+
+        if let Some(budget) = &budget {
+            let budget_skipped = budget.skipped_files.lock().unwrap().len();
+            if budget_skipped > 0 {
+                println!(
+                    "{}",
+                    style(format!(
+                        "{} files were not backed up because the budget of {} was reached",
+                        budget_skipped,
+                        ByteSize(budget.limit)
+                    ))
+                    .yellow()
+                );
+            }
+        }
+    }
+
+    Some(payload)
+}
+
+#[derive(serde::Serialize)]
+struct BackupOutput {
+    backup: String,
+    backup_short: String,
+    message: String,
+    author: String,
+    timestamp_unix: u64,
+    files_total: usize,
+    skipped_files: u64,
+    written_bytes: u64,
+    deduplicated_bytes: u64,
+    elapsed_ms: u64,
+    stats: BackupStatsOutput,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    budget: Option<BudgetOutput>,
+    /// With `--exclude-newer-than`, the files left out for being modified after the cutoff.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deferred: Option<DeferredOutput>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mounts: Option<MountReport>,
+    /// Hash of the tree with `--content-addressed-manifests`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    /// Whether an earlier backup with the same content already stored the manifest.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    manifest_shared: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    differential: Option<DifferentialOutput>,
+    /// With `--concurrency-auto`, where the concurrency settled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auto_concurrency: Option<ConcurrencyReport>,
+    /// With `--max-backups`, the old backups deleted after this one was saved.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rotation: Option<RotationOutput>,
+}
+
+#[derive(serde::Serialize)]
+struct RotationOutput {
+    max_backups: usize,
+    deleted_backups: Vec<String>,
+    /// Not known for `--dry-run`, which does not look at the chunk index.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_chunks: Option<usize>,
+}
+
+/// Splits `summaries` for `--max-backups`: the newest `max_backups` backups, counting
+/// `new_backup`, stay along with the bases they need; the rest are returned to be deleted.
+fn rotation_plan<'a>(
+    summaries: &'a [BackupSummary],
+    new_backup: &str,
+    max_backups: usize,
+) -> (Vec<&'a BackupSummary>, Vec<BackupSummary>) {
+    let policy = RetentionPolicy {
+        keep_last: Some(max_backups - 1),
+        ..Default::default()
+    };
+
+    let mut removed = Vec::new();
+    let mut remaining = Vec::new();
+    for (summary, reasons) in summaries
+        .iter()
+        .zip(policy.apply(summaries, Some(new_backup)))
+    {
+        if reasons.is_empty() {
+            removed.push(summary);
+        } else {
+            remaining.push(summary.clone());
+        }
+    }
+
+    (removed, remaining)
+}
+
+/// Deletes the backups beyond `--max-backups` once `new_backup` is saved, releasing their
+/// chunks the same way `gib backup delete` does.
+async fn rotate_backups(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    new_backup: &str,
+    max_backups: usize,
+    chunk_dir_levels: u8,
+    pb: &ProgressBar,
+) -> Result<RotationOutput, String> {
+    let summaries =
+        list_backup_summaries(Arc::clone(fs), key.to_string(), password.clone()).await?;
+    let (removed, remaining) = rotation_plan(&summaries, new_backup, max_backups);
+
+    if removed.is_empty() {
+        return Ok(RotationOutput {
+            max_backups,
+            deleted_backups: Vec::new(),
+            deleted_chunks: Some(0),
+        });
+    }
+
+    let message = format!("Rotating out {} old backups...", removed.len());
+    pb.set_message(message.clone());
+    if is_json_mode() {
+        emit_progress_message(&message);
+    }
+
+    let chunks_to_delete = remove_backups(fs, key, password, &removed, &remaining, pb).await?;
+
+    pb.set_message(format!(
+        "Deleting {} orphaned chunks...",
+        chunks_to_delete.len()
+    ));
+    delete_orphaned_chunks(fs, key, &chunks_to_delete, chunk_dir_levels, false).await?;
+
+    Ok(RotationOutput {
+        max_backups,
+        deleted_backups: removed.iter().map(|summary| summary.hash.clone()).collect(),
+        deleted_chunks: Some(chunks_to_delete.len()),
+    })
+}
+
+/// What `--max-backups` would delete with `--dry-run`, counting the backup that is not
+/// made.
+async fn preview_rotation(
+    fs: Arc<dyn FS>,
+    key: &str,
+    password: Option<String>,
+    message: &str,
+    timestamp: u64,
+    base: Option<&String>,
+    max_backups: usize,
+) -> Result<RotationOutput, String> {
+    let mut summaries = list_backup_summaries(fs, key.to_string(), password).await?;
+
+    let base = match base {
+        Some(base) => Some(
+            summaries
+                .iter()
+                .find(|summary| summary.hash.starts_with(base.as_str()))
+                .map(|summary| summary.hash.clone())
+                .ok_or_else(|| format!("No backup found matching hash prefix: {}", base))?,
+        ),
+        None => None,
+    };
+
+    summaries.insert(
+        0,
+        BackupSummary {
+            message: message.to_string(),
+            hash: String::new(),
+            timestamp: Some(timestamp),
+            size: None,
+            author: None,
+            content: None,
+            base,
+        },
+    );
+
+    let (removed, _) = rotation_plan(&summaries, "", max_backups);
+
+    Ok(RotationOutput {
+        max_backups,
+        deleted_backups: removed.iter().map(|summary| summary.hash.clone()).collect(),
+        deleted_chunks: None,
+    })
+}
+
+#[derive(serde::Serialize)]
+struct DifferentialOutput {
+    base: String,
+    changed_files: usize,
+    removed_files: usize,
+    unchanged_files: usize,
+}
+
+/// A differential backup reloads its whole base chain on restore, so long chains are
+/// reported once they reach this length.
+const DIFFERENTIAL_CHAIN_WARNING: usize = 10;
+
+/// The base of a differential backup (`--exclude-from-backup`), with its complete tree.
+struct DifferentialBase {
+    hash: String,
+    tree: HashMap<String, BackupObject>,
+    /// How many backups the base itself depends on.
+    chain_length: usize,
+}
+
+impl DifferentialBase {
+    /// Drops the files that are identical in the base from `backup` and records the
+    /// paths of the base that no longer exist. Their chunks were counted while backing
+    /// them up, so those references are released again; the base still holds its own.
+    fn reduce(
+        &self,
+        backup: &mut Backup,
+        chunk_indexes: &mut HashMap<String, ChunkIndex>,
+    ) -> DifferentialOutput {
+        let mut removed = self
+            .tree
+            .keys()
+            .filter(|path| !backup.tree.contains_key(*path))
+            .cloned()
+            .collect::<Vec<String>>();
+        removed.sort();
+
+        let unchanged = backup
+            .tree
+            .iter()
+            .filter(|(path, object)| self.tree.get(*path) == Some(*object))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<String>>();
+
+        for path in &unchanged {
+            let Some(object) = backup.tree.remove(path) else {
+                continue;
+            };
+            for chunk_hash in &object.chunks {
+                if let Some(chunk_index) = chunk_indexes.get_mut(chunk_hash) {
+                    chunk_index.refcount = chunk_index.refcount.saturating_sub(1);
+                }
+            }
+        }
+
+        debug!(
+            base = %self.hash,
+            changed = backup.tree.len(),
+            removed = removed.len(),
+            unchanged = unchanged.len(),
+            "reduced backup to a differential"
+        );
+
+        let output = DifferentialOutput {
+            base: self.hash.clone(),
+            changed_files: backup.tree.len(),
+            removed_files: removed.len(),
+            unchanged_files: unchanged.len(),
+        };
+        backup.removed = removed;
+        output
+    }
+}
+
+async fn load_differential_base(
+    fs: Arc<dyn FS>,
+    key: &str,
+    password: Option<String>,
+    base: &str,
+) -> Result<DifferentialBase, String> {
+    let backup_summaries =
+        list_backup_summaries(Arc::clone(&fs), key.to_string(), password.clone()).await?;
+
+    let base_hash = backup_summaries
+        .iter()
+        .find(|summary| summary.hash.starts_with(base))
+        .map(|summary| summary.hash.clone())
+        .ok_or_else(|| format!("No backup found matching hash prefix: {}", base))?;
+
+    let (base_backup, chain_length) =
+        load_resolved_backup(fs, key.to_string(), password, base_hash.clone())
+            .await
+            .map_err(|e| format!("Failed to load the base backup {}: {}", base, e))?;
+
+    Ok(DifferentialBase {
+        hash: base_hash,
+        tree: base_backup.tree,
+        chain_length,
+    })
+}
+
+#[derive(serde::Serialize)]
+struct BudgetOutput {
+    budget_bytes: u64,
+    priority_order: &'static str,
+    included_files: Vec<String>,
+    skipped_files: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct DeferredOutput {
+    cutoff_unix: u64,
+    deferred_files: usize,
+    files: Vec<String>,
+}
+
+/// Writes the manifest of a finished backup to a local JSON file, with the tree sorted by
+/// path so archived manifests diff cleanly.
+fn write_local_manifest(
+    manifest_path: &str,
+    key: &str,
+    backup: &Backup,
+    written_bytes: u64,
+    deduplicated_bytes: u64,
+) -> Result<(), String> {
+    #[derive(serde::Serialize)]
+    struct LocalManifest<'a> {
+        key: &'a str,
+        backup: &'a str,
+        message: &'a str,
+        author: &'a str,
+        timestamp_unix: u64,
+        files_total: usize,
+        total_bytes: u64,
+        written_bytes: u64,
+        deduplicated_bytes: u64,
+        tree: BTreeMap<&'a String, &'a BackupObject>,
+    }
+
+    let manifest = LocalManifest {
+        key,
+        backup: &backup.hash,
+        message: &backup.message,
+        author: &backup.author,
+        timestamp_unix: backup.timestamp,
+        files_total: backup.tree.len(),
+        total_bytes: backup.tree.values().map(|object| object.size).sum(),
+        written_bytes,
+        deduplicated_bytes,
+        tree: backup.tree.iter().collect(),
+    };
+
+    let json = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| format!("the manifest could not be serialized: {}", e))?;
+
+    std::fs::write(manifest_path, json).map_err(|e| {
+        format!(
+            "the manifest could not be written to {}: {}",
+            manifest_path, e
+        )
+    })
+}
+
+fn print_stats(stats: &BackupStatsOutput) {
+    println!();
+    println!(
+        "{} {} new, {} deduplicated (avg {})",
+        style("Chunks:").bold(),
+        stats.new_chunks,
+        stats.deduplicated_chunks,
+        ByteSize(stats.average_chunk_size),
+    );
+    println!(
+        "{} {} -> {} ({:.2}x)",
+        style("Compression:").bold(),
+        ByteSize(stats.uploaded_bytes),
+        ByteSize(stats.compressed_bytes),
+        stats.compression_ratio,
+    );
+
+    if let Some(mismatches) = stats.write_verify_mismatches {
+        println!(
+            "{} {} chunks rewritten after reading back differently",
+            style("Write verification:").bold(),
+            mismatches,
+        );
+    }
+
+    if let Some(assumed_unchanged_files) = stats.assumed_unchanged_files {
+        println!(
+            "{} {} files taken from the previous backup without reading them",
+            style("Assumed unchanged:").bold(),
+            assumed_unchanged_files,
+        );
+    }
+
+    if let Some(appended_files) = stats.appended_files {
+        println!(
+            "{} {} files only read after their previous content",
+            style("Appends:").bold(),
+            appended_files,
+        );
+    }
+
+    if let (Some(hits), Some(misses), Some(hit_rate)) = (
+        stats.chunk_cache_hits,
+        stats.chunk_cache_misses,
+        stats.chunk_cache_hit_rate,
+    ) {
+        println!(
+            "{} {} of {} files reused the chunks of a recent file ({:.1}%)",
+            style("Chunk cache:").bold(),
+            hits,
+            hits + misses,
+            hit_rate * 100.0,
+        );
+    }
+
+    if !stats.renames.is_empty() {
+        println!(
+            "{} {} files reused from the previous backup",
+            style("Renames:").bold(),
+            stats.renamed_files,
+        );
+        for rename in &stats.renames {
+            println!("  {} -> {}", rename.from, rename.to);
+        }
+    }
+
+    if !stats.top_level_bytes.is_empty() {
+        println!("{}", style("Top-level breakdown:").bold());
+        for entry in &stats.top_level_bytes {
+            println!("  {:<40} {}", entry.path, ByteSize(entry.bytes));
+        }
+    }
+}
+
+async fn watch_pending_backup(
+    pending_backup: Arc<Mutex<PendingBackup>>,
+    pending_backup_path: Arc<String>,
+    fs: Arc<dyn FS>,
+    pending_backup_watcher_stop: Arc<AtomicBool>,
+    password: Option<String>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+
+        if pending_backup_watcher_stop.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let bytes_to_write = {
+            let pending_backup_guard = pending_backup.lock().unwrap();
+            rmp_serde::to_vec_named(&*pending_backup_guard).unwrap_or_else(|_| Vec::new())
+        };
+
+        let Ok(compressed_bytes) = compress_bytes(&bytes_to_write, 3) else {
+            continue;
+        };
+
+        let _ = write_file_maybe_encrypt(
+            &fs,
+            pending_backup_path.as_str(),
+            &compressed_bytes,
+            password.as_deref(),
+        )
+        .await;
+    }
+}
+
+/// What every file of a backup shares: the options the run was started with and the state
+/// the files update together. Built once by `run_backup` and handed to each `backup_file`.
+struct BackupContext {
+    key: String,
+    password: Option<String>,
+    fs: Arc<dyn FS>,
+    root_path_string: String,
+    pb: ProgressBar,
+    json_progress: Option<Arc<JsonProgress>>,
+    chunk_indexes: Arc<Mutex<HashMap<String, ChunkIndex>>>,
+    new_backup: Arc<Mutex<Backup>>,
+    written_bytes: Arc<Mutex<u64>>,
+    deduplicated_bytes: Arc<Mutex<u64>>,
+    stats: Arc<Mutex<BackupStats>>,
+    pending_backup: Arc<Mutex<PendingBackup>>,
+    received_pending_backup: Arc<Mutex<Option<PendingBackupMatch>>>,
+    chunker: Chunker,
+    compress: i32,
+    store_extensions: HashSet<String>,
+    chunk_dir_levels: u8,
+    follow_symlinks: bool,
+    verify_writes: bool,
+    store_acls: bool,
+    skip_policy: SkipPolicy,
+    warning_policy: WarningPolicy,
+    renames: Option<Arc<RenameDetector>>,
+    appends: Option<Arc<AppendDetector>>,
+    assumed: Option<Arc<AssumeUnchanged>>,
+    newer_than: Option<Arc<NewerThanCutoff>>,
+    low_memory: Option<Arc<LowMemory>>,
+    content_cache: Option<Arc<ContentCache>>,
+}
+
+#[instrument(level = "debug", skip_all, fields(file = %file_path))]
+async fn backup_file(file_path: String, context: Arc<BackupContext>) -> Result<(), String> {
+    let BackupContext {
+        ref key,
+        ref password,
+        ref fs,
+        ref root_path_string,
+        ref pb,
+        ref json_progress,
+        ref chunk_indexes,
+        ref new_backup,
+        ref written_bytes,
+        ref deduplicated_bytes,
+        ref stats,
+        ref pending_backup,
+        ref received_pending_backup,
+        chunker,
+        compress,
+        ref store_extensions,
+        chunk_dir_levels,
+        follow_symlinks,
+        verify_writes,
+        store_acls,
+        skip_policy,
+        warning_policy,
+        ref renames,
+        ref appends,
+        ref assumed,
+        ref newer_than,
+        ref low_memory,
+        ref content_cache,
+    } = *context;
+
+    if let Some(newer_than) = &newer_than
+        && newer_than.is_newer(&file_path)
+    {
+        trace!(file = %file_path, "modified after the cutoff, deferring file");
+        newer_than
+            .deferred_files
+            .lock()
+            .unwrap()
+            .push(relative_path(&file_path, root_path_string));
+
+        if let Some(progress) = &json_progress {
+            progress.inc_by(1);
+        } else {
+            pb.inc(1);
+        }
+        return Ok(());
+    }
+
+    if !follow_symlinks
+        && let Ok(link_metadata) = std::fs::symlink_metadata(&file_path)
+        && link_metadata.file_type().is_symlink()
+    {
+        let link_target = std::fs::read_link(&file_path)
+            .map_err(|e| format!("Failed to read symbolic link: {}", e))?
+            .to_string_lossy()
+            .to_string();
+        let relative_path = relative_path(&file_path, root_path_string);
+
+        let (uid, gid) = get_file_owner(&link_metadata);
+        let (mtime_ns, inode) = get_file_identity(&link_metadata);
+
+        // The hash covers the target, so a link pointing elsewhere is a changed entry.
+        let backup_object = BackupObject {
+            hash: format!("{:x}", Sha256::digest(link_target.as_bytes())),
+            size: 0,
+            content_type: "inode/symlink".to_string(),
+            permissions: get_file_permissions_with_path(&link_metadata, &file_path),
+            chunks: Vec::new(),
+            uid,
+            gid,
+            mtime_ns,
+            inode,
+            acl: None,
+            append: None,
+            link_target: Some(link_target),
+        };
+
+        trace!(file = %file_path, "stored as a symbolic link");
+
+        match &low_memory {
+            Some(low_memory) => low_memory.tree.append(&relative_path, &backup_object)?,
+            None => {
+                new_backup
+                    .lock()
+                    .unwrap()
+                    .tree
+                    .insert(relative_path, backup_object);
+            }
+        }
+
+        if let Some(progress) = &json_progress {
+            progress.inc_by(1);
+        } else {
+            pb.inc(1);
+        }
+        return Ok(());
+    }
+
+    if let Some(renames) = &renames
+        && let Ok(file_metadata) = std::fs::metadata(&file_path)
+    {
+        let relative_path = relative_path(&file_path, root_path_string);
+
+        if let Some(mut backup_object) = renames.reuse(
+            &relative_path,
+            &file_path,
+            &file_metadata,
+            chunk_indexes,
+            stats,
+        ) {
+            trace!(file = %file_path, "rename detected, reusing previous entry");
+
+            // Counted as read too, so the byte percentage still reaches the total.
+            if let Some(progress) = &json_progress {
+                progress.add_bytes(ProgressBytes::Read, backup_object.size);
+                progress.add_bytes(ProgressBytes::Deduplicated, backup_object.size);
+            }
+
+            backup_object.acl = get_file_acl(&file_path, store_acls, stats);
+
+            *deduplicated_bytes.lock().unwrap() += backup_object.size;
+
+            {
+                let top_level = match relative_path.split_once('/') {
+                    Some((dir, _)) => dir.to_string(),
+                    None => ".".to_string(),
+                };
+                let mut stats_guard = stats.lock().unwrap();
+                stats_guard.deduplicated_chunks += backup_object.chunks.len() as u64;
+                *stats_guard.top_level_bytes.entry(top_level).or_insert(0) += backup_object.size;
+            }
+
+            new_backup
+                .lock()
+                .unwrap()
+                .tree
+                .insert(relative_path, backup_object);
+
+            if let Some(progress) = &json_progress {
+                progress.inc_by(1);
+            } else {
+                pb.inc(1);
+            }
+            return Ok(());
+        }
+    }
+
+    if let Some(assumed) = &assumed
+        && let Ok(file_metadata) = std::fs::metadata(&file_path)
+    {
+        let relative_path = relative_path(&file_path, root_path_string);
+
+        if let Some(mut backup_object) =
+            assumed.reuse(&relative_path, &file_path, &file_metadata, chunk_indexes)
+        {
+            trace!(file = %file_path, "assumed unchanged, reusing previous entry");
+
+            if let Some(progress) = &json_progress {
+                progress.add_bytes(ProgressBytes::Read, backup_object.size);
+                progress.add_bytes(ProgressBytes::Deduplicated, backup_object.size);
+            }
+
+            backup_object.acl = get_file_acl(&file_path, store_acls, stats);
+
+            *deduplicated_bytes.lock().unwrap() += backup_object.size;
+
+            {
+                let top_level = match relative_path.split_once('/') {
+                    Some((dir, _)) => dir.to_string(),
+                    None => ".".to_string(),
+                };
+                let mut stats_guard = stats.lock().unwrap();
+                stats_guard.deduplicated_chunks += backup_object.chunks.len() as u64;
+                *stats_guard.top_level_bytes.entry(top_level).or_insert(0) += backup_object.size;
+                if let Some(assumed_unchanged_files) = &mut stats_guard.assumed_unchanged_files {
+                    *assumed_unchanged_files += 1;
+                }
+            }
+
+            new_backup
+                .lock()
+                .unwrap()
+                .tree
+                .insert(relative_path, backup_object);
+
+            if let Some(progress) = &json_progress {
+                progress.inc_by(1);
+            } else {
+                pb.inc(1);
+            }
+            return Ok(());
+        }
+    }
+
+    let mut file = match std::fs::File::open(file_path.clone()) {
+        Ok(file) => file,
+        Err(e) => {
+            let skip_reason = if skip_policy.permission_denied
+                && e.kind() == std::io::ErrorKind::PermissionDenied
+            {
+                Some(("permission denied", "file_permission_denied"))
+            } else if skip_policy.vanished && !Path::new(&file_path).exists() {
+                Some(("file vanished during the backup", "file_vanished"))
+            } else {
+                None
+            };
+
+            let Some((reason, code)) = skip_reason else {
+                return Err(format!("Failed to open file: {}", e));
+            };
+
+            if warning_policy.abort_on_warning {
+                return Err(format!(
+                    "Failed to open file: {} ({}, not skipped because of --abort-on-warning)",
+                    e, reason
+                ));
+            }
+
+            pb.suspend(|| emit_warning(&format!("Skipping {}: {}", file_path, reason), code));
+            stats.lock().unwrap().skipped_files += 1;
+
+            if let Some(progress) = &json_progress {
+                progress.inc_by(1);
+            } else {
+                pb.inc(1);
+            }
+
+            return Ok(());
+        }
+    };
+    // Only a backup that detects appends stores where the hash stood, so only it pays for
+    // the resumable hasher.
+    let mut file_hasher = FileHasher::new(appends.is_some() && chunker.fixed_size().is_some());
+    let mut file_chunks = Vec::new();
+
+    let file_metadata = file
+        .metadata()
+        .map_err(|e| format!("Failed to get file metadata: {}", e))?;
+
+    // Bytes at the start of the next read that the hash already covers.
+    let mut hash_skip = 0;
+    let mut appended = false;
+
+    // Content-defined boundaries do not fall at fixed offsets, so only fixed-size chunks
+    // can be resumed.
+    if let Some(chunk_size) = chunker.fixed_size()
+        && let Some(resumed) = appends.as_ref().and_then(|appends| {
+            appends.resume(
+                &relative_path(&file_path, root_path_string),
+                &file_path,
+                &file_metadata,
+                chunk_size,
+                chunk_indexes,
+            )
+        })
+    {
+        trace!(file = %file_path, offset = resumed.chunk_offset, "file was appended to, resuming");
+
+        let hash_offset = resumed.hasher.length();
+        file_chunks = resumed.chunks;
+        file_hasher = FileHasher::Resumable(resumed.hasher);
+
+        let start = hash_offset.min(resumed.chunk_offset);
+        file.seek(SeekFrom::Start(start))
+            .map_err(|e| format!("Failed to read file {}: {}", file_path, e))?;
+
+        if hash_offset < resumed.chunk_offset {
+            let mut gap = vec![0u8; (resumed.chunk_offset - hash_offset) as usize];
+            file.read_exact(&mut gap)
+                .map_err(|e| format!("Failed to read file {}: {}", file_path, e))?;
+            file_hasher.update(&gap);
+        } else {
+            hash_skip = hash_offset - resumed.chunk_offset;
+        }
+
+        *deduplicated_bytes.lock().unwrap() += resumed.chunk_offset;
+        {
+            let mut stats_guard = stats.lock().unwrap();
+            stats_guard.deduplicated_chunks += file_chunks.len() as u64;
+            if let Some(appended_files) = &mut stats_guard.appended_files {
+                *appended_files += 1;
+            }
+        }
+        if let Some(progress) = &json_progress {
+            progress.add_bytes(ProgressBytes::Read, resumed.chunk_offset);
+            progress.add_bytes(ProgressBytes::Deduplicated, resumed.chunk_offset);
+        }
+        appended = true;
+    }
+
+    let content_key = match &content_cache {
+        Some(_) if !appended && file_metadata.len() > 0 => Some(
+            ContentKey::read(&mut file, file_metadata.len())
+                .map_err(|e| format!("Failed to read file {}: {}", file_path, e))?,
+        ),
+        _ => None,
+    };
+
+    let cached_content = match (&content_cache, &content_key) {
+        (Some(content_cache), Some(content_key)) => {
+            let cached_content = content_cache.get(content_key);
+            reuse_cached_content(&mut file, &mut file_hasher, cached_content)
+                .map_err(|e| format!("Failed to read file {}: {}", file_path, e))?
+        }
+        _ => None,
+    };
+
+    let mut new_content_key = None;
+    if let Some(chunks) = cached_content {
+        trace!(file = %file_path, "same content as a recent file, reusing its chunks");
+
+        for chunk_hash in &chunks {
+            match &low_memory {
+                Some(low_memory) => {
+                    low_memory.chunk_index.increment(chunk_hash)?;
+                }
+                None => {
+                    chunk_indexes
+                        .lock()
+                        .unwrap()
+                        .entry(chunk_hash.clone())
+                        .or_insert(ChunkIndex { refcount: 0 })
+                        .refcount += 1;
+                }
+            }
+        }
+
+        *deduplicated_bytes.lock().unwrap() += file_metadata.len();
+        {
+            let mut stats_guard = stats.lock().unwrap();
+            stats_guard.deduplicated_chunks += chunks.len() as u64;
+            if let Some(hits) = &mut stats_guard.chunk_cache_hits {
+                *hits += 1;
+            }
+        }
+        if let Some(progress) = &json_progress {
+            progress.add_bytes(ProgressBytes::Read, file_metadata.len());
+            progress.add_bytes(ProgressBytes::Deduplicated, file_metadata.len());
+        }
+
+        file_chunks = chunks;
+    } else if let Some(content_key) = content_key {
+        if let Some(misses) = &mut stats.lock().unwrap().chunk_cache_misses {
+            *misses += 1;
+        }
+        new_content_key = Some(content_key);
+    }
+
+    // After a cache hit the file was read to its end to confirm its hash, so the loop
+    // below reads no chunks.
+    let mut chunk_reader = chunker.reader(&mut file);
+
+    // Already compressed formats are stored as is instead of spending CPU on zstd.
+    let compress = if is_store_extension(&file_path, store_extensions) {
+        0
+    } else {
+        compress
+    };
+
+    // Zero-byte files end here on the first read and are stored with no chunks and the
+    // hash of empty content, which restore recreates as an empty file. A read error must
+    // not be mistaken for that end of file, or the file would be stored truncated.
+    loop {
+        let chunk_bytes = match chunk_reader.next_chunk() {
+            Ok(Some(chunk_bytes)) => chunk_bytes,
+            Ok(None) => break,
+            Err(e) => return Err(format!("Failed to read file {}: {}", file_path, e)),
+        };
+        let bytes_read = chunk_bytes.len();
+
+        let skipped = hash_skip.min(bytes_read as u64);
+        hash_skip -= skipped;
+        file_hasher.update(&chunk_bytes[skipped as usize..]);
+
+        if let Some(progress) = &json_progress {
+            progress.add_bytes(ProgressBytes::Read, bytes_read as u64);
+        }
+
+        let chunk_hash = format!("{:x}", Sha256::digest(chunk_bytes));
+        file_chunks.push(chunk_hash.clone());
+
+        let is_in_chunk_indexes = match &low_memory {
+            Some(low_memory) => low_memory.chunk_index.increment(&chunk_hash)? > 1,
+            None => {
+                let mut chunk_indexes_guard = chunk_indexes.lock().unwrap();
+                let entry = chunk_indexes_guard
+                    .entry(chunk_hash.clone())
+                    .or_insert(ChunkIndex { refcount: 0 });
+                entry.refcount += 1;
+
+                entry.refcount > 1
+            }
+        };
+
+        if is_in_chunk_indexes {
+            let mut deduplicated_bytes_guard = deduplicated_bytes.lock().unwrap();
+            *deduplicated_bytes_guard += chunk_bytes.len() as u64;
+            stats.lock().unwrap().deduplicated_chunks += 1;
+            if let Some(progress) = &json_progress {
+                progress.add_bytes(ProgressBytes::Deduplicated, chunk_bytes.len() as u64);
+            }
+            trace!(chunk = %chunk_hash, bytes = chunk_bytes.len(), "dedup hit");
+            continue;
+        }
+
+        {
+            let received_pending_backup_guard = received_pending_backup.lock().unwrap();
+
+            let exists = match received_pending_backup_guard.as_ref() {
+                Some(pending_backup) => {
+                    pending_backup.backup.processed_chunks.contains(&chunk_hash)
+                }
+                None => false,
+            };
+
+            if exists {
+                let mut written_bytes_guard = written_bytes.lock().unwrap();
+                *written_bytes_guard += chunk_bytes.len() as u64;
+                stats.lock().unwrap().new_chunks += 1;
+                trace!(chunk = %chunk_hash, "chunk already uploaded by pending backup");
+                continue;
+            }
+        }
+
+        let compressed_chunk_bytes = encode_chunk(chunk_bytes, compress)
+            .map_err(|e| format!("Failed to compress chunk {}: {}", chunk_hash, e))?;
+
+        let chunk_path = chunk_path(key, &chunk_hash, chunk_dir_levels);
+
+        let mut last_error = String::new();
+        let mut success = false;
+
+        for attempt in 1..=3 {
+            match write_file_maybe_encrypt(
+                fs,
+                &chunk_path,
+                &compressed_chunk_bytes,
+                password.as_deref(),
+            )
+            .await
+            {
+                Ok(_) if verify_writes => {
+                    let read_back = read_file_maybe_decrypt(
+                        fs,
+                        &chunk_path,
+                        password.as_deref(),
+                        "Chunk is encrypted but no password provided",
+                    )
+                    .await;
+
+                    if read_back.is_ok_and(|read_back| read_back.bytes == compressed_chunk_bytes) {
+                        success = true;
+                        break;
+                    }
+
+                    warn!(chunk = %chunk_hash, attempt, "chunk did not read back as written");
+                    if let Some(mismatches) = &mut stats.lock().unwrap().write_verify_mismatches {
+                        *mismatches += 1;
+                    }
+                    last_error = format!(
+                        "Chunk {} did not read back as written (attempt {}/3)",
+                        chunk_hash, attempt
+                    );
+                    if attempt < 3 {
+                        tokio::time::sleep(Duration::from_millis(100 * attempt as u64)).await;
+                    }
+                }
+                Ok(_) => {
+                    success = true;
+                    break;
+                }
+                Err(e) => {
+                    warn!(chunk = %chunk_hash, attempt, error = %e, "failed to write chunk");
+                    last_error = format!("Failed to write chunk (attempt {}/3): {}", attempt, e);
+                    if attempt < 3 {
+                        tokio::time::sleep(Duration::from_millis(100 * attempt as u64)).await;
+                    }
+                }
+            }
+        }
+
+        if !success {
+            return Err(last_error);
+        }
+
+        {
+            let mut written_bytes_guard = written_bytes.lock().unwrap();
+            *written_bytes_guard += chunk_bytes.len() as u64;
+        }
+
+        {
+            let mut stats_guard = stats.lock().unwrap();
+            stats_guard.new_chunks += 1;
+            stats_guard.uploaded_bytes += chunk_bytes.len() as u64;
+            stats_guard.compressed_bytes += compressed_chunk_bytes.len() as u64;
+        }
+
+        if let Some(progress) = &json_progress {
+            progress.add_bytes(ProgressBytes::Written, compressed_chunk_bytes.len() as u64);
+        }
+
+        debug!(
+            chunk = %chunk_hash,
+            bytes = chunk_bytes.len(),
+            compressed_bytes = compressed_chunk_bytes.len(),
+            "chunk written"
+        );
+
+        {
+            let mut pending_backup_guard = pending_backup.lock().unwrap();
+            pending_backup_guard
+                .processed_chunks
+                .push(chunk_hash.clone());
+        }
+    }
+
+    let file_append = appends
+        .as_ref()
+        .and(chunker.fixed_size())
+        .and_then(|chunk_size| {
+            let hasher = file_hasher.resumable()?;
+            let (hash_state, hash_offset) = hasher.checkpoint();
+            let sample = content_sample(Path::new(&file_path), hasher.length()).ok()?;
+
+            Some(AppendState {
+                chunk_size,
+                hash_state,
+                hash_offset,
+                sample,
+            })
+        });
+
+    let file_hash = file_hasher.finalize_hex();
+
+    if let (Some(content_cache), Some(content_key)) = (&content_cache, new_content_key) {
+        content_cache.insert(
+            content_key,
+            CachedContent {
+                hash: file_hash.clone(),
+                chunks: file_chunks.clone(),
+            },
+        );
+    }
+
+    let relative_path = relative_path(&file_path, root_path_string);
+
+    let file_permissions = get_file_permissions_with_path(&file_metadata, &file_path);
+    let (file_uid, file_gid) = get_file_owner(&file_metadata);
+    let (file_mtime_ns, file_inode) = get_file_identity(&file_metadata);
+    let file_acl = get_file_acl(&file_path, store_acls, stats);
+
+    {
+        let top_level = match relative_path.split_once('/') {
+            Some((dir, _)) => dir.to_string(),
+            None => ".".to_string(),
+        };
+        let mut stats_guard = stats.lock().unwrap();
+        *stats_guard.top_level_bytes.entry(top_level).or_insert(0) += file_metadata.len();
+    }
+
+    let backup_object = BackupObject {
+        hash: file_hash.clone(),
+        size: file_metadata.len(),
+        content_type: "application/octet-stream".to_string(),
+        permissions: file_permissions,
+        chunks: file_chunks,
+        uid: file_uid,
+        gid: file_gid,
+        mtime_ns: file_mtime_ns,
+        inode: file_inode,
+        acl: file_acl,
+        append: file_append,
+        link_target: None,
+    };
+
+    match &low_memory {
+        Some(low_memory) => low_memory.tree.append(&relative_path, &backup_object)?,
+        None => {
+            new_backup
+                .lock()
+                .unwrap()
+                .tree
+                .insert(relative_path.to_string(), backup_object);
+        }
+    }
+
+    if let Some(progress) = &json_progress {
+        progress.inc_by(1);
+    } else {
+        pb.inc(1);
+    }
+    Ok(())
+}
+
+/// Hashes `file` in full when a recent file looked the same, and returns the chunks of
+/// that file if the hashes match. On a match `file` is left at its end and `file_hasher`
+/// holds the hash; otherwise both are back at the start.
+fn reuse_cached_content(
+    file: &mut std::fs::File,
+    file_hasher: &mut FileHasher,
+    cached_content: Option<CachedContent>,
+) -> std::io::Result<Option<Vec<String>>> {
+    let Some(cached_content) = cached_content else {
+        return Ok(None);
+    };
+
+    let mut buffer = vec![0u8; 256 * 1024];
+    loop {
+        match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(bytes_read) => file_hasher.update(&buffer[..bytes_read]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    if file_hasher.clone().finalize_hex() == cached_content.hash {
+        return Ok(Some(cached_content.chunks));
+    }
+
+    *file_hasher = file_hasher.restarted();
+    file.seek(SeekFrom::Start(0))?;
+    Ok(None)
+}
+
+/// The ACL to store for `file_path`. Without `--store-acls` nothing is stored, but files
+/// that have one are counted so the backup can warn about them.
+fn get_file_acl(
+    file_path: &str,
+    store_acls: bool,
+    stats: &Mutex<BackupStats>,
+) -> Option<Vec<AclEntry>> {
+    if !store_acls {
+        if has_acl(Path::new(file_path)) {
+            stats.lock().unwrap().unstored_acl_files += 1;
+        }
+        return None;
+    }
+
+    match read_acl(Path::new(file_path)) {
+        Ok(acl) => acl,
+        Err(e) => {
+            debug!(file = %file_path, error = %e, "failed to read ACL");
+            None
+        }
+    }
+}
+
+/// Version-control metadata directories left out with `--exclude-vcs`, as tar does.
+const VCS_DIRECTORIES: [&str; 6] = [".git", ".hg", ".svn", ".bzr", "_darcs", "CVS"];
+
+/// Read from the root of the backup, with the same syntax as a `.gitignore` file.
+const IGNORE_FILE_NAME: &str = ".gibignore";
+
+#[derive(Clone)]
+pub(crate) struct IgnoreRules {
+    /// The lines of the `.gibignore` file followed by the `--ignore` patterns, matched
+    /// like a `.gitignore` against paths relative to the root, so the last match wins.
+    patterns: Gitignore,
+    /// `--exclude-vcs`, kept apart from `patterns` so a dry run can report what it skipped.
+    vcs_patterns: Option<GlobSet>,
+    /// The `--exclude-path` values as given, walked by the dry-run report.
+    exclude_paths: Vec<String>,
+    /// `exclude_paths` as matched: lowercased with `--ignore-case`.
+    matched_exclude_paths: Vec<String>,
+    ignore_case: bool,
+    mounts: Option<Arc<MountRules>>,
+}
+
+impl IgnoreRules {
+    fn new(
+        root_path: &str,
+        ignore_patterns: &[String],
+        exclude_paths: &[String],
+        ignore_case: bool,
+        exclude_vcs: bool,
+    ) -> Result<Self, String> {
+        let mut builder = GitignoreBuilder::new(root_path);
+        builder
+            .case_insensitive(ignore_case)
+            .map_err(|e| format!("Failed to build ignore patterns: {}", e))?;
+
+        let ignore_file = Path::new(root_path).join(IGNORE_FILE_NAME);
+        if ignore_file.is_file()
+            && let Some(e) = builder.add(&ignore_file)
+        {
+            return Err(format!(
+                "Invalid pattern in {}: {}",
+                ignore_file.display(),
+                e
+            ));
+        }
+
+        for pattern in ignore_patterns {
+            builder
+                .add_line(None, pattern)
+                .map_err(|e| format!("Invalid ignore pattern '{}': {}", pattern, e))?;
+        }
+
+        let patterns = builder
+            .build()
+            .map_err(|e| format!("Failed to build ignore patterns: {}", e))?;
+
+        let vcs_patterns = if exclude_vcs {
+            let mut builder = GlobSetBuilder::new();
+            for name in VCS_DIRECTORIES {
+                let glob = GlobBuilder::new(name)
+                    .case_insensitive(ignore_case)
+                    .build()
+                    .map_err(|e| format!("Invalid ignore pattern '{}': {}", name, e))?;
+                builder.add(glob);
+            }
+            Some(
+                builder
+                    .build()
+                    .map_err(|e| format!("Failed to build ignore patterns: {}", e))?,
+            )
+        } else {
+            None
+        };
+
+        let matched_exclude_paths = if ignore_case {
+            exclude_paths
+                .iter()
+                .map(|path| path.to_lowercase())
+                .collect()
+        } else {
+            exclude_paths.to_vec()
+        };
+
+        Ok(Self {
+            patterns,
+            vcs_patterns,
+            exclude_paths: exclude_paths.to_vec(),
+            matched_exclude_paths,
+            ignore_case,
+            mounts: None,
+        })
+    }
+
+    fn with_mounts(mut self, mounts: Option<Arc<MountRules>>) -> Self {
+        self.mounts = mounts;
+        self
+    }
+
+    pub(crate) fn is_ignored(&self, entry: &walkdir::DirEntry) -> bool {
+        self.is_vcs(entry) || self.is_excluded(entry)
+    }
+
+    /// The rule that leaves `entry` out, checked in the same order as `is_ignored`.
+    fn ignored_by(&self, entry: &walkdir::DirEntry) -> Option<IgnoredBy> {
+        if self.is_vcs(entry) {
+            return Some(IgnoredBy {
+                rule: "exclude_vcs",
+                pattern: Some(entry.file_name().to_string_lossy().to_string()),
+            });
+        }
+
+        self.excluded_by(entry)
+    }
+
+    fn is_vcs(&self, entry: &walkdir::DirEntry) -> bool {
+        self.vcs_patterns
+            .as_ref()
+            .is_some_and(|patterns| patterns.is_match(entry.file_name()))
+    }
+
+    /// Every rule except `--exclude-vcs`.
+    fn is_excluded(&self, entry: &walkdir::DirEntry) -> bool {
+        self.excluded_by(entry).is_some()
+    }
+
+    fn excluded_by(&self, entry: &walkdir::DirEntry) -> Option<IgnoredBy> {
+        if !self.matched_exclude_paths.is_empty() {
+            let entry_path = entry.path().to_string_lossy();
+            let entry_path = if self.ignore_case {
+                entry_path.to_lowercase()
+            } else {
+                entry_path.to_string()
+            };
+
+            if let Some(index) = self
+                .matched_exclude_paths
+                .iter()
+                .position(|exclude_path| *exclude_path == entry_path)
+            {
+                return Some(IgnoredBy {
+                    rule: "exclude_path",
+                    pattern: Some(self.exclude_paths[index].clone()),
+                });
+            }
+        }
+
+        // The root itself has no relative path to match.
+        if entry.depth() > 0
+            && let Match::Ignore(glob) = self
+                .patterns
+                .matched(entry.path(), entry.file_type().is_dir())
+        {
+            let rule = if glob.from().is_some() {
+                "gibignore"
+            } else {
+                "ignore"
+            };
+            return Some(IgnoredBy {
+                rule,
+                pattern: Some(glob.original().to_string()),
+            });
+        }
+
+        self.mounts
+            .as_ref()
+            .is_some_and(|mounts| !mounts.allows(entry))
+            .then_some(IgnoredBy {
+                rule: "one_file_system",
+                pattern: None,
+            })
+    }
+
+    fn exclude_paths(&self) -> &[String] {
+        &self.exclude_paths
+    }
+
+    fn mount_report(&self) -> Option<MountReport> {
+        self.mounts.as_ref().map(|mounts| mounts.report())
+    }
+}
+
+/// The rules of a walk of `root_path` from the `--ignore`, `--exclude-path`,
+/// `--ignore-case` and `--exclude-vcs` flags `diff` shares with `backup`, so comparing a
+/// backup with its directory leaves out what the backup did.
+pub(crate) fn ignore_rules_from_matches(
+    matches: &ArgMatches,
+    root_path: &str,
+) -> Result<IgnoreRules, String> {
+    let ignore_patterns: Vec<String> = matches
+        .get_many::<String>("ignore")
+        .map(|values| values.map(|s| s.to_string()).collect())
+        .unwrap_or_default();
+
+    let exclude_paths: Vec<String> = match matches.get_many::<String>("exclude-path") {
+        Some(values) => resolve_exclude_paths(
+            root_path,
+            &values.map(|s| s.to_string()).collect::<Vec<String>>(),
+            WarningPolicy {
+                abort_on_warning: false,
+            },
+        )?,
+        None => Vec::new(),
+    };
+
+    IgnoreRules::new(
+        root_path,
+        &ignore_patterns,
+        &exclude_paths,
+        matches.get_flag("ignore-case"),
+        matches.get_flag("exclude-vcs"),
+    )
+}
+
+/// Why a path is left out of the backup, for `--dry-run --list-ignored`: `rule` is
+/// `ignore`, `gibignore`, `exclude_path`, `exclude_vcs` or `one_file_system`, and
+/// `pattern` the `--ignore` or `.gibignore` pattern, excluded path or VCS directory name
+/// that matched.
+#[derive(serde::Serialize)]
+struct IgnoredBy {
+    rule: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pattern: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct IgnoredPathOutput {
+    #[serde(flatten)]
+    ignored_by: IgnoredBy,
+    directory: bool,
+}
+
+/// Every path the walk leaves out, relative to `root_path`. The content of an ignored
+/// directory is never visited, so only the directory itself is listed.
+fn list_ignored(
+    root_path: &str,
+    ignore_rules: &IgnoreRules,
+) -> BTreeMap<String, IgnoredPathOutput> {
+    let mut ignored = BTreeMap::new();
+    let mut walker = walkdir::WalkDir::new(root_path).into_iter();
+
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+
+        if entry.depth() == 0 {
+            continue;
+        }
+
+        let Some(ignored_by) = ignore_rules.ignored_by(&entry) else {
+            continue;
+        };
+
+        let directory = entry.file_type().is_dir();
+        if directory {
+            walker.skip_current_dir();
+        }
+
+        ignored.insert(
+            relative_path(&entry.path().to_string_lossy(), root_path),
+            IgnoredPathOutput {
+                ignored_by,
+                directory,
+            },
+        );
+    }
+
+    ignored
+}
+
+/// `--one-file-system`: the walk stays on the filesystem of the root path, except for the
+/// mount points allowed with `--include-mount`. A directory is a mount point when its
+/// device differs from its parent's; the ones met during the walk are recorded so the
+/// backup can report what it crossed and what it skipped.
+struct MountRules {
+    allowed: HashSet<PathBuf>,
+    crossed: Mutex<BTreeSet<String>>,
+    skipped: Mutex<BTreeSet<String>>,
+}
+
+#[derive(serde::Serialize)]
+struct MountReport {
+    crossed: Vec<String>,
+    skipped: Vec<String>,
+}
+
+impl MountRules {
+    /// Returns `None` without `--one-file-system`. `--include-mount` paths are resolved
+    /// like `--exclude-path` (absolute or relative to the root path).
+    fn from_matches(
+        matches: &ArgMatches,
+        root_path_string: &str,
+        warning_policy: WarningPolicy,
+    ) -> Result<Option<Arc<Self>>, String> {
+        if !matches.get_flag("one-file-system") {
+            return Ok(None);
+        }
+
+        if cfg!(not(unix)) {
+            return Err("--one-file-system is only supported on Unix".to_string());
+        }
+
+        let mut allowed = HashSet::new();
+
+        for include_mount in matches
+            .get_many::<String>("include-mount")
+            .unwrap_or_default()
+        {
+            let path = Path::new(root_path_string).join(include_mount);
+
+            let canonical_path = match std::fs::canonicalize(&path) {
+                Ok(canonical_path) => canonical_path,
+                Err(_) => {
+                    let warning = format!(
+                        "Included mount '{}' does not exist, ignoring it",
+                        include_mount
+                    );
+                    warning_policy.escalate(&warning, "include_mount_not_found", None);
+                    emit_warning(&warning, "include_mount_not_found");
+                    continue;
+                }
+            };
+
+            if !is_mount_point(&canonical_path) {
+                let warning = format!(
+                    "Included mount '{}' is not a mount point, so it is already on the root's filesystem or under another mount",
+                    include_mount
+                );
+                warning_policy.escalate(&warning, "include_mount_not_a_mount", None);
+                emit_warning(&warning, "include_mount_not_a_mount");
+            }
+
+            allowed.insert(canonical_path);
+        }
+
+        Ok(Some(Arc::new(MountRules {
+            allowed,
+            crossed: Mutex::new(BTreeSet::new()),
+            skipped: Mutex::new(BTreeSet::new()),
+        })))
+    }
+
+    fn allows(&self, entry: &walkdir::DirEntry) -> bool {
+        if entry.depth() == 0 || !entry.file_type().is_dir() || !is_mount_point(entry.path()) {
+            return true;
+        }
+
+        let path = entry.path().to_string_lossy().to_string();
+        let allowed = std::fs::canonicalize(entry.path())
+            .is_ok_and(|canonical_path| self.allowed.contains(&canonical_path));
+
+        debug!(mount = %path, allowed, "reached a mount point");
+
+        if allowed {
+            self.crossed.lock().unwrap().insert(path);
+        } else {
+            self.skipped.lock().unwrap().insert(path);
+        }
+
+        allowed
+    }
+
+    fn report(&self) -> MountReport {
+        MountReport {
+            crossed: self.crossed.lock().unwrap().iter().cloned().collect(),
+            skipped: self.skipped.lock().unwrap().iter().cloned().collect(),
+        }
+    }
+}
+
+impl MountReport {
+    fn print(&self) {
+        if !self.crossed.is_empty() {
+            println!("Crossed mounts: {}", self.crossed.join(", "));
+        }
+        if !self.skipped.is_empty() {
+            println!(
+                "{}",
+                style(format!("Skipped mounts: {}", self.skipped.join(", "))).dim()
+            );
+        }
+    }
+}
+
+fn is_mount_point(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let Some(parent) = path.parent() else {
+            return false;
+        };
+
+        match (std::fs::metadata(path), std::fs::metadata(parent)) {
+            (Ok(metadata), Ok(parent_metadata)) => metadata.dev() != parent_metadata.dev(),
+            _ => false,
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        false
+    }
+}
+
+/// With `--follow-root-symlink`, a root path that is itself a symlink is replaced by its
+/// target: files are walked under the resolved directory and the default key comes from
+/// the target's name, so a link and the directory it points to share a repository.
+fn resolve_root_symlink(root_path_string: String) -> Result<String, String> {
+    let is_symlink = std::fs::symlink_metadata(&root_path_string)
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false);
+
+    if !is_symlink {
+        return Ok(root_path_string);
+    }
+
+    let target = std::fs::canonicalize(&root_path_string).map_err(|e| {
+        format!(
+            "Failed to resolve root path symlink '{}': {}",
+            root_path_string, e
+        )
+    })?;
+
+    debug!(link = %root_path_string, target = %target.display(), "following root symlink");
+
+    Ok(target.to_string_lossy().to_string())
+}
+
+/// Symbolic links are listed as entries of their own; with `follow_symlinks` a link to a
+/// file is listed in its place and read through, and other links are left out. A root that
+/// is itself a link is walked through, never listed.
+fn list_files(path: &str, ignore_rules: &IgnoreRules, follow_symlinks: bool) -> Vec<String> {
+    let mut files = Vec::new();
+
+    let walker = walkdir::WalkDir::new(path)
+        .into_iter()
+        .filter_entry(|entry| !ignore_rules.is_ignored(entry));
+
+    for entry in walker.filter_map(|e| e.ok()).filter(|e| {
+        if follow_symlinks {
+            e.path().is_file()
+        } else {
+            e.file_type().is_file() || (e.depth() > 0 && e.path_is_symlink())
+        }
+    }) {
+        files.push(entry.path().display().to_string());
+    }
+
+    files
+}
+
+fn dry_run(
+    root_path: &str,
+    files: &[String],
+    ignore_rules: &IgnoreRules,
+    prediction: Option<DedupPrediction>,
+    list_ignored_paths: bool,
+    rotation: Option<RotationOutput>,
+) {
+    let bytes_total: u64 = files
+        .iter()
+        .filter_map(|file| std::fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+
+    #[derive(serde::Serialize)]
+    struct ExcludedPathOutput {
+        path: String,
+        files: u64,
+        bytes: u64,
+    }
+
+    let excluded = ignore_rules
+        .exclude_paths()
+        .iter()
+        .map(|exclude_path| {
+            let (files, bytes) = walkdir::WalkDir::new(exclude_path)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+                .fold((0u64, 0u64), |(files, bytes), entry| {
+                    let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                    (files + 1, bytes + size)
+                });
+
+            ExcludedPathOutput {
+                path: exclude_path.clone(),
+                files,
+                bytes,
+            }
+        })
+        .collect::<Vec<ExcludedPathOutput>>();
+
+    #[derive(serde::Serialize)]
+    struct BackupDryRunOutput {
+        dry_run: bool,
+        files_total: usize,
+        bytes_total: u64,
+        excluded: Vec<ExcludedPathOutput>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        mounts: Option<MountReport>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        vcs: Option<VcsExclusionOutput>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        detailed: Option<DedupPrediction>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        ignored: Option<BTreeMap<String, IgnoredPathOutput>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rotation: Option<RotationOutput>,
+    }
+
+    let payload = BackupDryRunOutput {
+        dry_run: true,
+        files_total: files.len(),
+        bytes_total,
+        excluded,
+        mounts: ignore_rules.mount_report(),
+        vcs: ignore_rules
+            .vcs_patterns
+            .is_some()
+            .then(|| count_vcs_exclusion(root_path, ignore_rules)),
+        detailed: prediction,
+        ignored: list_ignored_paths.then(|| list_ignored(root_path, ignore_rules)),
+        rotation,
+    };
+
+    if let Err(e) = write_report(&payload) {
+        handle_error(e, None);
+    }
+
+    if is_json_mode() {
+        emit_output(&payload);
+    } else {
+        println!(
+            "{} Would back up {} files ({})",
+            style("Dry run:").bold(),
+            files.len(),
+            ByteSize(bytes_total)
+        );
+
+        for entry in &payload.excluded {
+            println!(
+                "  Excluded {} ({} files, {})",
+                entry.path,
+                entry.files,
+                ByteSize(entry.bytes)
+            );
+        }
+
+        if let Some(vcs) = &payload.vcs {
+            println!(
+                "  Excluded {} version-control directories ({} files, {})",
+                vcs.directories,
+                vcs.files,
+                ByteSize(vcs.bytes)
+            );
+        }
+
+        if let Some(mounts) = &payload.mounts {
+            mounts.print();
+        }
+
+        if let Some(ignored) = &payload.ignored {
+            println!("  Ignored {} paths:", ignored.len());
+            for (path, entry) in ignored {
+                let path = if entry.directory {
+                    format!("{}/", path)
+                } else {
+                    path.clone()
+                };
+                match &entry.ignored_by.pattern {
+                    Some(pattern) => {
+                        println!("    {} ({} {})", path, entry.ignored_by.rule, pattern)
+                    }
+                    None => println!("    {} ({})", path, entry.ignored_by.rule),
+                }
+            }
+        }
+
+        if let Some(prediction) = &payload.detailed {
+            prediction.print();
+        }
+
+        if let Some(rotation) = &payload.rotation {
+            println!(
+                "  Would rotate out {} old backups to keep at most {}",
+                rotation.deleted_backups.len(),
+                rotation.max_backups
+            );
+            for backup in &rotation.deleted_backups {
+                println!("    {}", &backup[..8.min(backup.len())]);
+            }
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct VcsExclusionOutput {
+    directories: u64,
+    files: u64,
+    bytes: u64,
+}
+
+/// Counts what `--exclude-vcs` leaves out: the VCS directories the backup would otherwise
+/// reach, and the files inside them.
+fn count_vcs_exclusion(root_path: &str, ignore_rules: &IgnoreRules) -> VcsExclusionOutput {
+    let mut output = VcsExclusionOutput {
+        directories: 0,
+        files: 0,
+        bytes: 0,
+    };
+
+    let mut walker = walkdir::WalkDir::new(root_path)
+        .into_iter()
+        .filter_entry(|entry| !ignore_rules.is_excluded(entry));
+
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else {
+            continue;
+        };
+
+        if entry.depth() == 0 || !ignore_rules.is_vcs(&entry) {
+            continue;
+        }
+
+        if entry.file_type().is_dir() {
+            output.directories += 1;
+            walker.skip_current_dir();
+        }
+
+        // A `.git` file (submodules, worktrees) is left out as well.
+        for file in walkdir::WalkDir::new(entry.path())
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            output.files += 1;
+            output.bytes += file.metadata().map(|m| m.len()).unwrap_or(0);
+        }
+    }
+
+    output
+}
+
+/// What `--dry-run --detailed` predicts a backup would upload. Sizes are before
+/// compression, so the bytes actually written to the storage are usually lower.
+#[derive(serde::Serialize)]
+struct DedupPrediction {
+    predicted_new_bytes: u64,
+    predicted_deduplicated_bytes: u64,
+    new_chunks: u64,
+    deduplicated_chunks: u64,
+    /// Every file that could be read, most new data first.
+    files: Vec<FilePrediction>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unreadable_files: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct FilePrediction {
+    path: String,
+    bytes: u64,
+    new_bytes: u64,
+}
+
+/// How many files `DedupPrediction::print` lists.
+const PREDICTION_TOP_FILES: usize = 10;
+
+impl DedupPrediction {
+    fn print(&self) {
+        let total = self.predicted_new_bytes + self.predicted_deduplicated_bytes;
+        let deduplicated_percent = if total == 0 {
+            0.0
+        } else {
+            self.predicted_deduplicated_bytes as f64 / total as f64 * 100.0
+        };
+
+        println!(
+            "  Would upload ~{} of new data, {} is already in the repository ({:.1}% deduplicated, before compression)",
+            ByteSize(self.predicted_new_bytes),
+            ByteSize(self.predicted_deduplicated_bytes),
+            deduplicated_percent
+        );
+
+        let top_files = self
+            .files
+            .iter()
+            .filter(|file| file.new_bytes > 0)
+            .take(PREDICTION_TOP_FILES)
+            .collect::<Vec<&FilePrediction>>();
+
+        if !top_files.is_empty() {
+            println!("  Most new data:");
+            for file in top_files {
+                println!(
+                    "    {} {}",
+                    style(format!("{:>10}", ByteSize(file.new_bytes).to_string())).cyan(),
+                    file.path
+                );
+            }
+        }
+
+        if !self.unreadable_files.is_empty() {
+            println!(
+                "{}",
+                style(format!(
+                    "  {} files could not be read and are not part of the estimate",
+                    self.unreadable_files.len()
+                ))
+                .yellow()
+            );
+        }
+    }
+}
+
+/// Chunks every file the way a backup would, hashing only, and looks each chunk up in
+/// the repository's chunk index. A chunk repeated within the run counts as new once.
+async fn predict_dedup(
+    fs: Arc<dyn FS>,
+    key: &str,
+    password: Option<String>,
+    root_path_string: &str,
+    files: &[String],
+    chunker: Chunker,
+    concurrency: usize,
+) -> Result<DedupPrediction, String> {
+    let chunk_indexes =
+        load_chunk_indexes(fs, key.to_string(), password, Arc::new(Mutex::new(false))).await?;
+
+    let json_progress = if is_json_mode() {
+        let progress = JsonProgress::new(files.len() as u64);
+        progress.set_message("Hashing files to predict deduplication...");
+        Some(progress)
+    } else {
+        None
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(files.len() as u64);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap(),
+        );
+        pb.set_message("Hashing files to predict deduplication...");
+        pb
+    };
+
+    // `buffered` keeps the file order, so which copy of a repeated chunk counts as new
+    // does not depend on scheduling.
+    let mut hashed_files = stream::iter(files.iter().cloned())
+        .map(|file_path| {
+            tokio::task::spawn_blocking(move || {
+                let chunks = hash_file_chunks(&file_path, chunker);
+                (file_path, chunks)
+            })
+        })
+        .buffered(concurrency.max(1));
+
+    let mut seen_chunks = HashSet::new();
+    let mut prediction = DedupPrediction {
+        predicted_new_bytes: 0,
+        predicted_deduplicated_bytes: 0,
+        new_chunks: 0,
+        deduplicated_chunks: 0,
+        files: Vec::new(),
+        unreadable_files: Vec::new(),
+    };
+
+    while let Some(hashed_file) = hashed_files.next().await {
+        let (file_path, chunks) =
+            hashed_file.map_err(|e| format!("Failed to hash files: {}", e))?;
+        let path = relative_path(&file_path, root_path_string);
+
+        match chunks {
+            Ok(chunks) => {
+                let mut file = FilePrediction {
+                    path,
+                    bytes: 0,
+                    new_bytes: 0,
+                };
+
+                for (chunk_hash, chunk_bytes) in chunks {
+                    file.bytes += chunk_bytes;
+
+                    if chunk_indexes.contains_key(&chunk_hash) || !seen_chunks.insert(chunk_hash) {
+                        prediction.predicted_deduplicated_bytes += chunk_bytes;
+                        prediction.deduplicated_chunks += 1;
+                    } else {
+                        file.new_bytes += chunk_bytes;
+                        prediction.predicted_new_bytes += chunk_bytes;
+                        prediction.new_chunks += 1;
+                    }
+                }
+
+                prediction.files.push(file);
+            }
+            Err(e) => {
+                debug!(file = %file_path, error = %e, "file skipped from the prediction");
+                prediction.unreadable_files.push(path);
+            }
+        }
+
+        if let Some(progress) = &json_progress {
+            progress.inc_by(1);
+        } else {
+            pb.inc(1);
+        }
+    }
+
+    pb.finish_and_clear();
+
+    prediction.files.sort_by(|a, b| {
+        b.new_bytes
+            .cmp(&a.new_bytes)
+            .then_with(|| a.path.cmp(&b.path))
+    });
+
+    Ok(prediction)
+}
+
+/// Splits a file into chunks like `backup_file` and returns the hash and length of each
+/// one.
+fn hash_file_chunks(file_path: &str, chunker: Chunker) -> Result<Vec<(String, u64)>, String> {
+    let file = std::fs::File::open(file_path)
+        .map_err(|e| format!("Failed to open file {}: {}", file_path, e))?;
+    let mut chunk_reader = chunker.reader(file);
+    let mut chunks = Vec::new();
+
+    while let Some(chunk_bytes) = chunk_reader
+        .next_chunk()
+        .map_err(|e| format!("Failed to read file {}: {}", file_path, e))?
+    {
+        let chunk_hash = format!("{:x}", Sha256::digest(chunk_bytes));
+        chunks.push((chunk_hash, chunk_bytes.len() as u64));
+    }
+
+    Ok(chunks)
+}
+
+/// Resolves `--exclude-path` values (absolute or relative to the backup root) to the
+/// paths `list_files` will see while walking `root_path_string`, so excluded subtrees
+/// can be pruned with a plain path comparison.
+fn resolve_exclude_paths(
+    root_path_string: &str,
+    exclude_paths: &[String],
+    warning_policy: WarningPolicy,
+) -> Result<Vec<String>, String> {
+    let canonical_root = std::fs::canonicalize(root_path_string)
+        .map_err(|e| format!("Failed to resolve root path '{}': {}", root_path_string, e))?;
+
+    let mut resolved = Vec::new();
+
+    for exclude_path in exclude_paths {
+        let path = Path::new(root_path_string).join(exclude_path);
+
+        let canonical_path = match std::fs::canonicalize(&path) {
+            Ok(canonical_path) => canonical_path,
+            Err(_) => {
+                let warning = format!(
+                    "Exclude path '{}' does not exist, ignoring it",
+                    exclude_path
+                );
+                warning_policy.escalate(&warning, "exclude_path_not_found", None);
+                emit_warning(&warning, "exclude_path_not_found");
+                continue;
+            }
+        };
+
+        let relative = match canonical_path.strip_prefix(&canonical_root) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative,
+            _ => {
+                let warning = format!(
+                    "Exclude path '{}' is not inside the backup root, ignoring it",
+                    exclude_path
+                );
+                warning_policy.escalate(&warning, "exclude_path_outside_root", None);
+                emit_warning(&warning, "exclude_path_outside_root");
+                continue;
+            }
+        };
+
+        let walk_path = Path::new(root_path_string)
+            .join(relative)
+            .to_string_lossy()
+            .to_string();
+
+        if !resolved.contains(&walk_path) {
+            resolved.push(walk_path);
+        }
+    }
+
+    Ok(resolved)
+}
+
+async fn load_metadata(
+    fs: Arc<dyn FS>,
+    key: String,
+    message: String,
+    config: Config,
+    root_path_string: String,
+    password: Option<String>,
+    prev_not_encrypted_but_now_yes: Arc<Mutex<bool>>,
+    ignore_rules: IgnoreRules,
+    timestamp: u64,
+    low_memory: bool,
+    follow_symlinks: bool,
+) -> Result<
+    (
+        Backup,
+        Vec<String>,
+        HashMap<String, ChunkIndex>,
+        Option<Arc<LowMemory>>,
+    ),
+    String,
+> {
+    let new_backup = create_new_backup(message, config.author, timestamp);
+
+    let root_files_future =
+        tokio::spawn(async move { list_files(&root_path_string, &ignore_rules, follow_symlinks) });
+
+    if low_memory {
+        let chunk_index_future = tokio::spawn(DiskChunkIndex::load(
+            Arc::clone(&fs),
+            key.clone(),
+            password,
+            prev_not_encrypted_but_now_yes,
+        ));
+
+        let (root_files_result, chunk_index_result) =
+            tokio::join!(root_files_future, chunk_index_future);
+
+        let root_files =
+            root_files_result.map_err(|e| format!("Failed to list root files: {}", e))?;
+
+        let chunk_index = chunk_index_result
+            .map_err(|e| format!("Failed to load chunk indexes: {}", e))?
+            .map_err(|e| format!("Failed to load chunk indexes: {}", e))?;
+
+        let low_memory = LowMemory {
+            chunk_index,
+            tree: TreeSpool::create()?,
+        };
+
+        return Ok((
+            new_backup,
+            root_files,
+            HashMap::new(),
+            Some(Arc::new(low_memory)),
+        ));
+    }
+
+    let chunk_indexes_future = tokio::spawn(load_chunk_indexes(
+        Arc::clone(&fs),
+        key.clone(),
+        password,
+        prev_not_encrypted_but_now_yes,
+    ));
+
+    let (root_files_result, chunk_indexes_result) =
+        tokio::join!(root_files_future, chunk_indexes_future);
+
+    let root_files = root_files_result.map_err(|e| format!("Failed to list root files: {}", e))?;
+
+    let chunk_indexes = chunk_indexes_result
+        .map_err(|e| format!("Failed to load chunk indexes: {}", e))?
+        .map_err(|e| format!("Failed to load chunk indexes: {}", e))?;
+
+    Ok((new_backup, root_files, chunk_indexes, None))
+}
+
+/// `--low-memory`: the chunk index and the tree of the new backup live in temporary
+/// files instead of `chunk_indexes` and `Backup::tree`, which stay empty.
+struct LowMemory {
+    chunk_index: DiskChunkIndex,
+    tree: TreeSpool,
+}
+
+/// Picks the chunk folder depth. Repositories that already hold chunks (or saved their
+/// settings) keep their layout. A new repository uses one level (256 folders) unless
+/// `max_per_dir` is given and the chunks expected from this backup would put more than
+/// that many in each folder, in which case it uses two levels (65,536 folders).
+async fn resolve_chunk_dir_levels(
+    fs: Arc<dyn FS>,
+    key: String,
+    max_per_dir: Option<u64>,
+    is_new_repository: bool,
+    root_files: &[String],
+    chunker: Chunker,
+    warning_policy: WarningPolicy,
+) -> Result<u8, String> {
+    let repository_meta = load_repository_meta(Arc::clone(&fs), key.clone()).await?;
+
+    let Some(max_per_dir) = max_per_dir else {
+        return Ok(repository_meta.unwrap_or_default().chunk_dir_levels);
+    };
+
+    let expected_chunks = root_files
+        .iter()
+        .filter_map(|file_path| std::fs::metadata(file_path).ok())
+        .map(|metadata| metadata.len().div_ceil(chunker.target_size()).max(1))
+        .sum::<u64>();
+
+    let wanted_levels = if expected_chunks / 256 > max_per_dir {
+        MAX_CHUNK_DIR_LEVELS
+    } else {
+        1
+    };
+
+    if !is_new_repository || repository_meta.is_some() {
+        let chunk_dir_levels = repository_meta.unwrap_or_default().chunk_dir_levels;
+
+        if chunk_dir_levels != wanted_levels {
+            let warning = format!(
+                "The repository already stores chunks {} folder level(s) deep; --max-chunk-objects-per-dir only applies to new repositories",
+                chunk_dir_levels
+            );
+            warning_policy.escalate(&warning, "chunk_layout_locked", None);
+            emit_warning(&warning, "chunk_layout_locked");
+        }
+
+        return Ok(chunk_dir_levels);
+    }
+
+    let repository_meta = RepositoryMeta {
+        chunk_dir_levels: wanted_levels,
+        last_verified_backup: None,
+    };
+    save_repository_meta(fs, key, &repository_meta).await?;
+
+    debug!(
+        expected_chunks,
+        chunk_dir_levels = wanted_levels,
+        "chose the chunk folder depth"
+    );
+
+    Ok(wanted_levels)
+}
+
+/// Caps the new (non-deduplicated) bytes written by a backup. Once `limit` is reached no
+/// more files are started; files already in progress still finish, so the budget can be
+/// slightly exceeded.
+struct Budget {
+    limit: u64,
+    priority_order: PriorityOrder,
+    skipped_files: Mutex<Vec<String>>,
+}
+
+#[derive(Clone, Copy)]
+enum PriorityOrder {
+    Path,
+    Smallest,
+    Largest,
+}
+
+impl PriorityOrder {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PriorityOrder::Path => "path-first",
+            PriorityOrder::Smallest => "smallest-first",
+            PriorityOrder::Largest => "largest-first",
+        }
+    }
+
+    fn sort_files(&self, files: &mut [String]) {
+        let file_size = |file: &String| std::fs::metadata(file).map_or(0, |m| m.len());
+
+        match self {
+            PriorityOrder::Path => files.sort(),
+            PriorityOrder::Smallest => files.sort_by_cached_key(file_size),
+            PriorityOrder::Largest => files.sort_by_cached_key(|f| Reverse(file_size(f))),
+        }
+    }
+}
+
+fn get_budget(matches: &ArgMatches) -> Result<Option<Budget>, String> {
+    let Some(budget) = matches.get_one::<String>("budget") else {
+        return Ok(None);
+    };
+
+    let limit = parse_size(budget)
+        .map_err(|e| format!("Invalid value for --budget: '{}' ({})", budget, e))?;
+
+    let priority_order = match matches
+        .get_one::<String>("priority-order")
+        .map(|order| order.as_str())
+    {
+        None | Some("path-first") => PriorityOrder::Path,
+        Some("smallest-first") => PriorityOrder::Smallest,
+        Some("largest-first") => PriorityOrder::Largest,
+        Some(order) => return Err(format!("Unknown priority order '{}'", order)),
+    };
+
+    Ok(Some(Budget {
+        limit,
+        priority_order,
+        skipped_files: Mutex::new(Vec::new()),
+    }))
+}
+
+/// Files last modified after `cutoff` are left out of the backup, so it holds a set of
+/// files as they were at one point in time even while they keep changing. The check runs
+/// right before each file is read.
+struct NewerThanCutoff {
+    cutoff: std::time::SystemTime,
+    deferred_files: Mutex<Vec<String>>,
+}
+
+impl NewerThanCutoff {
+    fn is_newer(&self, file_path: &str) -> bool {
+        std::fs::metadata(file_path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified > self.cutoff)
+    }
+
+    fn cutoff_unix(&self) -> u64 {
+        self.cutoff
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs())
+    }
+}
+
+/// `--exclude-newer-than`: a unix timestamp in seconds, or `start` for the time the backup
+/// started (not the `--timestamp` it records).
+fn get_newer_than_cutoff(matches: &ArgMatches) -> Result<Option<NewerThanCutoff>, String> {
+    let Some(value) = matches.get_one::<String>("exclude-newer-than") else {
+        return Ok(None);
+    };
+
+    let cutoff = if value.trim() == "start" {
+        std::time::SystemTime::now()
+    } else {
+        let seconds = value.trim().parse::<u64>().map_err(|_| {
+            format!(
+                "Invalid value for --exclude-newer-than: '{}' (must be a unix timestamp in seconds or 'start')",
+                value
+            )
+        })?;
+        std::time::UNIX_EPOCH + Duration::from_secs(seconds)
+    };
+
+    Ok(Some(NewerThanCutoff {
+        cutoff,
+        deferred_files: Mutex::new(Vec::new()),
+    }))
+}
+
+fn relative_path(file_path: &str, root_path_string: &str) -> String {
+    let content = file_path
+        .strip_prefix(root_path_string)
+        .unwrap_or(file_path);
+
+    let mut content = content.replace('\\', "/");
+
+    if content.starts_with('/') {
+        content = content[1..].to_string();
+    }
+
+    content
+}
+
+/// With `--abort-on-warning`, every backup warning that means a degraded or unexpected
+/// result fails the backup at the point it is raised. `pending_backup_reuse` is not
+/// escalated: it only reports settings taken over by `--continue`. Files left out by
+/// `--budget` are not warnings either.
+#[derive(Clone, Copy)]
+struct WarningPolicy {
+    abort_on_warning: bool,
+}
+
+impl WarningPolicy {
+    fn from_matches(matches: &ArgMatches) -> Self {
+        WarningPolicy {
+            abort_on_warning: matches.get_flag("abort-on-warning"),
+        }
+    }
+
+    /// Exits with the warning as an error (keeping its code in JSON mode) when
+    /// `--abort-on-warning` is set; does nothing otherwise.
+    fn escalate(&self, warning: &str, code: &str, pb: Option<&ProgressBar>) {
+        if !self.abort_on_warning {
+            return;
+        }
+
+        let message = format!("{} (aborting because of --abort-on-warning)", warning);
+
+        if is_json_mode() {
+            if let Some(pb) = pb {
+                pb.finish_and_clear();
+            }
+            emit_error(&message, code);
+        }
+
+        handle_error(message, pb);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct SkipPolicy {
+    permission_denied: bool,
+    vanished: bool,
+}
+
+#[derive(Default)]
+struct BackupStats {
+    skipped_files: u64,
+    renames: Vec<RenameOutput>,
+    new_chunks: u64,
+    deduplicated_chunks: u64,
+    uploaded_bytes: u64,
+    compressed_bytes: u64,
+    top_level_bytes: HashMap<String, u64>,
+    /// `None` unless `--verify-chunks-after-write` is set.
+    write_verify_mismatches: Option<u64>,
+    /// Files with an ACL that was left out because `--store-acls` is not set.
+    unstored_acl_files: u64,
+    /// `None` unless `--exclude-bigger-deltas` is set.
+    appended_files: Option<u64>,
+    /// `None` unless `--assume-unchanged` is set.
+    assumed_unchanged_files: Option<u64>,
+    /// Files whose chunks came from `--chunk-cache-size`, and files looked up in it
+    /// without a match. `None` unless it is set.
+    chunk_cache_hits: Option<u64>,
+    chunk_cache_misses: Option<u64>,
+}
+
+#[derive(serde::Serialize)]
+struct BackupStatsOutput {
+    new_chunks: u64,
+    deduplicated_chunks: u64,
+    average_chunk_size: u64,
+    uploaded_bytes: u64,
+    compressed_bytes: u64,
+    compression_ratio: f64,
+    top_level_bytes: Vec<TopLevelBytes>,
+    renamed_files: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    renames: Vec<RenameOutput>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    write_verify_mismatches: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    appended_files: Option<u64>,
+    /// Files matched by `--assume-unchanged` and taken from the previous backup unread.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    assumed_unchanged_files: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_cache_hits: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_cache_misses: Option<u64>,
+    /// Share of the files looked up in the cache that were found there.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunk_cache_hit_rate: Option<f64>,
+}
+
+#[derive(serde::Serialize, Clone)]
+struct RenameOutput {
+    from: String,
+    to: String,
+}
+
+#[derive(serde::Serialize)]
+struct TopLevelBytes {
+    path: String,
+    bytes: u64,
+}
+
+impl BackupStats {
+    fn to_output(&self, written_bytes: u64, deduplicated_bytes: u64) -> BackupStatsOutput {
+        let total_chunks = self.new_chunks + self.deduplicated_chunks;
+        let average_chunk_size = (written_bytes + deduplicated_bytes)
+            .checked_div(total_chunks)
+            .unwrap_or(0);
+        let compression_ratio = if self.compressed_bytes > 0 {
+            self.uploaded_bytes as f64 / self.compressed_bytes as f64
+        } else {
+            1.0
+        };
+
+        let mut top_level_bytes: Vec<TopLevelBytes> = self
+            .top_level_bytes
+            .iter()
+            .map(|(path, bytes)| TopLevelBytes {
+                path: path.clone(),
+                bytes: *bytes,
+            })
+            .collect();
+        top_level_bytes.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.path.cmp(&b.path)));
+
+        let mut renames = self.renames.clone();
+        renames.sort_by(|a, b| a.to.cmp(&b.to));
+
+        BackupStatsOutput {
+            new_chunks: self.new_chunks,
+            deduplicated_chunks: self.deduplicated_chunks,
+            average_chunk_size,
+            uploaded_bytes: self.uploaded_bytes,
+            compressed_bytes: self.compressed_bytes,
+            compression_ratio,
+            top_level_bytes,
+            renamed_files: renames.len() as u64,
+            renames,
+            write_verify_mismatches: self.write_verify_mismatches,
+            appended_files: self.appended_files,
+            assumed_unchanged_files: self.assumed_unchanged_files,
+            chunk_cache_hits: self.chunk_cache_hits,
+            chunk_cache_misses: self.chunk_cache_misses,
+            chunk_cache_hit_rate: self.chunk_cache_hits.zip(self.chunk_cache_misses).map(
+                |(hits, misses)| {
+                    if hits + misses > 0 {
+                        hits as f64 / (hits + misses) as f64
+                    } else {
+                        0.0
+                    }
+                },
+            ),
+        }
+    }
+}
+
+/// Files of the previous backup keyed by (inode, size, mtime). A file found at a path the
+/// previous backup didn't have it at, with the same key, is the same file moved, so its
+/// entry (hash and chunks) is reused without reading it again.
+struct RenameDetector {
+    previous_files: HashMap<(u64, u64, u64), (String, BackupObject)>,
+}
+
+impl RenameDetector {
+    fn reuse(
+        &self,
+        relative_path: &str,
+        file_path: &str,
+        file_metadata: &std::fs::Metadata,
+        chunk_indexes: &Mutex<HashMap<String, ChunkIndex>>,
+        stats: &Mutex<BackupStats>,
+    ) -> Option<BackupObject> {
+        let (Some(mtime_ns), Some(inode)) = get_file_identity(file_metadata) else {
+            return None;
+        };
+
+        let (previous_path, previous_object) =
+            self.previous_files
+                .get(&(inode, file_metadata.len(), mtime_ns))?;
+
+        if previous_path == relative_path {
+            return None;
+        }
+
+        {
+            // The previous entry is only reusable if every chunk is still referenced;
+            // otherwise (e.g. after a prune) fall back to reading the file.
+            let mut chunk_indexes_guard = chunk_indexes.lock().unwrap();
+            if !previous_object
+                .chunks
+                .iter()
+                .all(|chunk_hash| chunk_indexes_guard.contains_key(chunk_hash))
+            {
+                return None;
+            }
+
+            for chunk_hash in &previous_object.chunks {
+                if let Some(chunk_index) = chunk_indexes_guard.get_mut(chunk_hash) {
+                    chunk_index.refcount += 1;
+                }
+            }
+        }
+
+        let (uid, gid) = get_file_owner(file_metadata);
+
+        stats.lock().unwrap().renames.push(RenameOutput {
+            from: previous_path.clone(),
+            to: relative_path.to_string(),
+        });
+
+        Some(BackupObject {
+            permissions: get_file_permissions_with_path(file_metadata, file_path),
+            uid,
+            gid,
+            ..previous_object.clone()
+        })
+    }
+}
+
+/// Files of the previous backup by path. A file found at its path again, with the same
+/// inode, larger, with the same sampled content as the previous backup recorded and its
+/// last reused chunk unchanged, only had data appended: its chunks are reused and only the
+/// data after them is read. A rewrite elsewhere in the old content still goes unnoticed.
+struct AppendDetector {
+    previous_files: HashMap<String, BackupObject>,
+}
+
+struct ResumedFile {
+    chunks: Vec<String>,
+    hasher: ResumableSha256,
+    /// Where the first chunk after the reused ones starts.
+    chunk_offset: u64,
+}
+
+impl AppendDetector {
+    fn resume(
+        &self,
+        relative_path: &str,
+        file_path: &str,
+        file_metadata: &std::fs::Metadata,
+        chunk_size: u64,
+        chunk_indexes: &Mutex<HashMap<String, ChunkIndex>>,
+    ) -> Option<ResumedFile> {
+        let previous_object = self.previous_files.get(relative_path)?;
+        let append_state = previous_object.append.as_ref()?;
+
+        if append_state.chunk_size != chunk_size
+            || file_metadata.len() <= previous_object.size
+            || append_state.hash_offset > previous_object.size
+        {
+            return None;
+        }
+
+        // A file replaced by a new one (log rotation, an editor saving a copy) is not an
+        // append, whatever its content.
+        let (_, inode) = get_file_identity(file_metadata);
+        if inode.is_none() || inode != previous_object.inode {
+            return None;
+        }
+
+        let sample = content_sample(Path::new(file_path), previous_object.size).ok()?;
+        if sample != append_state.sample {
+            return None;
+        }
+
+        let hasher = ResumableSha256::resume(append_state)?;
+
+        // The last chunk of the previous content was partial and is read again along
+        // with the new data.
+        let reused =
+            ((previous_object.size / chunk_size) as usize).min(previous_object.chunks.len());
+        let chunks = previous_object.chunks[..reused].to_vec();
+
+        // Reused chunks are never read again, so check the last one still holds what it
+        // did: with the sample, that covers the data right before the new content.
+        if let Some(last_chunk) = chunks.last()
+            && !chunk_matches(
+                Path::new(file_path),
+                (reused as u64 - 1) * chunk_size,
+                chunk_size,
+                last_chunk,
+            )
+            .unwrap_or(false)
+        {
+            return None;
+        }
+
+        {
+            let mut chunk_indexes_guard = chunk_indexes.lock().unwrap();
+            if !chunks
+                .iter()
+                .all(|chunk_hash| chunk_indexes_guard.contains_key(chunk_hash))
+            {
+                return None;
+            }
+
+            for chunk_hash in &chunks {
+                if let Some(chunk_index) = chunk_indexes_guard.get_mut(chunk_hash) {
+                    chunk_index.refcount += 1;
+                }
+            }
+        }
+
+        Some(ResumedFile {
+            chunks,
+            hasher,
+            chunk_offset: reused as u64 * chunk_size,
+        })
+    }
+}
+
+/// Files of the previous backup by path, for `--assume-unchanged`. A file matching one of
+/// the patterns and still of the size the previous backup recorded is taken to be
+/// unchanged: its entry is reused without opening the file, so an edit that keeps the size
+/// goes unnoticed.
+struct AssumeUnchanged {
+    patterns: GlobSet,
+    previous_files: HashMap<String, BackupObject>,
+}
+
+impl AssumeUnchanged {
+    fn reuse(
+        &self,
+        relative_path: &str,
+        file_path: &str,
+        file_metadata: &std::fs::Metadata,
+        chunk_indexes: &Mutex<HashMap<String, ChunkIndex>>,
+    ) -> Option<BackupObject> {
+        if !self.patterns.is_match(relative_path) {
+            return None;
+        }
+
+        let previous_object = self.previous_files.get(relative_path)?;
+        if previous_object.size != file_metadata.len() {
+            return None;
+        }
+
+        {
+            // Like renames, the entry is only reusable while every chunk is still stored.
+            let mut chunk_indexes_guard = chunk_indexes.lock().unwrap();
+            if !previous_object
+                .chunks
+                .iter()
+                .all(|chunk_hash| chunk_indexes_guard.contains_key(chunk_hash))
+            {
+                return None;
+            }
+
+            for chunk_hash in &previous_object.chunks {
+                if let Some(chunk_index) = chunk_indexes_guard.get_mut(chunk_hash) {
+                    chunk_index.refcount += 1;
+                }
+            }
+        }
+
+        let (uid, gid) = get_file_owner(file_metadata);
+
+        Some(BackupObject {
+            permissions: get_file_permissions_with_path(file_metadata, file_path),
+            uid,
+            gid,
+            ..previous_object.clone()
+        })
+    }
+}
+
+/// File extensions whose content is already compressed, so zstd gains next to nothing on
+/// their chunks; `--store-extensions` replaces the list.
+const DEFAULT_STORE_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "docx", "epub", "flac", "gif", "gz", "heic",
+    "jar", "jpeg", "jpg", "lz4", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "opus",
+    "png", "pptx", "rar", "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+/// The lowercase extensions of `--store-extensions`, or the default list. An empty value
+/// compresses every file.
+fn get_store_extensions(matches: &ArgMatches) -> HashSet<String> {
+    match matches.get_one::<String>("store-extensions") {
+        Some(extensions) => extensions
+            .split(',')
+            .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+            .filter(|extension| !extension.is_empty())
+            .collect(),
+        None => DEFAULT_STORE_EXTENSIONS
+            .iter()
+            .map(|extension| extension.to_string())
+            .collect(),
+    }
+}
+
+fn is_store_extension(file_path: &str, store_extensions: &HashSet<String>) -> bool {
+    Path::new(file_path).extension().is_some_and(|extension| {
+        store_extensions.contains(&extension.to_string_lossy().to_lowercase())
+    })
+}
+
+fn build_assume_unchanged_patterns<'a>(
+    patterns: impl Iterator<Item = &'a String>,
+) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern)
+            .build()
+            .map_err(|e| format!("Invalid --assume-unchanged pattern '{}': {}", pattern, e))?;
+        builder.add(glob);
+    }
+
+    builder
+        .build()
+        .map_err(|e| format!("Failed to build --assume-unchanged patterns: {}", e))
+}
+
+/// The tree of the latest backup of `key`, empty when there is none.
+async fn load_previous_tree(
+    fs: Arc<dyn FS>,
+    key: String,
+    password: Option<String>,
+) -> Result<HashMap<String, BackupObject>, String> {
+    let backup_summaries =
+        list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await?;
+
+    let Some(latest_summary) = backup_summaries.first() else {
+        return Ok(HashMap::new());
+    };
+
+    let (previous_backup, _) =
+        load_resolved_backup(fs, key, password, latest_summary.hash.clone()).await?;
+
+    Ok(previous_backup.tree)
+}
+
+async fn load_rename_detector(
+    fs: Arc<dyn FS>,
+    key: String,
+    password: Option<String>,
+) -> Result<RenameDetector, String> {
+    let previous_files = load_previous_tree(fs, key, password)
+        .await?
+        .into_iter()
+        .filter_map(|(path, backup_object)| {
+            let (Some(mtime_ns), Some(inode)) = (backup_object.mtime_ns, backup_object.inode)
+            else {
+                return None;
+            };
+
+            Some(((inode, backup_object.size, mtime_ns), (path, backup_object)))
+        })
+        .collect();
+
+    Ok(RenameDetector { previous_files })
+}
+
+fn get_file_identity(metadata: &std::fs::Metadata) -> (Option<u64>, Option<u64>) {
+    let mtime_ns = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos() as u64);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        (mtime_ns, Some(metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    {
+        (mtime_ns, None)
+    }
+}
+
+struct PendingBackupMatch {
+    backup: PendingBackup,
+    path: String,
+}
+
+async fn load_pending_backup(
+    fs: Arc<dyn FS>,
+    key: &str,
+    continue_prefix: &str,
+    password: &Option<String>,
+) -> Result<PendingBackupMatch, String> {
+    let indexes_path = format!("{}/indexes", key);
+    let files = fs
+        .list_files(&indexes_path)
+        .await
+        .map_err(|e| format!("Failed to list indexes in '{}': {}", indexes_path, e))?;
+
+    let pending_prefix = format!("{}/indexes/pending_{}", key, continue_prefix);
+    let mut matches: Vec<String> = files
+        .into_iter()
+        .filter(|path| path.starts_with(&pending_prefix))
+        .collect();
+
+    matches.sort();
+    matches.dedup();
+
+    if matches.is_empty() {
+        return Err(format!("No pending backup found for '{}'", continue_prefix));
+    }
+
+    if matches.len() > 1 {
+        return Err(format!(
+            "Hash prefix {} matches {} pending backups, use a longer prefix",
+            continue_prefix,
+            matches.len()
+        ));
+    }
+
+    let pending_path = matches
+        .pop()
+        .ok_or_else(|| "Pending backup match missing".to_string())?;
+
+    let pending_result = read_file_maybe_decrypt(
+        &fs,
+        &pending_path,
+        password.as_deref(),
+        "The pending backup is encrypted. Please enter the password to decrypt it.",
+    )
+    .await?;
+
+    let decompressed_bytes = decompress_bytes(&pending_result.bytes)
+        .map_err(|e| format!("Failed to decompress pending backup: {}", e))?;
+
+    let pending_backup: PendingBackup =
+        rmp_serde::from_slice(&decompressed_bytes).map_err(|e| {
+            format!(
+                "Failed to deserialize pending backup '{}': {}",
+                pending_path, e
+            )
+        })?;
+
+    Ok(PendingBackupMatch {
+        backup: pending_backup,
+        path: pending_path,
+    })
+}
+
+struct BackupParams {
+    key: String,
+    message: String,
+    root_path_string: String,
+    storage: String,
+    compress: i32,
+    password: Option<String>,
+    chunker: Chunker,
+    ignore_patterns: Vec<String>,
+    exclude_paths: Vec<String>,
+    ignore_case: bool,
+    exclude_vcs: bool,
+    pending_backup: Option<PendingBackupMatch>,
+    concurrency: usize,
+    progress_every: u64,
+    progress_interval: Duration,
+    timestamp: u64,
+}
+
+async fn get_params(matches: &ArgMatches) -> Result<BackupParams, String> {
+    let password: Option<String> = matches
+        .get_one::<String>("password")
+        .map(|s| s.to_string())
+        .map_or_else(
+            || get_password(false, false),
+            |password| Some(password.to_string()),
+        );
+
+    let pwd_string = get_pwd_string();
+
+    let root_path_string = matches.get_one::<String>("root-path").map_or_else(
+        || pwd_string.clone(),
+        |root_path| {
+            Path::new(&pwd_string)
+                .join(root_path)
+                .to_string_lossy()
+                .to_string()
+        },
+    );
+
+    let root_path_string = if matches.get_flag("follow-root-symlink") {
+        resolve_root_symlink(root_path_string)?
+    } else {
+        root_path_string
+    };
+
+    let folder_key = Path::new(&root_path_string)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .to_string()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    let pending_backup = match matches.get_one::<String>("continue") {
+        Some(continue_prefix) => {
+            let storage_config = get_storage(&storage);
+            let fs = get_fs(&storage_config, None);
+            Some(load_pending_backup(fs, &key, continue_prefix, &password).await?)
+        }
+        None => None,
+    };
+
+    let mut reused_data = Vec::new();
+
+    if let Some(pending) = &pending_backup
+        && !pending.backup.processed_chunks.is_empty()
+    {
+        reused_data.push("uploaded chunks".to_string());
+    }
+
+    let message = match matches.get_one::<String>("message") {
+        Some(message) => message.to_string(),
+        None => {
+            if let Some(pending) = &pending_backup
+                && !pending.backup.message.is_empty()
+            {
+                reused_data.push("message".to_string());
+                pending.backup.message.clone()
+            } else {
+                if !can_prompt() {
+                    return Err(
+                        "Missing required argument: --message (required in --mode json or with --non-interactive)"
+                            .to_string(),
+                    );
+                }
+                Input::<String>::new()
+                    .with_prompt("Enter the backup message")
+                    .interact_text()
+                    .map_err(|e| format!("{}", e))?
+            }
+        }
+    };
+
+    let default_compress = read_config()
+        .and_then(|config| config.default_compress)
+        .unwrap_or(3);
+
+    let compress: i32 = matches.get_one::<String>("compress").map_or_else(
+        || {
+            if let Some(pending) = &pending_backup
+                && pending.backup.compress != default_compress
+            {
+                reused_data.push("compress".to_string());
+                pending.backup.compress
+            } else {
+                default_compress
+            }
+        },
+        // `store` (0) writes chunks without compression; manifests and indexes are still
+        // compressed, at zstd's default level for 0.
+        |compress| {
+            if compress.eq_ignore_ascii_case("store") {
+                0
+            } else {
+                compress.parse().unwrap_or(default_compress)
+            }
+        },
+    );
+
+    let chunk_size: u64 = matches.get_one::<String>("chunk-size").map_or_else(
+        || {
+            if let Some(pending) = &pending_backup
+                && pending.backup.chunk_size != parse_size("5 MB").unwrap()
+            {
+                reused_data.push("chunk size".to_string());
+                pending.backup.chunk_size
+            } else {
+                parse_size("5 MB").unwrap()
+            }
+        },
+        |chunk_size| parse_size(chunk_size).unwrap(),
+    );
+
+    let pending_chunker = pending_backup
+        .as_ref()
+        .and_then(|pending| pending.backup.chunker);
+
+    let chunker_name = match matches.get_one::<String>("chunker") {
+        Some(chunker_name) => chunker_name.as_str(),
+        None => match pending_chunker {
+            Some(pending_chunker) => {
+                reused_data.push("chunker".to_string());
+                pending_chunker.name()
+            }
+            None => "fixed",
+        },
+    };
+
+    let chunker = get_chunker(matches, chunker_name, chunk_size, pending_chunker)?;
+
+    let ignore_patterns: Vec<String> = matches
+        .get_many::<String>("ignore")
+        .map(|values| values.map(|s| s.to_string()).collect())
+        .unwrap_or_else(|| {
+            if let Some(pending) = &pending_backup
+                && !pending.backup.ignore_patterns.is_empty()
+            {
+                reused_data.push("ignored files".to_string());
+                pending.backup.ignore_patterns.clone()
+            } else {
+                Vec::new()
+            }
+        });
+
+    let exclude_paths: Vec<String> = match matches.get_many::<String>("exclude-path") {
+        Some(values) => resolve_exclude_paths(
+            &root_path_string,
+            &values.map(|s| s.to_string()).collect::<Vec<String>>(),
+            WarningPolicy::from_matches(matches),
+        )?,
+        None => {
+            if let Some(pending) = &pending_backup
+                && !pending.backup.exclude_paths.is_empty()
+            {
+                reused_data.push("excluded paths".to_string());
+                pending.backup.exclude_paths.clone()
+            } else {
+                Vec::new()
+            }
+        }
+    };
+
+    let ignore_case = if matches.get_flag("ignore-case") {
+        true
+    } else if let Some(pending) = &pending_backup
+        && pending.backup.ignore_case
+    {
+        reused_data.push("ignore case".to_string());
+        true
+    } else {
+        false
+    };
+
+    let exclude_vcs = if matches.get_flag("exclude-vcs") {
+        true
+    } else if let Some(pending) = &pending_backup
+        && pending.backup.exclude_vcs
+    {
+        reused_data.push("VCS exclusion".to_string());
+        true
+    } else {
+        false
+    };
+
+    if !reused_data.is_empty() {
+        let pending_name = pending_backup
+            .as_ref()
+            .and_then(|pending| pending.path.rsplit('/').next())
+            .map_or("pending backup".to_string(), |pending| {
+                let hash = pending.replace("pending_", "");
+                hash[..8].to_string()
+            });
+        let warning = format!("Reusing from {}: {}", pending_name, reused_data.join(", "));
+
+        if is_json_mode() {
+            emit_warning(&warning, "pending_backup_reuse");
+        } else {
+            println!("{}", style(warning).yellow());
+        }
+    }
+
+    let default_concurrency = num_cpus::get() * 2;
+
+    let concurrency = matches.get_one::<String>("concurrency").map_or_else(
+        || {
+            if let Some(pending) = &pending_backup
+                && pending.backup.concurrency != default_concurrency
+            {
+                reused_data.push("concurrency".to_string());
+                pending.backup.concurrency
+            } else {
+                default_concurrency
+            }
+        },
+        |concurrency| concurrency.parse().unwrap_or(default_concurrency),
+    );
+
+    let progress_every = match matches.get_one::<String>("progress-every") {
+        Some(every) => match every.parse::<u64>() {
+            Ok(every) if every > 0 => every,
+            _ => {
+                return Err(format!(
+                    "Invalid value for --progress-every: '{}' (must be a positive number)",
+                    every
+                ));
+            }
+        },
+        None => 1,
+    };
+
+    let progress_interval = match matches.get_one::<String>("progress-interval") {
+        Some(interval) => interval
+            .parse::<u64>()
+            .map(Duration::from_millis)
+            .map_err(|_| {
+                format!(
+                    "Invalid value for --progress-interval: '{}' (must be a number of milliseconds)",
+                    interval
+                )
+            })?,
+        None => default_progress_interval(),
+    };
+
+    let timestamp = get_backup_timestamp(matches)?;
+
+    Ok(BackupParams {
+        key,
+        message,
+        root_path_string,
+        storage,
+        compress,
+        password,
+        chunker,
+        ignore_patterns,
+        exclude_paths,
+        ignore_case,
+        exclude_vcs,
+        pending_backup,
+        concurrency,
+        progress_every,
+        progress_interval,
+        timestamp,
+    })
+}
+
+/// The chunker named by `--chunker`, with `--chunk-size` as the fixed or the average size.
+/// Content-defined bounds not given keep those of the interrupted backup being continued
+/// when it had the same average, so its uploaded chunks still match.
+fn get_chunker(
+    matches: &ArgMatches,
+    chunker_name: &str,
+    chunk_size: u64,
+    pending_chunker: Option<Chunker>,
+) -> Result<Chunker, String> {
+    let parse_bound = |name: &str| {
+        matches
+            .get_one::<String>(name)
+            .map(|size| {
+                parse_size(size).map_err(|_| format!("Invalid value for --{}: '{}'", name, size))
+            })
+            .transpose()
+    };
+
+    let min_size = parse_bound("min-chunk-size")?;
+    let max_size = parse_bound("max-chunk-size")?;
+
+    if chunker_name == "fixed" {
+        if min_size.is_some() || max_size.is_some() {
+            return Err(
+                "--min-chunk-size and --max-chunk-size only apply with --chunker cdc".to_string(),
+            );
+        }
+
+        return Ok(Chunker::Fixed { size: chunk_size });
+    }
+
+    let (default_min_size, default_max_size) = match pending_chunker {
+        Some(Chunker::Cdc {
+            min_size,
+            avg_size,
+            max_size,
+        }) if avg_size == chunk_size => (min_size, max_size),
+        _ => (
+            (chunk_size / 4).max(MIN_CDC_SIZE),
+            chunk_size.saturating_mul(2),
+        ),
+    };
+
+    Chunker::cdc(
+        min_size.unwrap_or(default_min_size),
+        chunk_size,
+        max_size.unwrap_or(default_max_size),
+    )
+}
+
+/// The timestamp recorded for the backup (and hashed into its id): `--timestamp`, then
+/// `SOURCE_DATE_EPOCH`, then the current time. Fixing it makes backups reproducible.
+fn get_backup_timestamp(matches: &ArgMatches) -> Result<u64, String> {
+    if let Some(timestamp) = matches.get_one::<String>("timestamp") {
+        return timestamp.trim().parse::<u64>().map_err(|_| {
+            format!(
+                "Invalid value for --timestamp: '{}' (must be a unix timestamp in seconds)",
+                timestamp
+            )
+        });
+    }
+
+    if let Ok(source_date_epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        return source_date_epoch.trim().parse::<u64>().map_err(|_| {
+            format!(
+                "Invalid SOURCE_DATE_EPOCH: '{}' (must be a unix timestamp in seconds)",
+                source_date_epoch
+            )
+        });
+    }
+
+    Ok(std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs())
+}
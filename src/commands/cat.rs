@@ -0,0 +1,247 @@
+use crate::commands::config::{default_key, default_storage};
+use crate::core::chunks::fetch_verified_chunk;
+use crate::core::crypto::get_password;
+use crate::core::indexes::{list_backup_summaries, load_repository_meta, load_resolved_backup};
+use crate::core::layout::chunk_path;
+use crate::output::{can_prompt, emit_error, is_json_mode};
+use crate::utils::{get_fs, get_pwd_string, get_storage, handle_error};
+use clap::ArgMatches;
+use dialoguer::Select;
+use dirs::home_dir;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Writes one file of a backup to stdout, chunk by chunk, so it can be piped into another
+/// command without restoring the tree.
+pub async fn cat(matches: &ArgMatches) {
+    // The file is written as raw bytes, which would corrupt the JSON event stream.
+    if is_json_mode() {
+        emit_error(
+            "gib cat writes the file to stdout and cannot be used with --mode json; use 'gib restore --only' instead",
+            "cat_json_mode",
+        );
+    }
+
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let path = matches
+        .get_one::<String>("path")
+        .expect("the path is required")
+        .trim_start_matches("./")
+        .trim_start_matches('/')
+        .to_string();
+
+    let fs = get_fs(&get_storage(&storage), None);
+
+    let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+        Err(e) => handle_error(e, None),
+    };
+
+    let summaries =
+        match list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await {
+            Ok(summaries) => summaries,
+            Err(e) => handle_error(e, None),
+        };
+
+    // Without --backup the newest backup is used.
+    let backup_hash = match matches.get_one::<String>("backup") {
+        Some(prefix) => match summaries
+            .iter()
+            .find(|summary| summary.hash.starts_with(prefix.as_str()))
+        {
+            Some(summary) => summary.hash.clone(),
+            None => handle_error(
+                format!("No backup found matching hash prefix: {}", prefix),
+                None,
+            ),
+        },
+        None => match summaries.first() {
+            Some(summary) => summary.hash.clone(),
+            None => handle_error("No backups found in repository".to_string(), None),
+        },
+    };
+    let backup_short = &backup_hash[..8.min(backup_hash.len())];
+
+    let (backup, _) = match load_resolved_backup(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        backup_hash.clone(),
+    )
+    .await
+    {
+        Ok(resolved) => resolved,
+        Err(e) => handle_error(e, None),
+    };
+
+    let Some(backup_object) = backup.tree.get(&path) else {
+        let prefix = format!("{}/", path.trim_end_matches('/'));
+        if path.is_empty() || backup.tree.keys().any(|file| file.starts_with(&prefix)) {
+            handle_error(
+                format!(
+                    "'{}' is a directory in backup {}; pass the path of a file",
+                    path, backup_short
+                ),
+                None,
+            );
+        }
+        handle_error(
+            format!("No file '{}' in backup {}", path, backup_short),
+            None,
+        );
+    };
+
+    if let Some(link_target) = &backup_object.link_target {
+        handle_error(
+            format!(
+                "'{}' is a symbolic link to '{}' in backup {}",
+                path, link_target, backup_short
+            ),
+            None,
+        );
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let mut hasher = Sha256::new();
+
+    for chunk_hash in &backup_object.chunks {
+        let bytes = match fetch_verified_chunk(
+            &fs,
+            &chunk_path(&key, chunk_hash, chunk_dir_levels),
+            chunk_hash,
+            password.as_deref(),
+        )
+        .await
+        {
+            Ok(bytes) => bytes,
+            Err(e) => handle_error(
+                format!(
+                    "Failed to read chunk {} of '{}' ({}): {}",
+                    chunk_hash,
+                    path,
+                    e.reason_code(),
+                    e.detail()
+                ),
+                None,
+            ),
+        };
+
+        hasher.update(&bytes);
+
+        if let Err(e) = stdout.write_all(&bytes) {
+            // A closed pipe (`gib cat ... | head`) is not an error.
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                return;
+            }
+            handle_error(format!("Failed to write to stdout: {}", e), None);
+        }
+    }
+
+    if let Err(e) = stdout.flush()
+        && e.kind() != std::io::ErrorKind::BrokenPipe
+    {
+        handle_error(format!("Failed to write to stdout: {}", e), None);
+    }
+
+    let actual_hash = format!("{:x}", hasher.finalize());
+    if actual_hash != backup_object.hash {
+        handle_error(
+            format!(
+                "'{}' does not match the hash recorded in backup {} (got {})",
+                path, backup_short, actual_hash
+            ),
+            None,
+        );
+    }
+}
+
+fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>), String> {
+    let password: Option<String> = matches
+        .get_one::<String>("password")
+        .map(|s| s.to_string())
+        .map_or_else(
+            || get_password(false, true),
+            |password| Some(password.to_string()),
+        );
+
+    let pwd_string = get_pwd_string();
+
+    let folder_key = Path::new(&pwd_string)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    Ok((key, storage, password))
+}
@@ -0,0 +1,829 @@
+use crate::commands::config::{default_key, default_storage};
+use crate::core::chunks::fetch_verified_chunk;
+use crate::core::crypto::{get_password, read_file_maybe_decrypt};
+use crate::core::indexes::{
+    backfill_backup_summaries, list_backup_summaries, load_chunk_indexes, load_repository_meta,
+    load_summary_backup, replace_chunk_indexes,
+};
+use crate::core::layout::{chunk_hash_from_path, chunk_path};
+use crate::core::lock::acquire_lock;
+use crate::core::metadata::{Backup, BackupSummary, ChunkIndex, PendingBackup};
+use crate::fs::FS;
+use crate::output::{
+    JsonProgress, can_prompt, emit_error, emit_error_with_details, emit_output,
+    emit_progress_message, emit_warning, is_json_mode,
+};
+use crate::utils::{
+    decompress_bytes, get_fs, get_pwd_string, get_storage, handle_error, parse_duration,
+};
+use bytesize::ByteSize;
+use clap::ArgMatches;
+use console::style;
+use dialoguer::Select;
+use dirs::home_dir;
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+
+const MAX_CONCURRENT_CHUNKS: usize = 100;
+const MAX_CONCURRENT_MANIFEST_LOADS: usize = 16;
+
+#[derive(serde::Serialize)]
+struct CheckProblem {
+    /// `missing_chunk`, `decrypt_error`, `decompress_error`, `corrupt_chunk`,
+    /// `unindexed_chunk` or `orphaned_chunk`.
+    code: &'static str,
+    chunk: String,
+    detail: String,
+    /// The backups whose tree references the chunk, for `unindexed_chunk`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    backups: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct RefcountDrift {
+    chunk: String,
+    /// The refcount in the chunk index, 0 for a chunk that is not indexed.
+    indexed: u32,
+    /// How many times the backups reference the chunk, 0 for a chunk none references.
+    expected: u32,
+}
+
+#[derive(serde::Serialize)]
+struct RefcountRepair {
+    dry_run: bool,
+    /// Where the previous chunk index was copied to before it was rewritten.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_index: Option<String>,
+    drifted: Vec<RefcountDrift>,
+}
+
+#[derive(serde::Serialize)]
+struct CheckOutput {
+    backups_checked: usize,
+    chunks_indexed: usize,
+    chunks_stored: usize,
+    chunks_verified: usize,
+    bytes_verified: u64,
+    /// Indexed chunks that are not in the storage.
+    missing_chunks: usize,
+    /// Indexed chunks that do not decrypt, decompress or hash back to their hash.
+    corrupt_chunks: usize,
+    /// Chunks a backup references that are not in the chunk index.
+    unindexed_chunks: usize,
+    /// Stored chunks that neither the chunk index nor a pending backup references.
+    orphaned_chunks: usize,
+    problems: Vec<CheckProblem>,
+    /// Set with `--repair-refcounts`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refcount_repair: Option<RefcountRepair>,
+    elapsed_ms: u64,
+}
+
+pub async fn check(matches: &ArgMatches) {
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let started_at = Instant::now();
+
+    let concurrency = match matches.get_one::<String>("concurrency") {
+        Some(concurrency) => match concurrency.parse::<usize>() {
+            Ok(concurrency) if concurrency > 0 => concurrency,
+            _ => handle_error(format!("Invalid concurrency: '{}'", concurrency), None),
+        },
+        None => MAX_CONCURRENT_CHUNKS,
+    };
+
+    let repair_refcounts = matches.get_flag("repair-refcounts");
+    let dry_run = matches.get_flag("dry-run");
+
+    let lock_timeout = match matches.get_one::<String>("lock-timeout") {
+        Some(timeout) => match parse_duration(timeout, "--lock-timeout") {
+            Ok(timeout) => timeout,
+            Err(e) => handle_error(e, None),
+        },
+        None => Duration::ZERO,
+    };
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb
+    };
+
+    // Hold the lock while refcounts are compared and rewritten, so a backup or delete
+    // finishing in between cannot have its own refcount changes overwritten.
+    let lock = if repair_refcounts && !dry_run {
+        match acquire_lock(
+            Arc::clone(&fs),
+            &key,
+            lock_timeout,
+            |holder| {
+                set_progress_message(
+                    &pb,
+                    &format!("Waiting for the repository lock held by {}...", holder),
+                )
+            },
+            |holder, reason| {
+                pb.suspend(|| {
+                    emit_warning(
+                        &format!(
+                            "Taking over the stale repository lock held by {}: {}",
+                            holder, reason
+                        ),
+                        "stale_lock_taken_over",
+                    )
+                })
+            },
+        )
+        .await
+        {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                if is_json_mode() {
+                    emit_error(&e, "repository_locked");
+                }
+                handle_error(e, Some(&pb));
+            }
+        }
+    } else {
+        None
+    };
+
+    set_progress_message(&pb, "Loading indexes...");
+
+    let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let mut chunk_indexes = match load_chunk_indexes(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        Arc::new(Mutex::new(false)),
+    )
+    .await
+    {
+        Ok(chunk_indexes) => chunk_indexes,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let mut backup_summaries =
+        match list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await {
+            Ok(summaries) => summaries,
+            Err(e) => handle_error(e, Some(&pb)),
+        };
+
+    backfill_backup_summaries(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        &mut backup_summaries,
+    )
+    .await;
+
+    set_progress_message(
+        &pb,
+        &format!("Loading {} backups...", backup_summaries.len()),
+    );
+
+    let backups = match load_backups(&fs, &key, &password, &backup_summaries).await {
+        Ok(backups) => backups,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let refcount_repair = if repair_refcounts {
+        let drifted = find_refcount_drift(&chunk_indexes, &backups);
+
+        let previous_index = if !dry_run && !drifted.is_empty() {
+            set_progress_message(
+                &pb,
+                &format!("Repairing {} chunk refcounts...", drifted.len()),
+            );
+
+            let mut repaired = chunk_indexes.clone();
+            apply_refcount_drift(&mut repaired, &drifted);
+
+            match replace_chunk_indexes(&fs, &key, &password, &repaired).await {
+                Ok(previous_index) => {
+                    chunk_indexes = repaired;
+                    previous_index
+                }
+                Err(e) => handle_error(
+                    format!("Failed to repair the chunk refcounts: {}", e),
+                    Some(&pb),
+                ),
+            }
+        } else {
+            None
+        };
+
+        if let Some(lock) = lock
+            && let Err(e) = lock.release().await
+        {
+            pb.suspend(|| emit_warning(&e, "lock_release_failed"));
+        }
+
+        if !is_json_mode() {
+            pb.suspend(|| print_refcount_drift(&drifted, dry_run, previous_index.as_deref()));
+        }
+
+        Some(RefcountRepair {
+            dry_run,
+            previous_index,
+            drifted,
+        })
+    } else {
+        None
+    };
+
+    let mut problems = Vec::new();
+
+    // chunk -> backups referencing it
+    let mut unindexed: BTreeMap<&String, Vec<String>> = BTreeMap::new();
+    for backup in &backups {
+        for chunk in backup
+            .tree
+            .values()
+            .flat_map(|object| object.chunks.iter())
+            .filter(|chunk| !chunk_indexes.contains_key(*chunk))
+            .collect::<HashSet<&String>>()
+        {
+            unindexed
+                .entry(chunk)
+                .or_default()
+                .push(backup.hash.clone());
+        }
+    }
+
+    for (chunk, mut backups) in unindexed {
+        backups.sort();
+        problems.push(CheckProblem {
+            code: "unindexed_chunk",
+            chunk: chunk.clone(),
+            detail: format!(
+                "referenced by {} backups but not in the chunk index",
+                backups.len()
+            ),
+            backups,
+        });
+    }
+
+    set_progress_message(&pb, "Listing stored chunks...");
+
+    let stored_chunks = match fs.list_files(&format!("{}/chunks", key)).await {
+        Ok(chunks) => chunks
+            .iter()
+            .filter_map(|chunk| chunk_hash_from_path(chunk))
+            .filter(|chunk_hash| is_chunk_hash(chunk_hash))
+            .collect::<HashSet<String>>(),
+        Err(e) => handle_error(format!("Failed to list chunks: {}", e), Some(&pb)),
+    };
+
+    set_progress_message(&pb, "Loading pending backups...");
+
+    let pending_chunks = load_pending_chunks(&fs, &key, &password, &pb).await;
+
+    let (pending_only_chunks, mut orphaned_chunks): (Vec<&String>, Vec<&String>) = stored_chunks
+        .iter()
+        .filter(|chunk_hash| !chunk_indexes.contains_key(*chunk_hash))
+        .partition(|chunk_hash| pending_chunks.contains(*chunk_hash));
+    orphaned_chunks.sort();
+
+    for chunk in &orphaned_chunks {
+        problems.push(CheckProblem {
+            code: "orphaned_chunk",
+            chunk: chunk.to_string(),
+            detail: "stored but not in the chunk index".to_string(),
+            backups: Vec::new(),
+        });
+    }
+
+    let mut chunks = Vec::new();
+    for chunk_hash in chunk_indexes.keys() {
+        if stored_chunks.contains(chunk_hash) {
+            chunks.push(chunk_hash.clone());
+        } else {
+            problems.push(CheckProblem {
+                code: "missing_chunk",
+                chunk: chunk_hash.clone(),
+                detail: "missing".to_string(),
+                backups: Vec::new(),
+            });
+        }
+    }
+    chunks.sort();
+
+    pb.finish_and_clear();
+
+    let json_progress = if is_json_mode() {
+        let progress = JsonProgress::new(chunks.len() as u64);
+        progress.set_message("Checking chunks...");
+        Some(progress)
+    } else {
+        None
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(chunks.len() as u64);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap(),
+        );
+        pb.set_message("Checking chunks...");
+        pb
+    };
+
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let mut tasks = JoinSet::new();
+
+    for chunk_hash in &chunks {
+        let fs = Arc::clone(&fs);
+        let path = chunk_path(&key, chunk_hash, chunk_dir_levels);
+        let chunk_hash = chunk_hash.clone();
+        let password = password.clone();
+        let semaphore = Arc::clone(&semaphore);
+        let pb = pb.clone();
+        let json_progress = json_progress.clone();
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire().await.expect("Semaphore closed");
+            let result = fetch_verified_chunk(&fs, &path, &chunk_hash, password.as_deref()).await;
+
+            pb.inc(1);
+            if let Some(progress) = &json_progress {
+                progress.inc_by(1);
+            }
+
+            result
+                .map(|bytes| bytes.len() as u64)
+                .map_err(|e| CheckProblem {
+                    code: e.reason_code(),
+                    chunk: chunk_hash,
+                    detail: e.detail().to_string(),
+                    backups: Vec::new(),
+                })
+        });
+    }
+
+    let mut bytes_verified = 0;
+    let mut chunks_verified = 0;
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(bytes)) => {
+                bytes_verified += bytes;
+                chunks_verified += 1;
+            }
+            Ok(Err(problem)) => problems.push(problem),
+            Err(e) => handle_error(format!("Failed to check a chunk: {}", e), Some(&pb)),
+        }
+    }
+
+    if let Some(progress) = &json_progress {
+        progress.finish();
+    }
+
+    problems.sort_by(|a, b| a.chunk.cmp(&b.chunk).then(a.code.cmp(b.code)));
+
+    let count = |codes: &[&str]| {
+        problems
+            .iter()
+            .filter(|problem| codes.contains(&problem.code))
+            .count()
+    };
+
+    let payload = CheckOutput {
+        backups_checked: backups.len(),
+        chunks_indexed: chunk_indexes.len(),
+        chunks_stored: stored_chunks.len(),
+        chunks_verified,
+        bytes_verified,
+        missing_chunks: count(&["missing_chunk"]),
+        corrupt_chunks: count(&["decrypt_error", "decompress_error", "corrupt_chunk"]),
+        unindexed_chunks: count(&["unindexed_chunk"]),
+        orphaned_chunks: count(&["orphaned_chunk"]),
+        problems,
+        refcount_repair,
+        elapsed_ms: started_at.elapsed().as_millis() as u64,
+    };
+
+    if !payload.problems.is_empty() {
+        let message = format!(
+            "Found {} problems ({} missing, {} corrupt, {} unindexed and {} orphaned chunks):\n{}",
+            payload.problems.len(),
+            payload.missing_chunks,
+            payload.corrupt_chunks,
+            payload.unindexed_chunks,
+            payload.orphaned_chunks,
+            payload
+                .problems
+                .iter()
+                .map(|problem| format!(
+                    "  - {} ({}): {}",
+                    problem.chunk, problem.code, problem.detail
+                ))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+
+        if is_json_mode() {
+            emit_error_with_details(&message, "check_failed", &payload);
+        }
+        handle_error(message, Some(&pb));
+    }
+
+    if is_json_mode() {
+        emit_output(&payload);
+    } else {
+        let elapsed = pb.elapsed();
+        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
+        pb.set_prefix("OK");
+        pb.finish_with_message(format!(
+            "Checked {} chunks of {} backups ({}, {:.2?})",
+            payload.chunks_verified,
+            payload.backups_checked,
+            ByteSize(payload.bytes_verified),
+            elapsed
+        ));
+
+        if !pending_only_chunks.is_empty() {
+            println!(
+                "{}",
+                style(format!(
+                    "{} stored chunks are only used by pending backups, see 'gib backup pending'",
+                    pending_only_chunks.len()
+                ))
+                .dim()
+            );
+        }
+    }
+}
+
+/// The chunks whose refcount in the index is not the number of times the backups reference
+/// them, counted like `gib reindex` does: once per file that holds the chunk, in every
+/// backup. Sorted by chunk.
+fn find_refcount_drift(
+    chunk_indexes: &HashMap<String, ChunkIndex>,
+    backups: &[Backup],
+) -> Vec<RefcountDrift> {
+    let mut expected: HashMap<&String, u32> = HashMap::new();
+    for chunk in backups
+        .iter()
+        .flat_map(|backup| backup.tree.values())
+        .flat_map(|object| object.chunks.iter())
+    {
+        *expected.entry(chunk).or_default() += 1;
+    }
+
+    let mut drifted = chunk_indexes
+        .iter()
+        .map(|(chunk, index)| (chunk, index.refcount))
+        .chain(
+            expected
+                .keys()
+                .filter(|chunk| !chunk_indexes.contains_key(**chunk))
+                .map(|chunk| (*chunk, 0)),
+        )
+        .filter_map(|(chunk, indexed)| {
+            let expected = expected.get(chunk).copied().unwrap_or(0);
+            (indexed != expected).then(|| RefcountDrift {
+                chunk: chunk.clone(),
+                indexed,
+                expected,
+            })
+        })
+        .collect::<Vec<RefcountDrift>>();
+
+    drifted.sort_by(|a, b| a.chunk.cmp(&b.chunk));
+    drifted
+}
+
+/// Sets the refcount of every drifted chunk to the expected one. A chunk no backup
+/// references leaves the index, like it would after the last backup using it was deleted.
+fn apply_refcount_drift(
+    chunk_indexes: &mut HashMap<String, ChunkIndex>,
+    drifted: &[RefcountDrift],
+) {
+    for drift in drifted {
+        if drift.expected == 0 {
+            chunk_indexes.remove(&drift.chunk);
+        } else {
+            chunk_indexes.insert(
+                drift.chunk.clone(),
+                ChunkIndex {
+                    refcount: drift.expected,
+                },
+            );
+        }
+    }
+}
+
+fn print_refcount_drift(drifted: &[RefcountDrift], dry_run: bool, previous_index: Option<&str>) {
+    if drifted.is_empty() {
+        println!(
+            "{}",
+            style("Every chunk refcount matches the backups").dim()
+        );
+        return;
+    }
+
+    if dry_run {
+        println!(
+            "{} chunk refcounts do not match the backups (dry run, nothing was written):",
+            drifted.len()
+        );
+    } else {
+        println!(
+            "Repaired {} chunk refcounts{}:",
+            drifted.len(),
+            previous_index
+                .map(|path| format!(" (previous index kept at '{}')", path))
+                .unwrap_or_default()
+        );
+    }
+
+    for drift in drifted {
+        println!(
+            "  - {}: {} -> {}",
+            drift.chunk, drift.indexed, drift.expected
+        );
+    }
+}
+
+fn set_progress_message(pb: &ProgressBar, message: &str) {
+    pb.set_message(message.to_string());
+    if is_json_mode() {
+        emit_progress_message(message);
+    }
+}
+
+/// Listings also hold `.bak` copies and other leftovers, which are not chunks.
+fn is_chunk_hash(chunk_hash: &str) -> bool {
+    chunk_hash.len() == 64 && chunk_hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+async fn load_backups(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    summaries: &[BackupSummary],
+) -> Result<Vec<Backup>, String> {
+    stream::iter(summaries)
+        .map(|summary| {
+            let fs = Arc::clone(fs);
+            let key = key.to_string();
+            let password = password.clone();
+            async move { load_summary_backup(fs, key, password, summary).await }
+        })
+        .buffer_unordered(MAX_CONCURRENT_MANIFEST_LOADS)
+        .collect::<Vec<Result<Backup, String>>>()
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// The chunks uploaded by backups that are still pending. They are not in the chunk index
+/// until the backup finishes, so they are not orphaned.
+async fn load_pending_chunks(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    pb: &ProgressBar,
+) -> HashSet<String> {
+    let pending_prefix = format!("{}/indexes/pending_", key);
+
+    let pending_paths = match fs.list_files(&format!("{}/indexes", key)).await {
+        Ok(indexes) => indexes
+            .into_iter()
+            .filter(|index| index.starts_with(&pending_prefix))
+            .collect::<Vec<String>>(),
+        Err(e) => handle_error(format!("Failed to list indexes: {}", e), Some(pb)),
+    };
+
+    let mut pending_chunks = HashSet::new();
+
+    for pending_path in pending_paths {
+        match load_pending_backup(fs, &pending_path, password.as_deref()).await {
+            Ok(pending_backup) => pending_chunks.extend(pending_backup.processed_chunks),
+            Err(e) => pb.suspend(|| {
+                emit_warning(
+                    &format!("Skipping pending backup '{}': {}", pending_path, e),
+                    "pending_unreadable",
+                )
+            }),
+        }
+    }
+
+    pending_chunks
+}
+
+async fn load_pending_backup(
+    fs: &Arc<dyn FS>,
+    pending_path: &str,
+    password: Option<&str>,
+) -> Result<PendingBackup, String> {
+    let pending_result = read_file_maybe_decrypt(
+        fs,
+        pending_path,
+        password,
+        "Pending backup is encrypted but no password provided",
+    )
+    .await?;
+
+    if pending_result.bytes.is_empty() {
+        return Err("the file is empty".to_string());
+    }
+
+    let decompressed_bytes = decompress_bytes(&pending_result.bytes)
+        .map_err(|e| format!("Failed to decompress pending backup: {}", e))?;
+
+    rmp_serde::from_slice(&decompressed_bytes)
+        .map_err(|e| format!("Failed to deserialize pending backup: {}", e))
+}
+
+fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>), String> {
+    let password: Option<String> = matches
+        .get_one::<String>("password")
+        .map(|s| s.to_string())
+        .map_or_else(
+            || get_password(false, true),
+            |password| Some(password.to_string()),
+        );
+
+    let pwd_string = get_pwd_string();
+
+    let folder_key = Path::new(&pwd_string)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    Ok((key, storage, password))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::metadata::BackupObject;
+
+    fn backup(hash: &str, files: &[&[&str]]) -> Backup {
+        Backup {
+            message: hash.to_string(),
+            hash: hash.to_string(),
+            timestamp: 0,
+            author: "test".to_string(),
+            tree: files
+                .iter()
+                .enumerate()
+                .map(|(index, chunks)| {
+                    (format!("file-{}", index), BackupObject::with_chunks(chunks))
+                })
+                .collect(),
+            base: None,
+            removed: Vec::new(),
+        }
+    }
+
+    fn indexes(refcounts: &[(&str, u32)]) -> HashMap<String, ChunkIndex> {
+        refcounts
+            .iter()
+            .map(|(chunk, refcount)| {
+                (
+                    chunk.to_string(),
+                    ChunkIndex {
+                        refcount: *refcount,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn refcount_drift_counts_every_reference_of_every_backup() {
+        let backups = [
+            backup("one", &[&["a", "b"], &["a"]]),
+            backup("two", &[&["a"], &["c"]]),
+        ];
+        let chunk_indexes = indexes(&[("a", 3), ("b", 1), ("c", 5), ("gone", 2)]);
+
+        let drifted = find_refcount_drift(&chunk_indexes, &backups)
+            .into_iter()
+            .map(|drift| (drift.chunk, drift.indexed, drift.expected))
+            .collect::<Vec<(String, u32, u32)>>();
+
+        assert_eq!(
+            drifted,
+            vec![("c".to_string(), 5, 1), ("gone".to_string(), 2, 0)]
+        );
+    }
+
+    #[test]
+    fn refcount_drift_reports_referenced_chunks_missing_from_the_index() {
+        let backups = [backup("one", &[&["a", "b"]])];
+        let chunk_indexes = indexes(&[("a", 1)]);
+
+        let drifted = find_refcount_drift(&chunk_indexes, &backups);
+
+        assert_eq!(drifted.len(), 1);
+        assert_eq!(drifted[0].chunk, "b");
+        assert_eq!(drifted[0].indexed, 0);
+        assert_eq!(drifted[0].expected, 1);
+    }
+
+    #[test]
+    fn applying_the_drift_only_touches_drifted_chunks() {
+        let backups = [backup("one", &[&["a", "b"], &["b"]])];
+        let mut chunk_indexes = indexes(&[("a", 1), ("b", 7), ("gone", 1)]);
+
+        let drifted = find_refcount_drift(&chunk_indexes, &backups);
+        apply_refcount_drift(&mut chunk_indexes, &drifted);
+
+        assert_eq!(chunk_indexes, indexes(&[("a", 1), ("b", 2)]));
+        assert!(find_refcount_drift(&chunk_indexes, &backups).is_empty());
+    }
+}
@@ -1,25 +1,74 @@
 use clap::ArgMatches;
+use console::style;
 use dialoguer::Input;
 use dirs::home_dir;
 use indicatif::{ProgressBar, ProgressStyle};
 use rmp_serde::Serializer;
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use crate::output::{JsonProgress, emit_output, is_json_mode};
+use crate::commands::storage::add::{StorageFormat, encode_storage_config, parse_storage_config};
+use crate::output::{
+    JsonProgress, can_prompt, emit_error, emit_error_with_details, emit_output, is_json_mode,
+};
 use crate::utils::handle_error;
 
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct Config {
     pub author: String,
+    #[serde(default)]
+    pub default_storage: Option<String>,
+    #[serde(default)]
+    pub default_compress: Option<i32>,
+    #[serde(default)]
+    pub default_key: Option<String>,
 }
 
 pub fn config(matches: &ArgMatches) {
-    let author = matches.get_one::<String>("author").map_or_else(
-        || {
-            if is_json_mode() {
+    if matches.get_flag("migrate-storages") {
+        migrate_storages();
+        return;
+    }
+
+    let config_path = get_config_path();
+
+    let existing_config = if config_path.exists() {
+        match read_config_file(&config_path) {
+            Ok(config) => Some(config),
+            Err(e) => handle_error(e, None),
+        }
+    } else {
+        None
+    };
+
+    if matches.get_flag("show") {
+        match existing_config {
+            Some(config) => show_config(&config, &config_path),
+            None => handle_error(
+                "Seems like you didn't configure your backup tool yet. Run 'gib config' to configure your backup tool.".to_string(),
+                None,
+            ),
+        }
+        return;
+    }
+
+    let author_arg = matches.get_one::<String>("author");
+    let default_storage_arg = matches.get_one::<String>("default-storage");
+    let default_compress_arg = matches.get_one::<String>("default-compress");
+    let default_key_arg = matches.get_one::<String>("default-key");
+
+    let has_setting_args = default_storage_arg.is_some()
+        || default_compress_arg.is_some()
+        || default_key_arg.is_some();
+
+    let author = match (author_arg, &existing_config) {
+        (Some(author), _) => author.to_string(),
+        (None, Some(config)) if has_setting_args => config.author.clone(),
+        _ => {
+            if !can_prompt() {
                 handle_error(
-                    "Missing required argument: --author (required in --mode json)".to_string(),
+                    "Missing required argument: --author (required in --mode json or with --non-interactive)".to_string(),
                     None,
                 );
             }
@@ -31,19 +80,56 @@ pub fn config(matches: &ArgMatches) {
                 });
 
             typed_author
-        },
-        |author| author.to_string(),
-    );
+        }
+    };
 
-    let author_pattern =
-        regex::Regex::new(r"^[A-Za-z]+(?: [A-Za-z]+)+(?: )?<[^@ ]+@[^@ ]+\.[^@ >]+>$").unwrap();
+    let author = match validate_author(&author) {
+        Ok(author) => author,
+        Err(e) if is_json_mode() => emit_error(&e.to_string(), e.reason_code()),
+        Err(e) => handle_error(e.to_string(), None),
+    };
 
-    if !author_pattern.is_match(&author) {
-        handle_error(
-            "The author must be in the format 'Firstname Lastname <email>'".to_string(),
-            None,
-        );
-    }
+    let default_storage = match default_storage_arg {
+        Some(storage) if storage.is_empty() => None,
+        Some(storage) => {
+            let storage_path = home_dir()
+                .unwrap()
+                .join(".gib")
+                .join("storages")
+                .join(format!("{}.msgpack", storage));
+
+            if !storage_path.exists() {
+                handle_error(format!("Storage '{}' not found", storage), None);
+            }
+
+            Some(storage.to_string())
+        }
+        None => existing_config
+            .as_ref()
+            .and_then(|config| config.default_storage.clone()),
+    };
+
+    let default_compress = match default_compress_arg {
+        Some(compress) if compress.is_empty() => None,
+        Some(compress) => match parse_default_compress(compress) {
+            Ok(level) => Some(level),
+            Err(e) => handle_error(e, None),
+        },
+        None => existing_config
+            .as_ref()
+            .and_then(|config| config.default_compress),
+    };
+
+    let default_key = match default_key_arg {
+        Some(key) if key.is_empty() => None,
+        Some(key) => match validate_default_key(key) {
+            Ok(key) => Some(key),
+            Err(e) => handle_error(e, None),
+        },
+        None => existing_config
+            .as_ref()
+            .and_then(|config| config.default_key.clone()),
+    };
 
     let json_progress = if is_json_mode() {
         let progress = JsonProgress::new(1);
@@ -63,25 +149,24 @@ pub fn config(matches: &ArgMatches) {
         pb
     };
 
-    let config = Config { author };
+    let config = Config {
+        author,
+        default_storage,
+        default_compress,
+        default_key,
+    };
 
     let mut buf = Vec::new();
     config
         .serialize(&mut Serializer::new(&mut buf))
         .unwrap_or_else(|e| handle_error(format!("Failed to serialize config: {}", e), None));
 
-    let home_dir = home_dir().unwrap();
-
-    let mut config_path = home_dir.join(".gib");
-
-    if !config_path.exists() {
-        std::fs::create_dir_all(&config_path).unwrap_or_else(|e| {
+    if let Some(config_dir) = config_path.parent() {
+        std::fs::create_dir_all(config_dir).unwrap_or_else(|e| {
             handle_error(format!("Failed to create config directory: {}", e), None)
         });
     }
 
-    config_path.push("config.msgpack");
-
     std::fs::write(&config_path, buf)
         .unwrap_or_else(|e| handle_error(format!("Failed to write config: {}", e), None));
 
@@ -90,17 +175,7 @@ pub fn config(matches: &ArgMatches) {
     }
 
     if is_json_mode() {
-        #[derive(Serialize)]
-        struct ConfigOutput {
-            author: String,
-            path: String,
-        }
-
-        let payload = ConfigOutput {
-            author: config.author,
-            path: config_path.to_string_lossy().to_string(),
-        };
-        emit_output(&payload);
+        emit_output(&ConfigOutput::new(&config, &config_path));
     } else {
         let elapsed = pb.elapsed();
 
@@ -109,3 +184,410 @@ pub fn config(matches: &ArgMatches) {
         pb.finish_with_message(format!("Config written ({:.2?})", elapsed));
     }
 }
+
+/// Why `validate_author` rejected an author.
+#[derive(Debug, PartialEq)]
+pub(crate) enum AuthorError {
+    /// Nothing comes before the email.
+    MissingName(String),
+    /// No email in angle brackets at the end, or one without an `@` or a dot in its domain.
+    InvalidEmail(String),
+}
+
+impl AuthorError {
+    pub(crate) fn reason_code(&self) -> &'static str {
+        "invalid_author"
+    }
+}
+
+impl std::fmt::Display for AuthorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (author, reason) = match self {
+            AuthorError::MissingName(author) => (author, "the name is missing"),
+            AuthorError::InvalidEmail(author) => (
+                author,
+                "expected a name followed by an email in angle brackets",
+            ),
+        };
+
+        write!(
+            f,
+            "Invalid author '{}': {}. The author must be in the format 'Name <email>' (e.g. 'Jürgen Müller <jurgen@example.com>')",
+            author, reason
+        )
+    }
+}
+
+/// Parses `--default-compress`: a zstd level from 1 to 22, or `store` (or 0) to write the
+/// chunks of backups uncompressed, as `backup --compress store` does.
+fn parse_default_compress(compress: &str) -> Result<i32, String> {
+    if compress.eq_ignore_ascii_case("store") {
+        return Ok(0);
+    }
+
+    match compress.parse::<i32>() {
+        Ok(level) if (0..=22).contains(&level) => Ok(level),
+        _ => Err(format!(
+            "Invalid compression level '{}': it must be between 1 and 22, or 'store' (or 0) to store chunks uncompressed",
+            compress
+        )),
+    }
+}
+
+/// Checks that `key` can name a repository: a single path segment, since the key is the
+/// repository's folder in every storage.
+fn validate_default_key(key: &str) -> Result<String, String> {
+    let key = key.trim();
+
+    if key.is_empty() || key == "." || key == ".." || key.contains(['/', '\\']) {
+        return Err(format!(
+            "Invalid key '{}': it must be a single name without '/' or '\\'",
+            key
+        ));
+    }
+
+    Ok(key.to_string())
+}
+
+/// Checks that `author` looks like `Name <email>` and returns it trimmed. Any non-empty
+/// name is accepted (mononyms, non-ASCII, company names); the email only needs an `@`
+/// and a dot in its domain.
+fn validate_author(author: &str) -> Result<String, AuthorError> {
+    let author = author.trim();
+
+    let author_pattern =
+        regex::Regex::new(r"^(?<name>[^<>]+?)\s*<(?<email>[^@\s<>]+@[^@\s<>]+\.[^@\s<>]+)>$")
+            .unwrap();
+
+    let Some(captures) = author_pattern.captures(author) else {
+        return Err(if author.starts_with('<') {
+            AuthorError::MissingName(author.to_string())
+        } else {
+            AuthorError::InvalidEmail(author.to_string())
+        });
+    };
+
+    Ok(format!(
+        "{} <{}>",
+        captures["name"].trim(),
+        &captures["email"]
+    ))
+}
+
+/// Reads the config file if it exists, returning `None` when gib isn't configured yet
+/// or the file can't be parsed. Commands use it to pick up configured defaults.
+pub fn read_config() -> Option<Config> {
+    read_config_file(&get_config_path()).ok()
+}
+
+pub fn default_storage() -> Option<String> {
+    read_config().and_then(|config| config.default_storage)
+}
+
+/// The repository key commands use when `--key` is not provided, before falling back to
+/// the name of the current folder.
+pub fn default_key() -> Option<String> {
+    read_config().and_then(|config| config.default_key)
+}
+
+pub(crate) fn get_config_path() -> PathBuf {
+    home_dir().unwrap().join(".gib").join("config.msgpack")
+}
+
+pub(crate) fn read_config_file(config_path: &Path) -> Result<Config, String> {
+    let config_bytes =
+        std::fs::read(config_path).map_err(|e| format!("Failed to read config file: {}", e))?;
+
+    rmp_serde::from_slice(&config_bytes).map_err(|e| format!("Failed to deserialize config: {}", e))
+}
+
+#[derive(Serialize)]
+struct ConfigOutput {
+    author: String,
+    default_storage: Option<String>,
+    default_compress: Option<i32>,
+    default_key: Option<String>,
+    path: String,
+}
+
+impl ConfigOutput {
+    fn new(config: &Config, config_path: &Path) -> Self {
+        Self {
+            author: config.author.clone(),
+            default_storage: config.default_storage.clone(),
+            default_compress: config.default_compress,
+            default_key: config.default_key.clone(),
+            path: config_path.to_string_lossy().to_string(),
+        }
+    }
+}
+
+/// Rewrites every storage still in the legacy format as a `StorageConfig`. Each file is
+/// written next to the original and renamed over it, so an interrupted run never leaves a
+/// storage half written.
+fn migrate_storages() {
+    let storages_path = home_dir().unwrap().join(".gib").join("storages");
+
+    let mut names = match std::fs::read_dir(&storages_path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .strip_suffix(".msgpack")
+                    .map(|name| name.to_string())
+            })
+            .collect::<Vec<String>>(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => handle_error(format!("Failed to read storages: {}", e), None),
+    };
+    names.sort();
+
+    let mut migrated = Vec::new();
+    let mut current = Vec::new();
+    let mut failed = Vec::new();
+
+    for name in names {
+        let storage_path = storages_path.join(format!("{}.msgpack", name));
+
+        match migrate_storage(&storage_path) {
+            Ok(true) => migrated.push(name),
+            Ok(false) => current.push(name),
+            Err(error) => failed.push(MigrationFailure { name, error }),
+        }
+    }
+
+    if is_json_mode() {
+        #[derive(Serialize)]
+        struct MigrateOutput {
+            migrated: Vec<String>,
+            already_current: Vec<String>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            failed: Vec<MigrationFailure>,
+        }
+
+        if !failed.is_empty() {
+            emit_error_with_details(
+                &format!("Failed to migrate {} storages", failed.len()),
+                "storage_migration_failed",
+                &MigrateOutput {
+                    migrated,
+                    already_current: current,
+                    failed,
+                },
+            );
+        }
+
+        emit_output(&MigrateOutput {
+            migrated,
+            already_current: current,
+            failed,
+        });
+        return;
+    }
+
+    for name in &migrated {
+        println!("Migrated '{}'", name);
+    }
+
+    if !failed.is_empty() {
+        handle_error(
+            format!(
+                "Failed to migrate {} storages:\n{}",
+                failed.len(),
+                failed
+                    .iter()
+                    .map(|failure| format!("  - {}: {}", failure.name, failure.error))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+            None,
+        );
+    }
+
+    match migrated.len() {
+        0 if current.is_empty() => println!("No storages found."),
+        0 => println!(
+            "All {} storages are already in the current format.",
+            current.len()
+        ),
+        count => println!(
+            "{} {} migrated, {} already in the current format.",
+            style("OK").green(),
+            count,
+            current.len()
+        ),
+    }
+}
+
+/// Rewrites the storage at `storage_path` in the current format, returning whether it was
+/// still in the legacy one.
+pub(crate) fn migrate_storage(storage_path: &Path) -> Result<bool, String> {
+    let bytes =
+        std::fs::read(storage_path).map_err(|e| format!("Failed to read storage: {}", e))?;
+    let (storage, format) =
+        parse_storage_config(&bytes).map_err(|e| format!("Failed to parse storage: {}", e))?;
+
+    if format == StorageFormat::Current {
+        return Ok(false);
+    }
+
+    let bytes = encode_storage_config(&storage)
+        .map_err(|e| format!("Failed to serialize storage: {}", e))?;
+
+    let mut temp_path = storage_path.to_path_buf().into_os_string();
+    temp_path.push(format!(".tmp-{}", std::process::id()));
+    let temp_path = PathBuf::from(temp_path);
+
+    // Keep the mode of the original, which holds credentials.
+    std::fs::write(&temp_path, bytes)
+        .and_then(|()| std::fs::metadata(storage_path))
+        .and_then(|metadata| std::fs::set_permissions(&temp_path, metadata.permissions()))
+        .and_then(|()| std::fs::rename(&temp_path, storage_path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&temp_path);
+            format!("Failed to write storage: {}", e)
+        })?;
+
+    Ok(true)
+}
+
+#[derive(Serialize)]
+struct MigrationFailure {
+    name: String,
+    error: String,
+}
+
+fn show_config(config: &Config, config_path: &Path) {
+    if is_json_mode() {
+        emit_output(&ConfigOutput::new(config, config_path));
+        return;
+    }
+
+    println!("Author: {}", config.author);
+    println!(
+        "Default storage: {}",
+        config.default_storage.as_deref().unwrap_or("(not set)")
+    );
+    println!(
+        "Default compression: {}",
+        config
+            .default_compress
+            .map_or("(not set)".to_string(), |level| match level {
+                0 => "store".to_string(),
+                level => level.to_string(),
+            })
+    );
+    println!(
+        "Default key: {}",
+        config
+            .default_key
+            .as_deref()
+            .unwrap_or("(not set, the current folder's name)")
+    );
+    println!("Path: {}", config_path.display());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_international_names() {
+        for author in [
+            "Jürgen Müller <jurgen@example.com>",
+            "Beyoncé <beyonce@example.com>",
+            "李小龙 <bruce@example.cn>",
+            "Αριστοτέλης <aristotle@example.gr>",
+            "Дмитрий Иванов <dmitry@example.ru>",
+            "محمد علي <mohamed@example.eg>",
+            "Acme Corp. <ops@acme.io>",
+        ] {
+            assert_eq!(validate_author(author), Ok(author.to_string()));
+        }
+    }
+
+    #[test]
+    fn trims_the_spacing_around_the_name() {
+        assert_eq!(
+            validate_author("  Zoë   <zoe@example.com> "),
+            Ok("Zoë <zoe@example.com>".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_an_author_without_a_name() {
+        let error = validate_author(" <zoe@example.com>").unwrap_err();
+
+        assert_eq!(
+            error,
+            AuthorError::MissingName("<zoe@example.com>".to_string())
+        );
+        assert_eq!(error.reason_code(), "invalid_author");
+        assert!(error.to_string().contains("'Name <email>'"));
+    }
+
+    #[test]
+    fn rejects_an_author_without_a_valid_email() {
+        for author in [
+            "Jürgen Müller",
+            "Jürgen Müller jurgen@example.com",
+            "Jürgen Müller <jurgen>",
+            "Jürgen Müller <jurgen@example>",
+            "Jürgen Müller <jur gen@example.com>",
+        ] {
+            assert_eq!(
+                validate_author(author),
+                Err(AuthorError::InvalidEmail(author.to_string())),
+                "{}",
+                author
+            );
+        }
+    }
+
+    #[test]
+    fn default_compress_accepts_levels_and_store() {
+        assert_eq!(parse_default_compress("1"), Ok(1));
+        assert_eq!(parse_default_compress("22"), Ok(22));
+        assert_eq!(parse_default_compress("0"), Ok(0));
+        assert_eq!(parse_default_compress("store"), Ok(0));
+        assert_eq!(parse_default_compress("STORE"), Ok(0));
+
+        for invalid in ["23", "-1", "fast", ""] {
+            assert!(parse_default_compress(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn default_key_must_be_a_single_name() {
+        assert_eq!(
+            validate_default_key(" my-repository "),
+            Ok("my-repository".to_string())
+        );
+
+        for invalid in ["a/b", "a\\b", "..", ".", "  "] {
+            assert!(validate_default_key(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn configs_written_before_default_key_still_load() {
+        #[derive(Serialize)]
+        struct OldConfig {
+            author: String,
+            default_storage: Option<String>,
+        }
+
+        let bytes = rmp_serde::to_vec_named(&OldConfig {
+            author: "Zoë <zoe@example.com>".to_string(),
+            default_storage: Some("local".to_string()),
+        })
+        .unwrap();
+
+        let config: Config = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(config.default_key, None);
+        assert_eq!(config.default_compress, None);
+        assert_eq!(config.default_storage.as_deref(), Some("local"));
+    }
+}
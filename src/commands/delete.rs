@@ -1,18 +1,28 @@
+use crate::commands::config::{default_key, default_storage};
 use crate::core::crypto::get_password;
-use crate::core::crypto::read_file_maybe_decrypt;
 use crate::core::crypto::write_file_maybe_encrypt;
-use crate::core::indexes::{list_backup_summaries, load_chunk_indexes};
-use crate::core::metadata::Backup;
+use crate::core::indexes::{
+    backfill_backup_summaries, list_backup_summaries, load_backup, load_chunk_indexes,
+    load_repository_meta, load_summary_backup, save_backup_summaries, save_chunk_indexes,
+};
+use crate::core::layout::{backup_path, backup_summaries_path, chunk_index_path, chunk_path};
+use crate::core::metadata::{Backup, BackupSummary, ChunkIndex, TrashEntry};
+use crate::core::retention::RetentionPolicy;
+use crate::core::signing::remove_signature;
+use crate::core::trash::copy_to_trash;
 use crate::fs::FS;
-use crate::output::{JsonProgress, emit_output, emit_progress_message, is_json_mode};
+use crate::output::{JsonProgress, can_prompt, emit_output, emit_progress_message, is_json_mode};
 use crate::utils::{
-    compress_bytes, decompress_bytes, get_fs, get_pwd_string, get_storage, handle_error,
+    compress_bytes, get_fs, get_pwd_string, get_storage, handle_error, parse_duration,
 };
+use chrono::{DateTime, Local, Utc};
 use clap::ArgMatches;
+use console::style;
 use dialoguer::Select;
 use dirs::home_dir;
 use futures::stream::{self, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::HashMap;
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
@@ -29,10 +39,41 @@ pub async fn delete(matches: &ArgMatches) {
 
     let started_at = Instant::now();
 
+    let retention = match retention_policy(matches) {
+        Ok(retention) => retention,
+        Err(e) => handle_error(e, None),
+    };
+
+    if retention.is_empty() && matches.get_flag("dry-run") {
+        handle_error(
+            "--dry-run needs --keep-last, --keep-daily, --keep-weekly or --keep-monthly"
+                .to_string(),
+            None,
+        );
+    }
+
+    if !retention.is_empty() {
+        delete_by_retention(matches, key, storage, password, retention, started_at).await;
+        return;
+    }
+
+    let trash_retention = match matches.get_one::<String>("trash-retention") {
+        Some(retention) => match parse_duration(retention, "--trash-retention") {
+            Ok(retention) => Some(retention),
+            Err(e) => handle_error(e, None),
+        },
+        None => None,
+    };
+
     let storage = get_storage(&storage);
 
     let fs = get_fs(&storage, None);
 
+    let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+        Err(e) => handle_error(e, None),
+    };
+
     let full_backup_hash = match resolve_backup_hash(
         Arc::clone(&fs),
         key.clone(),
@@ -106,28 +147,80 @@ pub async fn delete(matches: &ArgMatches) {
         emit_progress_message("Processing chunks...");
     }
 
+    let dependents = backup_summaries
+        .iter()
+        .filter(|summary| summary.base.as_deref() == Some(full_backup_hash.as_str()))
+        .map(|summary| summary.hash[..8.min(summary.hash.len())].to_string())
+        .collect::<Vec<String>>();
+
+    if !dependents.is_empty() {
+        handle_error(
+            format!(
+                "Backup {} is the base of the differential backups {}; delete them first",
+                &full_backup_hash[..8.min(full_backup_hash.len())],
+                dependents.join(", ")
+            ),
+            Some(&pb),
+        );
+    }
+
+    let deleted_summary = backup_summaries
+        .iter()
+        .find(|summary| summary.hash == full_backup_hash)
+        .cloned();
+
+    let manifest_hash = deleted_summary
+        .as_ref()
+        .map(|summary| summary.manifest_hash().to_string())
+        .unwrap_or_else(|| full_backup_hash.clone());
+
     backup_summaries.retain(|summary| summary.hash != full_backup_hash);
 
-    let chunks_to_delete = Arc::new(Mutex::new(Vec::<String>::new()));
+    // A content-addressed manifest stays as long as another backup still shares it.
+    let manifest_shared = backup_summaries
+        .iter()
+        .any(|summary| summary.manifest_hash() == manifest_hash);
 
-    for (_relative_path, backup_object) in backup.tree.iter() {
-        for chunk_hash in &backup_object.chunks {
-            if let Some(chunk_index) = chunk_indexes.get_mut(chunk_hash) {
-                if chunk_index.refcount > 0 {
-                    chunk_index.refcount -= 1;
+    let chunks_to_delete_vec = release_chunks(&backup, &mut chunk_indexes);
 
-                    if chunk_index.refcount == 0 {
-                        chunks_to_delete.lock().unwrap().push(chunk_hash.clone());
-                    }
-                }
+    // Everything that is about to be deleted is copied to the trash first, so that
+    // `gib trash restore` can put the backup back until the entry expires.
+    let trash_entry = match trash_retention {
+        Some(retention) => {
+            pb.set_message("Moving the backup to the trash...");
+            if is_json_mode() {
+                emit_progress_message("Moving the backup to the trash...");
             }
-        }
-    }
 
-    let chunks_to_delete_vec = chunks_to_delete.lock().unwrap().clone();
-    for chunk_hash in &chunks_to_delete_vec {
-        chunk_indexes.remove(chunk_hash);
-    }
+            let key_prefix = format!("{}/", key);
+            let mut objects = chunks_to_delete_vec
+                .iter()
+                .map(|chunk_hash| chunk_path(&key, chunk_hash, chunk_dir_levels))
+                .collect::<Vec<String>>();
+            if !manifest_shared {
+                objects.push(backup_path(&key, &manifest_hash));
+            }
+            let objects = objects
+                .iter()
+                .map(|object| object.trim_start_matches(&key_prefix).to_string())
+                .collect::<Vec<String>>();
+
+            let entry = TrashEntry::new(
+                full_backup_hash.clone(),
+                "backup",
+                deleted_summary.clone(),
+                objects,
+                retention,
+            );
+
+            if let Err(e) = copy_to_trash(&fs, &key, password.as_deref(), &entry).await {
+                handle_error(e, Some(&pb));
+            }
+
+            Some(entry)
+        }
+        None => None,
+    };
 
     pb.set_message("Writing updated indexes...");
     if is_json_mode() {
@@ -141,9 +234,15 @@ pub async fn delete(matches: &ArgMatches) {
             Some(&pb),
         ),
     };
-    let compressed_chunk_indexes_bytes = compress_bytes(&chunk_indexes_bytes, 3);
+    let compressed_chunk_indexes_bytes = match compress_bytes(&chunk_indexes_bytes, 3) {
+        Ok(bytes) => bytes,
+        Err(e) => handle_error(
+            format!("Failed to compress chunk indexes: {}", e),
+            Some(&pb),
+        ),
+    };
 
-    let chunk_index_path = format!("{}/indexes/chunks", key);
+    let chunk_index_path = chunk_index_path(&key);
     let write_chunk_index_future = write_file_maybe_encrypt(
         &fs,
         &chunk_index_path,
@@ -158,9 +257,15 @@ pub async fn delete(matches: &ArgMatches) {
             Some(&pb),
         ),
     };
-    let compressed_backup_summaries_bytes = compress_bytes(&backup_summaries_bytes, 3);
+    let compressed_backup_summaries_bytes = match compress_bytes(&backup_summaries_bytes, 3) {
+        Ok(bytes) => bytes,
+        Err(e) => handle_error(
+            format!("Failed to compress backup summaries: {}", e),
+            Some(&pb),
+        ),
+    };
 
-    let backup_index_path = format!("{}/indexes/backups", key);
+    let backup_index_path = backup_summaries_path(&key);
     let write_backup_index_future = write_file_maybe_encrypt(
         &fs,
         &backup_index_path,
@@ -175,123 +280,32 @@ pub async fn delete(matches: &ArgMatches) {
         handle_error("Failed to write chunk indexes".to_string(), Some(&pb));
     }
 
+    if let Err(e) = remove_signature(&fs, &key, &chunk_index_path).await {
+        handle_error(e, Some(&pb));
+    }
+
     if write_backup_index_result.is_err() {
         handle_error("Failed to write backup index".to_string(), Some(&pb));
     }
 
-    pb.set_message("Deleting backup file...");
-    if is_json_mode() {
-        emit_progress_message("Deleting backup file...");
-    }
+    if !manifest_shared {
+        pb.set_message("Deleting backup file...");
+        if is_json_mode() {
+            emit_progress_message("Deleting backup file...");
+        }
 
-    let backup_file_path = format!("{}/backups/{}", key, full_backup_hash);
-    if let Err(e) = fs.delete_file(&backup_file_path).await {
-        handle_error(format!("Failed to delete backup file: {}", e), Some(&pb));
+        let backup_file_path = backup_path(&key, &manifest_hash);
+        if let Err(e) = fs.delete_file(&backup_file_path).await {
+            handle_error(format!("Failed to delete backup file: {}", e), Some(&pb));
+        }
     }
 
     pb.finish_and_clear();
 
-    if !chunks_to_delete_vec.is_empty() {
-        let json_progress = if is_json_mode() {
-            let progress = JsonProgress::new(chunks_to_delete_vec.len() as u64);
-            progress.set_message("Deleting orphaned chunks...");
-            Some(progress)
-        } else {
-            None
-        };
-
-        let pb = if is_json_mode() {
-            ProgressBar::hidden()
-        } else {
-            let pb = ProgressBar::new(chunks_to_delete_vec.len() as u64);
-            pb.enable_steady_tick(Duration::from_millis(100));
-            pb.set_style(
-                ProgressStyle::with_template(
-                    "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
-                )
-                .unwrap(),
-            );
-            pb.set_message("Deleting orphaned chunks...");
-            pb
-        };
-
-        let chunks_set = Arc::new(TokioMutex::new(JoinSet::new()));
-        let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_CHUNKS));
-        let chunks_stream = stream::iter(&chunks_to_delete_vec);
-
-        chunks_stream
-            .for_each_concurrent(MAX_CONCURRENT_CHUNKS, |chunk_hash| {
-                let pb_clone = pb.clone();
-                let fs_clone = Arc::clone(&fs);
-                let key_clone = key.clone();
-                let chunk_hash_clone = chunk_hash.clone();
-                let semaphore_clone = Arc::clone(&semaphore);
-                let chunks_set_clone = Arc::clone(&chunks_set);
-                let json_progress_clone = json_progress.clone();
-
-                async move {
-                    let mut guard = chunks_set_clone.lock().await;
-                    guard.spawn(async move {
-                        let _permit = semaphore_clone.acquire().await.expect("Semaphore closed");
-                        let (prefix, rest) = chunk_hash_clone.split_at(2);
-                        let chunk_path = format!("{}/chunks/{}/{}", key_clone, prefix, rest);
-
-                        if let Err(e) = fs_clone.delete_file(&chunk_path).await {
-                            return Err(format!(
-                                "Failed to delete chunk {}: {}",
-                                chunk_hash_clone, e
-                            ));
-                        }
-
-                        if let Some(progress) = &json_progress_clone {
-                            progress.inc_by(1);
-                        } else {
-                            pb_clone.inc(1);
-                        }
-                        Ok(())
-                    });
-                }
-            })
-            .await;
-
-        let mut failed_chunks = Vec::new();
-
-        {
-            let mut guard = chunks_set.lock().await;
-            while let Some(chunk_process_result) = guard.join_next().await {
-                match chunk_process_result {
-                    Ok(Ok(_)) => {}
-                    Ok(Err(e)) => failed_chunks.push(e),
-                    Err(e) => failed_chunks.push(e.to_string()),
-                }
-            }
-        }
-
-        if !failed_chunks.is_empty() {
-            handle_error(
-                format!(
-                    "Failed to delete {} chunks:\n{}",
-                    failed_chunks.len(),
-                    failed_chunks
-                        .iter()
-                        .map(|f| format!("  - {}", f))
-                        .collect::<Vec<String>>()
-                        .join("\n")
-                ),
-                Some(&pb),
-            );
-        }
-
-        if !is_json_mode() {
-            let elapsed = pb.elapsed();
-            pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
-            pb.set_prefix("OK");
-            pb.finish_with_message(format!(
-                "Deleted {} chunks ({:.2?})",
-                chunks_to_delete_vec.len(),
-                elapsed
-            ));
-        }
+    if let Err(e) =
+        delete_orphaned_chunks(&fs, &key, &chunks_to_delete_vec, chunk_dir_levels, true).await
+    {
+        handle_error(e, None);
     }
 
     if is_json_mode() {
@@ -300,6 +314,8 @@ pub async fn delete(matches: &ArgMatches) {
             backup: String,
             backup_short: String,
             deleted_chunks: usize,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            trash_expires_at: Option<u64>,
             elapsed_ms: u64,
         }
 
@@ -307,10 +323,468 @@ pub async fn delete(matches: &ArgMatches) {
             backup: full_backup_hash.clone(),
             backup_short: full_backup_hash[..8.min(full_backup_hash.len())].to_string(),
             deleted_chunks: chunks_to_delete_vec.len(),
+            trash_expires_at: trash_entry.as_ref().map(|entry| entry.expires_at),
             elapsed_ms: started_at.elapsed().as_millis() as u64,
         };
         emit_output(&payload);
+    } else if let Some(entry) = &trash_entry {
+        println!(
+            "{}",
+            style(format!(
+                "Moved to the trash until {}. Run 'gib trash restore --backup {}' to undo.",
+                DateTime::<Utc>::from_timestamp_secs(entry.expires_at as i64)
+                    .map(|dt| dt
+                        .with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M")
+                        .to_string())
+                    .unwrap_or_default(),
+                &full_backup_hash[..8.min(full_backup_hash.len())]
+            ))
+            .dim()
+        );
+    }
+}
+
+/// `--keep-last`, `--keep-daily`, `--keep-weekly` and `--keep-monthly`.
+fn retention_policy(matches: &ArgMatches) -> Result<RetentionPolicy, String> {
+    let parse_count = |name: &str| -> Result<Option<usize>, String> {
+        match matches.get_one::<String>(name) {
+            Some(count) => match count.parse::<usize>() {
+                Ok(count) if count > 0 => Ok(Some(count)),
+                _ => Err(format!(
+                    "Invalid value for --{}: '{}' (must be a positive number)",
+                    name, count
+                )),
+            },
+            None => Ok(None),
+        }
+    };
+
+    Ok(RetentionPolicy {
+        keep_last: parse_count("keep-last")?,
+        keep_daily: parse_count("keep-daily")?,
+        keep_weekly: parse_count("keep-weekly")?,
+        keep_monthly: parse_count("keep-monthly")?,
+    })
+}
+
+/// Deletes every backup the retention policy does not keep, dropping the chunks they
+/// leave orphaned in a single pass over the chunk indexes.
+async fn delete_by_retention(
+    matches: &ArgMatches,
+    key: String,
+    storage: String,
+    password: Option<String>,
+    retention: RetentionPolicy,
+    started_at: Instant,
+) {
+    let dry_run = matches.get_flag("dry-run");
+    let auto_confirm = matches.get_flag("yes");
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+        Err(e) => handle_error(e, None),
+    };
+
+    let backup_summaries =
+        match list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await {
+            Ok(summaries) => summaries,
+            Err(e) => handle_error(format!("Failed to load backup summaries: {}", e), None),
+        };
+
+    let reasons = retention.apply(&backup_summaries, None);
+
+    let (kept, removed): (Vec<_>, Vec<_>) = backup_summaries
+        .iter()
+        .zip(reasons)
+        .partition(|(_, reasons)| !reasons.is_empty());
+
+    #[derive(serde::Serialize)]
+    struct RetentionEntry {
+        backup: String,
+        backup_short: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timestamp: Option<u64>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        reasons: Vec<&'static str>,
+    }
+
+    #[derive(serde::Serialize)]
+    struct RetentionOutput {
+        dry_run: bool,
+        keep: Vec<RetentionEntry>,
+        remove: Vec<RetentionEntry>,
+        deleted_backups: usize,
+        deleted_chunks: usize,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        aborted: bool,
+        elapsed_ms: u64,
+    }
+
+    let to_entries = |plan: &[(&BackupSummary, Vec<&'static str>)]| {
+        plan.iter()
+            .map(|(summary, reasons)| RetentionEntry {
+                backup: summary.hash.clone(),
+                backup_short: summary.hash[..8.min(summary.hash.len())].to_string(),
+                timestamp: summary.timestamp,
+                reasons: reasons.clone(),
+            })
+            .collect::<Vec<RetentionEntry>>()
+    };
+
+    let output = |deleted_chunks: usize, deleted: bool, aborted: bool| RetentionOutput {
+        dry_run,
+        keep: to_entries(&kept),
+        remove: to_entries(&removed),
+        deleted_backups: if deleted { removed.len() } else { 0 },
+        deleted_chunks,
+        aborted,
+        elapsed_ms: started_at.elapsed().as_millis() as u64,
+    };
+
+    if !is_json_mode() {
+        for (summary, reasons) in &kept {
+            println!(
+                "{} {} {}",
+                style("keep  ").green(),
+                BackupSummaryDisplay::from(*summary).label(),
+                style(format!("[{}]", reasons.join(", "))).dim()
+            );
+        }
+        for (summary, _) in &removed {
+            println!(
+                "{} {}",
+                style("remove").red(),
+                BackupSummaryDisplay::from(*summary).label()
+            );
+        }
+    }
+
+    if dry_run || removed.is_empty() {
+        if is_json_mode() {
+            emit_output(&output(0, false, false));
+        } else if removed.is_empty() {
+            println!("No backups to delete ({} kept)", kept.len());
+        } else {
+            println!(
+                "Would delete {} of {} backups (dry run)",
+                removed.len(),
+                backup_summaries.len()
+            );
+        }
+        return;
+    }
+
+    if is_json_mode() && !auto_confirm {
+        handle_error(
+            "Confirmation required in --mode json. Re-run with --yes to delete the backups."
+                .to_string(),
+            None,
+        );
+    }
+
+    let confirm = if auto_confirm {
+        true
+    } else if !can_prompt() {
+        println!("Confirmation required with --non-interactive. Re-run with --yes to delete.");
+        false
+    } else {
+        dialoguer::Confirm::new()
+            .with_prompt(format!(
+                "Are you sure you want to DELETE {} of {} backups?",
+                removed.len(),
+                backup_summaries.len()
+            ))
+            .interact()
+            .unwrap_or_else(|e| handle_error(format!("Error: {}", e), None))
+    };
+
+    if !confirm {
+        if is_json_mode() {
+            emit_output(&output(0, false, true));
+        } else {
+            println!("Aborting...");
+        }
+        return;
+    }
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb.set_message("Loading backup data and indexes...");
+        pb
+    };
+
+    if is_json_mode() {
+        emit_progress_message("Loading backup data and indexes...");
+    }
+
+    let removed_summaries = removed
+        .iter()
+        .map(|(summary, _)| *summary)
+        .collect::<Vec<&BackupSummary>>();
+    let remaining_summaries = kept
+        .iter()
+        .map(|(summary, _)| (*summary).clone())
+        .collect::<Vec<BackupSummary>>();
+
+    let chunks_to_delete = match remove_backups(
+        &fs,
+        &key,
+        &password,
+        &removed_summaries,
+        &remaining_summaries,
+        &pb,
+    )
+    .await
+    {
+        Ok(chunks_to_delete) => chunks_to_delete,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    pb.finish_and_clear();
+
+    if let Err(e) =
+        delete_orphaned_chunks(&fs, &key, &chunks_to_delete, chunk_dir_levels, true).await
+    {
+        handle_error(e, None);
+    }
+
+    if is_json_mode() {
+        emit_output(&output(chunks_to_delete.len(), true, false));
+    } else {
+        println!(
+            "Deleted {} backups and {} chunks ({} kept)",
+            removed.len(),
+            chunks_to_delete.len(),
+            kept.len()
+        );
+    }
+}
+
+/// Removes `removed` from the repository, leaving `remaining` as its backups: drops their
+/// references from the chunk index in one pass, writes both indexes and deletes the
+/// manifests no remaining backup shares. Returns the chunks left without a backup, for
+/// `delete_orphaned_chunks` once the indexes no longer point at them.
+pub(crate) async fn remove_backups(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    removed: &[&BackupSummary],
+    remaining: &[BackupSummary],
+    pb: &ProgressBar,
+) -> Result<Vec<String>, String> {
+    let mut chunk_indexes = load_chunk_indexes(
+        Arc::clone(fs),
+        key.to_string(),
+        password.clone(),
+        Arc::new(Mutex::new(false)),
+    )
+    .await
+    .map_err(|e| format!("Failed to load chunk indexes: {}", e))?;
+
+    let mut chunks_to_delete = Vec::new();
+    for summary in removed {
+        let backup =
+            load_summary_backup(Arc::clone(fs), key.to_string(), password.clone(), summary)
+                .await
+                .map_err(|e| format!("Failed to load backup: {}", e))?;
+
+        chunks_to_delete.extend(release_chunks(&backup, &mut chunk_indexes));
+    }
+
+    // A content-addressed manifest stays as long as a remaining backup still shares it.
+    let mut manifests_to_delete = removed
+        .iter()
+        .map(|summary| summary.manifest_hash().to_string())
+        .filter(|manifest_hash| {
+            !remaining
+                .iter()
+                .any(|summary| summary.manifest_hash() == manifest_hash)
+        })
+        .collect::<Vec<String>>();
+    manifests_to_delete.sort();
+    manifests_to_delete.dedup();
+
+    pb.set_message("Writing updated indexes...");
+    if is_json_mode() {
+        emit_progress_message("Writing updated indexes...");
+    }
+
+    let (write_chunk_index_result, write_backup_index_result) = tokio::join!(
+        save_chunk_indexes(
+            Arc::clone(fs),
+            key.to_string(),
+            &chunk_indexes,
+            password.clone()
+        ),
+        save_backup_summaries(Arc::clone(fs), key.to_string(), remaining, password.clone())
+    );
+
+    write_chunk_index_result?;
+    write_backup_index_result?;
+
+    pb.set_message("Deleting backup files...");
+    if is_json_mode() {
+        emit_progress_message("Deleting backup files...");
+    }
+
+    for manifest_hash in &manifests_to_delete {
+        fs.delete_file(&backup_path(key, manifest_hash))
+            .await
+            .map_err(|e| format!("Failed to delete backup file: {}", e))?;
+    }
+
+    Ok(chunks_to_delete)
+}
+
+/// Drops the references of `backup` from the chunk indexes and returns the chunks no
+/// backup references anymore, which are removed from the indexes.
+fn release_chunks(backup: &Backup, chunk_indexes: &mut HashMap<String, ChunkIndex>) -> Vec<String> {
+    let mut chunks_to_delete = Vec::new();
+
+    for (_relative_path, backup_object) in backup.tree.iter() {
+        for chunk_hash in &backup_object.chunks {
+            if let Some(chunk_index) = chunk_indexes.get_mut(chunk_hash) {
+                if chunk_index.refcount > 0 {
+                    chunk_index.refcount -= 1;
+
+                    if chunk_index.refcount == 0 {
+                        chunks_to_delete.push(chunk_hash.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    for chunk_hash in &chunks_to_delete {
+        chunk_indexes.remove(chunk_hash);
+    }
+
+    chunks_to_delete
+}
+
+/// Deletes the chunks whose refcount dropped to zero, with a progress bar unless the
+/// caller reports progress itself.
+pub(crate) async fn delete_orphaned_chunks(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    chunks_to_delete_vec: &[String],
+    chunk_dir_levels: u8,
+    show_progress: bool,
+) -> Result<(), String> {
+    if chunks_to_delete_vec.is_empty() {
+        return Ok(());
+    }
+
+    let json_progress = if !show_progress {
+        None
+    } else if is_json_mode() {
+        let progress = JsonProgress::new(chunks_to_delete_vec.len() as u64);
+        progress.set_message("Deleting orphaned chunks...");
+        Some(progress)
+    } else {
+        None
+    };
+
+    let pb = if !show_progress || is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(chunks_to_delete_vec.len() as u64);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap(),
+        );
+        pb.set_message("Deleting orphaned chunks...");
+        pb
+    };
+
+    let chunks_set = Arc::new(TokioMutex::new(JoinSet::new()));
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_CHUNKS));
+    let chunks_stream = stream::iter(chunks_to_delete_vec);
+
+    chunks_stream
+        .for_each_concurrent(MAX_CONCURRENT_CHUNKS, |chunk_hash| {
+            let pb_clone = pb.clone();
+            let fs_clone = Arc::clone(fs);
+            let key_clone = key.to_string();
+            let chunk_hash_clone = chunk_hash.clone();
+            let semaphore_clone = Arc::clone(&semaphore);
+            let chunks_set_clone = Arc::clone(&chunks_set);
+            let json_progress_clone = json_progress.clone();
+
+            async move {
+                let mut guard = chunks_set_clone.lock().await;
+                guard.spawn(async move {
+                    let _permit = semaphore_clone.acquire().await.expect("Semaphore closed");
+                    let chunk_path = chunk_path(&key_clone, &chunk_hash_clone, chunk_dir_levels);
+
+                    if let Err(e) = fs_clone.delete_file(&chunk_path).await {
+                        return Err(format!(
+                            "Failed to delete chunk {}: {}",
+                            chunk_hash_clone, e
+                        ));
+                    }
+
+                    if let Some(progress) = &json_progress_clone {
+                        progress.inc_by(1);
+                    } else {
+                        pb_clone.inc(1);
+                    }
+                    Ok(())
+                });
+            }
+        })
+        .await;
+
+    let mut failed_chunks = Vec::new();
+
+    {
+        let mut guard = chunks_set.lock().await;
+        while let Some(chunk_process_result) = guard.join_next().await {
+            match chunk_process_result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => failed_chunks.push(e),
+                Err(e) => failed_chunks.push(e.to_string()),
+            }
+        }
     }
+
+    if !failed_chunks.is_empty() {
+        pb.finish_and_clear();
+        return Err(format!(
+            "Failed to delete {} chunks:\n{}",
+            failed_chunks.len(),
+            failed_chunks
+                .iter()
+                .map(|f| format!("  - {}", f))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    if show_progress && !is_json_mode() {
+        let elapsed = pb.elapsed();
+        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
+        pb.set_prefix("OK");
+        pb.finish_with_message(format!(
+            "Deleted {} chunks ({:.2?})",
+            chunks_to_delete_vec.len(),
+            elapsed
+        ));
+    }
+
+    Ok(())
 }
 
 async fn resolve_backup_hash(
@@ -336,34 +810,29 @@ async fn resolve_backup_hash(
             }
         }
         None => {
-            if is_json_mode() {
+            if !can_prompt() {
                 return Err(
-                    "Missing required argument: --backup (required in --mode json)".to_string(),
+                    "Missing required argument: --backup (required in --mode json or with --non-interactive)".to_string(),
                 );
             }
-            let summaries = list_backup_summaries(fs, key, password).await?;
+            let mut summaries =
+                list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await?;
 
             if summaries.is_empty() {
                 return Err("No backups found in repository".to_string());
             }
 
-            let recent_backups: Vec<BackupSummaryDisplay> = summaries
-                .iter()
-                .take(10)
-                .map(|s| BackupSummaryDisplay {
-                    hash: s.hash.clone(),
-                    message: s.message.clone(),
-                })
-                .collect();
+            summaries.truncate(10);
+            backfill_backup_summaries(fs, key, password, &mut summaries).await;
+
+            let recent_backups: Vec<BackupSummaryDisplay> =
+                summaries.iter().map(BackupSummaryDisplay::from).collect();
 
             if recent_backups.is_empty() {
                 return Err("No backups found in repository".to_string());
             }
 
-            let items: Vec<String> = recent_backups
-                .iter()
-                .map(|c| format!("{} {}", &c.hash[..8.min(c.hash.len())], &c.message))
-                .collect();
+            let items: Vec<String> = recent_backups.iter().map(|c| c.label()).collect();
 
             let selected_index = Select::new()
                 .with_prompt("Select a backup to delete")
@@ -380,34 +849,44 @@ async fn resolve_backup_hash(
 struct BackupSummaryDisplay {
     hash: String,
     message: String,
+    timestamp: Option<u64>,
+    author: Option<String>,
 }
 
-async fn load_backup(
-    fs: Arc<dyn FS>,
-    key: String,
-    password: Option<String>,
-    backup_hash: String,
-) -> Result<Backup, String> {
-    let backup_path = format!("{}/backups/{}", key, backup_hash);
+impl From<&BackupSummary> for BackupSummaryDisplay {
+    fn from(summary: &BackupSummary) -> Self {
+        BackupSummaryDisplay {
+            hash: summary.hash.clone(),
+            message: summary.message.clone(),
+            timestamp: summary.timestamp,
+            author: summary.author.clone(),
+        }
+    }
+}
 
-    let read_result = read_file_maybe_decrypt(
-        &fs,
-        &backup_path,
-        password.as_deref(),
-        "Backup is encrypted but no password provided",
-    )
-    .await?;
+impl BackupSummaryDisplay {
+    fn label(&self) -> String {
+        let mut label = format!("{} {}", &self.hash[..8.min(self.hash.len())], &self.message);
 
-    if read_result.bytes.is_empty() {
-        return Err(format!("Backup {} not found or is empty", backup_hash));
-    }
+        let date = self.timestamp.and_then(|ts| {
+            DateTime::<Utc>::from_timestamp_secs(ts as i64).map(|dt| {
+                dt.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+        });
 
-    let decompressed_bytes = decompress_bytes(&read_result.bytes);
+        let details = [date, self.author.clone()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<String>>();
 
-    let backup: Backup = rmp_serde::from_slice(&decompressed_bytes)
-        .map_err(|e| format!("Failed to deserialize backup: {}", e))?;
+        if !details.is_empty() {
+            label.push_str(&format!(" ({})", details.join(", ")));
+        }
 
-    Ok(backup)
+        label
+    }
 }
 
 fn get_params(
@@ -423,7 +902,7 @@ fn get_params(
 
     let pwd_string = get_pwd_string();
 
-    let default_key = Path::new(&pwd_string)
+    let folder_key = Path::new(&pwd_string)
         .file_name()
         .unwrap()
         .to_string_lossy()
@@ -431,7 +910,9 @@ fn get_params(
 
     let key = matches
         .get_one::<String>("key")
-        .map_or_else(|| default_key, |key| key.to_string());
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
 
     let home_dir = home_dir().unwrap();
     let storage_path = home_dir.join(".gib").join("storages");
@@ -462,12 +943,16 @@ fn get_params(
         return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
     }
 
-    let storage = match matches.get_one::<String>("storage") {
-        Some(storage) => storage.to_string(),
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
         None => {
-            if is_json_mode() {
+            if !can_prompt() {
                 return Err(
-                    "Missing required argument: --storage (required in --mode json)".to_string(),
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
                 );
             }
             let selected_index = Select::new()
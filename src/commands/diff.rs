@@ -0,0 +1,518 @@
+use crate::commands::backup::{IgnoreRules, ignore_rules_from_matches};
+use crate::commands::config::{default_key, default_storage};
+use crate::commands::restore::{needs_restore, resolve_backup_hash};
+use crate::core::crypto::get_password;
+use crate::core::indexes::load_resolved_backup;
+use crate::core::metadata::BackupObject;
+use crate::fs::FS;
+use crate::output::{can_prompt, emit_output, is_json_mode};
+use crate::utils::{get_fs, get_pwd_string, get_storage, handle_error};
+use bytesize::ByteSize;
+use clap::ArgMatches;
+use console::style;
+use dialoguer::Select;
+use dirs::home_dir;
+use futures::stream::{self, StreamExt};
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+const MAX_CONCURRENT_FILES: usize = 100;
+
+/// One path that differs between the two sides. Sizes are absent on the side that does
+/// not have the file.
+#[derive(serde::Serialize)]
+struct DiffEntry {
+    path: String,
+    /// `added`, `removed` or `modified`.
+    change: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    old_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    new_size: Option<u64>,
+    size_delta: i64,
+}
+
+#[derive(serde::Serialize)]
+struct DiffOutput {
+    from: String,
+    /// The newer backup, or absent when the working directory `root` was compared.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root: Option<String>,
+    changes: Vec<DiffEntry>,
+    added: usize,
+    removed: usize,
+    modified: usize,
+    unchanged: usize,
+    size_delta: i64,
+}
+
+/// Lists the files added, removed and modified between two backups, or between a backup
+/// and the files of a directory.
+pub async fn diff(matches: &ArgMatches) {
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let fs = get_fs(&get_storage(&storage), None);
+
+    let from = match resolve_backup_hash(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        matches.get_one::<String>("from").cloned(),
+    )
+    .await
+    {
+        Ok(hash) => hash,
+        Err(e) => handle_error(e, None),
+    };
+
+    let to = match matches.get_one::<String>("to") {
+        Some(to) => match resolve_backup_hash(
+            Arc::clone(&fs),
+            key.clone(),
+            password.clone(),
+            Some(to.clone()),
+        )
+        .await
+        {
+            Ok(hash) => Some(hash),
+            Err(e) => handle_error(e, None),
+        },
+        None => None,
+    };
+
+    let old_tree = match load_tree(&fs, &key, &password, &from).await {
+        Ok(tree) => tree,
+        Err(e) => handle_error(e, None),
+    };
+
+    let mut payload = match &to {
+        Some(to) => {
+            let new_tree = match load_tree(&fs, &key, &password, to).await {
+                Ok(tree) => tree,
+                Err(e) => handle_error(e, None),
+            };
+            diff_trees(&from, to, &old_tree, &new_tree)
+        }
+        None => {
+            let root = matches
+                .get_one::<String>("root")
+                .cloned()
+                .unwrap_or_else(get_pwd_string);
+            let ignore_rules = match ignore_rules_from_matches(matches, &root) {
+                Ok(rules) => rules,
+                Err(e) => handle_error(e, None),
+            };
+            match diff_worktree(&from, &root, &ignore_rules, old_tree).await {
+                Ok(payload) => payload,
+                Err(e) => handle_error(e, None),
+            }
+        }
+    };
+
+    payload.changes.sort_by(|a, b| a.path.cmp(&b.path));
+    for entry in &payload.changes {
+        match entry.change {
+            "added" => payload.added += 1,
+            "removed" => payload.removed += 1,
+            _ => payload.modified += 1,
+        }
+        payload.size_delta += entry.size_delta;
+    }
+
+    if is_json_mode() {
+        emit_output(&payload);
+    } else {
+        print_diff(&payload);
+    }
+}
+
+async fn load_tree(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    backup_hash: &str,
+) -> Result<HashMap<String, BackupObject>, String> {
+    let (backup, _) = load_resolved_backup(
+        Arc::clone(fs),
+        key.to_string(),
+        password.clone(),
+        backup_hash.to_string(),
+    )
+    .await?;
+
+    Ok(backup.tree)
+}
+
+fn new_output(from: &str, to: Option<String>, root: Option<String>) -> DiffOutput {
+    DiffOutput {
+        from: from.to_string(),
+        to,
+        root,
+        changes: Vec::new(),
+        added: 0,
+        removed: 0,
+        modified: 0,
+        unchanged: 0,
+        size_delta: 0,
+    }
+}
+
+fn added(path: &str, size: u64) -> DiffEntry {
+    DiffEntry {
+        path: path.to_string(),
+        change: "added",
+        old_size: None,
+        new_size: Some(size),
+        size_delta: size as i64,
+    }
+}
+
+fn removed(path: &str, size: u64) -> DiffEntry {
+    DiffEntry {
+        path: path.to_string(),
+        change: "removed",
+        old_size: Some(size),
+        new_size: None,
+        size_delta: -(size as i64),
+    }
+}
+
+fn modified(path: &str, old_size: u64, new_size: u64) -> DiffEntry {
+    DiffEntry {
+        path: path.to_string(),
+        change: "modified",
+        old_size: Some(old_size),
+        new_size: Some(new_size),
+        size_delta: new_size as i64 - old_size as i64,
+    }
+}
+
+/// A file is modified when both backups have its path with a different hash.
+fn diff_trees(
+    from: &str,
+    to: &str,
+    old_tree: &HashMap<String, BackupObject>,
+    new_tree: &HashMap<String, BackupObject>,
+) -> DiffOutput {
+    let mut output = new_output(from, Some(to.to_string()), None);
+
+    let paths = old_tree
+        .keys()
+        .chain(new_tree.keys())
+        .collect::<BTreeSet<&String>>();
+
+    for path in paths {
+        match (old_tree.get(path), new_tree.get(path)) {
+            (Some(old), Some(new)) if old.hash == new.hash => output.unchanged += 1,
+            (Some(old), Some(new)) => output.changes.push(modified(path, old.size, new.size)),
+            (Some(old), None) => output.changes.push(removed(path, old.size)),
+            (None, Some(new)) => output.changes.push(added(path, new.size)),
+            (None, None) => {}
+        }
+    }
+
+    output
+}
+
+/// Compares the backup with the files under `root`. A file with the size of the backed
+/// up one is hashed, so a modification that kept the size and time is still found. Paths
+/// left out by `ignore_rules` are skipped, as `backup` skips them.
+async fn diff_worktree(
+    from: &str,
+    root_path: &str,
+    ignore_rules: &IgnoreRules,
+    old_tree: HashMap<String, BackupObject>,
+) -> Result<DiffOutput, String> {
+    let root = Path::new(root_path);
+    if !root.is_dir() {
+        return Err(format!("'{}' is not a directory", root_path));
+    }
+
+    let mut local_files = Vec::new();
+    for entry in walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| !ignore_rules.is_ignored(entry))
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.file_type().is_file() || (entry.depth() > 0 && entry.path_is_symlink())
+        })
+    {
+        let Ok(relative_path) = entry.path().strip_prefix(root) else {
+            continue;
+        };
+        let relative_path = relative_path.to_string_lossy().replace('\\', "/");
+        // A link is stored without content, like the backup records it.
+        let size = if entry.path_is_symlink() {
+            0
+        } else {
+            entry.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+        };
+        local_files.push((relative_path, entry.into_path(), size));
+    }
+
+    let old_tree = Arc::new(old_tree);
+
+    let results = stream::iter(local_files)
+        .map(|(relative_path, local_path, size)| {
+            let old_tree = Arc::clone(&old_tree);
+
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    let changed = old_tree
+                        .get(&relative_path)
+                        .map(|backup_object| needs_restore(&local_path, backup_object, false));
+                    (relative_path, size, changed)
+                })
+                .await
+                .ok()
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_FILES)
+        .filter_map(|result| async move { result })
+        .collect::<Vec<(String, u64, Option<bool>)>>()
+        .await;
+
+    let mut output = new_output(from, None, Some(root_path.to_string()));
+    let mut seen = BTreeSet::new();
+
+    for (relative_path, size, changed) in results {
+        match (changed, old_tree.get(&relative_path)) {
+            (Some(true), Some(old)) => {
+                output
+                    .changes
+                    .push(modified(&relative_path, old.size, size))
+            }
+            (Some(_), _) => output.unchanged += 1,
+            (None, _) => output.changes.push(added(&relative_path, size)),
+        }
+        seen.insert(relative_path);
+    }
+
+    for (relative_path, backup_object) in old_tree.iter() {
+        if !seen.contains(relative_path) {
+            output
+                .changes
+                .push(removed(relative_path, backup_object.size));
+        }
+    }
+
+    Ok(output)
+}
+
+fn print_diff(output: &DiffOutput) {
+    let to = match (&output.to, &output.root) {
+        (Some(to), _) => format!("backup {}", &to[..8.min(to.len())]),
+        (None, Some(root)) => format!("the files in {}", root),
+        (None, None) => unreachable!("a diff compares with a backup or a directory"),
+    };
+
+    println!(
+        "Changes from backup {} to {}:",
+        style(&output.from[..8.min(output.from.len())])
+            .cyan()
+            .bold(),
+        to
+    );
+
+    if output.changes.is_empty() {
+        println!(
+            "{}",
+            style(format!(
+                "No changes ({} files identical).",
+                output.unchanged
+            ))
+            .green()
+        );
+        return;
+    }
+
+    for entry in &output.changes {
+        let (marker, color) = match entry.change {
+            "added" => ("+", console::Color::Green),
+            "removed" => ("-", console::Color::Red),
+            _ => ("~", console::Color::Yellow),
+        };
+        println!(
+            "  {} {} {}",
+            style(marker).fg(color),
+            style(&entry.path).fg(color),
+            style(format!("({})", format_delta(entry.size_delta))).dim()
+        );
+    }
+
+    println!();
+    println!(
+        "{} added, {} removed, {} modified, {} unchanged ({})",
+        output.added,
+        output.removed,
+        output.modified,
+        output.unchanged,
+        format_delta(output.size_delta)
+    );
+}
+
+fn format_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    format!("{}{}", sign, ByteSize(delta.unsigned_abs()))
+}
+
+fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>), String> {
+    let password: Option<String> = matches
+        .get_one::<String>("password")
+        .map(|s| s.to_string())
+        .map_or_else(
+            || get_password(false, true),
+            |password| Some(password.to_string()),
+        );
+
+    let pwd_string = get_pwd_string();
+
+    let folder_key = Path::new(&pwd_string)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    Ok((key, storage, password))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(hash: &str, size: u64) -> BackupObject {
+        BackupObject {
+            hash: hash.to_string(),
+            size,
+            ..BackupObject::with_chunks(&[hash])
+        }
+    }
+
+    fn tree(objects: &[(&str, &str, u64)]) -> HashMap<String, BackupObject> {
+        objects
+            .iter()
+            .map(|(path, hash, size)| (path.to_string(), object(hash, *size)))
+            .collect()
+    }
+
+    #[test]
+    fn trees_are_compared_by_path_and_hash() {
+        let old_tree = tree(&[
+            ("same.txt", "a", 10),
+            ("changed.txt", "b", 10),
+            ("gone.txt", "c", 7),
+        ]);
+        let new_tree = tree(&[
+            ("same.txt", "a", 10),
+            ("changed.txt", "d", 25),
+            ("new.txt", "e", 3),
+        ]);
+
+        let output = diff_trees("old", "new", &old_tree, &new_tree);
+
+        assert_eq!(output.to.as_deref(), Some("new"));
+        assert_eq!(output.unchanged, 1);
+        let changes = output
+            .changes
+            .iter()
+            .map(|entry| {
+                (
+                    entry.path.as_str(),
+                    entry.change,
+                    entry.old_size,
+                    entry.new_size,
+                    entry.size_delta,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                ("changed.txt", "modified", Some(10), Some(25), 15),
+                ("gone.txt", "removed", Some(7), None, -7),
+                ("new.txt", "added", None, Some(3), 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn identical_trees_have_no_changes() {
+        let old_tree = tree(&[("a.txt", "a", 1), ("b.txt", "b", 2)]);
+
+        let output = diff_trees("old", "new", &old_tree, &old_tree.clone());
+
+        assert!(output.changes.is_empty());
+        assert_eq!(output.unchanged, 2);
+    }
+}
@@ -0,0 +1,523 @@
+use crate::commands::config::{
+    default_storage, get_config_path, migrate_storage, read_config_file,
+};
+use crate::commands::storage::add::{StorageFormat, parse_storage_config};
+use crate::core::crypto::get_password;
+use crate::core::indexes::{list_backup_summaries, load_chunk_indexes};
+use crate::core::lock::{find_stale_lock, remove_stale_lock};
+use crate::output::{can_prompt, emit_error_with_details, emit_output, is_json_mode};
+use crate::utils::{get_fs, get_storage, handle_error};
+use clap::ArgMatches;
+use console::style;
+use dirs::home_dir;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::info;
+
+#[derive(serde::Serialize)]
+struct DoctorIssue {
+    issue: &'static str,
+    fixed: bool,
+    /// What is wrong, or what `--fix` did about it.
+    detail: String,
+}
+
+#[derive(serde::Serialize)]
+struct DoctorOutput {
+    issues: Vec<DoctorIssue>,
+    elapsed_ms: u64,
+}
+
+/// A problem found by a check, with the fixer `--fix` can run for it.
+struct Finding {
+    issue: &'static str,
+    detail: String,
+    fix: Option<Fix>,
+}
+
+enum Fix {
+    CreateDir(PathBuf),
+    #[cfg(unix)]
+    SetMode {
+        path: PathBuf,
+        from: u32,
+        to: u32,
+    },
+    RemoveFile(PathBuf),
+    MigrateStorage(PathBuf),
+    RemoveStaleLock {
+        storage: String,
+        key: String,
+        lock_id: String,
+    },
+}
+
+impl Fix {
+    /// Fixes that change or remove something gib or another client may still need.
+    fn needs_confirmation(&self) -> bool {
+        matches!(self, Fix::MigrateStorage(_) | Fix::RemoveStaleLock { .. })
+    }
+
+    /// Applies the fix and describes what was done, including how to undo it.
+    async fn apply(&self) -> Result<String, String> {
+        match self {
+            Fix::CreateDir(path) => {
+                std::fs::create_dir_all(path)
+                    .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+                #[cfg(unix)]
+                if is_inside_gib_dir(path) {
+                    set_mode(path, 0o700)?;
+                }
+                Ok(format!("Created {}", path.display()))
+            }
+            #[cfg(unix)]
+            Fix::SetMode { path, from, to } => {
+                set_mode(path, *to)?;
+                Ok(format!(
+                    "Changed the mode of {} from {:o} to {:o} (run 'chmod {:o} {}' to undo)",
+                    path.display(),
+                    from,
+                    to,
+                    from,
+                    path.display()
+                ))
+            }
+            Fix::RemoveFile(path) => {
+                std::fs::remove_file(path)
+                    .map_err(|e| format!("Failed to remove {}: {}", path.display(), e))?;
+                Ok(format!("Removed {}", path.display()))
+            }
+            Fix::MigrateStorage(path) => {
+                migrate_storage(path)?;
+                Ok(format!("Rewrote {} in the current format", path.display()))
+            }
+            Fix::RemoveStaleLock {
+                storage,
+                key,
+                lock_id,
+            } => {
+                let fs = get_fs(&get_storage(storage), None);
+                if remove_stale_lock(&fs, key, lock_id).await? {
+                    Ok(format!("Removed the stale lock of {}", key))
+                } else {
+                    Ok(format!(
+                        "The lock of {} was released or taken over meanwhile, left it alone",
+                        key
+                    ))
+                }
+            }
+        }
+    }
+}
+
+pub async fn doctor(matches: &ArgMatches) {
+    let started_at = Instant::now();
+    let fix = matches.get_flag("fix");
+    let auto_confirm = matches.get_flag("yes");
+
+    let mut findings = check_setup();
+
+    if let Some(key) = matches.get_one::<String>("key") {
+        let storage = match matches
+            .get_one::<String>("storage")
+            .cloned()
+            .or_else(default_storage)
+        {
+            Some(storage) => storage,
+            None => handle_error(
+                "Missing required argument: --storage (needed to check a repository)".to_string(),
+                None,
+            ),
+        };
+
+        let password = matches
+            .get_one::<String>("password")
+            .map(|password| password.to_string())
+            .or_else(|| get_password(false, true));
+
+        findings.extend(check_repository(&storage, key, password).await);
+    }
+
+    let mut issues = Vec::new();
+    for finding in findings {
+        let Finding {
+            issue,
+            detail,
+            fix: fixer,
+        } = finding;
+
+        let Some(fixer) = fixer.filter(|_| fix) else {
+            issues.push(DoctorIssue {
+                issue,
+                fixed: false,
+                detail,
+            });
+            continue;
+        };
+
+        if fixer.needs_confirmation() && !auto_confirm && !confirm_fix(&detail) {
+            issues.push(DoctorIssue {
+                issue,
+                fixed: false,
+                detail: format!(
+                    "{} (not fixed: needs confirmation, re-run with --yes)",
+                    detail
+                ),
+            });
+            continue;
+        }
+
+        match fixer.apply().await {
+            Ok(done) => {
+                info!(issue, "{}", done);
+                issues.push(DoctorIssue {
+                    issue,
+                    fixed: true,
+                    detail: done,
+                });
+            }
+            Err(e) => issues.push(DoctorIssue {
+                issue,
+                fixed: false,
+                detail: format!("{} (fix failed: {})", detail, e),
+            }),
+        }
+    }
+
+    let payload = DoctorOutput {
+        issues,
+        elapsed_ms: started_at.elapsed().as_millis() as u64,
+    };
+
+    let remaining = payload.issues.iter().filter(|issue| !issue.fixed).count();
+
+    if is_json_mode() {
+        if remaining > 0 {
+            emit_error_with_details(
+                &format!("Found {} issues that are not fixed", remaining),
+                "doctor_issues_found",
+                &payload,
+            );
+        }
+        emit_output(&payload);
+        return;
+    }
+
+    if payload.issues.is_empty() {
+        println!("{} No issues found", style("OK").green());
+        return;
+    }
+
+    for issue in &payload.issues {
+        if issue.fixed {
+            println!(
+                "{} {}: {}",
+                style("FIXED").green(),
+                issue.issue,
+                issue.detail
+            );
+        } else {
+            println!(
+                "{} {}: {}",
+                style("ISSUE").yellow(),
+                issue.issue,
+                issue.detail
+            );
+        }
+    }
+
+    if remaining > 0 {
+        let hint = if fix {
+            ""
+        } else {
+            ", run 'gib doctor --fix' to fix what can be fixed automatically"
+        };
+        handle_error(
+            format!("Found {} issues that are not fixed{}", remaining, hint),
+            None,
+        );
+    }
+}
+
+/// Asks before a fix that needs confirmation; never confirms when gib cannot prompt.
+fn confirm_fix(detail: &str) -> bool {
+    if is_json_mode() || !can_prompt() {
+        return false;
+    }
+
+    dialoguer::Confirm::new()
+        .with_prompt(format!("{}. Fix it?", detail))
+        .interact()
+        .unwrap_or_else(|e| handle_error(format!("Error: {}", e), None))
+}
+
+/// Checks the local gib setup: the config, the storages and their permissions.
+fn check_setup() -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let gib_dir = home_dir().unwrap().join(".gib");
+    let storages_dir = gib_dir.join("storages");
+
+    if !gib_dir.exists() {
+        findings.push(Finding {
+            issue: "config_dir_missing",
+            detail: format!("{} does not exist", gib_dir.display()),
+            fix: Some(Fix::CreateDir(gib_dir.clone())),
+        });
+    }
+
+    let config_path = get_config_path();
+    let config = if config_path.exists() {
+        match read_config_file(&config_path) {
+            Ok(config) => Some(config),
+            Err(e) => {
+                findings.push(Finding {
+                    issue: "config_unreadable",
+                    detail: format!("{}, run 'gib config' to write it again", e),
+                    fix: None,
+                });
+                None
+            }
+        }
+    } else {
+        findings.push(Finding {
+            issue: "config_missing",
+            detail: "gib is not configured yet, run 'gib config' to set your identity".to_string(),
+            fix: None,
+        });
+        None
+    };
+
+    if !storages_dir.exists() {
+        findings.push(Finding {
+            issue: "storages_dir_missing",
+            detail: format!("{} does not exist", storages_dir.display()),
+            fix: Some(Fix::CreateDir(storages_dir.clone())),
+        });
+    }
+
+    #[cfg(unix)]
+    for dir in [&gib_dir, &storages_dir] {
+        findings.extend(check_mode(dir, 0o700));
+    }
+
+    let mut storage_files = match std::fs::read_dir(&storages_dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .collect::<Vec<PathBuf>>(),
+        Err(_) => Vec::new(),
+    };
+    storage_files.sort();
+
+    let mut storage_names = Vec::new();
+    for path in storage_files {
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+
+        // Left behind by an interrupted 'gib config --migrate-storages'.
+        if file_name.contains(".msgpack.tmp-") {
+            findings.push(Finding {
+                issue: "stale_temp_file",
+                detail: format!("{} is a leftover temporary file", path.display()),
+                fix: Some(Fix::RemoveFile(path)),
+            });
+            continue;
+        }
+
+        let Some(name) = file_name.strip_suffix(".msgpack") else {
+            continue;
+        };
+        storage_names.push(name.to_string());
+
+        #[cfg(unix)]
+        findings.extend(check_mode(&path, 0o600));
+
+        let parsed = std::fs::read(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| parse_storage_config(&bytes));
+
+        let storage = match parsed {
+            Ok((storage, format)) => {
+                if format == StorageFormat::Legacy {
+                    findings.push(Finding {
+                        issue: "legacy_storage_format",
+                        detail: format!(
+                            "Storage '{}' is saved in the format of an older gib",
+                            name
+                        ),
+                        fix: Some(Fix::MigrateStorage(path.clone())),
+                    });
+                }
+                storage
+            }
+            Err(e) => {
+                findings.push(Finding {
+                    issue: "storage_unreadable",
+                    detail: format!(
+                        "Storage '{}' cannot be read ({}), run 'gib storage remove' and add it again",
+                        name, e
+                    ),
+                    fix: None,
+                });
+                continue;
+            }
+        };
+
+        if storage.storage_type == 0
+            && let Some(storage_path) = &storage.path
+            && !Path::new(storage_path).exists()
+        {
+            findings.push(Finding {
+                issue: "storage_path_missing",
+                detail: format!(
+                    "The folder of storage '{}' ({}) does not exist",
+                    name, storage_path
+                ),
+                fix: Some(Fix::CreateDir(PathBuf::from(storage_path))),
+            });
+        }
+    }
+
+    if let Some(default_storage) = config.and_then(|config| config.default_storage)
+        && !storage_names.contains(&default_storage)
+    {
+        findings.push(Finding {
+            issue: "default_storage_missing",
+            detail: format!(
+                "The default storage '{}' does not exist, run 'gib config --default-storage' to change it",
+                default_storage
+            ),
+            fix: None,
+        });
+    }
+
+    findings
+}
+
+/// Checks the lock and indexes of the repository `key`.
+async fn check_repository(storage: &str, key: &str, password: Option<String>) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    let storage_path = home_dir()
+        .unwrap()
+        .join(".gib")
+        .join("storages")
+        .join(format!("{}.msgpack", storage));
+    if !storage_path.exists() {
+        handle_error(format!("Storage '{}' not found", storage), None);
+    }
+
+    let fs = get_fs(&get_storage(storage), None);
+    let reindex = format!("run 'gib reindex -k {} -s {}' to rebuild it", key, storage);
+
+    match find_stale_lock(&fs, key).await {
+        Ok(Some((lock, reason))) => findings.push(Finding {
+            issue: "stale_lock",
+            detail: format!(
+                "Repository {} is still locked by {}, but {}",
+                key, lock, reason
+            ),
+            fix: Some(Fix::RemoveStaleLock {
+                storage: storage.to_string(),
+                key: key.to_string(),
+                lock_id: lock.id,
+            }),
+        }),
+        Ok(None) => {}
+        Err(e) => findings.push(Finding {
+            issue: "lock_unreadable",
+            detail: e,
+            fix: None,
+        }),
+    }
+
+    let chunk_indexes = match load_chunk_indexes(
+        Arc::clone(&fs),
+        key.to_string(),
+        password.clone(),
+        Arc::new(Mutex::new(false)),
+    )
+    .await
+    {
+        Ok(chunk_indexes) => Some(chunk_indexes),
+        Err(e) => {
+            findings.push(Finding {
+                issue: "chunk_index_unreadable",
+                detail: format!("{}, {}", e, reindex),
+                fix: None,
+            });
+            None
+        }
+    };
+
+    let backup_summaries =
+        match list_backup_summaries(Arc::clone(&fs), key.to_string(), password).await {
+            Ok(summaries) => Some(summaries),
+            Err(e) => {
+                findings.push(Finding {
+                    issue: "backup_index_unreadable",
+                    detail: format!("{}, {}", e, reindex),
+                    fix: None,
+                });
+                None
+            }
+        };
+
+    if let (Some(chunk_indexes), Some(backup_summaries)) = (chunk_indexes, backup_summaries)
+        && chunk_indexes.is_empty()
+        && backup_summaries.is_empty()
+        && fs
+            .list_files(&format!("{}/backups", key))
+            .await
+            .is_ok_and(|manifests| !manifests.is_empty())
+    {
+        findings.push(Finding {
+            issue: "indexes_missing",
+            detail: format!(
+                "Repository {} has backup manifests but no indexes, {}",
+                key, reindex
+            ),
+            fix: None,
+        });
+    }
+
+    findings
+}
+
+#[cfg(unix)]
+fn check_mode(path: &Path, mode: u32) -> Option<Finding> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let current = std::fs::metadata(path).ok()?.permissions().mode() & 0o777;
+    if current & 0o077 == 0 {
+        return None;
+    }
+
+    Some(Finding {
+        issue: "insecure_permissions",
+        detail: format!(
+            "{} can be read by other users (mode {:o})",
+            path.display(),
+            current
+        ),
+        fix: Some(Fix::SetMode {
+            path: path.to_path_buf(),
+            from: current,
+            to: mode,
+        }),
+    })
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .map_err(|e| format!("Failed to change the mode of {}: {}", path.display(), e))
+}
+
+/// The folders gib keeps credentials in, which should only be readable by their owner.
+#[cfg(unix)]
+fn is_inside_gib_dir(path: &Path) -> bool {
+    path.starts_with(home_dir().unwrap().join(".gib"))
+}
@@ -1,19 +1,26 @@
+use crate::commands::config::{default_key, default_storage};
 use crate::core::crypto::{read_file_maybe_decrypt, write_file_maybe_encrypt};
-use crate::core::indexes::list_backup_summaries;
-use crate::core::metadata::{BackupSummary, ChunkIndex};
+use crate::core::indexes::{list_backup_summaries, load_repository_meta};
+use crate::core::layout::{backup_path, backup_summaries_path, chunk_index_path, chunk_path};
+use crate::core::metadata::{BackupSummary, ChunkIndex, EncryptCheckpoint};
+use crate::core::signing::remove_signature;
 use crate::core::{crypto::get_password, indexes::load_chunk_indexes};
 use crate::fs::FS;
-use crate::output::{JsonProgress, emit_output, emit_progress_message, is_json_mode};
-use crate::utils::{get_fs, get_pwd_string, get_storage, handle_error};
+use crate::output::{JsonProgress, can_prompt, emit_output, emit_progress_message, is_json_mode};
+use crate::utils::{
+    compress_bytes, decompress_bytes, get_fs, get_pwd_string, get_storage, handle_error,
+};
 use clap::ArgMatches;
 use console::style;
 use dialoguer::Select;
 use dirs::home_dir;
 use futures::stream::{self, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 use tokio::sync::{Mutex as TokioMutex, Semaphore};
 use tokio::task::JoinSet;
@@ -21,7 +28,7 @@ use tokio::task::JoinSet;
 const MAX_CONCURRENT_FILES: usize = 100;
 
 pub async fn encrypt(matches: &ArgMatches) {
-    let (key, storage, password) = match get_params(matches) {
+    let (key, storage, password, concurrency) = match get_params(matches) {
         Ok(params) => params,
         Err(e) => handle_error(e, None),
     };
@@ -48,6 +55,11 @@ pub async fn encrypt(matches: &ArgMatches) {
 
     let fs = get_fs(&storage, Some(&pb));
 
+    let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
     let prev_not_encrypted_but_now_yes = Arc::new(Mutex::new(false));
 
     let (chunk_indexes, backup_summaries) = match load_metadata(
@@ -64,24 +76,51 @@ pub async fn encrypt(matches: &ArgMatches) {
 
     let mut files_to_encrypt = Vec::new();
 
-    files_to_encrypt.push(format!("{}/indexes/chunks", key));
-    files_to_encrypt.push(format!("{}/indexes/backups", key));
+    files_to_encrypt.push(chunk_index_path(&key));
+    files_to_encrypt.push(backup_summaries_path(&key));
 
     for (chunk_hash, _) in chunk_indexes.iter() {
-        let (chunk_hash_prefix, chunk_hash_rest) = chunk_hash.split_at(2);
-        let chunk_path = format!("{}/chunks/{}/{}", &key, chunk_hash_prefix, chunk_hash_rest);
+        let chunk_path = chunk_path(&key, chunk_hash, chunk_dir_levels);
         files_to_encrypt.push(chunk_path);
     }
 
-    for backup_summary in backup_summaries.iter() {
-        let backup_file_path = format!("{}/backups/{}", key, backup_summary.hash);
+    let manifest_hashes = backup_summaries
+        .iter()
+        .map(|backup_summary| backup_summary.manifest_hash())
+        .collect::<BTreeSet<&str>>();
+
+    for manifest_hash in manifest_hashes {
+        let backup_file_path = backup_path(&key, manifest_hash);
         files_to_encrypt.push(backup_file_path);
     }
 
+    let checkpoint_path = format!("{}/indexes/encrypt_checkpoint", key);
+
+    let checkpoint = match load_checkpoint(&fs, &checkpoint_path, &password).await {
+        Ok(checkpoint) => checkpoint,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let completed_files: HashSet<String> = checkpoint.completed_files.iter().cloned().collect();
+    let skipped_amount = files_to_encrypt
+        .iter()
+        .filter(|file_path| completed_files.contains(*file_path))
+        .count() as u64;
+    files_to_encrypt.retain(|file_path| !completed_files.contains(file_path));
+
     pb.finish_and_clear();
 
     if !is_json_mode() {
-        if *prev_not_encrypted_but_now_yes.lock().unwrap() {
+        if skipped_amount > 0 {
+            println!(
+                "{}",
+                style(format!(
+                    "Resuming from checkpoint, skipping {} files that were already encrypted...",
+                    skipped_amount
+                ))
+                .green()
+            );
+        } else if *prev_not_encrypted_but_now_yes.lock().unwrap() {
             println!(
                 "{}",
                 style("Encrypting all chunks of the repository...").green()
@@ -119,14 +158,36 @@ pub async fn encrypt(matches: &ArgMatches) {
     };
 
     let encrypted_amount = Arc::new(Mutex::new(0));
-    let already_encrypted_amount = Arc::new(Mutex::new(0));
+    let already_encrypted_amount = Arc::new(Mutex::new(skipped_amount));
     let files_set = Arc::new(TokioMutex::new(JoinSet::new()));
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_FILES));
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let checkpoint = Arc::new(Mutex::new(checkpoint));
+    let checkpoint_path = Arc::new(checkpoint_path);
+    let checkpoint_watcher_stop = Arc::new(AtomicBool::new(false));
+
+    let checkpoint_watcher = {
+        let fs_clone = Arc::clone(&fs);
+        let checkpoint_clone = Arc::clone(&checkpoint);
+        let checkpoint_path_clone = Arc::clone(&checkpoint_path);
+        let checkpoint_watcher_stop_clone = Arc::clone(&checkpoint_watcher_stop);
+        let password_clone = password.clone();
+
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(watch_checkpoint(
+                checkpoint_clone,
+                checkpoint_path_clone,
+                fs_clone,
+                checkpoint_watcher_stop_clone,
+                password_clone,
+            ));
+        })
+    };
 
     let files_stream = stream::iter(files_to_encrypt);
 
     files_stream
-        .for_each_concurrent(MAX_CONCURRENT_FILES, |file_path| {
+        .for_each_concurrent(concurrency, |file_path| {
             let pb_clone = pb.clone();
             let password_clone = password.clone();
             let fs_clone = Arc::clone(&fs);
@@ -136,6 +197,8 @@ pub async fn encrypt(matches: &ArgMatches) {
             let semaphore_clone = Arc::clone(&semaphore);
             let files_set_clone = Arc::clone(&files_set);
             let json_progress_clone = json_progress.clone();
+            let checkpoint_clone = Arc::clone(&checkpoint);
+            let key_clone = key.clone();
 
             async move {
                 let mut guard = files_set_clone.lock().await;
@@ -156,6 +219,12 @@ pub async fn encrypt(matches: &ArgMatches) {
                             *already_encrypted_amount_guard += 1;
                         }
 
+                        checkpoint_clone
+                            .lock()
+                            .unwrap()
+                            .completed_files
+                            .push(file_path_clone.clone());
+
                         if let Some(progress) = &json_progress_clone {
                             progress.inc_by(1);
                         } else {
@@ -172,11 +241,24 @@ pub async fn encrypt(matches: &ArgMatches) {
                     )
                     .await?;
 
+                    // A signature covers the stored bytes, which just changed.
+                    if file_path_clone == chunk_index_path(&key_clone)
+                        || file_path_clone.starts_with(&backup_path(&key_clone, ""))
+                    {
+                        remove_signature(&fs_clone, &key_clone, &file_path_clone).await?;
+                    }
+
                     {
                         let mut encrypted_amount_guard = encrypted_amount_clone.lock().unwrap();
                         *encrypted_amount_guard += 1;
                     }
 
+                    checkpoint_clone
+                        .lock()
+                        .unwrap()
+                        .completed_files
+                        .push(file_path_clone.clone());
+
                     if let Some(progress) = &json_progress_clone {
                         progress.inc_by(1);
                     } else {
@@ -201,10 +283,18 @@ pub async fn encrypt(matches: &ArgMatches) {
         }
     }
 
+    checkpoint_watcher_stop.store(true, Ordering::SeqCst);
+
+    // The watcher may be in the middle of a save; wait for it so it cannot write the
+    // checkpoint again after the final save below, or recreate it after the delete.
+    let _ = tokio::task::spawn_blocking(move || checkpoint_watcher.join()).await;
+
     if !failed_files.is_empty() {
+        let _ = save_checkpoint(&fs, &checkpoint_path, &checkpoint, &password).await;
+
         handle_error(
             format!(
-                "Failed to process {} files:\n{}",
+                "Failed to process {} files:\n{}\n\nRun the same command again to resume from where it stopped.",
                 failed_files.len(),
                 failed_files
                     .iter()
@@ -216,6 +306,8 @@ pub async fn encrypt(matches: &ArgMatches) {
         );
     }
 
+    let _ = fs.delete_file(&checkpoint_path).await;
+
     let encrypted_amount = encrypted_amount.lock().unwrap();
     let already_encrypted_amount = already_encrypted_amount.lock().unwrap();
 
@@ -246,6 +338,80 @@ pub async fn encrypt(matches: &ArgMatches) {
     }
 }
 
+async fn load_checkpoint(
+    fs: &Arc<dyn FS>,
+    checkpoint_path: &str,
+    password: &Option<String>,
+) -> Result<EncryptCheckpoint, String> {
+    let read_result = read_file_maybe_decrypt(
+        fs,
+        checkpoint_path,
+        password.as_deref(),
+        "Encrypt checkpoint is encrypted but no password provided",
+    )
+    .await?;
+
+    if read_result.bytes.is_empty() {
+        return Ok(EncryptCheckpoint::default());
+    }
+
+    let decompressed_bytes = decompress_bytes(&read_result.bytes)
+        .map_err(|e| format!("Failed to decompress encrypt checkpoint: {}", e))?;
+
+    rmp_serde::from_slice(&decompressed_bytes)
+        .map_err(|e| format!("Failed to deserialize encrypt checkpoint: {}", e))
+}
+
+async fn save_checkpoint(
+    fs: &Arc<dyn FS>,
+    checkpoint_path: &str,
+    checkpoint: &Arc<Mutex<EncryptCheckpoint>>,
+    password: &Option<String>,
+) -> Result<(), String> {
+    let bytes_to_write = {
+        let checkpoint_guard = checkpoint.lock().unwrap();
+        rmp_serde::to_vec_named(&*checkpoint_guard)
+            .map_err(|e| format!("Failed to serialize encrypt checkpoint: {}", e))?
+    };
+
+    let compressed_bytes = compress_bytes(&bytes_to_write, 3)
+        .map_err(|e| format!("Failed to compress encrypt checkpoint: {}", e))?;
+
+    write_file_maybe_encrypt(fs, checkpoint_path, &compressed_bytes, password.as_deref()).await
+}
+
+async fn watch_checkpoint(
+    checkpoint: Arc<Mutex<EncryptCheckpoint>>,
+    checkpoint_path: Arc<String>,
+    fs: Arc<dyn FS>,
+    checkpoint_watcher_stop: Arc<AtomicBool>,
+    password: Option<String>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut last_saved_len = checkpoint.lock().unwrap().completed_files.len();
+
+    loop {
+        interval.tick().await;
+
+        if checkpoint_watcher_stop.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let current_len = checkpoint.lock().unwrap().completed_files.len();
+
+        if current_len == last_saved_len {
+            continue;
+        }
+
+        if save_checkpoint(&fs, &checkpoint_path, &checkpoint, &password)
+            .await
+            .is_ok()
+        {
+            last_saved_len = current_len;
+        }
+    }
+}
+
 async fn load_metadata(
     fs: Arc<dyn FS>,
     key: String,
@@ -279,7 +445,7 @@ async fn load_metadata(
     Ok((chunk_indexes, backup_summaries))
 }
 
-fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>), String> {
+fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>, usize), String> {
     let password: Option<String> = matches
         .get_one::<String>("password")
         .map(|s| s.to_string())
@@ -290,7 +456,7 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
 
     let pwd_string = get_pwd_string();
 
-    let default_key = Path::new(&pwd_string)
+    let folder_key = Path::new(&pwd_string)
         .file_name()
         .unwrap()
         .to_string_lossy()
@@ -298,7 +464,9 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
 
     let key = matches
         .get_one::<String>("key")
-        .map_or_else(|| default_key, |key| key.to_string());
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
 
     let home_dir = home_dir().unwrap();
     let storage_path = home_dir.join(".gib").join("storages");
@@ -329,12 +497,16 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
         return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
     }
 
-    let storage = match matches.get_one::<String>("storage") {
-        Some(storage) => storage.to_string(),
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
         None => {
-            if is_json_mode() {
+            if !can_prompt() {
                 return Err(
-                    "Missing required argument: --storage (required in --mode json)".to_string(),
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
                 );
             }
             let selected_index = Select::new()
@@ -356,5 +528,13 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
         return Err(format!("Storage '{}' not found", storage));
     }
 
-    Ok((key, storage, password))
+    let concurrency = match matches.get_one::<String>("concurrency") {
+        Some(concurrency) => match concurrency.parse::<usize>() {
+            Ok(concurrency) if concurrency > 0 => concurrency,
+            _ => return Err(format!("Invalid concurrency: '{}'", concurrency)),
+        },
+        None => MAX_CONCURRENT_FILES,
+    };
+
+    Ok((key, storage, password, concurrency))
 }
@@ -1,7 +1,11 @@
+use crate::commands::config::{default_key, default_storage};
+use crate::commands::restore::needs_restore;
 use crate::core::crypto::get_password;
-use crate::core::indexes::list_backup_summaries;
+use crate::core::indexes::{
+    backfill_backup_summaries, list_backup_summaries, load_resolved_backup,
+};
 use crate::core::metadata::BackupSummary;
-use crate::output::{emit_output, is_json_mode};
+use crate::output::{can_prompt, emit_output, is_json_mode};
 use crate::utils::{get_fs, get_pwd_string, get_storage, handle_error};
 use bytesize::ByteSize;
 use chrono::{DateTime, Local, SecondsFormat, Utc};
@@ -12,6 +16,8 @@ use crossterm::execute;
 use crossterm::terminal::{Clear, ClearType, disable_raw_mode, enable_raw_mode};
 use dialoguer::Select;
 use dirs::home_dir;
+use futures::stream::{self, StreamExt};
+use std::collections::HashSet;
 use std::io;
 use std::path::Path;
 use std::sync::Arc;
@@ -26,21 +32,50 @@ pub async fn log(matches: &ArgMatches) {
 
     let fs = get_fs(&storage, None);
 
-    let backup_summaries =
+    let mut backup_summaries =
         match list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await {
             Ok(summaries) => summaries,
             Err(e) => handle_error(e, None),
         };
 
+    backfill_backup_summaries(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        &mut backup_summaries,
+    )
+    .await;
+
+    let backup_summaries = match matches.get_one::<String>("author") {
+        Some(author) => filter_by_author(backup_summaries, author),
+        None => backup_summaries,
+    };
+
     if backup_summaries.is_empty() {
         if is_json_mode() {
             let empty: Vec<LogEntry> = Vec::new();
             emit_output(&empty);
         } else {
-            println!(
-                "{}",
-                style("No backups found for this repository.").yellow()
-            );
+            let message = match matches.get_one::<String>("author") {
+                Some(author) => format!("No backups found for author '{}'.", author),
+                None => "No backups found for this repository.".to_string(),
+            };
+            println!("{}", style(message).yellow());
+        }
+        return;
+    }
+
+    if let Some(root_path) = matches.get_one::<String>("compare-worktree") {
+        let drift =
+            match compare_worktree(fs, key, password, &backup_summaries[0].hash, root_path).await {
+                Ok(drift) => drift,
+                Err(e) => handle_error(e, None),
+            };
+
+        if is_json_mode() {
+            emit_output(&drift);
+        } else {
+            drift.print();
         }
         return;
     }
@@ -51,6 +86,8 @@ pub async fn log(matches: &ArgMatches) {
             .map(|backup| LogEntry::from_summary(backup))
             .collect::<Vec<LogEntry>>();
         emit_output(&entries);
+    } else if matches.get_flag("graph") {
+        display_size_graph(&backup_summaries);
     } else {
         display_paginated_backups(&backup_summaries);
     }
@@ -67,7 +104,7 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
 
     let pwd_string = get_pwd_string();
 
-    let default_key = Path::new(&pwd_string)
+    let folder_key = Path::new(&pwd_string)
         .file_name()
         .unwrap()
         .to_string_lossy()
@@ -75,7 +112,9 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
 
     let key = matches
         .get_one::<String>("key")
-        .map_or_else(|| default_key, |key| key.to_string());
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
 
     let home_dir = home_dir().unwrap();
     let storage_path = home_dir.join(".gib").join("storages");
@@ -106,12 +145,16 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
         return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
     }
 
-    let storage = match matches.get_one::<String>("storage") {
-        Some(storage) => storage.to_string(),
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
         None => {
-            if is_json_mode() {
+            if !can_prompt() {
                 return Err(
-                    "Missing required argument: --storage (required in --mode json)".to_string(),
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
                 );
             }
             let selected_index = Select::new()
@@ -137,6 +180,23 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
 }
 
 const BACKUPS_PER_PAGE: usize = 10;
+const MAX_CONCURRENT_FILES: usize = 100;
+/// How many paths of each kind `--compare-worktree` prints; JSON lists them all.
+const DRIFT_PATHS_SHOWN: usize = 20;
+/// Keeps the summaries whose author contains `author` (case-insensitive).
+fn filter_by_author(backup_summaries: Vec<BackupSummary>, author: &str) -> Vec<BackupSummary> {
+    let needle = author.to_lowercase();
+
+    backup_summaries
+        .into_iter()
+        .filter(|summary| {
+            summary
+                .author
+                .as_ref()
+                .is_some_and(|a| a.to_lowercase().contains(&needle))
+        })
+        .collect()
+}
 
 #[derive(serde::Serialize)]
 struct LogEntry {
@@ -144,11 +204,20 @@ struct LogEntry {
     backup_short: String,
     message: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    author: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     timestamp: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     timestamp_unix: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
     size_bytes: Option<u64>,
+    /// Hash of the tree for content-addressed backups; backups with the same `content`
+    /// are the same snapshot taken at different times.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>,
+    /// The backup a differential backup is based on.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base: Option<String>,
 }
 
 impl LogEntry {
@@ -162,13 +231,87 @@ impl LogEntry {
             backup: summary.hash.clone(),
             backup_short: summary.hash[..8.min(summary.hash.len())].to_string(),
             message: summary.message.clone(),
+            author: summary.author.clone(),
             timestamp,
             timestamp_unix: summary.timestamp,
             size_bytes: summary.size,
+            content: summary.content.clone(),
+            base: summary.base.clone(),
         }
     }
 }
 
+/// Prints one bar per backup, oldest first, scaled to the largest backup. The size of a
+/// backup is the new data it wrote, so a bar much longer than usual is a backup that
+/// suddenly ballooned; bars above twice the median are highlighted.
+fn display_size_graph(backup_summaries: &[BackupSummary]) {
+    let term = Term::stdout();
+    let is_tty = term.is_term();
+    let width = if is_tty { term.size().1 as usize } else { 80 };
+    let bar_char = if is_tty { "█" } else { "#" };
+
+    let mut summaries = backup_summaries.iter().collect::<Vec<&BackupSummary>>();
+    summaries.sort_by_key(|summary| summary.timestamp.unwrap_or(0));
+
+    let max_size = summaries
+        .iter()
+        .filter_map(|summary| summary.size)
+        .max()
+        .unwrap_or(0);
+
+    let mut sizes = summaries
+        .iter()
+        .filter_map(|summary| summary.size)
+        .collect::<Vec<u64>>();
+    sizes.sort_unstable();
+    let median_size = sizes.get(sizes.len() / 2).copied().unwrap_or(0);
+
+    // "YYYY-MM-DD HH:MM" + hash + size column, with the spaces between them.
+    let bar_width = width.saturating_sub(16 + 1 + 8 + 1 + 1 + 12).max(10);
+
+    println!("{}", style("New data written per backup").bold());
+
+    for summary in summaries {
+        let date = summary
+            .timestamp
+            .and_then(|ts| DateTime::<Utc>::from_timestamp_secs(ts as i64))
+            .map(|dt| {
+                dt.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+            .unwrap_or_else(|| "unknown date".to_string());
+        let hash_short = &summary.hash[..8.min(summary.hash.len())];
+
+        let (bar, size_label) = match summary.size {
+            Some(size) => {
+                let length = if max_size == 0 {
+                    0
+                } else {
+                    ((size as f64 / max_size as f64) * bar_width as f64).round() as usize
+                };
+                let length = if size > 0 { length.max(1) } else { 0 };
+                let bar = format!("{:<bar_width$}", bar_char.repeat(length));
+                let bar = if median_size > 0 && size > median_size * 2 {
+                    style(bar).yellow()
+                } else {
+                    style(bar).cyan()
+                };
+                (bar, ByteSize(size).to_string())
+            }
+            None => (style(format!("{:<bar_width$}", "")), "unknown".to_string()),
+        };
+
+        println!(
+            "{:<16} {} {} {:>12}",
+            date,
+            style(hash_short).dim(),
+            bar,
+            size_label
+        );
+    }
+}
+
 fn display_paginated_backups(backup_summaries: &[BackupSummary]) {
     let total_backups = backup_summaries.len();
     let total_pages = (total_backups + BACKUPS_PER_PAGE - 1) / BACKUPS_PER_PAGE;
@@ -205,6 +348,38 @@ fn display_paginated_backups(backup_summaries: &[BackupSummary]) {
                 parts.push(style(format!("Size: {}", ByteSize(size))).dim());
             }
 
+            if let Some(author) = &backup.author {
+                parts.push(style(format!("\r\nAuthor: {}", author)).dim());
+            }
+
+            if let Some(base) = &backup.base {
+                parts.push(
+                    style(format!(
+                        "\r\nDifferential of: {}",
+                        &base[..8.min(base.len())]
+                    ))
+                    .dim(),
+                );
+            }
+
+            if let Some(content) = &backup.content {
+                let shared_by = backup_summaries
+                    .iter()
+                    .filter(|summary| summary.content.as_ref() == Some(content))
+                    .count();
+                let content_short = &content[..8.min(content.len())];
+                let content_label = if shared_by > 1 {
+                    format!(
+                        "\r\nContent: {} (shared with {} other backups)",
+                        content_short,
+                        shared_by - 1
+                    )
+                } else {
+                    format!("\r\nContent: {}", content_short)
+                };
+                parts.push(style(content_label).dim());
+            }
+
             let line = parts
                 .iter()
                 .map(|p| p.to_string())
@@ -266,3 +441,181 @@ fn display_paginated_backups(backup_summaries: &[BackupSummary]) {
     disable_raw_mode().unwrap_or(());
     term.clear_screen().unwrap_or(());
 }
+
+/// What `--compare-worktree` found: the files of `root` that are not in `backup`, that
+/// differ from it or that it has but `root` no longer does. `changed_bytes` is the current
+/// size of the new and modified files, about what the next backup would read.
+#[derive(serde::Serialize)]
+struct WorktreeDrift {
+    backup: String,
+    root: String,
+    new: Vec<String>,
+    modified: Vec<String>,
+    deleted: Vec<String>,
+    unchanged: u64,
+    changed_bytes: u64,
+    deleted_bytes: u64,
+}
+
+impl WorktreeDrift {
+    fn print(&self) {
+        println!(
+            "Compared to backup {}:",
+            style(&self.backup[..8.min(self.backup.len())])
+                .cyan()
+                .bold()
+        );
+
+        if self.new.is_empty() && self.modified.is_empty() && self.deleted.is_empty() {
+            println!(
+                "{}",
+                style(format!(
+                    "Nothing changed ({} files up to date).",
+                    self.unchanged
+                ))
+                .green()
+            );
+            return;
+        }
+
+        print_drift_paths("New", &self.new, console::Color::Green);
+        print_drift_paths("Modified", &self.modified, console::Color::Yellow);
+        print_drift_paths("Deleted", &self.deleted, console::Color::Red);
+
+        println!();
+        println!(
+            "{} new, {} modified, {} deleted, {} unchanged",
+            self.new.len(),
+            self.modified.len(),
+            self.deleted.len(),
+            self.unchanged
+        );
+        println!(
+            "Changed: {} ({} deleted)",
+            ByteSize(self.changed_bytes),
+            ByteSize(self.deleted_bytes)
+        );
+    }
+}
+
+fn print_drift_paths(label: &str, paths: &[String], color: console::Color) {
+    if paths.is_empty() {
+        return;
+    }
+
+    println!("{} ({}):", label, paths.len());
+    for path in paths.iter().take(DRIFT_PATHS_SHOWN) {
+        println!("  {}", style(path).fg(color));
+    }
+    if paths.len() > DRIFT_PATHS_SHOWN {
+        println!(
+            "{}",
+            style(format!(
+                "  ... and {} more",
+                paths.len() - DRIFT_PATHS_SHOWN
+            ))
+            .dim()
+        );
+    }
+}
+
+/// Walks `root_path` and compares every file against the tree of `backup_hash`, the same
+/// way `restore` decides whether a file needs restoring: a different size is a change, a
+/// matching size and modification time is not, and anything else is hashed.
+async fn compare_worktree(
+    fs: Arc<dyn crate::fs::FS>,
+    key: String,
+    password: Option<String>,
+    backup_hash: &str,
+    root_path: &str,
+) -> Result<WorktreeDrift, String> {
+    let root = Path::new(root_path);
+    if !root.is_dir() {
+        return Err(format!("'{}' is not a directory", root_path));
+    }
+
+    let (backup, _) = load_resolved_backup(fs, key, password, backup_hash.to_string()).await?;
+    let tree = Arc::new(backup.tree);
+
+    let mut local_files = Vec::new();
+    for entry in walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| {
+            entry.file_type().is_file() || (entry.depth() > 0 && entry.path_is_symlink())
+        })
+    {
+        let Ok(relative_path) = entry.path().strip_prefix(root) else {
+            continue;
+        };
+        let relative_path = relative_path.to_string_lossy().replace('\\', "/");
+        let size = entry.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+        local_files.push((relative_path, entry.into_path(), size));
+    }
+
+    let seen = local_files
+        .iter()
+        .map(|(relative_path, _, _)| relative_path.clone())
+        .collect::<HashSet<String>>();
+
+    let results = stream::iter(local_files)
+        .map(|(relative_path, local_path, size)| {
+            let tree = Arc::clone(&tree);
+
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    let change = match tree.get(&relative_path) {
+                        None => Some(false),
+                        Some(backup_object) => {
+                            needs_restore(&local_path, backup_object, true).then_some(true)
+                        }
+                    };
+                    (relative_path, size, change)
+                })
+                .await
+                .ok()
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_FILES)
+        .filter_map(|result| async move { result })
+        .collect::<Vec<(String, u64, Option<bool>)>>()
+        .await;
+
+    let mut drift = WorktreeDrift {
+        backup: backup_hash.to_string(),
+        root: root_path.to_string(),
+        new: Vec::new(),
+        modified: Vec::new(),
+        deleted: Vec::new(),
+        unchanged: 0,
+        changed_bytes: 0,
+        deleted_bytes: 0,
+    };
+
+    for (relative_path, size, change) in results {
+        match change {
+            None => drift.unchanged += 1,
+            Some(modified) => {
+                drift.changed_bytes += size;
+                if modified {
+                    drift.modified.push(relative_path);
+                } else {
+                    drift.new.push(relative_path);
+                }
+            }
+        }
+    }
+
+    for (relative_path, backup_object) in tree.iter() {
+        if !seen.contains(relative_path) {
+            drift.deleted_bytes += backup_object.size;
+            drift.deleted.push(relative_path.clone());
+        }
+    }
+
+    drift.new.sort();
+    drift.modified.sort();
+    drift.deleted.sort();
+
+    Ok(drift)
+}
@@ -1,19 +1,40 @@
+mod apply_ownership;
 mod backup;
+mod cat;
+mod check;
 mod config;
 mod delete;
+mod diff;
+mod doctor;
 mod encrypt;
 mod log;
 mod pending;
+mod reindex;
+mod repair;
 mod restore;
+mod spec;
+mod stats;
+mod verify;
 mod whoami;
 
 pub mod storage;
+pub mod trash;
 
+pub use apply_ownership::apply_ownership;
 pub use backup::backup;
+pub use cat::cat;
+pub use check::check;
 pub use config::config;
 pub use delete::delete;
+pub use diff::diff;
+pub use doctor::doctor;
 pub use encrypt::encrypt;
 pub use log::log;
 pub use pending::pending;
+pub use reindex::reindex;
+pub use repair::repair;
 pub use restore::restore;
+pub use spec::spec;
+pub use stats::stats;
+pub use verify::verify;
 pub use whoami::whoami;
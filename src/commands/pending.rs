@@ -1,6 +1,7 @@
+use crate::commands::config::{default_key, default_storage};
 use crate::core::crypto::{get_password, read_file_maybe_decrypt};
 use crate::core::metadata::PendingBackup;
-use crate::output::{emit_output, emit_progress_message, is_json_mode};
+use crate::output::{can_prompt, emit_output, emit_progress_message, is_json_mode};
 use crate::utils::{decompress_bytes, get_fs, get_pwd_string, get_storage, handle_error};
 use bytesize::ByteSize;
 use clap::ArgMatches;
@@ -26,6 +27,7 @@ struct PendingBackupEntry {
     message: String,
     uploaded_chunks: usize,
     chunk_size_bytes: u64,
+    chunker: &'static str,
     compress: i32,
     concurrency: usize,
     ignored_entries: usize,
@@ -154,7 +156,8 @@ async fn load_pending_backup_entry(
         return Err(format!("Pending backup '{}' is empty", pending_path));
     }
 
-    let decompressed_bytes = decompress_bytes(&pending_result.bytes);
+    let decompressed_bytes = decompress_bytes(&pending_result.bytes)
+        .map_err(|e| format!("Failed to decompress pending backup: {}", e))?;
 
     let pending_backup: PendingBackup =
         rmp_serde::from_slice(&decompressed_bytes).map_err(|e| {
@@ -173,6 +176,9 @@ async fn load_pending_backup_entry(
         message: pending_backup.message,
         uploaded_chunks: pending_backup.processed_chunks.len(),
         chunk_size_bytes: pending_backup.chunk_size,
+        chunker: pending_backup
+            .chunker
+            .map_or("fixed", |chunker| chunker.name()),
         compress: pending_backup.compress,
         concurrency: pending_backup.concurrency,
         ignored_entries: pending_backup.ignore_patterns.len(),
@@ -219,9 +225,10 @@ fn display_paginated_pending_backups(entries: &[PendingBackupEntry]) {
             ];
 
             let details = format!(
-                "Uploaded chunks: {} | Chunk size: {} | Compress: {} | Concurrency: {} | Ignored: {}",
+                "Uploaded chunks: {} | Chunk size: {} ({}) | Compress: {} | Concurrency: {} | Ignored: {}",
                 backup.uploaded_chunks,
                 ByteSize(backup.chunk_size_bytes),
+                backup.chunker,
                 backup.compress,
                 backup.concurrency,
                 backup.ignored_entries
@@ -301,7 +308,7 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
 
     let pwd_string = get_pwd_string();
 
-    let default_key = Path::new(&pwd_string)
+    let folder_key = Path::new(&pwd_string)
         .file_name()
         .unwrap()
         .to_string_lossy()
@@ -309,7 +316,9 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
 
     let key = matches
         .get_one::<String>("key")
-        .map_or_else(|| default_key, |key| key.to_string());
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
 
     let home_dir = home_dir().unwrap();
     let storage_path = home_dir.join(".gib").join("storages");
@@ -340,12 +349,16 @@ fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>),
         return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
     }
 
-    let storage = match matches.get_one::<String>("storage") {
-        Some(storage) => storage.to_string(),
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
         None => {
-            if is_json_mode() {
+            if !can_prompt() {
                 return Err(
-                    "Missing required argument: --storage (required in --mode json)".to_string(),
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
                 );
             }
             let selected_index = Select::new()
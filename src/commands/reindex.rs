@@ -0,0 +1,538 @@
+use crate::commands::config::{default_key, default_storage};
+use crate::core::crypto::get_password;
+use crate::core::indexes::{
+    list_backup_summaries, load_backup, load_chunk_indexes, replace_backup_summaries,
+    replace_chunk_indexes,
+};
+use crate::core::layout::{chunk_hash_from_path, chunk_index_path};
+use crate::core::metadata::{Backup, BackupSummary, ChunkIndex};
+use crate::output::{
+    JsonProgress, can_prompt, emit_output, emit_progress_message, emit_warning, is_json_mode,
+};
+use crate::utils::{get_fs, get_pwd_string, get_storage, handle_error};
+use clap::ArgMatches;
+use dialoguer::Select;
+use dirs::home_dir;
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex as TokioMutex, Semaphore};
+use tokio::task::JoinSet;
+
+const MAX_CONCURRENT_BACKUPS: usize = 100;
+
+pub async fn reindex(matches: &ArgMatches) {
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let started_at = Instant::now();
+    let dry_run = matches.get_flag("dry-run");
+    let verify_chunks = matches.get_flag("verify-chunks");
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb.set_message("Listing backup manifests...");
+        pb
+    };
+
+    if is_json_mode() {
+        emit_progress_message("Listing backup manifests...");
+    }
+
+    let backups_folder = format!("{}/backups", key);
+
+    let backup_hashes = match fs.list_files(&backups_folder).await {
+        Ok(files) => files
+            .iter()
+            .filter_map(|file| file.split('/').next_back().map(|hash| hash.to_string()))
+            .collect::<Vec<String>>(),
+        Err(e) => handle_error(format!("Failed to list backup manifests: {}", e), Some(&pb)),
+    };
+
+    if backup_hashes.is_empty() {
+        handle_error(
+            format!("No backup manifests found for repository '{}'", key),
+            Some(&pb),
+        );
+    }
+
+    let previous_chunk_indexes = match load_chunk_indexes(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        Arc::new(Mutex::new(false)),
+    )
+    .await
+    {
+        Ok(chunk_indexes) => chunk_indexes,
+        Err(e) => {
+            emit_warning(
+                &format!(
+                    "Existing chunk indexes could not be read, rebuilding from scratch: {}",
+                    e
+                ),
+                "chunk_indexes_unreadable",
+            );
+            HashMap::new()
+        }
+    };
+
+    let previous_backup_summaries =
+        match list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await {
+            Ok(summaries) => summaries,
+            Err(e) => {
+                emit_warning(
+                    &format!(
+                        "Existing backup summaries could not be read, rebuilding from scratch: {}",
+                        e
+                    ),
+                    "backup_summaries_unreadable",
+                );
+                Vec::new()
+            }
+        };
+
+    pb.finish_and_clear();
+
+    let json_progress = if is_json_mode() {
+        let progress = JsonProgress::new(backup_hashes.len() as u64);
+        progress.set_message("Reading backup manifests...");
+        Some(progress)
+    } else {
+        None
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(backup_hashes.len() as u64);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap(),
+        );
+        pb.set_message("Reading backup manifests...");
+        pb
+    };
+
+    let backups = Arc::new(Mutex::new(Vec::<Backup>::new()));
+    let backups_set = Arc::new(TokioMutex::new(JoinSet::new()));
+    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_BACKUPS));
+
+    let backups_stream = stream::iter(backup_hashes);
+
+    backups_stream
+        .for_each_concurrent(MAX_CONCURRENT_BACKUPS, |backup_hash| {
+            let pb_clone = pb.clone();
+            let fs_clone = Arc::clone(&fs);
+            let key_clone = key.clone();
+            let password_clone = password.clone();
+            let backups_clone = Arc::clone(&backups);
+            let semaphore_clone = Arc::clone(&semaphore);
+            let backups_set_clone = Arc::clone(&backups_set);
+            let json_progress_clone = json_progress.clone();
+
+            async move {
+                let mut guard = backups_set_clone.lock().await;
+                guard.spawn(async move {
+                    let _permit = semaphore_clone.acquire().await.expect("Semaphore closed");
+
+                    let backup =
+                        load_backup(fs_clone, key_clone, password_clone, backup_hash.clone())
+                            .await
+                            .map_err(|e| format!("{}: {}", backup_hash, e))?;
+
+                    backups_clone.lock().unwrap().push(backup);
+
+                    if let Some(progress) = &json_progress_clone {
+                        progress.inc_by(1);
+                    } else {
+                        pb_clone.inc(1);
+                    }
+                    Ok(())
+                });
+            }
+        })
+        .await;
+
+    let mut failed_backups = Vec::new();
+
+    {
+        let mut guard = backups_set.lock().await;
+        while let Some(backup_process_result) = guard.join_next().await {
+            match backup_process_result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => failed_backups.push(e),
+                Err(e) => failed_backups.push(e.to_string()),
+            }
+        }
+    }
+
+    if !failed_backups.is_empty() {
+        handle_error(
+            format!(
+                "Failed to read {} backup manifests, refusing to rebuild indexes from partial data:\n{}",
+                failed_backups.len(),
+                failed_backups
+                    .iter()
+                    .map(|f| format!("  - {}", f))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+            Some(&pb),
+        );
+    }
+
+    let mut backups = std::mem::take(&mut *backups.lock().unwrap());
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.timestamp));
+
+    let previous_summaries_by_hash: HashMap<&str, &BackupSummary> = previous_backup_summaries
+        .iter()
+        .map(|summary| (summary.hash.as_str(), summary))
+        .collect();
+
+    // A manifest written with --content-addressed-manifests is shared by every backup with
+    // the same tree. Those backups only exist in the summaries, so they are kept from there
+    // and each one counts as a reference to the chunks of the manifest.
+    let mut shared_summaries: HashMap<&str, Vec<&BackupSummary>> = HashMap::new();
+    for summary in &previous_backup_summaries {
+        if let Some(content) = &summary.content {
+            shared_summaries
+                .entry(content.as_str())
+                .or_default()
+                .push(summary);
+        }
+    }
+
+    let mut chunk_indexes: HashMap<String, ChunkIndex> = HashMap::new();
+    let mut backup_summaries = Vec::new();
+
+    for backup in backups.iter() {
+        let summaries = match shared_summaries.get(backup.hash.as_str()) {
+            Some(summaries) => summaries
+                .iter()
+                .map(|summary| (*summary).clone())
+                .collect::<Vec<BackupSummary>>(),
+            None => vec![BackupSummary {
+                message: backup.message.clone(),
+                hash: backup.hash.clone(),
+                timestamp: Some(backup.timestamp),
+                size: previous_summaries_by_hash
+                    .get(backup.hash.as_str())
+                    .and_then(|summary| summary.size),
+                author: Some(backup.author.clone()),
+                content: None,
+                base: backup.base.clone(),
+            }],
+        };
+
+        for _ in 0..summaries.len() {
+            for backup_object in backup.tree.values() {
+                for chunk_hash in &backup_object.chunks {
+                    chunk_indexes
+                        .entry(chunk_hash.clone())
+                        .or_insert(ChunkIndex { refcount: 0 })
+                        .refcount += 1;
+                }
+            }
+        }
+
+        backup_summaries.extend(summaries);
+    }
+
+    backup_summaries.sort_by_key(|summary| std::cmp::Reverse(summary.timestamp.unwrap_or(0)));
+
+    let restored_summaries = backup_summaries
+        .iter()
+        .filter(|summary| !previous_summaries_by_hash.contains_key(summary.hash.as_str()))
+        .count();
+
+    let rebuilt_hashes: HashSet<&str> = backup_summaries
+        .iter()
+        .map(|summary| summary.hash.as_str())
+        .collect();
+
+    let dropped_summaries = previous_backup_summaries
+        .iter()
+        .filter(|summary| !rebuilt_hashes.contains(summary.hash.as_str()))
+        .count();
+
+    let added_chunks = chunk_indexes
+        .keys()
+        .filter(|chunk_hash| !previous_chunk_indexes.contains_key(*chunk_hash))
+        .count();
+
+    let removed_chunks = previous_chunk_indexes
+        .keys()
+        .filter(|chunk_hash| !chunk_indexes.contains_key(*chunk_hash))
+        .count();
+
+    let changed_refcounts = chunk_indexes
+        .iter()
+        .filter(|(chunk_hash, chunk_index)| {
+            previous_chunk_indexes
+                .get(*chunk_hash)
+                .is_some_and(|previous| previous.refcount != chunk_index.refcount)
+        })
+        .count();
+
+    let missing_chunks = if verify_chunks {
+        pb.set_message("Verifying referenced chunks...");
+        if is_json_mode() {
+            emit_progress_message("Verifying referenced chunks...");
+        }
+
+        let chunks_folder = format!("{}/chunks", key);
+
+        // Stream the listing and strike off every referenced chunk that exists, so only the
+        // referenced hashes are held in memory instead of the whole chunk listing.
+        let mut unseen_chunks = chunk_indexes.keys().collect::<HashSet<&String>>();
+        let mut chunks = fs.list_files_stream(&chunks_folder);
+
+        while let Some(chunk) = chunks.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => handle_error(format!("Failed to list chunks: {}", e), Some(&pb)),
+            };
+
+            if let Some(chunk_hash) = chunk_hash_from_path(&chunk) {
+                unseen_chunks.remove(&chunk_hash);
+            }
+        }
+
+        drop(chunks);
+
+        let mut missing_chunks = unseen_chunks.into_iter().cloned().collect::<Vec<String>>();
+        missing_chunks.sort();
+
+        if !missing_chunks.is_empty() {
+            emit_warning(
+                &format!(
+                    "{} referenced chunks are missing from the storage",
+                    missing_chunks.len()
+                ),
+                "missing_chunks",
+            );
+        }
+
+        Some(missing_chunks)
+    } else {
+        None
+    };
+
+    if !dry_run {
+        pb.set_message("Writing rebuilt indexes...");
+        if is_json_mode() {
+            emit_progress_message("Writing rebuilt indexes...");
+        }
+
+        // Each index is kept as a .bak and read back after it is written. If the backup
+        // index cannot be replaced, the chunk index is put back too so the two stay in step.
+        let previous_chunk_index =
+            match replace_chunk_indexes(&fs, &key, &password, &chunk_indexes).await {
+                Ok(previous_chunk_index) => previous_chunk_index,
+                Err(e) => handle_error(e, Some(&pb)),
+            };
+
+        if let Err(e) = replace_backup_summaries(&fs, &key, &password, &backup_summaries).await {
+            let rollback = match &previous_chunk_index {
+                Some(previous_chunk_index) => match fs.read_file(previous_chunk_index).await {
+                    Ok(bytes) => fs.write_file(&chunk_index_path(&key), &bytes).await,
+                    Err(read_error) => Err(read_error),
+                },
+                None => fs.delete_file(&chunk_index_path(&key)).await,
+            };
+
+            match rollback {
+                Ok(()) => handle_error(
+                    format!("{}; the previous chunk index was put back too", e),
+                    Some(&pb),
+                ),
+                Err(rollback_error) => handle_error(
+                    format!(
+                        "{}; putting the previous chunk index back failed too ({}), restore it from '{}.bak'",
+                        e,
+                        rollback_error,
+                        chunk_index_path(&key)
+                    ),
+                    Some(&pb),
+                ),
+            }
+        }
+    }
+
+    if is_json_mode() {
+        #[derive(serde::Serialize)]
+        struct ReindexOutput {
+            dry_run: bool,
+            backups: usize,
+            chunks: usize,
+            previous_chunks: usize,
+            added_chunks: usize,
+            removed_chunks: usize,
+            changed_refcounts: usize,
+            restored_summaries: usize,
+            dropped_summaries: usize,
+            missing_chunks: Option<Vec<String>>,
+            elapsed_ms: u64,
+        }
+
+        let payload = ReindexOutput {
+            dry_run,
+            backups: backup_summaries.len(),
+            chunks: chunk_indexes.len(),
+            previous_chunks: previous_chunk_indexes.len(),
+            added_chunks,
+            removed_chunks,
+            changed_refcounts,
+            restored_summaries,
+            dropped_summaries,
+            missing_chunks,
+            elapsed_ms: started_at.elapsed().as_millis() as u64,
+        };
+        emit_output(&payload);
+    } else {
+        let elapsed = pb.elapsed();
+        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
+        pb.set_prefix("OK");
+
+        if dry_run {
+            pb.finish_with_message(format!(
+                "Dry run: would rebuild indexes from {} backups with {} chunks ({:.2?})",
+                backup_summaries.len(),
+                chunk_indexes.len(),
+                elapsed
+            ));
+        } else {
+            pb.finish_with_message(format!(
+                "Rebuilt indexes from {} backups with {} chunks ({:.2?})",
+                backup_summaries.len(),
+                chunk_indexes.len(),
+                elapsed
+            ));
+        }
+
+        println!(
+            "  Chunks: {} added, {} removed, {} with a different refcount (previously {})",
+            added_chunks,
+            removed_chunks,
+            changed_refcounts,
+            previous_chunk_indexes.len()
+        );
+        println!(
+            "  Backups: {} restored to the log, {} dropped without a manifest",
+            restored_summaries, dropped_summaries
+        );
+
+        if let Some(missing_chunks) = &missing_chunks {
+            if missing_chunks.is_empty() {
+                println!("  All referenced chunks exist in the storage");
+            } else {
+                println!("  Missing chunks:");
+                for chunk_hash in missing_chunks {
+                    println!("    - {}", chunk_hash);
+                }
+            }
+        }
+    }
+}
+
+fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>), String> {
+    let password: Option<String> = matches
+        .get_one::<String>("password")
+        .map(|s| s.to_string())
+        .map_or_else(
+            || get_password(false, false),
+            |password| Some(password.to_string()),
+        );
+
+    let pwd_string = get_pwd_string();
+
+    let folder_key = Path::new(&pwd_string)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    Ok((key, storage, password))
+}
@@ -0,0 +1,464 @@
+use crate::commands::config::default_storage;
+use crate::core::chunks::fetch_verified_chunk;
+use crate::core::crypto::get_password;
+use crate::core::indexes::{
+    load_chunk_indexes, load_repository_meta, read_manifest, save_chunk_indexes, write_manifest,
+};
+use crate::core::layout::chunk_path;
+use crate::core::metadata::{
+    ChunkIndex, REPAIR_PLAN_VERSION, RepairAction, RepairPlan, RepairProblem,
+};
+use crate::fs::FS;
+use crate::output::{
+    can_prompt, emit_error_with_details, emit_output, emit_progress_message, is_json_mode,
+};
+use crate::utils::{get_fs, get_storage, handle_error};
+use clap::ArgMatches;
+use console::style;
+use dialoguer::Select;
+use dirs::home_dir;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(serde::Serialize)]
+struct RepairOutput {
+    key: String,
+    problems: usize,
+    results: Vec<RepairResult>,
+    restored_chunks: usize,
+    dropped_files: usize,
+    rewritten_manifests: usize,
+    elapsed_ms: u64,
+}
+
+#[derive(serde::Serialize)]
+struct RepairResult {
+    chunk: String,
+    /// `restore_copy` or `drop_files`, as in the plan.
+    action: &'static str,
+    /// `repaired`, `failed`, or `already_intact` / `already_repaired` when the chunk reads
+    /// fine or no file uses it anymore (nothing was done).
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    dropped_files: Vec<String>,
+}
+
+pub async fn repair(matches: &ArgMatches) {
+    let plan_path = matches
+        .get_one::<String>("plan")
+        .expect("--plan is required");
+
+    let plan = match read_repair_plan(plan_path) {
+        Ok(plan) => plan,
+        Err(e) => handle_error(e, None),
+    };
+
+    let (storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let started_at = Instant::now();
+    let key = plan.key.clone();
+
+    let storage = get_storage(&storage);
+    let fs = get_fs(&storage, None);
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb
+    };
+
+    set_progress_message(&pb, "Loading indexes...");
+
+    let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let mut chunk_indexes = match load_chunk_indexes(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        Arc::new(Mutex::new(false)),
+    )
+    .await
+    {
+        Ok(chunk_indexes) => chunk_indexes,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let mut results = Vec::new();
+    let mut rewritten_manifests = 0;
+
+    for problem in &plan.problems {
+        set_progress_message(
+            &pb,
+            &format!(
+                "Repairing chunk {}...",
+                &problem.chunk[..8.min(problem.chunk.len())]
+            ),
+        );
+
+        let action = match problem.action {
+            RepairAction::RestoreCopy { .. } => "restore_copy",
+            RepairAction::DropFiles => "drop_files",
+        };
+        let path = chunk_path(&key, &problem.chunk, chunk_dir_levels);
+
+        // The plan may be older than the repository: never touch a chunk that reads fine.
+        if fetch_verified_chunk(&fs, &path, &problem.chunk, password.as_deref())
+            .await
+            .is_ok()
+        {
+            results.push(RepairResult {
+                chunk: problem.chunk.clone(),
+                action,
+                status: "already_intact",
+                detail: None,
+                dropped_files: Vec::new(),
+            });
+            continue;
+        }
+
+        let result = match &problem.action {
+            RepairAction::RestoreCopy { source } => {
+                restore_copy(&fs, &key, &password, &problem.chunk, source, &path)
+                    .await
+                    .map(|()| Vec::new())
+            }
+            RepairAction::DropFiles => {
+                drop_files(&fs, &key, &password, problem, &mut chunk_indexes)
+                    .await
+                    .map(|(dropped_files, manifests)| {
+                        rewritten_manifests += manifests;
+                        dropped_files
+                    })
+            }
+        };
+
+        results.push(match result {
+            Ok(dropped_files) => RepairResult {
+                chunk: problem.chunk.clone(),
+                action,
+                status: if action == "drop_files" && dropped_files.is_empty() {
+                    "already_repaired"
+                } else {
+                    "repaired"
+                },
+                detail: None,
+                dropped_files,
+            },
+            Err(e) => RepairResult {
+                chunk: problem.chunk.clone(),
+                action,
+                status: "failed",
+                detail: Some(e),
+                dropped_files: Vec::new(),
+            },
+        });
+    }
+
+    if rewritten_manifests > 0 {
+        set_progress_message(&pb, "Writing the chunk index...");
+
+        if let Err(e) = save_chunk_indexes(
+            Arc::clone(&fs),
+            key.clone(),
+            &chunk_indexes,
+            password.clone(),
+        )
+        .await
+        {
+            handle_error(e, Some(&pb));
+        }
+    }
+
+    let payload = RepairOutput {
+        key,
+        problems: plan.problems.len(),
+        restored_chunks: results
+            .iter()
+            .filter(|result| result.status == "repaired" && result.action == "restore_copy")
+            .count(),
+        dropped_files: results
+            .iter()
+            .map(|result| result.dropped_files.len())
+            .sum(),
+        rewritten_manifests,
+        results,
+        elapsed_ms: started_at.elapsed().as_millis() as u64,
+    };
+
+    let failed = payload
+        .results
+        .iter()
+        .filter(|result| result.status == "failed")
+        .collect::<Vec<&RepairResult>>();
+
+    if !failed.is_empty() {
+        let message = format!(
+            "{} of {} problems could not be repaired:\n{}",
+            failed.len(),
+            payload.problems,
+            failed
+                .iter()
+                .map(|result| format!(
+                    "  - {}: {}",
+                    result.chunk,
+                    result.detail.as_deref().unwrap_or_default()
+                ))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+
+        if is_json_mode() {
+            emit_error_with_details(&message, "repair_failed", &payload);
+        }
+        handle_error(message, Some(&pb));
+    }
+
+    if is_json_mode() {
+        emit_output(&payload);
+        return;
+    }
+
+    pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
+    pb.set_prefix("OK");
+    let repaired = payload
+        .results
+        .iter()
+        .filter(|result| result.status == "repaired")
+        .count();
+    if repaired == payload.problems {
+        pb.finish_with_message(format!(
+            "Repaired {} problems ({:.2?})",
+            repaired,
+            pb.elapsed()
+        ));
+    } else {
+        pb.finish_with_message(format!(
+            "Repaired {} of {} problems, the others needed nothing ({:.2?})",
+            repaired,
+            payload.problems,
+            pb.elapsed()
+        ));
+    }
+
+    for result in &payload.results {
+        let chunk = &result.chunk[..8.min(result.chunk.len())];
+        let line = match (result.status, result.action) {
+            ("already_intact", _) => format!("  - {}: already intact, left as is", chunk),
+            ("already_repaired", _) => format!("  - {}: no file uses it anymore", chunk),
+            (_, "restore_copy") => format!("  - {}: restored from a copy", chunk),
+            _ => format!(
+                "  - {}: dropped {} files: {}",
+                chunk,
+                result.dropped_files.len(),
+                result.dropped_files.join(", ")
+            ),
+        };
+        println!("{}", style(line).dim());
+    }
+
+    if payload.dropped_files > 0 {
+        println!(
+            "{}",
+            style("Chunks no longer used by any backup are removed by 'gib storage prune'.").dim()
+        );
+    }
+}
+
+fn read_repair_plan(path: &str) -> Result<RepairPlan, String> {
+    let json = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read the repair plan {}: {}", path, e))?;
+
+    let plan: RepairPlan =
+        serde_json::from_str(&json).map_err(|e| format!("Invalid repair plan {}: {}", path, e))?;
+
+    if plan.version != REPAIR_PLAN_VERSION {
+        return Err(format!(
+            "Unsupported repair plan version {} (this gib reads version {}); run 'gib verify --output-repair-plan' again",
+            plan.version, REPAIR_PLAN_VERSION
+        ));
+    }
+
+    Ok(plan)
+}
+
+/// Copies `source` over the chunk at `path`, once the copy checks out, and reads the
+/// chunk back.
+async fn restore_copy(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    chunk: &str,
+    source: &str,
+    path: &str,
+) -> Result<(), String> {
+    let source_path = format!("{}/{}", key, source);
+
+    fetch_verified_chunk(fs, &source_path, chunk, password.as_deref())
+        .await
+        .map_err(|e| format!("The copy {} is not intact: {}", source, e.detail()))?;
+
+    let bytes = fs
+        .read_file(&source_path)
+        .await
+        .map_err(|e| format!("Failed to read {}: {}", source, e))?;
+
+    fs.write_file(path, &bytes)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path, e))?;
+
+    fetch_verified_chunk(fs, path, chunk, password.as_deref())
+        .await
+        .map(|_| ())
+        .map_err(|e| format!("The restored chunk does not read back: {}", e.detail()))
+}
+
+/// Removes the files of the plan that still use the chunk from their manifests, and
+/// releases their chunks in the chunk index. In a differential backup they are recorded
+/// as removed, so the restore does not fall back to the base's version. Returns the
+/// dropped files and how many manifests were rewritten.
+async fn drop_files(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    problem: &RepairProblem,
+    chunk_indexes: &mut HashMap<String, ChunkIndex>,
+) -> Result<(Vec<String>, usize), String> {
+    let mut dropped_files = Vec::new();
+    let mut rewritten_manifests = 0;
+
+    for affected in &problem.manifests {
+        let Some(mut backup) = read_manifest(fs, key, password, &affected.manifest).await? else {
+            continue;
+        };
+
+        let mut dropped = false;
+        for file in &affected.files {
+            if !backup
+                .tree
+                .get(file)
+                .is_some_and(|object| object.chunks.contains(&problem.chunk))
+            {
+                continue;
+            }
+
+            let object = backup.tree.remove(file).unwrap();
+            for chunk_hash in &object.chunks {
+                if let Some(chunk_index) = chunk_indexes.get_mut(chunk_hash) {
+                    chunk_index.refcount = chunk_index.refcount.saturating_sub(1);
+                    if chunk_index.refcount == 0 {
+                        chunk_indexes.remove(chunk_hash);
+                    }
+                }
+            }
+
+            if backup.base.is_some() && !backup.removed.contains(file) {
+                backup.removed.push(file.clone());
+            }
+
+            dropped_files.push(format!(
+                "{}:{}",
+                &affected.manifest[..8.min(affected.manifest.len())],
+                file
+            ));
+            dropped = true;
+        }
+
+        if dropped {
+            write_manifest(fs, key, password, &affected.manifest, &backup).await?;
+            rewritten_manifests += 1;
+        }
+    }
+
+    Ok((dropped_files, rewritten_manifests))
+}
+
+fn set_progress_message(pb: &ProgressBar, message: &str) {
+    pb.set_message(message.to_string());
+    if is_json_mode() {
+        emit_progress_message(message);
+    }
+}
+
+fn get_params(matches: &ArgMatches) -> Result<(String, Option<String>), String> {
+    let password: Option<String> = matches
+        .get_one::<String>("password")
+        .map(|s| s.to_string())
+        .map_or_else(
+            || get_password(false, true),
+            |password| Some(password.to_string()),
+        );
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    Ok((storage, password))
+}
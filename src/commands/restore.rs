@@ -1,57 +1,184 @@
+use crate::commands::config::{default_key, default_storage};
+use crate::core::acl::write_acl;
+use crate::core::archive::{ArchiveSource, write_tar};
+use crate::core::atomic::AtomicRestore;
+use crate::core::case_conflicts::{CaseConflict, ConflictResolution, resolve_case_conflicts};
+use crate::core::chunks::{ChunkReadError, fetch_chunk};
 use crate::core::crypto::get_password;
-use crate::core::crypto::read_file_maybe_decrypt;
-use crate::core::indexes::list_backup_summaries;
-use crate::core::metadata::Backup;
+use crate::core::flatten::{CollisionPolicy, FlattenCollision, flatten_files};
+use crate::core::hooks::Hooks;
+use crate::core::indexes::{
+    backfill_backup_summaries, list_backup_summaries, load_repository_meta, load_resolved_backup,
+};
+use crate::core::layout::chunk_path;
+use crate::core::metadata::{Backup, BackupObject};
 use crate::core::only::OnlyRequest;
 use crate::core::only::filter_only_paths;
 use crate::core::only::parse_only_request;
 use crate::core::only::select_only_paths_interactive;
+use crate::core::ownership::{
+    OWNERSHIP_MANIFEST_NAME, OwnerMap, OwnershipEntry, OwnershipManifest, can_restore_ownership,
+    set_file_owner, set_link_owner,
+};
 use crate::core::permissions::set_file_permissions;
 use crate::fs::FS;
-use crate::output::{JsonProgress, emit_output, emit_progress_message, emit_warning, is_json_mode};
-use crate::utils::{decompress_bytes, get_fs, get_pwd_string, get_storage, handle_error};
+use crate::output::{
+    JsonProgress, ProgressBytes, can_prompt, emit_error, emit_error_with_details, emit_output,
+    emit_progress_message, emit_warning, is_json_mode,
+};
+use crate::utils::{
+    DecompressError, decompress_to_writer, get_fs, get_pwd_string, get_storage, handle_error,
+};
+use chrono::{DateTime, Local, Utc};
 use clap::ArgMatches;
 use dialoguer::Select;
 use dirs::home_dir;
 use futures::stream::{self, StreamExt};
 use indicatif::{ProgressBar, ProgressStyle};
 use sha2::{Digest, Sha256};
-use std::collections::HashSet;
-use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::{Duration, Instant};
 use tokio::sync::{Mutex as TokioMutex, Semaphore};
 use tokio::task::JoinSet;
+use tracing::{Instrument, debug, debug_span, info, info_span, trace, warn};
 use walkdir::WalkDir;
 
 const MAX_CONCURRENT_FILES: usize = 100;
+const SPARSE_BLOCK_SIZE: usize = 4096;
+const DEFAULT_MAX_RETRIES: u32 = 2;
 
 pub async fn restore(matches: &ArgMatches) {
+    // The archive is written as raw bytes, which would corrupt the JSON event stream.
+    if matches.get_flag("stdout") && is_json_mode() {
+        emit_error(
+            "restore --stdout writes a tar archive to stdout and cannot be used with --mode json",
+            "restore_stdout_json_mode",
+        );
+    }
+
     let (key, storage, password, backup_hash, target_path, prune_local, only_request) =
         match get_params(matches) {
             Ok(params) => params,
             Err(e) => handle_error(e, None),
         };
 
+    // Running 'gib restore' in the wrong folder is an easy mistake, and with --prune-local
+    // a costly one, so restoring into a non-empty current directory is called out.
+    if matches.get_one::<String>("target-path").is_none()
+        && !matches.contains_id("into-subdir")
+        && !matches.get_flag("stdout")
+        && !matches.get_flag("force")
+        && std::fs::read_dir(&target_path).is_ok_and(|mut entries| entries.next().is_some())
+    {
+        let message = if prune_local {
+            format!(
+                "Restoring into the current directory {}, which is not empty; files that are not in the backup will be deleted. Use --target-path or --into-subdir to restore elsewhere, or --force to silence this warning",
+                target_path
+            )
+        } else {
+            format!(
+                "Restoring into the current directory {}, which is not empty; files with the same paths will be overwritten. Use --target-path or --into-subdir to restore elsewhere, or --force to silence this warning",
+                target_path
+            )
+        };
+        emit_warning(&message, "restore_into_nonempty_cwd");
+    }
+
     let started_at = Instant::now();
+    let sparse = matches.get_flag("sparse");
+    let progress_resume = matches.get_flag("progress-resume");
+    let only_changed = matches.get_flag("only-changed");
+    let touch_only = matches.get_flag("touch-only");
+    let checksum = matches.get_flag("checksum");
+    let continue_on_error = matches.get_flag("continue-on-error");
+    let verify = !matches.get_flag("no-verify");
+
+    let conflict_resolution = match ConflictResolution::parse(
+        matches
+            .get_one::<String>("resolve-conflicts")
+            .map(|value| value.as_str()),
+    ) {
+        Ok(resolution) => resolution,
+        Err(e) => handle_error(e, None),
+    };
+
+    let collision_policy = match CollisionPolicy::parse(
+        matches
+            .get_one::<String>("on-collision")
+            .map(|value| value.as_str()),
+    ) {
+        Ok(policy) => policy,
+        Err(e) => handle_error(e, None),
+    };
+
+    let owner_map = match matches.get_one::<String>("owner-map") {
+        Some(path) => match OwnerMap::from_file(path) {
+            Ok(owner_map) => Some(owner_map),
+            Err(e) => handle_error(e, None),
+        },
+        None => None,
+    };
+
+    let ignore_permissions = matches.get_flag("ignore-permissions");
+    let restore_ownership = !ignore_permissions && can_restore_ownership();
+
+    if owner_map.is_some() && !restore_ownership {
+        emit_warning(
+            "--owner-map requires running as root; file ownership will not be restored",
+            "owner_map_unprivileged",
+        );
+    }
+
+    let owner_map = Arc::new(owner_map.unwrap_or_default());
+
+    let max_retries = match matches.get_one::<String>("max-retries") {
+        Some(retries) => match retries.parse::<u32>() {
+            Ok(retries) => retries,
+            Err(_) => handle_error(
+                format!(
+                    "Invalid value for --max-retries: '{}' (must be a number)",
+                    retries
+                ),
+                None,
+            ),
+        },
+        None => DEFAULT_MAX_RETRIES,
+    };
 
     let storage = get_storage(&storage);
 
     let fs = get_fs(&storage, None);
 
-    let full_backup_hash = match resolve_backup_hash(
-        Arc::clone(&fs),
-        key.clone(),
-        password.clone(),
-        backup_hash,
-    )
-    .await
-    {
-        Ok(hash) => hash,
+    let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+        Err(e) => handle_error(e, None),
+    };
+
+    let fallback_fs = matches
+        .get_one::<String>("fallback-storage")
+        .map(|name| get_fs(&get_storage(name), None));
+
+    let merge_range = match get_merge_range(matches) {
+        Ok(range) => range,
         Err(e) => handle_error(e, None),
     };
 
+    let full_backup_hash = match &merge_range {
+        Some(_) => String::new(),
+        None => {
+            match resolve_backup_hash(Arc::clone(&fs), key.clone(), password.clone(), backup_hash)
+                .await
+            {
+                Ok(hash) => hash,
+                Err(e) => handle_error(e, None),
+            }
+        }
+    };
+
     let pb = if is_json_mode() {
         ProgressBar::hidden()
     } else {
@@ -66,46 +193,304 @@ pub async fn restore(matches: &ArgMatches) {
         emit_progress_message("Loading backup data...");
     }
 
-    let backup = match load_backup(
-        Arc::clone(&fs),
-        key.clone(),
-        password.clone(),
-        &full_backup_hash,
-    )
-    .await
-    {
-        Ok(backup) => backup,
-        Err(e) => handle_error(e, Some(&pb)),
+    let (full_backup_hash, tree, merged_backups) = match merge_range {
+        Some(range) => {
+            match load_merged_tree(Arc::clone(&fs), key.clone(), password.clone(), range)
+                .instrument(info_span!("load_merged_tree"))
+                .await
+            {
+                Ok(merged) => merged,
+                Err(e) => handle_error(e, Some(&pb)),
+            }
+        }
+        None => {
+            let (backup, chain_length) = match load_resolved_backup(
+                Arc::clone(&fs),
+                key.clone(),
+                password.clone(),
+                full_backup_hash.clone(),
+            )
+            .instrument(info_span!("load_backup", backup = %full_backup_hash))
+            .await
+            {
+                Ok(resolved) => resolved,
+                Err(e) => handle_error(e, Some(&pb)),
+            };
+
+            if chain_length > 0 {
+                debug!(chain_length, "resolved differential chain");
+            }
+
+            (full_backup_hash, backup.tree, Vec::new())
+        }
     };
 
     pb.finish_and_clear();
 
     let files_to_restore = match only_request {
-        OnlyRequest::None => backup
-            .tree
-            .iter()
-            .map(|(k, v)| (k.clone(), v.clone()))
-            .collect(),
-        OnlyRequest::Paths(paths) => match filter_only_paths(&backup.tree, &paths) {
+        OnlyRequest::None => tree.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        OnlyRequest::Paths(paths) => match filter_only_paths(&tree, &paths) {
             Ok(files) => files,
             Err(e) => handle_error(e, None),
         },
         OnlyRequest::Interactive => {
-            let selected_paths = match select_only_paths_interactive(&backup.tree) {
+            let selected_paths = match select_only_paths_interactive(&tree) {
                 Ok(paths) => paths,
                 Err(e) => handle_error(e, None),
             };
-            match filter_only_paths(&backup.tree, &selected_paths) {
+            match filter_only_paths(&tree, &selected_paths) {
                 Ok(files) => files,
                 Err(e) => handle_error(e, None),
             }
         }
     };
 
+    // Tree keys come from the repository, so a corrupted or crafted backup could hold one
+    // that escapes the target once joined to it. Those files fail instead of being written.
+    let (files_to_restore, unsafe_files) = reject_unsafe_paths(files_to_restore);
+
+    if matches.get_flag("stdout") {
+        restore_to_stdout(
+            ArchiveSource {
+                fs,
+                key,
+                password,
+                chunk_dir_levels,
+            },
+            files_to_restore,
+            unsafe_files,
+            verify,
+        )
+        .await;
+        return;
+    }
+
+    let (files_to_restore, flatten_collisions) = if matches.get_flag("flatten") {
+        flatten_files(files_to_restore, collision_policy)
+    } else {
+        (files_to_restore, Vec::new())
+    };
+
+    if !flatten_collisions.is_empty() {
+        let message = format!(
+            "{} file names are shared by files from different folders:\n{}",
+            flatten_collisions.len(),
+            flatten_collisions
+                .iter()
+                .map(|collision| format!("  - {}", collision))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+
+        match collision_policy {
+            CollisionPolicy::Fail => {
+                let message = format!(
+                    "{}\nUse --on-collision number or --on-collision skip to restore anyway",
+                    message
+                );
+                if is_json_mode() {
+                    emit_error_with_details(&message, "flatten_collision", &flatten_collisions);
+                }
+                handle_error(message, None);
+            }
+            CollisionPolicy::Number => emit_warning(&message, "flatten_collision_numbered"),
+            CollisionPolicy::Skip => emit_warning(&message, "flatten_collision_skipped"),
+        }
+    }
+
+    if matches.contains_id("into-subdir")
+        && let Err(e) = std::fs::create_dir_all(&target_path)
+    {
+        handle_error(
+            format!("Failed to create directory {}: {}", target_path, e),
+            None,
+        );
+    }
+
+    let final_target = target_path.clone();
+    let atomic = if matches.get_flag("atomic") {
+        match AtomicRestore::prepare(Path::new(&final_target)) {
+            Ok(atomic) => Some(atomic),
+            Err(e) => handle_error(e, None),
+        }
+    } else {
+        None
+    };
+    let target_path = atomic.as_ref().map_or(target_path, |atomic| {
+        atomic.staging_path().to_string_lossy().to_string()
+    });
+
+    let (files_to_restore, case_conflicts) = match resolve_case_conflicts(
+        files_to_restore,
+        Path::new(&target_path),
+        conflict_resolution,
+    ) {
+        Ok(resolved) => resolved,
+        Err(e) => handle_error(e, None),
+    };
+
+    if !case_conflicts.is_empty() {
+        let message = format!(
+            "{} sets of files only differ by case and cannot coexist in {}:\n{}",
+            case_conflicts.len(),
+            target_path,
+            case_conflicts
+                .iter()
+                .map(|conflict| format!("  - {}", conflict))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+
+        match conflict_resolution {
+            ConflictResolution::Fail => {
+                let message = format!(
+                    "{}\nUse --resolve-conflicts rename or --resolve-conflicts skip to restore anyway",
+                    message
+                );
+                if is_json_mode() {
+                    emit_error_with_details(&message, "case_conflict", &case_conflicts);
+                }
+                handle_error(message, None);
+            }
+            ConflictResolution::Rename => emit_warning(&message, "case_conflict_renamed"),
+            ConflictResolution::Skip => emit_warning(&message, "case_conflict_skipped"),
+        }
+    }
+
+    if touch_only
+        && files_to_restore
+            .iter()
+            .all(|(_, backup_object)| backup_object.mtime_ns.is_none())
+    {
+        handle_error(
+            "--touch-only needs the modification times of the files, which this backup did not record".to_string(),
+            None,
+        );
+    }
+
+    let (files_to_restore, kept_newer) = if matches.get_flag("exclude-existing-newer") {
+        if files_to_restore
+            .iter()
+            .all(|(_, backup_object)| backup_object.mtime_ns.is_none())
+        {
+            handle_error(
+                "--exclude-existing-newer needs the modification times of the files, which this backup did not record".to_string(),
+                None,
+            );
+        }
+
+        exclude_existing_newer(files_to_restore, Path::new(&target_path))
+    } else {
+        (files_to_restore, Vec::new())
+    };
+
+    if !kept_newer.is_empty() {
+        let message = format!(
+            "Kept {} local files that were modified after the backup:\n{}",
+            kept_newer.len(),
+            kept_newer
+                .iter()
+                .map(|path| format!("  - {}", path))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+        emit_warning(&message, "existing_newer_kept");
+    }
+
+    let renamed_paths = files_to_restore
+        .iter()
+        .filter(|(path, _)| !tree.contains_key(path))
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<String>>();
+
+    let hooks = Hooks::from_matches("restore", matches).map(|hooks| {
+        hooks
+            .with_env("KEY", key.clone())
+            .with_env("BACKUP", full_backup_hash.clone())
+            .with_env("TARGET", final_target.clone())
+    });
+
+    if let Some(hooks) = &hooks {
+        hooks.run_pre_hook();
+    }
+
     let total_files = files_to_restore.len() as u64;
 
+    // With --progress-resume the files that are already correct are found first, so the
+    // progress only covers the bytes that actually have to be restored.
+    let up_to_date = if progress_resume {
+        let pb = if is_json_mode() {
+            ProgressBar::hidden()
+        } else {
+            let pb = ProgressBar::new(100);
+            pb.enable_steady_tick(Duration::from_millis(100));
+            pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+            pb
+        };
+        let message = format!("Checking {} existing files...", total_files);
+        pb.set_message(message.clone());
+        if is_json_mode() {
+            emit_progress_message(&message);
+        }
+
+        let up_to_date = find_up_to_date_files(&target_path, &files_to_restore, !checksum).await;
+        pb.finish_and_clear();
+        Some(up_to_date)
+    } else {
+        None
+    };
+
+    let (progress_total, progress_template) = match &up_to_date {
+        Some(up_to_date) => (
+            files_to_restore
+                .iter()
+                .filter(|(path, _)| !up_to_date.contains(path))
+                .map(|(_, backup_object)| backup_object.size)
+                .sum(),
+            "[{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta}) {msg}",
+        ),
+        None => (
+            total_files,
+            "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+        ),
+    };
+    let up_to_date = up_to_date.map(Arc::new);
+
+    // 'gib apply-ownership' would follow symbolic links, so they are left out.
+    let mut ownership_entries = if matches.get_flag("store-ownership-manifest") {
+        files_to_restore
+            .iter()
+            .filter(|(_, backup_object)| backup_object.link_target.is_none())
+            .filter_map(
+                |(path, backup_object)| match (backup_object.uid, backup_object.gid) {
+                    (Some(uid), Some(gid)) => Some(OwnershipEntry {
+                        path: path.clone(),
+                        uid: owner_map.map_uid(uid),
+                        gid: owner_map.map_gid(gid),
+                        mode: backup_object.permissions,
+                    }),
+                    _ => None,
+                },
+            )
+            .collect::<Vec<OwnershipEntry>>()
+    } else {
+        Vec::new()
+    };
+
     let json_progress = if is_json_mode() {
-        let progress = JsonProgress::new(total_files);
+        let progress = JsonProgress::new(progress_total);
+        if matches.get_flag("json-progress-bytes") {
+            let total_bytes = files_to_restore
+                .iter()
+                .filter(|(path, _)| {
+                    up_to_date
+                        .as_ref()
+                        .is_none_or(|up_to_date| !up_to_date.contains(path))
+                })
+                .map(|(_, backup_object)| backup_object.size)
+                .sum();
+            progress.track_bytes(total_bytes, true);
+        }
         progress.set_message(&format!(
             "Restoring files from {}...",
             full_backup_hash[..8.min(full_backup_hash.len())].to_string()
@@ -118,14 +503,9 @@ pub async fn restore(matches: &ArgMatches) {
     let pb = if is_json_mode() {
         ProgressBar::hidden()
     } else {
-        let pb = ProgressBar::new(total_files);
+        let pb = ProgressBar::new(progress_total);
         pb.enable_steady_tick(Duration::from_millis(100));
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
-            )
-            .unwrap(),
-        );
+        pb.set_style(ProgressStyle::with_template(progress_template).unwrap());
         pb.set_message(format!(
             "Restoring files from {}...",
             full_backup_hash[..8.min(full_backup_hash.len())].to_string()
@@ -136,6 +516,14 @@ pub async fn restore(matches: &ArgMatches) {
     let files_set = Arc::new(TokioMutex::new(JoinSet::new()));
     let restored_files = Arc::new(std::sync::Mutex::new(0u64));
     let skipped_files = Arc::new(std::sync::Mutex::new(0u64));
+    let touched_files = Arc::new(std::sync::Mutex::new(0u64));
+    let mismatched_files = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+    let permission_failures = Arc::new(std::sync::Mutex::new(Vec::<RestoreFailure>::new()));
+    let acl_failures = Arc::new(std::sync::Mutex::new(Vec::<RestoreFailure>::new()));
+    let changed_files = only_changed.then(|| Arc::new(std::sync::Mutex::new(Vec::new())));
+    let identical_files = matches
+        .get_flag("hardlink-identical")
+        .then(|| Arc::new(IdenticalFiles::new(!ignore_permissions)));
     let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_FILES));
 
     let files_stream = stream::iter(files_to_restore);
@@ -150,123 +538,417 @@ pub async fn restore(matches: &ArgMatches) {
             let relative_path_clone = relative_path.clone();
             let restored_files_clone = Arc::clone(&restored_files);
             let skipped_files_clone = Arc::clone(&skipped_files);
+            let touched_files_clone = Arc::clone(&touched_files);
+            let mismatched_files_clone = Arc::clone(&mismatched_files);
             let semaphore_clone = Arc::clone(&semaphore);
             let files_set_clone = Arc::clone(&files_set);
             let json_progress_clone = json_progress.clone();
+            let owner_map_clone = Arc::clone(&owner_map);
+            let permission_failures_clone = Arc::clone(&permission_failures);
+            let acl_failures_clone = Arc::clone(&acl_failures);
+            let identical_files_clone = identical_files.clone();
+            let changed_files_clone = changed_files.clone();
+            let fallback_fs_clone = fallback_fs.clone();
+            let up_to_date_clone = up_to_date.clone();
+            let file_span = debug_span!("restore_file", file = %relative_path);
 
             async move {
                 let mut guard = files_set_clone.lock().await;
-                guard.spawn(async move {
-                    let _permit = semaphore_clone.acquire().await.expect("Semaphore closed");
-                    let local_path = Path::new(&target_path_clone).join(&relative_path_clone);
-
-                    let needs_restore = if local_path.exists() {
-                        match calculate_file_hash(&local_path) {
-                            Ok(local_hash) => local_hash != backup_object.hash,
-                            Err(_) => true,
+                guard.spawn(
+                    async move {
+                        let _permit = semaphore_clone.acquire().await.expect("Semaphore closed");
+                        let local_path = Path::new(&target_path_clone).join(&relative_path_clone);
+
+                        if let Some(link_target) = &backup_object.link_target {
+                            let restored = !touch_only
+                                && restore_symlink(
+                                    &local_path,
+                                    &relative_path_clone,
+                                    link_target,
+                                    &backup_object,
+                                    restore_ownership,
+                                    &owner_map_clone,
+                                )?;
+
+                            if restored {
+                                debug!("symbolic link restored");
+                                *restored_files_clone.lock().unwrap() += 1;
+                            } else {
+                                *skipped_files_clone.lock().unwrap() += 1;
+                            }
+
+                            if let Some(progress) = &json_progress_clone {
+                                progress.inc_by(1);
+                            } else {
+                                pb_clone.inc(1);
+                            }
+                            return Ok(());
                         }
-                    } else {
-                        true
-                    };
 
-                    if !needs_restore {
-                        {
-                            let mut skipped = skipped_files_clone.lock().unwrap();
-                            *skipped += 1;
+                        if touch_only {
+                            let matched = touch_file(
+                                &local_path,
+                                &relative_path_clone,
+                                &backup_object,
+                                checksum,
+                            )?;
+
+                            if matched {
+                                if !ignore_permissions
+                                    && let Err(failure) = apply_permissions(
+                                        &local_path,
+                                        &relative_path_clone,
+                                        &backup_object,
+                                        restore_ownership,
+                                        &owner_map_clone,
+                                    )
+                                {
+                                    if !continue_on_error {
+                                        return Err(failure);
+                                    }
+
+                                    warn!(error = %failure.detail, "permissions not applied");
+                                    permission_failures_clone.lock().unwrap().push(failure);
+                                }
+
+                                if !ignore_permissions
+                                    && let Some(acl) = &backup_object.acl
+                                    && let Err(e) = write_acl(&local_path, acl, &owner_map_clone)
+                                {
+                                    warn!(error = %e, "ACL not applied");
+                                    acl_failures_clone.lock().unwrap().push(RestoreFailure {
+                                        path: relative_path_clone.clone(),
+                                        reason_code: "acl_error",
+                                        detail: e,
+                                    });
+                                }
+
+                                debug!("file metadata updated");
+                                *touched_files_clone.lock().unwrap() += 1;
+                            } else {
+                                trace!("file content differs, not touched");
+                                *skipped_files_clone.lock().unwrap() += 1;
+                                mismatched_files_clone
+                                    .lock()
+                                    .unwrap()
+                                    .push(relative_path_clone.clone());
+                            }
+
+                            if let Some(progress) = &json_progress_clone {
+                                progress.inc_by(1);
+                            } else {
+                                pb_clone.inc(1);
+                            }
+                            return Ok(());
                         }
-                        if let Some(progress) = &json_progress_clone {
-                            progress.inc_by(1);
+
+                        let needs_restore = match &up_to_date_clone {
+                            Some(up_to_date) => !up_to_date.contains(&relative_path_clone),
+                            None => needs_restore(
+                                &local_path,
+                                &backup_object,
+                                only_changed && !checksum,
+                            ),
+                        };
+
+                        // Files known to be up to date are not part of the byte total.
+                        let progress_step = if up_to_date_clone.is_some() {
+                            backup_object.size
                         } else {
-                            pb_clone.inc(1);
+                            1
+                        };
+
+                        if !needs_restore {
+                            trace!("file is unchanged, skipping");
+                            {
+                                let mut skipped = skipped_files_clone.lock().unwrap();
+                                *skipped += 1;
+                            }
+                            if up_to_date_clone.is_none() {
+                                if let Some(progress) = &json_progress_clone {
+                                    progress.inc_by(1);
+                                } else {
+                                    pb_clone.inc(1);
+                                }
+                            }
+                            return Ok(());
                         }
-                        return Ok(());
-                    }
 
-                    if let Some(parent) = local_path.parent() {
-                        std::fs::create_dir_all(parent).map_err(|e| {
-                            format!(
-                                "Failed to create parent directory for {}: {}",
-                                relative_path_clone, e
-                            )
+                        let change = if std::fs::symlink_metadata(&local_path).is_ok() {
+                            "modified"
+                        } else {
+                            "missing"
+                        };
+
+                        if let Some(parent) = local_path.parent() {
+                            std::fs::create_dir_all(parent).map_err(|e| {
+                                RestoreFailure::io(
+                                    &relative_path_clone,
+                                    "Failed to create parent directory",
+                                    e,
+                                )
+                            })?;
+                        }
+
+                        if let Some(identical_files) = &identical_files_clone
+                            && let Some(source) = identical_files.source(&backup_object)
+                            && link_identical(&source, &local_path, &backup_object.hash)
+                        {
+                            debug!(source = %source.display(), "file linked to an identical one");
+                            identical_files.linked.fetch_add(1, Ordering::Relaxed);
+
+                            *restored_files_clone.lock().unwrap() += 1;
+                            if let Some(changed_files) = &changed_files_clone {
+                                changed_files.lock().unwrap().push(ChangedFile {
+                                    path: relative_path_clone.clone(),
+                                    change,
+                                });
+                            }
+                            if let Some(progress) = &json_progress_clone {
+                                progress.add_bytes(ProgressBytes::Restored, backup_object.size);
+                                progress.inc_by(progress_step);
+                            } else {
+                                pb_clone.inc(progress_step);
+                            }
+                            return Ok(());
+                        }
+
+                        let mut file = std::fs::File::create(&local_path).map_err(|e| {
+                            RestoreFailure::io(&relative_path_clone, "Failed to create file", e)
                         })?;
-                    }
 
-                    let mut file = std::fs::File::create(&local_path).map_err(|e| {
-                        format!("Failed to create file {}: {}", relative_path_clone, e)
-                    })?;
-
-                    for chunk_hash in &backup_object.chunks {
-                        let (prefix, rest) = chunk_hash.split_at(2);
-                        let chunk_path = format!("{}/chunks/{}/{}", key_clone, prefix, rest);
-
-                        let chunk_data = read_file_maybe_decrypt(
-                            &fs_clone,
-                            &chunk_path,
-                            password_clone.as_deref(),
-                            "Chunk is encrypted but no password provided",
-                        )
-                        .await
-                        .map_err(|e| format!("Failed to read chunk {}: {}", chunk_hash, e))?;
-
-                        let decompressed = decompress_bytes(&chunk_data.bytes);
-
-                        file.write_all(&decompressed).map_err(|e| {
-                            format!(
-                                "Failed to write chunk {} to file {}: {}",
-                                chunk_hash, relative_path_clone, e
+                        let mut writer = ChunkWriter {
+                            file: &mut file,
+                            sparse,
+                            hasher: Sha256::new(),
+                        };
+
+                        for chunk_hash in &backup_object.chunks {
+                            let chunk_path = chunk_path(&key_clone, chunk_hash, chunk_dir_levels);
+
+                            let chunk_bytes = restore_chunk(
+                                &fs_clone,
+                                fallback_fs_clone.as_ref(),
+                                &chunk_path,
+                                chunk_hash,
+                                password_clone.as_deref(),
+                                max_retries,
+                                &mut writer,
                             )
-                        })?;
-                    }
+                            .await
+                            .map_err(|error| match error {
+                                ChunkWriteError::Read(reason_code, detail) => RestoreFailure {
+                                    path: relative_path_clone.clone(),
+                                    reason_code,
+                                    detail,
+                                },
+                                ChunkWriteError::Write(e) => RestoreFailure::io(
+                                    &relative_path_clone,
+                                    &format!("Failed to write chunk {}", chunk_hash),
+                                    e,
+                                ),
+                            })?;
+
+                            trace!(chunk = %chunk_hash, bytes = chunk_bytes, "chunk written");
+
+                            if let Some(progress) = &json_progress_clone {
+                                progress.add_bytes(ProgressBytes::Restored, chunk_bytes);
+                            }
+                        }
 
-                    set_file_permissions(&local_path, backup_object.permissions).map_err(|e| {
-                        format!(
-                            "Failed to set permissions for {}: {}",
-                            relative_path_clone, e
-                        )
-                    })?;
+                        if sparse {
+                            file.stream_position()
+                                .and_then(|len| file.set_len(len))
+                                .map_err(|e| {
+                                    RestoreFailure::io(
+                                        &relative_path_clone,
+                                        "Failed to set the length of sparse file",
+                                        e,
+                                    )
+                                })?;
+                        }
 
-                    {
-                        let mut restored = restored_files_clone.lock().unwrap();
-                        *restored += 1;
-                    }
+                        // Every chunk was checked as it was written, but the file is read
+                        // back as a whole to catch anything lost on the way to the disk.
+                        if verify
+                            && let Err(failure) = verify_restored_file(
+                                &local_path,
+                                &relative_path_clone,
+                                &backup_object,
+                            )
+                        {
+                            drop(file);
+                            let _ = std::fs::remove_file(&local_path);
+                            return Err(failure);
+                        }
+
+                        // Set on the open file once the content is written, so nothing
+                        // bumps it again; mode and owner changes leave it alone. Backups
+                        // made before modification times were recorded keep the restore
+                        // time.
+                        if let Some(mtime_ns) = backup_object.mtime_ns
+                            && let Err(e) = file.set_modified(
+                                std::time::UNIX_EPOCH + Duration::from_nanos(mtime_ns),
+                            )
+                        {
+                            warn!(error = %e, "modification time not restored");
+                        }
+
+                        if !ignore_permissions
+                            && let Err(failure) = apply_permissions(
+                                &local_path,
+                                &relative_path_clone,
+                                &backup_object,
+                                restore_ownership,
+                                &owner_map_clone,
+                            )
+                        {
+                            if !continue_on_error {
+                                return Err(failure);
+                            }
+
+                            warn!(error = %failure.detail, "permissions not applied");
+                            permission_failures_clone.lock().unwrap().push(failure);
+                        }
+
+                        // Applied after the mode, which would otherwise rewrite the mask
+                        // entry. A target without ACL support is not a failed restore.
+                        if !ignore_permissions
+                            && let Some(acl) = &backup_object.acl
+                            && let Err(e) = write_acl(&local_path, acl, &owner_map_clone)
+                        {
+                            warn!(error = %e, "ACL not applied");
+                            acl_failures_clone.lock().unwrap().push(RestoreFailure {
+                                path: relative_path_clone.clone(),
+                                reason_code: "acl_error",
+                                detail: e,
+                            });
+                        }
+
+                        debug!(chunks = backup_object.chunks.len(), "file restored");
+
+                        if let Some(identical_files) = &identical_files_clone {
+                            identical_files.register(&backup_object, &local_path);
+                        }
 
-                    if let Some(progress) = &json_progress_clone {
-                        progress.inc_by(1);
-                    } else {
-                        pb_clone.inc(1);
+                        {
+                            let mut restored = restored_files_clone.lock().unwrap();
+                            *restored += 1;
+                        }
+
+                        if let Some(changed_files) = &changed_files_clone {
+                            changed_files.lock().unwrap().push(ChangedFile {
+                                path: relative_path_clone.clone(),
+                                change,
+                            });
+                        }
+
+                        if let Some(progress) = &json_progress_clone {
+                            progress.inc_by(progress_step);
+                        } else {
+                            pb_clone.inc(progress_step);
+                        }
+                        Ok(())
                     }
-                    Ok(())
-                });
+                    .instrument(file_span),
+                );
             }
         })
         .await;
 
-    let mut failed_files = Vec::new();
+    let mut failed_files = unsafe_files;
 
     {
         let mut guard = files_set.lock().await;
         while let Some(file_process_result) = guard.join_next().await {
             match file_process_result {
                 Ok(Ok(_)) => {}
-                Ok(Err(e)) => failed_files.push(e),
-                Err(e) => failed_files.push(e.to_string()),
+                Ok(Err(failure)) => failed_files.push(failure),
+                Err(e) => failed_files.push(RestoreFailure {
+                    path: String::new(),
+                    reason_code: "internal_error",
+                    detail: e.to_string(),
+                }),
             }
         }
     }
 
+    failed_files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    info!(
+        restored = *restored_files.lock().unwrap(),
+        skipped = *skipped_files.lock().unwrap(),
+        failed = failed_files.len(),
+        "finished restoring files"
+    );
+
     if !failed_files.is_empty() {
-        handle_error(
-            format!(
-                "Failed to restore {} files:\n{}",
-                failed_files.len(),
-                failed_files
-                    .iter()
-                    .map(|f| format!("  - {}", f))
-                    .collect::<Vec<String>>()
-                    .join("\n")
-            ),
-            Some(&pb),
+        let message = format!(
+            "Failed to restore {} files:\n{}",
+            failed_files.len(),
+            failed_files
+                .iter()
+                .map(|failure| format!("  - {}", failure))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+
+        if !continue_on_error {
+            if is_json_mode() {
+                emit_error_with_details(&message, "restore_failed", &failed_files);
+            }
+            handle_error(message, Some(&pb));
+        }
+
+        pb.suspend(|| emit_warning(&message, "restore_partial_failure"));
+    }
+
+    let mut permission_failures = std::mem::take(&mut *permission_failures.lock().unwrap());
+    permission_failures.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if !permission_failures.is_empty() {
+        let message = format!(
+            "Restored {} files whose permissions could not be applied (pass --ignore-permissions on targets without Unix permissions):\n{}",
+            permission_failures.len(),
+            permission_failures
+                .iter()
+                .map(|failure| format!("  - {}", failure))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+        pb.suspend(|| emit_warning(&message, "permissions_not_applied"));
+    }
+
+    let mut acl_failures = std::mem::take(&mut *acl_failures.lock().unwrap());
+    acl_failures.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if !acl_failures.is_empty() {
+        let message = format!(
+            "Restored {} files whose ACLs could not be applied:\n{}",
+            acl_failures.len(),
+            acl_failures
+                .iter()
+                .map(|failure| format!("  - {}", failure))
+                .collect::<Vec<String>>()
+                .join("\n")
         );
+        pb.suspend(|| emit_warning(&message, "acls_not_applied"));
+    }
+
+    let mut mismatched_files = std::mem::take(&mut *mismatched_files.lock().unwrap());
+    mismatched_files.sort();
+
+    if !mismatched_files.is_empty() {
+        let message = format!(
+            "Left {} files alone because they are missing or their content differs from the backup (restore them without --touch-only):\n{}",
+            mismatched_files.len(),
+            mismatched_files
+                .iter()
+                .map(|path| format!("  - {}", path))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+        pb.suspend(|| emit_warning(&message, "touch_content_mismatch"));
     }
 
     let deleted_count = if prune_local {
@@ -274,7 +956,7 @@ pub async fn restore(matches: &ArgMatches) {
         if is_json_mode() {
             emit_progress_message("Cleaning up files not in backup...");
         }
-        match cleanup_extra_files(&target_path, &backup.tree) {
+        match cleanup_extra_files(&target_path, &tree, &renamed_paths) {
             Ok(count) => count,
             Err(e) => {
                 emit_warning(
@@ -288,8 +970,62 @@ pub async fn restore(matches: &ArgMatches) {
         0
     };
 
+    let ownership_manifest = if matches.get_flag("store-ownership-manifest") {
+        let failed_paths = failed_files
+            .iter()
+            .map(|failure| failure.path.as_str())
+            .collect::<HashSet<&str>>();
+        ownership_entries.retain(|entry| !failed_paths.contains(entry.path.as_str()));
+        ownership_entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let manifest_path = Path::new(&target_path).join(OWNERSHIP_MANIFEST_NAME);
+        let manifest = OwnershipManifest {
+            backup: full_backup_hash.clone(),
+            entries: ownership_entries,
+        };
+
+        if let Err(e) = manifest.write(&manifest_path) {
+            handle_error(format!("Files were restored, but {}", e), Some(&pb));
+        }
+
+        Some(
+            Path::new(&final_target)
+                .join(OWNERSHIP_MANIFEST_NAME)
+                .to_string_lossy()
+                .to_string(),
+        )
+    } else {
+        None
+    };
+
+    if let Some(atomic) = atomic {
+        match atomic.commit() {
+            Ok(Some(warning)) => pb.suspend(|| emit_warning(&warning, "atomic_cleanup_failed")),
+            Ok(None) => {}
+            Err(e) => handle_error(e, Some(&pb)),
+        }
+    }
+
     let restored_count = *restored_files.lock().unwrap();
     let skipped_count = *skipped_files.lock().unwrap();
+    let touched_count = touch_only.then(|| *touched_files.lock().unwrap());
+    let hardlinked = identical_files
+        .as_ref()
+        .map(|identical_files| identical_files.linked.load(Ordering::Relaxed));
+    let changed_files = changed_files.map(|changed_files| {
+        let mut changed_files = std::mem::take(&mut *changed_files.lock().unwrap());
+        changed_files.sort_by(|a, b| a.path.cmp(&b.path));
+        changed_files
+    });
+
+    if let Some(hooks) = &hooks {
+        let status = if failed_files.is_empty() {
+            "success"
+        } else {
+            "partial"
+        };
+        pb.suspend(|| hooks.run_post_hook(status, &[]));
+    }
 
     if is_json_mode() {
         #[derive(serde::Serialize)]
@@ -299,7 +1035,29 @@ pub async fn restore(matches: &ArgMatches) {
             restored: u64,
             skipped: u64,
             deleted_local: u64,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            touched: Option<u64>,
             target_path: String,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            merged_backups: Vec<String>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            failed_files: Vec<RestoreFailure>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            permission_failures: Vec<RestoreFailure>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            acl_failures: Vec<RestoreFailure>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            kept_newer: Vec<String>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            case_conflicts: Vec<CaseConflict>,
+            #[serde(skip_serializing_if = "Vec::is_empty")]
+            flatten_collisions: Vec<FlattenCollision>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            ownership_manifest: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            hardlinked: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            changed_files: Option<Vec<ChangedFile>>,
             elapsed_ms: u64,
         }
 
@@ -309,7 +1067,18 @@ pub async fn restore(matches: &ArgMatches) {
             restored: restored_count,
             skipped: skipped_count,
             deleted_local: deleted_count,
-            target_path: target_path.clone(),
+            touched: touched_count,
+            target_path: final_target.clone(),
+            merged_backups: merged_backups.clone(),
+            failed_files,
+            permission_failures,
+            acl_failures,
+            kept_newer,
+            case_conflicts,
+            flatten_collisions,
+            ownership_manifest,
+            hardlinked,
+            changed_files,
             elapsed_ms: started_at.elapsed().as_millis() as u64,
         };
         emit_output(&payload);
@@ -318,7 +1087,30 @@ pub async fn restore(matches: &ArgMatches) {
         pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
         pb.set_prefix("OK");
 
-        if deleted_count > 0 {
+        if !failed_files.is_empty() {
+            pb.set_style(ProgressStyle::with_template("{prefix:.yellow} {msg}").unwrap());
+            pb.set_prefix("PARTIAL");
+            pb.finish_with_message(format!(
+                "Restored {} files, skipped {} files, failed {} files ({:.2?})",
+                restored_count,
+                skipped_count,
+                failed_files.len(),
+                elapsed
+            ));
+        } else if let Some(touched_count) = touched_count {
+            pb.finish_with_message(format!(
+                "Updated the metadata of {} files, skipped {} files ({:.2?})",
+                touched_count, skipped_count, elapsed
+            ));
+        } else if merged_backups.len() > 1 {
+            pb.finish_with_message(format!(
+                "Restored {} files, skipped {} files from {} merged backups ({:.2?})",
+                restored_count,
+                skipped_count,
+                merged_backups.len(),
+                elapsed
+            ));
+        } else if deleted_count > 0 {
             pb.finish_with_message(format!(
                 "Restored {} files, skipped {} files, deleted {} files ({:.2?})",
                 restored_count, skipped_count, deleted_count, elapsed
@@ -329,10 +1121,440 @@ pub async fn restore(matches: &ArgMatches) {
                 restored_count, skipped_count, elapsed
             ));
         }
+
+        if let Some(changed_files) = &changed_files {
+            if changed_files.is_empty() {
+                println!("Every file already matched the backup");
+            }
+            for changed_file in changed_files {
+                println!("  {} ({})", changed_file.path, changed_file.change);
+            }
+        }
+
+        if let Some(hardlinked) = hardlinked.filter(|hardlinked| *hardlinked > 0) {
+            println!(
+                "{} files were linked to an identical restored file",
+                hardlinked
+            );
+        }
+
+        if let Some(manifest_path) = &ownership_manifest {
+            println!(
+                "Ownership manifest written to {}. Run 'sudo gib apply-ownership {}' to apply it.",
+                manifest_path, manifest_path
+            );
+        }
     }
 }
 
-async fn resolve_backup_hash(
+/// `--stdout`: writes the selected files to stdout as a tar archive instead of restoring
+/// them. Files whose path is unsafe are left out of the archive, with a warning.
+async fn restore_to_stdout(
+    source: ArchiveSource,
+    mut files: Vec<(String, BackupObject)>,
+    unsafe_files: Vec<RestoreFailure>,
+    verify: bool,
+) {
+    if !unsafe_files.is_empty() {
+        emit_warning(
+            &format!(
+                "{} files were left out of the archive:\n{}",
+                unsafe_files.len(),
+                unsafe_files
+                    .iter()
+                    .map(|failure| format!("  - {}: {}", failure.path, failure.detail))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+            "restore_stdout_unsafe_paths",
+        );
+    }
+
+    let handle = tokio::runtime::Handle::current();
+    let result = tokio::task::spawn_blocking(move || {
+        write_tar(
+            &source,
+            &handle,
+            &mut files,
+            verify,
+            std::io::stdout().lock(),
+        )
+    })
+    .await
+    .unwrap_or_else(|e| Err(std::io::Error::other(e.to_string())));
+
+    match result {
+        Ok(()) => {}
+        // A closed pipe (`gib restore --stdout | head`) is not an error.
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
+        Err(e) => handle_error(format!("Failed to write the tar archive: {}", e), None),
+    }
+}
+
+/// Applies the stored mode of a restored file and, when running as root, its owner.
+fn apply_permissions(
+    local_path: &Path,
+    relative_path: &str,
+    backup_object: &BackupObject,
+    restore_ownership: bool,
+    owner_map: &OwnerMap,
+) -> Result<(), RestoreFailure> {
+    set_file_permissions(local_path, backup_object.permissions).map_err(|e| RestoreFailure {
+        path: relative_path.to_string(),
+        reason_code: "permission_error",
+        detail: format!("Failed to set permissions: {}", e),
+    })?;
+
+    if restore_ownership && let (Some(uid), Some(gid)) = (backup_object.uid, backup_object.gid) {
+        set_file_owner(local_path, owner_map.map_uid(uid), owner_map.map_gid(gid)).map_err(
+            |e| RestoreFailure {
+                path: relative_path.to_string(),
+                reason_code: "permission_error",
+                detail: format!("Failed to set ownership: {}", e),
+            },
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Recreates a symbolic link, replacing whatever file or link is at `local_path`. Returns
+/// false when the link is already there. Permissions are not applied, since changing them
+/// would change the target instead; the owner is set on the link itself.
+fn restore_symlink(
+    local_path: &Path,
+    relative_path: &str,
+    link_target: &str,
+    backup_object: &BackupObject,
+    restore_ownership: bool,
+    owner_map: &OwnerMap,
+) -> Result<bool, RestoreFailure> {
+    if let Ok(metadata) = std::fs::symlink_metadata(local_path) {
+        if metadata.file_type().is_symlink()
+            && std::fs::read_link(local_path)
+                .is_ok_and(|target| target.to_string_lossy() == link_target)
+        {
+            return Ok(false);
+        }
+
+        if metadata.is_dir() {
+            return Err(RestoreFailure {
+                path: relative_path.to_string(),
+                reason_code: "write_error",
+                detail: "A directory is in the way of the symbolic link".to_string(),
+            });
+        }
+
+        std::fs::remove_file(local_path).map_err(|e| {
+            RestoreFailure::io(relative_path, "Failed to replace the existing file", e)
+        })?;
+    }
+
+    if let Some(parent) = local_path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| {
+            RestoreFailure::io(relative_path, "Failed to create parent directory", e)
+        })?;
+    }
+
+    #[cfg(unix)]
+    let result = std::os::unix::fs::symlink(link_target, local_path);
+    #[cfg(windows)]
+    let result = std::os::windows::fs::symlink_file(link_target, local_path);
+
+    result.map_err(|e| RestoreFailure::io(relative_path, "Failed to create symbolic link", e))?;
+
+    if restore_ownership && let (Some(uid), Some(gid)) = (backup_object.uid, backup_object.gid) {
+        set_link_owner(local_path, owner_map.map_uid(uid), owner_map.map_gid(gid)).map_err(
+            |e| RestoreFailure {
+                path: relative_path.to_string(),
+                reason_code: "permission_error",
+                detail: format!("Failed to set ownership: {}", e),
+            },
+        )?;
+    }
+
+    Ok(true)
+}
+
+/// A file `--only-changed` restored: `change` is `missing` when it did not exist in the
+/// target and `modified` when its content differed from the backup.
+#[derive(serde::Serialize)]
+struct ChangedFile {
+    path: String,
+    change: &'static str,
+}
+
+/// A file that could not be restored. `reason_code` is one of `missing_chunk`,
+/// `decrypt_error`, `decompress_error`, `corrupt_chunk`, `write_error`, `permission_error`,
+/// `acl_error`, `hash_mismatch`, `unsafe_path` or `internal_error`.
+#[derive(serde::Serialize)]
+struct RestoreFailure {
+    path: String,
+    reason_code: &'static str,
+    detail: String,
+}
+
+impl RestoreFailure {
+    fn io(path: &str, context: &str, error: std::io::Error) -> Self {
+        let reason_code = if error.kind() == std::io::ErrorKind::PermissionDenied {
+            "permission_error"
+        } else {
+            "write_error"
+        };
+
+        RestoreFailure {
+            path: path.to_string(),
+            reason_code,
+            detail: format!("{}: {}", context, error),
+        }
+    }
+}
+
+impl std::fmt::Display for RestoreFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.detail)
+        } else {
+            write!(f, "{}: {}", self.path, self.detail)
+        }
+    }
+}
+
+/// Why a chunk could not be restored: reading it failed (with the `RestoreFailure`
+/// reason code and detail) or writing it to the file did.
+enum ChunkWriteError {
+    Read(&'static str, String),
+    Write(std::io::Error),
+}
+
+/// Decompresses a chunk into `file` at the current position and checks its hash as the
+/// bytes are written, so a chunk is never held decompressed in memory. A missing chunk
+/// fails right away; a corrupt one is cut off the file again and refetched up to
+/// `max_retries` times, then read from the fallback storage, if any. Returns the number
+/// of bytes written.
+async fn restore_chunk(
+    fs: &Arc<dyn FS>,
+    fallback_fs: Option<&Arc<dyn FS>>,
+    chunk_path: &str,
+    chunk_hash: &str,
+    password: Option<&str>,
+    max_retries: u32,
+    writer: &mut ChunkWriter<'_>,
+) -> Result<u64, ChunkWriteError> {
+    let mut last_error = ChunkReadError::Missing;
+
+    for attempt in 0..=max_retries {
+        match write_verified_chunk(fs, chunk_path, chunk_hash, password, writer).await? {
+            Ok(bytes) => return Ok(bytes),
+            Err(ChunkReadError::Missing) => {
+                return Err(ChunkWriteError::Read(
+                    "missing_chunk",
+                    format!("Chunk {} is missing from the storage", chunk_hash),
+                ));
+            }
+            Err(e) => {
+                warn!(chunk = %chunk_hash, attempt, error = %e.detail(), "corrupt chunk read");
+                last_error = e;
+            }
+        }
+    }
+
+    let mut detail = last_error.detail().to_string();
+
+    if let Some(fallback_fs) = fallback_fs {
+        match write_verified_chunk(fallback_fs, chunk_path, chunk_hash, password, writer).await? {
+            Ok(bytes) => {
+                debug!(chunk = %chunk_hash, "chunk read from fallback storage");
+                return Ok(bytes);
+            }
+            Err(ChunkReadError::Missing) => {
+                detail = format!("{}; missing from the fallback storage", detail);
+            }
+            Err(e) => {
+                detail = format!("{}; fallback storage: {}", detail, e.detail());
+            }
+        }
+    }
+
+    Err(ChunkWriteError::Read(
+        last_error.reason_code(),
+        format!(
+            "Chunk {} is corrupt after {} attempts: {}",
+            chunk_hash,
+            max_retries + 1,
+            detail
+        ),
+    ))
+}
+
+/// Writes one copy of a chunk read from `fs` into `file`. The outer error is a failed
+/// write; the inner one a copy that could not be read, decompressed or did not match its
+/// hash, in which case whatever was written of it is removed from the file again.
+async fn write_verified_chunk(
+    fs: &Arc<dyn FS>,
+    chunk_path: &str,
+    chunk_hash: &str,
+    password: Option<&str>,
+    writer: &mut ChunkWriter<'_>,
+) -> Result<Result<u64, ChunkReadError>, ChunkWriteError> {
+    let compressed = match fetch_chunk(fs, chunk_path, password).await {
+        Ok(compressed) => compressed,
+        Err(e) => return Ok(Err(e)),
+    };
+
+    let start = writer
+        .file
+        .stream_position()
+        .map_err(ChunkWriteError::Write)?;
+
+    let result = decompress_to_writer(&compressed, writer);
+    let actual_hash = format!("{:x}", writer.hasher.finalize_reset());
+
+    let error = match result {
+        Ok(bytes) if actual_hash == chunk_hash => return Ok(Ok(bytes)),
+        Ok(_) => ChunkReadError::HashMismatch(format!("hash mismatch (got {})", actual_hash)),
+        Err(DecompressError::Decompress(e)) => {
+            ChunkReadError::Decompress(format!("failed to decompress: {}", e))
+        }
+        Err(DecompressError::Write(e)) => return Err(ChunkWriteError::Write(e)),
+    };
+
+    writer
+        .file
+        .set_len(start)
+        .and_then(|_| writer.file.seek(SeekFrom::Start(start)))
+        .map_err(ChunkWriteError::Write)?;
+
+    Ok(Err(error))
+}
+
+/// Writes decompressed chunk bytes to the restored file, sparse or not, and hashes them
+/// on the way; the hash is reset after each chunk.
+struct ChunkWriter<'a> {
+    file: &'a mut std::fs::File,
+    sparse: bool,
+    hasher: Sha256,
+}
+
+impl Write for ChunkWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.sparse {
+            write_sparse(self.file, buf)?;
+        } else {
+            self.file.write_all(buf)?;
+        }
+        self.hasher.update(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+const MAX_CONCURRENT_MANIFEST_LOADS: usize = 16;
+
+/// The range of backups merged by `--include-deleted`.
+enum MergeRange {
+    Last(usize),
+    Between { from: String, to: Option<String> },
+}
+
+fn get_merge_range(matches: &ArgMatches) -> Result<Option<MergeRange>, String> {
+    if !matches.get_flag("include-deleted") {
+        return Ok(None);
+    }
+
+    if let Some(last) = matches.get_one::<String>("last") {
+        return match last.parse::<usize>() {
+            Ok(last) if last > 0 => Ok(Some(MergeRange::Last(last))),
+            _ => Err(format!(
+                "Invalid value for --last: '{}' (must be a positive number)",
+                last
+            )),
+        };
+    }
+
+    match matches.get_one::<String>("from") {
+        Some(from) => Ok(Some(MergeRange::Between {
+            from: from.to_string(),
+            to: matches.get_one::<String>("to").cloned(),
+        })),
+        None => Err("--include-deleted requires --last <N> or --from <BACKUP>".to_string()),
+    }
+}
+
+/// Merges the trees of every backup in `range`, keeping for each path the version from
+/// the most recent backup that contains it. Returns the newest backup hash, the merged
+/// tree and the hashes of the merged backups (newest first).
+async fn load_merged_tree(
+    fs: Arc<dyn FS>,
+    key: String,
+    password: Option<String>,
+    range: MergeRange,
+) -> Result<(String, HashMap<String, BackupObject>, Vec<String>), String> {
+    let summaries = list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await?;
+
+    if summaries.is_empty() {
+        return Err("No backups found in repository".to_string());
+    }
+
+    let find_index = |hash: &str| {
+        summaries
+            .iter()
+            .position(|summary| summary.hash.starts_with(hash))
+            .ok_or_else(|| format!("No backup found matching hash prefix: {}", hash))
+    };
+
+    // Summaries are stored newest first.
+    let selected = match range {
+        MergeRange::Last(last) => &summaries[..last.min(summaries.len())],
+        MergeRange::Between { from, to } => {
+            let from_index = find_index(&from)?;
+            let to_index = match to {
+                Some(to) => find_index(&to)?,
+                None => 0,
+            };
+            &summaries[from_index.min(to_index)..=from_index.max(to_index)]
+        }
+    };
+
+    // Load oldest first so the stable sort below keeps summary order for equal timestamps.
+    let mut backups = stream::iter(selected.iter().rev().map(|summary| summary.hash.clone()))
+        .map(|hash| load_resolved_backup(Arc::clone(&fs), key.clone(), password.clone(), hash))
+        .buffered(MAX_CONCURRENT_MANIFEST_LOADS)
+        .collect::<Vec<Result<(Backup, usize), String>>>()
+        .await
+        .into_iter()
+        .map(|resolved| resolved.map(|(backup, _)| backup))
+        .collect::<Result<Vec<Backup>, String>>()?;
+
+    backups.sort_by_key(|backup| backup.timestamp);
+
+    let mut merged_tree = HashMap::new();
+    for backup in &backups {
+        for (path, object) in &backup.tree {
+            merged_tree.insert(path.clone(), object.clone());
+        }
+    }
+
+    let merged_backups = backups
+        .iter()
+        .rev()
+        .map(|backup| backup.hash.clone())
+        .collect::<Vec<String>>();
+
+    debug!(
+        backups = merged_backups.len(),
+        files = merged_tree.len(),
+        "merged backup trees"
+    );
+
+    Ok((merged_backups[0].clone(), merged_tree, merged_backups))
+}
+
+pub(crate) async fn resolve_backup_hash(
     fs: Arc<dyn FS>,
     key: String,
     password: Option<String>,
@@ -355,23 +1577,28 @@ async fn resolve_backup_hash(
             }
         }
         None => {
-            if is_json_mode() {
+            if !can_prompt() {
                 return Err(
-                    "Missing required argument: --backup (required in --mode json)".to_string(),
+                    "Missing required argument: --backup (required in --mode json or with --non-interactive)".to_string(),
                 );
             }
-            let summaries = list_backup_summaries(fs, key, password).await?;
+            let mut summaries =
+                list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await?;
 
             if summaries.is_empty() {
                 return Err("No backups found in repository".to_string());
             }
 
+            summaries.truncate(10);
+            backfill_backup_summaries(fs, key, password, &mut summaries).await;
+
             let recent_backups: Vec<BackupSummaryDisplay> = summaries
                 .iter()
-                .take(10)
                 .map(|s| BackupSummaryDisplay {
                     hash: s.hash.clone(),
                     message: s.message.clone(),
+                    timestamp: s.timestamp,
+                    author: s.author.clone(),
                 })
                 .collect();
 
@@ -379,10 +1606,7 @@ async fn resolve_backup_hash(
                 return Err("No backups found in repository".to_string());
             }
 
-            let items: Vec<String> = recent_backups
-                .iter()
-                .map(|c| format!("{} {}", &c.hash[..8.min(c.hash.len())], &c.message))
-                .collect();
+            let items: Vec<String> = recent_backups.iter().map(|c| c.label()).collect();
 
             let selected_index = Select::new()
                 .with_prompt("Select a backup to restore")
@@ -399,34 +1623,363 @@ async fn resolve_backup_hash(
 struct BackupSummaryDisplay {
     hash: String,
     message: String,
+    timestamp: Option<u64>,
+    author: Option<String>,
 }
 
-async fn load_backup(
-    fs: Arc<dyn FS>,
-    key: String,
-    password: Option<String>,
-    backup_hash: &str,
-) -> Result<Backup, String> {
-    let backup_path = format!("{}/backups/{}", key, backup_hash);
+impl BackupSummaryDisplay {
+    fn label(&self) -> String {
+        let mut label = format!("{} {}", &self.hash[..8.min(self.hash.len())], &self.message);
+
+        let date = self.timestamp.and_then(|ts| {
+            DateTime::<Utc>::from_timestamp_secs(ts as i64).map(|dt| {
+                dt.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M")
+                    .to_string()
+            })
+        });
+
+        let details = [date, self.author.clone()]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<String>>();
+
+        if !details.is_empty() {
+            label.push_str(&format!(" ({})", details.join(", ")));
+        }
+
+        label
+    }
+}
+
+/// Writes `bytes` at the current position, seeking over all-zero blocks instead of
+/// writing them so they become holes on filesystems that support sparse files. On
+/// filesystems that don't, the skipped ranges are simply filled with zeros. Callers must
+/// `set_len` the file once it is complete so a trailing hole is kept.
+fn write_sparse(file: &mut std::fs::File, bytes: &[u8]) -> std::io::Result<()> {
+    let mut data_start: Option<usize> = None;
 
-    let read_result = read_file_maybe_decrypt(
-        &fs,
-        &backup_path,
-        password.as_deref(),
-        "Backup is encrypted but no password provided",
-    )
-    .await?;
+    for (index, block) in bytes.chunks(SPARSE_BLOCK_SIZE).enumerate() {
+        let offset = index * SPARSE_BLOCK_SIZE;
 
-    if read_result.bytes.is_empty() {
-        return Err(format!("Backup {} not found or is empty", backup_hash));
+        if block.iter().all(|byte| *byte == 0) {
+            if let Some(start) = data_start.take() {
+                file.write_all(&bytes[start..offset])?;
+            }
+            file.seek(SeekFrom::Current(block.len() as i64))?;
+        } else if data_start.is_none() {
+            data_start = Some(offset);
+        }
+    }
+
+    if let Some(start) = data_start {
+        file.write_all(&bytes[start..])?;
+    }
+
+    Ok(())
+}
+
+/// `--hardlink-identical`: the first restored path of each content. Unless permissions are
+/// ignored, files must also have the same mode, owner and ACL, which links share.
+struct IdenticalFiles {
+    first_paths: std::sync::Mutex<HashMap<String, PathBuf>>,
+    compare_metadata: bool,
+    linked: AtomicU64,
+}
+
+impl IdenticalFiles {
+    fn new(compare_metadata: bool) -> Self {
+        IdenticalFiles {
+            first_paths: std::sync::Mutex::new(HashMap::new()),
+            compare_metadata,
+            linked: AtomicU64::new(0),
+        }
+    }
+
+    fn identity(&self, backup_object: &BackupObject) -> String {
+        if !self.compare_metadata {
+            return backup_object.hash.clone();
+        }
+
+        format!(
+            "{}:{:o}:{:?}:{:?}:{:?}",
+            backup_object.hash,
+            backup_object.permissions,
+            backup_object.uid,
+            backup_object.gid,
+            backup_object.acl
+        )
+    }
+
+    /// Empty files are not worth a link.
+    fn source(&self, backup_object: &BackupObject) -> Option<PathBuf> {
+        if backup_object.size == 0 {
+            return None;
+        }
+
+        self.first_paths
+            .lock()
+            .unwrap()
+            .get(&self.identity(backup_object))
+            .cloned()
+    }
+
+    fn register(&self, backup_object: &BackupObject, local_path: &Path) {
+        self.first_paths
+            .lock()
+            .unwrap()
+            .entry(self.identity(backup_object))
+            .or_insert_with(|| local_path.to_path_buf());
+    }
+}
+
+/// Replaces `local_path` with a hard link to `source` (a copy where hard links are not
+/// available) once `source` is checked to still hold `hash`. Returns false, leaving the
+/// file to be written normally, when that fails or the two are on different filesystems.
+fn link_identical(source: &Path, local_path: &Path, hash: &str) -> bool {
+    if calculate_file_hash(source).ok().as_deref() != Some(hash) {
+        return false;
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let same_filesystem = match (
+            std::fs::metadata(source),
+            local_path.parent().map(std::fs::metadata),
+        ) {
+            (Ok(source), Some(Ok(parent))) => source.dev() == parent.dev(),
+            _ => false,
+        };
+        if !same_filesystem {
+            return false;
+        }
+    }
+
+    match std::fs::symlink_metadata(local_path) {
+        Ok(metadata) if metadata.is_dir() => return false,
+        Ok(_) if std::fs::remove_file(local_path).is_err() => return false,
+        _ => {}
     }
 
-    let decompressed_bytes = decompress_bytes(&read_result.bytes);
+    #[cfg(unix)]
+    {
+        std::fs::hard_link(source, local_path).is_ok()
+    }
+
+    #[cfg(not(unix))]
+    {
+        std::fs::copy(source, local_path).is_ok()
+    }
+}
+
+/// Whether the file at `local_path` differs from `backup_object`. A different size
+/// answers without reading the file; with `trust_mtime`, so does a matching size and
+/// modification time. Otherwise the file is hashed.
+pub(crate) fn needs_restore(
+    local_path: &Path,
+    backup_object: &BackupObject,
+    trust_mtime: bool,
+) -> bool {
+    if let Some(link_target) = &backup_object.link_target {
+        return std::fs::read_link(local_path).map_or(true, |target| {
+            target.to_string_lossy() != link_target.as_str()
+        });
+    }
 
-    let backup: Backup = rmp_serde::from_slice(&decompressed_bytes)
-        .map_err(|e| format!("Failed to deserialize backup: {}", e))?;
+    let Ok(metadata) = std::fs::metadata(local_path) else {
+        return true;
+    };
+
+    if !metadata.is_file() || metadata.len() != backup_object.size {
+        return true;
+    }
+
+    if trust_mtime
+        && let Some(mtime_ns) = backup_object.mtime_ns
+        && metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+            .is_some_and(|modified| modified.as_nanos() as u64 == mtime_ns)
+    {
+        return false;
+    }
 
-    Ok(backup)
+    match calculate_file_hash(local_path) {
+        Ok(local_hash) => local_hash != backup_object.hash,
+        Err(_) => true,
+    }
+}
+
+/// The pre-pass of `--progress-resume`: the paths of `files` that already exist in
+/// `target_path` with the right content.
+async fn find_up_to_date_files(
+    target_path: &str,
+    files: &[(String, BackupObject)],
+    trust_mtime: bool,
+) -> HashSet<String> {
+    stream::iter(files)
+        .map(|(relative_path, backup_object)| {
+            let local_path = Path::new(target_path).join(relative_path);
+            let relative_path = relative_path.clone();
+            let backup_object = backup_object.clone();
+
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    (!needs_restore(&local_path, &backup_object, trust_mtime))
+                        .then_some(relative_path)
+                })
+                .await
+                .ok()
+                .flatten()
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_FILES)
+        .filter_map(|path| async move { path })
+        .collect::<HashSet<String>>()
+        .await
+}
+
+/// `--touch-only`: gives the file at `local_path` the modification time of
+/// `backup_object` when its content matches, which is decided by its size, or with
+/// `checksum` by its hash. Returns whether it matched; a missing file does not.
+fn touch_file(
+    local_path: &Path,
+    relative_path: &str,
+    backup_object: &BackupObject,
+    checksum: bool,
+) -> Result<bool, RestoreFailure> {
+    let matches = if checksum {
+        !needs_restore(local_path, backup_object, false)
+    } else {
+        std::fs::metadata(local_path)
+            .is_ok_and(|metadata| metadata.is_file() && metadata.len() == backup_object.size)
+    };
+
+    if !matches {
+        return Ok(false);
+    }
+
+    if let Some(mtime_ns) = backup_object.mtime_ns {
+        std::fs::File::open(local_path)
+            .and_then(|file| {
+                file.set_modified(std::time::UNIX_EPOCH + Duration::from_nanos(mtime_ns))
+            })
+            .map_err(|e| {
+                RestoreFailure::io(relative_path, "Failed to set the modification time", e)
+            })?;
+    }
+
+    Ok(true)
+}
+
+/// `--exclude-existing-newer`: leaves out the files whose copy in `target_path` was
+/// modified after the time the backup recorded for them, and returns their paths. Files
+/// without a recorded time are restored.
+fn exclude_existing_newer(
+    files_to_restore: Vec<(String, BackupObject)>,
+    target_path: &Path,
+) -> (Vec<(String, BackupObject)>, Vec<String>) {
+    let mut kept_newer = Vec::new();
+
+    let files_to_restore = files_to_restore
+        .into_iter()
+        .filter(|(relative_path, backup_object)| {
+            let Some(mtime_ns) = backup_object.mtime_ns else {
+                return true;
+            };
+
+            let local_mtime_ns = std::fs::metadata(target_path.join(relative_path))
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.duration_since(std::time::UNIX_EPOCH).ok())
+                .map(|since_epoch| since_epoch.as_nanos());
+
+            match local_mtime_ns {
+                Some(local_mtime_ns) if local_mtime_ns > mtime_ns as u128 => {
+                    kept_newer.push(relative_path.clone());
+                    false
+                }
+                _ => true,
+            }
+        })
+        .collect();
+
+    kept_newer.sort();
+
+    (files_to_restore, kept_newer)
+}
+
+/// Splits off the files whose path in the backup is not a plain relative path. A `..`
+/// component, a root or a drive prefix would place the file outside the target, and so
+/// could a path below a symbolic link of the same backup.
+fn reject_unsafe_paths(
+    files: Vec<(String, BackupObject)>,
+) -> (Vec<(String, BackupObject)>, Vec<RestoreFailure>) {
+    let links = files
+        .iter()
+        .filter(|(_, backup_object)| backup_object.link_target.is_some())
+        .map(|(relative_path, _)| relative_path.clone())
+        .collect::<HashSet<String>>();
+
+    let mut safe = Vec::new();
+    let mut rejected = Vec::new();
+
+    for (relative_path, backup_object) in files {
+        let mut components = Path::new(&relative_path).components().peekable();
+        let detail = if components.peek().is_none()
+            || !components
+                .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            "The path in the backup is not inside the restore target"
+        } else if Path::new(&relative_path)
+            .ancestors()
+            .skip(1)
+            .any(|ancestor| links.contains(ancestor.to_string_lossy().as_ref()))
+        {
+            "The path in the backup is below a symbolic link"
+        } else {
+            safe.push((relative_path, backup_object));
+            continue;
+        };
+
+        warn!(path = %relative_path, "rejected a backup path outside the target");
+        rejected.push(RestoreFailure {
+            path: relative_path,
+            reason_code: "unsafe_path",
+            detail: detail.to_string(),
+        });
+    }
+
+    (safe, rejected)
+}
+
+/// Hashes the file just restored at `local_path` against the backup. The caller removes
+/// a file that does not match, so a corrupt copy is never left behind as if restored.
+fn verify_restored_file(
+    local_path: &Path,
+    relative_path: &str,
+    backup_object: &BackupObject,
+) -> Result<(), RestoreFailure> {
+    let actual_hash = calculate_file_hash(local_path).map_err(|e| {
+        RestoreFailure::io(relative_path, "Failed to read back the restored file", e)
+    })?;
+
+    if actual_hash == backup_object.hash {
+        trace!("restored file verified");
+        return Ok(());
+    }
+
+    Err(RestoreFailure {
+        path: relative_path.to_string(),
+        reason_code: "hash_mismatch",
+        detail: format!(
+            "The restored file hashes to {} instead of {}; it was removed",
+            actual_hash, backup_object.hash
+        ),
+    })
 }
 
 fn calculate_file_hash(path: &Path) -> Result<String, std::io::Error> {
@@ -448,6 +2001,7 @@ fn calculate_file_hash(path: &Path) -> Result<String, std::io::Error> {
 fn cleanup_extra_files(
     target_path: &str,
     backup_tree: &std::collections::HashMap<String, crate::core::metadata::BackupObject>,
+    renamed_paths: &[String],
 ) -> Result<u64, String> {
     let target_path_buf = PathBuf::from(target_path);
 
@@ -455,7 +2009,11 @@ fn cleanup_extra_files(
         return Ok(0);
     }
 
-    let backup_paths: HashSet<String> = backup_tree.keys().map(|p| p.replace('\\', "/")).collect();
+    let backup_paths: HashSet<String> = backup_tree
+        .keys()
+        .chain(renamed_paths)
+        .map(|p| p.replace('\\', "/"))
+        .collect();
 
     let mut deleted_count = 0u64;
     let mut dirs_to_check = HashSet::new();
@@ -546,7 +2104,27 @@ fn get_params(
         },
     );
 
-    let default_key = Path::new(&pwd_string)
+    // --into-subdir goes inside --target-path (or the current directory), never elsewhere.
+    let target_path = match matches.get_one::<String>("into-subdir") {
+        Some(name) => {
+            let mut components = Path::new(name).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => Path::new(&target_path)
+                    .join(name)
+                    .to_string_lossy()
+                    .to_string(),
+                _ => {
+                    return Err(format!(
+                        "Invalid value for --into-subdir: '{}' (must be a single folder name)",
+                        name
+                    ));
+                }
+            }
+        }
+        None => target_path,
+    };
+
+    let folder_key = Path::new(&pwd_string)
         .file_name()
         .unwrap()
         .to_string_lossy()
@@ -554,7 +2132,9 @@ fn get_params(
 
     let key = matches
         .get_one::<String>("key")
-        .map_or_else(|| default_key, |key| key.to_string());
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
 
     let prune_local = matches.get_flag("prune-local");
     let only_request = parse_only_request(matches, prune_local)?;
@@ -588,12 +2168,16 @@ fn get_params(
         return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
     }
 
-    let storage = match matches.get_one::<String>("storage") {
-        Some(storage) => storage.to_string(),
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
         None => {
-            if is_json_mode() {
+            if !can_prompt() {
                 return Err(
-                    "Missing required argument: --storage (required in --mode json)".to_string(),
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
                 );
             }
             let selected_index = Select::new()
@@ -627,3 +2211,87 @@ fn get_params(
         only_request,
     ))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(path: &str) -> (String, BackupObject) {
+        (path.to_string(), BackupObject::with_chunks(&[]))
+    }
+
+    fn link(path: &str, target: &str) -> (String, BackupObject) {
+        let mut object = BackupObject::with_chunks(&[]);
+        object.link_target = Some(target.to_string());
+        (path.to_string(), object)
+    }
+
+    fn rejected_paths(files: Vec<(String, BackupObject)>) -> Vec<String> {
+        let (_, rejected) = reject_unsafe_paths(files);
+        assert!(
+            rejected
+                .iter()
+                .all(|failure| failure.reason_code == "unsafe_path")
+        );
+        rejected.into_iter().map(|failure| failure.path).collect()
+    }
+
+    #[test]
+    fn rejects_paths_that_climb_out_of_the_target() {
+        assert_eq!(
+            rejected_paths(vec![file("../evil"), file("docs/../../evil")]),
+            vec!["../evil", "docs/../../evil"]
+        );
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        assert_eq!(
+            rejected_paths(vec![file("/etc/passwd")]),
+            vec!["/etc/passwd"]
+        );
+    }
+
+    #[test]
+    fn rejects_an_empty_path() {
+        assert_eq!(rejected_paths(vec![file("")]), vec![""]);
+    }
+
+    #[test]
+    fn rejects_paths_below_a_stored_symlink() {
+        let (safe, rejected) = reject_unsafe_paths(vec![
+            link("etc", "/etc"),
+            file("etc/passwd"),
+            file("etc-backup/passwd"),
+        ]);
+
+        assert_eq!(
+            rejected
+                .iter()
+                .map(|failure| failure.path.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["etc/passwd"]
+        );
+        assert_eq!(
+            rejected[0].detail,
+            "The path in the backup is below a symbolic link"
+        );
+
+        // The link itself is restored as a link, and a sibling sharing its prefix is fine.
+        assert_eq!(
+            safe.iter()
+                .map(|(path, _)| path.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["etc", "etc-backup/passwd"]
+        );
+    }
+
+    #[test]
+    fn keeps_plain_relative_paths() {
+        let (safe, rejected) =
+            reject_unsafe_paths(vec![file("notes.txt"), file("./docs/a.txt"), file("a/b/c")]);
+
+        assert!(rejected.is_empty());
+        assert_eq!(safe.len(), 3);
+    }
+}
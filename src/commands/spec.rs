@@ -0,0 +1,251 @@
+use crate::core::layout::{
+    CHUNK_PATH_TEMPLATE, NESTED_CHUNK_PATH_TEMPLATE, backup_path, backup_summaries_path,
+    chunk_index_path, chunk_path, heal_log_path, lock_path, pending_backup_path,
+    repository_meta_path, signature_path, trash_entry_path, trash_object_path,
+};
+use crate::core::metadata::{
+    Backup, BackupSummary, ChunkIndex, ObjectSignature, PendingBackup, RepairPlan, RepositoryLock,
+    RepositoryMeta, TrashEntry,
+};
+use crate::output::{emit_output, is_json_mode};
+use crate::utils::{KEY_LEN, MAGIC, NONCE_LEN, SALT_LEN, handle_error};
+use schemars::{Schema, schema_for};
+use sha2::{Digest, Sha256};
+
+const EXAMPLE_KEY: &str = "my-repository";
+
+#[derive(serde::Serialize)]
+struct SpecOutput {
+    gib_version: &'static str,
+    layout: Vec<ObjectSpec>,
+    pipeline: PipelineSpec,
+    hashing: HashingSpec,
+    encryption: EncryptionSpec,
+    schemas: SchemasSpec,
+}
+
+#[derive(serde::Serialize)]
+struct ObjectSpec {
+    name: &'static str,
+    path_template: String,
+    example: String,
+    contents: &'static str,
+}
+
+#[derive(serde::Serialize)]
+struct PipelineSpec {
+    serialization: &'static str,
+    compression: &'static str,
+    write_order: Vec<&'static str>,
+}
+
+#[derive(serde::Serialize)]
+struct HashingSpec {
+    algorithm: &'static str,
+    encoding: &'static str,
+    chunk_hash: &'static str,
+    file_hash: &'static str,
+}
+
+#[derive(serde::Serialize)]
+struct EncryptionSpec {
+    detection: &'static str,
+    magic: String,
+    magic_hex: String,
+    header: Vec<HeaderField>,
+    cipher: &'static str,
+    kdf: KdfSpec,
+}
+
+#[derive(serde::Serialize)]
+struct HeaderField {
+    field: &'static str,
+    offset: usize,
+    length: Option<usize>,
+}
+
+#[derive(serde::Serialize)]
+struct KdfSpec {
+    algorithm: &'static str,
+    version: u32,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    key_length: usize,
+    salt: &'static str,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SchemasSpec {
+    backup: Schema,
+    backup_summary: Schema,
+    chunk_index: Schema,
+    pending_backup: Schema,
+    repository_meta: Schema,
+    repository_lock: Schema,
+    trash_entry: Schema,
+    object_signature: Schema,
+    repair_plan: Schema,
+}
+
+/// Prints the on-disk format of a repository as JSON. Everything is derived from the
+/// constants, path helpers and types the other commands use, so it can't drift from them.
+pub fn spec() {
+    let example_hash = format!("{:x}", Sha256::digest(b""));
+    let argon2_params = argon2::Params::default();
+
+    let payload = SpecOutput {
+        gib_version: env!("CARGO_PKG_VERSION"),
+        layout: vec![
+            ObjectSpec {
+                name: "chunk",
+                path_template: CHUNK_PATH_TEMPLATE.to_string(),
+                example: chunk_path(EXAMPLE_KEY, &example_hash, 1),
+                contents: "Raw file bytes of one chunk, zstd-compressed, or stored as is after a 0x00 byte (not msgpack)",
+            },
+            ObjectSpec {
+                name: "chunk_nested",
+                path_template: NESTED_CHUNK_PATH_TEMPLATE.to_string(),
+                example: chunk_path(EXAMPLE_KEY, &example_hash, 2),
+                contents: "Chunk path used instead when RepositoryMeta.chunk_dir_levels is 2",
+            },
+            ObjectSpec {
+                name: "repository",
+                path_template: repository_meta_path("{key}"),
+                example: repository_meta_path(EXAMPLE_KEY),
+                contents: "RepositoryMeta, never encrypted; optional (defaults apply when absent)",
+            },
+            ObjectSpec {
+                name: "chunk_index",
+                path_template: chunk_index_path("{key}"),
+                example: chunk_index_path(EXAMPLE_KEY),
+                contents: "Map of chunk hash to ChunkIndex",
+            },
+            ObjectSpec {
+                name: "backup_summaries",
+                path_template: backup_summaries_path("{key}"),
+                example: backup_summaries_path(EXAMPLE_KEY),
+                contents: "Array of BackupSummary, newest first",
+            },
+            ObjectSpec {
+                name: "backup",
+                path_template: backup_path("{key}", "{hash}"),
+                example: backup_path(EXAMPLE_KEY, &example_hash),
+                contents: "Backup manifest; tree maps relative paths to BackupObject (only the changed files when base is set). Named after BackupSummary.content instead of the backup hash when set",
+            },
+            ObjectSpec {
+                name: "pending_backup",
+                path_template: pending_backup_path("{key}", "{hash}"),
+                example: pending_backup_path(EXAMPLE_KEY, &example_hash),
+                contents: "PendingBackup of an interrupted backup, removed once it completes",
+            },
+            ObjectSpec {
+                name: "lock",
+                path_template: lock_path("{key}"),
+                example: lock_path(EXAMPLE_KEY),
+                contents: "RepositoryLock of a running backup, never encrypted; removed once it completes",
+            },
+            ObjectSpec {
+                name: "heal_log",
+                path_template: heal_log_path("{key}"),
+                example: heal_log_path(EXAMPLE_KEY),
+                contents: "JSON lines, one object (chunk, reason, detail, source, healed_at) per chunk verify --repair-from copied from another storage, never encrypted",
+            },
+            ObjectSpec {
+                name: "signature",
+                path_template: signature_path("{key}", "{key}/{object}"),
+                example: signature_path(EXAMPLE_KEY, &backup_path(EXAMPLE_KEY, &example_hash)),
+                contents: "ObjectSignature of a manifest or the chunk index (backup --sign-key), never encrypted; {object} is its path relative to {key}",
+            },
+            ObjectSpec {
+                name: "trash_entry",
+                path_template: trash_entry_path("{key}", "{id}"),
+                example: trash_entry_path(EXAMPLE_KEY, &example_hash),
+                contents: "TrashEntry describing objects removed with --trash-retention",
+            },
+            ObjectSpec {
+                name: "trash_object",
+                path_template: trash_object_path("{key}", "{id}", "{object}"),
+                example: trash_object_path(
+                    EXAMPLE_KEY,
+                    &example_hash,
+                    &format!("backups/{}", example_hash),
+                ),
+                contents: "Byte-for-byte copy of a trashed object; {object} is its path relative to {key}",
+            },
+        ],
+        pipeline: PipelineSpec {
+            serialization: "msgpack with structs encoded as maps of named fields",
+            compression: "zstd frame; a chunk may instead be a 0x00 byte followed by its uncompressed bytes (backup --compress store, --store-extensions), which no zstd frame starts with",
+            write_order: vec![
+                "serialize (all objects except chunks)",
+                "compress",
+                "encrypt (only when a password is used)",
+            ],
+        },
+        hashing: HashingSpec {
+            algorithm: "sha256",
+            encoding: "lowercase hex",
+            chunk_hash: "Hash of the uncompressed, unencrypted chunk bytes",
+            file_hash: "Hash of the whole file contents",
+        },
+        encryption: EncryptionSpec {
+            detection: "An object is encrypted if and only if it starts with the magic bytes",
+            magic: String::from_utf8_lossy(MAGIC).to_string(),
+            magic_hex: MAGIC.iter().map(|byte| format!("{:02x}", byte)).collect(),
+            header: vec![
+                HeaderField {
+                    field: "magic",
+                    offset: 0,
+                    length: Some(MAGIC.len()),
+                },
+                HeaderField {
+                    field: "salt",
+                    offset: MAGIC.len(),
+                    length: Some(SALT_LEN),
+                },
+                HeaderField {
+                    field: "nonce",
+                    offset: MAGIC.len() + SALT_LEN,
+                    length: Some(NONCE_LEN),
+                },
+                HeaderField {
+                    field: "ciphertext_and_tag",
+                    offset: MAGIC.len() + SALT_LEN + NONCE_LEN,
+                    length: None,
+                },
+            ],
+            cipher: "ChaCha20-Poly1305",
+            kdf: KdfSpec {
+                algorithm: argon2::Algorithm::default().as_str(),
+                version: argon2::Version::default() as u32,
+                memory_kib: argon2_params.m_cost(),
+                iterations: argon2_params.t_cost(),
+                parallelism: argon2_params.p_cost(),
+                key_length: KEY_LEN,
+                salt: "Random per object, stored in the header",
+            },
+        },
+        schemas: SchemasSpec {
+            backup: schema_for!(Backup),
+            backup_summary: schema_for!(BackupSummary),
+            chunk_index: schema_for!(ChunkIndex),
+            pending_backup: schema_for!(PendingBackup),
+            repository_meta: schema_for!(RepositoryMeta),
+            repository_lock: schema_for!(RepositoryLock),
+            trash_entry: schema_for!(TrashEntry),
+            object_signature: schema_for!(ObjectSignature),
+            repair_plan: schema_for!(RepairPlan),
+        },
+    };
+
+    if is_json_mode() {
+        emit_output(&payload);
+    } else {
+        match serde_json::to_string_pretty(&payload) {
+            Ok(spec) => println!("{}", spec),
+            Err(e) => handle_error(format!("Failed to serialize spec: {}", e), None),
+        }
+    }
+}
@@ -0,0 +1,462 @@
+use crate::commands::config::{default_key, default_storage};
+use crate::core::crypto::get_password;
+use crate::core::indexes::{
+    apply_differential, list_backup_summaries, load_repository_meta, load_summary_backup,
+};
+use crate::core::layout::chunk_path;
+use crate::core::metadata::{Backup, BackupObject};
+use crate::fs::FS;
+use crate::output::{can_prompt, emit_output, emit_progress_message, is_json_mode};
+use crate::utils::{get_fs, get_pwd_string, get_storage, handle_error};
+use bytesize::ByteSize;
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use clap::ArgMatches;
+use console::style;
+use dialoguer::Select;
+use dirs::home_dir;
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tabled::{Table, Tabled};
+
+const MAX_CONCURRENT_MANIFEST_LOADS: usize = 16;
+const MAX_CONCURRENT_SIZE_REQUESTS: usize = 100;
+const SECONDS_PER_WEEK: f64 = 7.0 * 24.0 * 60.0 * 60.0;
+
+/// The state of the repository right after one backup, counting only the backups that
+/// still exist. `stored_bytes` is the physical size of every chunk referenced by this
+/// backup or an older one, and `dedup_ratio` divides the logical size of all those
+/// backups by it.
+#[derive(serde::Serialize)]
+struct StatsPoint {
+    backup: String,
+    backup_short: String,
+    timestamp: Option<String>,
+    timestamp_unix: u64,
+    files: u64,
+    logical_bytes: u64,
+    stored_bytes: u64,
+    new_stored_bytes: u64,
+    unique_chunks: u64,
+    new_chunks: u64,
+    dedup_ratio: f64,
+}
+
+#[derive(serde::Serialize)]
+struct StatsOutput {
+    backups: usize,
+    latest_logical_bytes: u64,
+    total_logical_bytes: u64,
+    stored_bytes: u64,
+    unique_chunks: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    missing_chunks: u64,
+    dedup_ratio: f64,
+    /// Average growth of `stored_bytes` between the first and the last backup.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    growth_bytes_per_week: Option<f64>,
+}
+
+#[derive(Tabled)]
+struct StatsRow {
+    date: String,
+    backup: String,
+    files: u64,
+    logical: String,
+    stored: String,
+    new: String,
+    chunks: u64,
+    dedup: String,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+pub async fn stats(matches: &ArgMatches) {
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let over_time = matches.get_flag("over-time");
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb
+    };
+
+    set_progress_message(&pb, "Loading backups...");
+
+    let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let backup_summaries =
+        match list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await {
+            Ok(summaries) => summaries,
+            Err(e) => handle_error(e, Some(&pb)),
+        };
+
+    if backup_summaries.is_empty() {
+        pb.finish_and_clear();
+        if is_json_mode() {
+            if over_time {
+                let empty: Vec<StatsPoint> = Vec::new();
+                emit_output(&empty);
+            } else {
+                emit_output(&build_output(&[], 0));
+            }
+        } else {
+            println!(
+                "{}",
+                style("No backups found for this repository.").yellow()
+            );
+        }
+        return;
+    }
+
+    set_progress_message(
+        &pb,
+        &format!("Loading {} backups...", backup_summaries.len()),
+    );
+
+    let mut backups = stream::iter(backup_summaries)
+        .map(|summary| {
+            let fs = Arc::clone(&fs);
+            let key = key.clone();
+            let password = password.clone();
+            async move { load_summary_backup(fs, key, password, &summary).await }
+        })
+        .buffer_unordered(MAX_CONCURRENT_MANIFEST_LOADS)
+        .collect::<Vec<Result<Backup, String>>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<Backup>, String>>()
+        .unwrap_or_else(|e| handle_error(e, Some(&pb)));
+
+    backups.sort_by_key(|backup| backup.timestamp);
+    resolve_differentials(&mut backups);
+
+    let chunk_hashes = backups
+        .iter()
+        .flat_map(|backup| backup.tree.values())
+        .flat_map(|object| object.chunks.iter().cloned())
+        .collect::<HashSet<String>>();
+
+    set_progress_message(&pb, &format!("Measuring {} chunks...", chunk_hashes.len()));
+
+    let chunk_sizes = match load_chunk_sizes(&fs, &key, chunk_dir_levels, chunk_hashes).await {
+        Ok(sizes) => sizes,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    pb.finish_and_clear();
+
+    let points = build_points(&backups, &chunk_sizes);
+    let missing_chunks = chunk_sizes.values().filter(|size| size.is_none()).count() as u64;
+
+    if is_json_mode() {
+        if over_time {
+            emit_output(&points);
+        } else {
+            emit_output(&build_output(&points, missing_chunks));
+        }
+        return;
+    }
+
+    if over_time {
+        display_over_time(&points);
+    }
+
+    display_summary(&build_output(&points, missing_chunks));
+}
+
+fn set_progress_message(pb: &ProgressBar, message: &str) {
+    pb.set_message(message.to_string());
+    if is_json_mode() {
+        emit_progress_message(message);
+    }
+}
+
+/// Looks up the stored size of every chunk once, so each data point is computed from this
+/// map instead of asking the storage again. Missing chunks map to `None`.
+async fn load_chunk_sizes(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    chunk_dir_levels: u8,
+    chunk_hashes: HashSet<String>,
+) -> Result<HashMap<String, Option<u64>>, String> {
+    stream::iter(chunk_hashes)
+        .map(|chunk_hash| {
+            let fs = Arc::clone(fs);
+            let path = chunk_path(key, &chunk_hash, chunk_dir_levels);
+            async move {
+                match fs.file_size(&path).await {
+                    Ok(size) => Ok((chunk_hash, Some(size))),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok((chunk_hash, None)),
+                    Err(e) => Err(format!("Failed to get the size of chunk {}: {}", path, e)),
+                }
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_SIZE_REQUESTS)
+        .collect::<Vec<Result<(String, Option<u64>), String>>>()
+        .await
+        .into_iter()
+        .collect()
+}
+
+/// Completes the trees of differential backups from their bases. Bases are older, so
+/// walking oldest first always finds them already complete. A differential whose base is
+/// gone keeps its partial tree.
+fn resolve_differentials(backups: &mut [Backup]) {
+    let bases = backups
+        .iter()
+        .filter_map(|backup| backup.base.clone())
+        .collect::<HashSet<String>>();
+    let mut resolved: HashMap<String, HashMap<String, BackupObject>> = HashMap::new();
+
+    for backup in backups.iter_mut() {
+        if let Some(base_tree) = backup.base.as_ref().and_then(|base| resolved.get(base)) {
+            let mut tree = base_tree.clone();
+            apply_differential(&mut tree, std::mem::take(&mut backup.tree), &backup.removed);
+            backup.tree = tree;
+        }
+
+        if bases.contains(&backup.hash) {
+            resolved.insert(backup.hash.clone(), backup.tree.clone());
+        }
+    }
+}
+
+/// Walks `backups` oldest first, accumulating the chunks seen so far.
+fn build_points(backups: &[Backup], chunk_sizes: &HashMap<String, Option<u64>>) -> Vec<StatsPoint> {
+    let mut seen_chunks = HashSet::new();
+    let mut stored_bytes = 0u64;
+    let mut total_logical_bytes = 0u64;
+
+    backups
+        .iter()
+        .map(|backup| {
+            let mut new_chunks = 0u64;
+            let mut new_stored_bytes = 0u64;
+
+            for chunk_hash in backup.tree.values().flat_map(|object| &object.chunks) {
+                if seen_chunks.insert(chunk_hash.as_str()) {
+                    new_chunks += 1;
+                    new_stored_bytes += chunk_sizes.get(chunk_hash).copied().flatten().unwrap_or(0);
+                }
+            }
+
+            let logical_bytes = backup.tree.values().map(|object| object.size).sum::<u64>();
+            stored_bytes += new_stored_bytes;
+            total_logical_bytes += logical_bytes;
+
+            StatsPoint {
+                backup: backup.hash.clone(),
+                backup_short: backup.hash[..8.min(backup.hash.len())].to_string(),
+                timestamp: DateTime::<Utc>::from_timestamp_secs(backup.timestamp as i64)
+                    .map(|dt| dt.to_rfc3339_opts(SecondsFormat::Secs, true)),
+                timestamp_unix: backup.timestamp,
+                files: backup.tree.len() as u64,
+                logical_bytes,
+                stored_bytes,
+                new_stored_bytes,
+                unique_chunks: seen_chunks.len() as u64,
+                new_chunks,
+                dedup_ratio: dedup_ratio(total_logical_bytes, stored_bytes),
+            }
+        })
+        .collect()
+}
+
+fn build_output(points: &[StatsPoint], missing_chunks: u64) -> StatsOutput {
+    let total_logical_bytes = points.iter().map(|point| point.logical_bytes).sum::<u64>();
+    let last = points.last();
+
+    let growth_bytes_per_week = match (points.first(), last) {
+        (Some(first), Some(last)) if last.timestamp_unix > first.timestamp_unix => {
+            let elapsed = (last.timestamp_unix - first.timestamp_unix) as f64;
+            Some((last.stored_bytes - first.stored_bytes) as f64 / elapsed * SECONDS_PER_WEEK)
+        }
+        _ => None,
+    };
+
+    StatsOutput {
+        backups: points.len(),
+        latest_logical_bytes: last.map_or(0, |point| point.logical_bytes),
+        total_logical_bytes,
+        stored_bytes: last.map_or(0, |point| point.stored_bytes),
+        unique_chunks: last.map_or(0, |point| point.unique_chunks),
+        missing_chunks,
+        dedup_ratio: last.map_or(0.0, |point| point.dedup_ratio),
+        growth_bytes_per_week,
+    }
+}
+
+fn dedup_ratio(logical_bytes: u64, stored_bytes: u64) -> f64 {
+    if stored_bytes == 0 {
+        return 0.0;
+    }
+
+    logical_bytes as f64 / stored_bytes as f64
+}
+
+fn display_over_time(points: &[StatsPoint]) {
+    let rows = points
+        .iter()
+        .map(|point| StatsRow {
+            date: DateTime::<Utc>::from_timestamp_secs(point.timestamp_unix as i64)
+                .map(|dt| {
+                    dt.with_timezone(&Local)
+                        .format("%Y-%m-%d %H:%M")
+                        .to_string()
+                })
+                .unwrap_or_default(),
+            backup: point.backup_short.clone(),
+            files: point.files,
+            logical: ByteSize(point.logical_bytes).to_string(),
+            stored: ByteSize(point.stored_bytes).to_string(),
+            new: format!("+{}", ByteSize(point.new_stored_bytes)),
+            chunks: point.unique_chunks,
+            dedup: format!("{:.2}x", point.dedup_ratio),
+        })
+        .collect::<Vec<StatsRow>>();
+
+    println!("{}", Table::new(rows));
+    println!();
+}
+
+fn display_summary(output: &StatsOutput) {
+    println!("{}", style("Repository stats").bold());
+    println!("  Backups:          {}", output.backups);
+    println!(
+        "  Latest backup:    {}",
+        ByteSize(output.latest_logical_bytes)
+    );
+    println!(
+        "  All backups:      {}",
+        ByteSize(output.total_logical_bytes)
+    );
+    println!(
+        "  Stored:           {} in {} chunks",
+        ByteSize(output.stored_bytes),
+        output.unique_chunks
+    );
+    println!("  Dedup ratio:      {:.2}x", output.dedup_ratio);
+
+    if let Some(growth) = output.growth_bytes_per_week {
+        println!(
+            "  Growth:           {} per week",
+            ByteSize(growth.max(0.0) as u64)
+        );
+    }
+
+    if output.missing_chunks > 0 {
+        println!(
+            "{}",
+            style(format!(
+                "{} referenced chunks are missing from the storage and were counted as 0 bytes. Run 'gib reindex --verify-chunks' for details.",
+                output.missing_chunks
+            ))
+            .yellow()
+        );
+    }
+}
+
+fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>), String> {
+    let password: Option<String> = matches
+        .get_one::<String>("password")
+        .map(|s| s.to_string())
+        .map_or_else(
+            || get_password(false, true),
+            |password| Some(password.to_string()),
+        );
+
+    let pwd_string = get_pwd_string();
+
+    let folder_key = Path::new(&pwd_string)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    Ok((key, storage, password))
+}
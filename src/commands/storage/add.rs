@@ -2,15 +2,14 @@ use clap::ArgMatches;
 use dialoguer::{Input, Select};
 use dirs::home_dir;
 use indicatif::{ProgressBar, ProgressStyle};
-use rmp_serde::Serializer;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use std::time::Duration;
 
-use crate::output::{JsonProgress, emit_output, is_json_mode};
-use crate::utils::handle_error;
+use crate::output::{JsonProgress, can_prompt, emit_output, is_json_mode};
+use crate::utils::{get_storage, handle_error};
 
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, PartialEq)]
 pub struct Storage {
     pub storage_type: u8,
     pub path: Option<String>,
@@ -19,14 +18,197 @@ pub struct Storage {
     pub access_key: Option<String>,
     pub secret_key: Option<String>,
     pub endpoint: Option<String>,
+    pub aws_profile: Option<String>,
+    pub use_credential_chain: bool,
+    /// Folder of the bucket or path that every repository key is stored under.
+    pub prefix: Option<String>,
+}
+
+impl Storage {
+    /// Describes where S3 credentials come from, for display in `storage add`/`storage list`.
+    pub fn credentials_source(&self) -> Option<String> {
+        if self.storage_type != 1 {
+            return None;
+        }
+
+        Some(match &self.aws_profile {
+            Some(profile) => format!("profile:{}", profile),
+            None if self.use_credential_chain => "credential_chain".to_string(),
+            None => "static".to_string(),
+        })
+    }
+}
+
+/// How storages are written to `~/.gib/storages/{name}.msgpack`: a map of named fields,
+/// with the backend as a `type` name.
+#[derive(Debug, PartialEq, Deserialize, Serialize)]
+pub struct StorageConfig {
+    #[serde(rename = "type")]
+    pub storage_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bucket: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aws_profile: Option<String>,
+    #[serde(default)]
+    pub use_credential_chain: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<String>,
+}
+
+/// The format storages were written in before `StorageConfig`: the fields in order, with
+/// the backend as its numeric id. Still read, never written.
+#[derive(Debug, PartialEq, Deserialize)]
+pub struct LegacyStorage {
+    pub storage_type: u8,
+    pub path: Option<String>,
+    pub region: Option<String>,
+    pub bucket: Option<String>,
+    pub access_key: Option<String>,
+    pub secret_key: Option<String>,
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub aws_profile: Option<String>,
+    #[serde(default)]
+    pub use_credential_chain: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StorageFormat {
+    Current,
+    Legacy,
+}
+
+/// Reads a storage file in either format, and tells which one it was in.
+pub fn parse_storage_config(bytes: &[u8]) -> Result<(Storage, StorageFormat), String> {
+    if let Ok(config) = rmp_serde::from_slice::<StorageConfig>(bytes) {
+        let storage_type = storage_type_id(&config.storage_type)
+            .ok_or_else(|| format!("Unknown storage type '{}'", config.storage_type))?;
+
+        let storage = Storage {
+            storage_type,
+            path: config.path,
+            region: config.region,
+            bucket: config.bucket,
+            access_key: config.access_key,
+            secret_key: config.secret_key,
+            endpoint: config.endpoint,
+            aws_profile: config.aws_profile,
+            use_credential_chain: config.use_credential_chain,
+            prefix: config.prefix,
+        };
+
+        return Ok((storage, StorageFormat::Current));
+    }
+
+    let legacy: LegacyStorage = rmp_serde::from_slice(bytes).map_err(|e| e.to_string())?;
+
+    let storage = Storage {
+        storage_type: legacy.storage_type,
+        path: legacy.path,
+        region: legacy.region,
+        bucket: legacy.bucket,
+        access_key: legacy.access_key,
+        secret_key: legacy.secret_key,
+        endpoint: legacy.endpoint,
+        aws_profile: legacy.aws_profile,
+        use_credential_chain: legacy.use_credential_chain,
+        prefix: None,
+    };
+
+    Ok((storage, StorageFormat::Legacy))
+}
+
+/// Serializes `storage` as a `StorageConfig`.
+pub fn encode_storage_config(storage: &Storage) -> Result<Vec<u8>, String> {
+    let storage_type = storage_type_name(storage.storage_type)
+        .ok_or_else(|| format!("Unknown storage type {}", storage.storage_type))?;
+
+    let config = StorageConfig {
+        storage_type: storage_type.to_string(),
+        path: storage.path.clone(),
+        region: storage.region.clone(),
+        bucket: storage.bucket.clone(),
+        access_key: storage.access_key.clone(),
+        secret_key: storage.secret_key.clone(),
+        endpoint: storage.endpoint.clone(),
+        aws_profile: storage.aws_profile.clone(),
+        use_credential_chain: storage.use_credential_chain,
+        prefix: storage.prefix.clone(),
+    };
+
+    rmp_serde::to_vec_named(&config).map_err(|e| e.to_string())
+}
+
+/// Checks a `--prefix` and strips its surrounding slashes; an empty prefix means none.
+/// Only plain folder names are accepted, so a prefix can never point outside the storage.
+pub fn normalize_prefix(prefix: &str) -> Result<Option<String>, String> {
+    let prefix = prefix.trim_matches('/');
+
+    if prefix.is_empty() {
+        return Ok(None);
+    }
+
+    let valid = prefix.split('/').all(|segment| {
+        !segment.is_empty()
+            && segment != "."
+            && segment != ".."
+            && segment
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    });
+
+    if !valid {
+        return Err(format!(
+            "Invalid prefix '{}': it must be folder names of letters, numbers, underscores (_), hyphens (-) or dots (.), separated by slashes",
+            prefix
+        ));
+    }
+
+    Ok(Some(prefix.to_string()))
+}
+
+/// Every storage backend gib knows about, by the `storage_type` stored in its config.
+const STORAGE_TYPES: &[(u8, &str)] = &[(0, "local"), (1, "s3")];
+
+/// The storage types compiled into this build, as accepted by `storage add --type`.
+pub fn storage_type_values() -> Vec<&'static str> {
+    vec![
+        "local",
+        #[cfg(feature = "s3")]
+        "s3",
+    ]
+}
+
+pub fn storage_type_name(storage_type: u8) -> Option<&'static str> {
+    STORAGE_TYPES
+        .iter()
+        .find(|(id, _)| *id == storage_type)
+        .map(|(_, name)| *name)
+}
+
+fn storage_type_id(name: &str) -> Option<u8> {
+    STORAGE_TYPES
+        .iter()
+        .find(|(_, type_name)| *type_name == name)
+        .map(|(id, _)| *id)
 }
 
 pub fn add(matches: &ArgMatches) {
     let name = matches.get_one::<String>("name").map_or_else(
         || {
-            if is_json_mode() {
+            if !can_prompt() {
                 handle_error(
-                    "Missing required argument: --name (required in --mode json)".to_string(),
+                    "Missing required argument: --name (required in --mode json or with --non-interactive)".to_string(),
                     None,
                 );
             }
@@ -53,33 +235,64 @@ pub fn add(matches: &ArgMatches) {
         );
     }
 
+    let copied_from = matches.get_one::<String>("copy-from").cloned();
+
+    if copied_from.as_ref() == Some(&name) {
+        handle_error(
+            format!("Storage '{}' cannot be copied onto itself", name),
+            None,
+        );
+    }
+
+    let base = copied_from.as_deref().map(get_storage);
+
     let storage_type: u8 = matches.get_one::<String>("type").map_or_else(
         || {
-            if is_json_mode() {
+            if let Some(base) = &base {
+                return base.storage_type;
+            }
+            if !can_prompt() {
                 handle_error(
-                    "Missing required argument: --type (required in --mode json)".to_string(),
+                    "Missing required argument: --type (required in --mode json or with --non-interactive)".to_string(),
                     None,
                 );
             }
-            let selected_storage_type: u8 = Select::new()
+            let storage_types = storage_type_values();
+            let selected_index = Select::new()
                 .with_prompt("Enter the type of the storage")
                 .default(0)
-                .items(&["local", "s3"])
+                .items(&storage_types)
                 .interact()
                 .unwrap_or_else(|e| {
                     handle_error(format!("Error: {}", e), None);
-                }) as u8;
-            selected_storage_type
+                });
+            storage_type_id(storage_types[selected_index]).unwrap()
         },
-        |storage_type| match storage_type.as_str() {
-            "local" => 0u8,
-            "s3" => 1u8,
+        |storage_type| match storage_type_id(storage_type) {
+            Some(id) if storage_type_values().contains(&storage_type.as_str()) => id,
             _ => {
                 handle_error(format!("Unknown storage type '{}'", storage_type), None);
             }
         },
     );
 
+    if let (Some(base), Some(copied_from)) = (&base, &copied_from)
+        && base.storage_type != storage_type
+    {
+        handle_error(
+            format!(
+                "--type {} does not match storage '{}', which is {}",
+                storage_type_name(storage_type).unwrap_or("unknown"),
+                copied_from,
+                storage_type_name(base.storage_type).unwrap_or("unknown")
+            ),
+            None,
+        );
+    }
+
+    let copied =
+        |field: fn(&Storage) -> &Option<String>| base.as_ref().and_then(|base| field(base).clone());
+
     let mut storage = Storage {
         storage_type,
         path: None,
@@ -88,26 +301,20 @@ pub fn add(matches: &ArgMatches) {
         access_key: None,
         secret_key: None,
         endpoint: None,
+        aws_profile: None,
+        use_credential_chain: false,
+        prefix: match matches.get_one::<String>("prefix") {
+            Some(prefix) => normalize_prefix(prefix).unwrap_or_else(|e| handle_error(e, None)),
+            None => base.as_ref().and_then(|base| base.prefix.clone()),
+        },
     };
 
     if storage_type == 0 {
-        let path = matches.get_one::<String>("path").map_or_else(
-            || {
-                if is_json_mode() {
-                    handle_error(
-                        "Missing required argument: --path (required in --mode json)".to_string(),
-                        None,
-                    );
-                }
-                let typed_path: String = Input::<String>::new()
-                    .with_prompt("Enter the path for local storage")
-                    .interact_text()
-                    .unwrap_or_else(|e| {
-                        handle_error(format!("Error: {}", e), None);
-                    });
-                typed_path
-            },
-            |path| path.to_string(),
+        let path = value_or_prompt(
+            matches,
+            "path",
+            "Enter the path for local storage",
+            copied(|storage| &storage.path),
         );
 
         if !Path::new(&path).exists() {
@@ -117,92 +324,79 @@ pub fn add(matches: &ArgMatches) {
 
         storage.path = Some(path);
     } else {
-        let region = matches.get_one::<String>("region").map_or_else(
-            || {
-                if is_json_mode() {
-                    handle_error(
-                        "Missing required argument: --region (required in --mode json)".to_string(),
-                        None,
-                    );
-                }
-                let typed_region: String = Input::<String>::new()
-                    .with_prompt("Enter the S3 region")
-                    .interact_text()
-                    .unwrap_or_else(|e| {
-                        handle_error(format!("Error: {}", e), None);
-                    });
-                typed_region
-            },
-            |region| region.to_string(),
+        let region = value_or_prompt(
+            matches,
+            "region",
+            "Enter the S3 region",
+            copied(|storage| &storage.region),
         );
 
-        let bucket = matches.get_one::<String>("bucket").map_or_else(
-            || {
-                if is_json_mode() {
-                    handle_error(
-                        "Missing required argument: --bucket (required in --mode json)".to_string(),
-                        None,
-                    );
-                }
-                let typed_bucket: String = Input::<String>::new()
-                    .with_prompt("Enter the S3 bucket")
-                    .interact_text()
-                    .unwrap_or_else(|e| {
-                        handle_error(format!("Error: {}", e), None);
-                    });
-                typed_bucket
-            },
-            |bucket| bucket.to_string(),
+        let bucket = value_or_prompt(
+            matches,
+            "bucket",
+            "Enter the S3 bucket",
+            copied(|storage| &storage.bucket),
         );
 
-        let access_key = matches.get_one::<String>("access-key").map_or_else(
-            || {
-                if is_json_mode() {
-                    handle_error(
-                        "Missing required argument: --access-key (required in --mode json)"
-                            .to_string(),
-                        None,
-                    );
-                }
-                let typed_access_key: String = Input::<String>::new()
-                    .with_prompt("Enter the S3 access key")
-                    .interact_text()
-                    .unwrap_or_else(|e| {
-                        handle_error(format!("Error: {}", e), None);
-                    });
-                typed_access_key
-            },
-            |access_key| access_key.to_string(),
-        );
+        let credential_flags_given = ["aws-profile", "access-key", "secret-key"]
+            .iter()
+            .any(|arg| matches.get_one::<String>(arg).is_some())
+            || matches.get_flag("use-credential-chain");
 
-        let secret_key = matches.get_one::<String>("secret-key").map_or_else(
-            || {
-                if is_json_mode() {
-                    handle_error(
-                        "Missing required argument: --secret-key (required in --mode json)"
-                            .to_string(),
-                        None,
-                    );
-                }
-                let typed_secret_key: String = Input::<String>::new()
-                    .with_prompt("Enter the S3 secret key")
-                    .interact_text()
-                    .unwrap_or_else(|e| {
-                        handle_error(format!("Error: {}", e), None);
-                    });
-                typed_secret_key
-            },
-            |secret_key| secret_key.to_string(),
-        );
+        // Without credential flags, a copy keeps the credentials of its source as they
+        // are, so secrets never have to be typed again.
+        let (aws_profile, use_credential_chain) = match &base {
+            Some(base) if !credential_flags_given => {
+                (base.aws_profile.clone(), base.use_credential_chain)
+            }
+            _ => (
+                matches.get_one::<String>("aws-profile").cloned(),
+                matches.get_flag("use-credential-chain"),
+            ),
+        };
+
+        if aws_profile.is_none() && !use_credential_chain {
+            let access_key = matches
+                .get_one::<String>("access-key")
+                .cloned()
+                .or_else(|| copied(|storage| &storage.access_key))
+                .unwrap_or_else(|| {
+                    value_or_prompt(matches, "access-key", "Enter the S3 access key", None)
+                });
+
+            let secret_key = matches
+                .get_one::<String>("secret-key")
+                .cloned()
+                .or_else(|| copied(|storage| &storage.secret_key))
+                .unwrap_or_else(|| {
+                    value_or_prompt(matches, "secret-key", "Enter the S3 secret key", None)
+                });
+
+            storage.access_key = Some(access_key);
+            storage.secret_key = Some(secret_key);
+        }
+
+        // A copied endpoint that is just the AWS default of the source's region follows
+        // the new region instead.
+        let aws_endpoint = |region: &str| format!("https://s3.{}.amazonaws.com", region);
+        let default_endpoint = match copied(|storage| &storage.endpoint) {
+            Some(endpoint)
+                if copied(|storage| &storage.region)
+                    .is_none_or(|base_region| endpoint != aws_endpoint(&base_region)) =>
+            {
+                endpoint
+            }
+            _ => aws_endpoint(&region),
+        };
 
         let endpoint = matches.get_one::<String>("endpoint").map_or_else(
             || {
-                if is_json_mode() {
-                    return format!("https://s3.{}.amazonaws.com", region);
+                if !can_prompt() {
+                    return default_endpoint.clone();
                 }
                 let typed_endpoint: String = Input::<String>::new()
                     .with_prompt("Enter the S3 endpoint")
-                    .default(format!("https://s3.{}.amazonaws.com", region))
+                    .default(default_endpoint.clone())
                     .show_default(true)
                     .interact_text()
                     .unwrap_or_else(|e| {
@@ -215,9 +409,9 @@ pub fn add(matches: &ArgMatches) {
 
         storage.region = Some(region);
         storage.bucket = Some(bucket);
-        storage.access_key = Some(access_key);
-        storage.secret_key = Some(secret_key);
         storage.endpoint = Some(endpoint);
+        storage.aws_profile = aws_profile;
+        storage.use_credential_chain = use_credential_chain;
     }
 
     let json_progress = if is_json_mode() {
@@ -250,9 +444,7 @@ pub fn add(matches: &ArgMatches) {
 
     storage_path.push(format!("{}.msgpack", name));
 
-    let mut buf = Vec::new();
-    storage
-        .serialize(&mut Serializer::new(&mut buf))
+    let buf = encode_storage_config(&storage)
         .unwrap_or_else(|e| handle_error(format!("Failed to serialize storage: {}", e), None));
 
     std::fs::write(&storage_path, buf)
@@ -271,13 +463,15 @@ pub fn add(matches: &ArgMatches) {
             region: Option<String>,
             bucket: Option<String>,
             endpoint: Option<String>,
+            credentials: Option<String>,
+            prefix: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            copied_from: Option<String>,
         }
 
-        let storage_type_label = match storage.storage_type {
-            0 => "local",
-            1 => "s3",
-            _ => "unknown",
-        };
+        let storage_type_label = storage_type_name(storage.storage_type).unwrap_or("unknown");
+
+        let credentials = storage.credentials_source();
 
         let payload = StorageOutput {
             name,
@@ -286,6 +480,9 @@ pub fn add(matches: &ArgMatches) {
             region: storage.region,
             bucket: storage.bucket,
             endpoint: storage.endpoint,
+            credentials,
+            prefix: storage.prefix,
+            copied_from,
         };
         emit_output(&payload);
     } else {
@@ -294,5 +491,73 @@ pub fn add(matches: &ArgMatches) {
         pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
         pb.set_prefix("OK");
         pb.finish_with_message(format!("Storage written ({:.2?})", elapsed));
+
+        if let Some(copied_from) = &copied_from {
+            println!("Copied from '{}':", copied_from);
+            match storage.storage_type {
+                0 => println!("  path: {}", storage.path.as_deref().unwrap_or_default()),
+                _ => {
+                    println!(
+                        "  region: {}",
+                        storage.region.as_deref().unwrap_or_default()
+                    );
+                    println!(
+                        "  bucket: {}",
+                        storage.bucket.as_deref().unwrap_or_default()
+                    );
+                    println!(
+                        "  endpoint: {}",
+                        storage.endpoint.as_deref().unwrap_or_default()
+                    );
+                    if storage.access_key.is_some() {
+                        println!("  access_key: ********");
+                        println!("  secret_key: ********");
+                    } else {
+                        println!(
+                            "  credentials: {}",
+                            storage.credentials_source().unwrap_or_default()
+                        );
+                    }
+                }
+            }
+            if let Some(prefix) = &storage.prefix {
+                println!("  prefix: {}", prefix);
+            }
+        }
     }
 }
+
+/// Returns the value of `arg`, or asks for it. `copied` is the value of the storage given
+/// with `--copy-from`: used as is when prompting isn't possible, otherwise offered as the
+/// default answer.
+fn value_or_prompt(
+    matches: &ArgMatches,
+    arg: &str,
+    prompt: &str,
+    copied: Option<String>,
+) -> String {
+    if let Some(value) = matches.get_one::<String>(arg) {
+        return value.clone();
+    }
+
+    if !can_prompt() {
+        return copied.unwrap_or_else(|| {
+            handle_error(
+                format!(
+                    "Missing required argument: --{} (required in --mode json or with --non-interactive)",
+                    arg
+                ),
+                None,
+            )
+        });
+    }
+
+    let mut input = Input::<String>::new().with_prompt(prompt);
+    if let Some(copied) = copied {
+        input = input.default(copied);
+    }
+
+    input.interact_text().unwrap_or_else(|e| {
+        handle_error(format!("Error: {}", e), None);
+    })
+}
@@ -1,3 +1,4 @@
+use crate::commands::storage::add::storage_type_name;
 use crate::output::{emit_output, is_json_mode};
 use crate::utils::{get_storage, handle_error};
 use dirs::home_dir;
@@ -38,15 +39,11 @@ pub fn list() {
         let storage_name = file_name.split('.').next().unwrap();
         let storage = get_storage(storage_name);
 
-        let storage_type = match storage.storage_type {
-            0 => "local",
-            1 => "s3",
-            _ => "unknown",
-        };
+        let storage_type = storage_type_name(storage.storage_type).unwrap_or("unknown");
 
         let details = match storage.storage_type {
             0 => format!("path: {}", storage.path.clone().unwrap_or_default()),
-            1 => format!(
+            1 if storage.access_key.is_some() => format!(
                 "region: {}, bucket: {}, access_key: {}, secret_key: {}, endpoint: {}",
                 storage.region.clone().unwrap_or_default(),
                 storage.bucket.clone().unwrap_or_default(),
@@ -54,15 +51,29 @@ pub fn list() {
                 "********",
                 storage.endpoint.clone().unwrap_or_default()
             ),
+            1 => format!(
+                "region: {}, bucket: {}, credentials: {}, endpoint: {}",
+                storage.region.clone().unwrap_or_default(),
+                storage.bucket.clone().unwrap_or_default(),
+                storage.credentials_source().unwrap_or_default(),
+                storage.endpoint.clone().unwrap_or_default()
+            ),
             _ => "unknown".to_string(),
         };
 
+        let details = match &storage.prefix {
+            Some(prefix) => format!("{}, prefix: {}", details, prefix),
+            None => details,
+        };
+
         rows.push(StorageRow {
             name: storage_name.to_string(),
             storage_type: storage_type.to_string(),
             details: details.clone(),
         });
 
+        let credentials = storage.credentials_source();
+
         json_rows.push(StorageInfo {
             name: storage_name.to_string(),
             storage_type: storage_type.to_string(),
@@ -70,6 +81,8 @@ pub fn list() {
             region: storage.region,
             bucket: storage.bucket,
             endpoint: storage.endpoint,
+            credentials,
+            prefix: storage.prefix,
         });
     }
 
@@ -89,4 +102,6 @@ struct StorageInfo {
     region: Option<String>,
     bucket: Option<String>,
     endpoint: Option<String>,
+    credentials: Option<String>,
+    prefix: Option<String>,
 }
@@ -0,0 +1,594 @@
+use crate::commands::config::{default_key, default_storage};
+use crate::core::indexes::{load_repository_meta, save_repository_meta};
+use crate::core::layout::{MAX_CHUNK_DIR_LEVELS, chunk_path};
+use crate::core::lock::acquire_lock;
+use crate::fs::FS;
+use crate::output::{
+    JsonProgress, can_prompt, emit_error, emit_error_with_details, emit_output,
+    emit_progress_message, emit_warning, is_json_mode,
+};
+use crate::utils::{get_fs, get_pwd_string, get_storage, handle_error, parse_duration};
+use clap::ArgMatches;
+use dialoguer::Select;
+use dirs::home_dir;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{debug, info};
+
+const MAX_CONCURRENT_CHUNKS: usize = 100;
+
+/// A stored chunk object: its path, hash and how many folders deep it is nested.
+struct ChunkObject {
+    path: String,
+    hash: String,
+    dir_levels: u8,
+}
+
+#[derive(serde::Serialize)]
+struct MigrateLayoutOutput {
+    dry_run: bool,
+    from_levels: u8,
+    to_levels: u8,
+    /// Distinct chunks stored, in any layout.
+    chunks_stored: usize,
+    /// Chunks that were (or, in a dry run, would be) copied to the new layout.
+    chunks_copied: usize,
+    /// Objects in another layout that were (or would be) removed once every chunk was
+    /// found in the new one.
+    old_objects_removed: usize,
+    elapsed_ms: u64,
+}
+
+#[derive(serde::Serialize)]
+struct FailedObject {
+    path: String,
+    detail: String,
+}
+
+pub async fn migrate_layout(matches: &ArgMatches) {
+    let (key, storage) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let started_at = Instant::now();
+    let dry_run = matches.get_flag("dry-run");
+
+    let to_levels = match matches
+        .get_one::<String>("to")
+        .map(|to| (to, to.parse::<u8>()))
+    {
+        Some((_, Ok(levels))) if (1..=MAX_CHUNK_DIR_LEVELS).contains(&levels) => levels,
+        Some((to, _)) => handle_error(
+            format!(
+                "Invalid value for --to: '{}' (must be 1 to {})",
+                to, MAX_CHUNK_DIR_LEVELS
+            ),
+            None,
+        ),
+        None => handle_error("Missing required argument: --to".to_string(), None),
+    };
+
+    let concurrency = match matches.get_one::<String>("concurrency") {
+        Some(concurrency) => match concurrency.parse::<usize>() {
+            Ok(concurrency) if concurrency > 0 => concurrency,
+            _ => handle_error(format!("Invalid concurrency: '{}'", concurrency), None),
+        },
+        None => MAX_CONCURRENT_CHUNKS,
+    };
+
+    let lock_timeout = match matches.get_one::<String>("lock-timeout") {
+        Some(timeout) => match parse_duration(timeout, "--lock-timeout") {
+            Ok(timeout) => timeout,
+            Err(e) => handle_error(e, None),
+        },
+        None => Duration::ZERO,
+    };
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb
+    };
+
+    set_progress_message(&pb, "Loading repository settings...");
+
+    let repository_meta = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+    let has_settings = repository_meta.is_some();
+    let mut repository_meta = repository_meta.unwrap_or_default();
+    let from_levels = repository_meta.chunk_dir_levels;
+
+    set_progress_message(&pb, "Listing chunks...");
+
+    let objects = match list_chunk_objects(&fs, &key).await {
+        Ok(objects) => objects,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    if objects.is_empty() && !has_settings {
+        handle_error(
+            format!("Repository '{}' has no chunks to migrate", key),
+            Some(&pb),
+        );
+    }
+
+    let (to_copy, old_objects) = plan_migration(&objects, to_levels);
+    let chunks_stored = objects
+        .iter()
+        .map(|object| object.hash.as_str())
+        .collect::<HashSet<&str>>()
+        .len();
+
+    let mut payload = MigrateLayoutOutput {
+        dry_run,
+        from_levels,
+        to_levels,
+        chunks_stored,
+        chunks_copied: to_copy.len(),
+        old_objects_removed: old_objects.len(),
+        elapsed_ms: 0,
+    };
+
+    if dry_run || (to_copy.is_empty() && old_objects.is_empty() && from_levels == to_levels) {
+        pb.finish_and_clear();
+        payload.elapsed_ms = started_at.elapsed().as_millis() as u64;
+        print_output(&payload);
+        return;
+    }
+
+    let lock = match acquire_lock(
+        Arc::clone(&fs),
+        &key,
+        lock_timeout,
+        |holder| {
+            set_progress_message(
+                &pb,
+                &format!("Waiting for the repository lock held by {}...", holder),
+            )
+        },
+        |holder, reason| {
+            pb.suspend(|| {
+                emit_warning(
+                    &format!(
+                        "Taking over the stale repository lock held by {}: {}",
+                        holder, reason
+                    ),
+                    "stale_lock_taken_over",
+                )
+            })
+        },
+    )
+    .await
+    {
+        Ok(lock) => lock,
+        Err(e) => {
+            if is_json_mode() {
+                emit_error(&e, "repository_locked");
+            }
+            handle_error(e, Some(&pb));
+        }
+    };
+
+    pb.finish_and_clear();
+
+    let failures = copy_chunks(&fs, &key, to_copy, to_levels, concurrency).await;
+
+    if !failures.is_empty() {
+        let _ = lock.release().await;
+        let message = format!(
+            "Failed to copy {} chunks, nothing was removed (run the command again to resume):\n{}",
+            failures.len(),
+            format_failures(&failures)
+        );
+        if is_json_mode() {
+            emit_error_with_details(&message, "migrate_layout_failed", &failures);
+        }
+        handle_error(message, None);
+    }
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb
+    };
+
+    // Nothing is removed until a fresh listing shows every chunk in the new layout.
+    set_progress_message(&pb, "Verifying the new layout...");
+
+    let objects = match list_chunk_objects(&fs, &key).await {
+        Ok(objects) => objects,
+        Err(e) => {
+            let _ = lock.release().await;
+            handle_error(e, Some(&pb))
+        }
+    };
+
+    let (missing, old_objects) = plan_migration(&objects, to_levels);
+    if !missing.is_empty() {
+        let _ = lock.release().await;
+        handle_error(
+            format!(
+                "{} chunks are still missing from the new layout, nothing was removed (run the command again to resume)",
+                missing.len()
+            ),
+            Some(&pb),
+        );
+    }
+
+    if from_levels != to_levels || !has_settings {
+        repository_meta.chunk_dir_levels = to_levels;
+        if let Err(e) = save_repository_meta(Arc::clone(&fs), key.clone(), &repository_meta).await {
+            let _ = lock.release().await;
+            handle_error(e, Some(&pb));
+        }
+        info!(from_levels, to_levels, "switched the chunk layout");
+    }
+
+    set_progress_message(
+        &pb,
+        &format!(
+            "Removing {} objects of the old layout...",
+            old_objects.len()
+        ),
+    );
+
+    let old_object_count = old_objects.len();
+    let failures = remove_objects(&fs, old_objects, concurrency).await;
+    payload.old_objects_removed = old_object_count - failures.len();
+
+    if !failures.is_empty() {
+        let message = format!(
+            "The repository uses the new layout, but {} objects of the old one could not be removed (run the command again to remove them):\n{}",
+            failures.len(),
+            format_failures(&failures)
+        );
+        pb.suspend(|| emit_warning(&message, "old_objects_not_removed"));
+    }
+
+    if let Err(e) = lock.release().await {
+        pb.suspend(|| emit_warning(&e, "lock_release_failed"));
+    }
+
+    pb.finish_and_clear();
+    payload.elapsed_ms = started_at.elapsed().as_millis() as u64;
+    print_output(&payload);
+}
+
+fn print_output(payload: &MigrateLayoutOutput) {
+    if is_json_mode() {
+        emit_output(payload);
+        return;
+    }
+
+    if payload.dry_run {
+        println!(
+            "Would copy {} of {} chunks from {} to {} folder level(s) and remove {} objects of the old layout",
+            payload.chunks_copied,
+            payload.chunks_stored,
+            payload.from_levels,
+            payload.to_levels,
+            payload.old_objects_removed
+        );
+    } else if payload.chunks_copied == 0 && payload.old_objects_removed == 0 {
+        println!(
+            "The repository already stores its {} chunks {} folder level(s) deep",
+            payload.chunks_stored, payload.to_levels
+        );
+    } else {
+        println!(
+            "Moved {} chunks to {} folder level(s) and removed {} objects of the old layout ({:.2?})",
+            payload.chunks_copied,
+            payload.to_levels,
+            payload.old_objects_removed,
+            Duration::from_millis(payload.elapsed_ms)
+        );
+    }
+}
+
+fn set_progress_message(pb: &ProgressBar, message: &str) {
+    pb.set_message(message.to_string());
+    if is_json_mode() {
+        emit_progress_message(message);
+    }
+}
+
+fn format_failures(failures: &[FailedObject]) -> String {
+    failures
+        .iter()
+        .map(|failure| format!("  - {}: {}", failure.path, failure.detail))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Every chunk object of `key`, in any layout. Other files in the chunks folder, like
+/// `.bak` copies, are left out.
+async fn list_chunk_objects(fs: &Arc<dyn FS>, key: &str) -> Result<Vec<ChunkObject>, String> {
+    let prefix = format!("{}/chunks/", key);
+
+    let paths = fs
+        .list_files(&format!("{}/chunks", key))
+        .await
+        .map_err(|e| format!("Failed to list chunks: {}", e))?;
+
+    Ok(paths
+        .into_iter()
+        .filter_map(|path| {
+            let segments = path
+                .strip_prefix(&prefix)?
+                .split('/')
+                .collect::<Vec<&str>>();
+            let (rest, folders) = segments.split_last()?;
+
+            if folders.iter().any(|folder| folder.len() != 2) {
+                return None;
+            }
+
+            let hash = format!("{}{}", folders.concat(), rest);
+            if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+
+            Some(ChunkObject {
+                dir_levels: folders.len() as u8,
+                path,
+                hash,
+            })
+        })
+        .collect())
+}
+
+/// The chunks missing from the `to_levels` layout, each with the path of a copy to take
+/// it from, and the paths of every object in another layout.
+fn plan_migration(objects: &[ChunkObject], to_levels: u8) -> (Vec<(String, String)>, Vec<String>) {
+    let migrated = objects
+        .iter()
+        .filter(|object| object.dir_levels == to_levels)
+        .map(|object| object.hash.as_str())
+        .collect::<HashSet<&str>>();
+
+    let mut to_copy = BTreeMap::new();
+    let mut old_objects = Vec::new();
+
+    for object in objects
+        .iter()
+        .filter(|object| object.dir_levels != to_levels)
+    {
+        if !migrated.contains(object.hash.as_str()) {
+            to_copy
+                .entry(object.hash.clone())
+                .or_insert_with(|| object.path.clone());
+        }
+        old_objects.push(object.path.clone());
+    }
+
+    old_objects.sort();
+
+    (to_copy.into_iter().collect(), old_objects)
+}
+
+/// Copies each chunk, as stored, to its path in the `to_levels` layout. The bytes are
+/// not decrypted or decompressed, so no password is needed.
+async fn copy_chunks(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    to_copy: Vec<(String, String)>,
+    to_levels: u8,
+    concurrency: usize,
+) -> Vec<FailedObject> {
+    let json_progress = if is_json_mode() {
+        let progress = JsonProgress::new(to_copy.len() as u64);
+        progress.set_message("Copying chunks to the new layout...");
+        Some(progress)
+    } else {
+        None
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(to_copy.len() as u64);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap(),
+        );
+        pb.set_message("Copying chunks to the new layout...");
+        pb
+    };
+
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let mut tasks = JoinSet::new();
+
+    for (hash, from) in to_copy {
+        let fs = Arc::clone(fs);
+        let to = chunk_path(key, &hash, to_levels);
+        let semaphore = Arc::clone(&semaphore);
+        let pb = pb.clone();
+        let json_progress = json_progress.clone();
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire().await.expect("Semaphore closed");
+
+            let result = match fs.read_file(&from).await {
+                Ok(bytes) => fs.write_file(&to, &bytes).await.map_err(|e| FailedObject {
+                    path: to.clone(),
+                    detail: e.to_string(),
+                }),
+                Err(e) => Err(FailedObject {
+                    path: from.clone(),
+                    detail: e.to_string(),
+                }),
+            };
+
+            if result.is_ok() {
+                debug!(from = %from, to = %to, "chunk copied");
+            }
+
+            pb.inc(1);
+            if let Some(progress) = &json_progress {
+                progress.inc_by(1);
+            }
+
+            result
+        });
+    }
+
+    let mut failures = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(failure)) => failures.push(failure),
+            Err(e) => failures.push(FailedObject {
+                path: String::new(),
+                detail: e.to_string(),
+            }),
+        }
+    }
+
+    if let Some(progress) = &json_progress {
+        progress.finish();
+    }
+    pb.finish_and_clear();
+
+    failures.sort_by(|a, b| a.path.cmp(&b.path));
+    failures
+}
+
+async fn remove_objects(
+    fs: &Arc<dyn FS>,
+    paths: Vec<String>,
+    concurrency: usize,
+) -> Vec<FailedObject> {
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let mut tasks = JoinSet::new();
+
+    for path in paths {
+        let fs = Arc::clone(fs);
+        let semaphore = Arc::clone(&semaphore);
+
+        tasks.spawn(async move {
+            let _permit = semaphore.acquire().await.expect("Semaphore closed");
+
+            match fs.delete_file(&path).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(FailedObject {
+                    path,
+                    detail: e.to_string(),
+                }),
+            }
+        });
+    }
+
+    let mut failures = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(failure)) => failures.push(failure),
+            Err(e) => failures.push(FailedObject {
+                path: String::new(),
+                detail: e.to_string(),
+            }),
+        }
+    }
+
+    failures.sort_by(|a, b| a.path.cmp(&b.path));
+    failures
+}
+
+fn get_params(matches: &ArgMatches) -> Result<(String, String), String> {
+    let pwd_string = get_pwd_string();
+
+    let folder_key = Path::new(&pwd_string)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    Ok((key, storage))
+}
@@ -1,9 +1,11 @@
 pub mod add;
 mod list;
+mod migrate_layout;
 mod prune;
 mod remove;
 
 pub use add::add;
 pub use list::list;
+pub use migrate_layout::migrate_layout;
 pub use prune::prune;
 pub use remove::remove;
@@ -1,347 +1,1208 @@
-use std::sync::{Arc, Mutex};
-
-use crate::core::crypto::get_password;
-use crate::core::indexes::load_chunk_indexes;
-use crate::output::{JsonProgress, emit_output, emit_progress_message, is_json_mode};
-use crate::utils::{get_fs, get_pwd_string, get_storage, handle_error};
-use clap::ArgMatches;
-use dialoguer::Select;
-use dirs::home_dir;
-use futures::stream::{self, StreamExt};
-use indicatif::{ProgressBar, ProgressStyle};
-use std::path::Path;
-use std::time::{Duration, Instant};
-use tokio::sync::{Mutex as TokioMutex, Semaphore};
-use tokio::task::JoinSet;
-
-const MAX_CONCURRENT_CHUNKS: usize = 100;
-
-pub async fn prune(matches: &ArgMatches) {
-    let (key, storage, password) = match get_params(matches) {
-        Ok(params) => params,
-        Err(e) => handle_error(e, None),
-    };
-
-    let started_at = Instant::now();
-    let auto_confirm = matches.get_flag("yes");
-
-    let storage = get_storage(&storage);
-
-    let fs = get_fs(&storage, None);
-
-    let pb = if is_json_mode() {
-        ProgressBar::hidden()
-    } else {
-        let pb = ProgressBar::new(100);
-        pb.enable_steady_tick(Duration::from_millis(100));
-        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
-        pb.set_message("Loading chunk indexes...");
-        pb
-    };
-
-    if is_json_mode() {
-        emit_progress_message("Loading chunk indexes...");
-    }
-
-    let chunk_indexes = match load_chunk_indexes(
-        Arc::clone(&fs),
-        key.clone(),
-        password.clone(),
-        Arc::new(Mutex::new(false)),
-    )
-    .await
-    {
-        Ok(chunk_indexes) => chunk_indexes,
-        Err(e) => handle_error(e, Some(&pb)),
-    };
-
-    pb.set_message("Loading all chunks in the repository...");
-    if is_json_mode() {
-        emit_progress_message("Loading all chunks in the repository...");
-    }
-
-    let chunks_folder = format!("{}/chunks", key);
-    let indexes_folder = format!("{}/indexes", key);
-
-    let chunks = match fs.list_files(&chunks_folder).await {
-        Ok(chunks) => chunks,
-        Err(e) => handle_error(e.to_string(), Some(&pb)),
-    };
-
-    let pending_backups = match fs.list_files(&indexes_folder).await {
-        Ok(indexes) => indexes
-            .iter()
-            .filter(|index| {
-                let last_part: &str = index.split('/').last().unwrap_or(&"");
-
-                last_part.starts_with("pending_")
-            })
-            .cloned()
-            .collect::<Vec<String>>(),
-        Err(e) => handle_error(e.to_string(), Some(&pb)),
-    };
-
-    let items_to_prune = {
-        let mut items_to_prune = chunks
-            .iter()
-            .filter(|chunk| {
-                let parts: Vec<&str> = chunk.split('/').collect();
-                let key = if parts.len() >= 2 {
-                    format!("{}{}", parts[parts.len() - 2], parts[parts.len() - 1])
-                } else {
-                    chunk.to_string()
-                };
-
-                !chunk_indexes.contains_key(&key)
-            })
-            .cloned()
-            .collect::<Vec<String>>();
-
-        items_to_prune.extend(pending_backups);
-
-        items_to_prune
-    };
-
-    pb.finish_and_clear();
-
-    if items_to_prune.is_empty() {
-        if is_json_mode() {
-            #[derive(serde::Serialize)]
-            struct PruneOutput {
-                deleted_items: usize,
-                elapsed_ms: u64,
-            }
-
-            let payload = PruneOutput {
-                deleted_items: 0,
-                elapsed_ms: started_at.elapsed().as_millis() as u64,
-            };
-            emit_output(&payload);
-        } else {
-            println!("No chunks to prune");
-        }
-        return;
-    }
-
-    if is_json_mode() && !auto_confirm {
-        handle_error(
-            "Confirmation required in --mode json. Re-run with --yes to delete unused chunks."
-                .to_string(),
-            None,
-        );
-    }
-
-    let confirm = if auto_confirm {
-        true
-    } else {
-        dialoguer::Confirm::new()
-            .with_prompt(format!(
-                "Seems like you have {} items to prune (not used chunks and pending backups). Are you sure you want to DELETE them?",
-                items_to_prune.len()
-            ))
-            .interact()
-            .unwrap_or_else(|e| handle_error(format!("Error: {}", e), None))
-    };
-
-    if !confirm {
-        if is_json_mode() {
-            #[derive(serde::Serialize)]
-            struct PruneOutput {
-                deleted_items: usize,
-                aborted: bool,
-            }
-
-            let payload = PruneOutput {
-                deleted_items: 0,
-                aborted: true,
-            };
-            emit_output(&payload);
-        } else {
-            println!("Aborting...");
-        }
-        return;
-    }
-
-    let json_progress = if is_json_mode() {
-        let progress = JsonProgress::new(items_to_prune.len() as u64);
-        progress.set_message("Deleting chunks...");
-        Some(progress)
-    } else {
-        None
-    };
-
-    let pb = if is_json_mode() {
-        ProgressBar::hidden()
-    } else {
-        let pb = ProgressBar::new(items_to_prune.len() as u64);
-        pb.enable_steady_tick(Duration::from_millis(100));
-        pb.set_style(
-            ProgressStyle::with_template(
-                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
-            )
-            .unwrap(),
-        );
-        pb.set_message("Deleting chunks...");
-        pb
-    };
-
-    let chunks_set = Arc::new(TokioMutex::new(JoinSet::new()));
-    let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_CHUNKS));
-
-    let chunks_stream = stream::iter(&items_to_prune);
-
-    chunks_stream
-        .for_each_concurrent(MAX_CONCURRENT_CHUNKS, |chunk| {
-            let pb_clone = pb.clone();
-            let fs_clone = Arc::clone(&fs);
-            let chunk_clone = chunk.clone();
-            let semaphore_clone = Arc::clone(&semaphore);
-            let chunks_set_clone = Arc::clone(&chunks_set);
-            let json_progress_clone = json_progress.clone();
-
-            async move {
-                let mut guard = chunks_set_clone.lock().await;
-                guard.spawn(async move {
-                    let _permit = semaphore_clone.acquire().await.expect("Semaphore closed");
-                    let _ = fs_clone.delete_file(&chunk_clone).await;
-                    if let Some(progress) = &json_progress_clone {
-                        progress.inc_by(1);
-                    } else {
-                        pb_clone.inc(1);
-                    }
-                    Ok(())
-                });
-            }
-        })
-        .await;
-
-    let mut failed_chunks = Vec::new();
-
-    {
-        let mut guard = chunks_set.lock().await;
-        while let Some(file_process_result) = guard.join_next().await {
-            match file_process_result {
-                Ok(Ok(_)) => {}
-                Ok(Err(e)) => failed_chunks.push(e),
-                Err(e) => failed_chunks.push(e.to_string()),
-            }
-        }
-    }
-
-    if !failed_chunks.is_empty() {
-        handle_error(
-            format!(
-                "Failed to process {} files:\n{}",
-                failed_chunks.len(),
-                failed_chunks
-                    .iter()
-                    .map(|f| format!("  - {}", f))
-                    .collect::<Vec<String>>()
-                    .join("\n")
-            ),
-            Some(&pb),
-        );
-    }
-
-    if is_json_mode() {
-        #[derive(serde::Serialize)]
-        struct PruneOutput {
-            deleted_items: usize,
-            elapsed_ms: u64,
-        }
-
-        let payload = PruneOutput {
-            deleted_items: items_to_prune.len(),
-            elapsed_ms: started_at.elapsed().as_millis() as u64,
-        };
-        emit_output(&payload);
-    } else {
-        let elapsed = pb.elapsed();
-        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
-        pb.set_prefix("OK");
-        pb.finish_with_message(format!(
-            "Deleted {} items ({:.2?})",
-            items_to_prune.len(),
-            elapsed,
-        ));
-    }
-}
-
-fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>), String> {
-    let password: Option<String> = matches
-        .get_one::<String>("password")
-        .map(|s| s.to_string())
-        .map_or_else(
-            || get_password(false, true),
-            |password| Some(password.to_string()),
-        );
-
-    let pwd_string = get_pwd_string();
-
-    let default_key = Path::new(&pwd_string)
-        .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .to_string();
-
-    let key = matches
-        .get_one::<String>("key")
-        .map_or_else(|| default_key, |key| key.to_string());
-
-    let home_dir = home_dir().unwrap();
-    let storage_path = home_dir.join(".gib").join("storages");
-
-    if !storage_path.exists() {
-        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
-    }
-
-    let files =
-        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
-
-    let storages_names = &files
-        .map(|file| {
-            file.map_err(|e| format!("Failed to read storage entry: {}", e))
-                .map(|file| {
-                    file.file_name()
-                        .to_string_lossy()
-                        .to_string()
-                        .split('.')
-                        .next()
-                        .unwrap()
-                        .to_string()
-                })
-        })
-        .collect::<Result<Vec<String>, String>>()?;
-
-    if storages_names.is_empty() {
-        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
-    }
-
-    let storage = match matches.get_one::<String>("storage") {
-        Some(storage) => storage.to_string(),
-        None => {
-            if is_json_mode() {
-                return Err(
-                    "Missing required argument: --storage (required in --mode json)".to_string(),
-                );
-            }
-            let selected_index = Select::new()
-                .with_prompt("Select the storage to use")
-                .items(storages_names)
-                .default(0)
-                .interact()
-                .map_err(|e| format!("{}", e))?;
-            storages_names[selected_index].clone()
-        }
-    };
-
-    let exists = storages_names
-        .iter()
-        .any(|storage_name| storage_name == &storage);
-
-    if !exists {
-        return Err(format!("Storage '{}' not found", storage));
-    }
-
-    Ok((key, storage, password))
-}
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::commands::config::{default_key, default_storage};
+use crate::core::crypto::{get_password, read_file_maybe_decrypt};
+use crate::core::indexes::{
+    list_backup_summaries, load_chunk_indexes, load_repository_meta, read_manifest,
+};
+use crate::core::layout::{chunk_hash_from_path, chunk_path};
+use crate::core::metadata::{ChunkIndex, PendingBackup, TrashEntry};
+use crate::core::trash::copy_to_trash;
+use crate::fs::FS;
+use crate::output::{
+    JsonProgress, can_prompt, default_progress_interval, emit_output, emit_progress_message,
+    emit_progress_update, emit_warning, is_json_mode,
+};
+use crate::utils::{
+    decompress_bytes, get_fs, get_pwd_string, get_storage, handle_error, parse_duration,
+};
+use chrono::{DateTime, Local, Utc};
+use clap::ArgMatches;
+use console::style;
+use dialoguer::Select;
+use dirs::home_dir;
+use futures::future;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex as TokioMutex, Semaphore};
+use tokio::task::JoinSet;
+
+const MAX_CONCURRENT_CHUNKS: usize = 100;
+const MAX_CONCURRENT_LISTS: usize = 32;
+
+pub async fn prune(matches: &ArgMatches) {
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let started_at = Instant::now();
+    let auto_confirm = matches.get_flag("yes");
+    let parallel_list = matches.get_flag("parallel-list");
+
+    let concurrency = match matches.get_one::<String>("concurrency") {
+        Some(concurrency) => match concurrency.parse::<usize>() {
+            Ok(concurrency) if concurrency > 0 => concurrency,
+            _ => handle_error(format!("Invalid concurrency: '{}'", concurrency), None),
+        },
+        None => MAX_CONCURRENT_CHUNKS,
+    };
+
+    let trash_retention = match matches.get_one::<String>("trash-retention") {
+        Some(retention) => match parse_duration(retention, "--trash-retention") {
+            Ok(retention) => Some(retention),
+            Err(e) => handle_error(e, None),
+        },
+        None => None,
+    };
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    if matches.get_flag("orphan-pending-only") {
+        let older_than = match matches.get_one::<String>("older-than") {
+            Some(age) => match parse_duration(age, "--older-than") {
+                Ok(age) => Some(age),
+                Err(e) => handle_error(e, None),
+            },
+            None => None,
+        };
+
+        let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+            Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+            Err(e) => handle_error(e, None),
+        };
+
+        prune_orphan_pending(
+            fs,
+            key,
+            password,
+            older_than,
+            auto_confirm,
+            chunk_dir_levels,
+            concurrency,
+        )
+        .await;
+        return;
+    }
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb.set_message("Loading chunk indexes...");
+        pb
+    };
+
+    if is_json_mode() {
+        emit_progress_message("Loading chunk indexes...");
+    }
+
+    let chunk_indexes = match load_chunk_indexes(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        Arc::new(Mutex::new(false)),
+    )
+    .await
+    {
+        Ok(chunk_indexes) => chunk_indexes,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    pb.set_message("Loading all chunks in the repository...");
+    if is_json_mode() {
+        emit_progress_message("Loading all chunks in the repository...");
+    }
+
+    let chunks_folder = format!("{}/chunks", key);
+    let indexes_folder = format!("{}/indexes", key);
+
+    let listing_started_at = Instant::now();
+    let listing_progress = ListingProgress::new(&pb, parallel_list);
+
+    let orphan_chunks = if parallel_list {
+        match list_orphan_chunks_parallel(
+            Arc::clone(&fs),
+            &chunks_folder,
+            &chunk_indexes,
+            &listing_progress,
+        )
+        .await
+        {
+            Ok(orphan_chunks) => orphan_chunks,
+            Err(e) => handle_error(e, Some(&pb)),
+        }
+    } else {
+        match collect_orphan_chunks(
+            fs.as_ref(),
+            &chunks_folder,
+            &chunk_indexes,
+            &listing_progress,
+        )
+        .await
+        {
+            Ok(orphan_chunks) => orphan_chunks,
+            Err(e) => handle_error(e, Some(&pb)),
+        }
+    };
+
+    listing_progress.report();
+
+    let pending_backups = match fs.list_files(&indexes_folder).await {
+        Ok(indexes) => indexes
+            .iter()
+            .filter(|index| {
+                let last_part: &str = index.split('/').last().unwrap_or(&"");
+
+                last_part.starts_with("pending_")
+            })
+            .cloned()
+            .collect::<Vec<String>>(),
+        Err(e) => handle_error(e.to_string(), Some(&pb)),
+    };
+
+    let listing_elapsed = listing_started_at.elapsed();
+
+    let orphan_chunk_count = orphan_chunks.len();
+
+    let items_to_prune = {
+        let mut items_to_prune = orphan_chunks;
+
+        items_to_prune.extend(pending_backups);
+
+        items_to_prune
+    };
+
+    pb.finish_and_clear();
+
+    if items_to_prune.is_empty() {
+        if is_json_mode() {
+            #[derive(serde::Serialize)]
+            struct PruneOutput {
+                deleted_items: usize,
+                listing_ms: u64,
+                elapsed_ms: u64,
+            }
+
+            let payload = PruneOutput {
+                deleted_items: 0,
+                listing_ms: listing_elapsed.as_millis() as u64,
+                elapsed_ms: started_at.elapsed().as_millis() as u64,
+            };
+            emit_output(&payload);
+        } else {
+            println!("No chunks to prune (listing took {:.2?})", listing_elapsed);
+        }
+        return;
+    }
+
+    if matches.get_flag("dry-run") {
+        let explanations = if matches.get_flag("explain") {
+            Some(
+                explain_orphan_chunks(
+                    &fs,
+                    &key,
+                    &password,
+                    &items_to_prune[..orphan_chunk_count],
+                    &items_to_prune[orphan_chunk_count..],
+                    concurrency,
+                )
+                .await,
+            )
+        } else {
+            None
+        };
+
+        report_dry_run(
+            &items_to_prune,
+            orphan_chunk_count,
+            explanations,
+            listing_elapsed,
+            started_at,
+        );
+        return;
+    }
+
+    if is_json_mode() && !auto_confirm {
+        handle_error(
+            "Confirmation required in --mode json. Re-run with --yes to delete unused chunks."
+                .to_string(),
+            None,
+        );
+    }
+
+    let confirm = if auto_confirm {
+        true
+    } else if !can_prompt() {
+        println!("Confirmation required with --non-interactive. Re-run with --yes to delete.");
+        false
+    } else {
+        dialoguer::Confirm::new()
+            .with_prompt(format!(
+                "Seems like you have {} items to prune (not used chunks and pending backups). Are you sure you want to DELETE them?",
+                items_to_prune.len()
+            ))
+            .interact()
+            .unwrap_or_else(|e| handle_error(format!("Error: {}", e), None))
+    };
+
+    if !confirm {
+        if is_json_mode() {
+            #[derive(serde::Serialize)]
+            struct PruneOutput {
+                deleted_items: usize,
+                aborted: bool,
+            }
+
+            let payload = PruneOutput {
+                deleted_items: 0,
+                aborted: true,
+            };
+            emit_output(&payload);
+        } else {
+            println!("Aborting...");
+        }
+        return;
+    }
+
+    let trash_entry = match trash_retention {
+        Some(retention) => {
+            let pb = if is_json_mode() {
+                ProgressBar::hidden()
+            } else {
+                let pb = ProgressBar::new(100);
+                pb.enable_steady_tick(Duration::from_millis(100));
+                pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+                pb.set_message("Moving items to the trash...");
+                pb
+            };
+
+            if is_json_mode() {
+                emit_progress_message("Moving items to the trash...");
+            }
+
+            let key_prefix = format!("{}/", key);
+            let objects = items_to_prune
+                .iter()
+                .map(|item| item.trim_start_matches(&key_prefix).to_string())
+                .collect::<Vec<String>>();
+
+            let deleted_at = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+
+            let entry = TrashEntry::new(
+                format!("prune-{}", deleted_at),
+                "prune",
+                None,
+                objects,
+                retention,
+            );
+
+            if let Err(e) = copy_to_trash(&fs, &key, password.as_deref(), &entry).await {
+                handle_error(e, Some(&pb));
+            }
+
+            pb.finish_and_clear();
+
+            Some(entry)
+        }
+        None => None,
+    };
+
+    let json_progress = if is_json_mode() {
+        let progress = JsonProgress::new(items_to_prune.len() as u64);
+        progress.set_message("Deleting chunks...");
+        Some(progress)
+    } else {
+        None
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(items_to_prune.len() as u64);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap(),
+        );
+        pb.set_message("Deleting chunks...");
+        pb
+    };
+
+    let failed_chunks = delete_items(
+        Arc::clone(&fs),
+        &items_to_prune,
+        concurrency,
+        &pb,
+        &json_progress,
+    )
+    .await;
+
+    if !failed_chunks.is_empty() {
+        handle_error(
+            format!(
+                "Failed to process {} files:\n{}",
+                failed_chunks.len(),
+                failed_chunks
+                    .iter()
+                    .map(|f| format!("  - {}", f))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+            Some(&pb),
+        );
+    }
+
+    if is_json_mode() {
+        #[derive(serde::Serialize)]
+        struct PruneOutput {
+            deleted_items: usize,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            trash_entry: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            trash_expires_at: Option<u64>,
+            listing_ms: u64,
+            elapsed_ms: u64,
+        }
+
+        let payload = PruneOutput {
+            deleted_items: items_to_prune.len(),
+            trash_entry: trash_entry.as_ref().map(|entry| entry.id.clone()),
+            trash_expires_at: trash_entry.as_ref().map(|entry| entry.expires_at),
+            listing_ms: listing_elapsed.as_millis() as u64,
+            elapsed_ms: started_at.elapsed().as_millis() as u64,
+        };
+        emit_output(&payload);
+    } else {
+        let elapsed = pb.elapsed();
+        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
+        pb.set_prefix("OK");
+        pb.finish_with_message(format!(
+            "Deleted {} items ({:.2?}, listing took {:.2?})",
+            items_to_prune.len(),
+            elapsed,
+            listing_elapsed,
+        ));
+
+        if let Some(entry) = &trash_entry {
+            println!(
+                "{}",
+                style(format!(
+                    "Moved to the trash until {}. Run 'gib trash restore --entry {}' to undo.",
+                    DateTime::<Utc>::from_timestamp_secs(entry.expires_at as i64)
+                        .map(|dt| dt
+                            .with_timezone(&Local)
+                            .format("%Y-%m-%d %H:%M")
+                            .to_string())
+                        .unwrap_or_default(),
+                    entry.id
+                ))
+                .dim()
+            );
+        }
+    }
+}
+
+/// Why `--dry-run --explain` considers a chunk orphaned. It is never in the chunk index;
+/// `reason` tells what else was checked: `unreferenced` when no backup manifest or
+/// pending backup uses it, `pending_backup` when only pending backups (pruned along with
+/// it) do, and `referenced_by_backup` when a manifest still uses it, which means the
+/// chunk index drifted and the chunk must not be deleted.
+#[derive(serde::Serialize)]
+struct OrphanExplanation {
+    reason: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    backups: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pending: Vec<String>,
+}
+
+/// Checks every orphan chunk against all backup manifests (loaded `concurrency` at a
+/// time) and pending backups. Keys are chunk hashes, or the path of objects that are not
+/// chunks.
+async fn explain_orphan_chunks(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    orphan_chunks: &[String],
+    pending_paths: &[String],
+    concurrency: usize,
+) -> BTreeMap<String, OrphanExplanation> {
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb
+    };
+
+    let mut explanations = orphan_chunks
+        .iter()
+        .map(|chunk| {
+            (
+                chunk_hash_from_path(chunk).unwrap_or_else(|| chunk.clone()),
+                OrphanExplanation {
+                    reason: "unreferenced",
+                    backups: Vec::new(),
+                    pending: Vec::new(),
+                },
+            )
+        })
+        .collect::<BTreeMap<String, OrphanExplanation>>();
+
+    let summaries =
+        match list_backup_summaries(Arc::clone(fs), key.to_string(), password.clone()).await {
+            Ok(summaries) => summaries,
+            Err(e) => handle_error(e, Some(&pb)),
+        };
+
+    let mut backups_by_manifest = HashMap::<String, Vec<String>>::new();
+    for summary in &summaries {
+        backups_by_manifest
+            .entry(summary.manifest_hash().to_string())
+            .or_default()
+            .push(summary.hash[..8.min(summary.hash.len())].to_string());
+    }
+
+    let message = format!(
+        "Checking the orphaned chunks against {} backup manifests...",
+        backups_by_manifest.len()
+    );
+    pb.set_message(message.clone());
+    if is_json_mode() {
+        emit_progress_message(&message);
+    }
+
+    let manifests = stream::iter(backups_by_manifest.keys())
+        .map(|manifest_hash| async move {
+            read_manifest(fs, key, password, manifest_hash)
+                .await
+                .map(|backup| (manifest_hash, backup))
+        })
+        .buffer_unordered(concurrency);
+    futures::pin_mut!(manifests);
+
+    while let Some(result) = manifests.next().await {
+        let (manifest_hash, backup) = match result {
+            Ok(loaded) => loaded,
+            Err(e) => handle_error(e, Some(&pb)),
+        };
+
+        let Some(backup) = backup else {
+            continue;
+        };
+
+        let chunks = backup
+            .tree
+            .values()
+            .flat_map(|backup_object| backup_object.chunks.iter())
+            .collect::<HashSet<&String>>();
+
+        for chunk_hash in chunks {
+            if let Some(explanation) = explanations.get_mut(chunk_hash) {
+                explanation.reason = "referenced_by_backup";
+                explanation
+                    .backups
+                    .extend(backups_by_manifest[manifest_hash].iter().cloned());
+            }
+        }
+    }
+
+    let pending_prefix = format!("{}/indexes/pending_", key);
+
+    for pending_path in pending_paths {
+        let pending_backup =
+            match load_pending_backup(Arc::clone(fs), pending_path, password.as_deref()).await {
+                Ok(pending_backup) => pending_backup,
+                Err(e) => {
+                    pb.suspend(|| {
+                        emit_warning(
+                            &format!("Skipping pending backup '{}': {}", pending_path, e),
+                            "pending_unreadable",
+                        )
+                    });
+                    continue;
+                }
+            };
+
+        let pending_hash = pending_path.trim_start_matches(&pending_prefix);
+        for chunk_hash in &pending_backup.processed_chunks {
+            if let Some(explanation) = explanations.get_mut(chunk_hash) {
+                if explanation.reason == "unreferenced" {
+                    explanation.reason = "pending_backup";
+                }
+                explanation.pending.push(pending_hash.to_string());
+            }
+        }
+    }
+
+    pb.finish_and_clear();
+
+    for explanation in explanations.values_mut() {
+        explanation.backups.sort();
+        explanation.pending.sort();
+    }
+
+    explanations
+}
+
+fn report_dry_run(
+    items_to_prune: &[String],
+    orphan_chunk_count: usize,
+    explanations: Option<BTreeMap<String, OrphanExplanation>>,
+    listing_elapsed: Duration,
+    started_at: Instant,
+) {
+    let drifted = explanations
+        .iter()
+        .flat_map(|explanations| explanations.iter())
+        .filter(|(_, explanation)| explanation.reason == "referenced_by_backup")
+        .map(|(chunk, _)| chunk.clone())
+        .collect::<Vec<String>>();
+
+    if !drifted.is_empty() {
+        emit_warning(
+            &format!(
+                "{} chunks missing from the chunk index are still used by backups; the index drifted. Run 'gib reindex' before pruning, or these chunks will be deleted:\n{}",
+                drifted.len(),
+                drifted
+                    .iter()
+                    .map(|chunk| format!("  - {}", chunk))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+            "index_drift",
+        );
+    }
+
+    let pending_backups = items_to_prune[orphan_chunk_count..].to_vec();
+
+    if is_json_mode() {
+        #[derive(serde::Serialize)]
+        struct PruneDryRunOutput {
+            dry_run: bool,
+            orphan_chunks: usize,
+            pending_backups: Vec<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            explain: Option<BTreeMap<String, OrphanExplanation>>,
+            listing_ms: u64,
+            elapsed_ms: u64,
+        }
+
+        let payload = PruneDryRunOutput {
+            dry_run: true,
+            orphan_chunks: orphan_chunk_count,
+            pending_backups,
+            explain: explanations,
+            listing_ms: listing_elapsed.as_millis() as u64,
+            elapsed_ms: started_at.elapsed().as_millis() as u64,
+        };
+        emit_output(&payload);
+        return;
+    }
+
+    if let Some(explanations) = &explanations {
+        for (chunk, explanation) in explanations {
+            let justification = match explanation.reason {
+                "referenced_by_backup" => style(format!(
+                    "still used by backups {}",
+                    explanation.backups.join(", ")
+                ))
+                .red(),
+                "pending_backup" => style(format!(
+                    "only used by the pending backups {}",
+                    explanation.pending.join(", ")
+                ))
+                .dim(),
+                _ => style("not used by any backup".to_string()).dim(),
+            };
+            println!("  {} {}", chunk, justification);
+        }
+    }
+
+    for pending_path in &pending_backups {
+        println!("  {} {}", pending_path, style("pending backup").dim());
+    }
+
+    println!(
+        "Would delete {} orphaned chunks and {} pending backups (dry run, listing took {:.2?})",
+        orphan_chunk_count,
+        pending_backups.len(),
+        listing_elapsed
+    );
+}
+
+async fn delete_items(
+    fs: Arc<dyn FS>,
+    items: &[String],
+    concurrency: usize,
+    pb: &ProgressBar,
+    json_progress: &Option<Arc<JsonProgress>>,
+) -> Vec<String> {
+    let items_set = Arc::new(TokioMutex::new(JoinSet::new()));
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+
+    let items_stream = stream::iter(items);
+
+    items_stream
+        .for_each_concurrent(concurrency, |item| {
+            let pb_clone = pb.clone();
+            let fs_clone = Arc::clone(&fs);
+            let item_clone = item.clone();
+            let semaphore_clone = Arc::clone(&semaphore);
+            let items_set_clone = Arc::clone(&items_set);
+            let json_progress_clone = json_progress.clone();
+
+            async move {
+                let mut guard = items_set_clone.lock().await;
+                guard.spawn(async move {
+                    let _permit = semaphore_clone.acquire().await.expect("Semaphore closed");
+                    let _ = fs_clone.delete_file(&item_clone).await;
+                    if let Some(progress) = &json_progress_clone {
+                        progress.inc_by(1);
+                    } else {
+                        pb_clone.inc(1);
+                    }
+                    Ok::<(), String>(())
+                });
+            }
+        })
+        .await;
+
+    let mut failed_items = Vec::new();
+
+    {
+        let mut guard = items_set.lock().await;
+        while let Some(item_process_result) = guard.join_next().await {
+            match item_process_result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => failed_items.push(e),
+                Err(e) => failed_items.push(e.to_string()),
+            }
+        }
+    }
+
+    failed_items
+}
+
+/// Deletes abandoned `indexes/pending_*` files and the chunks only they reference: chunks
+/// that no live backup (the chunk index) or remaining pending backup uses. Pending files
+/// written before their creation time was recorded count as older than any `older_than`.
+async fn prune_orphan_pending(
+    fs: Arc<dyn FS>,
+    key: String,
+    password: Option<String>,
+    older_than: Option<Duration>,
+    auto_confirm: bool,
+    chunk_dir_levels: u8,
+    concurrency: usize,
+) {
+    let started_at = Instant::now();
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb.set_message("Loading pending backups...");
+        pb
+    };
+
+    if is_json_mode() {
+        emit_progress_message("Loading pending backups...");
+    }
+
+    let indexes_folder = format!("{}/indexes", key);
+    let pending_prefix = format!("{}/indexes/pending_", key);
+
+    let pending_paths = match fs.list_files(&indexes_folder).await {
+        Ok(indexes) => indexes
+            .into_iter()
+            .filter(|index| index.starts_with(&pending_prefix))
+            .collect::<Vec<String>>(),
+        Err(e) => handle_error(e.to_string(), Some(&pb)),
+    };
+
+    let mut pending_backups = Vec::new();
+
+    for pending_path in pending_paths {
+        match load_pending_backup(Arc::clone(&fs), &pending_path, password.as_deref()).await {
+            Ok(pending_backup) => pending_backups.push((pending_path, pending_backup)),
+            Err(e) => pb.suspend(|| {
+                emit_warning(
+                    &format!("Skipping pending backup '{}': {}", pending_path, e),
+                    "pending_unreadable",
+                )
+            }),
+        }
+    }
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let (selected, kept): (Vec<_>, Vec<_>) =
+        pending_backups
+            .into_iter()
+            .partition(
+                |(_, pending_backup)| match (older_than, pending_backup.created_at) {
+                    (Some(older_than), Some(created_at)) => {
+                        now.saturating_sub(created_at) >= older_than.as_secs()
+                    }
+                    _ => true,
+                },
+            );
+
+    pb.set_message("Loading chunk indexes...");
+    if is_json_mode() {
+        emit_progress_message("Loading chunk indexes...");
+    }
+
+    let chunk_indexes = match load_chunk_indexes(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        Arc::new(Mutex::new(false)),
+    )
+    .await
+    {
+        Ok(chunk_indexes) => chunk_indexes,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let kept_chunks = kept
+        .iter()
+        .flat_map(|(_, pending_backup)| pending_backup.processed_chunks.iter())
+        .collect::<HashSet<&String>>();
+
+    let orphan_chunks = selected
+        .iter()
+        .flat_map(|(_, pending_backup)| pending_backup.processed_chunks.iter())
+        .filter(|chunk_hash| {
+            !chunk_indexes.contains_key(*chunk_hash) && !kept_chunks.contains(chunk_hash)
+        })
+        .collect::<HashSet<&String>>()
+        .into_iter()
+        .map(|chunk_hash| chunk_path(&key, chunk_hash, chunk_dir_levels))
+        .collect::<Vec<String>>();
+
+    pb.finish_and_clear();
+
+    let pending_hashes = selected
+        .iter()
+        .map(|(path, _)| path.trim_start_matches(&pending_prefix).to_string())
+        .collect::<Vec<String>>();
+
+    let mut items_to_prune = orphan_chunks;
+    let deleted_chunks = items_to_prune.len();
+    items_to_prune.extend(selected.iter().map(|(path, _)| path.clone()));
+
+    #[derive(serde::Serialize)]
+    struct PrunePendingOutput {
+        deleted_pending: Vec<String>,
+        deleted_chunks: usize,
+        kept_pending: usize,
+        aborted: bool,
+        elapsed_ms: u64,
+    }
+
+    if items_to_prune.is_empty() {
+        if is_json_mode() {
+            emit_output(&PrunePendingOutput {
+                deleted_pending: Vec::new(),
+                deleted_chunks: 0,
+                kept_pending: kept.len(),
+                aborted: false,
+                elapsed_ms: started_at.elapsed().as_millis() as u64,
+            });
+        } else {
+            println!("No pending backups to prune");
+        }
+        return;
+    }
+
+    if is_json_mode() && !auto_confirm {
+        handle_error(
+            "Confirmation required in --mode json. Re-run with --yes to delete pending backups."
+                .to_string(),
+            None,
+        );
+    }
+
+    let confirm = if auto_confirm {
+        true
+    } else if !can_prompt() {
+        println!("Confirmation required with --non-interactive. Re-run with --yes to delete.");
+        false
+    } else {
+        dialoguer::Confirm::new()
+            .with_prompt(format!(
+                "Seems like you have {} pending backups and {} chunks only they use. Are you sure you want to DELETE them?",
+                pending_hashes.len(),
+                deleted_chunks
+            ))
+            .interact()
+            .unwrap_or_else(|e| handle_error(format!("Error: {}", e), None))
+    };
+
+    if !confirm {
+        if is_json_mode() {
+            emit_output(&PrunePendingOutput {
+                deleted_pending: Vec::new(),
+                deleted_chunks: 0,
+                kept_pending: kept.len() + pending_hashes.len(),
+                aborted: true,
+                elapsed_ms: started_at.elapsed().as_millis() as u64,
+            });
+        } else {
+            println!("Aborting...");
+        }
+        return;
+    }
+
+    let json_progress = if is_json_mode() {
+        let progress = JsonProgress::new(items_to_prune.len() as u64);
+        progress.set_message("Deleting pending backups...");
+        Some(progress)
+    } else {
+        None
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(items_to_prune.len() as u64);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap(),
+        );
+        pb.set_message("Deleting pending backups...");
+        pb
+    };
+
+    // Chunks go first so an interrupted run leaves the pending files to retry with.
+    let mut failed_items = delete_items(
+        Arc::clone(&fs),
+        &items_to_prune[..deleted_chunks],
+        concurrency,
+        &pb,
+        &json_progress,
+    )
+    .await;
+    failed_items.extend(
+        delete_items(
+            Arc::clone(&fs),
+            &items_to_prune[deleted_chunks..],
+            concurrency,
+            &pb,
+            &json_progress,
+        )
+        .await,
+    );
+
+    if !failed_items.is_empty() {
+        handle_error(
+            format!(
+                "Failed to process {} files:\n{}",
+                failed_items.len(),
+                failed_items
+                    .iter()
+                    .map(|f| format!("  - {}", f))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+            Some(&pb),
+        );
+    }
+
+    if is_json_mode() {
+        emit_output(&PrunePendingOutput {
+            deleted_pending: pending_hashes,
+            deleted_chunks,
+            kept_pending: kept.len(),
+            aborted: false,
+            elapsed_ms: started_at.elapsed().as_millis() as u64,
+        });
+    } else {
+        let elapsed = pb.elapsed();
+        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
+        pb.set_prefix("OK");
+        pb.finish_with_message(format!(
+            "Deleted {} pending backups and {} chunks ({:.2?})",
+            pending_hashes.len(),
+            deleted_chunks,
+            elapsed,
+        ));
+    }
+}
+
+async fn load_pending_backup(
+    fs: Arc<dyn FS>,
+    pending_path: &str,
+    password: Option<&str>,
+) -> Result<PendingBackup, String> {
+    let pending_result = read_file_maybe_decrypt(
+        &fs,
+        pending_path,
+        password,
+        "Pending backup is encrypted but no password provided",
+    )
+    .await?;
+
+    if pending_result.bytes.is_empty() {
+        return Err("the file is empty".to_string());
+    }
+
+    let decompressed_bytes = decompress_bytes(&pending_result.bytes)
+        .map_err(|e| format!("Failed to decompress pending backup: {}", e))?;
+
+    rmp_serde::from_slice(&decompressed_bytes)
+        .map_err(|e| format!("Failed to deserialize pending backup: {}", e))
+}
+
+fn is_orphan_chunk(chunk: &str, chunk_indexes: &HashMap<String, ChunkIndex>) -> bool {
+    let chunk_hash = chunk_hash_from_path(chunk).unwrap_or_else(|| chunk.to_string());
+
+    !chunk_indexes.contains_key(&chunk_hash)
+}
+
+/// Running counts of the listing phase, so a long listing shows that it is moving. The
+/// spinner message and JSON progress events are refreshed at most every progress interval.
+struct ListingProgress {
+    pb: ProgressBar,
+    parallel: bool,
+    listed: AtomicU64,
+    candidates: AtomicU64,
+    listed_prefixes: AtomicU64,
+    started_at: Instant,
+    last_report_ms: AtomicU64,
+}
+
+impl ListingProgress {
+    fn new(pb: &ProgressBar, parallel: bool) -> Self {
+        ListingProgress {
+            pb: pb.clone(),
+            parallel,
+            listed: AtomicU64::new(0),
+            candidates: AtomicU64::new(0),
+            listed_prefixes: AtomicU64::new(0),
+            started_at: Instant::now(),
+            last_report_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn record_chunk(&self, is_orphan: bool) {
+        self.listed.fetch_add(1, Ordering::Relaxed);
+        if is_orphan {
+            self.candidates.fetch_add(1, Ordering::Relaxed);
+        }
+        self.report_if_due();
+    }
+
+    fn record_prefix(&self) {
+        self.listed_prefixes.fetch_add(1, Ordering::Relaxed);
+        self.report_if_due();
+    }
+
+    fn report_if_due(&self) {
+        let elapsed_ms = self.started_at.elapsed().as_millis() as u64;
+        let last_report_ms = self.last_report_ms.load(Ordering::Relaxed);
+
+        if elapsed_ms.saturating_sub(last_report_ms)
+            < default_progress_interval().as_millis() as u64
+        {
+            return;
+        }
+
+        if self
+            .last_report_ms
+            .compare_exchange(
+                last_report_ms,
+                elapsed_ms,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        let listed = self.listed.load(Ordering::Relaxed);
+        let candidates = self.candidates.load(Ordering::Relaxed);
+
+        let message = if self.parallel {
+            format!(
+                "Loading all chunks in the repository ({}/256 prefixes, {} chunks listed, {} to prune)...",
+                self.listed_prefixes.load(Ordering::Relaxed),
+                listed,
+                candidates
+            )
+        } else {
+            format!(
+                "Loading all chunks in the repository ({} chunks listed, {} to prune)...",
+                listed, candidates
+            )
+        };
+
+        if is_json_mode() {
+            emit_progress_update(listed, 0, Some(message));
+        } else {
+            self.pb.set_message(message);
+        }
+    }
+}
+
+/// Streams the listing of `folder` and keeps only orphan chunks, so memory grows with the
+/// number of chunks to delete rather than with the size of the repository.
+async fn collect_orphan_chunks(
+    fs: &dyn FS,
+    folder: &str,
+    chunk_indexes: &HashMap<String, ChunkIndex>,
+    progress: &ListingProgress,
+) -> Result<Vec<String>, String> {
+    fs.list_files_stream(folder)
+        .map_err(|e| format!("Failed to list '{}': {}", folder, e))
+        .try_filter(|chunk| {
+            let is_orphan = is_orphan_chunk(chunk, chunk_indexes);
+            progress.record_chunk(is_orphan);
+            future::ready(is_orphan)
+        })
+        .try_collect()
+        .await
+}
+
+/// Lists each of the 256 `chunks/<prefix>/` folders concurrently and keeps only the
+/// orphan chunks of every listing, instead of paginating the whole chunk set serially.
+async fn list_orphan_chunks_parallel(
+    fs: Arc<dyn FS>,
+    chunks_folder: &str,
+    chunk_indexes: &HashMap<String, ChunkIndex>,
+    progress: &ListingProgress,
+) -> Result<Vec<String>, String> {
+    let mut listings = stream::iter(0..=255u8)
+        .map(|prefix| {
+            let fs_clone = Arc::clone(&fs);
+            let prefix_folder = format!("{}/{:02x}", chunks_folder, prefix);
+
+            async move {
+                collect_orphan_chunks(fs_clone.as_ref(), &prefix_folder, chunk_indexes, progress)
+                    .await
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_LISTS);
+
+    let mut orphan_chunks = Vec::new();
+
+    while let Some(listing) = listings.next().await {
+        orphan_chunks.extend(listing?);
+        progress.record_prefix();
+    }
+
+    Ok(orphan_chunks)
+}
+
+fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>), String> {
+    let password: Option<String> = matches
+        .get_one::<String>("password")
+        .map(|s| s.to_string())
+        .map_or_else(
+            || get_password(false, true),
+            |password| Some(password.to_string()),
+        );
+
+    let pwd_string = get_pwd_string();
+
+    let folder_key = Path::new(&pwd_string)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .to_string()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    Ok((key, storage, password))
+}
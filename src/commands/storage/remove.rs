@@ -4,7 +4,7 @@ use dirs::home_dir;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::time::Duration;
 
-use crate::output::{JsonProgress, emit_output, is_json_mode};
+use crate::output::{JsonProgress, can_prompt, emit_output, is_json_mode};
 use crate::utils::handle_error;
 
 pub fn remove(matches: &ArgMatches) {
@@ -39,9 +39,9 @@ pub fn remove(matches: &ArgMatches) {
 
     let name = matches.get_one::<String>("name").map_or_else(
         || {
-            if is_json_mode() {
+            if !can_prompt() {
                 handle_error(
-                    "Missing required argument: --name (required in --mode json)".to_string(),
+                    "Missing required argument: --name (required in --mode json or with --non-interactive)".to_string(),
                     None,
                 );
             }
@@ -0,0 +1,127 @@
+use super::{describe_entry, get_params};
+use crate::core::trash::{list_trash, purge_trash_entry};
+use crate::output::{can_prompt, emit_output, is_json_mode};
+use crate::utils::{get_fs, get_storage, handle_error};
+use clap::ArgMatches;
+use console::style;
+use std::sync::Arc;
+use std::time::Instant;
+
+pub async fn empty(matches: &ArgMatches) {
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let started_at = Instant::now();
+    let all = matches.get_flag("all");
+    let auto_confirm = matches.get_flag("yes");
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    let entries = match list_trash(Arc::clone(&fs), key.clone(), password).await {
+        Ok(entries) => entries,
+        Err(e) => handle_error(e, None),
+    };
+
+    let entries = entries
+        .into_iter()
+        .filter(|entry| all || entry.is_expired())
+        .collect::<Vec<_>>();
+
+    // Expired entries are past their retention, so only emptying everything asks first.
+    if all && !entries.is_empty() {
+        if is_json_mode() && !auto_confirm {
+            handle_error(
+                "Confirmation required in --mode json. Re-run with --yes to empty the trash."
+                    .to_string(),
+                None,
+            );
+        }
+
+        let confirm = if auto_confirm {
+            true
+        } else if !can_prompt() {
+            println!(
+                "Confirmation required with --non-interactive. Re-run with --yes to empty the trash."
+            );
+            false
+        } else {
+            dialoguer::Confirm::new()
+                .with_prompt(format!(
+                    "Permanently DELETE {} trash entries, including the ones that have not expired?",
+                    entries.len()
+                ))
+                .interact()
+                .unwrap_or_else(|e| handle_error(format!("Error: {}", e), None))
+        };
+
+        if !confirm {
+            if is_json_mode() {
+                #[derive(serde::Serialize)]
+                struct TrashEmptyOutput {
+                    purged_entries: usize,
+                    aborted: bool,
+                }
+
+                let payload = TrashEmptyOutput {
+                    purged_entries: 0,
+                    aborted: true,
+                };
+                emit_output(&payload);
+            } else {
+                println!("Aborting...");
+            }
+            return;
+        }
+    }
+
+    let mut purged_objects = 0;
+
+    for entry in &entries {
+        if let Err(e) = purge_trash_entry(&fs, &key, entry).await {
+            handle_error(e, None);
+        }
+
+        purged_objects += entry.objects.len();
+
+        if !is_json_mode() {
+            println!("Purged {}", describe_entry(entry));
+        }
+    }
+
+    if is_json_mode() {
+        #[derive(serde::Serialize)]
+        struct TrashEmptyOutput {
+            purged_entries: usize,
+            purged_objects: usize,
+            elapsed_ms: u64,
+        }
+
+        let payload = TrashEmptyOutput {
+            purged_entries: entries.len(),
+            purged_objects,
+            elapsed_ms: started_at.elapsed().as_millis() as u64,
+        };
+        emit_output(&payload);
+    } else if entries.is_empty() {
+        println!(
+            "{}",
+            style(if all {
+                "The trash is empty."
+            } else {
+                "No expired trash entries."
+            })
+            .yellow()
+        );
+    } else {
+        println!(
+            "{} {} trash entries ({} objects)",
+            style("OK").green(),
+            entries.len(),
+            purged_objects
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use super::{describe_entry, format_date, get_params};
+use crate::core::trash::list_trash;
+use crate::output::{emit_output, is_json_mode};
+use crate::utils::{get_fs, get_storage, handle_error};
+use clap::ArgMatches;
+use console::style;
+use tabled::{Table, Tabled};
+
+#[derive(Tabled)]
+struct TrashRow {
+    id: String,
+    contents: String,
+    objects: usize,
+    deleted: String,
+    expires: String,
+}
+
+pub async fn list(matches: &ArgMatches) {
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    let entries = match list_trash(fs, key, password).await {
+        Ok(entries) => entries,
+        Err(e) => handle_error(e, None),
+    };
+
+    if is_json_mode() {
+        let json_rows = entries
+            .iter()
+            .map(|entry| TrashInfo {
+                id: entry.id.clone(),
+                kind: entry.kind.clone(),
+                backup: entry.backup.as_ref().map(|summary| summary.hash.clone()),
+                message: entry.backup.as_ref().map(|summary| summary.message.clone()),
+                objects: entry.objects.len(),
+                deleted_at: entry.deleted_at,
+                expires_at: entry.expires_at,
+                expired: entry.is_expired(),
+            })
+            .collect::<Vec<TrashInfo>>();
+        emit_output(&json_rows);
+        return;
+    }
+
+    if entries.is_empty() {
+        println!("{}", style("The trash is empty.").yellow());
+        return;
+    }
+
+    let rows = entries
+        .iter()
+        .map(|entry| TrashRow {
+            id: if entry.backup.is_some() {
+                entry.id[..8.min(entry.id.len())].to_string()
+            } else {
+                entry.id.clone()
+            },
+            contents: match entry.backup {
+                Some(_) => describe_entry(entry),
+                None => "pruned chunks and pending backups".to_string(),
+            },
+            objects: entry.objects.len(),
+            deleted: format_date(entry.deleted_at),
+            expires: if entry.is_expired() {
+                format!("{} (expired)", format_date(entry.expires_at))
+            } else {
+                format_date(entry.expires_at)
+            },
+        })
+        .collect::<Vec<TrashRow>>();
+
+    let table = Table::new(rows).to_string();
+    println!("{table}");
+}
+
+#[derive(serde::Serialize)]
+struct TrashInfo {
+    id: String,
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    objects: usize,
+    deleted_at: u64,
+    expires_at: u64,
+    expired: bool,
+}
@@ -0,0 +1,281 @@
+use super::{describe_entry, format_date, get_params};
+use crate::core::indexes::{
+    list_backup_summaries, load_chunk_indexes, load_repository_meta, load_summary_backup,
+    save_backup_summaries, save_chunk_indexes,
+};
+use crate::core::metadata::{BackupSummary, ChunkIndex, TrashEntry};
+use crate::core::trash::{copy_from_trash, list_trash, purge_trash_entry};
+use crate::fs::FS;
+use crate::output::{can_prompt, emit_output, emit_progress_message, emit_warning, is_json_mode};
+use crate::utils::{get_fs, get_storage, handle_error};
+use clap::ArgMatches;
+use dialoguer::Select;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub async fn restore(matches: &ArgMatches) {
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let started_at = Instant::now();
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    let entries = match list_trash(Arc::clone(&fs), key.clone(), password.clone()).await {
+        Ok(entries) => entries,
+        Err(e) => handle_error(e, None),
+    };
+
+    let entry = match select_entry(matches, entries) {
+        Ok(entry) => entry,
+        Err(e) => handle_error(e, None),
+    };
+
+    let chunk_dir_levels = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default().chunk_dir_levels,
+        Err(e) => handle_error(e, None),
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb
+    };
+
+    // The backup is checked before anything is copied back, so a refused restore leaves
+    // both the repository and the trash as they were.
+    let mut backup_summaries = match &entry.backup {
+        Some(summary) => {
+            let backup_summaries =
+                match list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await {
+                    Ok(backup_summaries) => backup_summaries,
+                    Err(e) => handle_error(e, Some(&pb)),
+                };
+
+            if backup_summaries
+                .iter()
+                .any(|existing| existing.hash == summary.hash)
+            {
+                handle_error(
+                    format!(
+                        "Backup {} already exists in the repository",
+                        &summary.hash[..8.min(summary.hash.len())]
+                    ),
+                    Some(&pb),
+                );
+            }
+
+            if let Some(base) = &summary.base
+                && !backup_summaries
+                    .iter()
+                    .any(|existing| &existing.hash == base)
+            {
+                handle_error(
+                    format!(
+                        "Backup {} is differential and its base {} was deleted too. Restore the base from the trash first.",
+                        &summary.hash[..8.min(summary.hash.len())],
+                        &base[..8.min(base.len())]
+                    ),
+                    Some(&pb),
+                );
+            }
+
+            Some(backup_summaries)
+        }
+        None => None,
+    };
+
+    pb.set_message(format!(
+        "Copying {} objects back from the trash...",
+        entry.objects.len()
+    ));
+    if is_json_mode() {
+        emit_progress_message("Copying objects back from the trash...");
+    }
+
+    if let Err(e) = copy_from_trash(&fs, &key, &entry, chunk_dir_levels).await {
+        handle_error(e, Some(&pb));
+    }
+
+    if let (Some(summary), Some(backup_summaries)) = (&entry.backup, backup_summaries.as_mut()) {
+        pb.set_message("Writing updated indexes...");
+        if is_json_mode() {
+            emit_progress_message("Writing updated indexes...");
+        }
+
+        if let Err(e) = relink_chunks(&fs, &key, &password, summary).await {
+            handle_error(e, Some(&pb));
+        }
+
+        // Summaries are newest first; the restored one goes back where it was.
+        let position = backup_summaries
+            .iter()
+            .position(|existing| existing.timestamp <= summary.timestamp)
+            .unwrap_or(backup_summaries.len());
+        backup_summaries.insert(position, summary.clone());
+
+        if let Err(e) = save_backup_summaries(
+            Arc::clone(&fs),
+            key.clone(),
+            backup_summaries,
+            password.clone(),
+        )
+        .await
+        {
+            handle_error(e, Some(&pb));
+        }
+    }
+
+    pb.set_message("Removing the trash entry...");
+    let purge_warning = purge_trash_entry(&fs, &key, &entry).await.err();
+
+    pb.finish_and_clear();
+
+    if let Some(warning) = &purge_warning {
+        emit_warning(
+            &format!(
+                "The restore completed, but the trash entry was left behind: {}",
+                warning
+            ),
+            "trash_purge_failed",
+        );
+    }
+
+    if is_json_mode() {
+        #[derive(serde::Serialize)]
+        struct TrashRestoreOutput {
+            entry: String,
+            kind: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            backup: Option<String>,
+            restored_objects: usize,
+            elapsed_ms: u64,
+        }
+
+        let payload = TrashRestoreOutput {
+            entry: entry.id.clone(),
+            kind: entry.kind.clone(),
+            backup: entry.backup.as_ref().map(|summary| summary.hash.clone()),
+            restored_objects: entry.objects.len(),
+            elapsed_ms: started_at.elapsed().as_millis() as u64,
+        };
+        emit_output(&payload);
+    } else {
+        println!(
+            "Restored {} ({} objects, deleted {})",
+            describe_entry(&entry),
+            entry.objects.len(),
+            format_date(entry.deleted_at)
+        );
+    }
+}
+
+/// Adds the references of the restored backup back to the chunk indexes. Chunks whose
+/// refcount dropped to zero were removed from the indexes on delete and come back with
+/// the copied objects.
+async fn relink_chunks(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    summary: &BackupSummary,
+) -> Result<(), String> {
+    let backup =
+        load_summary_backup(Arc::clone(fs), key.to_string(), password.clone(), summary).await?;
+
+    let mut chunk_indexes = load_chunk_indexes(
+        Arc::clone(fs),
+        key.to_string(),
+        password.clone(),
+        Arc::new(Mutex::new(false)),
+    )
+    .await?;
+
+    for backup_object in backup.tree.values() {
+        for chunk_hash in &backup_object.chunks {
+            chunk_indexes
+                .entry(chunk_hash.clone())
+                .or_insert(ChunkIndex { refcount: 0 })
+                .refcount += 1;
+        }
+    }
+
+    save_chunk_indexes(
+        Arc::clone(fs),
+        key.to_string(),
+        &chunk_indexes,
+        password.clone(),
+    )
+    .await
+}
+
+/// Picks the entry named by `--backup` or `--entry` (full value or a prefix), or asks for
+/// one when neither is given.
+fn select_entry(matches: &ArgMatches, entries: Vec<TrashEntry>) -> Result<TrashEntry, String> {
+    if entries.is_empty() {
+        return Err("The trash is empty.".to_string());
+    }
+
+    let (mut candidates, wanted) = if let Some(backup) = matches.get_one::<String>("backup") {
+        let candidates = entries
+            .into_iter()
+            .filter(|entry| {
+                entry
+                    .backup
+                    .as_ref()
+                    .is_some_and(|summary| summary.hash.starts_with(backup.as_str()))
+            })
+            .collect::<Vec<TrashEntry>>();
+        (candidates, format!("backup {}", backup))
+    } else if let Some(id) = matches.get_one::<String>("entry") {
+        let candidates = entries
+            .into_iter()
+            .filter(|entry| entry.id.starts_with(id.as_str()))
+            .collect::<Vec<TrashEntry>>();
+        (candidates, format!("entry {}", id))
+    } else {
+        if !can_prompt() {
+            return Err(
+                "Missing required argument: --backup or --entry (required in --mode json or with --non-interactive)".to_string(),
+            );
+        }
+
+        let items = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{} (deleted {}, expires {})",
+                    describe_entry(entry),
+                    format_date(entry.deleted_at),
+                    format_date(entry.expires_at)
+                )
+            })
+            .collect::<Vec<String>>();
+
+        let selected_index = Select::new()
+            .with_prompt("Select what to restore")
+            .items(&items)
+            .default(items.len() - 1)
+            .interact()
+            .map_err(|e| format!("{}", e))?;
+
+        return Ok(entries.into_iter().nth(selected_index).unwrap());
+    };
+
+    match candidates.len() {
+        0 => Err(format!("No trash entry found for {}", wanted)),
+        1 => Ok(candidates.remove(0)),
+        _ => Err(format!(
+            "{} matches {} trash entries, use a longer prefix",
+            wanted,
+            candidates.len()
+        )),
+    }
+}
@@ -0,0 +1,1050 @@
+use crate::commands::config::{default_key, default_storage};
+use crate::core::chunks::fetch_verified_chunk;
+use crate::core::crypto::get_password;
+use crate::core::indexes::{
+    backfill_backup_summaries, list_backup_summaries, load_repository_meta, load_summary_backup,
+    read_manifest, save_repository_meta,
+};
+use crate::core::layout::{
+    backup_path, chunk_hash_from_path, chunk_index_path, chunk_path, heal_log_path,
+    trash_object_path,
+};
+use crate::core::metadata::{
+    AffectedManifest, Backup, BackupSummary, REPAIR_PLAN_VERSION, RepairAction, RepairPlan,
+    RepairProblem,
+};
+use crate::core::signing::{load_verifying_key, verify_object};
+use crate::core::trash::list_trash;
+use crate::fs::FS;
+use crate::output::{
+    JsonProgress, can_prompt, emit_error_with_details, emit_output, emit_progress_message,
+    emit_warning, is_json_mode,
+};
+use crate::utils::{get_fs, get_pwd_string, get_storage, handle_error};
+use bytesize::ByteSize;
+use clap::ArgMatches;
+use console::style;
+use dialoguer::Select;
+use dirs::home_dir;
+use ed25519_dalek::VerifyingKey;
+use futures::stream::{self, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const MAX_CONCURRENT_CHUNKS: usize = 100;
+const MAX_CONCURRENT_MANIFEST_LOADS: usize = 16;
+const MAX_CONCURRENT_SIGNATURE_CHECKS: usize = 16;
+
+#[derive(serde::Serialize)]
+struct VerifyFailure {
+    chunk: String,
+    reason: &'static str,
+    detail: String,
+}
+
+/// A chunk that failed verification and was rewritten from an intact copy in a
+/// `--repair-from` storage. Also the line appended to the repository's heal log.
+#[derive(serde::Serialize)]
+struct HealedChunk {
+    chunk: String,
+    reason: &'static str,
+    detail: String,
+    /// The storage the copy came from.
+    source: String,
+    healed_at: u64,
+    /// Size of the chunk once decompressed, added to `bytes_verified`.
+    #[serde(skip)]
+    bytes: u64,
+}
+
+#[derive(serde::Serialize)]
+struct VerifyOutput {
+    /// The backup verification started after, with `--since` or `--incremental`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    since: Option<String>,
+    backups_verified: usize,
+    chunks_verified: usize,
+    /// Chunks also referenced by the backups up to `since`, which were not read again.
+    chunks_skipped: usize,
+    bytes_verified: u64,
+    failures: Vec<VerifyFailure>,
+    /// Chunks rewritten from a `--repair-from` storage; they count as verified.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    healed: Vec<HealedChunk>,
+    /// Where `--output-repair-plan` wrote the plan.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    repair_plan: Option<String>,
+    elapsed_ms: u64,
+}
+
+#[derive(serde::Serialize)]
+struct SignatureCheckFailure {
+    object: String,
+    reason: &'static str,
+    detail: String,
+}
+
+#[derive(serde::Serialize)]
+struct SignatureOutput {
+    objects_checked: usize,
+    failures: Vec<SignatureCheckFailure>,
+    elapsed_ms: u64,
+}
+
+pub async fn verify(matches: &ArgMatches) {
+    let (key, storage, password) = match get_params(matches) {
+        Ok(params) => params,
+        Err(e) => handle_error(e, None),
+    };
+
+    let started_at = Instant::now();
+    let repair_plan_path = matches.get_one::<String>("output-repair-plan").cloned();
+
+    if matches.get_flag("verify-signatures") {
+        let public_key = matches
+            .get_one::<String>("public-key")
+            .expect("--public-key is required by --verify-signatures");
+        let verifying_key = match load_verifying_key(public_key) {
+            Ok(verifying_key) => verifying_key,
+            Err(e) => handle_error(e, None),
+        };
+
+        let fs = get_fs(&get_storage(&storage), None);
+        verify_signatures(fs, key, verifying_key, started_at).await;
+        return;
+    }
+
+    let concurrency = match matches.get_one::<String>("concurrency") {
+        Some(concurrency) => match concurrency.parse::<usize>() {
+            Ok(concurrency) if concurrency > 0 => concurrency,
+            _ => handle_error(format!("Invalid concurrency: '{}'", concurrency), None),
+        },
+        None => MAX_CONCURRENT_CHUNKS,
+    };
+
+    let repair_sources = matches
+        .get_many::<String>("repair-from")
+        .map(|names| {
+            names
+                .map(|name| (name.clone(), get_fs(&get_storage(name), None)))
+                .collect::<Vec<(String, Arc<dyn FS>)>>()
+        })
+        .unwrap_or_default();
+
+    let storage = get_storage(&storage);
+
+    let fs = get_fs(&storage, None);
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb
+    };
+
+    set_progress_message(&pb, "Loading backups...");
+
+    let mut repository_meta = match load_repository_meta(Arc::clone(&fs), key.clone()).await {
+        Ok(repository_meta) => repository_meta.unwrap_or_default(),
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let mut backup_summaries =
+        match list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await {
+            Ok(summaries) => summaries,
+            Err(e) => handle_error(e, Some(&pb)),
+        };
+
+    backfill_backup_summaries(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        &mut backup_summaries,
+    )
+    .await;
+
+    let Some(newest_backup) = backup_summaries.first().map(|summary| summary.hash.clone()) else {
+        pb.finish_and_clear();
+        if let Some(path) = &repair_plan_path
+            && let Err(e) = write_repair_plan(path, &new_repair_plan(&key, Vec::new()))
+        {
+            handle_error(e, None);
+        }
+        if is_json_mode() {
+            emit_output(&VerifyOutput {
+                since: None,
+                backups_verified: 0,
+                chunks_verified: 0,
+                chunks_skipped: 0,
+                bytes_verified: 0,
+                failures: Vec::new(),
+                healed: Vec::new(),
+                repair_plan: repair_plan_path,
+                elapsed_ms: started_at.elapsed().as_millis() as u64,
+            });
+        } else {
+            println!(
+                "{}",
+                style("No backups found for this repository.").yellow()
+            );
+        }
+        return;
+    };
+
+    let since = if let Some(since) = matches.get_one::<String>("since") {
+        match find_backup(&backup_summaries, since) {
+            Ok(hash) => Some(hash),
+            Err(e) => handle_error(e, Some(&pb)),
+        }
+    } else if matches.get_flag("incremental") {
+        match &repository_meta.last_verified_backup {
+            Some(last_verified)
+                if backup_summaries
+                    .iter()
+                    .any(|summary| &summary.hash == last_verified) =>
+            {
+                Some(last_verified.clone())
+            }
+            Some(last_verified) => {
+                pb.suspend(|| {
+                    emit_warning(
+                        &format!(
+                            "The last verified backup {} no longer exists, verifying every backup",
+                            &last_verified[..8.min(last_verified.len())]
+                        ),
+                        "last_verified_backup_missing",
+                    )
+                });
+                None
+            }
+            None => None,
+        }
+    } else {
+        None
+    };
+
+    // Summaries are newest first, so the backups to verify are the ones before `since`.
+    let split_at = since
+        .as_ref()
+        .and_then(|since| {
+            backup_summaries
+                .iter()
+                .position(|summary| &summary.hash == since)
+        })
+        .unwrap_or(backup_summaries.len());
+    let verified_summaries = backup_summaries.split_off(split_at);
+    let summaries_to_verify = backup_summaries;
+
+    set_progress_message(
+        &pb,
+        &format!(
+            "Loading {} backups...",
+            summaries_to_verify.len() + verified_summaries.len()
+        ),
+    );
+
+    let chunks_to_verify = match collect_chunks(&fs, &key, &password, &summaries_to_verify).await {
+        Ok(chunks) => chunks,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let verified_chunks = match collect_chunks(&fs, &key, &password, &verified_summaries).await {
+        Ok(chunks) => chunks,
+        Err(e) => handle_error(e, Some(&pb)),
+    };
+
+    let mut chunks = chunks_to_verify
+        .difference(&verified_chunks)
+        .cloned()
+        .collect::<Vec<String>>();
+    chunks.sort();
+    let chunks_skipped = chunks_to_verify.len() - chunks.len();
+
+    pb.finish_and_clear();
+
+    let json_progress = if is_json_mode() {
+        let progress = JsonProgress::new(chunks.len() as u64);
+        progress.set_message("Verifying chunks...");
+        Some(progress)
+    } else {
+        None
+    };
+
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(chunks.len() as u64);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+            )
+            .unwrap(),
+        );
+        pb.set_message("Verifying chunks...");
+        pb
+    };
+
+    let chunk_dir_levels = repository_meta.chunk_dir_levels;
+    let results = stream::iter(&chunks)
+        .map(|chunk_hash| {
+            let fs = Arc::clone(&fs);
+            let path = chunk_path(&key, chunk_hash, chunk_dir_levels);
+            let password = password.clone();
+            let pb = pb.clone();
+            let json_progress = json_progress.clone();
+
+            async move {
+                let result =
+                    fetch_verified_chunk(&fs, &path, chunk_hash, password.as_deref()).await;
+
+                pb.inc(1);
+                if let Some(progress) = &json_progress {
+                    progress.inc_by(1);
+                }
+
+                result
+                    .map(|bytes| bytes.len() as u64)
+                    .map_err(|e| VerifyFailure {
+                        chunk: chunk_hash.clone(),
+                        reason: e.reason_code(),
+                        detail: e.detail().to_string(),
+                    })
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<Result<u64, VerifyFailure>>>()
+        .await;
+
+    if let Some(progress) = &json_progress {
+        progress.finish();
+    }
+
+    let mut bytes_verified = 0;
+    let mut failures = Vec::new();
+    for result in results {
+        match result {
+            Ok(bytes) => bytes_verified += bytes,
+            Err(failure) => failures.push(failure),
+        }
+    }
+    failures.sort_by(|a, b| a.chunk.cmp(&b.chunk));
+
+    let mut healed = Vec::new();
+    if !failures.is_empty() && !repair_sources.is_empty() {
+        set_progress_message(&pb, "Healing chunks from other storages...");
+
+        (healed, failures) = heal_chunks(
+            &fs,
+            &key,
+            &password,
+            chunk_dir_levels,
+            &repair_sources,
+            failures,
+        )
+        .await;
+
+        for chunk in &healed {
+            bytes_verified += chunk.bytes;
+        }
+
+        if let Err(e) = append_heal_log(&fs, &key, &healed).await {
+            pb.suspend(|| {
+                emit_warning(
+                    &format!(
+                        "The chunks were healed, but writing the heal log failed: {}",
+                        e
+                    ),
+                    "heal_log_not_saved",
+                )
+            });
+        }
+    }
+
+    if let Some(path) = &repair_plan_path {
+        if !failures.is_empty() {
+            pb.set_message("Writing the repair plan...");
+        }
+
+        let all_summaries = summaries_to_verify
+            .iter()
+            .chain(verified_summaries.iter())
+            .cloned()
+            .collect::<Vec<BackupSummary>>();
+
+        let plan = match build_repair_plan(
+            &fs,
+            &key,
+            &password,
+            chunk_dir_levels,
+            &all_summaries,
+            &failures,
+            &pb,
+        )
+        .await
+        {
+            Ok(plan) => plan,
+            Err(e) => handle_error(e, Some(&pb)),
+        };
+
+        if let Err(e) = write_repair_plan(path, &plan) {
+            handle_error(e, Some(&pb));
+        }
+    }
+
+    let payload = VerifyOutput {
+        since: since.clone(),
+        backups_verified: summaries_to_verify.len(),
+        chunks_verified: chunks.len() - failures.len(),
+        chunks_skipped,
+        bytes_verified,
+        failures,
+        healed,
+        repair_plan: repair_plan_path.clone(),
+        elapsed_ms: started_at.elapsed().as_millis() as u64,
+    };
+
+    if !payload.failures.is_empty() {
+        let mut message = format!(
+            "{} of {} chunks failed verification:\n{}",
+            payload.failures.len(),
+            chunks.len(),
+            payload
+                .failures
+                .iter()
+                .map(|failure| format!("  - {}: {}", failure.chunk, failure.detail))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+
+        if let Some(path) = &repair_plan_path {
+            message.push_str(&format!(
+                "\n\nA repair plan was written to {}. Review it, then run 'gib repair --plan {}'.",
+                path, path
+            ));
+        }
+
+        if is_json_mode() {
+            emit_error_with_details(&message, "verify_failed", &payload);
+        }
+        handle_error(message, Some(&pb));
+    }
+
+    // Everything up to the newest backup is known good now, so the next
+    // `--incremental` run starts after it.
+    repository_meta.last_verified_backup = Some(newest_backup);
+    if let Err(e) = save_repository_meta(Arc::clone(&fs), key.clone(), &repository_meta).await {
+        pb.suspend(|| {
+            emit_warning(
+                &format!("Failed to record the verified backup: {}", e),
+                "verify_state_not_saved",
+            )
+        });
+    }
+
+    if is_json_mode() {
+        emit_output(&payload);
+    } else {
+        let elapsed = pb.elapsed();
+        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
+        pb.set_prefix("OK");
+        pb.finish_with_message(format!(
+            "Verified {} chunks of {} backups ({}, {:.2?})",
+            payload.chunks_verified,
+            payload.backups_verified,
+            ByteSize(payload.bytes_verified),
+            elapsed
+        ));
+
+        for chunk in &payload.healed {
+            println!(
+                "{}",
+                style(format!(
+                    "Healed chunk {} ({}) from storage '{}'",
+                    chunk.chunk, chunk.detail, chunk.source
+                ))
+                .yellow()
+            );
+        }
+
+        if let Some(since) = &since {
+            println!(
+                "{}",
+                style(format!(
+                    "Skipped {} chunks already referenced by {} and older backups",
+                    chunks_skipped,
+                    &since[..8.min(since.len())]
+                ))
+                .dim()
+            );
+        }
+
+        if let Some(path) = &repair_plan_path {
+            println!(
+                "{}",
+                style(format!("Wrote an empty repair plan to {}", path)).dim()
+            );
+        }
+    }
+}
+
+/// `--repair-from`: looks for an intact copy of each failed chunk in the other storages, in
+/// the order given, and writes its stored bytes over the chunk here. A copy is only used
+/// once it decrypts, decompresses and hashes back to the chunk, and the rewritten chunk is
+/// read back the same way. Returns the healed chunks and the failures left.
+async fn heal_chunks(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    chunk_dir_levels: u8,
+    sources: &[(String, Arc<dyn FS>)],
+    failures: Vec<VerifyFailure>,
+) -> (Vec<HealedChunk>, Vec<VerifyFailure>) {
+    // Each storage may hold the repository with a different chunk fan-out.
+    let mut source_levels = Vec::new();
+    for (name, source_fs) in sources {
+        match load_repository_meta(Arc::clone(source_fs), key.to_string()).await {
+            Ok(meta) => source_levels.push(Some(meta.unwrap_or_default().chunk_dir_levels)),
+            Err(e) => {
+                emit_warning(
+                    &format!(
+                        "Skipping storage '{}' for healing, its repository settings could not be read: {}",
+                        name, e
+                    ),
+                    "repair_source_unreadable",
+                );
+                source_levels.push(None);
+            }
+        }
+    }
+
+    let mut healed = Vec::new();
+    let mut remaining = Vec::new();
+
+    for failure in failures {
+        let path = chunk_path(key, &failure.chunk, chunk_dir_levels);
+        let mut healed_from = None;
+
+        for ((name, source_fs), levels) in sources.iter().zip(&source_levels) {
+            let Some(levels) = levels else {
+                continue;
+            };
+            let source_path = chunk_path(key, &failure.chunk, *levels);
+
+            // A size lookup is cheaper than a download for the common case of a chunk the
+            // other storage never had.
+            if source_fs.file_size(&source_path).await.is_err() {
+                continue;
+            }
+
+            if fetch_verified_chunk(source_fs, &source_path, &failure.chunk, password.as_deref())
+                .await
+                .is_err()
+            {
+                continue;
+            }
+
+            let Ok(bytes) = source_fs.read_file(&source_path).await else {
+                continue;
+            };
+
+            if fs.write_file(&path, &bytes).await.is_err() {
+                continue;
+            }
+
+            if let Ok(chunk) =
+                fetch_verified_chunk(fs, &path, &failure.chunk, password.as_deref()).await
+            {
+                healed_from = Some((name.clone(), chunk.len() as u64));
+                break;
+            }
+        }
+
+        match healed_from {
+            Some((source, bytes)) => healed.push(HealedChunk {
+                chunk: failure.chunk,
+                reason: failure.reason,
+                detail: failure.detail,
+                source,
+                healed_at: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+                bytes,
+            }),
+            None => remaining.push(failure),
+        }
+    }
+
+    (healed, remaining)
+}
+
+/// Appends one JSON line per healed chunk to the repository's heal log, so what
+/// `--repair-from` rewrote stays on record next to the data.
+async fn append_heal_log(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    healed: &[HealedChunk],
+) -> Result<(), String> {
+    if healed.is_empty() {
+        return Ok(());
+    }
+
+    let path = heal_log_path(key);
+    let mut log = match fs.read_file(&path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e)),
+    };
+
+    for chunk in healed {
+        let line = serde_json::to_string(chunk)
+            .map_err(|e| format!("Failed to serialize the heal log: {}", e))?;
+        log.extend_from_slice(line.as_bytes());
+        log.push(b'\n');
+    }
+
+    fs.write_file(&path, &log)
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", path, e))
+}
+
+fn new_repair_plan(key: &str, problems: Vec<RepairProblem>) -> RepairPlan {
+    RepairPlan {
+        version: REPAIR_PLAN_VERSION,
+        key: key.to_string(),
+        created_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        problems,
+    }
+}
+
+/// Builds the `--output-repair-plan` for `failures`: the files of every manifest made of a
+/// failed chunk, and the intact copies of the chunk still in the repository (a `.bak` next
+/// to it, then the trash, newest first). A chunk with a copy is restored from it; otherwise
+/// the files using it are dropped.
+async fn build_repair_plan(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    chunk_dir_levels: u8,
+    summaries: &[BackupSummary],
+    failures: &[VerifyFailure],
+    pb: &ProgressBar,
+) -> Result<RepairPlan, String> {
+    if failures.is_empty() {
+        return Ok(new_repair_plan(key, Vec::new()));
+    }
+
+    let failed_chunks = failures
+        .iter()
+        .map(|failure| failure.chunk.as_str())
+        .collect::<HashSet<&str>>();
+
+    let mut manifest_backups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for summary in summaries {
+        manifest_backups
+            .entry(summary.manifest_hash().to_string())
+            .or_default()
+            .push(summary.hash.clone());
+    }
+
+    let manifests = stream::iter(manifest_backups.keys())
+        .map(|manifest_hash| {
+            let fs = Arc::clone(fs);
+            async move {
+                read_manifest(&fs, key, password, manifest_hash)
+                    .await
+                    .map(|backup| (manifest_hash.clone(), backup))
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_MANIFEST_LOADS)
+        .collect::<Vec<Result<(String, Option<Backup>), String>>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<(String, Option<Backup>)>, String>>()?;
+
+    // chunk -> manifest -> files
+    let mut affected: HashMap<&str, BTreeMap<String, BTreeSet<String>>> = HashMap::new();
+    for (manifest_hash, backup) in &manifests {
+        let Some(backup) = backup else {
+            continue;
+        };
+
+        for (path, object) in &backup.tree {
+            for chunk in &object.chunks {
+                if let Some(chunk) = failed_chunks.get(chunk.as_str()) {
+                    affected
+                        .entry(chunk)
+                        .or_default()
+                        .entry(manifest_hash.clone())
+                        .or_default()
+                        .insert(path.clone());
+                }
+            }
+        }
+    }
+
+    let key_prefix = format!("{}/", key);
+    let mut candidates: HashMap<&str, Vec<String>> = failed_chunks
+        .iter()
+        .map(|chunk| {
+            (
+                *chunk,
+                vec![format!("{}.bak", chunk_path(key, chunk, chunk_dir_levels))],
+            )
+        })
+        .collect();
+
+    match list_trash(Arc::clone(fs), key.to_string(), password.clone()).await {
+        Ok(entries) => {
+            for entry in entries.iter().rev() {
+                for object in &entry.objects {
+                    if let Some(chunk) = chunk_hash_from_path(&format!("{}{}", key_prefix, object))
+                        && let Some(paths) = candidates.get_mut(chunk.as_str())
+                    {
+                        paths.push(trash_object_path(key, &entry.id, object));
+                    }
+                }
+            }
+        }
+        Err(e) => pb.suspend(|| {
+            emit_warning(
+                &format!(
+                    "Could not look for chunk copies in the trash, the repair plan only uses .bak copies: {}",
+                    e
+                ),
+                "repair_plan_trash_unreadable",
+            )
+        }),
+    }
+
+    let mut problems = Vec::new();
+    for failure in failures {
+        let mut copies = Vec::new();
+        for candidate in candidates
+            .remove(failure.chunk.as_str())
+            .unwrap_or_default()
+        {
+            if fetch_verified_chunk(fs, &candidate, &failure.chunk, password.as_deref())
+                .await
+                .is_ok()
+            {
+                copies.push(candidate.trim_start_matches(&key_prefix).to_string());
+            }
+        }
+
+        let manifests = affected
+            .remove(failure.chunk.as_str())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(manifest, files)| AffectedManifest {
+                backups: manifest_backups.get(&manifest).cloned().unwrap_or_default(),
+                manifest,
+                files: files.into_iter().collect(),
+            })
+            .collect();
+
+        let action = match copies.first() {
+            Some(source) => RepairAction::RestoreCopy {
+                source: source.clone(),
+            },
+            None => RepairAction::DropFiles,
+        };
+
+        problems.push(RepairProblem {
+            chunk: failure.chunk.clone(),
+            reason: failure.reason.to_string(),
+            detail: failure.detail.clone(),
+            manifests,
+            copies,
+            action,
+        });
+    }
+
+    Ok(new_repair_plan(key, problems))
+}
+
+/// Writes the plan next to `path` and renames it over it, so `gib repair` never reads a
+/// partial plan.
+fn write_repair_plan(path: &str, plan: &RepairPlan) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(plan)
+        .map_err(|e| format!("Failed to serialize the repair plan: {}", e))?;
+
+    let temp_path = PathBuf::from(format!("{}.tmp-{}", path, std::process::id()));
+
+    std::fs::write(&temp_path, json + "\n")
+        .and_then(|()| std::fs::rename(&temp_path, path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&temp_path);
+            format!("Failed to write the repair plan to {}: {}", path, e)
+        })
+}
+
+/// `--verify-signatures`: checks every backup manifest and the chunk index against the
+/// signatures `backup --sign-key` stored. Only the stored bytes are read, so no password
+/// is needed; the chunks themselves are not checked.
+async fn verify_signatures(
+    fs: Arc<dyn FS>,
+    key: String,
+    verifying_key: VerifyingKey,
+    started_at: Instant,
+) {
+    let pb = if is_json_mode() {
+        ProgressBar::hidden()
+    } else {
+        let pb = ProgressBar::new(100);
+        pb.enable_steady_tick(Duration::from_millis(100));
+        pb.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+        pb
+    };
+
+    set_progress_message(&pb, "Listing backup manifests...");
+
+    let mut objects = match fs.list_files(&format!("{}/backups", key)).await {
+        Ok(files) => files
+            .iter()
+            .filter_map(|file| file.split('/').next_back())
+            .map(|hash| backup_path(&key, hash))
+            .collect::<Vec<String>>(),
+        Err(e) => handle_error(format!("Failed to list backup manifests: {}", e), Some(&pb)),
+    };
+    objects.sort();
+
+    if fs.file_size(&chunk_index_path(&key)).await.is_ok() {
+        objects.push(chunk_index_path(&key));
+    }
+
+    set_progress_message(
+        &pb,
+        &format!("Checking the signatures of {} objects...", objects.len()),
+    );
+
+    let mut failures = stream::iter(&objects)
+        .map(|object_path| {
+            let fs = Arc::clone(&fs);
+            let key = key.clone();
+
+            async move {
+                verify_object(&fs, &key, object_path, &verifying_key)
+                    .await
+                    .map_err(|failure| SignatureCheckFailure {
+                        object: object_path.clone(),
+                        reason: failure.reason_code,
+                        detail: failure.detail,
+                    })
+                    .err()
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_SIGNATURE_CHECKS)
+        .filter_map(|failure| async move { failure })
+        .collect::<Vec<SignatureCheckFailure>>()
+        .await;
+    failures.sort_by(|a, b| a.object.cmp(&b.object));
+
+    let payload = SignatureOutput {
+        objects_checked: objects.len(),
+        failures,
+        elapsed_ms: started_at.elapsed().as_millis() as u64,
+    };
+
+    if !payload.failures.is_empty() {
+        let message = format!(
+            "{} of {} objects failed signature verification:\n{}",
+            payload.failures.len(),
+            payload.objects_checked,
+            payload
+                .failures
+                .iter()
+                .map(|failure| format!("  - {}: {}", failure.object, failure.detail))
+                .collect::<Vec<String>>()
+                .join("\n")
+        );
+
+        if is_json_mode() {
+            emit_error_with_details(&message, "signature_verification_failed", &payload);
+        }
+        handle_error(message, Some(&pb));
+    }
+
+    if is_json_mode() {
+        emit_output(&payload);
+    } else {
+        pb.set_style(ProgressStyle::with_template("{prefix:.green} {msg}").unwrap());
+        pb.set_prefix("OK");
+        pb.finish_with_message(format!(
+            "Verified the signatures of {} objects ({:.2?})",
+            payload.objects_checked,
+            pb.elapsed()
+        ));
+    }
+}
+
+fn set_progress_message(pb: &ProgressBar, message: &str) {
+    pb.set_message(message.to_string());
+    if is_json_mode() {
+        emit_progress_message(message);
+    }
+}
+
+/// Finds the backup whose hash is `hash` or starts with it.
+fn find_backup(backup_summaries: &[BackupSummary], hash: &str) -> Result<String, String> {
+    let matching = backup_summaries
+        .iter()
+        .filter(|summary| summary.hash.starts_with(hash))
+        .collect::<Vec<&BackupSummary>>();
+
+    match matching.as_slice() {
+        [summary] => Ok(summary.hash.clone()),
+        [] => Err(format!("No backup found matching hash prefix: {}", hash)),
+        _ => Err(format!(
+            "Hash prefix {} matches {} backups, use a longer prefix",
+            hash,
+            matching.len()
+        )),
+    }
+}
+
+/// Every chunk referenced by the manifests of `summaries`. A differential backup only
+/// references its changed files, which is exactly the data it added.
+async fn collect_chunks(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    summaries: &[BackupSummary],
+) -> Result<HashSet<String>, String> {
+    let backups = stream::iter(summaries)
+        .map(|summary| {
+            let fs = Arc::clone(fs);
+            let key = key.to_string();
+            let password = password.clone();
+            async move { load_summary_backup(fs, key, password, summary).await }
+        })
+        .buffer_unordered(MAX_CONCURRENT_MANIFEST_LOADS)
+        .collect::<Vec<Result<Backup, String>>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<Backup>, String>>()?;
+
+    Ok(backups
+        .iter()
+        .flat_map(|backup| backup.tree.values())
+        .flat_map(|object| object.chunks.iter().cloned())
+        .collect::<HashSet<String>>())
+}
+
+fn get_params(matches: &ArgMatches) -> Result<(String, String, Option<String>), String> {
+    // Signatures cover the stored bytes, so checking them never needs the password.
+    let password: Option<String> = if matches.get_flag("verify-signatures") {
+        None
+    } else {
+        matches
+            .get_one::<String>("password")
+            .map(|s| s.to_string())
+            .map_or_else(
+                || get_password(false, true),
+                |password| Some(password.to_string()),
+            )
+    };
+
+    let pwd_string = get_pwd_string();
+
+    let folder_key = Path::new(&pwd_string)
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .to_string();
+
+    let key = matches
+        .get_one::<String>("key")
+        .cloned()
+        .or_else(default_key)
+        .unwrap_or(folder_key);
+
+    let home_dir = home_dir().unwrap();
+    let storage_path = home_dir.join(".gib").join("storages");
+
+    if !storage_path.exists() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let files =
+        std::fs::read_dir(&storage_path).map_err(|e| format!("Failed to read storages: {}", e))?;
+
+    let storages_names = &files
+        .map(|file| {
+            file.map_err(|e| format!("Failed to read storage entry: {}", e))
+                .map(|file| {
+                    file.file_name()
+                        .to_string_lossy()
+                        .split('.')
+                        .next()
+                        .unwrap()
+                        .to_string()
+                })
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+
+    if storages_names.is_empty() {
+        return Err("Seems like you didn't create any storage yet. Run 'gib storage add' to create a storage.".to_string());
+    }
+
+    let storage = match matches
+        .get_one::<String>("storage")
+        .cloned()
+        .or_else(default_storage)
+    {
+        Some(storage) => storage,
+        None => {
+            if !can_prompt() {
+                return Err(
+                    "Missing required argument: --storage (required in --mode json or with --non-interactive)".to_string(),
+                );
+            }
+            let selected_index = Select::new()
+                .with_prompt("Select the storage to use")
+                .items(storages_names)
+                .default(0)
+                .interact()
+                .map_err(|e| format!("{}", e))?;
+
+            storages_names[selected_index].clone()
+        }
+    };
+
+    let exists = storages_names
+        .iter()
+        .any(|storage_name| storage_name == &storage);
+
+    if !exists {
+        return Err(format!("Storage '{}' not found", storage));
+    }
+
+    for source in matches
+        .get_many::<String>("repair-from")
+        .into_iter()
+        .flatten()
+    {
+        if source == &storage {
+            return Err(format!(
+                "Invalid value for --repair-from: '{}' (must be another storage than the one verified)",
+                source
+            ));
+        }
+        if !storages_names
+            .iter()
+            .any(|storage_name| storage_name == source)
+        {
+            return Err(format!("Storage '{}' not found", source));
+        }
+    }
+
+    Ok((key, storage, password))
+}
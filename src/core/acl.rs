@@ -0,0 +1,296 @@
+//! POSIX access ACLs (`--store-acls`), read and written through the
+//! `system.posix_acl_access` extended attribute that Linux exposes them as.
+
+use crate::core::metadata::AclEntry;
+use crate::core::ownership::OwnerMap;
+use std::path::Path;
+
+#[cfg(target_os = "linux")]
+const ACL_XATTR_NAME: &str = "system.posix_acl_access";
+#[cfg(target_os = "linux")]
+const ACL_XATTR_VERSION: u32 = 2;
+#[cfg(target_os = "linux")]
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+#[cfg(target_os = "linux")]
+const ACL_TAGS: [(u16, &str); 6] = [
+    (0x01, "user_obj"),
+    (0x02, "user"),
+    (0x04, "group_obj"),
+    (0x08, "group"),
+    (0x10, "mask"),
+    (0x20, "other"),
+];
+
+/// The extended ACL of `path`, or `None` when it only has the entries its mode bits
+/// already describe, or when the filesystem does not support ACLs.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub(crate) fn read_acl(path: &Path) -> Result<Option<Vec<AclEntry>>, String> {
+    #[cfg(target_os = "linux")]
+    {
+        let Some(bytes) = get_xattr(path)? else {
+            return Ok(None);
+        };
+
+        let entries = parse_acl(&bytes)?;
+        let extended = entries
+            .iter()
+            .any(|entry| !matches!(entry.tag.as_str(), "user_obj" | "group_obj" | "other"));
+
+        Ok(extended.then_some(entries))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        Ok(None)
+    }
+}
+
+/// Whether `path` has an extended ACL, without failing on errors.
+pub(crate) fn has_acl(path: &Path) -> bool {
+    read_acl(path).is_ok_and(|acl| acl.is_some())
+}
+
+/// Sets the ACL of `path` to `entries`, passing the uids and gids of named entries
+/// through `owner_map`. Call it after the mode bits are set, since setting the mode
+/// rewrites the mask entry.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub(crate) fn write_acl(
+    path: &Path,
+    entries: &[AclEntry],
+    owner_map: &OwnerMap,
+) -> Result<(), String> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let bytes = encode_acl(entries, owner_map)?;
+
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| "Path contains a NUL byte".to_string())?;
+        let name = CString::new(ACL_XATTR_NAME).unwrap();
+
+        let result = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                bytes.as_ptr() as *const libc::c_void,
+                bytes.len(),
+                0,
+            )
+        };
+
+        if result != 0 {
+            let error = std::io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::EOPNOTSUPP) {
+                return Err("The filesystem does not support ACLs".to_string());
+            }
+            return Err(format!("Failed to set ACL: {}", error));
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        Err("POSIX ACLs can only be restored on Linux".to_string())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn get_xattr(path: &Path) -> Result<Option<Vec<u8>>, String> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| "Path contains a NUL byte".to_string())?;
+    let name = CString::new(ACL_XATTR_NAME).unwrap();
+
+    let missing = |error: &std::io::Error| {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::ENODATA) | Some(libc::EOPNOTSUPP)
+        )
+    };
+
+    let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let error = std::io::Error::last_os_error();
+        if missing(&error) {
+            return Ok(None);
+        }
+        return Err(format!("Failed to read ACL: {}", error));
+    }
+
+    let mut buffer = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buffer.as_mut_ptr() as *mut libc::c_void,
+            buffer.len(),
+        )
+    };
+    if size < 0 {
+        let error = std::io::Error::last_os_error();
+        if missing(&error) {
+            return Ok(None);
+        }
+        return Err(format!("Failed to read ACL: {}", error));
+    }
+
+    buffer.truncate(size as usize);
+    Ok(Some(buffer))
+}
+
+/// Encodes `entries` in the xattr form `parse_acl` reads, with the uids and gids of named
+/// entries passed through `owner_map`.
+#[cfg(target_os = "linux")]
+fn encode_acl(entries: &[AclEntry], owner_map: &OwnerMap) -> Result<Vec<u8>, String> {
+    let mut bytes = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+    for entry in entries {
+        let tag = ACL_TAGS
+            .iter()
+            .find(|(_, name)| *name == entry.tag)
+            .map(|(tag, _)| *tag)
+            .ok_or_else(|| format!("Unknown ACL entry tag '{}'", entry.tag))?;
+        let id = match (entry.tag.as_str(), entry.id) {
+            ("user", Some(uid)) => owner_map.map_uid(uid),
+            ("group", Some(gid)) => owner_map.map_gid(gid),
+            _ => ACL_UNDEFINED_ID,
+        };
+
+        bytes.extend_from_slice(&tag.to_le_bytes());
+        bytes.extend_from_slice(&u16::from(entry.perm & 0o7).to_le_bytes());
+        bytes.extend_from_slice(&id.to_le_bytes());
+    }
+
+    Ok(bytes)
+}
+
+/// Decodes the xattr form of an ACL: a little-endian version, then one
+/// (tag: u16, perm: u16, id: u32) record per entry.
+#[cfg(target_os = "linux")]
+fn parse_acl(bytes: &[u8]) -> Result<Vec<AclEntry>, String> {
+    let (version, records) = bytes
+        .split_first_chunk::<4>()
+        .ok_or_else(|| "ACL is too short".to_string())?;
+
+    if u32::from_le_bytes(*version) != ACL_XATTR_VERSION || records.len() % 8 != 0 {
+        return Err("Unsupported ACL format".to_string());
+    }
+
+    records
+        .chunks_exact(8)
+        .map(|record| {
+            let tag = u16::from_le_bytes([record[0], record[1]]);
+            let perm = u16::from_le_bytes([record[2], record[3]]);
+            let id = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+
+            let name = ACL_TAGS
+                .iter()
+                .find(|(known, _)| *known == tag)
+                .map(|(_, name)| *name)
+                .ok_or_else(|| format!("Unknown ACL entry tag {:#x}", tag))?;
+
+            Ok(AclEntry {
+                tag: name.to_string(),
+                id: matches!(name, "user" | "group").then_some(id),
+                perm: (perm & 0o7) as u8,
+            })
+        })
+        .collect()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    fn entry(tag: &str, id: Option<u32>, perm: u8) -> AclEntry {
+        AclEntry {
+            tag: tag.to_string(),
+            id,
+            perm,
+        }
+    }
+
+    fn extended_acl() -> Vec<AclEntry> {
+        vec![
+            entry("user_obj", None, 0o6),
+            entry("user", Some(1000), 0o6),
+            entry("group_obj", None, 0o4),
+            entry("group", Some(2000), 0o5),
+            entry("mask", None, 0o7),
+            entry("other", None, 0o4),
+        ]
+    }
+
+    #[test]
+    fn encoded_acl_parses_back_to_the_same_entries() {
+        let entries = extended_acl();
+
+        let bytes = encode_acl(&entries, &OwnerMap::default()).unwrap();
+
+        assert_eq!(bytes.len(), 4 + 8 * entries.len());
+        assert_eq!(parse_acl(&bytes).unwrap(), entries);
+    }
+
+    #[test]
+    fn encodes_the_kernel_xattr_layout() {
+        let bytes = encode_acl(
+            &[entry("user_obj", None, 0o6), entry("user", Some(1000), 0o4)],
+            &OwnerMap::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            bytes,
+            [
+                2, 0, 0, 0, // version
+                0x01, 0, 6, 0, 0xff, 0xff, 0xff, 0xff, // user_obj, rw-, no id
+                0x02, 0, 4, 0, 0xe8, 0x03, 0, 0, // user 1000, r--
+            ]
+        );
+    }
+
+    #[test]
+    fn encoding_maps_named_ids_through_the_owner_map() {
+        let dir = tempfile::tempdir().unwrap();
+        let map_path = dir.path().join("owners");
+        std::fs::write(&map_path, "uid:1000:1500\ngid:2000:2500\n").unwrap();
+        let owner_map = OwnerMap::from_file(map_path.to_str().unwrap()).unwrap();
+
+        let bytes = encode_acl(&extended_acl(), &owner_map).unwrap();
+        let parsed = parse_acl(&bytes).unwrap();
+
+        assert_eq!(parsed[1], entry("user", Some(1500), 0o6));
+        assert_eq!(parsed[3], entry("group", Some(2500), 0o5));
+        assert_eq!(parsed[0], entry("user_obj", None, 0o6));
+    }
+
+    #[test]
+    fn rejects_malformed_acls() {
+        assert!(encode_acl(&[entry("nobody", None, 0o7)], &OwnerMap::default()).is_err());
+
+        assert!(parse_acl(&[2, 0]).is_err());
+        assert!(parse_acl(&[1, 0, 0, 0]).is_err());
+        assert!(parse_acl(&[2, 0, 0, 0, 0x01, 0, 6, 0]).is_err());
+        assert!(parse_acl(&[2, 0, 0, 0, 0x40, 0, 6, 0, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn written_acl_reads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"content").unwrap();
+
+        match write_acl(&path, &extended_acl(), &OwnerMap::default()) {
+            Err(e) if e.contains("does not support ACLs") => return,
+            result => result.unwrap(),
+        }
+
+        assert_eq!(read_acl(&path).unwrap(), Some(extended_acl()));
+        assert!(has_acl(&path));
+    }
+}
@@ -0,0 +1,331 @@
+//! `gib backup --exclude-bigger-deltas`: a file that only grew since the previous backup
+//! (a log, a WAL) keeps the chunks of its old content, and only the data after them is
+//! read. The previous backup stores where its hash computation stood and a sample of its
+//! content, which tells an appended file from a rewritten one without reading it all.
+
+use crate::core::metadata::AppendState;
+use sha2::compress256;
+use sha2::digest::generic_array::GenericArray;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// How much of the start and of the end of the content goes into a sample.
+const SAMPLE_WINDOW: u64 = 64 * 1024;
+
+const SHA256_INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// SHA-256 that can be stopped at a block boundary and resumed from there by a later
+/// backup, so the hash of an appended file only needs its new data.
+#[derive(Clone)]
+pub(crate) struct ResumableSha256 {
+    state: [u32; 8],
+    pending: Vec<u8>,
+    length: u64,
+}
+
+impl ResumableSha256 {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: SHA256_INITIAL_STATE,
+            pending: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    /// Continues from a checkpoint of `AppendState`; `None` if it is malformed.
+    pub(crate) fn resume(append_state: &AppendState) -> Option<Self> {
+        if !append_state.hash_offset.is_multiple_of(64) || append_state.hash_state.len() != 64 {
+            return None;
+        }
+
+        let mut state = [0u32; 8];
+        for (index, word) in state.iter_mut().enumerate() {
+            let hex = append_state.hash_state.get(index * 8..index * 8 + 8)?;
+            *word = u32::from_str_radix(hex, 16).ok()?;
+        }
+
+        Some(Self {
+            state,
+            pending: Vec::with_capacity(64),
+            length: append_state.hash_offset,
+        })
+    }
+
+    pub(crate) fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+
+        if !self.pending.is_empty() {
+            let needed = (64 - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..needed]);
+            data = &data[needed..];
+
+            if self.pending.len() < 64 {
+                return;
+            }
+
+            compress256(&mut self.state, &[*GenericArray::from_slice(&self.pending)]);
+            self.pending.clear();
+        }
+
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            compress256(&mut self.state, &[*GenericArray::from_slice(block)]);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    /// Bytes hashed so far.
+    pub(crate) fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// The state after the last complete block, as stored in `AppendState`, and the
+    /// number of bytes it covers.
+    pub(crate) fn checkpoint(&self) -> (String, u64) {
+        let state = self
+            .state
+            .iter()
+            .map(|word| format!("{:08x}", word))
+            .collect::<String>();
+
+        (state, self.length - self.pending.len() as u64)
+    }
+
+    pub(crate) fn finalize_hex(mut self) -> String {
+        let bit_length = self.length.wrapping_mul(8);
+
+        let mut tail = std::mem::take(&mut self.pending);
+        tail.push(0x80);
+        let padded_length = if tail.len() > 56 { 128 } else { 64 };
+        tail.resize(padded_length - 8, 0);
+        tail.extend_from_slice(&bit_length.to_be_bytes());
+
+        for block in tail.chunks_exact(64) {
+            compress256(&mut self.state, &[*GenericArray::from_slice(block)]);
+        }
+
+        self.state
+            .iter()
+            .map(|word| format!("{:08x}", word))
+            .collect()
+    }
+}
+
+/// The hash of a file being backed up: `Sha256`, or `ResumableSha256` when the backup
+/// detects appends and has to store where the hash stood.
+#[derive(Clone)]
+pub(crate) enum FileHasher {
+    Plain(Sha256),
+    Resumable(ResumableSha256),
+}
+
+impl FileHasher {
+    pub(crate) fn new(resumable: bool) -> Self {
+        if resumable {
+            Self::Resumable(ResumableSha256::new())
+        } else {
+            Self::Plain(Sha256::new())
+        }
+    }
+
+    /// A new hasher of the same kind, to hash the file again from its start.
+    pub(crate) fn restarted(&self) -> Self {
+        Self::new(matches!(self, Self::Resumable(_)))
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Plain(hasher) => hasher.update(data),
+            Self::Resumable(hasher) => hasher.update(data),
+        }
+    }
+
+    /// The resumable state, when this hasher keeps one.
+    pub(crate) fn resumable(&self) -> Option<&ResumableSha256> {
+        match self {
+            Self::Plain(_) => None,
+            Self::Resumable(hasher) => Some(hasher),
+        }
+    }
+
+    pub(crate) fn finalize_hex(self) -> String {
+        match self {
+            Self::Plain(hasher) => format!("{:x}", hasher.finalize()),
+            Self::Resumable(hasher) => hasher.finalize_hex(),
+        }
+    }
+}
+
+/// SHA-256 of the first and last `SAMPLE_WINDOW` bytes of the first `length` bytes of the
+/// file, and of `length`. It is only a heuristic: a file rewritten in the middle with its
+/// start and end unchanged is taken as appended to, see `chunk_matches`.
+pub(crate) fn content_sample(path: &Path, length: u64) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+
+    let head_length = length.min(SAMPLE_WINDOW);
+    let mut head = vec![0u8; head_length as usize];
+    file.read_exact(&mut head)?;
+    hasher.update(&head);
+
+    let tail_start = length.saturating_sub(SAMPLE_WINDOW).max(head_length);
+    let mut tail = vec![0u8; (length - tail_start) as usize];
+    file.seek(SeekFrom::Start(tail_start))?;
+    file.read_exact(&mut tail)?;
+    hasher.update(&tail);
+
+    hasher.update(length.to_le_bytes());
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Whether the `length` bytes of the file at `offset` still hash to `chunk_hash`. Checks the
+/// last chunk a resumed file reuses, which the sample does not cover when the file is
+/// larger than two sample windows.
+pub(crate) fn chunk_matches(
+    path: &Path,
+    offset: u64,
+    length: u64,
+    chunk_hash: &str,
+) -> std::io::Result<bool> {
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut chunk = vec![0u8; length as usize];
+    file.read_exact(&mut chunk)?;
+
+    Ok(format!("{:x}", Sha256::digest(&chunk)) == chunk_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that do not repeat with any period a block boundary could hide.
+    fn content(length: usize) -> Vec<u8> {
+        let mut state = 0x2545f4914f6cdd1du64;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn sha256_hex(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    fn resumable_hex(data: &[u8]) -> String {
+        let mut hasher = ResumableSha256::new();
+        hasher.update(data);
+        hasher.finalize_hex()
+    }
+
+    #[test]
+    fn matches_sha256_around_the_padding_boundaries() {
+        for length in [0, 1, 55, 56, 63, 64, 65, 119, 120, 127, 128, 129] {
+            let data = content(length);
+            assert_eq!(resumable_hex(&data), sha256_hex(&data), "length {}", length);
+        }
+    }
+
+    #[test]
+    fn matches_sha256_on_a_multi_mib_buffer() {
+        let data = content(3 * 1024 * 1024 + 17);
+        assert_eq!(resumable_hex(&data), sha256_hex(&data));
+    }
+
+    #[test]
+    fn matches_sha256_whatever_the_update_sizes() {
+        let data = content(10_000);
+
+        for step in [1, 3, 63, 64, 65, 1000] {
+            let mut hasher = ResumableSha256::new();
+            for piece in data.chunks(step) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.length(), data.len() as u64);
+            assert_eq!(hasher.finalize_hex(), sha256_hex(&data), "step {}", step);
+        }
+    }
+
+    #[test]
+    fn resumed_checkpoint_finishes_like_a_one_shot_hash() {
+        let previous = content(1_000_003);
+        let appended = content(1_500_000);
+
+        let mut hasher = ResumableSha256::new();
+        hasher.update(&previous);
+        let (hash_state, hash_offset) = hasher.checkpoint();
+        assert_eq!(hash_offset, 1_000_003 / 64 * 64);
+
+        let append_state = AppendState {
+            chunk_size: 1024 * 1024,
+            hash_state,
+            hash_offset,
+            sample: String::new(),
+        };
+        let mut resumed = ResumableSha256::resume(&append_state).unwrap();
+        assert_eq!(resumed.length(), hash_offset);
+
+        // The bytes after the checkpoint are hashed again, followed by the new data.
+        resumed.update(&appended[hash_offset as usize..]);
+        assert_eq!(resumed.finalize_hex(), sha256_hex(&appended));
+    }
+
+    #[test]
+    fn malformed_checkpoints_are_not_resumed() {
+        let valid = ResumableSha256::new().checkpoint().0;
+
+        for (hash_state, hash_offset) in [
+            (valid.clone(), 65),
+            (valid[..60].to_string(), 64),
+            (format!("{}zz", &valid[..62]), 64),
+        ] {
+            let append_state = AppendState {
+                chunk_size: 64,
+                hash_state,
+                hash_offset,
+                sample: String::new(),
+            };
+            assert!(ResumableSha256::resume(&append_state).is_none());
+        }
+    }
+
+    #[test]
+    fn file_hasher_gives_the_same_hash_either_way() {
+        let data = content(100_000);
+
+        let mut plain = FileHasher::new(false);
+        let mut resumable = FileHasher::new(true);
+        plain.update(&data);
+        resumable.update(&data);
+
+        assert!(plain.resumable().is_none());
+        assert_eq!(resumable.resumable().unwrap().length(), data.len() as u64);
+        assert_eq!(plain.finalize_hex(), sha256_hex(&data));
+        assert_eq!(resumable.finalize_hex(), sha256_hex(&data));
+    }
+
+    #[test]
+    fn chunk_matches_reads_the_range_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        let mut data = content(4096);
+        std::fs::write(&path, &data).unwrap();
+
+        let chunk_hash = sha256_hex(&data[1024..2048]);
+        assert!(chunk_matches(&path, 1024, 1024, &chunk_hash).unwrap());
+
+        data[1500] ^= 0xff;
+        std::fs::write(&path, &data).unwrap();
+        assert!(!chunk_matches(&path, 1024, 1024, &chunk_hash).unwrap());
+        assert!(chunk_matches(&path, 4000, 1024, &chunk_hash).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! `gib restore --stdout`: the selected files of a backup written as a tar archive instead
+//! of to the filesystem.
+
+use crate::core::chunks::fetch_verified_chunk;
+use crate::core::layout::chunk_path;
+use crate::core::metadata::BackupObject;
+use crate::fs::FS;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+
+/// Where the chunks of the archived files are read from.
+pub(crate) struct ArchiveSource {
+    pub(crate) fs: Arc<dyn FS>,
+    pub(crate) key: String,
+    pub(crate) password: Option<String>,
+    pub(crate) chunk_dir_levels: u8,
+}
+
+/// Writes `files` to `writer` as a tar archive, in path order, with their stored mode,
+/// owner and modification time. Each file is streamed one chunk at a time, so memory does
+/// not grow with the file size. With `verify`, a file that does not hash back to the
+/// backup's record fails the archive; nothing after it is written, and the archive is left
+/// without its end marker so the reader notices.
+///
+/// The tar writer is synchronous, so this blocks on `handle` for every chunk: run it with
+/// `spawn_blocking`.
+pub(crate) fn write_tar<W: Write>(
+    source: &ArchiveSource,
+    handle: &Handle,
+    files: &mut [(String, BackupObject)],
+    verify: bool,
+    writer: W,
+) -> std::io::Result<()> {
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut builder = tar::Builder::new(writer);
+
+    for (path, object) in files.iter() {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(object.permissions & 0o7777);
+        if let Some(uid) = object.uid {
+            header.set_uid(uid as u64);
+        }
+        if let Some(gid) = object.gid {
+            header.set_gid(gid as u64);
+        }
+        if let Some(mtime_ns) = object.mtime_ns {
+            header.set_mtime(mtime_ns / 1_000_000_000);
+        }
+
+        if let Some(link_target) = &object.link_target {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            builder.append_link(&mut header, path, link_target)?;
+            continue;
+        }
+
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(object.size);
+
+        let mut reader = ChunkReader {
+            source,
+            handle,
+            path,
+            chunks: object.chunks.iter(),
+            buffer: Vec::new(),
+            position: 0,
+            length: 0,
+            hasher: Sha256::new(),
+        };
+        builder.append_data(&mut header, path, &mut reader)?;
+
+        // The header promised `size` bytes; anything else leaves the archive unreadable.
+        if reader.length != object.size {
+            return Err(std::io::Error::other(format!(
+                "'{}' is {} bytes in the backup, but its chunks hold {}",
+                path, object.size, reader.length
+            )));
+        }
+
+        if verify {
+            let actual_hash = format!("{:x}", reader.hasher.finalize());
+            if actual_hash != object.hash {
+                return Err(std::io::Error::other(format!(
+                    "'{}' does not match the hash recorded in the backup (got {})",
+                    path, actual_hash
+                )));
+            }
+        }
+    }
+
+    builder.into_inner()?.flush()
+}
+
+/// Reads a file's content chunk by chunk, fetching the next chunk once the previous one
+/// was consumed.
+struct ChunkReader<'a> {
+    source: &'a ArchiveSource,
+    handle: &'a Handle,
+    path: &'a str,
+    chunks: std::slice::Iter<'a, String>,
+    buffer: Vec<u8>,
+    position: usize,
+    length: u64,
+    hasher: Sha256,
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            let Some(chunk_hash) = self.chunks.next() else {
+                return Ok(0);
+            };
+
+            let source = self.source;
+            let bytes = self
+                .handle
+                .block_on(fetch_verified_chunk(
+                    &source.fs,
+                    &chunk_path(&source.key, chunk_hash, source.chunk_dir_levels),
+                    chunk_hash,
+                    source.password.as_deref(),
+                ))
+                .map_err(|e| {
+                    std::io::Error::other(format!(
+                        "Failed to read chunk {} of '{}' ({}): {}",
+                        chunk_hash,
+                        self.path,
+                        e.reason_code(),
+                        e.detail()
+                    ))
+                })?;
+
+            self.hasher.update(&bytes);
+            self.length += bytes.len() as u64;
+            self.buffer = bytes;
+            self.position = 0;
+        }
+
+        let count = buf.len().min(self.buffer.len() - self.position);
+        buf[..count].copy_from_slice(&self.buffer[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
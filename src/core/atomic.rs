@@ -0,0 +1,215 @@
+use crate::output::{clear_exit_hook, set_exit_hook};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+const EXIT_HOOK_NAME: &str = "atomic-restore";
+
+/// A `gib restore --atomic` in progress: files are restored into a staging directory
+/// next to the target, which only replaces the target once everything was written. If
+/// gib exits with an error before `commit`, the staging directory is removed and the
+/// target is left untouched.
+pub(crate) struct AtomicRestore {
+    target: PathBuf,
+    staging: PathBuf,
+}
+
+impl AtomicRestore {
+    /// Creates the staging directory. It lives in the parent of `target` so that both
+    /// are on the same filesystem and the final swap is a rename.
+    pub(crate) fn prepare(target: &Path) -> Result<Self, String> {
+        let name = target
+            .file_name()
+            .ok_or_else(|| format!("Cannot restore atomically into {}", target.display()))?;
+        let parent = target
+            .parent()
+            .ok_or_else(|| format!("Cannot restore atomically into {}", target.display()))?;
+
+        std::fs::create_dir_all(parent).map_err(|e| {
+            format!(
+                "Failed to create parent directory {}: {}",
+                parent.display(),
+                e
+            )
+        })?;
+
+        if let Ok(metadata) = std::fs::symlink_metadata(target) {
+            if !metadata.is_dir() {
+                return Err(format!(
+                    "Cannot restore atomically into {}: it exists and is not a directory",
+                    target.display()
+                ));
+            }
+
+            if !same_filesystem(target, parent) {
+                return Err(format!(
+                    "Cannot restore atomically into {}: it is a mount point, so it cannot be swapped with a directory of its parent",
+                    target.display()
+                ));
+            }
+        }
+
+        let staging = parent.join(format!(
+            ".{}.gib-atomic-{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging).map_err(|e| {
+                format!(
+                    "Failed to remove leftover staging directory {}: {}",
+                    staging.display(),
+                    e
+                )
+            })?;
+        }
+
+        std::fs::create_dir(&staging).map_err(|e| {
+            format!(
+                "Failed to create staging directory {}: {}",
+                staging.display(),
+                e
+            )
+        })?;
+
+        let rollback_path = staging.clone();
+        set_exit_hook(
+            EXIT_HOOK_NAME,
+            Box::new(move || {
+                let _ = std::fs::remove_dir_all(&rollback_path);
+            }),
+        );
+
+        info!(target = %target.display(), staging = %staging.display(), "atomic restore prepared");
+
+        Ok(AtomicRestore {
+            target: target.to_path_buf(),
+            staging,
+        })
+    }
+
+    pub(crate) fn staging_path(&self) -> &Path {
+        &self.staging
+    }
+
+    /// Moves the staging directory into place. The previous content of the target is
+    /// removed afterwards; failing to remove it is returned as `Ok(Some(warning))` since
+    /// the restore itself already succeeded.
+    pub(crate) fn commit(self) -> Result<Option<String>, String> {
+        let replaced = if self.target.exists() {
+            self.swap()?;
+            true
+        } else {
+            std::fs::rename(&self.staging, &self.target).map_err(|e| {
+                format!(
+                    "Failed to move {} to {}: {}",
+                    self.staging.display(),
+                    self.target.display(),
+                    e
+                )
+            })?;
+            false
+        };
+
+        clear_exit_hook(EXIT_HOOK_NAME);
+        info!(target = %self.target.display(), replaced, "atomic restore committed");
+
+        if !replaced {
+            return Ok(None);
+        }
+
+        // After the swap the staging path holds the previous content of the target.
+        match std::fs::remove_dir_all(&self.staging) {
+            Ok(()) => Ok(None),
+            Err(e) => Ok(Some(format!(
+                "The restore completed, but the previous content of {} could not be removed from {}: {}",
+                self.target.display(),
+                self.staging.display(),
+                e
+            ))),
+        }
+    }
+
+    /// Exchanges the staging directory and the target in one step where the platform
+    /// supports it, so the target never goes missing. Elsewhere the target is moved
+    /// aside first and put back if the second rename fails.
+    fn swap(&self) -> Result<(), String> {
+        #[cfg(target_os = "linux")]
+        if exchange(&self.staging, &self.target) {
+            return Ok(());
+        }
+
+        let mut aside = self.staging.clone().into_os_string();
+        aside.push(".old");
+        let aside = PathBuf::from(aside);
+
+        std::fs::rename(&self.target, &aside)
+            .map_err(|e| format!("Failed to move {} aside: {}", self.target.display(), e))?;
+
+        if let Err(e) = std::fs::rename(&self.staging, &self.target) {
+            let _ = std::fs::rename(&aside, &self.target);
+            return Err(format!(
+                "Failed to move {} to {}: {}",
+                self.staging.display(),
+                self.target.display(),
+                e
+            ));
+        }
+
+        std::fs::rename(&aside, &self.staging).map_err(|e| {
+            format!(
+                "The restore completed, but the previous content of {} was left in {}: {}",
+                self.target.display(),
+                aside.display(),
+                e
+            )
+        })
+    }
+}
+
+/// `renameat2(RENAME_EXCHANGE)`; returns false when the kernel or filesystem does not
+/// support it, in which case the caller falls back to plain renames.
+#[cfg(target_os = "linux")]
+fn exchange(a: &Path, b: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (Ok(a), Ok(b)) = (
+        CString::new(a.as_os_str().as_bytes()),
+        CString::new(b.as_os_str().as_bytes()),
+    ) else {
+        return false;
+    };
+
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+
+    result == 0
+}
+
+/// Without device ids to compare, the paths are assumed to share a filesystem and a
+/// failing rename reports the problem instead.
+fn same_filesystem(_a: &Path, _b: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        match (std::fs::metadata(_a), std::fs::metadata(_b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev(),
+            _ => true,
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        true
+    }
+}
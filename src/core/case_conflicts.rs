@@ -0,0 +1,201 @@
+use crate::core::metadata::BackupObject;
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+type RestoreFiles = Vec<(String, BackupObject)>;
+
+/// What `gib restore` does with backup paths that only differ by case when the target
+/// filesystem cannot hold them side by side (`File.txt` and `file.txt` on macOS or
+/// Windows).
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConflictResolution {
+    /// Abort before writing anything and report the colliding sets.
+    Fail,
+    /// Restore every path, appending ` (case conflict N)` to all but the first.
+    Rename,
+    /// Restore only the first path of each set.
+    Skip,
+}
+
+impl ConflictResolution {
+    pub(crate) fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("fail") => Ok(ConflictResolution::Fail),
+            Some("rename") => Ok(ConflictResolution::Rename),
+            Some("skip") => Ok(ConflictResolution::Skip),
+            Some(other) => Err(format!(
+                "Unknown value for --resolve-conflicts: '{}' (expected fail, rename or skip)",
+                other
+            )),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConflictResolution::Fail => "fail",
+            ConflictResolution::Rename => "rename",
+            ConflictResolution::Skip => "skip",
+        }
+    }
+}
+
+/// A set of backup paths that map to the same file on a case-insensitive target.
+/// `restored_as` lists where each restored path ended up, in the order of `paths`.
+#[derive(serde::Serialize)]
+pub(crate) struct CaseConflict {
+    pub(crate) paths: Vec<String>,
+    pub(crate) resolution: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) restored_as: Vec<String>,
+}
+
+impl std::fmt::Display for CaseConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.paths.join(", "))?;
+
+        if !self.restored_as.is_empty() && self.restored_as != self.paths {
+            write!(f, " -> {}", self.restored_as.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Finds paths among `files` that collide once case is ignored and, if the target
+/// directory is case-insensitive, resolves them according to `resolution`. The target
+/// is only probed when such paths exist. With `ConflictResolution::Fail` the files are
+/// returned untouched along with the conflicts, and the caller is expected to abort.
+pub(crate) fn resolve_case_conflicts(
+    files: RestoreFiles,
+    target_path: &Path,
+    resolution: ConflictResolution,
+) -> Result<(RestoreFiles, Vec<CaseConflict>), String> {
+    let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (index, (path, _)) in files.iter().enumerate() {
+        groups.entry(path.to_lowercase()).or_default().push(index);
+    }
+
+    let mut colliding = groups
+        .into_values()
+        .filter(|indexes| indexes.len() > 1)
+        .collect::<Vec<Vec<usize>>>();
+
+    if colliding.is_empty() || !is_case_insensitive(target_path)? {
+        return Ok((files, Vec::new()));
+    }
+
+    for indexes in &mut colliding {
+        indexes.sort_by(|a, b| files[*a].0.cmp(&files[*b].0));
+    }
+
+    let mut taken = files
+        .iter()
+        .map(|(path, _)| path.to_lowercase())
+        .collect::<HashSet<String>>();
+    let mut dropped = HashSet::new();
+    let mut renamed = Vec::new();
+    let mut conflicts = Vec::new();
+
+    for indexes in colliding {
+        let paths = indexes
+            .iter()
+            .map(|index| files[*index].0.clone())
+            .collect::<Vec<String>>();
+
+        let restored_as = match resolution {
+            ConflictResolution::Fail => Vec::new(),
+            ConflictResolution::Skip => {
+                dropped.extend(indexes.iter().skip(1).copied());
+                vec![paths[0].clone()]
+            }
+            ConflictResolution::Rename => {
+                let mut restored_as = vec![paths[0].clone()];
+                for index in indexes.iter().skip(1) {
+                    let new_path = conflict_free_path(&files[*index].0, &mut taken);
+                    renamed.push((*index, new_path.clone()));
+                    restored_as.push(new_path);
+                }
+                restored_as
+            }
+        };
+
+        conflicts.push(CaseConflict {
+            paths,
+            resolution: resolution.as_str(),
+            restored_as,
+        });
+    }
+
+    let mut files = files;
+    for (index, new_path) in renamed {
+        files[index].0 = new_path;
+    }
+
+    let files = files
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !dropped.contains(index))
+        .map(|(_, file)| file)
+        .collect();
+
+    Ok((files, conflicts))
+}
+
+/// Inserts ` (case conflict N)` before the extension of the file name, picking the
+/// first N that no other restored path uses (ignoring case).
+fn conflict_free_path(path: &str, taken: &mut HashSet<String>) -> String {
+    let (parent, name) = match path.rsplit_once('/') {
+        Some((parent, name)) => (format!("{}/", parent), name),
+        None => (String::new(), path),
+    };
+
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+
+    let mut attempt = 1;
+    loop {
+        let candidate = format!(
+            "{}{} (case conflict {}){}",
+            parent, stem, attempt, extension
+        );
+        if taken.insert(candidate.to_lowercase()) {
+            return candidate;
+        }
+        attempt += 1;
+    }
+}
+
+/// Probes the target instead of guessing from the OS: macOS volumes can be
+/// case-sensitive and Linux can mount case-insensitive filesystems.
+fn is_case_insensitive(target_path: &Path) -> Result<bool, String> {
+    std::fs::create_dir_all(target_path).map_err(|e| {
+        format!(
+            "Failed to create target directory {}: {}",
+            target_path.display(),
+            e
+        )
+    })?;
+
+    let probe_name = format!(".gib-case-probe-{}", std::process::id());
+    let probe_path = target_path.join(&probe_name);
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe_path)
+        .map_err(|e| {
+            format!(
+                "Failed to probe the case sensitivity of {}: {}",
+                target_path.display(),
+                e
+            )
+        })?;
+
+    let insensitive = target_path.join(probe_name.to_uppercase()).exists();
+
+    let _ = std::fs::remove_file(&probe_path);
+
+    Ok(insensitive)
+}
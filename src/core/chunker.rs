@@ -0,0 +1,412 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::io::{ErrorKind, Read};
+
+/// How `backup` splits files into chunks. Either way a chunk is stored under the SHA-256
+/// of its bytes, so repositories can mix both and restore does not need to know which
+/// one was used.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Copy, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Chunker {
+    /// Slices of `size` bytes; the last one holds the rest of the file.
+    Fixed { size: u64 },
+    /// FastCDC: boundaries are picked by a rolling hash of the content, so inserting or
+    /// removing bytes only changes the chunks around the edit.
+    Cdc {
+        min_size: u64,
+        avg_size: u64,
+        max_size: u64,
+    },
+}
+
+/// Smallest `min_size` accepted for content-defined chunking; the rolling hash looks at
+/// the last 64 bytes.
+pub(crate) const MIN_CDC_SIZE: u64 = 64;
+
+impl Chunker {
+    /// Checks the content-defined sizes are ordered and usable.
+    pub(crate) fn cdc(min_size: u64, avg_size: u64, max_size: u64) -> Result<Self, String> {
+        if min_size < MIN_CDC_SIZE {
+            return Err(format!(
+                "The minimum chunk size must be at least {} bytes",
+                MIN_CDC_SIZE
+            ));
+        }
+
+        if !(min_size <= avg_size && avg_size <= max_size) {
+            return Err(format!(
+                "Chunk sizes must satisfy min <= average <= max (got {} <= {} <= {})",
+                min_size, avg_size, max_size
+            ));
+        }
+
+        if max_size > usize::MAX as u64 {
+            return Err(format!("The maximum chunk size {} is too large", max_size));
+        }
+
+        Ok(Chunker::Cdc {
+            min_size,
+            avg_size,
+            max_size,
+        })
+    }
+
+    /// The size chunks are expected to have: the fixed size or the average.
+    pub(crate) fn target_size(&self) -> u64 {
+        match self {
+            Chunker::Fixed { size } => *size,
+            Chunker::Cdc { avg_size, .. } => *avg_size,
+        }
+    }
+
+    pub(crate) fn fixed_size(&self) -> Option<u64> {
+        match self {
+            Chunker::Fixed { size } => Some(*size),
+            Chunker::Cdc { .. } => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Chunker::Fixed { .. } => "fixed",
+            Chunker::Cdc { .. } => "cdc",
+        }
+    }
+
+    pub(crate) fn reader<R: Read>(self, reader: R) -> ChunkReader<R> {
+        let capacity = match self {
+            Chunker::Fixed { size } => size,
+            Chunker::Cdc { max_size, .. } => max_size,
+        };
+
+        ChunkReader {
+            reader,
+            cut: Cut::new(self),
+            buffer: vec![0u8; capacity as usize],
+            start: 0,
+            end: 0,
+            eof: false,
+        }
+    }
+}
+
+/// Reads a file chunk by chunk. A chunk only ends short of the chunker's size at the end
+/// of the file, whatever the reads returned, so the same content always splits the same.
+pub(crate) struct ChunkReader<R> {
+    reader: R,
+    cut: Cut,
+    buffer: Vec<u8>,
+    /// The bytes read but not handed out yet are `buffer[start..end]`.
+    start: usize,
+    end: usize,
+    eof: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    /// The next chunk, or `None` at the end of the file (so an empty file has no chunks).
+    pub(crate) fn next_chunk(&mut self) -> std::io::Result<Option<&[u8]>> {
+        if !self.eof && self.end - self.start < self.buffer.len() {
+            self.buffer.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+            self.fill()?;
+        }
+
+        if self.start == self.end {
+            return Ok(None);
+        }
+
+        let start = self.start;
+        let length = self.cut.find(&self.buffer[start..self.end]);
+        self.start += length;
+
+        Ok(Some(&self.buffer[start..start + length]))
+    }
+
+    fn fill(&mut self) -> std::io::Result<()> {
+        while self.end < self.buffer.len() {
+            match self.reader.read(&mut self.buffer[self.end..]) {
+                Ok(0) => {
+                    self.eof = true;
+                    break;
+                }
+                Ok(bytes_read) => self.end += bytes_read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+enum Cut {
+    Fixed,
+    Cdc {
+        min_size: usize,
+        avg_size: usize,
+        /// Checked before the average size; more bits make an early cut less likely.
+        mask_small: u64,
+        /// Checked after the average size; fewer bits make a cut more likely.
+        mask_large: u64,
+    },
+}
+
+impl Cut {
+    fn new(chunker: Chunker) -> Self {
+        match chunker {
+            Chunker::Fixed { .. } => Cut::Fixed,
+            Chunker::Cdc {
+                min_size, avg_size, ..
+            } => {
+                // A cut every 2^bits bytes on average, with normalization level 2.
+                let bits = (avg_size as f64).log2().round() as u32;
+
+                Cut::Cdc {
+                    min_size: min_size as usize,
+                    avg_size: avg_size as usize,
+                    mask_small: high_bits_mask(bits + 2),
+                    mask_large: high_bits_mask(bits.saturating_sub(2).max(1)),
+                }
+            }
+        }
+    }
+
+    /// The length of the chunk at the start of `data`, which holds at least a full chunk
+    /// unless the file ends in it.
+    fn find(&self, data: &[u8]) -> usize {
+        let Cut::Cdc {
+            min_size,
+            avg_size,
+            mask_small,
+            mask_large,
+        } = *self
+        else {
+            return data.len();
+        };
+
+        if data.len() <= min_size {
+            return data.len();
+        }
+
+        let normal_size = avg_size.min(data.len());
+        let mut hash: u64 = 0;
+
+        for (i, byte) in data.iter().enumerate().take(normal_size).skip(min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & mask_small == 0 {
+                return i + 1;
+            }
+        }
+
+        for (i, byte) in data.iter().enumerate().skip(normal_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & mask_large == 0 {
+                return i + 1;
+            }
+        }
+
+        data.len()
+    }
+}
+
+/// The top `bits` bits, which the gear hash mixes from the last 64 bytes.
+fn high_bits_mask(bits: u32) -> u64 {
+    let bits = bits.min(63);
+    !(u64::MAX >> bits)
+}
+
+/// Pseudo-random values for the gear hash, from a fixed seed so boundaries are the same
+/// on every run and machine: changing them would move every boundary and stop
+/// deduplication against chunks stored before.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6769_622d_6364_6331;
+    let mut i = 0;
+
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// xorshift64 bytes: the same input on every run, without repeating patterns.
+    fn content(length: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..length)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks<R: Read>(chunker: Chunker, reader: R) -> Vec<Vec<u8>> {
+        let mut reader = chunker.reader(reader);
+        let mut chunks = Vec::new();
+        while let Some(chunk) = reader.next_chunk().unwrap() {
+            chunks.push(chunk.to_vec());
+        }
+        chunks
+    }
+
+    fn lengths(chunks: &[Vec<u8>]) -> Vec<usize> {
+        chunks.iter().map(|chunk| chunk.len()).collect()
+    }
+
+    fn cdc() -> Chunker {
+        Chunker::cdc(256, 1024, 4096).unwrap()
+    }
+
+    /// Hands out at most 7 bytes per read and is interrupted every few calls, like a
+    /// pipe or a slow network filesystem.
+    struct ShortReads<'a> {
+        data: &'a [u8],
+        calls: usize,
+    }
+
+    impl Read for ShortReads<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(5) {
+                return Err(std::io::Error::from(ErrorKind::Interrupted));
+            }
+
+            let length = buf.len().min(self.data.len()).min(1 + self.calls % 7);
+            buf[..length].copy_from_slice(&self.data[..length]);
+            self.data = &self.data[length..];
+            Ok(length)
+        }
+    }
+
+    #[test]
+    fn cdc_boundaries_are_pinned() {
+        // A change here moves every boundary, so repositories written before it would
+        // stop sharing chunks with new backups.
+        let data = content(20_000, 0x5eed);
+        let chunks = chunks(cdc(), data.as_slice());
+
+        assert_eq!(
+            lengths(&chunks),
+            vec![
+                1005, 1039, 1386, 1113, 1083, 1199, 1054, 1789, 1037, 388, 1272, 351, 1211, 944,
+                1235, 1256, 1042, 1086, 510,
+            ]
+        );
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn cdc_chunks_stay_within_the_sizes() {
+        let data = content(200_000, 7);
+        let chunks = chunks(cdc(), data.as_slice());
+        let (last, rest) = chunks.split_last().unwrap();
+
+        assert!(rest.iter().all(|chunk| (256..=4096).contains(&chunk.len())));
+        assert!(last.len() <= 4096);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn small_file_is_a_single_chunk() {
+        let data = content(100, 1);
+
+        assert_eq!(chunks(cdc(), data.as_slice()), vec![data.clone()]);
+        assert_eq!(
+            chunks(Chunker::Fixed { size: 4096 }, data.as_slice()),
+            vec![data]
+        );
+    }
+
+    #[test]
+    fn empty_file_has_no_chunks() {
+        assert!(chunks(cdc(), &[][..]).is_empty());
+        assert!(chunks(Chunker::Fixed { size: 4096 }, &[][..]).is_empty());
+    }
+
+    #[test]
+    fn fixed_chunks_are_sliced_by_size() {
+        let data = content(10_000, 3);
+        let chunks = chunks(Chunker::Fixed { size: 4096 }, data.as_slice());
+
+        assert_eq!(lengths(&chunks), vec![4096, 4096, 1808]);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[test]
+    fn insertion_only_changes_nearby_chunks() {
+        let data = content(100_000, 11);
+        let mut edited = data.clone();
+        edited.splice(50_000..50_000, content(100, 12));
+
+        let before = chunks(cdc(), data.as_slice());
+        let after = chunks(cdc(), edited.as_slice());
+
+        let shared_prefix = before
+            .iter()
+            .zip(&after)
+            .take_while(|(a, b)| a == b)
+            .count();
+        let shared_suffix = before
+            .iter()
+            .rev()
+            .zip(after.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        // Everything up to the edit is untouched, and the boundaries resync right after
+        // it, so only a couple of chunks around byte 50 000 differ.
+        let prefix_bytes: usize = before[..shared_prefix].iter().map(Vec::len).sum();
+        assert!(prefix_bytes + 4096 > 50_000);
+        assert!(before.len() - shared_prefix - shared_suffix <= 3);
+        assert!(after.len() - shared_prefix - shared_suffix <= 3);
+
+        // Fixed-size chunks, by contrast, all shift after the edit.
+        let fixed = Chunker::Fixed { size: 1024 };
+        let before = chunks(fixed, data.as_slice());
+        let after = chunks(fixed, edited.as_slice());
+        let shared = before.iter().filter(|chunk| after.contains(chunk)).count();
+        assert!(shared <= 50);
+    }
+
+    #[test]
+    fn short_reads_give_the_same_boundaries() {
+        let data = content(50_000, 21);
+        let short = ShortReads {
+            data: &data,
+            calls: 0,
+        };
+
+        assert_eq!(chunks(cdc(), short), chunks(cdc(), data.as_slice()));
+
+        let short = ShortReads {
+            data: &data,
+            calls: 0,
+        };
+        let fixed = Chunker::Fixed { size: 4096 };
+        assert_eq!(chunks(fixed, short), chunks(fixed, data.as_slice()));
+    }
+
+    #[test]
+    fn cdc_sizes_are_validated() {
+        assert!(Chunker::cdc(MIN_CDC_SIZE - 1, 1024, 4096).is_err());
+        assert!(Chunker::cdc(2048, 1024, 4096).is_err());
+        assert!(Chunker::cdc(256, 8192, 4096).is_err());
+        assert!(Chunker::cdc(MIN_CDC_SIZE, MIN_CDC_SIZE, MIN_CDC_SIZE).is_ok());
+    }
+}
@@ -0,0 +1,79 @@
+use crate::core::crypto::read_file_maybe_decrypt;
+use crate::fs::FS;
+use crate::utils::decode_chunk;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+/// Why a chunk could not be read back as the data it was stored for.
+pub(crate) enum ChunkReadError {
+    Missing,
+    Decrypt(String),
+    Decompress(String),
+    HashMismatch(String),
+}
+
+impl ChunkReadError {
+    pub(crate) fn reason_code(&self) -> &'static str {
+        match self {
+            ChunkReadError::Missing => "missing_chunk",
+            ChunkReadError::Decrypt(_) => "decrypt_error",
+            ChunkReadError::Decompress(_) => "decompress_error",
+            ChunkReadError::HashMismatch(_) => "corrupt_chunk",
+        }
+    }
+
+    pub(crate) fn detail(&self) -> &str {
+        match self {
+            ChunkReadError::Missing => "missing",
+            ChunkReadError::Decrypt(e)
+            | ChunkReadError::Decompress(e)
+            | ChunkReadError::HashMismatch(e) => e,
+        }
+    }
+}
+
+/// Reads a chunk and decrypts it, leaving it compressed (or stored, see `encode_chunk`) for
+/// the caller to decode and check against its hash.
+pub(crate) async fn fetch_chunk(
+    fs: &Arc<dyn FS>,
+    chunk_path: &str,
+    password: Option<&str>,
+) -> Result<Vec<u8>, ChunkReadError> {
+    let chunk_data = read_file_maybe_decrypt(
+        fs,
+        chunk_path,
+        password,
+        "Chunk is encrypted but no password provided",
+    )
+    .await
+    .map_err(ChunkReadError::Decrypt)?;
+
+    if chunk_data.bytes.is_empty() {
+        return Err(ChunkReadError::Missing);
+    }
+
+    Ok(chunk_data.bytes)
+}
+
+/// Reads a chunk and checks it decrypts, decompresses and hashes back to `chunk_hash`.
+pub(crate) async fn fetch_verified_chunk(
+    fs: &Arc<dyn FS>,
+    chunk_path: &str,
+    chunk_hash: &str,
+    password: Option<&str>,
+) -> Result<Vec<u8>, ChunkReadError> {
+    let compressed = fetch_chunk(fs, chunk_path, password).await?;
+
+    let decompressed = decode_chunk(&compressed)
+        .map_err(|e| ChunkReadError::Decompress(format!("failed to decompress: {}", e)))?;
+
+    let actual_hash = format!("{:x}", Sha256::digest(&decompressed));
+    if actual_hash != chunk_hash {
+        return Err(ChunkReadError::HashMismatch(format!(
+            "hash mismatch (got {})",
+            actual_hash
+        )));
+    }
+
+    Ok(decompressed)
+}
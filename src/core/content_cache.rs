@@ -0,0 +1,97 @@
+//! `gib backup --chunk-cache-size`: trees like `node_modules` hold many files with the
+//! same content. The chunks of recently backed up files are remembered by size and first
+//! bytes, so a file that looks like one of them only has its full hash computed to
+//! confirm it, instead of being split, hashed chunk by chunk and looked up again.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+/// Bytes from the start of a file that are part of its key.
+const PREFIX_LEN: u64 = 4096;
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub(crate) struct ContentKey {
+    size: u64,
+    prefix: Vec<u8>,
+}
+
+impl ContentKey {
+    /// Reads the key of `file`, which is `size` bytes long, and rewinds it.
+    pub(crate) fn read<R: Read + Seek>(file: &mut R, size: u64) -> std::io::Result<Self> {
+        let mut prefix = Vec::with_capacity(size.min(PREFIX_LEN) as usize);
+        file.by_ref().take(PREFIX_LEN).read_to_end(&mut prefix)?;
+        file.seek(SeekFrom::Start(0))?;
+
+        Ok(ContentKey { size, prefix })
+    }
+}
+
+/// What a file with that key was stored as: its full hash, to confirm a match, and its
+/// chunks.
+#[derive(Clone)]
+pub(crate) struct CachedContent {
+    pub(crate) hash: String,
+    pub(crate) chunks: Vec<String>,
+}
+
+/// An LRU of at most `capacity` file contents, shared by the files backed up at once.
+pub(crate) struct ContentCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    next_use: u64,
+    by_key: HashMap<ContentKey, (u64, CachedContent)>,
+    /// Keys by last use, oldest first.
+    by_use: BTreeMap<u64, ContentKey>,
+}
+
+impl ContentCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ContentCache {
+            capacity,
+            entries: Mutex::new(Entries {
+                next_use: 0,
+                by_key: HashMap::new(),
+                by_use: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &ContentKey) -> Option<CachedContent> {
+        let mut entries = self.entries.lock().unwrap();
+        let next_use = entries.next_use;
+
+        let (last_use, content) = entries.by_key.get_mut(key)?;
+        let previous_use = std::mem::replace(last_use, next_use);
+        let content = content.clone();
+
+        entries.by_use.remove(&previous_use);
+        entries.by_use.insert(next_use, key.clone());
+        entries.next_use += 1;
+
+        Some(content)
+    }
+
+    /// Remembers `content` under `key`, replacing what was there and evicting the least
+    /// recently used entry when the cache is full.
+    pub(crate) fn insert(&self, key: ContentKey, content: CachedContent) {
+        let mut entries = self.entries.lock().unwrap();
+        let next_use = entries.next_use;
+        entries.next_use += 1;
+
+        if let Some((previous_use, _)) = entries.by_key.insert(key.clone(), (next_use, content)) {
+            entries.by_use.remove(&previous_use);
+        }
+        entries.by_use.insert(next_use, key);
+
+        while entries.by_key.len() > self.capacity {
+            let Some((_, oldest)) = entries.by_use.pop_first() else {
+                break;
+            };
+            entries.by_key.remove(&oldest);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::core::metadata::BackupObject;
+use std::collections::{BTreeMap, HashSet};
+
+type RestoreFiles = Vec<(String, BackupObject)>;
+
+/// What `gib restore --flatten` does when files from different folders share a name.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CollisionPolicy {
+    /// Restore every file, appending ` (N)` to the name of all but the first.
+    Number,
+    /// Restore only the first file with each name.
+    Skip,
+    /// Abort before writing anything and report the colliding names.
+    Fail,
+}
+
+impl CollisionPolicy {
+    pub(crate) fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value {
+            None | Some("number") => Ok(CollisionPolicy::Number),
+            Some("skip") => Ok(CollisionPolicy::Skip),
+            Some("fail") => Ok(CollisionPolicy::Fail),
+            Some(other) => Err(format!(
+                "Unknown value for --on-collision: '{}' (expected number, skip or fail)",
+                other
+            )),
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            CollisionPolicy::Number => "number",
+            CollisionPolicy::Skip => "skip",
+            CollisionPolicy::Fail => "fail",
+        }
+    }
+}
+
+/// Backup paths that have the same file name once flattened. `restored_as` lists the
+/// name each path was restored under, in the order of `paths`, or only the first one
+/// when the others were skipped.
+#[derive(serde::Serialize)]
+pub(crate) struct FlattenCollision {
+    pub(crate) name: String,
+    pub(crate) paths: Vec<String>,
+    pub(crate) resolution: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) restored_as: Vec<String>,
+}
+
+impl std::fmt::Display for FlattenCollision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.name, self.paths.join(", "))?;
+
+        if self.restored_as.len() > 1 {
+            write!(f, " -> {}", self.restored_as.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Replaces the path of every file with its file name, so they are all restored
+/// directly into the target. Files are ordered by path first, so the first path of a
+/// colliding set keeps the name. With `CollisionPolicy::Fail` the caller is expected to
+/// abort when collisions are returned.
+pub(crate) fn flatten_files(
+    files: RestoreFiles,
+    policy: CollisionPolicy,
+) -> (RestoreFiles, Vec<FlattenCollision>) {
+    let mut files = files;
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (index, (path, _)) in files.iter().enumerate() {
+        groups
+            .entry(file_name(path).to_string())
+            .or_default()
+            .push(index);
+    }
+
+    let mut taken = groups.keys().cloned().collect::<HashSet<String>>();
+    let mut restored_names = vec![None; files.len()];
+    let mut collisions = Vec::new();
+
+    for (name, indexes) in groups {
+        restored_names[indexes[0]] = Some(name.clone());
+
+        if indexes.len() == 1 {
+            continue;
+        }
+
+        let paths = indexes
+            .iter()
+            .map(|index| files[*index].0.clone())
+            .collect::<Vec<String>>();
+
+        let restored_as = match policy {
+            CollisionPolicy::Fail => Vec::new(),
+            CollisionPolicy::Skip => vec![name.clone()],
+            CollisionPolicy::Number => {
+                let mut restored_as = vec![name.clone()];
+                for index in indexes.iter().skip(1) {
+                    let numbered_name = numbered_name(&name, &mut taken);
+                    restored_names[*index] = Some(numbered_name.clone());
+                    restored_as.push(numbered_name);
+                }
+                restored_as
+            }
+        };
+
+        collisions.push(FlattenCollision {
+            name,
+            paths,
+            resolution: policy.as_str(),
+            restored_as,
+        });
+    }
+
+    let files = files
+        .into_iter()
+        .zip(restored_names)
+        .filter_map(|((_, backup_object), name)| Some((name?, backup_object)))
+        .collect();
+
+    (files, collisions)
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Inserts ` (N)` before the extension, picking the first N no other file uses.
+fn numbered_name(name: &str, taken: &mut HashSet<String>) -> String {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+
+    let mut attempt = 1;
+    loop {
+        let candidate = format!("{} ({}){}", stem, attempt, extension);
+        if taken.insert(candidate.clone()) {
+            return candidate;
+        }
+        attempt += 1;
+    }
+}
@@ -0,0 +1,212 @@
+use crate::output::{
+    clear_exit_hook, emit_error, emit_hook, emit_warning, is_json_mode, set_exit_hook,
+};
+use crate::utils::handle_error;
+use clap::ArgMatches;
+use console::style;
+use std::process::Command;
+use tracing::info;
+
+/// The captured result of a `--pre-hook` / `--post-hook` command.
+#[derive(serde::Serialize)]
+pub(crate) struct HookResult {
+    pub(crate) hook: &'static str,
+    pub(crate) command: String,
+    /// `None` when the command was killed by a signal.
+    pub(crate) exit_code: Option<i32>,
+    pub(crate) stdout: String,
+    pub(crate) stderr: String,
+}
+
+impl HookResult {
+    pub(crate) fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    pub(crate) fn status(&self) -> String {
+        match self.exit_code {
+            Some(code) => format!("exit code {}", code),
+            None => "killed by a signal".to_string(),
+        }
+    }
+}
+
+/// Runs `command` through the platform shell with `envs` added to its environment, waits
+/// for it, and reports its output as a `hook` event in JSON mode or on the terminal
+/// otherwise.
+pub(crate) fn run_hook(
+    hook: &'static str,
+    command: &str,
+    envs: &[(String, String)],
+) -> Result<HookResult, String> {
+    info!(hook, command, "running hook");
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+
+    let output = shell
+        .arg(command)
+        .envs(envs.iter().map(|(name, value)| (name, value)))
+        .output()
+        .map_err(|e| format!("Failed to run {} '{}': {}", hook, command, e))?;
+
+    let result = HookResult {
+        hook,
+        command: command.to_string(),
+        exit_code: output.status.code(),
+        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+    };
+
+    info!(hook, exit_code = ?result.exit_code, "hook finished");
+
+    if is_json_mode() {
+        emit_hook(&result);
+    } else {
+        println!(
+            "{}",
+            style(format!("{}: {} ({})", hook, command, result.status())).dim()
+        );
+        for line in result.stdout.lines() {
+            println!("  {}", line);
+        }
+        for line in result.stderr.lines() {
+            eprintln!("  {}", style(line).yellow());
+        }
+    }
+
+    Ok(result)
+}
+
+/// The `--pre-hook` / `--post-hook` pair of a command. Every variable is exported as
+/// `GIB_<OPERATION>_<NAME>` (`GIB_BACKUP_KEY`, `GIB_RESTORE_STATUS`, ...) along with
+/// `GIB_HOOK` (`pre` or `post`).
+pub(crate) struct Hooks {
+    operation: &'static str,
+    pre_hook: Option<String>,
+    post_hook: Option<String>,
+    envs: Vec<(String, String)>,
+}
+
+impl Hooks {
+    /// Returns `None` when neither hook was given.
+    pub(crate) fn from_matches(operation: &'static str, matches: &ArgMatches) -> Option<Self> {
+        let pre_hook = matches.get_one::<String>("pre-hook").cloned();
+        let post_hook = matches.get_one::<String>("post-hook").cloned();
+
+        if pre_hook.is_none() && post_hook.is_none() {
+            return None;
+        }
+
+        Some(Hooks {
+            operation,
+            pre_hook,
+            post_hook,
+            envs: Vec::new(),
+        })
+    }
+
+    pub(crate) fn with_env(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.envs.push((self.env_name(name), value.into()));
+        self
+    }
+
+    fn env_name(&self, name: &str) -> String {
+        format!("GIB_{}_{}", self.operation.to_uppercase(), name)
+    }
+
+    fn envs(&self, hook: &str, extra: &[(&str, String)]) -> Vec<(String, String)> {
+        let mut envs = self.envs.clone();
+        envs.push(("GIB_HOOK".to_string(), hook.to_string()));
+        envs.extend(
+            extra
+                .iter()
+                .map(|(name, value)| (self.env_name(name), value.clone())),
+        );
+        envs
+    }
+
+    /// Arms the post-hook to run with status `failure` if gib exits with an error from
+    /// here on, then runs the pre-hook and aborts if it fails.
+    pub(crate) fn run_pre_hook(&self) {
+        if let Some(post_hook) = &self.post_hook {
+            let post_hook = post_hook.clone();
+            let envs = self.envs(
+                "post",
+                &[
+                    ("STATUS", "failure".to_string()),
+                    ("EXIT_CODE", "1".to_string()),
+                ],
+            );
+
+            set_exit_hook(
+                "post-hook",
+                Box::new(move || {
+                    if let Err(e) = run_hook("post-hook", &post_hook, &envs) {
+                        emit_warning(&e, "post_hook_failed");
+                    }
+                }),
+            );
+        }
+
+        let Some(pre_hook) = &self.pre_hook else {
+            return;
+        };
+
+        let result = match run_hook("pre-hook", pre_hook, &self.envs("pre", &[])) {
+            Ok(result) => result,
+            Err(e) => {
+                if is_json_mode() {
+                    emit_error(&e, "pre_hook_failed");
+                }
+                handle_error(e, None);
+            }
+        };
+
+        if !result.success() {
+            let message = format!(
+                "The pre-hook failed with {}, aborting the {}",
+                result.status(),
+                self.operation
+            );
+            if is_json_mode() {
+                emit_error(&message, "pre_hook_failed");
+            }
+            handle_error(message, None);
+        }
+    }
+
+    /// Disarms the failure post-hook and runs the post-hook with `status`. A failing
+    /// post-hook is only a warning since the operation itself already finished.
+    pub(crate) fn run_post_hook(&self, status: &str, extra: &[(&str, String)]) {
+        clear_exit_hook("post-hook");
+
+        let Some(post_hook) = &self.post_hook else {
+            return;
+        };
+
+        let mut extra = extra.to_vec();
+        extra.push(("STATUS", status.to_string()));
+        extra.push(("EXIT_CODE", "0".to_string()));
+
+        match run_hook("post-hook", post_hook, &self.envs("post", &extra)) {
+            Ok(result) if !result.success() => emit_warning(
+                &format!(
+                    "The {} finished but the post-hook failed with {}",
+                    self.operation,
+                    result.status()
+                ),
+                "post_hook_failed",
+            ),
+            Ok(_) => {}
+            Err(e) => emit_warning(&e, "post_hook_failed"),
+        }
+    }
+}
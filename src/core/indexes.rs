@@ -1,11 +1,19 @@
 use crate::core::crypto::{read_file_maybe_decrypt, write_file_maybe_encrypt};
-use crate::core::metadata::{Backup, BackupSummary, ChunkIndex};
+use crate::core::layout::{
+    MAX_CHUNK_DIR_LEVELS, backup_path, backup_summaries_path, chunk_index_path,
+    repository_meta_path,
+};
+use crate::core::metadata::{Backup, BackupObject, BackupSummary, ChunkIndex, RepositoryMeta};
+use crate::core::signing::remove_signature;
 use crate::fs::FS;
 use crate::utils::{compress_bytes, decompress_bytes};
+use futures::stream::{self, StreamExt};
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
 
+const MAX_CONCURRENT_MANIFEST_LOADS: usize = 16;
+
 pub(crate) async fn load_chunk_indexes(
     fs: Arc<dyn FS>,
     key: String,
@@ -14,7 +22,7 @@ pub(crate) async fn load_chunk_indexes(
 ) -> Result<HashMap<String, ChunkIndex>, String> {
     let read_result = read_file_maybe_decrypt(
         &fs,
-        format!("{}/indexes/chunks", key).as_str(),
+        chunk_index_path(&key).as_str(),
         password.as_deref(),
         "Chunk indexes are encrypted but no password provided",
     )
@@ -28,7 +36,8 @@ pub(crate) async fn load_chunk_indexes(
     let chunk_indexes: HashMap<String, ChunkIndex> = if read_result.bytes.is_empty() {
         HashMap::new()
     } else {
-        let decompressed_chunk_index_bytes = decompress_bytes(&read_result.bytes);
+        let decompressed_chunk_index_bytes = decompress_bytes(&read_result.bytes)
+            .map_err(|e| format!("Failed to decompress chunk indexes: {}", e))?;
 
         rmp_serde::from_slice(&decompressed_chunk_index_bytes)
             .map_err(|e| format!("Failed to deserialize chunk indexes: {}", e))?
@@ -44,7 +53,7 @@ pub(crate) async fn list_backup_summaries(
 ) -> Result<Vec<BackupSummary>, String> {
     let read_result = read_file_maybe_decrypt(
         &fs,
-        format!("{}/indexes/backups", key).as_str(),
+        backup_summaries_path(&key).as_str(),
         password.as_deref(),
         "Backup summaries are encrypted but no password provided",
     )
@@ -53,7 +62,8 @@ pub(crate) async fn list_backup_summaries(
     let backup_summaries: Vec<BackupSummary> = if read_result.bytes.is_empty() {
         Vec::new()
     } else {
-        let decompressed_backup_summaries_bytes = decompress_bytes(&read_result.bytes);
+        let decompressed_backup_summaries_bytes = decompress_bytes(&read_result.bytes)
+            .map_err(|e| format!("Failed to decompress backup summaries: {}", e))?;
 
         rmp_serde::from_slice(&decompressed_backup_summaries_bytes)
             .map_err(|e| format!("Failed to deserialize backup summaries: {}", e))?
@@ -62,29 +72,472 @@ pub(crate) async fn list_backup_summaries(
     Ok(backup_summaries)
 }
 
-pub(crate) fn create_new_backup(message: String, author: String) -> Backup {
-    let backup_hash = Sha256::digest(
-        format!(
-            "{}:{}:{}",
-            message,
-            author,
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs()
+/// Fills in `author` and `timestamp` for summaries written before they were recorded,
+/// reading them from the backup manifests. Manifests that can't be read are left as they
+/// are, so old entries simply keep their missing fields. Returns how many were filled in.
+pub(crate) async fn backfill_backup_summaries(
+    fs: Arc<dyn FS>,
+    key: String,
+    password: Option<String>,
+    backup_summaries: &mut [BackupSummary],
+) -> usize {
+    let incomplete_hashes = backup_summaries
+        .iter()
+        .filter(|summary| summary.author.is_none() || summary.timestamp.is_none())
+        .map(|summary| summary.hash.clone())
+        .collect::<Vec<String>>();
+
+    if incomplete_hashes.is_empty() {
+        return 0;
+    }
+
+    let loaded_backups: HashMap<String, Backup> = stream::iter(incomplete_hashes)
+        .map(|hash| {
+            let fs = Arc::clone(&fs);
+            let key = key.clone();
+            let password = password.clone();
+            async move { load_backup(fs, key, password, hash).await.ok() }
+        })
+        .buffer_unordered(MAX_CONCURRENT_MANIFEST_LOADS)
+        .filter_map(|backup| async move { backup.map(|backup| (backup.hash.clone(), backup)) })
+        .collect()
+        .await;
+
+    let mut filled = 0;
+    for summary in backup_summaries.iter_mut() {
+        if let Some(backup) = loaded_backups.get(&summary.hash) {
+            summary.author.get_or_insert_with(|| backup.author.clone());
+            summary.timestamp.get_or_insert(backup.timestamp);
+            filled += 1;
+        }
+    }
+
+    filled
+}
+
+/// Loads a backup by its hash. Backups made with `--content-addressed-manifests` have no
+/// manifest of their own, so when `backups/{hash}` is missing the summaries are searched
+/// for the content manifest it shares.
+pub(crate) async fn load_backup(
+    fs: Arc<dyn FS>,
+    key: String,
+    password: Option<String>,
+    backup_hash: String,
+) -> Result<Backup, String> {
+    if let Some(backup) = read_manifest(&fs, &key, &password, &backup_hash).await? {
+        return Ok(backup);
+    }
+
+    let backup_summaries =
+        list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await?;
+
+    match backup_summaries
+        .iter()
+        .find(|summary| summary.hash == backup_hash && summary.content.is_some())
+    {
+        Some(summary) => load_summary_backup(fs, key, password, summary).await,
+        None => Err(format!("Backup {} not found or is empty", backup_hash)),
+    }
+}
+
+/// Loads the backup of `summary`, reading its content manifest directly when it has one.
+/// The message, author and timestamp of a shared manifest are those of the first backup
+/// that wrote it, so they are taken from the summary instead.
+pub(crate) async fn load_summary_backup(
+    fs: Arc<dyn FS>,
+    key: String,
+    password: Option<String>,
+    summary: &BackupSummary,
+) -> Result<Backup, String> {
+    let mut backup = read_manifest(&fs, &key, &password, summary.manifest_hash())
+        .await?
+        .ok_or_else(|| format!("Backup {} not found or is empty", summary.hash))?;
+
+    if summary.content.is_some() {
+        backup.hash = summary.hash.clone();
+        backup.message = summary.message.clone();
+        if let Some(timestamp) = summary.timestamp {
+            backup.timestamp = timestamp;
+        }
+        if let Some(author) = &summary.author {
+            backup.author = author.clone();
+        }
+    }
+
+    Ok(backup)
+}
+
+/// Loads a backup with the files of its differential base chain filled in, so `tree` is
+/// the complete snapshot. Also returns how many base backups had to be loaded.
+pub(crate) async fn load_resolved_backup(
+    fs: Arc<dyn FS>,
+    key: String,
+    password: Option<String>,
+    backup_hash: String,
+) -> Result<(Backup, usize), String> {
+    let mut backup =
+        load_backup(Arc::clone(&fs), key.clone(), password.clone(), backup_hash).await?;
+
+    let mut chain = Vec::new();
+    let mut seen = HashSet::from([backup.hash.clone()]);
+    let mut next_base = backup.base.clone();
+
+    while let Some(base_hash) = next_base {
+        if !seen.insert(base_hash.clone()) {
+            return Err(format!(
+                "The differential chain of backup {} loops back to {}",
+                short_hash(&backup.hash),
+                short_hash(&base_hash)
+            ));
+        }
+
+        let base = load_backup(
+            Arc::clone(&fs),
+            key.clone(),
+            password.clone(),
+            base_hash.clone(),
         )
-        .as_bytes(),
-    );
+        .await
+        .map_err(|e| {
+            format!(
+                "Failed to load base backup {} of differential backup {}: {}",
+                short_hash(&base_hash),
+                short_hash(&backup.hash),
+                e
+            )
+        })?;
+
+        next_base = base.base.clone();
+        chain.push(base);
+    }
+
+    let chain_length = chain.len();
+    if chain_length == 0 {
+        return Ok((backup, 0));
+    }
+
+    let mut tree = HashMap::new();
+    for base in chain.into_iter().rev() {
+        apply_differential(&mut tree, base.tree, &base.removed);
+    }
+    apply_differential(&mut tree, std::mem::take(&mut backup.tree), &backup.removed);
+    backup.tree = tree;
+
+    Ok((backup, chain_length))
+}
+
+/// Applies one differential backup (`changed` files and `removed` paths) on top of `tree`.
+pub(crate) fn apply_differential(
+    tree: &mut HashMap<String, BackupObject>,
+    changed: HashMap<String, BackupObject>,
+    removed: &[String],
+) {
+    for path in removed {
+        tree.remove(path);
+    }
+    tree.extend(changed);
+}
+
+fn short_hash(hash: &str) -> &str {
+    &hash[..8.min(hash.len())]
+}
+
+/// Reads `backups/{manifest_hash}`, or `None` when it does not exist.
+pub(crate) async fn read_manifest(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    manifest_hash: &str,
+) -> Result<Option<Backup>, String> {
+    let backup_path = backup_path(key, manifest_hash);
+
+    let read_result = read_file_maybe_decrypt(
+        fs,
+        &backup_path,
+        password.as_deref(),
+        "Backup is encrypted but no password provided",
+    )
+    .await?;
+
+    if read_result.bytes.is_empty() {
+        return Ok(None);
+    }
+
+    let decompressed_bytes = decompress_bytes(&read_result.bytes)
+        .map_err(|e| format!("Failed to decompress backup: {}", e))?;
+
+    let backup: Backup = rmp_serde::from_slice(&decompressed_bytes)
+        .map_err(|e| format!("Failed to deserialize backup: {}", e))?;
+
+    Ok(Some(backup))
+}
+
+/// Rewrites `backups/{manifest_hash}` in place. Its signature no longer matches, so it is
+/// removed and the manifest reads as unsigned.
+pub(crate) async fn write_manifest(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    manifest_hash: &str,
+    backup: &Backup,
+) -> Result<(), String> {
+    let backup_bytes = rmp_serde::to_vec_named(backup)
+        .map_err(|e| format!("Failed to serialize backup: {}", e))?;
+
+    let compressed_backup_bytes = compress_bytes(&backup_bytes, 3)
+        .map_err(|e| format!("Failed to compress backup: {}", e))?;
+
+    let backup_path = backup_path(key, manifest_hash);
+    write_file_maybe_encrypt(
+        fs,
+        &backup_path,
+        &compressed_backup_bytes,
+        password.as_deref(),
+    )
+    .await
+    .map_err(|e| format!("Failed to write backup manifest {}: {}", manifest_hash, e))?;
+
+    remove_signature(fs, key, &backup_path).await
+}
+
+/// Whether a manifest is already stored at `backups/{manifest_hash}`.
+pub(crate) async fn manifest_exists(
+    fs: Arc<dyn FS>,
+    key: String,
+    manifest_hash: &str,
+) -> Result<bool, String> {
+    match fs.read_file(&backup_path(&key, manifest_hash)).await {
+        Ok(bytes) => Ok(!bytes.is_empty()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(format!(
+            "Failed to read backup manifest {}: {}",
+            manifest_hash, e
+        )),
+    }
+}
+
+/// Hash of a backup tree, independent of when and by whom the backup was made. Paths are
+/// sorted so the same tree always hashes the same.
+pub(crate) fn content_hash(tree: &HashMap<String, BackupObject>) -> Result<String, String> {
+    let sorted_tree = tree.iter().collect::<std::collections::BTreeMap<_, _>>();
+    let tree_bytes = rmp_serde::to_vec_named(&sorted_tree)
+        .map_err(|e| format!("Failed to serialize backup tree: {}", e))?;
+
+    Ok(format!("{:x}", Sha256::digest(&tree_bytes)))
+}
+
+/// Loads the repository settings, or `None` for repositories that never saved any (use
+/// the defaults then). The file is never encrypted, so no password is needed to read it.
+pub(crate) async fn load_repository_meta(
+    fs: Arc<dyn FS>,
+    key: String,
+) -> Result<Option<RepositoryMeta>, String> {
+    let read_result = read_file_maybe_decrypt(
+        &fs,
+        &repository_meta_path(&key),
+        None,
+        "Repository settings are encrypted",
+    )
+    .await?;
+
+    if read_result.bytes.is_empty() {
+        return Ok(None);
+    }
+
+    let decompressed_bytes = decompress_bytes(&read_result.bytes)
+        .map_err(|e| format!("Failed to decompress repository settings: {}", e))?;
+
+    let repository_meta: RepositoryMeta = rmp_serde::from_slice(&decompressed_bytes)
+        .map_err(|e| format!("Failed to deserialize repository settings: {}", e))?;
+
+    if repository_meta.chunk_dir_levels == 0
+        || repository_meta.chunk_dir_levels > MAX_CHUNK_DIR_LEVELS
+    {
+        return Err(format!(
+            "Unsupported chunk folder depth {} in the repository settings (this version supports 1 to {})",
+            repository_meta.chunk_dir_levels, MAX_CHUNK_DIR_LEVELS
+        ));
+    }
+
+    Ok(Some(repository_meta))
+}
+
+pub(crate) async fn save_repository_meta(
+    fs: Arc<dyn FS>,
+    key: String,
+    repository_meta: &RepositoryMeta,
+) -> Result<(), String> {
+    let repository_meta_bytes = rmp_serde::to_vec_named(repository_meta)
+        .map_err(|e| format!("Failed to serialize repository settings: {}", e))?;
+    let compressed_repository_meta_bytes = compress_bytes(&repository_meta_bytes, 3)
+        .map_err(|e| format!("Failed to compress repository settings: {}", e))?;
+
+    write_file_maybe_encrypt(
+        &fs,
+        &repository_meta_path(&key),
+        &compressed_repository_meta_bytes,
+        None,
+    )
+    .await
+    .map_err(|e| format!("Failed to write repository settings: {}", e))
+}
+
+pub(crate) async fn save_chunk_indexes(
+    fs: Arc<dyn FS>,
+    key: String,
+    chunk_indexes: &HashMap<String, ChunkIndex>,
+    password: Option<String>,
+) -> Result<(), String> {
+    let chunk_indexes_bytes = rmp_serde::to_vec_named(chunk_indexes)
+        .map_err(|e| format!("Failed to serialize chunk indexes: {}", e))?;
+    let compressed_chunk_indexes_bytes = compress_bytes(&chunk_indexes_bytes, 3)
+        .map_err(|e| format!("Failed to compress chunk indexes: {}", e))?;
+
+    let index_path = chunk_index_path(&key);
+    write_file_maybe_encrypt(
+        &fs,
+        &index_path,
+        &compressed_chunk_indexes_bytes,
+        password.as_deref(),
+    )
+    .await
+    .map_err(|e| format!("Failed to write chunk indexes: {}", e))?;
+
+    remove_signature(&fs, &key, &index_path).await
+}
+
+pub(crate) async fn save_backup_summaries(
+    fs: Arc<dyn FS>,
+    key: String,
+    backup_summaries: &[BackupSummary],
+    password: Option<String>,
+) -> Result<(), String> {
+    let backup_summaries_bytes = rmp_serde::to_vec_named(backup_summaries)
+        .map_err(|e| format!("Failed to serialize backup summaries: {}", e))?;
+    let compressed_backup_summaries_bytes = compress_bytes(&backup_summaries_bytes, 3)
+        .map_err(|e| format!("Failed to compress backup summaries: {}", e))?;
+
+    let index_path = backup_summaries_path(&key);
+    write_file_maybe_encrypt(
+        &fs,
+        &index_path,
+        &compressed_backup_summaries_bytes,
+        password.as_deref(),
+    )
+    .await
+    .map_err(|e| format!("Failed to write backup index: {}", e))
+}
+
+/// Replaces the chunk index and returns where the previous one was copied to, see
+/// `replace_index`.
+pub(crate) async fn replace_chunk_indexes(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    chunk_indexes: &HashMap<String, ChunkIndex>,
+) -> Result<Option<String>, String> {
+    replace_index(
+        fs,
+        &chunk_index_path(key),
+        chunk_indexes,
+        save_chunk_indexes(
+            Arc::clone(fs),
+            key.to_string(),
+            chunk_indexes,
+            password.clone(),
+        ),
+        load_chunk_indexes(
+            Arc::clone(fs),
+            key.to_string(),
+            password.clone(),
+            Arc::new(Mutex::new(false)),
+        ),
+    )
+    .await
+}
+
+/// Replaces the backup index and returns where the previous one was copied to, see
+/// `replace_index`.
+pub(crate) async fn replace_backup_summaries(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: &Option<String>,
+    backup_summaries: &[BackupSummary],
+) -> Result<Option<String>, String> {
+    replace_index(
+        fs,
+        &backup_summaries_path(key),
+        &backup_summaries.to_vec(),
+        save_backup_summaries(
+            Arc::clone(fs),
+            key.to_string(),
+            backup_summaries,
+            password.clone(),
+        ),
+        list_backup_summaries(Arc::clone(fs), key.to_string(), password.clone()),
+    )
+    .await
+}
+
+/// Copies the index at `index_path` to `{index_path}.bak`, then runs `save`. The storage
+/// cannot rename, so the new index is read back with `load` after it was written: if it
+/// does not load as `expected`, the previous bytes are put back.
+async fn replace_index<T: PartialEq>(
+    fs: &Arc<dyn FS>,
+    index_path: &str,
+    expected: &T,
+    save: impl Future<Output = Result<(), String>>,
+    load: impl Future<Output = Result<T, String>>,
+) -> Result<Option<String>, String> {
+    let backup_path = format!("{}.bak", index_path);
+
+    let previous_bytes = match fs.read_file(index_path).await {
+        Ok(bytes) if !bytes.is_empty() => Some(bytes),
+        _ => None,
+    };
+
+    if let Some(bytes) = &previous_bytes {
+        fs.write_file(&backup_path, bytes)
+            .await
+            .map_err(|e| format!("Failed to back up '{}': {}", index_path, e))?;
+    }
+
+    let written = match save.await {
+        Ok(()) => load.await.and_then(|written| {
+            (written == *expected)
+                .then_some(())
+                .ok_or_else(|| "the written index does not read back as written".to_string())
+        }),
+        Err(e) => Err(e),
+    };
+
+    match (written, previous_bytes) {
+        (Ok(()), previous_bytes) => Ok(previous_bytes.map(|_| backup_path)),
+        (Err(e), Some(bytes)) => match fs.write_file(index_path, &bytes).await {
+            Ok(()) => Err(format!(
+                "Failed to write '{}', the previous index was put back: {}",
+                index_path, e
+            )),
+            Err(restore_error) => Err(format!(
+                "Failed to write '{}' ({}), and putting the previous index back failed too ({}); it is kept at '{}'",
+                index_path, e, restore_error, backup_path
+            )),
+        },
+        (Err(e), None) => Err(format!("Failed to write '{}': {}", index_path, e)),
+    }
+}
+
+pub(crate) fn create_new_backup(message: String, author: String, timestamp: u64) -> Backup {
+    let backup_hash = Sha256::digest(format!("{}:{}:{}", message, author, timestamp).as_bytes());
 
     Backup {
         message: message.to_string(),
         author: author.to_string(),
-        timestamp: std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
+        timestamp,
         tree: std::collections::HashMap::new(),
         hash: format!("{:x}", backup_hash),
+        base: None,
+        removed: Vec::new(),
     }
 }
 
@@ -92,6 +545,7 @@ pub(crate) async fn add_backup_summary(
     fs: Arc<dyn FS>,
     key: String,
     backup: &Backup,
+    content: Option<String>,
     compress: i32,
     password: Option<String>,
     written_bytes: &u64,
@@ -101,18 +555,30 @@ pub(crate) async fn add_backup_summary(
         hash: backup.hash.clone(),
         timestamp: Some(backup.timestamp),
         size: Some(*written_bytes),
+        author: Some(backup.author.clone()),
+        content,
+        base: backup.base.clone(),
     };
 
     let mut backup_summaries =
         list_backup_summaries(Arc::clone(&fs), key.clone(), password.clone()).await?;
 
+    backfill_backup_summaries(
+        Arc::clone(&fs),
+        key.clone(),
+        password.clone(),
+        &mut backup_summaries,
+    )
+    .await;
+
     backup_summaries.insert(0, new_backup_summary);
 
     let backup_summaries_bytes = rmp_serde::to_vec_named(&backup_summaries)
         .map_err(|e| format!("Failed to serialize backup summaries: {}", e))?;
-    let compressed_backup_summaries_bytes = compress_bytes(&backup_summaries_bytes, compress);
+    let compressed_backup_summaries_bytes = compress_bytes(&backup_summaries_bytes, compress)
+        .map_err(|e| format!("Failed to compress backup summaries: {}", e))?;
 
-    let index_path = format!("{}/indexes/backups", key);
+    let index_path = backup_summaries_path(&key);
     write_file_maybe_encrypt(
         &fs,
         &index_path,
@@ -124,3 +590,160 @@ pub(crate) async fn add_backup_summary(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::LocalFS;
+
+    fn indexes(refcounts: &[(&str, u32)]) -> HashMap<String, ChunkIndex> {
+        refcounts
+            .iter()
+            .map(|(chunk, refcount)| {
+                (
+                    chunk.to_string(),
+                    ChunkIndex {
+                        refcount: *refcount,
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn summary(hash: &str) -> BackupSummary {
+        BackupSummary {
+            message: hash.to_string(),
+            hash: hash.to_string(),
+            timestamp: Some(0),
+            size: Some(0),
+            author: Some("test".to_string()),
+            content: None,
+            base: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn replaced_chunk_index_keeps_a_copy_of_the_previous_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs: Arc<dyn FS> = Arc::new(LocalFS::new(dir.path()));
+        let password = Some("secret".to_string());
+
+        let previous = indexes(&[("a", 4)]);
+        save_chunk_indexes(
+            Arc::clone(&fs),
+            "repo".to_string(),
+            &previous,
+            password.clone(),
+        )
+        .await
+        .unwrap();
+        let previous_bytes = fs.read_file("repo/indexes/chunks").await.unwrap();
+
+        let repaired = indexes(&[("a", 2)]);
+        let previous_index = replace_chunk_indexes(&fs, "repo", &password, &repaired)
+            .await
+            .unwrap();
+
+        assert_eq!(previous_index.as_deref(), Some("repo/indexes/chunks.bak"));
+        assert_eq!(
+            fs.read_file("repo/indexes/chunks.bak").await.unwrap(),
+            previous_bytes
+        );
+
+        let written = load_chunk_indexes(
+            Arc::clone(&fs),
+            "repo".to_string(),
+            password,
+            Arc::new(Mutex::new(false)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, repaired);
+    }
+
+    #[tokio::test]
+    async fn replaced_backup_index_keeps_a_copy_of_the_previous_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs: Arc<dyn FS> = Arc::new(LocalFS::new(dir.path()));
+
+        let previous = vec![summary("one")];
+        save_backup_summaries(Arc::clone(&fs), "repo".to_string(), &previous, None)
+            .await
+            .unwrap();
+        let previous_bytes = fs.read_file("repo/indexes/backups").await.unwrap();
+
+        let rebuilt = vec![summary("one"), summary("two")];
+        let previous_index = replace_backup_summaries(&fs, "repo", &None, &rebuilt)
+            .await
+            .unwrap();
+
+        assert_eq!(previous_index.as_deref(), Some("repo/indexes/backups.bak"));
+        assert_eq!(
+            fs.read_file("repo/indexes/backups.bak").await.unwrap(),
+            previous_bytes
+        );
+        assert_eq!(
+            list_backup_summaries(Arc::clone(&fs), "repo".to_string(), None)
+                .await
+                .unwrap(),
+            rebuilt
+        );
+    }
+
+    #[tokio::test]
+    async fn first_index_is_written_without_a_copy() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs: Arc<dyn FS> = Arc::new(LocalFS::new(dir.path()));
+
+        let previous_index = replace_chunk_indexes(&fs, "repo", &None, &indexes(&[("a", 1)]))
+            .await
+            .unwrap();
+
+        assert_eq!(previous_index, None);
+        assert!(fs.read_file("repo/indexes/chunks.bak").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn index_that_does_not_read_back_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let fs: Arc<dyn FS> = Arc::new(LocalFS::new(dir.path()));
+
+        save_chunk_indexes(
+            Arc::clone(&fs),
+            "repo".to_string(),
+            &indexes(&[("a", 4)]),
+            None,
+        )
+        .await
+        .unwrap();
+        let previous_bytes = fs.read_file("repo/indexes/chunks").await.unwrap();
+
+        let result = replace_index(
+            &fs,
+            &chunk_index_path("repo"),
+            &indexes(&[("a", 2)]),
+            async {
+                fs.write_file("repo/indexes/chunks", b"half-written")
+                    .await
+                    .map_err(|e| e.to_string())
+            },
+            load_chunk_indexes(
+                Arc::clone(&fs),
+                "repo".to_string(),
+                None,
+                Arc::new(Mutex::new(false)),
+            ),
+        )
+        .await;
+
+        assert!(
+            result
+                .unwrap_err()
+                .contains("the previous index was put back")
+        );
+        assert_eq!(
+            fs.read_file("repo/indexes/chunks").await.unwrap(),
+            previous_bytes
+        );
+    }
+}
@@ -0,0 +1,87 @@
+//! Object keys of a repository inside a storage. Every command builds its paths through
+//! these helpers, and `gib spec` documents them from the same functions.
+
+pub(crate) const CHUNK_PATH_TEMPLATE: &str = "{key}/chunks/{hash[0..2]}/{hash[2..]}";
+pub(crate) const NESTED_CHUNK_PATH_TEMPLATE: &str =
+    "{key}/chunks/{hash[0..2]}/{hash[2..4]}/{hash[4..]}";
+
+/// The deepest chunk fan-out supported: `chunks/ab/cd/<rest>`.
+pub(crate) const MAX_CHUNK_DIR_LEVELS: u8 = 2;
+
+/// Builds the path of a chunk, nesting it `dir_levels` two-hex-digit folders deep (the
+/// repository's `RepositoryMeta::chunk_dir_levels`).
+pub(crate) fn chunk_path(key: &str, chunk_hash: &str, dir_levels: u8) -> String {
+    let mut path = format!("{}/chunks", key);
+    let mut rest = chunk_hash;
+
+    for _ in 0..dir_levels {
+        let (prefix, remaining) = rest.split_at(2);
+        path.push('/');
+        path.push_str(prefix);
+        rest = remaining;
+    }
+
+    path.push('/');
+    path.push_str(rest);
+    path
+}
+
+/// Recovers the chunk hash from a chunk path of any fan-out depth.
+pub(crate) fn chunk_hash_from_path(chunk_path: &str) -> Option<String> {
+    let (_, relative) = chunk_path.rsplit_once("/chunks/")?;
+    Some(relative.replace('/', ""))
+}
+
+pub(crate) fn chunk_index_path(key: &str) -> String {
+    format!("{}/indexes/chunks", key)
+}
+
+pub(crate) fn backup_summaries_path(key: &str) -> String {
+    format!("{}/indexes/backups", key)
+}
+
+pub(crate) fn backup_path(key: &str, backup_hash: &str) -> String {
+    format!("{}/backups/{}", key, backup_hash)
+}
+
+pub(crate) fn pending_backup_path(key: &str, backup_hash: &str) -> String {
+    format!("{}/indexes/pending_{}", key, backup_hash)
+}
+
+pub(crate) fn repository_meta_path(key: &str) -> String {
+    format!("{}/repository", key)
+}
+
+pub(crate) fn lock_path(key: &str) -> String {
+    format!("{}/lock", key)
+}
+
+/// The audit log of the chunks `gib verify --repair-from` rewrote from another storage.
+pub(crate) fn heal_log_path(key: &str) -> String {
+    format!("{}/heal_log", key)
+}
+
+/// Where the signature of a stored object is kept. `object_path` is the full path of the
+/// object (`{key}/backups/<hash>`, `{key}/indexes/chunks`).
+pub(crate) fn signature_path(key: &str, object_path: &str) -> String {
+    let object = object_path
+        .strip_prefix(key)
+        .and_then(|object| object.strip_prefix('/'))
+        .unwrap_or(object_path);
+
+    format!("{}/signatures/{}", key, object)
+}
+
+pub(crate) fn trash_folder(key: &str) -> String {
+    format!("{}/trash", key)
+}
+
+pub(crate) fn trash_entry_path(key: &str, trash_id: &str) -> String {
+    format!("{}/trash/{}/entry", key, trash_id)
+}
+
+/// Where a trashed object is kept. `object` is its path inside the repository, relative to
+/// the key (`chunks/ab/...`, `backups/<hash>`).
+pub(crate) fn trash_object_path(key: &str, trash_id: &str, object: &str) -> String {
+    format!("{}/trash/{}/objects/{}", key, trash_id, object)
+}
@@ -0,0 +1,255 @@
+use crate::core::layout::lock_path;
+use crate::core::metadata::RepositoryLock;
+use crate::fs::FS;
+use crate::utils::{compress_bytes, decompress_bytes};
+use chrono::{DateTime, Local, Utc};
+use rand_core::{OsRng, TryRngCore};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// How often the holder rewrites its lock to show it is still alive.
+const LOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+/// A lock that was not refreshed for this long is considered abandoned, wherever it
+/// was taken. Locks of a dead process on this same host are taken over right away.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(10 * 60);
+/// How often a waiting client checks whether the lock was released.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How long to wait before reading a freshly written lock back, so a client that wrote
+/// at the same moment has the time to overwrite it and one of the two backs off.
+const LOCK_SETTLE_DELAY: Duration = Duration::from_millis(500);
+
+/// A held repository lock. It is refreshed in the background until `release` is
+/// called; a process that exits without releasing leaves a lock that goes stale.
+pub(crate) struct LockGuard {
+    fs: Arc<dyn FS>,
+    path: String,
+    refresher: JoinHandle<()>,
+}
+
+impl LockGuard {
+    pub(crate) async fn release(self) -> Result<(), String> {
+        self.refresher.abort();
+
+        match self.fs.delete_file(&self.path).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Failed to remove lock {}: {}", self.path, e)),
+        }
+    }
+}
+
+impl std::fmt::Display for RepositoryLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let started_at = DateTime::<Utc>::from_timestamp(self.started_at as i64, 0)
+            .map(|time| {
+                time.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_else(|| self.started_at.to_string());
+
+        write!(f, "{} (pid {}) since {}", self.host, self.pid, started_at)
+    }
+}
+
+/// Takes the lock of `key`, waiting up to `timeout` for another client to release it.
+/// Stale locks (see `stale_reason`) are taken over and reported through `on_takeover`;
+/// `on_wait` is called once when the lock is busy and the client starts waiting.
+pub(crate) async fn acquire_lock(
+    fs: Arc<dyn FS>,
+    key: &str,
+    timeout: Duration,
+    on_wait: impl Fn(&RepositoryLock),
+    on_takeover: impl Fn(&RepositoryLock, &str),
+) -> Result<LockGuard, String> {
+    let path = lock_path(key);
+    let started = Instant::now();
+    let mut waiting = false;
+
+    loop {
+        if let Some(holder) = read_lock(&fs, &path).await? {
+            match stale_reason(&holder) {
+                Some(reason) => on_takeover(&holder, &reason),
+                None => {
+                    if started.elapsed() >= timeout {
+                        return Err(format!(
+                            "Repository {} is locked by {}. Wait for that backup to finish, or pass --lock-timeout to wait for it",
+                            key, holder
+                        ));
+                    }
+
+                    if !waiting {
+                        on_wait(&holder);
+                        waiting = true;
+                    }
+
+                    let remaining = timeout.saturating_sub(started.elapsed());
+                    tokio::time::sleep(LOCK_POLL_INTERVAL.min(remaining)).await;
+                    continue;
+                }
+            }
+        }
+
+        let lock = new_lock();
+        write_lock(&fs, &path, &lock).await?;
+
+        tokio::time::sleep(LOCK_SETTLE_DELAY).await;
+
+        match read_lock(&fs, &path).await? {
+            Some(current) if current.id == lock.id => {
+                info!(key, host = %lock.host, pid = lock.pid, "repository lock acquired");
+                let refresher = tokio::spawn(refresh_lock(Arc::clone(&fs), path.clone(), lock));
+                return Ok(LockGuard {
+                    fs,
+                    path,
+                    refresher,
+                });
+            }
+            _ => continue,
+        }
+    }
+}
+
+/// The lock of `key` with the reason it is stale, or `None` when there is no lock or its
+/// holder may still be running.
+pub(crate) async fn find_stale_lock(
+    fs: &Arc<dyn FS>,
+    key: &str,
+) -> Result<Option<(RepositoryLock, String)>, String> {
+    let Some(lock) = read_lock(fs, &lock_path(key)).await? else {
+        return Ok(None);
+    };
+
+    Ok(stale_reason(&lock).map(|reason| (lock, reason)))
+}
+
+/// Deletes the lock of `key` if it is still the stale lock `lock_id`, so a client that
+/// took it over in the meantime keeps its lock. Returns whether it was deleted.
+pub(crate) async fn remove_stale_lock(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    lock_id: &str,
+) -> Result<bool, String> {
+    let path = lock_path(key);
+
+    match read_lock(fs, &path).await? {
+        Some(lock) if lock.id == lock_id && stale_reason(&lock).is_some() => {
+            match fs.delete_file(&path).await {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(format!("Failed to remove lock {}: {}", path, e)),
+            }
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Why `lock` can be taken over, or `None` while its holder may still be running.
+fn stale_reason(lock: &RepositoryLock) -> Option<String> {
+    if lock.host == hostname() && lock.pid != std::process::id() && !is_process_alive(lock.pid) {
+        return Some(format!("process {} is no longer running", lock.pid));
+    }
+
+    let idle = now_unix().saturating_sub(lock.refreshed_at);
+    if idle >= STALE_LOCK_AGE.as_secs() {
+        return Some(format!("it was not refreshed for {} minutes", idle / 60));
+    }
+
+    None
+}
+
+async fn refresh_lock(fs: Arc<dyn FS>, path: String, mut lock: RepositoryLock) {
+    let mut interval = tokio::time::interval(LOCK_REFRESH_INTERVAL);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        lock.refreshed_at = now_unix();
+        if let Err(e) = write_lock(&fs, &path, &lock).await {
+            warn!(error = %e, "failed to refresh repository lock");
+        }
+    }
+}
+
+async fn read_lock(fs: &Arc<dyn FS>, path: &str) -> Result<Option<RepositoryLock>, String> {
+    let bytes = match fs.read_file(path).await {
+        Ok(bytes) if bytes.is_empty() => return Ok(None),
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(format!("Failed to read lock {}: {}", path, e)),
+    };
+
+    let bytes = decompress_bytes(&bytes)
+        .map_err(|e| format!("Failed to decompress lock {}: {}", path, e))?;
+
+    rmp_serde::from_slice(&bytes)
+        .map(Some)
+        .map_err(|e| format!("Failed to deserialize lock {}: {}", path, e))
+}
+
+async fn write_lock(fs: &Arc<dyn FS>, path: &str, lock: &RepositoryLock) -> Result<(), String> {
+    let bytes =
+        rmp_serde::to_vec_named(lock).map_err(|e| format!("Failed to serialize lock: {}", e))?;
+    let bytes = compress_bytes(&bytes, 3).map_err(|e| format!("Failed to compress lock: {}", e))?;
+
+    fs.write_file(path, &bytes)
+        .await
+        .map_err(|e| format!("Failed to write lock {}: {}", path, e))
+}
+
+fn new_lock() -> RepositoryLock {
+    let mut id = [0u8; 16];
+    OsRng.try_fill_bytes(&mut id).unwrap();
+    let now = now_unix();
+
+    RepositoryLock {
+        id: id.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        host: hostname(),
+        pid: std::process::id(),
+        started_at: now,
+        refreshed_at: now,
+    }
+}
+
+fn now_unix() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buffer = [0u8; 256];
+        let result =
+            unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+        if result == 0 {
+            let end = buffer
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(buffer.len());
+            return String::from_utf8_lossy(&buffer[..end]).to_string();
+        }
+    }
+
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Without a way to probe the process, it is assumed alive and only the lock age counts.
+fn is_process_alive(_pid: u32) -> bool {
+    #[cfg(unix)]
+    {
+        let result = unsafe { libc::kill(_pid as libc::pid_t, 0) };
+        result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+
+    #[cfg(not(unix))]
+    {
+        true
+    }
+}
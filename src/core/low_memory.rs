@@ -0,0 +1,455 @@
+//! Backup data structures for `gib backup --low-memory`: the chunk index and the tree of
+//! the new backup are kept in temporary files instead of in memory, so memory use no
+//! longer grows with the number of chunks and files. Both are still written to the
+//! storage as the usual single objects, streamed through the compressor; only their
+//! compressed bytes are held at once, because an object is encrypted as a whole.
+
+use crate::core::crypto::read_file_maybe_decrypt;
+use crate::core::layout::chunk_index_path;
+use crate::core::metadata::{Backup, BackupObject, ChunkIndex};
+use crate::fs::FS;
+use rand_core::{OsRng, TryRngCore};
+use serde::de::{MapAccess, Visitor};
+use serde::ser::{Error as _, SerializeMap};
+use serde::{Deserializer, Serialize, Serializer};
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A slot of the on-disk table: an occupied flag, the 32-byte chunk hash and a
+/// little-endian `u32` refcount.
+const SLOT_SIZE: usize = 1 + 32 + 4;
+const MIN_SLOTS: u64 = 1024;
+/// The table is doubled once more than this share of its slots is taken.
+const MAX_LOAD: f64 = 0.7;
+
+/// Chunk refcounts in an open-addressing hash table stored in a temporary file, which
+/// the page cache keeps as warm as memory allows.
+pub(crate) struct DiskChunkIndex {
+    table: Mutex<DiskTable>,
+}
+
+struct DiskTable {
+    file: TempFile,
+    slots: u64,
+    len: u64,
+}
+
+impl DiskChunkIndex {
+    /// Streams the stored chunk index of `key` into a new table, without building it in
+    /// memory. Sets `prev_not_encrypted_but_now_yes` like `load_chunk_indexes`.
+    pub(crate) async fn load(
+        fs: Arc<dyn FS>,
+        key: String,
+        password: Option<String>,
+        prev_not_encrypted_but_now_yes: Arc<Mutex<bool>>,
+    ) -> Result<Self, String> {
+        let read_result = read_file_maybe_decrypt(
+            &fs,
+            chunk_index_path(&key).as_str(),
+            password.as_deref(),
+            "Chunk indexes are encrypted but no password provided",
+        )
+        .await?;
+
+        if password.is_some() && !read_result.was_encrypted && !read_result.bytes.is_empty() {
+            *prev_not_encrypted_but_now_yes.lock().unwrap() = true;
+        }
+
+        if read_result.bytes.is_empty() {
+            return Ok(DiskChunkIndex {
+                table: Mutex::new(DiskTable::create(MIN_SLOTS)?),
+            });
+        }
+
+        tokio::task::spawn_blocking(move || {
+            let decoder = zstd::Decoder::new(read_result.bytes.as_slice())
+                .map_err(|e| format!("Failed to decompress chunk indexes: {}", e))?;
+
+            rmp_serde::Deserializer::new(decoder)
+                .deserialize_map(TableLoader)
+                .map_err(|e| format!("Failed to deserialize chunk indexes: {}", e))
+        })
+        .await
+        .map_err(|e| format!("Failed to load chunk indexes: {}", e))?
+        .map(|table| DiskChunkIndex {
+            table: Mutex::new(table),
+        })
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.table.lock().unwrap().len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Adds a reference to `chunk_hash`, inserting it if needed, and returns its new
+    /// refcount.
+    pub(crate) fn increment(&self, chunk_hash: &str) -> Result<u32, String> {
+        let hash = decode_hash(chunk_hash)?;
+        let mut table = self.table.lock().unwrap();
+
+        let (slot, refcount) = table.find(&hash)?;
+        let refcount = match refcount {
+            Some(refcount) => refcount + 1,
+            None => {
+                table.len += 1;
+                1
+            }
+        };
+        table.write_slot(slot, &hash, refcount)?;
+
+        if table.len as f64 > table.slots as f64 * MAX_LOAD {
+            let grown = table.rehash(table.slots * 2)?;
+            *table = grown;
+        }
+
+        Ok(refcount)
+    }
+
+    /// The index as stored in the repository (a msgpack map of chunk hash to
+    /// `ChunkIndex`, like `save_chunk_indexes` writes), compressed at `level`.
+    pub(crate) fn to_compressed(&self, level: i32) -> Result<Vec<u8>, String> {
+        let table = self.table.lock().unwrap();
+        let entries = TableEntries {
+            table: &table,
+            reader: RefCell::new(
+                table
+                    .file
+                    .reader()
+                    .map_err(|e| format!("Failed to read the chunk index: {}", e))?,
+            ),
+        };
+
+        write_compressed(&entries, level)
+            .map_err(|e| format!("Failed to serialize chunk indexes: {}", e))
+    }
+}
+
+impl DiskTable {
+    fn create(slots: u64) -> Result<Self, String> {
+        let file = TempFile::create("chunk-index")
+            .map_err(|e| format!("Failed to create the on-disk chunk index: {}", e))?;
+        file.file
+            .set_len(slots * SLOT_SIZE as u64)
+            .map_err(|e| format!("Failed to size the on-disk chunk index: {}", e))?;
+
+        Ok(DiskTable {
+            file,
+            slots,
+            len: 0,
+        })
+    }
+
+    /// The slot holding `hash` and its refcount, or the free slot to put it in.
+    fn find(&mut self, hash: &[u8; 32]) -> Result<(u64, Option<u32>), String> {
+        let mut slot = u64::from_le_bytes(hash[..8].try_into().unwrap()) & (self.slots - 1);
+
+        loop {
+            let bytes = self.read_slot(slot)?;
+            if bytes[0] == 0 {
+                return Ok((slot, None));
+            }
+            if bytes[1..33] == hash[..] {
+                return Ok((
+                    slot,
+                    Some(u32::from_le_bytes(bytes[33..].try_into().unwrap())),
+                ));
+            }
+            slot = (slot + 1) & (self.slots - 1);
+        }
+    }
+
+    fn read_slot(&mut self, slot: u64) -> Result<[u8; SLOT_SIZE], String> {
+        let mut bytes = [0u8; SLOT_SIZE];
+        self.file
+            .file
+            .seek(SeekFrom::Start(slot * SLOT_SIZE as u64))
+            .and_then(|_| self.file.file.read_exact(&mut bytes))
+            .map_err(|e| format!("Failed to read the on-disk chunk index: {}", e))?;
+        Ok(bytes)
+    }
+
+    fn write_slot(&mut self, slot: u64, hash: &[u8; 32], refcount: u32) -> Result<(), String> {
+        let mut bytes = [0u8; SLOT_SIZE];
+        bytes[0] = 1;
+        bytes[1..33].copy_from_slice(hash);
+        bytes[33..].copy_from_slice(&refcount.to_le_bytes());
+
+        self.file
+            .file
+            .seek(SeekFrom::Start(slot * SLOT_SIZE as u64))
+            .and_then(|_| self.file.file.write_all(&bytes))
+            .map_err(|e| format!("Failed to write the on-disk chunk index: {}", e))
+    }
+
+    fn insert(&mut self, hash: &[u8; 32], refcount: u32) -> Result<(), String> {
+        let (slot, existing) = self.find(hash)?;
+        if existing.is_none() {
+            self.len += 1;
+        }
+        self.write_slot(slot, hash, refcount)
+    }
+
+    /// A copy of the table with `slots` slots.
+    fn rehash(&self, slots: u64) -> Result<DiskTable, String> {
+        let mut grown = DiskTable::create(slots)?;
+        let mut reader = self
+            .file
+            .reader()
+            .map_err(|e| format!("Failed to read the on-disk chunk index: {}", e))?;
+
+        let mut bytes = [0u8; SLOT_SIZE];
+        for _ in 0..self.slots {
+            reader
+                .read_exact(&mut bytes)
+                .map_err(|e| format!("Failed to read the on-disk chunk index: {}", e))?;
+            if bytes[0] != 0 {
+                grown.insert(
+                    bytes[1..33].try_into().unwrap(),
+                    u32::from_le_bytes(bytes[33..].try_into().unwrap()),
+                )?;
+            }
+        }
+
+        Ok(grown)
+    }
+}
+
+/// Fills a `DiskTable` from the msgpack map of a stored chunk index, one entry at a time.
+struct TableLoader;
+
+impl<'de> Visitor<'de> for TableLoader {
+    type Value = DiskTable;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a map of chunk hashes to chunk indexes")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<DiskTable, A::Error> {
+        use serde::de::Error;
+
+        let expected = map.size_hint().unwrap_or(0) as f64 / MAX_LOAD;
+        let slots = (expected as u64 + 1).next_power_of_two().max(MIN_SLOTS);
+        let mut table = DiskTable::create(slots).map_err(A::Error::custom)?;
+
+        while let Some((chunk_hash, chunk_index)) = map.next_entry::<String, ChunkIndex>()? {
+            let hash = decode_hash(&chunk_hash).map_err(A::Error::custom)?;
+            table
+                .insert(&hash, chunk_index.refcount)
+                .map_err(A::Error::custom)?;
+
+            if table.len as f64 > table.slots as f64 * MAX_LOAD {
+                table = table.rehash(table.slots * 2).map_err(A::Error::custom)?;
+            }
+        }
+
+        Ok(table)
+    }
+}
+
+/// Serializes the occupied slots of a table as a map, reading them in file order.
+struct TableEntries<'a> {
+    table: &'a DiskTable,
+    reader: RefCell<BufReader<File>>,
+}
+
+impl Serialize for TableEntries<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut reader = self.reader.borrow_mut();
+        let mut map = serializer.serialize_map(Some(self.table.len as usize))?;
+
+        let mut bytes = [0u8; SLOT_SIZE];
+        for _ in 0..self.table.slots {
+            reader.read_exact(&mut bytes).map_err(S::Error::custom)?;
+            if bytes[0] == 0 {
+                continue;
+            }
+
+            let chunk_hash = bytes[1..33]
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>();
+            let refcount = u32::from_le_bytes(bytes[33..].try_into().unwrap());
+            map.serialize_entry(&chunk_hash, &ChunkIndex { refcount })?;
+        }
+
+        map.end()
+    }
+}
+
+/// The files of the new backup, appended to a temporary file as they are backed up.
+pub(crate) struct TreeSpool {
+    file: TempFile,
+    writer: Mutex<BufWriter<File>>,
+    len: AtomicU64,
+}
+
+impl TreeSpool {
+    pub(crate) fn create() -> Result<Self, String> {
+        let file = TempFile::create("tree")
+            .map_err(|e| format!("Failed to create the backup tree spool: {}", e))?;
+        let writer = file
+            .file
+            .try_clone()
+            .map_err(|e| format!("Failed to create the backup tree spool: {}", e))?;
+
+        Ok(TreeSpool {
+            file,
+            writer: Mutex::new(BufWriter::new(writer)),
+            len: AtomicU64::new(0),
+        })
+    }
+
+    pub(crate) fn append(&self, path: &str, object: &BackupObject) -> Result<(), String> {
+        let mut writer = self.writer.lock().unwrap();
+        rmp_serde::encode::write_named(&mut *writer, &(path, object))
+            .map_err(|e| format!("Failed to spool {}: {}", path, e))?;
+        self.len.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// The manifest of `backup` with the spooled files as its tree, serialized like
+    /// `rmp_serde::to_vec_named(&backup)` and compressed at `level`. The tree of `backup`
+    /// itself is ignored.
+    pub(crate) fn to_compressed_manifest(
+        &self,
+        backup: &Backup,
+        level: i32,
+    ) -> Result<Vec<u8>, String> {
+        self.writer
+            .lock()
+            .unwrap()
+            .flush()
+            .map_err(|e| format!("Failed to write the backup tree spool: {}", e))?;
+
+        #[derive(Serialize)]
+        struct SpooledBackup<'a> {
+            message: &'a str,
+            hash: &'a str,
+            timestamp: u64,
+            author: &'a str,
+            tree: SpooledTree,
+        }
+
+        let manifest = SpooledBackup {
+            message: &backup.message,
+            hash: &backup.hash,
+            timestamp: backup.timestamp,
+            author: &backup.author,
+            tree: SpooledTree {
+                len: self.len.load(Ordering::SeqCst),
+                reader: RefCell::new(
+                    self.file
+                        .reader()
+                        .map_err(|e| format!("Failed to read the backup tree spool: {}", e))?,
+                ),
+            },
+        };
+
+        write_compressed(&manifest, level)
+            .map_err(|e| format!("Failed to serialize the backup manifest: {}", e))
+    }
+}
+
+struct SpooledTree {
+    len: u64,
+    reader: RefCell<BufReader<File>>,
+}
+
+impl Serialize for SpooledTree {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut reader = self.reader.borrow_mut();
+        let mut map = serializer.serialize_map(Some(self.len as usize))?;
+
+        for _ in 0..self.len {
+            let (path, object): (String, BackupObject) =
+                rmp_serde::from_read(&mut *reader).map_err(S::Error::custom)?;
+            map.serialize_entry(&path, &object)?;
+        }
+
+        map.end()
+    }
+}
+
+fn write_compressed<T: Serialize>(value: &T, level: i32) -> Result<Vec<u8>, String> {
+    let mut encoder = zstd::Encoder::new(Vec::new(), level).map_err(|e| e.to_string())?;
+    rmp_serde::encode::write_named(&mut encoder, value).map_err(|e| e.to_string())?;
+    encoder.finish().map_err(|e| e.to_string())
+}
+
+fn decode_hash(chunk_hash: &str) -> Result<[u8; 32], String> {
+    let mut hash = [0u8; 32];
+
+    if chunk_hash.len() != 64 {
+        return Err(format!(
+            "Invalid chunk hash in the chunk index: {}",
+            chunk_hash
+        ));
+    }
+
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&chunk_hash[i * 2..i * 2 + 2], 16)
+            .map_err(|_| format!("Invalid chunk hash in the chunk index: {}", chunk_hash))?;
+    }
+
+    Ok(hash)
+}
+
+/// A file in the system temporary directory. On Unix it is unlinked as soon as it is
+/// open, so it goes away even when gib exits on an error; elsewhere it is deleted on drop.
+struct TempFile {
+    #[cfg_attr(unix, allow(dead_code))]
+    path: PathBuf,
+    file: File,
+}
+
+impl TempFile {
+    fn create(name: &str) -> std::io::Result<Self> {
+        let mut suffix = [0u8; 8];
+        OsRng
+            .try_fill_bytes(&mut suffix)
+            .map_err(std::io::Error::other)?;
+
+        let path = std::env::temp_dir().join(format!(
+            "gib-{}-{}-{}",
+            name,
+            std::process::id(),
+            suffix
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        ));
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        #[cfg(unix)]
+        std::fs::remove_file(&path)?;
+
+        Ok(TempFile { path, file })
+    }
+
+    /// A buffered reader from the start of the file. It shares the file's cursor, so the
+    /// file must not be used while the reader is.
+    fn reader(&self) -> std::io::Result<BufReader<File>> {
+        let mut file = self.file.try_clone()?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(BufReader::new(file))
+    }
+}
+
+#[cfg(not(unix))]
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
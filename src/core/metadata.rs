@@ -1,7 +1,9 @@
+use crate::core::chunker::Chunker;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
 pub(crate) struct BackupSummary {
     pub(crate) message: String,
     pub(crate) hash: String,
@@ -9,37 +11,259 @@ pub(crate) struct BackupSummary {
     pub(crate) timestamp: Option<u64>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub(crate) size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) author: Option<String>,
+    /// Hash of the tree, set for backups made with `--content-addressed-manifests`. Their
+    /// manifest is stored at `backups/{content}` and shared by every backup with the same
+    /// tree; `hash` stays the backup's own id.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) content: Option<String>,
+    /// The backup this one is a differential of, copied from `Backup::base`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) base: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+impl BackupSummary {
+    /// The name of the manifest object of this backup under `backups/`.
+    pub(crate) fn manifest_hash(&self) -> &str {
+        self.content.as_deref().unwrap_or(&self.hash)
+    }
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
 pub(crate) struct Backup {
     pub(crate) message: String,
     pub(crate) hash: String,
     pub(crate) timestamp: u64,
     pub(crate) author: String,
     pub(crate) tree: HashMap<String, BackupObject>,
+    /// Set for differential backups (`--exclude-from-backup`): `tree` then only holds the
+    /// files that differ from this backup, and `removed` the paths of the base that no
+    /// longer exist. Restoring one needs every backup of its base chain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) base: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) removed: Vec<String>,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
 pub(crate) struct BackupObject {
     pub(crate) hash: String,
     pub(crate) size: u64,
     pub(crate) content_type: String,
     pub(crate) permissions: u32,
     pub(crate) chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) mtime_ns: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) inode: Option<u64>,
+    /// The extended POSIX ACL of the file, with `--store-acls`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) acl: Option<Vec<AclEntry>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) append: Option<AppendState>,
+    /// Set for a symbolic link, which is stored as the path it points to, without chunks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) link_target: Option<String>,
+}
+
+#[cfg(test)]
+impl BackupObject {
+    /// A regular file made of `chunks`, with everything else empty.
+    pub(crate) fn with_chunks(chunks: &[&str]) -> Self {
+        BackupObject {
+            hash: String::new(),
+            size: 0,
+            content_type: String::new(),
+            permissions: 0o644,
+            chunks: chunks.iter().map(|chunk| chunk.to_string()).collect(),
+            uid: None,
+            gid: None,
+            mtime_ns: None,
+            inode: None,
+            acl: None,
+            append: None,
+            link_target: None,
+        }
+    }
+}
+
+/// Stored with `--exclude-bigger-deltas`, so the next backup can resume the file if it
+/// only grew.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct AppendState {
+    /// The chunk size the file was split with; chunks are only reused at the same size.
+    pub(crate) chunk_size: u64,
+    /// The SHA-256 state words (hex) after the first `hash_offset` bytes, a multiple of 64.
+    pub(crate) hash_state: String,
+    pub(crate) hash_offset: u64,
+    /// SHA-256 of the first and last 64 KiB of the content and of its length.
+    pub(crate) sample: String,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+/// One entry of a POSIX access ACL.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct AclEntry {
+    /// `user_obj`, `user`, `group_obj`, `group`, `mask` or `other`.
+    pub(crate) tag: String,
+    /// The uid of a `user` entry or the gid of a `group` entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) id: Option<u32>,
+    /// Read, write and execute bits, like one digit of a mode.
+    pub(crate) perm: u8,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
 pub(crate) struct ChunkIndex {
     pub(crate) refcount: u32,
 }
 
-#[derive(Debug, PartialEq, Deserialize, Serialize, Clone)]
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
 pub(crate) struct PendingBackup {
     pub(crate) message: String,
     pub(crate) compress: i32,
     pub(crate) chunk_size: u64,
     pub(crate) ignore_patterns: Vec<String>,
+    #[serde(default)]
+    pub(crate) exclude_paths: Vec<String>,
+    #[serde(default)]
+    pub(crate) ignore_case: bool,
+    #[serde(default)]
+    pub(crate) exclude_vcs: bool,
     pub(crate) concurrency: usize,
     pub(crate) processed_chunks: Vec<String>,
+    #[serde(default)]
+    pub(crate) created_at: Option<u64>,
+    /// `None` for fixed-size chunks of `chunk_size`, as before `--chunker` existed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) chunker: Option<Chunker>,
+}
+
+/// An Ed25519 signature of a stored object (`backup --sign-key`), kept unencrypted at
+/// `{key}/signatures/<object>` so it can be checked with the public key alone.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct ObjectSignature {
+    /// Always `ed25519`.
+    pub(crate) algorithm: String,
+    /// The public key of the signer, lowercase hex.
+    pub(crate) public_key: String,
+    /// Signature of the object bytes exactly as stored, lowercase hex.
+    pub(crate) signature: String,
+}
+
+/// Repository-wide settings, stored unencrypted at `{key}/repository`. Repositories
+/// without one use the defaults.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct RepositoryMeta {
+    /// How many two-hex-digit folders chunks are nested in: 1 for `chunks/ab/<rest>`,
+    /// 2 for `chunks/ab/cd/<rest>`.
+    #[serde(default = "default_chunk_dir_levels")]
+    pub(crate) chunk_dir_levels: u8,
+    /// The newest backup at the end of the last `gib verify` that found no problems;
+    /// `gib verify --incremental` only checks the data added after it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) last_verified_backup: Option<String>,
+}
+
+fn default_chunk_dir_levels() -> u8 {
+    1
+}
+
+impl Default for RepositoryMeta {
+    fn default() -> Self {
+        RepositoryMeta {
+            chunk_dir_levels: default_chunk_dir_levels(),
+            last_verified_backup: None,
+        }
+    }
+}
+
+/// The holder of a repository's backup lock, stored unencrypted at `{key}/lock` so any
+/// client can tell who holds it. The holder rewrites it periodically to bump
+/// `refreshed_at`; a lock that stopped being refreshed is stale.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct RepositoryLock {
+    /// Random token identifying this lock, used to confirm an acquisition.
+    pub(crate) id: String,
+    pub(crate) host: String,
+    pub(crate) pid: u32,
+    /// Unix seconds.
+    pub(crate) started_at: u64,
+    /// Unix seconds.
+    pub(crate) refreshed_at: u64,
+}
+
+/// Objects removed with `--trash-retention`, kept at `{key}/trash/{id}` so they can be put
+/// back with `gib trash restore` until `expires_at`.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct TrashEntry {
+    pub(crate) id: String,
+    /// `backup` for `gib backup delete`, `prune` for `gib storage prune`.
+    pub(crate) kind: String,
+    /// The summary of the deleted backup, for `backup` entries.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) backup: Option<BackupSummary>,
+    /// Paths of the trashed objects inside the repository, relative to the key.
+    pub(crate) objects: Vec<String>,
+    /// Unix seconds.
+    pub(crate) deleted_at: u64,
+    /// Unix seconds.
+    pub(crate) expires_at: u64,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, Default)]
+pub(crate) struct EncryptCheckpoint {
+    pub(crate) completed_files: Vec<String>,
+}
+
+/// The format version of `RepairPlan`; `gib repair` refuses plans of another version.
+pub(crate) const REPAIR_PLAN_VERSION: u32 = 1;
+
+/// What `gib verify --output-repair-plan` found broken and how `gib repair --plan` will fix
+/// it. Written as JSON so it can be reviewed (or edited) before anything changes.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct RepairPlan {
+    pub(crate) version: u32,
+    pub(crate) key: String,
+    /// Unix seconds.
+    pub(crate) created_at: u64,
+    pub(crate) problems: Vec<RepairProblem>,
+}
+
+/// A chunk that failed verification.
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct RepairProblem {
+    pub(crate) chunk: String,
+    /// The reason code of `gib verify`: `missing_chunk`, `corrupt_chunk`, `decrypt_error`
+    /// or `decompress_error`.
+    pub(crate) reason: String,
+    pub(crate) detail: String,
+    /// The manifests with files made of this chunk.
+    pub(crate) manifests: Vec<AffectedManifest>,
+    /// Intact copies of the chunk in the repository, relative to the key: a `.bak` next to
+    /// the chunk or a copy in the trash.
+    pub(crate) copies: Vec<String>,
+    pub(crate) action: RepairAction,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+pub(crate) struct AffectedManifest {
+    /// The manifest object under `backups/`.
+    pub(crate) manifest: String,
+    /// The backups using it; several with `--content-addressed-manifests`.
+    pub(crate) backups: Vec<String>,
+    pub(crate) files: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Deserialize, Serialize, Clone, JsonSchema)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum RepairAction {
+    /// Copy `source` (relative to the key) over the chunk.
+    RestoreCopy { source: String },
+    /// Remove the files of `manifests` from them, so the rest of those backups restores.
+    DropFiles,
 }
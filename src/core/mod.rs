@@ -1,5 +1,23 @@
+pub mod acl;
+pub mod append;
+pub mod archive;
+pub mod atomic;
+pub mod case_conflicts;
+pub mod chunker;
+pub mod chunks;
+pub mod content_cache;
 pub mod crypto;
+pub mod flatten;
+pub mod hooks;
 pub mod indexes;
+pub mod layout;
+pub mod lock;
+pub mod low_memory;
 pub mod metadata;
 pub mod only;
+pub mod ownership;
 pub mod permissions;
+pub mod permits;
+pub mod retention;
+pub mod signing;
+pub mod trash;
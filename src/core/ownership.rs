@@ -0,0 +1,208 @@
+use crate::core::permissions::set_file_permissions;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// File name of the ownership manifest `gib restore --store-ownership-manifest` writes at
+/// the root of the target directory.
+pub(crate) const OWNERSHIP_MANIFEST_NAME: &str = ".gib-ownership";
+
+/// uid/gid remapping applied when restoring ownership on a host whose user database
+/// differs from the one the backup was taken on. Unmapped ids are kept as they are.
+#[derive(Debug, Default)]
+pub(crate) struct OwnerMap {
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>,
+}
+
+impl OwnerMap {
+    /// Reads a mapping file with one mapping per line:
+    ///
+    /// ```text
+    /// # comments and blank lines are ignored
+    /// uid:111:999   # uid 111 becomes 999
+    /// gid:118:998   # gid 118 becomes 998
+    /// 1000:1001     # both uid and gid 1000 become 1001
+    /// ```
+    pub(crate) fn from_file(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read owner map '{}': {}", path, e))?;
+
+        let mut owner_map = OwnerMap::default();
+
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let invalid = || {
+                format!(
+                    "Invalid owner map entry on line {} of '{}': '{}' (expected 'uid:OLD:NEW', 'gid:OLD:NEW' or 'OLD:NEW')",
+                    index + 1,
+                    path,
+                    line
+                )
+            };
+
+            let parts = line.split(':').map(str::trim).collect::<Vec<&str>>();
+
+            let (kind, old, new) = match parts.as_slice() {
+                [kind, old, new] => (Some(*kind), *old, *new),
+                [old, new] => (None, *old, *new),
+                _ => return Err(invalid()),
+            };
+
+            let old = old.parse::<u32>().map_err(|_| invalid())?;
+            let new = new.parse::<u32>().map_err(|_| invalid())?;
+
+            match kind {
+                Some("uid") => {
+                    owner_map.uids.insert(old, new);
+                }
+                Some("gid") => {
+                    owner_map.gids.insert(old, new);
+                }
+                None => {
+                    owner_map.uids.insert(old, new);
+                    owner_map.gids.insert(old, new);
+                }
+                Some(_) => return Err(invalid()),
+            }
+        }
+
+        Ok(owner_map)
+    }
+
+    pub(crate) fn map_uid(&self, uid: u32) -> u32 {
+        self.uids.get(&uid).copied().unwrap_or(uid)
+    }
+
+    pub(crate) fn map_gid(&self, gid: u32) -> u32 {
+        self.gids.get(&gid).copied().unwrap_or(gid)
+    }
+}
+
+pub(crate) fn get_file_owner(_metadata: &std::fs::Metadata) -> (Option<u32>, Option<u32>) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        (Some(_metadata.uid()), Some(_metadata.gid()))
+    }
+
+    #[cfg(not(unix))]
+    {
+        (None, None)
+    }
+}
+
+/// Ownership can only be changed freely by root; other users get EPERM from chown.
+pub(crate) fn can_restore_ownership() -> bool {
+    #[cfg(unix)]
+    {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+pub(crate) fn set_file_owner(_path: &Path, _uid: u32, _gid: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::chown(_path, Some(_uid), Some(_gid))?;
+    }
+
+    Ok(())
+}
+
+/// Like `set_file_owner`, but changes a symbolic link itself rather than what it points to.
+pub(crate) fn set_link_owner(_path: &Path, _uid: u32, _gid: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::lchown(_path, Some(_uid), Some(_gid))?;
+    }
+
+    Ok(())
+}
+
+/// The owner and mode each restored file should have, written by an unprivileged restore
+/// so that `gib apply-ownership` can apply them later as root. Paths are relative to the
+/// directory the files were restored to.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct OwnershipManifest {
+    pub(crate) backup: String,
+    pub(crate) entries: Vec<OwnershipEntry>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub(crate) struct OwnershipEntry {
+    pub(crate) path: String,
+    pub(crate) uid: u32,
+    pub(crate) gid: u32,
+    pub(crate) mode: u32,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct OwnershipFailure {
+    pub(crate) path: String,
+    pub(crate) error: String,
+}
+
+impl OwnershipManifest {
+    pub(crate) fn read(path: &Path) -> Result<Self, String> {
+        let contents = std::fs::read(path).map_err(|e| {
+            format!(
+                "Failed to read ownership manifest '{}': {}",
+                path.display(),
+                e
+            )
+        })?;
+
+        serde_json::from_slice(&contents).map_err(|e| {
+            format!(
+                "Failed to parse ownership manifest '{}': {}",
+                path.display(),
+                e
+            )
+        })
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<(), String> {
+        let contents = serde_json::to_vec_pretty(self)
+            .map_err(|e| format!("Failed to serialize ownership manifest: {}", e))?;
+
+        std::fs::write(path, contents).map_err(|e| {
+            format!(
+                "Failed to write ownership manifest '{}': {}",
+                path.display(),
+                e
+            )
+        })
+    }
+
+    /// Sets the owner, then the mode (chown can clear setuid/setgid bits), of every entry
+    /// under `root`. Returns how many files were updated and the ones that failed.
+    pub(crate) fn apply(&self, root: &Path) -> (u64, Vec<OwnershipFailure>) {
+        let mut applied = 0;
+        let mut failures = Vec::new();
+
+        for entry in &self.entries {
+            let path = root.join(&entry.path);
+
+            let result = set_file_owner(&path, entry.uid, entry.gid)
+                .and_then(|_| set_file_permissions(&path, entry.mode));
+
+            match result {
+                Ok(()) => applied += 1,
+                Err(e) => failures.push(OwnershipFailure {
+                    path: entry.path.clone(),
+                    error: e.to_string(),
+                }),
+            }
+        }
+
+        (applied, failures)
+    }
+}
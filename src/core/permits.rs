@@ -0,0 +1,349 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::debug;
+
+/// How often `--concurrency-auto` looks at the throughput and adjusts the limit.
+const TUNE_INTERVAL: Duration = Duration::from_secs(2);
+/// Throughput has to move by more than this fraction to count as rising or falling.
+const THROUGHPUT_TOLERANCE: f64 = 0.05;
+/// Weight of the latest interval in the smoothed throughput; bytes are counted as files
+/// finish, so a single interval is noisy.
+const THROUGHPUT_SMOOTHING: f64 = 0.5;
+/// The limit never goes beyond this many times the starting concurrency.
+const MAX_GROWTH: usize = 8;
+
+/// A semaphore whose number of permits can change while tasks hold them. Lowering the
+/// limit retires idle permits right away and the others as they are released, so the
+/// tasks already running are never interrupted.
+pub(crate) struct PermitPool {
+    semaphore: Semaphore,
+    limit: AtomicUsize,
+    /// Permits still to retire after the limit was lowered.
+    debt: AtomicUsize,
+    errors: AtomicU64,
+}
+
+pub(crate) struct PoolPermit<'a> {
+    pool: &'a PermitPool,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for PoolPermit<'_> {
+    fn drop(&mut self) {
+        let Some(permit) = self.permit.take() else {
+            return;
+        };
+
+        let retired = self
+            .pool
+            .debt
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                debt.checked_sub(1)
+            })
+            .is_ok();
+
+        if retired {
+            permit.forget();
+        }
+    }
+}
+
+impl PermitPool {
+    pub(crate) fn new(limit: usize) -> Self {
+        let limit = limit.max(1);
+
+        Self {
+            semaphore: Semaphore::new(limit),
+            limit: AtomicUsize::new(limit),
+            debt: AtomicUsize::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) async fn acquire(&self) -> PoolPermit<'_> {
+        let permit = self.semaphore.acquire().await.expect("Semaphore closed");
+
+        PoolPermit {
+            pool: self,
+            permit: Some(permit),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit.load(Ordering::SeqCst)
+    }
+
+    /// Records a failed task, which `ConcurrencyTuner` takes as a sign of an overloaded backend.
+    pub(crate) fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn resize(&self, limit: usize) {
+        let limit = limit.max(1);
+        let previous = self.limit.swap(limit, Ordering::SeqCst);
+
+        if limit > previous {
+            let added = limit - previous;
+            // Cancel the permits still waiting to be retired before creating new ones.
+            let debt = self
+                .debt
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |debt| {
+                    Some(debt.saturating_sub(added))
+                })
+                .unwrap_or(0);
+            self.semaphore.add_permits(added - debt.min(added));
+        } else if limit < previous {
+            let removed = previous - limit;
+            let forgotten = self.semaphore.forget_permits(removed);
+            self.debt.fetch_add(removed - forgotten, Ordering::SeqCst);
+        }
+    }
+}
+
+/// How `--concurrency-auto` ended up: the concurrency it started and finished with and
+/// how many times it changed along the way.
+#[derive(serde::Serialize, Clone, Copy)]
+pub(crate) struct ConcurrencyReport {
+    pub(crate) initial: usize,
+    pub(crate) stabilized: usize,
+    pub(crate) adjustments: u64,
+}
+
+/// Adjusts the limit of `pool` every couple of seconds, AIMD-style: it grows by a fixed
+/// step while the throughput reported by `processed_bytes` keeps rising, steps back when
+/// it falls, and halves when a task failed since the last look (throttling, timeouts).
+/// It runs on its own thread so busy file tasks cannot delay it; `report` stops it and
+/// waits for it.
+pub(crate) struct ConcurrencyTuner {
+    pool: Arc<PermitPool>,
+    initial: usize,
+    adjustments: Arc<AtomicU64>,
+    /// Dropped to wake the thread up and stop it.
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl ConcurrencyTuner {
+    pub(crate) fn start<F>(pool: Arc<PermitPool>, processed_bytes: F) -> Self
+    where
+        F: Fn() -> u64 + Send + 'static,
+    {
+        let initial = pool.limit();
+        let step = (initial / 4).max(1);
+        let max_limit = initial * MAX_GROWTH;
+        let adjustments = Arc::new(AtomicU64::new(0));
+        let (stop, stopped) = mpsc::channel::<()>();
+
+        let thread = {
+            let pool = Arc::clone(&pool);
+            let adjustments = Arc::clone(&adjustments);
+
+            thread::spawn(move || {
+                let mut last_bytes = processed_bytes();
+                let mut last_errors = pool.errors.load(Ordering::SeqCst);
+                let mut last_tick = Instant::now();
+                let mut last_throughput: Option<f64> = None;
+
+                loop {
+                    // Sleeps for the interval, unless `report` stops the tuner first.
+                    if stopped.recv_timeout(TUNE_INTERVAL) != Err(RecvTimeoutError::Timeout) {
+                        break;
+                    }
+
+                    let bytes = processed_bytes();
+                    let errors = pool.errors.load(Ordering::SeqCst);
+                    let elapsed = last_tick.elapsed().as_secs_f64();
+                    let sample = bytes.saturating_sub(last_bytes) as f64 / elapsed;
+                    let failed = errors > last_errors;
+                    last_bytes = bytes;
+                    last_errors = errors;
+                    last_tick = Instant::now();
+
+                    let throughput = match last_throughput {
+                        Some(last) => last + (sample - last) * THROUGHPUT_SMOOTHING,
+                        None => sample,
+                    };
+
+                    let limit = pool.limit();
+                    let new_limit = if failed {
+                        limit / 2
+                    } else if sample == 0.0 {
+                        // Nothing was read or written (e.g. one large file being hashed).
+                        continue;
+                    } else {
+                        match last_throughput {
+                            Some(last) if throughput < last * (1.0 - THROUGHPUT_TOLERANCE) => {
+                                limit.saturating_sub(step)
+                            }
+                            Some(last) if throughput <= last * (1.0 + THROUGHPUT_TOLERANCE) => {
+                                limit
+                            }
+                            _ => limit + step,
+                        }
+                    }
+                    .clamp(1, max_limit);
+
+                    last_throughput = Some(throughput);
+
+                    if new_limit != limit {
+                        debug!(
+                            from = limit,
+                            to = new_limit,
+                            throughput,
+                            failed,
+                            "adjusted concurrency"
+                        );
+                        pool.resize(new_limit);
+                        adjustments.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            })
+        };
+
+        Self {
+            pool,
+            initial,
+            adjustments,
+            stop,
+            thread,
+        }
+    }
+
+    pub(crate) fn report(self) -> ConcurrencyReport {
+        // The thread wakes up as soon as the sender is gone, so this does not wait for the
+        // rest of the interval. Once it returned, the limit can no longer change.
+        drop(self.stop);
+        let _ = self.thread.join();
+
+        ConcurrencyReport {
+            initial: self.initial,
+            stabilized: self.pool.limit(),
+            adjustments: self.adjustments.load(Ordering::SeqCst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn available(pool: &PermitPool) -> usize {
+        pool.semaphore.available_permits()
+    }
+
+    async fn hold(pool: &PermitPool, count: usize) -> Vec<PoolPermit<'_>> {
+        let mut permits = Vec::new();
+        for _ in 0..count {
+            permits.push(pool.acquire().await);
+        }
+        permits
+    }
+
+    #[tokio::test]
+    async fn raising_the_limit_adds_permits_right_away() {
+        let pool = PermitPool::new(2);
+        let mut held = hold(&pool, 2).await;
+
+        pool.resize(4);
+        assert_eq!(pool.limit(), 4);
+        assert_eq!(available(&pool), 2);
+
+        held.clear();
+        assert_eq!(available(&pool), 4);
+    }
+
+    #[tokio::test]
+    async fn lowering_the_limit_retires_idle_permits_first() {
+        let pool = PermitPool::new(4);
+        let mut held = hold(&pool, 1).await;
+
+        pool.resize(2);
+        assert_eq!(available(&pool), 1);
+        assert_eq!(pool.debt.load(Ordering::SeqCst), 0);
+
+        held.clear();
+        assert_eq!(available(&pool), 2);
+    }
+
+    #[tokio::test]
+    async fn lowering_the_limit_retires_held_permits_as_they_are_released() {
+        let pool = PermitPool::new(4);
+        let mut held = hold(&pool, 4).await;
+
+        pool.resize(2);
+        assert_eq!(pool.limit(), 2);
+        assert_eq!(pool.debt.load(Ordering::SeqCst), 2);
+
+        // The first two releases pay the debt back; the permits are gone, not returned.
+        held.pop();
+        held.pop();
+        assert_eq!(available(&pool), 0);
+        assert_eq!(pool.debt.load(Ordering::SeqCst), 0);
+
+        held.clear();
+        assert_eq!(available(&pool), 2);
+    }
+
+    #[tokio::test]
+    async fn raising_the_limit_cancels_debt_before_adding_permits() {
+        let pool = PermitPool::new(4);
+        let mut held = hold(&pool, 4).await;
+
+        pool.resize(1);
+        assert_eq!(pool.debt.load(Ordering::SeqCst), 3);
+
+        // Two of the three permits still to retire are kept instead; none are created.
+        pool.resize(3);
+        assert_eq!(pool.debt.load(Ordering::SeqCst), 1);
+        assert_eq!(available(&pool), 0);
+
+        held.clear();
+        assert_eq!(available(&pool), 3);
+    }
+
+    #[tokio::test]
+    async fn raising_the_limit_past_the_debt_adds_the_difference() {
+        let pool = PermitPool::new(4);
+        let mut held = hold(&pool, 4).await;
+
+        pool.resize(2);
+        pool.resize(5);
+        assert_eq!(pool.debt.load(Ordering::SeqCst), 0);
+        assert_eq!(available(&pool), 1);
+
+        held.clear();
+        assert_eq!(available(&pool), 5);
+    }
+
+    #[tokio::test]
+    async fn the_limit_never_drops_below_one() {
+        let pool = PermitPool::new(0);
+        assert_eq!(pool.limit(), 1);
+
+        let held = hold(&pool, 1).await;
+        pool.resize(0);
+        assert_eq!(pool.limit(), 1);
+        assert_eq!(pool.debt.load(Ordering::SeqCst), 0);
+
+        drop(held);
+        assert_eq!(available(&pool), 1);
+    }
+
+    #[test]
+    fn report_stops_the_tuner_without_waiting_for_the_interval() {
+        let pool = Arc::new(PermitPool::new(4));
+        let tuner = ConcurrencyTuner::start(Arc::clone(&pool), || 0);
+
+        let started = Instant::now();
+        let report = tuner.report();
+
+        assert!(started.elapsed() < TUNE_INTERVAL);
+        assert_eq!(report.initial, 4);
+        assert_eq!(report.stabilized, 4);
+        assert_eq!(report.adjustments, 0);
+    }
+}
@@ -0,0 +1,405 @@
+use crate::core::metadata::BackupSummary;
+use chrono::{DateTime, Datelike, Local, Utc};
+use std::collections::HashSet;
+
+/// Which backups `gib backup delete --keep-*` and `gib backup --max-backups` keep.
+/// `keep_last` keeps the newest backups; the period rules keep the newest backup of each of
+/// the most recent days, ISO weeks or months (in local time) that have one, like `restic
+/// forget`. A backup kept by any rule stays.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct RetentionPolicy {
+    pub(crate) keep_last: Option<usize>,
+    pub(crate) keep_daily: Option<usize>,
+    pub(crate) keep_weekly: Option<usize>,
+    pub(crate) keep_monthly: Option<usize>,
+}
+
+/// Why a backup is kept, reported by `--dry-run` and in the JSON plan.
+pub(crate) const KEEP_LAST: &str = "last";
+pub(crate) const KEEP_DAILY: &str = "daily";
+pub(crate) const KEEP_WEEKLY: &str = "weekly";
+pub(crate) const KEEP_MONTHLY: &str = "monthly";
+/// Summaries written before backups recorded a timestamp cannot be placed in time.
+pub(crate) const KEEP_NO_TIMESTAMP: &str = "no_timestamp";
+/// The base of a differential backup that is kept.
+pub(crate) const KEEP_BASE: &str = "base";
+/// The backup `gib backup --max-backups` just made, which its rotation never deletes.
+pub(crate) const KEEP_NEW: &str = "new";
+
+impl RetentionPolicy {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.keep_last.is_none()
+            && self.keep_daily.is_none()
+            && self.keep_weekly.is_none()
+            && self.keep_monthly.is_none()
+    }
+
+    /// Returns, for each of `summaries`, the reasons it is kept; an empty list means the
+    /// backup is removed. The backup with the hash `pinned` is always kept.
+    pub(crate) fn apply(
+        &self,
+        summaries: &[BackupSummary],
+        pinned: Option<&str>,
+    ) -> Vec<Vec<&'static str>> {
+        let mut reasons = vec![Vec::new(); summaries.len()];
+
+        if let Some(pinned) = pinned
+            && let Some(index) = summaries.iter().position(|summary| summary.hash == pinned)
+        {
+            reasons[index].push(KEEP_NEW);
+        }
+
+        let mut dated = Vec::new();
+        for (index, summary) in summaries.iter().enumerate() {
+            if pinned == Some(summary.hash.as_str()) {
+                continue;
+            }
+
+            match summary
+                .timestamp
+                .and_then(|ts| DateTime::<Utc>::from_timestamp_secs(ts as i64))
+            {
+                Some(date) => dated.push((index, date.with_timezone(&Local))),
+                None => reasons[index].push(KEEP_NO_TIMESTAMP),
+            }
+        }
+
+        // Newest first; summaries are stored that way, but an older client may not have.
+        dated.sort_by_key(|(_, date)| std::cmp::Reverse(*date));
+
+        if let Some(keep_last) = self.keep_last {
+            for (index, _) in dated.iter().take(keep_last) {
+                reasons[*index].push(KEEP_LAST);
+            }
+        }
+
+        if let Some(count) = self.keep_daily {
+            keep_periods(&dated, count, KEEP_DAILY, &mut reasons, |date| {
+                (date.year(), date.ordinal())
+            });
+        }
+
+        if let Some(count) = self.keep_weekly {
+            keep_periods(&dated, count, KEEP_WEEKLY, &mut reasons, |date| {
+                let week = date.iso_week();
+                (week.year(), week.week())
+            });
+        }
+
+        if let Some(count) = self.keep_monthly {
+            keep_periods(&dated, count, KEEP_MONTHLY, &mut reasons, |date| {
+                (date.year(), date.month())
+            });
+        }
+
+        // Restoring a differential backup needs its whole base chain, so the base of every
+        // kept backup is kept too.
+        let hashes = summaries
+            .iter()
+            .map(|summary| summary.hash.as_str())
+            .collect::<Vec<&str>>();
+        let mut kept_bases = HashSet::new();
+        loop {
+            let bases = summaries
+                .iter()
+                .zip(&reasons)
+                .filter(|(_, reasons)| !reasons.is_empty())
+                .filter_map(|(summary, _)| summary.base.clone())
+                .filter(|base| !kept_bases.contains(base))
+                .collect::<Vec<String>>();
+
+            if bases.is_empty() {
+                break;
+            }
+
+            for base in bases {
+                if let Some(index) = hashes.iter().position(|hash| *hash == base)
+                    && reasons[index].is_empty()
+                {
+                    reasons[index].push(KEEP_BASE);
+                }
+                kept_bases.insert(base);
+            }
+        }
+
+        reasons
+    }
+}
+
+/// Keeps the newest backup of each of the `count` most recent periods in `dated` (newest
+/// first) that have one.
+fn keep_periods(
+    dated: &[(usize, DateTime<Local>)],
+    count: usize,
+    reason: &'static str,
+    reasons: &mut [Vec<&'static str>],
+    period_of: impl Fn(&DateTime<Local>) -> (i32, u32),
+) {
+    let mut last_period = None;
+    let mut kept = 0;
+
+    for (index, date) in dated {
+        if kept == count {
+            break;
+        }
+
+        let period = period_of(date);
+        if last_period != Some(period) {
+            last_period = Some(period);
+            reasons[*index].push(reason);
+            kept += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// A backup made at noon local time on the given day, or one without a timestamp.
+    fn summary(hash: &str, date: Option<(i32, u32, u32)>) -> BackupSummary {
+        BackupSummary {
+            message: hash.to_string(),
+            hash: hash.to_string(),
+            timestamp: date.map(|(year, month, day)| {
+                Local
+                    .with_ymd_and_hms(year, month, day, 12, 0, 0)
+                    .single()
+                    .unwrap()
+                    .timestamp() as u64
+            }),
+            size: Some(0),
+            author: Some("test".to_string()),
+            content: None,
+            base: None,
+        }
+    }
+
+    fn differential(hash: &str, date: (i32, u32, u32), base: &str) -> BackupSummary {
+        BackupSummary {
+            base: Some(base.to_string()),
+            ..summary(hash, Some(date))
+        }
+    }
+
+    /// The hashes of the kept backups, with their reasons.
+    fn kept<'a>(
+        policy: RetentionPolicy,
+        summaries: &'a [BackupSummary],
+        pinned: Option<&str>,
+    ) -> Vec<(&'a str, Vec<&'static str>)> {
+        summaries
+            .iter()
+            .zip(policy.apply(summaries, pinned))
+            .filter(|(_, reasons)| !reasons.is_empty())
+            .map(|(summary, reasons)| (summary.hash.as_str(), reasons))
+            .collect()
+    }
+
+    #[test]
+    fn keep_last_keeps_the_newest_backups() {
+        // Not newest first, as an older client may have stored them.
+        let summaries = [
+            summary("b", Some((2024, 3, 2))),
+            summary("d", Some((2024, 3, 4))),
+            summary("a", Some((2024, 3, 1))),
+            summary("c", Some((2024, 3, 3))),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            kept(policy, &summaries, None),
+            vec![("d", vec![KEEP_LAST]), ("c", vec![KEEP_LAST])]
+        );
+    }
+
+    #[test]
+    fn keep_daily_keeps_the_newest_backup_of_each_day() {
+        let mut morning = summary("c-morning", Some((2024, 3, 5)));
+        morning.timestamp = morning.timestamp.map(|ts| ts - 3600);
+        let summaries = [
+            summary("c", Some((2024, 3, 5))),
+            morning,
+            // Nothing on the 4th: days without a backup are not counted.
+            summary("b", Some((2024, 3, 3))),
+            summary("a", Some((2024, 3, 2))),
+        ];
+        let policy = RetentionPolicy {
+            keep_daily: Some(2),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            kept(policy, &summaries, None),
+            vec![("c", vec![KEEP_DAILY]), ("b", vec![KEEP_DAILY])]
+        );
+    }
+
+    #[test]
+    fn keep_weekly_uses_iso_weeks_across_the_year_boundary() {
+        // 2020-12-28 to 2021-01-03 is ISO week 53 of 2020, and 2021-01-04 starts week 1
+        // of 2021, so the backups of the 30th and the 2nd are in the same week.
+        let summaries = [
+            summary("jan-5", Some((2021, 1, 5))),
+            summary("jan-2", Some((2021, 1, 2))),
+            summary("dec-30", Some((2020, 12, 30))),
+            summary("dec-20", Some((2020, 12, 20))),
+        ];
+        let policy = RetentionPolicy {
+            keep_weekly: Some(3),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            kept(policy, &summaries, None),
+            vec![
+                ("jan-5", vec![KEEP_WEEKLY]),
+                ("jan-2", vec![KEEP_WEEKLY]),
+                ("dec-20", vec![KEEP_WEEKLY]),
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_monthly_keeps_the_newest_backup_of_each_month() {
+        let summaries = [
+            summary("mar-1", Some((2024, 3, 1))),
+            summary("feb-29", Some((2024, 2, 29))),
+            summary("feb-1", Some((2024, 2, 1))),
+            summary("dec-31", Some((2023, 12, 31))),
+        ];
+        let policy = RetentionPolicy {
+            keep_monthly: Some(2),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            kept(policy, &summaries, None),
+            vec![
+                ("mar-1", vec![KEEP_MONTHLY]),
+                ("feb-29", vec![KEEP_MONTHLY])
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_add_up() {
+        let summaries = [
+            summary("c", Some((2024, 3, 3))),
+            summary("b", Some((2024, 3, 2))),
+            summary("a", Some((2024, 2, 1))),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            keep_daily: Some(1),
+            keep_monthly: Some(2),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            kept(policy, &summaries, None),
+            vec![
+                ("c", vec![KEEP_LAST, KEEP_DAILY, KEEP_MONTHLY]),
+                ("a", vec![KEEP_MONTHLY]),
+            ]
+        );
+    }
+
+    #[test]
+    fn legacy_summaries_without_a_timestamp_are_kept() {
+        let summaries = [
+            summary("new", Some((2024, 3, 2))),
+            summary("old", Some((2024, 3, 1))),
+            summary("legacy", None),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+
+        // The legacy backup does not use up the keep_last slot either.
+        assert_eq!(
+            kept(policy, &summaries, None),
+            vec![
+                ("new", vec![KEEP_LAST]),
+                ("legacy", vec![KEEP_NO_TIMESTAMP])
+            ]
+        );
+    }
+
+    #[test]
+    fn pinned_backup_is_kept_outside_the_rules() {
+        let summaries = [
+            summary("new", Some((2024, 3, 3))),
+            summary("b", Some((2024, 3, 2))),
+            summary("a", Some((2024, 3, 1))),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+
+        // `--max-backups 2` keeps the new backup plus the newest of the others.
+        assert_eq!(
+            kept(policy, &summaries, Some("new")),
+            vec![("new", vec![KEEP_NEW]), ("b", vec![KEEP_LAST])]
+        );
+    }
+
+    #[test]
+    fn base_chain_of_a_kept_differential_is_kept() {
+        let summaries = [
+            differential("d", (2024, 3, 4), "c"),
+            differential("c", (2024, 3, 3), "a"),
+            summary("b", Some((2024, 3, 2))),
+            summary("a", Some((2024, 3, 1))),
+        ];
+        let policy = RetentionPolicy {
+            keep_last: Some(1),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            kept(policy, &summaries, None),
+            vec![
+                ("d", vec![KEEP_LAST]),
+                ("c", vec![KEEP_BASE]),
+                ("a", vec![KEEP_BASE]),
+            ]
+        );
+
+        // A base kept by a rule is not also reported as a base.
+        let policy = RetentionPolicy {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            kept(policy, &summaries, None),
+            vec![
+                ("d", vec![KEEP_LAST]),
+                ("c", vec![KEEP_LAST]),
+                ("a", vec![KEEP_BASE]),
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_policy_removes_everything_but_the_pinned_backup() {
+        let summaries = [
+            summary("new", Some((2024, 3, 2))),
+            summary("old", Some((2024, 3, 1))),
+        ];
+        let policy = RetentionPolicy::default();
+
+        assert!(policy.is_empty());
+        assert_eq!(
+            kept(policy, &summaries, Some("new")),
+            vec![("new", vec![KEEP_NEW])]
+        );
+        assert!(kept(policy, &summaries, None).is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::core::layout::signature_path;
+use crate::core::metadata::ObjectSignature;
+use crate::fs::FS;
+use crate::utils::{compress_bytes, decompress_bytes};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use std::sync::Arc;
+
+const SIGNATURE_ALGORITHM: &str = "ed25519";
+
+/// Reads an Ed25519 private key in PKCS#8 PEM, as written by
+/// `openssl genpkey -algorithm ed25519`.
+pub(crate) fn load_signing_key(path: &str) -> Result<SigningKey, String> {
+    let pem = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read signing key {}: {}", path, e))?;
+
+    SigningKey::from_pkcs8_pem(&pem).map_err(|e| {
+        format!(
+            "Invalid signing key {} (expected an Ed25519 PEM private key): {}",
+            path, e
+        )
+    })
+}
+
+/// Reads an Ed25519 public key in PEM, as written by `openssl pkey -pubout`.
+pub(crate) fn load_verifying_key(path: &str) -> Result<VerifyingKey, String> {
+    let pem = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read public key {}: {}", path, e))?;
+
+    VerifyingKey::from_public_key_pem(&pem).map_err(|e| {
+        format!(
+            "Invalid public key {} (expected an Ed25519 PEM public key): {}",
+            path, e
+        )
+    })
+}
+
+/// Signs `stored_bytes`, the object at `object_path` exactly as it is stored (compressed
+/// and, with a password, encrypted), so it can be checked without the password.
+pub(crate) async fn sign_object(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    object_path: &str,
+    stored_bytes: &[u8],
+    signing_key: &SigningKey,
+) -> Result<(), String> {
+    let signature = ObjectSignature {
+        algorithm: SIGNATURE_ALGORITHM.to_string(),
+        public_key: to_hex(signing_key.verifying_key().as_bytes()),
+        signature: to_hex(&signing_key.sign(stored_bytes).to_bytes()),
+    };
+
+    let signature_bytes = rmp_serde::to_vec_named(&signature)
+        .map_err(|e| format!("Failed to serialize signature: {}", e))?;
+
+    let signature_bytes = compress_bytes(&signature_bytes, 3)
+        .map_err(|e| format!("Failed to compress signature: {}", e))?;
+
+    let path = signature_path(key, object_path);
+    fs.write_file(&path, &signature_bytes)
+        .await
+        .map_err(|e| format!("Failed to write signature {}: {}", path, e))
+}
+
+/// Removes the signature of an object that was rewritten without the signing key, so it
+/// reads as unsigned rather than tampered with.
+pub(crate) async fn remove_signature(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    object_path: &str,
+) -> Result<(), String> {
+    let path = signature_path(key, object_path);
+
+    match fs.delete_file(&path).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Failed to remove signature {}: {}", path, e)),
+    }
+}
+
+/// Why an object did not pass `verify_object`. `reason_code` is `unsigned`,
+/// `wrong_key`, `invalid_signature` or `read_error`.
+pub(crate) struct SignatureFailure {
+    pub(crate) reason_code: &'static str,
+    pub(crate) detail: String,
+}
+
+impl SignatureFailure {
+    fn new(reason_code: &'static str, detail: String) -> Self {
+        SignatureFailure {
+            reason_code,
+            detail,
+        }
+    }
+}
+
+/// Checks the stored object at `object_path` against its signature and `verifying_key`.
+pub(crate) async fn verify_object(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    object_path: &str,
+    verifying_key: &VerifyingKey,
+) -> Result<(), SignatureFailure> {
+    let path = signature_path(key, object_path);
+
+    let signature_bytes = match fs.read_file(&path).await {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(SignatureFailure::new(
+                "unsigned",
+                "No signature stored".to_string(),
+            ));
+        }
+        Err(e) => {
+            return Err(SignatureFailure::new(
+                "read_error",
+                format!("Failed to read signature {}: {}", path, e),
+            ));
+        }
+    };
+
+    let signature_bytes = decompress_bytes(&signature_bytes).map_err(|e| {
+        SignatureFailure::new(
+            "read_error",
+            format!("Failed to decompress signature: {}", e),
+        )
+    })?;
+
+    let signature: ObjectSignature = rmp_serde::from_slice(&signature_bytes).map_err(|e| {
+        SignatureFailure::new("read_error", format!("Failed to parse signature: {}", e))
+    })?;
+
+    if signature.algorithm != SIGNATURE_ALGORITHM {
+        return Err(SignatureFailure::new(
+            "read_error",
+            format!("Unsupported signature algorithm '{}'", signature.algorithm),
+        ));
+    }
+
+    if signature.public_key != to_hex(verifying_key.as_bytes()) {
+        return Err(SignatureFailure::new(
+            "wrong_key",
+            format!("Signed by another key ({})", signature.public_key),
+        ));
+    }
+
+    let stored_bytes = fs.read_file(object_path).await.map_err(|e| {
+        SignatureFailure::new(
+            "read_error",
+            format!("Failed to read {}: {}", object_path, e),
+        )
+    })?;
+
+    let signature = from_hex(&signature.signature)
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(|| SignatureFailure::new("read_error", "Malformed signature".to_string()))?;
+
+    verifying_key
+        .verify(&stored_bytes, &signature)
+        .map_err(|_| {
+            SignatureFailure::new(
+                "invalid_signature",
+                "The object does not match its signature".to_string(),
+            )
+        })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
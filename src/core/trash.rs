@@ -0,0 +1,221 @@
+use crate::core::crypto::{read_file_maybe_decrypt, write_file_maybe_encrypt};
+use crate::core::layout::{
+    chunk_hash_from_path, chunk_path, trash_entry_path, trash_folder, trash_object_path,
+};
+use crate::core::metadata::{BackupSummary, TrashEntry};
+use crate::fs::FS;
+use crate::utils::{compress_bytes, decompress_bytes};
+use futures::stream::{self, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+
+const MAX_CONCURRENT_TRASH_OBJECTS: usize = 100;
+
+impl TrashEntry {
+    pub(crate) fn new(
+        id: String,
+        kind: &str,
+        backup: Option<BackupSummary>,
+        objects: Vec<String>,
+        retention: Duration,
+    ) -> Self {
+        let deleted_at = now_unix();
+
+        TrashEntry {
+            id,
+            kind: kind.to_string(),
+            backup,
+            objects,
+            deleted_at,
+            expires_at: deleted_at + retention.as_secs(),
+        }
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.expires_at <= now_unix()
+    }
+}
+
+/// Copies the objects of `entry` (paths relative to `key`) into the trash and writes the
+/// entry describing them. The originals are left in place for the caller to delete once
+/// its own indexes are updated, so a failure here never loses anything.
+pub(crate) async fn copy_to_trash(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    password: Option<&str>,
+    entry: &TrashEntry,
+) -> Result<(), String> {
+    copy_objects(fs, key, &entry.objects, |object| {
+        (
+            format!("{}/{}", key, object),
+            trash_object_path(key, &entry.id, object),
+        )
+    })
+    .await?;
+
+    let entry_bytes = rmp_serde::to_vec_named(entry)
+        .map_err(|e| format!("Failed to serialize trash entry: {}", e))?;
+    let compressed_entry_bytes = compress_bytes(&entry_bytes, 3)
+        .map_err(|e| format!("Failed to compress trash entry: {}", e))?;
+
+    write_file_maybe_encrypt(
+        fs,
+        &trash_entry_path(key, &entry.id),
+        &compressed_entry_bytes,
+        password,
+    )
+    .await
+    .map_err(|e| format!("Failed to write trash entry {}: {}", entry.id, e))
+}
+
+/// Copies the objects of `entry` back to their original paths. Chunks go where the
+/// current layout (`chunk_dir_levels`) expects them, which differs from where they were
+/// trashed if `gib storage migrate-layout` ran since. The trash copy is kept until
+/// `purge_trash_entry` is called.
+pub(crate) async fn copy_from_trash(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    entry: &TrashEntry,
+    chunk_dir_levels: u8,
+) -> Result<(), String> {
+    copy_objects(fs, key, &entry.objects, |object| {
+        let to = match object.strip_prefix("chunks/") {
+            Some(_) => chunk_hash_from_path(object)
+                .map(|chunk_hash| chunk_path(key, &chunk_hash, chunk_dir_levels))
+                .unwrap_or_else(|| format!("{}/{}", key, object)),
+            None => format!("{}/{}", key, object),
+        };
+        (trash_object_path(key, &entry.id, object), to)
+    })
+    .await
+}
+
+/// Deletes the trashed objects of `entry`, then the entry itself.
+pub(crate) async fn purge_trash_entry(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    entry: &TrashEntry,
+) -> Result<(), String> {
+    let failures = stream::iter(&entry.objects)
+        .map(|object| {
+            let path = trash_object_path(key, &entry.id, object);
+            async move {
+                match fs.delete_file(&path).await {
+                    Ok(()) => None,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                    Err(e) => Some(format!("{}: {}", path, e)),
+                }
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_TRASH_OBJECTS)
+        .filter_map(|failure| async move { failure })
+        .collect::<Vec<String>>()
+        .await;
+
+    if !failures.is_empty() {
+        return Err(format!(
+            "Failed to purge trash entry {}:\n{}",
+            entry.id,
+            failures
+                .iter()
+                .map(|failure| format!("  - {}", failure))
+                .collect::<Vec<String>>()
+                .join("\n")
+        ));
+    }
+
+    match fs.delete_file(&trash_entry_path(key, &entry.id)).await {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(format!("Failed to delete trash entry {}: {}", entry.id, e)),
+    }
+}
+
+/// Every trash entry of `key`, oldest first.
+pub(crate) async fn list_trash(
+    fs: Arc<dyn FS>,
+    key: String,
+    password: Option<String>,
+) -> Result<Vec<TrashEntry>, String> {
+    let entry_paths = fs
+        .list_files(&trash_folder(&key))
+        .await
+        .map_err(|e| format!("Failed to list the trash: {}", e))?
+        .into_iter()
+        .filter(|path| path.ends_with("/entry"))
+        .collect::<Vec<String>>();
+
+    let mut entries = Vec::new();
+
+    for entry_path in entry_paths {
+        let read_result = read_file_maybe_decrypt(
+            &fs,
+            &entry_path,
+            password.as_deref(),
+            "Trash entries are encrypted but no password provided",
+        )
+        .await?;
+
+        if read_result.bytes.is_empty() {
+            continue;
+        }
+
+        let entry_bytes = decompress_bytes(&read_result.bytes)
+            .map_err(|e| format!("Failed to decompress trash entry {}: {}", entry_path, e))?;
+        let entry: TrashEntry = rmp_serde::from_slice(&entry_bytes)
+            .map_err(|e| format!("Failed to deserialize trash entry {}: {}", entry_path, e))?;
+        entries.push(entry);
+    }
+
+    entries.sort_by_key(|entry| entry.deleted_at);
+
+    Ok(entries)
+}
+
+/// Copies each object from the first path returned by `paths` to the second one.
+async fn copy_objects(
+    fs: &Arc<dyn FS>,
+    key: &str,
+    objects: &[String],
+    paths: impl Fn(&str) -> (String, String),
+) -> Result<(), String> {
+    let failures = stream::iter(objects)
+        .map(|object| {
+            let (from, to) = paths(object);
+            async move {
+                let bytes = fs
+                    .read_file(&from)
+                    .await
+                    .map_err(|e| format!("{}: {}", from, e))?;
+                fs.write_file(&to, &bytes)
+                    .await
+                    .map_err(|e| format!("{}: {}", to, e))
+            }
+        })
+        .buffer_unordered(MAX_CONCURRENT_TRASH_OBJECTS)
+        .filter_map(|result| async move { result.err() })
+        .collect::<Vec<String>>()
+        .await;
+
+    if failures.is_empty() {
+        return Ok(());
+    }
+
+    Err(format!(
+        "Failed to copy {} objects of {}:\n{}",
+        failures.len(),
+        key,
+        failures
+            .iter()
+            .map(|failure| format!("  - {}", failure))
+            .collect::<Vec<String>>()
+            .join("\n")
+    ))
+}
+
+fn now_unix() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
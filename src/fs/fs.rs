@@ -1,9 +1,23 @@
 use async_trait::async_trait;
+use futures::TryStreamExt;
+use futures::stream::BoxStream;
 
 #[async_trait]
 pub trait FS: Send + Sync {
     async fn read_file(&self, path: &str) -> Result<Vec<u8>, std::io::Error>;
     async fn write_file(&self, path: &str, data: &[u8]) -> Result<(), std::io::Error>;
-    async fn list_files(&self, path: &str) -> Result<Vec<String>, std::io::Error>;
+    /// Lists every file under `path` lazily, so callers walking millions of keys (prune,
+    /// reindex --verify-chunks) only hold one listing page in memory at a time.
+    fn list_files_stream<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxStream<'a, Result<String, std::io::Error>>;
     async fn delete_file(&self, path: &str) -> Result<(), std::io::Error>;
+    /// Size in bytes of the stored object, without downloading it. Fails with
+    /// `ErrorKind::NotFound` when it does not exist.
+    async fn file_size(&self, path: &str) -> Result<u64, std::io::Error>;
+
+    async fn list_files(&self, path: &str) -> Result<Vec<String>, std::io::Error> {
+        self.list_files_stream(path).try_collect().await
+    }
 }
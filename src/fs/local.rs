@@ -1,72 +1,86 @@
-use crate::fs::FS;
-use async_trait::async_trait;
-use walkdir::WalkDir;
-
-pub struct LocalFS {
-    path: std::path::PathBuf,
-}
-
-impl LocalFS {
-    pub fn new(path: impl Into<std::path::PathBuf>) -> Self {
-        Self { path: path.into() }
-    }
-}
-
-#[async_trait]
-impl FS for LocalFS {
-    async fn read_file(&self, path: &str) -> Result<Vec<u8>, std::io::Error> {
-        std::fs::read(&self.path.join(path))
-    }
-
-    async fn write_file(&self, path: &str, data: &[u8]) -> Result<(), std::io::Error> {
-        let path = self.path.join(path);
-        let parent_dir = path.parent().unwrap();
-
-        if !parent_dir.exists() {
-            std::fs::create_dir_all(parent_dir).unwrap();
-        }
-
-        std::fs::write(path, data)
-    }
-
-    async fn list_files(&self, path: &str) -> Result<Vec<String>, std::io::Error> {
-        let mut files = Vec::new();
-
-        let full_path = self.path.join(path);
-
-        if !full_path.exists() {
-            return Ok(files);
-        }
-
-        for entry in WalkDir::new(full_path) {
-            let entry = entry?;
-            if entry.file_type().is_file() {
-                let path_str = entry
-                    .path()
-                    .strip_prefix(&self.path)
-                    .unwrap()
-                    .to_string_lossy()
-                    .replace('\\', "/");
-                files.push(path_str);
-            }
-        }
-
-        Ok(files)
-    }
-
-    async fn delete_file(&self, path: &str) -> Result<(), std::io::Error> {
-        let full_path = self.path.join(path);
-
-        std::fs::remove_file(&full_path)?;
-
-        if let Some(folder) = full_path.parent() {
-            if let Ok(mut it) = folder.read_dir() {
-                if it.next().is_none() {
-                    let _ = std::fs::remove_dir(folder);
-                }
-            }
-        }
-
-        Ok(())
-    }
-}
+use crate::fs::FS;
+use async_trait::async_trait;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, future};
+use tracing::instrument;
+use walkdir::WalkDir;
+
+pub struct LocalFS {
+    path: std::path::PathBuf,
+}
+
+impl LocalFS {
+    pub fn new(path: impl Into<std::path::PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl FS for LocalFS {
+    #[instrument(level = "debug", skip(self))]
+    async fn read_file(&self, path: &str) -> Result<Vec<u8>, std::io::Error> {
+        std::fs::read(&self.path.join(path))
+    }
+
+    #[instrument(level = "debug", skip(self, data), fields(bytes = data.len()))]
+    async fn write_file(&self, path: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let path = self.path.join(path);
+
+        if let Some(parent_dir) = path.parent() {
+            std::fs::create_dir_all(parent_dir)?;
+        }
+
+        std::fs::write(path, data)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    fn list_files_stream<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxStream<'a, Result<String, std::io::Error>> {
+        let full_path = self.path.join(path);
+
+        if !full_path.exists() {
+            return stream::empty().boxed();
+        }
+
+        stream::iter(WalkDir::new(full_path))
+            .filter_map(move |entry| {
+                let file = match entry {
+                    Ok(entry) if entry.file_type().is_file() => Some(Ok(entry
+                        .path()
+                        .strip_prefix(&self.path)
+                        .unwrap()
+                        .to_string_lossy()
+                        .replace('\\', "/"))),
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e.into())),
+                };
+
+                future::ready(file)
+            })
+            .boxed()
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn delete_file(&self, path: &str) -> Result<(), std::io::Error> {
+        let full_path = self.path.join(path);
+
+        std::fs::remove_file(&full_path)?;
+
+        if let Some(folder) = full_path.parent() {
+            if let Ok(mut it) = folder.read_dir() {
+                if it.next().is_none() {
+                    let _ = std::fs::remove_dir(folder);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn file_size(&self, path: &str) -> Result<u64, std::io::Error> {
+        std::fs::metadata(self.path.join(path)).map(|metadata| metadata.len())
+    }
+}
@@ -1,7 +1,9 @@
 mod fs;
 mod local;
+#[cfg(feature = "s3")]
 mod s3;
 
 pub use fs::FS;
 pub use local::LocalFS;
+#[cfg(feature = "s3")]
 pub use s3::{S3FS, S3FSConfig};
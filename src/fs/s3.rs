@@ -1,13 +1,28 @@
 use crate::fs::FS;
 use async_trait::async_trait;
+use aws_config::default_provider::credentials::DefaultCredentialsChain;
 use aws_credential_types::Credentials;
+use aws_credential_types::provider::{self, ProvideCredentials};
 use aws_sdk_s3 as s3;
 use aws_types::region::Region;
 use bytes::Bytes;
+use futures::stream::{self, BoxStream};
+use futures::{StreamExt, TryStreamExt};
+use s3::types::{CompletedMultipartUpload, CompletedPart};
+use tokio::sync::OnceCell;
+use tracing::{instrument, warn};
+
+/// Objects larger than this are uploaded in parts, so a large `--chunk-size` neither holds
+/// a second copy of the whole chunk nor runs into the single PUT size limit.
+const MULTIPART_THRESHOLD: usize = 8 * 1024 * 1024;
+/// Size of each part but the last; S3 requires at least 5 MiB.
+const MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
 
 pub struct S3FS {
     client: s3::Client,
     bucket: String,
+    /// Prepended to every key, with a trailing slash (`--prefix`); empty without one.
+    prefix: String,
 }
 
 pub struct S3FSConfig {
@@ -16,20 +31,75 @@ pub struct S3FSConfig {
     pub access_key: Option<String>,
     pub secret_key: Option<String>,
     pub endpoint: Option<String>,
+    pub aws_profile: Option<String>,
+    pub use_credential_chain: bool,
+    pub prefix: Option<String>,
+}
+
+/// Resolves credentials through the standard AWS provider chain (environment, profile,
+/// SSO, web identity, ECS, IMDS). Building the chain is async, so it happens on first use.
+#[derive(Debug)]
+struct CredentialChain {
+    region: Region,
+    profile: Option<String>,
+    chain: OnceCell<DefaultCredentialsChain>,
+}
+
+impl CredentialChain {
+    fn new(region: Region, profile: Option<String>) -> Self {
+        Self {
+            region,
+            profile,
+            chain: OnceCell::new(),
+        }
+    }
+
+    async fn chain(&self) -> &DefaultCredentialsChain {
+        self.chain
+            .get_or_init(|| async {
+                let mut builder = DefaultCredentialsChain::builder().region(self.region.clone());
+                if let Some(profile) = &self.profile {
+                    builder = builder.profile_name(profile);
+                }
+                builder.build().await
+            })
+            .await
+    }
+}
+
+impl ProvideCredentials for CredentialChain {
+    fn provide_credentials<'a>(&'a self) -> provider::future::ProvideCredentials<'a>
+    where
+        Self: 'a,
+    {
+        provider::future::ProvideCredentials::new(async move {
+            self.chain().await.provide_credentials().await
+        })
+    }
 }
 
 impl S3FS {
     pub fn new(config: S3FSConfig) -> Self {
         let region = config.region.expect("Region is required");
         let bucket = config.bucket.expect("Bucket is required");
-        let access_key = config.access_key.expect("Access key is required");
-        let secret_key = config.secret_key.expect("Secret key is required");
+        let region = Region::new(region);
 
-        let creds = Credentials::new(access_key, secret_key, None, None, "custom");
+        let credentials_provider = if config.use_credential_chain || config.aws_profile.is_some() {
+            s3::config::SharedCredentialsProvider::new(CredentialChain::new(
+                region.clone(),
+                config.aws_profile,
+            ))
+        } else {
+            let access_key = config.access_key.expect("Access key is required");
+            let secret_key = config.secret_key.expect("Secret key is required");
+
+            let creds = Credentials::new(access_key, secret_key, None, None, "custom");
+            s3::config::SharedCredentialsProvider::new(creds)
+        };
 
         let shared_config = aws_config::SdkConfig::builder()
-            .credentials_provider(s3::config::SharedCredentialsProvider::new(creds))
-            .region(Region::new(region))
+            .credentials_provider(credentials_provider)
+            .region(region)
             .build();
 
         let mut s3_config_builder = s3::config::Builder::from(&shared_config);
@@ -40,18 +110,117 @@ impl S3FS {
 
         let client = s3::Client::from_conf(s3_config);
 
-        Self { client, bucket }
+        let prefix = config
+            .prefix
+            .map_or_else(String::new, |prefix| format!("{}/", prefix));
+
+        Self {
+            client,
+            bucket,
+            prefix,
+        }
+    }
+
+    fn key(&self, path: &str) -> String {
+        format!("{}{}", self.prefix, path)
+    }
+
+    /// Uploads `data` as a multipart upload, one part at a time so only one part is copied
+    /// at once. The upload is aborted if a part fails, so no incomplete parts are left to
+    /// be billed.
+    async fn write_multipart(&self, path: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        let key = self.key(path);
+
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        let upload_id = upload.upload_id().ok_or_else(|| {
+            std::io::Error::other(format!("S3 returned no upload id for {}", key))
+        })?;
+
+        let result = self.upload_parts(&key, upload_id, data).await;
+
+        if result.is_err()
+            && let Err(e) = self
+                .client
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&key)
+                .upload_id(upload_id)
+                .send()
+                .await
+        {
+            warn!(key = %key, error = %e, "failed to abort multipart upload");
+        }
+
+        result
+    }
+
+    async fn upload_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+        data: &[u8],
+    ) -> Result<(), std::io::Error> {
+        let mut parts = Vec::new();
+
+        for (index, part) in data.chunks(MULTIPART_PART_SIZE).enumerate() {
+            // Part numbers start at 1.
+            let part_number = index as i32 + 1;
+
+            let uploaded = self
+                .client
+                .upload_part()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .part_number(part_number)
+                .body(Bytes::copy_from_slice(part).into())
+                .send()
+                .await
+                .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(uploaded.e_tag().map(str::to_string))
+                    .part_number(part_number)
+                    .build(),
+            );
+        }
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        Ok(())
     }
 }
 
 #[async_trait]
 impl FS for S3FS {
+    #[instrument(level = "debug", skip(self))]
     async fn read_file(&self, path: &str) -> Result<Vec<u8>, std::io::Error> {
         let resp = self
             .client
             .get_object()
             .bucket(&self.bucket)
-            .key(path)
+            .key(self.key(path))
             .send()
             .await
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
@@ -65,12 +234,20 @@ impl FS for S3FS {
         Ok(data.into_bytes().to_vec())
     }
 
+    #[instrument(level = "debug", skip(self, data), fields(bytes = data.len()))]
     async fn write_file(&self, path: &str, data: &[u8]) -> Result<(), std::io::Error> {
+        if data.len() > MULTIPART_THRESHOLD {
+            return self.write_multipart(path, data).await;
+        }
+
+        // The SDK needs an owned body and `FS::write_file` only lends `data`, so one copy
+        // cannot be avoided without changing the trait; it is at most
+        // `MULTIPART_THRESHOLD` bytes here, and one part at a time above it.
         self.client
             .put_object()
             .bucket(&self.bucket)
-            .key(path)
-            .body(Bytes::from(data.to_vec()).into())
+            .key(self.key(path))
+            .body(Bytes::copy_from_slice(data).into())
             .send()
             .await
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
@@ -78,57 +255,349 @@ impl FS for S3FS {
         Ok(())
     }
 
-    async fn list_files(&self, path: &str) -> Result<Vec<String>, std::io::Error> {
-        let mut files = Vec::new();
-        let mut continuation_token = None;
+    #[instrument(level = "debug", skip(self))]
+    fn list_files_stream<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> BoxStream<'a, Result<String, std::io::Error>> {
+        // Listing under `path/` rather than `path` keeps `key` from matching `key2`.
         let prefix = if path.is_empty() {
-            "".to_string()
+            self.prefix.clone()
         } else if path.ends_with('/') {
-            path.to_string()
+            self.key(path)
         } else {
-            format!("{}/", path)
+            format!("{}/", self.key(path))
         };
 
-        loop {
-            let mut req = self
-                .client
-                .list_objects_v2()
-                .bucket(&self.bucket)
-                .prefix(&prefix);
+        // `None` state means the last page was already fetched; the first request starts
+        // with `Some(None)` (no continuation token yet).
+        let pages = stream::try_unfold(Some(None::<String>), move |continuation_token| {
+            let prefix = prefix.clone();
 
-            if let Some(ref token) = continuation_token {
-                req = req.continuation_token(token);
-            }
+            async move {
+                let Some(continuation_token) = continuation_token else {
+                    return Ok(None);
+                };
 
-            let resp = req
-                .send()
-                .await
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+                let mut req = self
+                    .client
+                    .list_objects_v2()
+                    .bucket(&self.bucket)
+                    .prefix(&prefix);
 
-            for obj in resp.contents() {
-                if let Some(key) = obj.key() {
-                    files.push(key.to_string());
+                if let Some(ref token) = continuation_token {
+                    req = req.continuation_token(token);
                 }
-            }
 
-            continuation_token = resp.next_continuation_token().map(|ct| ct.to_string());
+                let resp = req
+                    .send()
+                    .await
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+
+                let keys = resp
+                    .contents()
+                    .iter()
+                    .filter_map(|obj| obj.key())
+                    .filter_map(|key| key.strip_prefix(&self.prefix))
+                    .map(|key| Ok(key.to_string()))
+                    .collect::<Vec<Result<String, std::io::Error>>>();
 
-            if continuation_token.is_none() {
-                break;
+                let next_state = resp
+                    .next_continuation_token()
+                    .map(|ct| Some(ct.to_string()));
+
+                Ok::<_, std::io::Error>(Some((stream::iter(keys), next_state)))
             }
-        }
+        });
 
-        Ok(files)
+        pages.try_flatten().boxed()
     }
 
+    #[instrument(level = "debug", skip(self))]
     async fn delete_file(&self, path: &str) -> Result<(), std::io::Error> {
         self.client
             .delete_object()
             .bucket(&self.bucket)
-            .key(path)
+            .key(self.key(path))
             .send()
             .await
             .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
         Ok(())
     }
+
+    #[instrument(level = "debug", skip(self))]
+    async fn file_size(&self, path: &str) -> Result<u64, std::io::Error> {
+        let resp = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.key(path))
+            .send()
+            .await
+            .map_err(|e| {
+                let kind = match e.as_service_error() {
+                    Some(service_error) if service_error.is_not_found() => {
+                        std::io::ErrorKind::NotFound
+                    }
+                    _ => std::io::ErrorKind::Other,
+                };
+                std::io::Error::new(kind, e.to_string())
+            })?;
+
+        Ok(resp.content_length().unwrap_or(0).max(0) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_smithy_runtime_api::client::http::{
+        HttpClient, HttpConnector, HttpConnectorFuture, HttpConnectorSettings, SharedHttpConnector,
+    };
+    use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
+    use aws_smithy_runtime_api::client::runtime_components::RuntimeComponents;
+    use aws_smithy_types::body::SdkBody;
+    use s3::config::retry::RetryConfig;
+    use s3::config::{BehaviorVersion, RequestChecksumCalculation};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug)]
+    struct Request {
+        method: String,
+        uri: String,
+        body: Vec<u8>,
+    }
+
+    /// Answers like S3 would and records every request. The part numbered `failing_part`
+    /// is answered with a 500.
+    #[derive(Clone, Debug, Default)]
+    struct MockS3 {
+        requests: Arc<Mutex<Vec<Request>>>,
+        failing_part: Option<u32>,
+    }
+
+    impl MockS3 {
+        fn respond(&self, request: &Request) -> (u16, Vec<(&'static str, String)>, String) {
+            let part_number = request
+                .uri
+                .split(['?', '&'])
+                .find_map(|param| param.strip_prefix("partNumber="))
+                .map(|number| number.parse::<u32>().unwrap());
+
+            match (request.method.as_str(), part_number) {
+                ("POST", _) if request.uri.contains("uploads") => (
+                    200,
+                    Vec::new(),
+                    "<InitiateMultipartUploadResult><Bucket>bucket</Bucket><Key>key</Key><UploadId>upload-1</UploadId></InitiateMultipartUploadResult>".to_string(),
+                ),
+                ("PUT", Some(number)) if self.failing_part == Some(number) => (
+                    500,
+                    Vec::new(),
+                    "<Error><Code>InternalError</Code><Message>boom</Message></Error>".to_string(),
+                ),
+                ("PUT", Some(number)) => (
+                    200,
+                    vec![("etag", format!("\"etag-{}\"", number))],
+                    String::new(),
+                ),
+                ("PUT", None) => (200, vec![("etag", "\"etag\"".to_string())], String::new()),
+                ("POST", _) => (
+                    200,
+                    Vec::new(),
+                    "<CompleteMultipartUploadResult><ETag>\"etag\"</ETag></CompleteMultipartUploadResult>".to_string(),
+                ),
+                ("DELETE", _) => (204, Vec::new(), String::new()),
+                _ => (400, Vec::new(), String::new()),
+            }
+        }
+
+        fn requests(&self) -> Vec<(String, String, usize)> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|request| {
+                    let query = request.uri.split_once('?').map_or("", |(_, query)| query);
+                    // The SDK adds an `x-id=` parameter naming the operation; drop it.
+                    let query = query
+                        .split('&')
+                        .filter(|param| !param.starts_with("x-id="))
+                        .collect::<Vec<&str>>()
+                        .join("&");
+                    (request.method.clone(), query, request.body.len())
+                })
+                .collect()
+        }
+
+        /// The bodies of the uploaded parts (or the single PUT), in order.
+        fn uploaded(&self) -> Vec<u8> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|request| request.method == "PUT")
+                .flat_map(|request| request.body.clone())
+                .collect()
+        }
+    }
+
+    impl HttpConnector for MockS3 {
+        fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+            let request = Request {
+                method: request.method().to_string(),
+                uri: request.uri().to_string(),
+                body: request.body().bytes().unwrap_or_default().to_vec(),
+            };
+
+            let (status, headers, body) = self.respond(&request);
+            self.requests.lock().unwrap().push(request);
+
+            let mut response = HttpResponse::new(status.try_into().unwrap(), SdkBody::from(body));
+            for (name, value) in headers {
+                response.headers_mut().insert(name, value);
+            }
+            HttpConnectorFuture::ready(Ok(response))
+        }
+    }
+
+    impl HttpClient for MockS3 {
+        fn http_connector(
+            &self,
+            _settings: &HttpConnectorSettings,
+            _components: &RuntimeComponents,
+        ) -> SharedHttpConnector {
+            SharedHttpConnector::new(self.clone())
+        }
+    }
+
+    fn s3fs(mock: &MockS3) -> S3FS {
+        let config = s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .credentials_provider(Credentials::new("access", "secret", None, None, "test"))
+            .http_client(mock.clone())
+            .retry_config(RetryConfig::disabled())
+            .force_path_style(true)
+            // Plain bodies, so the mock sees exactly the bytes of each part.
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+            .build();
+
+        S3FS {
+            client: s3::Client::from_conf(config),
+            bucket: "bucket".to_string(),
+            prefix: "prefix/".to_string(),
+        }
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|index| (index % 251) as u8).collect()
+    }
+
+    #[tokio::test]
+    async fn small_objects_are_a_single_put() {
+        let mock = MockS3::default();
+        let data = data(1024);
+
+        s3fs(&mock)
+            .write_file("repo/chunks/aa", &data)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            mock.requests(),
+            vec![("PUT".to_string(), String::new(), 1024)]
+        );
+        let uri = mock.requests.lock().unwrap()[0].uri.clone();
+        assert!(
+            uri.split('?')
+                .next()
+                .unwrap()
+                .ends_with("/bucket/prefix/repo/chunks/aa"),
+            "{}",
+            uri
+        );
+        assert_eq!(mock.uploaded(), data);
+    }
+
+    #[tokio::test]
+    async fn large_objects_are_uploaded_in_parts() {
+        let mock = MockS3::default();
+        let data = data(2 * MULTIPART_PART_SIZE + 100);
+
+        s3fs(&mock)
+            .write_file("repo/chunks/aa", &data)
+            .await
+            .unwrap();
+
+        let requests = mock.requests();
+        assert_eq!(
+            requests[..4],
+            [
+                ("POST".to_string(), "uploads".to_string(), 0),
+                (
+                    "PUT".to_string(),
+                    "partNumber=1&uploadId=upload-1".to_string(),
+                    MULTIPART_PART_SIZE
+                ),
+                (
+                    "PUT".to_string(),
+                    "partNumber=2&uploadId=upload-1".to_string(),
+                    MULTIPART_PART_SIZE
+                ),
+                (
+                    "PUT".to_string(),
+                    "partNumber=3&uploadId=upload-1".to_string(),
+                    100
+                ),
+            ]
+        );
+        assert_eq!(requests.len(), 5);
+        assert_eq!(
+            (requests[4].0.as_str(), requests[4].1.as_str()),
+            ("POST", "uploadId=upload-1")
+        );
+        assert_eq!(mock.uploaded(), data);
+
+        // Every part is completed with the ETag S3 returned for it.
+        let complete = String::from_utf8(mock.requests.lock().unwrap()[4].body.clone()).unwrap();
+        for number in 1..=3 {
+            assert!(
+                complete.contains(&format!("<PartNumber>{}</PartNumber>", number)),
+                "{}",
+                complete
+            );
+            assert!(
+                complete.contains(&format!("etag-{}", number)),
+                "{}",
+                complete
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_failed_part_aborts_the_upload() {
+        let mock = MockS3 {
+            failing_part: Some(2),
+            ..MockS3::default()
+        };
+        let data = data(3 * MULTIPART_PART_SIZE);
+
+        let result = s3fs(&mock).write_file("repo/chunks/aa", &data).await;
+
+        assert!(result.is_err());
+        let methods = mock
+            .requests()
+            .into_iter()
+            .map(|(method, query, _)| format!("{} {}", method, query))
+            .collect::<Vec<String>>();
+        assert_eq!(
+            methods,
+            vec![
+                "POST uploads",
+                "PUT partNumber=1&uploadId=upload-1",
+                "PUT partNumber=2&uploadId=upload-1",
+                "DELETE uploadId=upload-1",
+            ]
+        );
+    }
 }
@@ -1,8 +1,8 @@
 use clap::{Arg, Command, arg};
 
 use crate::output::{
-    detect_mode_from_args, emit_error, emit_help, emit_version, init_panic_hook_if_json,
-    is_json_mode, set_output_mode,
+    OutputMode, detect_mode_from_args, emit_error, emit_help, emit_version, init_logging,
+    init_panic_hook_if_json, is_json_mode, set_non_interactive, set_output_mode,
 };
 use crate::utils::handle_error;
 
@@ -23,18 +23,71 @@ fn cli() -> Command {
             Arg::new("mode")
                 .long("mode")
                 .value_name("MODE")
-                .help("Output mode")
+                .help("Output mode: 'json' writes one JSON event per line to stdout and never prompts")
                 .default_value("interactive")
                 .value_parser(["interactive", "json"])
                 .global(true),
         )
+        .arg(
+            Arg::new("non-interactive")
+                .long("non-interactive")
+                .help("Never prompt: fail on missing arguments and treat confirmations as 'no' unless --yes is given")
+                .action(clap::ArgAction::SetTrue)
+                .global(true),
+        )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .long("verbose")
+                .help("Log what gib is doing to stderr (repeat for more detail: -v, -vv, -vvv). GIB_LOG or RUST_LOG can be used instead")
+                .action(clap::ArgAction::Count)
+                .global(true),
+        )
         .subcommand(
             Command::new("config")
                 .about("Configure your backup tool")
                 .arg(
                 arg!(-a --author <AUTHOR> "Your identity like 'John Doe <john.doe@example.com>'")
                     .required(false),
-            ),
+            )
+                .arg(
+                    Arg::new("default-storage")
+                        .long("default-storage")
+                        .value_name("STORAGE")
+                        .help("The storage to use when --storage is not provided (pass an empty value to unset)")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("default-compress")
+                        .long("default-compress")
+                        .value_name("LEVEL")
+                        .help("The compression level (1-22), or 'store' (or 0) to keep chunks uncompressed, used by backups when --compress is not provided (pass an empty value to unset)")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("default-key")
+                        .long("default-key")
+                        .value_name("KEY")
+                        .help("The repository key to use when --key is not provided, instead of the current folder's name (pass an empty value to unset)")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("show")
+                        .long("show")
+                        .help("Show the current config")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("migrate-storages")
+                        .long("migrate-storages")
+                        .help("Rewrite storages saved by older versions of gib in the current format")
+                        .conflicts_with_all(["author", "default-storage", "default-compress", "default-key", "show"])
+                        .action(clap::ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("spec")
+                .about("Print the on-disk repository format as JSON, for writing third-party readers")
         )
         .subcommand(
             Command::new("whoami")
@@ -46,6 +99,32 @@ fn cli() -> Command {
                 .arg(arg!(-p --password <PASSWORD> "The password to use for the encryption").required(false))
                 .arg(arg!(-s --storage <STORAGE> "The storage to use for the encryption").required(false))
                 .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                .arg(
+                    Arg::new("concurrency")
+                        .long("concurrency")
+                        .help("How many files to encrypt at the same time [default: 100]")
+                        .value_name("CONCURRENCY")
+                        .required(false),
+                )
+        )
+        .subcommand(
+            Command::new("reindex")
+                .about("Rebuild the chunk and backup indexes of a repository from its backup manifests")
+                .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Only show what the rebuilt indexes would look like, without writing them")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("verify-chunks")
+                        .long("verify-chunks")
+                        .help("Check that every chunk referenced by a manifest exists in the storage")
+                        .action(clap::ArgAction::SetTrue),
+                )
         )
         .subcommand(
             Command::new("log")
@@ -53,6 +132,238 @@ fn cli() -> Command {
                 .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
                 .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
                 .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                .arg(arg!(-a --author <AUTHOR> "Only show backups whose author contains this text (case-insensitive)").required(false))
+                .arg(
+                    Arg::new("graph")
+                        .long("graph")
+                        .help("Show a bar chart of the new data each backup wrote instead of the list (ignored in --mode json)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("compare-worktree")
+                        .long("compare-worktree")
+                        .value_name("ROOT")
+                        .help("Instead of the list, show the files of ROOT that are new, modified or deleted since the latest backup")
+                        .conflicts_with("graph")
+                        .required(false),
+                )
+        )
+        .subcommand(
+            Command::new("cat")
+                .about("Write one file of a backup to stdout")
+                .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                .arg(arg!(-b --backup <BACKUP> "The backup to read the file from (full hash or a prefix; default: the newest backup)").required(false))
+                .arg(
+                    Arg::new("path")
+                        .value_name("PATH")
+                        .help("The path of the file inside the backup, relative to its root")
+                        .required(true),
+                )
+        )
+        .subcommand(
+            Command::new("diff")
+                .about("List the files added, removed and modified between two backups, or since a backup")
+                .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                .arg(
+                    Arg::new("from")
+                        .value_name("BACKUP_A")
+                        .help("The backup to compare from (full hash or first 8 chars)")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("to")
+                        .value_name("BACKUP_B")
+                        .help("The backup to compare with (full hash or first 8 chars; default: the files of --root)")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("root")
+                        .long("root")
+                        .value_name("ROOT")
+                        .help("Without BACKUP_B, the directory to compare the backup with [default: current directory]")
+                        .conflicts_with("to")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("ignore")
+                        .short('i')
+                        .long("ignore")
+                        .value_name("IGNORE")
+                        .help("Without BACKUP_B, patterns to leave out of the directory, in .gitignore syntax, added after its .gibignore file (can be used multiple times)")
+                        .conflicts_with("to")
+                        .required(false)
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("exclude-vcs")
+                        .long("exclude-vcs")
+                        .help("Without BACKUP_B, leave out version-control directories (.git, .hg, .svn, .bzr, _darcs, CVS)")
+                        .conflicts_with("to")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("ignore-case")
+                        .long("ignore-case")
+                        .help("Match --ignore patterns and --exclude-path case-insensitively")
+                        .conflicts_with("to")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("exclude-path")
+                        .long("exclude-path")
+                        .value_name("PATH")
+                        .help("Without BACKUP_B, leave out a specific file or folder, absolute or relative to --root (can be used multiple times)")
+                        .conflicts_with("to")
+                        .required(false)
+                        .action(clap::ArgAction::Append),
+                )
+        )
+        .subcommand(
+            Command::new("stats")
+                .about("Show how much data a repository holds and how well it deduplicates")
+                .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                .arg(
+                    Arg::new("over-time")
+                        .long("over-time")
+                        .help("Show how the logical and stored size evolved with every backup")
+                        .action(clap::ArgAction::SetTrue),
+                )
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Read back the chunks of every backup and check that they are intact")
+                .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("BACKUP")
+                        .help("Only verify the chunks added by backups newer than this one (full hash or a prefix)")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("incremental")
+                        .long("incremental")
+                        .help("Only verify the chunks added since the last verify that found no problems")
+                        .conflicts_with("since")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("concurrency")
+                        .long("concurrency")
+                        .help("How many chunks to read at the same time [default: 100]")
+                        .value_name("CONCURRENCY")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("verify-signatures")
+                        .long("verify-signatures")
+                        .help("Instead of reading chunks, check the manifests and chunk index against the signatures of 'backup --sign-key' (no password needed)")
+                        .requires("public-key")
+                        .conflicts_with_all(["since", "incremental"])
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("public-key")
+                        .long("public-key")
+                        .value_name("PATH")
+                        .help("The Ed25519 public key (PEM) to check signatures with")
+                        .requires("verify-signatures")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("output-repair-plan")
+                        .long("output-repair-plan")
+                        .value_name("PATH")
+                        .help("Also write the problems found and how to fix them to PATH as JSON, for 'gib repair --plan'")
+                        .conflicts_with("verify-signatures")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("repair-from")
+                        .long("repair-from")
+                        .value_name("STORAGE")
+                        .help("Rewrite failed chunks from an intact copy in another storage holding the same repository (can be used multiple times, tried in order)")
+                        .conflicts_with("verify-signatures")
+                        .action(clap::ArgAction::Append),
+                )
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Check that the chunk index, the stored chunks and every backup agree, and that each chunk is intact")
+                .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                .arg(
+                    Arg::new("concurrency")
+                        .long("concurrency")
+                        .help("How many chunks to read at the same time [default: 100]")
+                        .value_name("CONCURRENCY")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("repair-refcounts")
+                        .long("repair-refcounts")
+                        .help("Recount how many times the backups reference each chunk and rewrite the refcounts in the chunk index that drifted (the previous index is kept as a .bak)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("With --repair-refcounts, only report the drifted refcounts")
+                        .requires("repair-refcounts")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("lock-timeout")
+                        .long("lock-timeout")
+                        .value_name("DURATION")
+                        .help("With --repair-refcounts, how long to wait for a backup of the same repository to release its lock, like '30s' or '10m' [default: don't wait]")
+                        .requires("repair-refcounts")
+                        .required(false),
+                )
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Diagnose common setup problems, and fix the safe ones with --fix")
+                .arg(arg!(-k --key <KEY> "Also check the lock and indexes of this repository").required(false))
+                .arg(arg!(-s --storage <STORAGE> "The storage of the repository to check").required(false))
+                .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                .arg(
+                    Arg::new("fix")
+                        .long("fix")
+                        .help("Fix the issues that can be fixed automatically; migrating storages and removing stale locks asks first")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("yes")
+                        .short('y')
+                        .long("yes")
+                        .help("Skip confirmation prompts")
+                        .requires("fix")
+                        .action(clap::ArgAction::SetTrue)
+                        .required(false),
+                )
+        )
+        .subcommand(
+            Command::new("repair")
+                .about("Fix the problems of a repair plan written by 'gib verify --output-repair-plan'")
+                .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                .arg(
+                    Arg::new("plan")
+                        .long("plan")
+                        .value_name("PATH")
+                        .help("The repair plan to apply")
+                        .required(true),
+                )
         )
         .subcommand(
             Command::new("backup")
@@ -61,13 +372,43 @@ fn cli() -> Command {
                 .arg(arg!(-m --message <MESSAGE> "The backup message").required(false))
                 .arg(arg!(-s --storage <STORAGE> "The storage to use for the backup").required(false))
                 .arg(arg!(-p --password <PASSWORD> "The password to use for the backup").required(false))
-                .arg(arg!(-c --compress <COMPRESS> "The compression level to use for the backup").required(false))
+                .arg(arg!(-c --compress <COMPRESS> "The compression level to use for the backup (1-22), or 'store' (or 0) to write chunks uncompressed").required(false))
+                .arg(
+                    Arg::new("store-extensions")
+                        .long("store-extensions")
+                        .value_name("EXTS")
+                        .help("Comma-separated file extensions whose chunks are stored uncompressed, replacing the default list of compressed formats (jpg, mp4, zip, ...); pass an empty value to compress every file")
+                        .required(false),
+                )
                 .arg(
                     Arg::new("chunk-size")
                         .short('z')
                         .long("chunk-size")
                         .value_name("CHUNK_SIZE")
-                        .help("The chunk size to use for the backup (default: 5 MB)")
+                        .help("The chunk size to use for the backup, the average size with --chunker cdc (default: 5 MB)")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("chunker")
+                        .long("chunker")
+                        .value_name("CHUNKER")
+                        .help("How to split files into chunks: 'fixed' slices of --chunk-size, or 'cdc' to cut at content-defined boundaries so edits only change nearby chunks (default: fixed)")
+                        .value_parser(["fixed", "cdc"])
+                        .conflicts_with("exclude-bigger-deltas")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("min-chunk-size")
+                        .long("min-chunk-size")
+                        .value_name("SIZE")
+                        .help("With --chunker cdc, the smallest chunk except at the end of a file (default: a quarter of --chunk-size)")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("max-chunk-size")
+                        .long("max-chunk-size")
+                        .value_name("SIZE")
+                        .help("With --chunker cdc, the largest chunk (default: twice --chunk-size)")
                         .required(false),
                 )
                 .arg(
@@ -78,20 +419,85 @@ fn cli() -> Command {
                         .help("The root path to backup")
                         .required(false),
                 )
+                .arg(
+                    Arg::new("follow-root-symlink")
+                        .long("follow-root-symlink")
+                        .help("If the root path is a symlink, back up its target and name the repository after the target instead of the link")
+                        .action(clap::ArgAction::SetTrue),
+                )
                 .arg(
                     Arg::new("ignore")
                         .short('i')
                         .long("ignore")
                         .value_name("IGNORE")
-                        .help("File or folder names to ignore (can be used multiple times)")
+                        .help("Patterns to ignore, in .gitignore syntax like '*.log', 'build/' or '!keep.log', added after the .gibignore file of the root (can be used multiple times)")
                         .required(false)
                         .action(clap::ArgAction::Append),
                 )
+                .arg(
+                    Arg::new("exclude-vcs")
+                        .long("exclude-vcs")
+                        .help("Ignore version-control directories (.git, .hg, .svn, .bzr, _darcs, CVS)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("ignore-case")
+                        .long("ignore-case")
+                        .help("Match --ignore patterns and --exclude-path case-insensitively")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("exclude-path")
+                        .long("exclude-path")
+                        .value_name("PATH")
+                        .help("Exclude a specific file or folder, absolute or relative to the root path (can be used multiple times)")
+                        .required(false)
+                        .action(clap::ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .help("Only show which files would be backed up and what the excluded paths cover, without uploading anything")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("detailed")
+                        .long("detailed")
+                        .help("With --dry-run, read and hash every file to predict how much new data the backup would upload (I/O heavy)")
+                        .requires("dry-run")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("list-ignored")
+                        .long("list-ignored")
+                        .help("With --dry-run, list every ignored path and the rule that matched it")
+                        .requires("dry-run")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("report-json")
+                        .long("report-json")
+                        .value_name("PATH")
+                        .help("Also write the result of the backup, with its warnings, as a JSON object to PATH (in any --mode)")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("fail-if-empty")
+                        .long("fail-if-empty")
+                        .help("Fail instead of only warning when no files are selected for backup")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("max-backups")
+                        .long("max-backups")
+                        .value_name("N")
+                        .help("After a successful backup, delete the oldest backups so that at most N remain"),
+                )
                 .arg(
                     Arg::new("continue")
                         .long("continue")
                         .value_name("BACKUP")
-                        .help("Continue the backup from an incomplete backup")
+                        .help("Continue an incomplete backup by its hash or hash prefix (see 'gib backup pending')")
                         .required(false),
                 )
                 .arg(
@@ -101,6 +507,216 @@ fn cli() -> Command {
                         .value_name("CONCURRENCY")
                         .required(false),
                 )
+                .arg(
+                    Arg::new("sign-key")
+                        .long("sign-key")
+                        .value_name("PATH")
+                        .help("Sign the manifest and chunk index with this Ed25519 private key (PEM), so 'gib verify --verify-signatures' can check them with the public key alone")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("concurrency-auto")
+                        .long("concurrency-auto")
+                        .help("Start at --concurrency and adjust it while backing up: raise it while the throughput rises, lower it when it drops or writes fail")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("chunk-cache-size")
+                        .long("chunk-cache-size")
+                        .value_name("FILES")
+                        .help("Remember the chunks of up to FILES recent files by size and first bytes, so files with the same content (node_modules, build outputs) are only hashed to confirm it instead of chunked again")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("low-memory")
+                        .long("low-memory")
+                        .help("Keep the chunk index and the new backup's file list in temporary files instead of memory, for very large trees on small machines (slower)")
+                        .conflicts_with_all(["content-addressed-manifests", "exclude-from-backup", "detect-renames", "exclude-bigger-deltas", "assume-unchanged", "budget", "output-manifest"])
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("timestamp")
+                        .long("timestamp")
+                        .value_name("UNIX")
+                        .help("Record this unix timestamp (seconds) for the backup instead of the current time; SOURCE_DATE_EPOCH is used when unset")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("exclude-newer-than")
+                        .long("exclude-newer-than")
+                        .value_name("UNIX|start")
+                        .help("Only back up files last modified at or before this unix timestamp (seconds), or before the backup started with 'start'; newer files are left for the next backup")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("budget")
+                        .long("budget")
+                        .alias("exclude-larger-total")
+                        .value_name("SIZE")
+                        .help("Stop adding files once the new (non-deduplicated) bytes of this backup reach SIZE, like '5 GB'; remaining files are skipped")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("max-chunk-objects-per-dir")
+                        .long("max-chunk-objects-per-dir")
+                        .value_name("N")
+                        .help("For a new repository, nest chunks two folders deep if one level would put more than N chunks in each folder")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("priority-order")
+                        .long("priority-order")
+                        .value_name("ORDER")
+                        .help("The order in which files are backed up when using --budget [default: path-first]")
+                        .value_parser(["path-first", "smallest-first", "largest-first"])
+                        .requires("budget"),
+                )
+                .arg(
+                    Arg::new("progress-every")
+                        .long("progress-every")
+                        .value_name("N")
+                        .help("In JSON mode, emit a progress event at most every N files [default: 1]")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("progress-interval")
+                        .long("progress-interval")
+                        .value_name("MS")
+                        .help("In JSON mode, emit a progress event at most every MS milliseconds, 0 for every file [default: 100]")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("json-progress-bytes")
+                        .long("json-progress-bytes")
+                        .help("In JSON mode, add bytes read, written and deduplicated to progress events")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("output-manifest")
+                        .long("output-manifest")
+                        .value_name("PATH")
+                        .help("Also write the manifest of the new backup to a local JSON file")
+                        .conflicts_with("split-by-top-level"),
+                )
+                .arg(
+                    Arg::new("split-by-top-level")
+                        .long("split-by-top-level")
+                        .help("Back up each subdirectory of the root path as its own repository, named after the subdirectory")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with_all(["key", "continue", "dry-run"]),
+                )
+                .arg(
+                    Arg::new("one-file-system")
+                        .long("one-file-system")
+                        .help("Don't descend into directories on other filesystems (mount points), except the ones given with --include-mount (Unix)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("include-mount")
+                        .long("include-mount")
+                        .value_name("PATH")
+                        .help("With --one-file-system, still back up this mount point (absolute or relative to the root path, repeatable)")
+                        .action(clap::ArgAction::Append)
+                        .requires("one-file-system"),
+                )
+                .arg(
+                    Arg::new("lock-timeout")
+                        .long("lock-timeout")
+                        .value_name("DURATION")
+                        .help("How long to wait for another backup of the same repository to release its lock, like '30s' or '10m' [default: don't wait]")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("pre-hook")
+                        .long("pre-hook")
+                        .value_name("COMMAND")
+                        .help("Shell command to run before listing files; the backup is aborted if it fails")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("post-hook")
+                        .long("post-hook")
+                        .value_name("COMMAND")
+                        .help("Shell command to run after the backup, also when it fails (GIB_BACKUP_STATUS is 'success' or 'failure')")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("verify-chunks-after-write")
+                        .long("verify-chunks-after-write")
+                        .help("Read every new chunk back after writing it and rewrite it if it differs (doubles storage I/O)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("store-acls")
+                        .long("store-acls")
+                        .help("Store the POSIX ACLs of files and reapply them on restore (Linux only)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("abort-on-warning")
+                        .long("abort-on-warning")
+                        .help("Fail the backup on any warning (unencrypted chunks, skipped files, leftover pending files, ...)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("skip-permission-denied")
+                        .long("skip-permission-denied")
+                        .help("Skip files that can't be read due to permissions, reporting them as warnings")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("skip-vanished")
+                        .long("skip-vanished")
+                        .help("Skip files deleted between listing and reading them, reporting them as warnings")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("detect-renames")
+                        .long("detect-renames")
+                        .help("Reuse the previous backup's entry for moved or renamed files (same inode, size and mtime) instead of re-reading them")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("exclude-bigger-deltas")
+                        .long("exclude-bigger-deltas")
+                        .help("Only read the new data of files that were appended to since the previous backup (logs, WALs), reusing the chunks of their previous content. A file counts as appended to when it kept its inode, grew, and its first and last 64 KiB and last reused chunk are unchanged; a rewrite elsewhere in its old content is not detected and the old data is kept")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("follow-symlinks")
+                        .long("follow-symlinks")
+                        .help("Back up the content of the files symbolic links point to instead of the links themselves")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("assume-unchanged")
+                        .long("assume-unchanged")
+                        .value_name("GLOB")
+                        .help("Reuse the previous backup's entry for files matching GLOB that still have the same size, without reading them; other changes to them are not detected (repeatable)")
+                        .action(clap::ArgAction::Append)
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("exclude-from-backup")
+                        .long("exclude-from-backup")
+                        .value_name("BACKUP")
+                        .help("Store only the files that differ from BACKUP (full hash or first 8 chars), as a differential backup that needs it to be restored")
+                        .conflicts_with_all(["content-addressed-manifests", "split-by-top-level"])
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("content-addressed-manifests")
+                        .long("content-addressed-manifests")
+                        .help("Store the manifest under the hash of the backed up tree, so backups of identical content share one manifest")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("stats")
+                        .long("stats")
+                        .alias("stats-only")
+                        .help("Print a detailed breakdown of chunks, compression and per-directory sizes after the backup")
+                        .action(clap::ArgAction::SetTrue),
+                )
                 .subcommand(
                     Command::new("pending")
                         .about("List pending backups for a repository")
@@ -115,6 +731,54 @@ fn cli() -> Command {
                         .arg(arg!(-b --backup <BACKUP> "The backup hash to delete (full hash or first 8 chars)").required(false))
                         .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
                         .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                        .arg(
+                            Arg::new("trash-retention")
+                                .long("trash-retention")
+                                .value_name("DURATION")
+                                .help("Move the deleted backup to the trash for this long (example: '7d') so 'gib trash restore' can undo the delete")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("keep-last")
+                                .long("keep-last")
+                                .value_name("N")
+                                .help("Instead of one backup, delete every backup except the N newest and those kept by the other --keep-* rules")
+                                .conflicts_with_all(["backup", "trash-retention"]),
+                        )
+                        .arg(
+                            Arg::new("keep-daily")
+                                .long("keep-daily")
+                                .value_name("N")
+                                .help("Keep the newest backup of each of the last N days that have one")
+                                .conflicts_with_all(["backup", "trash-retention"]),
+                        )
+                        .arg(
+                            Arg::new("keep-weekly")
+                                .long("keep-weekly")
+                                .value_name("N")
+                                .help("Keep the newest backup of each of the last N weeks that have one")
+                                .conflicts_with_all(["backup", "trash-retention"]),
+                        )
+                        .arg(
+                            Arg::new("keep-monthly")
+                                .long("keep-monthly")
+                                .value_name("N")
+                                .help("Keep the newest backup of each of the last N months that have one")
+                                .conflicts_with_all(["backup", "trash-retention"]),
+                        )
+                        .arg(
+                            Arg::new("dry-run")
+                                .long("dry-run")
+                                .help("With --keep-*, only list which backups would be kept and deleted")
+                                .action(clap::ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("yes")
+                                .short('y')
+                                .long("yes")
+                                .help("With --keep-*, skip the confirmation prompt")
+                                .action(clap::ArgAction::SetTrue),
+                        )
                 )
         )
         .subcommand(
@@ -141,6 +805,33 @@ fn cli() -> Command {
                         .help("The target directory to restore files to (default: current directory)")
                         .required(false),
                 )
+                .arg(
+                    Arg::new("into-subdir")
+                        .long("into-subdir")
+                        .value_name("NAME")
+                        .help("Restore into a folder NAME inside the target directory, creating it")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Do not warn when restoring straight into a non-empty current directory")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("stdout")
+                        .long("stdout")
+                        .help("Write the selected files to stdout as a tar archive instead of restoring them (not available with --mode json)")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with_all([
+                            "target-path", "into-subdir", "force", "prune-local", "progress-resume",
+                            "only-changed", "touch-only", "exclude-existing-newer", "checksum",
+                            "json-progress-bytes", "sparse", "hardlink-identical", "max-retries",
+                            "fallback-storage", "continue-on-error", "resolve-conflicts", "flatten",
+                            "pre-hook", "post-hook", "atomic", "store-ownership-manifest",
+                            "ignore-permissions", "owner-map",
+                        ]),
+                )
                 .arg(
                     Arg::new("prune-local")
                         .short('d')
@@ -150,6 +841,194 @@ fn cli() -> Command {
                         .action(clap::ArgAction::SetTrue)
                         .required(false),
                 )
+                .arg(
+                    Arg::new("progress-resume")
+                        .long("progress-resume")
+                        .help("Find the files that are already up to date first, so the progress and ETA only cover the data left to restore")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("only-changed")
+                        .long("only-changed")
+                        .help("Only rewrite files that are missing or differ from the backup, trusting a matching size and modification time, and list them; unlike --prune-local, extra files are kept")
+                        .conflicts_with("atomic")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("touch-only")
+                        .long("touch-only")
+                        .help("Do not write any content: give files whose content already matches the backup their stored modification time and permissions, and leave the others alone")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with_all(["atomic", "prune-local", "only-changed", "progress-resume", "hardlink-identical"]),
+                )
+                .arg(
+                    Arg::new("exclude-existing-newer")
+                        .long("exclude-existing-newer")
+                        .help("Leave alone the files of the target that were modified after the backup recorded them, keeping newer local edits, and list them")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with("atomic"),
+                )
+                .arg(
+                    Arg::new("checksum")
+                        .long("checksum")
+                        .help("Compare existing files by their hash instead of trusting a matching size and modification time (with --touch-only, instead of their size alone)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("json-progress-bytes")
+                        .long("json-progress-bytes")
+                        .help("In JSON mode, add the bytes restored to progress events")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("sparse")
+                        .long("sparse")
+                        .help("Recreate runs of zero bytes as holes (sparse files) instead of writing them")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("hardlink-identical")
+                        .long("hardlink-identical")
+                        .help("Hard link files with identical content to the first one restored instead of writing each (copy where hard links are unsupported)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("no-verify")
+                        .long("no-verify")
+                        .help("Do not read each restored file back to check it against the hash in the backup (faster, but silent corruption goes unnoticed)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("max-retries")
+                        .long("max-retries")
+                        .value_name("N")
+                        .help("How many times to refetch a chunk that fails verification before giving up [default: 2]")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("fallback-storage")
+                        .long("fallback-storage")
+                        .value_name("STORAGE")
+                        .help("A storage holding a copy of the repository, used for chunks that stay corrupt after retries")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("continue-on-error")
+                        .long("continue-on-error")
+                        .alias("jobs-report")
+                        .help("Restore every file that can be restored and report the failed ones instead of failing")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("resolve-conflicts")
+                        .long("resolve-conflicts")
+                        .value_name("MODE")
+                        .help("What to do with paths that only differ by case on a case-insensitive target: fail, rename or skip [default: fail]")
+                        .value_parser(["fail", "rename", "skip"]),
+                )
+                .arg(
+                    Arg::new("flatten")
+                        .long("flatten")
+                        .help("Restore every file directly into the target path under its file name, dropping the folders it was in (combine with --only to pick the files)")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with("prune-local"),
+                )
+                .arg(
+                    Arg::new("on-collision")
+                        .long("on-collision")
+                        .value_name("POLICY")
+                        .help("With --flatten, what to do with files from different folders that have the same name: number, skip or fail [default: number]")
+                        .value_parser(["number", "skip", "fail"])
+                        .requires("flatten"),
+                )
+                .arg(
+                    Arg::new("pre-hook")
+                        .long("pre-hook")
+                        .value_name("COMMAND")
+                        .help("Shell command to run before writing any file; the restore is aborted if it fails")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("post-hook")
+                        .long("post-hook")
+                        .value_name("COMMAND")
+                        .help("Shell command to run after the restore, also when it fails (GIB_RESTORE_STATUS is 'success', 'partial' or 'failure')")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("atomic")
+                        .long("atomic")
+                        .help("Restore into a temporary directory next to the target and swap it into place only once every file was written; files of the target that are not in the backup are dropped")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with_all(["only", "prune-local", "continue-on-error"]),
+                )
+                .arg(
+                    Arg::new("store-ownership-manifest")
+                        .long("store-ownership-manifest")
+                        .help("Write the intended owner and mode of every restored file to .gib-ownership in the target path, for 'gib apply-ownership' to apply later as root (Unix)")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("ignore-permissions")
+                        .long("ignore-permissions")
+                        .help("Do not apply the stored permissions and ownership, for targets where they do not apply (FAT, Windows, some containers)")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with("owner-map"),
+                )
+                .arg(
+                    Arg::new("owner-map")
+                        .long("owner-map")
+                        .value_name("FILE")
+                        .help("Remap file owners with 'uid:OLD:NEW' / 'gid:OLD:NEW' lines from FILE when restoring ownership (requires root)")
+                        .required(false),
+                )
+                .arg(
+                    Arg::new("include-deleted")
+                        .long("include-deleted")
+                        .help("Restore the union of several backups, taking the most recent version of each path (requires --last or --from)")
+                        .action(clap::ArgAction::SetTrue)
+                        .conflicts_with("backup"),
+                )
+                .arg(
+                    Arg::new("last")
+                        .long("last")
+                        .value_name("N")
+                        .help("Merge the last N backups (with --include-deleted)")
+                        .requires("include-deleted")
+                        .conflicts_with_all(["from", "to"]),
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("BACKUP")
+                        .help("The oldest backup of the range to merge (with --include-deleted)")
+                        .requires("include-deleted"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("BACKUP")
+                        .help("The newest backup of the range to merge (default: latest; with --include-deleted)")
+                        .requires("from"),
+                )
+        )
+        .subcommand(
+            Command::new("apply-ownership")
+                .about("Apply the owners and modes recorded by 'gib restore --store-ownership-manifest' (requires root, Unix only)")
+                .arg(
+                    Arg::new("manifest")
+                        .value_name("MANIFEST")
+                        .help("Path to the .gib-ownership manifest")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("target-path")
+                        .long("target-path")
+                        .short('t')
+                        .value_name("PATH")
+                        .help("Folder the manifest paths are relative to [default: the folder holding the manifest]")
+                        .required(false),
+                )
         )
         .subcommand(
             Command::new("storage")
@@ -159,9 +1038,16 @@ fn cli() -> Command {
                         .about("Add a new storage")
                         .arg(arg!(-n --name <NAME> "The name of the storage").required(false))
                         .arg(
-                            arg!(-t --type <TYPE> "The type of the storage ('local' or 's3')")
+                            Arg::new("copy-from")
+                                .long("copy-from")
+                                .value_name("STORAGE")
+                                .help("Start from the settings and credentials of an existing storage; other flags override them")
+                                .required(false),
+                        )
+                        .arg(
+                            arg!(-t --type <TYPE> "The type of the storage")
                                 .required(false)
-                                .value_parser(["local", "s3"]),
+                                .value_parser(commands::storage::add::storage_type_values()),
                         )
                         .arg(arg!(-p --path <PATH> "The path for storing backups (only for local storage)").required(false))
                         .arg(arg!(-r --region <REGION> "The region for the S3 storage (only for S3 storage)").required(false))
@@ -183,6 +1069,28 @@ fn cli() -> Command {
                                 .required(false),
                         )
                         .arg(arg!(-e --endpoint <ENDPOINT> "The endpoint for the S3 storage (only for S3 storage)").required(false))
+                        .arg(
+                            Arg::new("prefix")
+                                .long("prefix")
+                                .value_name("PREFIX")
+                                .help("Store every repository under this folder of the bucket or path, to share it with others (pass an empty value to unset a copied one)")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("aws-profile")
+                                .long("aws-profile")
+                                .value_name("PROFILE")
+                                .help("Resolve S3 credentials from this AWS profile instead of static keys (only for S3 storage)")
+                                .required(false)
+                                .conflicts_with_all(["access-key", "secret-key"]),
+                        )
+                        .arg(
+                            Arg::new("use-credential-chain")
+                                .long("use-credential-chain")
+                                .help("Resolve S3 credentials from the default AWS credential chain (env, profile, SSO, instance role) instead of static keys (only for S3 storage)")
+                                .action(clap::ArgAction::SetTrue)
+                                .conflicts_with_all(["access-key", "secret-key"]),
+                        )
                 )
                 .subcommand(
                     Command::new("list")
@@ -207,6 +1115,134 @@ fn cli() -> Command {
                                 .action(clap::ArgAction::SetTrue)
                                 .required(false),
                         )
+                        .arg(
+                            Arg::new("concurrency")
+                                .long("concurrency")
+                                .help("How many items to delete at the same time [default: 100]")
+                                .value_name("CONCURRENCY")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("parallel-list")
+                                .long("parallel-list")
+                                .help("List the chunk prefix folders concurrently (faster on large S3 repositories)")
+                                .action(clap::ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("orphan-pending-only")
+                                .long("orphan-pending-only")
+                                .help("Only delete pending backups and the chunks referenced by nothing else")
+                                .action(clap::ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("older-than")
+                                .long("older-than")
+                                .value_name("AGE")
+                                .help("Only prune pending backups older than AGE, like '7d' or '12h'")
+                                .requires("orphan-pending-only"),
+                        )
+                        .arg(
+                            Arg::new("trash-retention")
+                                .long("trash-retention")
+                                .value_name("DURATION")
+                                .help("Move the pruned items to the trash for this long (example: '7d') so 'gib trash restore' can undo the prune")
+                                .conflicts_with("orphan-pending-only"),
+                        )
+                        .arg(
+                            Arg::new("dry-run")
+                                .long("dry-run")
+                                .help("Only list the orphaned chunks and pending backups a prune would delete")
+                                .action(clap::ArgAction::SetTrue)
+                                .conflicts_with("orphan-pending-only"),
+                        )
+                        .arg(
+                            Arg::new("explain")
+                                .long("explain")
+                                .help("With --dry-run, check each orphaned chunk against every backup manifest and pending backup and show why it can be deleted")
+                                .action(clap::ArgAction::SetTrue)
+                                .requires("dry-run"),
+                        )
+                )
+                .subcommand(
+                    Command::new("migrate-layout")
+                        .about("Move every chunk of a repository to another folder depth")
+                        .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                        .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                        .arg(
+                            Arg::new("to")
+                                .long("to")
+                                .value_name("DEPTH")
+                                .help("How many two-hex-digit folders to nest chunks in: 1 for chunks/ab/<rest>, 2 for chunks/ab/cd/<rest>")
+                                .required(true),
+                        )
+                        .arg(
+                            Arg::new("dry-run")
+                                .long("dry-run")
+                                .help("Only report how many chunks would be copied and objects removed")
+                                .action(clap::ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("concurrency")
+                                .long("concurrency")
+                                .help("How many chunks to copy or remove at the same time [default: 100]")
+                                .value_name("CONCURRENCY")
+                                .required(false),
+                        )
+                        .arg(
+                            Arg::new("lock-timeout")
+                                .long("lock-timeout")
+                                .value_name("DURATION")
+                                .help("How long to wait for a backup of the same repository to release its lock, like '30s' or '10m' [default: don't wait]")
+                                .required(false),
+                        )
+                )
+        )
+        .subcommand(
+            Command::new("trash")
+                .about("Manage backups and chunks deleted with --trash-retention")
+                .subcommand(
+                    Command::new("list")
+                        .about("List what can be restored from the trash and when it expires")
+                        .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                        .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                        .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                )
+                .subcommand(
+                    Command::new("restore")
+                        .about("Undo a delete or prune by restoring its trash entry")
+                        .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                        .arg(arg!(-b --backup <BACKUP> "The deleted backup to restore (full hash or first 8 chars)").required(false))
+                        .arg(
+                            Arg::new("entry")
+                                .long("entry")
+                                .value_name("ID")
+                                .help("The trash entry to restore, as shown by 'gib trash list'")
+                                .conflicts_with("backup")
+                                .required(false),
+                        )
+                        .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                        .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                )
+                .subcommand(
+                    Command::new("empty")
+                        .about("Permanently delete the expired trash entries")
+                        .arg(arg!(-k --key <KEY> "An unique key for your repository (example: 'my-repository')").required(false))
+                        .arg(arg!(-s --storage <STORAGE> "The storage to use").required(false))
+                        .arg(arg!(-p --password <PASSWORD> "The password to use for encrypted repositories").required(false))
+                        .arg(
+                            Arg::new("all")
+                                .long("all")
+                                .help("Also delete the entries that have not expired yet")
+                                .action(clap::ArgAction::SetTrue),
+                        )
+                        .arg(
+                            Arg::new("yes")
+                                .short('y')
+                                .long("yes")
+                                .help("Skip confirmation prompt")
+                                .action(clap::ArgAction::SetTrue)
+                                .required(false),
+                        )
                 )
         )
 }
@@ -240,11 +1276,34 @@ async fn main() {
         }
     };
 
+    let mode = OutputMode::from_arg(
+        matches
+            .get_one::<String>("mode")
+            .expect("--mode has a default value"),
+    );
+    if mode != detected_mode {
+        set_output_mode(mode);
+        init_panic_hook_if_json();
+    }
+
+    init_logging(matches.get_count("verbose"));
+    set_non_interactive(matches.get_flag("non-interactive"));
+
     match matches.subcommand() {
         Some(("config", matches)) => commands::config(matches),
         Some(("whoami", _)) => commands::whoami(),
+        Some(("apply-ownership", matches)) => commands::apply_ownership(matches),
+        Some(("spec", _)) => commands::spec(),
         Some(("encrypt", matches)) => commands::encrypt(matches).await,
+        Some(("reindex", matches)) => commands::reindex(matches).await,
         Some(("log", matches)) => commands::log(matches).await,
+        Some(("cat", matches)) => commands::cat(matches).await,
+        Some(("diff", matches)) => commands::diff(matches).await,
+        Some(("stats", matches)) => commands::stats(matches).await,
+        Some(("verify", matches)) => commands::verify(matches).await,
+        Some(("repair", matches)) => commands::repair(matches).await,
+        Some(("check", matches)) => commands::check(matches).await,
+        Some(("doctor", matches)) => commands::doctor(matches).await,
         Some(("backup", matches)) => match matches.subcommand() {
             Some(("delete", matches)) => commands::delete(matches).await,
             Some(("pending", matches)) => commands::pending(matches).await,
@@ -268,6 +1327,7 @@ async fn main() {
                 commands::storage::remove(matches);
             }
             Some(("prune", matches)) => commands::storage::prune(matches).await,
+            Some(("migrate-layout", matches)) => commands::storage::migrate_layout(matches).await,
             _ => {
                 handle_error(
                     "Invalid subcommand! Run 'gib --help' for more information.".to_string(),
@@ -275,6 +1335,17 @@ async fn main() {
                 );
             }
         },
+        Some(("trash", matches)) => match matches.subcommand() {
+            Some(("list", matches)) => commands::trash::list(matches).await,
+            Some(("restore", matches)) => commands::trash::restore(matches).await,
+            Some(("empty", matches)) => commands::trash::empty(matches).await,
+            _ => {
+                handle_error(
+                    "Invalid subcommand! Run 'gib trash --help' for more information.".to_string(),
+                    None,
+                );
+            }
+        },
         _ => {
             handle_error(
                 "Invalid command! Run 'gib --help' for more information.".to_string(),
@@ -1,7 +1,11 @@
 use serde::Serialize;
+use std::path::PathBuf;
 use std::sync::OnceLock;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum OutputMode {
@@ -9,10 +13,23 @@ pub enum OutputMode {
     Json,
 }
 
-impl OutputMode {}
+impl OutputMode {
+    /// The mode of a `--mode` value clap accepted.
+    pub fn from_arg(value: &str) -> Self {
+        if value.eq_ignore_ascii_case("json") {
+            OutputMode::Json
+        } else {
+            OutputMode::Interactive
+        }
+    }
+}
 
-static OUTPUT_MODE: OnceLock<OutputMode> = OnceLock::new();
+static JSON_MODE: AtomicBool = AtomicBool::new(false);
 
+/// Guesses `--mode` before clap runs, so errors raised while parsing the arguments (and
+/// panics) already come out as JSON. An unknown value is taken as JSON, since only a
+/// script would pass one. `main` replaces the guess with clap's value once parsing
+/// succeeds.
 pub fn detect_mode_from_args(args: &[String]) -> OutputMode {
     let mut iter = args.iter().skip(1);
     while let Some(arg) = iter.next() {
@@ -38,17 +55,53 @@ pub fn detect_mode_from_args(args: &[String]) -> OutputMode {
 }
 
 pub fn set_output_mode(mode: OutputMode) {
-    let _ = OUTPUT_MODE.set(mode);
+    JSON_MODE.store(mode == OutputMode::Json, Ordering::Relaxed);
 }
 
 pub fn output_mode() -> OutputMode {
-    *OUTPUT_MODE.get_or_init(|| OutputMode::Interactive)
+    if JSON_MODE.load(Ordering::Relaxed) {
+        OutputMode::Json
+    } else {
+        OutputMode::Interactive
+    }
 }
 
 pub fn is_json_mode() -> bool {
     output_mode() == OutputMode::Json
 }
 
+static NON_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_non_interactive(non_interactive: bool) {
+    NON_INTERACTIVE.store(non_interactive, Ordering::Relaxed);
+}
+
+/// Whether gib may ask the user for input. Prompts are disabled in JSON mode and with
+/// `--non-interactive`; a missing value is then an error and a confirmation is a "no".
+pub fn can_prompt() -> bool {
+    !is_json_mode() && !NON_INTERACTIVE.load(Ordering::Relaxed)
+}
+
+pub fn init_logging(verbosity: u8) {
+    let filter = match verbosity {
+        0 => std::env::var("GIB_LOG")
+            .or_else(|_| std::env::var("RUST_LOG"))
+            .ok()
+            .and_then(|directives| EnvFilter::try_new(directives).ok())
+            .unwrap_or_else(|| EnvFilter::new("off")),
+        1 => EnvFilter::new("gib=info"),
+        2 => EnvFilter::new("gib=debug"),
+        _ => EnvFilter::new("gib=trace"),
+    };
+
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(!is_json_mode())
+        .with_span_events(FmtSpan::CLOSE)
+        .try_init();
+}
+
 #[derive(Serialize)]
 struct Event<'a, T: Serialize> {
     #[serde(rename = "type")]
@@ -68,6 +121,13 @@ struct ErrorDataOwned {
     code: String,
 }
 
+#[derive(Serialize)]
+struct ErrorDetailsData<'a, T: Serialize> {
+    message: &'a str,
+    code: &'a str,
+    details: &'a T,
+}
+
 #[derive(Serialize)]
 struct WarningData<'a> {
     message: &'a str,
@@ -93,6 +153,26 @@ struct ProgressData {
     processed: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<ProgressBytesData>,
+}
+
+/// Byte counts of a progress event, with `--json-progress-bytes`. Backups report `read`,
+/// `written` (after compression) and `deduplicated`; restores report `restored`.
+/// `percent` and `bytes_per_second` follow `read` or `restored`.
+#[derive(Serialize)]
+struct ProgressBytesData {
+    percent: u64,
+    total: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deduplicated: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    restored: Option<u64>,
+    bytes_per_second: u64,
 }
 
 fn emit_event<T: Serialize>(kind: &'static str, data: &T, to_stderr: bool) {
@@ -120,6 +200,93 @@ pub fn emit_output<T: Serialize>(data: &T) {
     emit_event("output", data, false);
 }
 
+/// Reports the captured output of a backup hook command.
+pub fn emit_hook<T: Serialize>(data: &T) {
+    emit_event("hook", data, false);
+}
+
+static REPORT_PATH: OnceLock<PathBuf> = OnceLock::new();
+static REPORT_WARNINGS: Mutex<Vec<WarningDataOwned>> = Mutex::new(Vec::new());
+
+#[derive(Serialize)]
+struct WarningDataOwned {
+    message: String,
+    code: String,
+}
+
+/// Makes `write_report` write the result of the command to `path`, in any output mode.
+/// From now on warnings are collected for the report as well.
+pub fn set_report_path(path: PathBuf) {
+    let _ = REPORT_PATH.set(path);
+}
+
+/// Writes `data`, the payload of the `output` event, to the `--report-json` file with the
+/// warnings emitted so far added as `warnings`. The file is written next to its final path
+/// and renamed over it, so a reader never sees a partial report. Does nothing without a
+/// report path.
+pub fn write_report<T: Serialize>(data: &T) -> Result<(), String> {
+    let Some(path) = REPORT_PATH.get() else {
+        return Ok(());
+    };
+
+    #[derive(Serialize)]
+    struct Report<'a, T: Serialize> {
+        #[serde(flatten)]
+        data: &'a T,
+        warnings: &'a [WarningDataOwned],
+    }
+
+    let warnings = REPORT_WARNINGS.lock().unwrap();
+    let json = serde_json::to_string_pretty(&Report {
+        data,
+        warnings: &warnings,
+    })
+    .map_err(|e| format!("Failed to serialize the report: {}", e))?;
+
+    let mut temp_path = path.clone().into_os_string();
+    temp_path.push(format!(".tmp-{}", std::process::id()));
+    let temp_path = PathBuf::from(temp_path);
+
+    std::fs::write(&temp_path, json + "\n")
+        .and_then(|()| std::fs::rename(&temp_path, path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&temp_path);
+            format!("Failed to write the report to {}: {}", path.display(), e)
+        })
+}
+
+type ExitHook = Box<dyn FnOnce() + Send>;
+
+static EXIT_HOOKS: Mutex<Vec<(&'static str, ExitHook)>> = Mutex::new(Vec::new());
+
+/// Registers `hook` under `name` to run once before gib exits because of a fatal error,
+/// such as a `--post-hook` that must run even when the command fails. Setting a name
+/// again replaces its hook.
+pub fn set_exit_hook(name: &'static str, hook: ExitHook) {
+    let mut hooks = EXIT_HOOKS.lock().unwrap();
+    hooks.retain(|(hook_name, _)| *hook_name != name);
+    hooks.push((name, hook));
+}
+
+pub fn clear_exit_hook(name: &'static str) {
+    EXIT_HOOKS
+        .lock()
+        .unwrap()
+        .retain(|(hook_name, _)| *hook_name != name);
+}
+
+/// Runs the registered exit hooks in the order they were set, before a fatal exit.
+pub fn run_exit_hooks() {
+    let hooks = EXIT_HOOKS
+        .lock()
+        .map(|mut hooks| std::mem::take(&mut *hooks))
+        .unwrap_or_default();
+
+    for (_, hook) in hooks {
+        hook();
+    }
+}
+
 pub fn emit_help(text: String) {
     let payload = TextData { text };
     emit_event("help", &payload, false);
@@ -131,21 +298,33 @@ pub fn emit_version(text: String) {
 }
 
 pub fn emit_progress_update(processed: u64, total: u64, message: Option<String>) {
-    let percent = if total == 0 {
-        0
-    } else {
-        (processed.saturating_mul(100)) / total
-    };
+    emit_progress(processed, total, message, None);
+}
 
+fn emit_progress(
+    processed: u64,
+    total: u64,
+    message: Option<String>,
+    bytes: Option<ProgressBytesData>,
+) {
     let payload = ProgressData {
-        percent,
+        percent: percent_of(processed, total),
         total,
         processed,
         message,
+        bytes,
     };
     emit_event("progress", &payload, false);
 }
 
+fn percent_of(processed: u64, total: u64) -> u64 {
+    if total == 0 {
+        0
+    } else {
+        (processed.saturating_mul(100)) / total
+    }
+}
+
 pub fn emit_progress_message(message: &str) {
     emit_progress_update(0, 0, Some(message.to_string()));
 }
@@ -153,10 +332,30 @@ pub fn emit_progress_message(message: &str) {
 pub fn emit_error(message: &str, code: &str) -> ! {
     let payload = ErrorData { message, code };
     emit_event("error", &payload, true);
+    run_exit_hooks();
+    std::process::exit(1);
+}
+
+/// Like `emit_error`, with a machine-readable `details` payload for automation.
+pub fn emit_error_with_details<T: Serialize>(message: &str, code: &str, details: &T) -> ! {
+    let payload = ErrorDetailsData {
+        message,
+        code,
+        details,
+    };
+    emit_event("error", &payload, true);
+    run_exit_hooks();
     std::process::exit(1);
 }
 
 pub fn emit_warning(message: &str, code: &str) {
+    if REPORT_PATH.get().is_some() {
+        REPORT_WARNINGS.lock().unwrap().push(WarningDataOwned {
+            message: message.to_string(),
+            code: code.to_string(),
+        });
+    }
+
     if is_json_mode() {
         let payload = WarningData { message, code };
         emit_event("warning", &payload, true);
@@ -170,7 +369,16 @@ pub fn init_panic_hook_if_json() {
         return;
     }
 
-    std::panic::set_hook(Box::new(|info| {
+    // The mode can still change once clap has parsed the arguments; the default hook
+    // handles panics outside JSON mode.
+    let default_hook = std::panic::take_hook();
+
+    std::panic::set_hook(Box::new(move |info| {
+        if !is_json_mode() {
+            default_hook(info);
+            return;
+        }
+
         let message = if let Some(value) = info.payload().downcast_ref::<&str>() {
             value.to_string()
         } else if let Some(value) = info.payload().downcast_ref::<String>() {
@@ -193,31 +401,198 @@ pub fn init_panic_hook_if_json() {
     }));
 }
 
+const DEFAULT_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Minimum time between two JSON progress events, 100ms unless `GIB_PROGRESS_INTERVAL`
+/// sets another value in milliseconds (`0` emits an event for every increment).
+pub fn default_progress_interval() -> Duration {
+    static INTERVAL: OnceLock<Duration> = OnceLock::new();
+
+    *INTERVAL.get_or_init(|| {
+        std::env::var("GIB_PROGRESS_INTERVAL")
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map_or(DEFAULT_PROGRESS_INTERVAL, Duration::from_millis)
+    })
+}
+
+/// What a byte count passed to `JsonProgress::add_bytes` measures.
+#[derive(Clone, Copy)]
+pub enum ProgressBytes {
+    Read,
+    Written,
+    Deduplicated,
+    Restored,
+}
+
+struct ByteCounters {
+    total: u64,
+    restore: bool,
+    read: AtomicU64,
+    written: AtomicU64,
+    deduplicated: AtomicU64,
+    restored: AtomicU64,
+}
+
 pub struct JsonProgress {
     total: u64,
     processed: AtomicU64,
     message: Mutex<Option<String>>,
+    every: u64,
+    interval: Duration,
+    started_at: Instant,
+    last_emit_ms: AtomicU64,
+    last_emitted: Mutex<u64>,
+    bytes: OnceLock<ByteCounters>,
 }
 
 impl JsonProgress {
     pub fn new(total: u64) -> Arc<Self> {
+        Self::with_throttle(total, 1, default_progress_interval())
+    }
+
+    /// Coalesces increments so a progress event is only emitted once at least `every`
+    /// items were processed and `interval` elapsed since the previous one. Reaching the
+    /// total always emits, and `finish` flushes whatever is left.
+    pub fn with_throttle(total: u64, every: u64, interval: Duration) -> Arc<Self> {
         Arc::new(Self {
             total,
             processed: AtomicU64::new(0),
             message: Mutex::new(None),
+            every: every.max(1),
+            interval,
+            started_at: Instant::now(),
+            last_emit_ms: AtomicU64::new(0),
+            last_emitted: Mutex::new(0),
+            bytes: OnceLock::new(),
+        })
+    }
+
+    /// Adds byte counts to every progress event from now on. `total` is the number of
+    /// bytes to read (backup) or restore (restore), which byte percentages are based on.
+    pub fn track_bytes(&self, total: u64, restore: bool) {
+        let _ = self.bytes.set(ByteCounters {
+            total,
+            restore,
+            read: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            deduplicated: AtomicU64::new(0),
+            restored: AtomicU64::new(0),
+        });
+    }
+
+    /// Counts `delta` bytes when bytes are tracked. Since a large file can take a long
+    /// time between two item increments, byte updates emit an event of their own once the
+    /// throttling interval elapsed.
+    pub fn add_bytes(&self, kind: ProgressBytes, delta: u64) {
+        let Some(bytes) = self.bytes.get() else {
+            return;
+        };
+
+        let counter = match kind {
+            ProgressBytes::Read => &bytes.read,
+            ProgressBytes::Written => &bytes.written,
+            ProgressBytes::Deduplicated => &bytes.deduplicated,
+            ProgressBytes::Restored => &bytes.restored,
+        };
+        counter.fetch_add(delta, Ordering::Relaxed);
+
+        let since_last_emit = self
+            .elapsed_ms()
+            .saturating_sub(self.last_emit_ms.load(Ordering::Relaxed));
+        if since_last_emit < self.interval.as_millis() as u64 {
+            return;
+        }
+
+        let last_emitted = self.last_emitted.lock().unwrap();
+        self.last_emit_ms
+            .store(self.elapsed_ms(), Ordering::Relaxed);
+
+        let message = self.message.lock().unwrap().clone();
+        emit_progress(*last_emitted, self.total, message, self.bytes_data());
+    }
+
+    fn bytes_data(&self) -> Option<ProgressBytesData> {
+        let bytes = self.bytes.get()?;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        let done = if bytes.restore {
+            load(&bytes.restored)
+        } else {
+            load(&bytes.read)
+        };
+        let elapsed_ms = self.elapsed_ms().max(1);
+
+        Some(ProgressBytesData {
+            percent: percent_of(done, bytes.total),
+            total: bytes.total,
+            read: (!bytes.restore).then(|| load(&bytes.read)),
+            written: (!bytes.restore).then(|| load(&bytes.written)),
+            deduplicated: (!bytes.restore).then(|| load(&bytes.deduplicated)),
+            restored: bytes.restore.then(|| load(&bytes.restored)),
+            bytes_per_second: done.saturating_mul(1000) / elapsed_ms,
         })
     }
 
+    fn elapsed_ms(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64
+    }
+
     pub fn set_message(&self, message: &str) {
         let mut guard = self.message.lock().unwrap();
         *guard = Some(message.to_string());
         let processed = self.processed.load(Ordering::SeqCst);
-        emit_progress_update(processed, self.total, guard.clone());
+        *self.last_emitted.lock().unwrap() = processed;
+        self.last_emit_ms
+            .store(self.elapsed_ms(), Ordering::Relaxed);
+        emit_progress(processed, self.total, guard.clone(), self.bytes_data());
     }
 
     pub fn inc_by(&self, delta: u64) {
         let processed = self.processed.fetch_add(delta, Ordering::SeqCst) + delta;
+        let is_complete = processed >= self.total;
+
+        // Cheap check first so throttled increments don't contend on the lock.
+        let since_last_emit = self
+            .elapsed_ms()
+            .saturating_sub(self.last_emit_ms.load(Ordering::Relaxed));
+        if !is_complete && since_last_emit < self.interval.as_millis() as u64 {
+            return;
+        }
+
+        let mut last_emitted = self.last_emitted.lock().unwrap();
+
+        let is_due = processed.saturating_sub(*last_emitted) >= self.every;
+        let is_final = is_complete && *last_emitted < self.total;
+
+        if !is_due && !is_final {
+            return;
+        }
+
+        let processed = processed.max(*last_emitted);
+        *last_emitted = processed;
+        self.last_emit_ms
+            .store(self.elapsed_ms(), Ordering::Relaxed);
+
+        let message = self.message.lock().unwrap().clone();
+        emit_progress(processed, self.total, message, self.bytes_data());
+    }
+
+    /// Emits the exact final count if the last increments were coalesced away, and always
+    /// when bytes are tracked, since byte updates may have been coalesced too.
+    pub fn finish(&self) {
+        let processed = self.processed.load(Ordering::SeqCst);
+        let mut last_emitted = self.last_emitted.lock().unwrap();
+
+        if *last_emitted == processed && self.bytes.get().is_none() {
+            return;
+        }
+
+        *last_emitted = processed;
+        self.last_emit_ms
+            .store(self.elapsed_ms(), Ordering::Relaxed);
+
         let message = self.message.lock().unwrap().clone();
-        emit_progress_update(processed, self.total, message);
+        emit_progress(processed, self.total, message, self.bytes_data());
     }
 }
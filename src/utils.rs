@@ -1,5 +1,9 @@
 use crate::commands::storage::add::Storage;
-use crate::fs::{FS, LocalFS, S3FS, S3FSConfig};
+use crate::commands::storage::add::parse_storage_config;
+use crate::commands::storage::add::storage_type_name;
+use crate::fs::{FS, LocalFS};
+#[cfg(feature = "s3")]
+use crate::fs::{S3FS, S3FSConfig};
 use argon2::Argon2;
 use chacha20poly1305::{
     ChaCha20Poly1305, Key, Nonce,
@@ -9,21 +13,106 @@ use console::style;
 use dirs::home_dir;
 use indicatif::ProgressBar;
 use rand_core::{OsRng, TryRngCore};
+use std::io::{Read, Write};
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::output::{emit_error, is_json_mode};
-const MAGIC: &[u8; 4] = b"GIB1";
+use crate::output::{emit_error, is_json_mode, run_exit_hooks};
+pub(crate) const MAGIC: &[u8; 4] = b"GIB1";
+pub(crate) const SALT_LEN: usize = 16;
+pub(crate) const NONCE_LEN: usize = 12;
+pub(crate) const KEY_LEN: usize = 32;
 
-pub fn compress_bytes(data: &[u8], level: i32) -> Vec<u8> {
-    zstd::encode_all(data, level).unwrap()
+pub fn compress_bytes(data: &[u8], level: i32) -> Result<Vec<u8>, String> {
+    zstd::encode_all(data, level).map_err(|e| e.to_string())
 }
 
-pub fn decompress_bytes(data: &[u8]) -> Vec<u8> {
-    zstd::decode_all(data).unwrap()
+/// Fails on data that is not valid zstd, such as a truncated or corrupted object; callers
+/// say which object it was.
+pub fn decompress_bytes(data: &[u8]) -> Result<Vec<u8>, String> {
+    zstd::decode_all(data).map_err(|e| e.to_string())
 }
 
-fn derive_key(password: &[u8], salt: &[u8]) -> Result<[u8; 32], String> {
-    let mut key = [0u8; 32];
+/// First byte of a chunk stored without compression (`--compress store`, or a file type
+/// that does not compress). A zstd frame never starts with it, so chunks written before it
+/// existed still read as zstd.
+pub(crate) const STORED_CHUNK_MARKER: u8 = 0x00;
+
+/// Encodes a chunk for the storage: zstd at `level`, or as is behind
+/// `STORED_CHUNK_MARKER` when `level` is 0.
+pub fn encode_chunk(data: &[u8], level: i32) -> Result<Vec<u8>, String> {
+    if level == 0 {
+        let mut stored = Vec::with_capacity(data.len() + 1);
+        stored.push(STORED_CHUNK_MARKER);
+        stored.extend_from_slice(data);
+        return Ok(stored);
+    }
+
+    compress_bytes(data, level)
+}
+
+/// Decodes a chunk written by `encode_chunk`, stored or zstd.
+pub fn decode_chunk(data: &[u8]) -> Result<Vec<u8>, String> {
+    match data.split_first() {
+        Some((&STORED_CHUNK_MARKER, stored)) => Ok(stored.to_vec()),
+        _ => decompress_bytes(data),
+    }
+}
+
+/// Size of the buffer `decompress_to_writer` decompresses through.
+const DECOMPRESS_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Why `decompress_to_writer` stopped: the data is not valid zstd, or the writer failed.
+pub enum DecompressError {
+    Decompress(std::io::Error),
+    Write(std::io::Error),
+}
+
+/// Decompresses `data` into `writer` through a fixed-size buffer, so the decompressed
+/// bytes are never held in memory all at once. Every write but the last one gets a full
+/// buffer, so block-aligned writers see the same boundaries as with the whole output.
+/// A stored chunk (see `encode_chunk`) is written as is, in the same buffer-sized pieces.
+/// Returns the number of decompressed bytes.
+pub fn decompress_to_writer<W: Write>(data: &[u8], writer: &mut W) -> Result<u64, DecompressError> {
+    if let Some((&STORED_CHUNK_MARKER, stored)) = data.split_first() {
+        for piece in stored.chunks(DECOMPRESS_BUFFER_SIZE) {
+            writer.write_all(piece).map_err(DecompressError::Write)?;
+        }
+        return Ok(stored.len() as u64);
+    }
+
+    let mut decoder =
+        zstd::stream::read::Decoder::with_buffer(data).map_err(DecompressError::Decompress)?;
+    let mut buffer = vec![0u8; DECOMPRESS_BUFFER_SIZE];
+    let mut written = 0;
+
+    loop {
+        let mut filled = 0;
+
+        while filled < buffer.len() {
+            match decoder.read(&mut buffer[filled..]) {
+                Ok(0) => break,
+                Ok(bytes_read) => filled += bytes_read,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(DecompressError::Decompress(e)),
+            }
+        }
+
+        if filled > 0 {
+            writer
+                .write_all(&buffer[..filled])
+                .map_err(DecompressError::Write)?;
+            written += filled as u64;
+        }
+
+        if filled < buffer.len() {
+            return Ok(written);
+        }
+    }
+}
+
+fn derive_key(password: &[u8], salt: &[u8]) -> Result<[u8; KEY_LEN], String> {
+    let mut key = [0u8; KEY_LEN];
 
     let argon2 = Argon2::default();
     argon2
@@ -34,7 +123,7 @@ fn derive_key(password: &[u8], salt: &[u8]) -> Result<[u8; 32], String> {
 }
 
 pub fn encrypt_bytes(data: &[u8], password: &[u8]) -> Result<Vec<u8>, String> {
-    let mut salt = [0u8; 16];
+    let mut salt = [0u8; SALT_LEN];
     let mut rng = OsRng;
 
     rng.try_fill_bytes(&mut salt).unwrap();
@@ -42,7 +131,7 @@ pub fn encrypt_bytes(data: &[u8], password: &[u8]) -> Result<Vec<u8>, String> {
     let key_bytes = derive_key(password, &salt)?;
     let cipher = ChaCha20Poly1305::new(Key::from_slice(&key_bytes));
 
-    let mut nonce_bytes = [0u8; 12];
+    let mut nonce_bytes = [0u8; NONCE_LEN];
     rng.try_fill_bytes(&mut nonce_bytes).unwrap();
     let nonce = Nonce::from_slice(&nonce_bytes);
 
@@ -62,17 +151,19 @@ pub fn encrypt_bytes(data: &[u8], password: &[u8]) -> Result<Vec<u8>, String> {
 }
 
 pub fn decrypt_bytes(blob: &[u8], password: &[u8]) -> Result<Vec<u8>, String> {
-    if blob.len() < 4 + 16 + 12 {
+    let header_len = MAGIC.len() + SALT_LEN + NONCE_LEN;
+
+    if blob.len() < header_len {
         return Err("Blob too small".to_string());
     }
 
-    if &blob[..4] != MAGIC {
+    if &blob[..MAGIC.len()] != MAGIC {
         return Err("Not encrypted".to_string());
     }
 
-    let salt = &blob[4..20];
-    let nonce = &blob[20..32];
-    let ciphertext = &blob[32..];
+    let salt = &blob[MAGIC.len()..MAGIC.len() + SALT_LEN];
+    let nonce = &blob[MAGIC.len() + SALT_LEN..header_len];
+    let ciphertext = &blob[header_len..];
 
     let key_bytes = derive_key(password, salt)?;
     let cipher = ChaCha20Poly1305::new(Key::from_slice(&key_bytes));
@@ -83,7 +174,7 @@ pub fn decrypt_bytes(blob: &[u8], password: &[u8]) -> Result<Vec<u8>, String> {
 }
 
 pub fn is_encrypted(data: &[u8]) -> bool {
-    data.len() >= 4 && &data[..4] == MAGIC
+    data.len() >= MAGIC.len() && &data[..MAGIC.len()] == MAGIC
 }
 
 pub fn get_pwd_string() -> String {
@@ -103,9 +194,10 @@ pub fn get_storage(name: &str) -> Storage {
         handle_error(format!("Failed to read storage '{}': {}", name, e), None)
     });
 
-    rmp_serde::from_slice(&contents).unwrap_or_else(|e| {
-        handle_error(format!("Failed to parse storage '{}': {}", name, e), None)
-    })
+    match parse_storage_config(&contents) {
+        Ok((storage, _)) => storage,
+        Err(e) => handle_error(format!("Failed to parse storage '{}': {}", name, e), None),
+    }
 }
 
 pub fn handle_error(error: String, pb: Option<&ProgressBar>) -> ! {
@@ -116,22 +208,148 @@ pub fn handle_error(error: String, pb: Option<&ProgressBar>) -> ! {
         emit_error(&error, "error");
     } else {
         eprintln!("{}", style(error).red());
+        run_exit_hooks();
         std::process::exit(1);
     }
 }
 
 pub fn get_fs(storage: &Storage, pb: Option<&ProgressBar>) -> Arc<dyn FS> {
     let fs: Arc<dyn FS> = match storage.storage_type {
-        0 => Arc::new(LocalFS::new(storage.path.as_ref().unwrap().clone())),
+        0 => {
+            let mut path = std::path::PathBuf::from(storage.path.as_ref().unwrap());
+            if let Some(prefix) = &storage.prefix {
+                path.push(prefix);
+            }
+            Arc::new(LocalFS::new(path))
+        }
+        #[cfg(feature = "s3")]
         1 => Arc::new(S3FS::new(S3FSConfig {
             region: storage.region.clone(),
             bucket: storage.bucket.clone(),
             access_key: storage.access_key.clone(),
             secret_key: storage.secret_key.clone(),
             endpoint: storage.endpoint.clone(),
+            aws_profile: storage.aws_profile.clone(),
+            use_credential_chain: storage.use_credential_chain,
+            prefix: storage.prefix.clone(),
         })),
-        _ => handle_error("Invalid storage type".to_string(), pb),
+        storage_type => match storage_type_name(storage_type) {
+            Some(name) => handle_error(
+                format!(
+                    "This storage uses the '{}' backend, but this build of gib was compiled without the '{}' feature",
+                    name, name
+                ),
+                pb,
+            ),
+            None => handle_error("Invalid storage type".to_string(), pb),
+        },
     };
 
     fs
 }
+
+/// Parses durations like `30s`, `15m`, `12h`, `7d` or `2w`; `flag` names the option in the
+/// error message.
+pub(crate) fn parse_duration(value: &str, flag: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let invalid = || {
+        format!(
+            "Invalid value for {}: '{}' (expected a number followed by s, m, h, d or w, like '7d')",
+            flag, value
+        )
+    };
+
+    let unit_index = value
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let (amount, unit) = value.split_at(unit_index);
+    let amount = amount.parse::<u64>().map_err(|_| invalid())?;
+
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+
+    // A huge amount like '99999999999999999w' is rejected rather than wrapped around.
+    let seconds = amount.checked_mul(seconds).ok_or_else(invalid)?;
+
+    Ok(Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_reads_every_unit() {
+        assert_eq!(parse_duration("90s", "--x"), Ok(Duration::from_secs(90)));
+        assert_eq!(
+            parse_duration("2w", "--x"),
+            Ok(Duration::from_secs(1_209_600))
+        );
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflowing_amounts() {
+        let error = parse_duration("99999999999999999w", "--lock-timeout").unwrap_err();
+        assert!(error.contains("--lock-timeout"), "{}", error);
+    }
+
+    /// The first bytes of every zstd frame.
+    const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+    #[test]
+    fn stored_chunk_round_trips() {
+        // Content that itself starts with the marker or looks like zstd stays as is.
+        for data in [
+            &b"plain text"[..],
+            &[STORED_CHUNK_MARKER, 1, 2],
+            &ZSTD_MAGIC,
+            &[],
+        ] {
+            let encoded = encode_chunk(data, 0).unwrap();
+
+            assert_eq!(encoded[0], STORED_CHUNK_MARKER);
+            assert_eq!(&encoded[1..], data);
+            assert_eq!(decode_chunk(&encoded).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn zstd_chunk_round_trips() {
+        let data = b"compressible ".repeat(1000);
+        let encoded = encode_chunk(&data, 3).unwrap();
+
+        assert_eq!(encoded[..4], ZSTD_MAGIC);
+        assert!(encoded.len() < data.len());
+        assert_eq!(decode_chunk(&encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn legacy_unmarked_zstd_chunk_still_decodes() {
+        // Chunks written before stored chunks existed are bare zstd frames.
+        let data = b"written by an older gib".repeat(10);
+        let legacy = zstd::encode_all(&data[..], 3).unwrap();
+
+        assert_eq!(decode_chunk(&legacy).unwrap(), data);
+
+        let mut written = Vec::new();
+        assert!(matches!(
+            decompress_to_writer(&legacy, &mut written),
+            Ok(length) if length == data.len() as u64
+        ));
+        assert_eq!(written, data);
+    }
+
+    #[test]
+    fn corrupted_chunk_fails_to_decode() {
+        let mut encoded = encode_chunk(&b"data ".repeat(100), 3).unwrap();
+        encoded.truncate(encoded.len() / 2);
+
+        assert!(decode_chunk(&encoded).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! `--exclude-bigger-deltas` reuses the chunks of a file that only grew. It must not take a
+//! replaced file, or one whose data right before the new content changed, for an append.
+
+mod common;
+
+use common::Sandbox;
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+
+const APPEND_ARGS: &[&str] = &[
+    "--exclude-bigger-deltas",
+    "--chunk-size",
+    "64KiB",
+    "--stats",
+];
+
+fn content(length: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed;
+    (0..length)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn append(path: &std::path::Path, data: &[u8]) {
+    fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .unwrap()
+        .write_all(data)
+        .unwrap();
+}
+
+fn appended_files(sandbox: &Sandbox, message: &str) -> (String, u64) {
+    let mut args = vec!["backup", "-s", "local", "-m", message];
+    args.extend_from_slice(APPEND_ARGS);
+
+    let output = sandbox.gib(&sandbox.project(), &args);
+    (
+        output["backup"].as_str().unwrap().to_string(),
+        output["stats"]["appended_files"].as_u64().unwrap(),
+    )
+}
+
+fn assert_restores(sandbox: &Sandbox, backup: &str, expected: &[u8]) {
+    let target = sandbox.path(&format!("restored-{}", &backup[..8]));
+    sandbox.restore(backup, &target, &[]);
+    assert_eq!(fs::read(target.join("log")).unwrap(), expected);
+}
+
+#[test]
+fn appended_file_is_resumed() {
+    let sandbox = Sandbox::new();
+    let log = sandbox.project().join("log");
+    fs::write(&log, content(300_000, 1)).unwrap();
+    appended_files(&sandbox, "first");
+
+    append(&log, &content(1_000, 2));
+    let (backup, appended) = appended_files(&sandbox, "second");
+
+    assert_eq!(appended, 1);
+    assert_restores(&sandbox, &backup, &fs::read(&log).unwrap());
+}
+
+#[test]
+fn rewrite_of_the_last_reused_chunk_is_read_in_full() {
+    let sandbox = Sandbox::new();
+    let log = sandbox.project().join("log");
+    fs::write(&log, content(300_000, 1)).unwrap();
+    appended_files(&sandbox, "first");
+
+    // Inside the last full 64 KiB chunk, outside the sampled first and last 64 KiB.
+    let mut file = fs::OpenOptions::new().write(true).open(&log).unwrap();
+    file.seek(SeekFrom::Start(200_000)).unwrap();
+    file.write_all(b"rewritten").unwrap();
+    drop(file);
+    append(&log, &content(1_000, 2));
+
+    let (backup, appended) = appended_files(&sandbox, "second");
+
+    assert_eq!(appended, 0);
+    assert_restores(&sandbox, &backup, &fs::read(&log).unwrap());
+}
+
+#[cfg(unix)]
+#[test]
+fn replaced_file_is_read_in_full() {
+    let sandbox = Sandbox::new();
+    let log = sandbox.project().join("log");
+    let previous = content(300_000, 1);
+    fs::write(&log, &previous).unwrap();
+    appended_files(&sandbox, "first");
+
+    // Same content plus new data, but a new file: what log rotation or an editor does.
+    let replacement = sandbox.project().join("log.new");
+    fs::write(&replacement, [previous, content(1_000, 2)].concat()).unwrap();
+    fs::rename(&replacement, &log).unwrap();
+
+    let (backup, appended) = appended_files(&sandbox, "second");
+
+    assert_eq!(appended, 0);
+    assert_restores(&sandbox, &backup, &fs::read(&log).unwrap());
+}
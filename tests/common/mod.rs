@@ -0,0 +1,178 @@
+//! Runs the `gib` binary against a local storage, with its home in a temporary directory
+//! so the tests never touch the real configuration.
+
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+/// SHA-256 of no bytes, the hash every empty file is stored with.
+pub const EMPTY_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+pub struct Sandbox {
+    root: TempDir,
+}
+
+impl Sandbox {
+    /// A home with an author configured, a local storage named `local` and an empty
+    /// `project` directory to back up.
+    pub fn new() -> Self {
+        let sandbox = Sandbox {
+            root: tempfile::tempdir().unwrap(),
+        };
+
+        std::fs::create_dir_all(sandbox.home()).unwrap();
+        std::fs::create_dir_all(sandbox.project()).unwrap();
+
+        let home = sandbox.home();
+        sandbox.gib(&home, &["config", "-a", "Test <test@example.com>"]);
+        sandbox.gib(
+            &home,
+            &[
+                "storage",
+                "add",
+                "-n",
+                "local",
+                "-t",
+                "local",
+                "-p",
+                sandbox.store().to_str().unwrap(),
+            ],
+        );
+
+        sandbox
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.root.path().join(name)
+    }
+
+    pub fn home(&self) -> PathBuf {
+        self.path("home")
+    }
+
+    pub fn store(&self) -> PathBuf {
+        self.path("store")
+    }
+
+    /// The directory backed up by `backup`; its name, `project`, is the repository key.
+    pub fn project(&self) -> PathBuf {
+        self.path("project")
+    }
+
+    /// Runs gib in `cwd` without prompting and returns what it printed.
+    pub fn run(&self, cwd: &Path, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_gib"))
+            .args(args)
+            .args(["--mode", "json"])
+            .current_dir(cwd)
+            .env("HOME", self.home())
+            .env_remove("GIB_LOG")
+            .env_remove("RUST_LOG")
+            .output()
+            .unwrap()
+    }
+
+    /// Runs gib in `cwd` and returns the data of its output event. Panics with what gib
+    /// printed if it failed.
+    pub fn gib(&self, cwd: &Path, args: &[&str]) -> Value {
+        let output = self.run(cwd, args);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        let event = stdout
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .find(|event| event["type"] == "output");
+
+        match event {
+            Some(event) if output.status.success() => event["data"].clone(),
+            _ => panic!(
+                "gib {:?} failed ({}):\n{}\n{}",
+                args,
+                output.status,
+                stdout,
+                String::from_utf8_lossy(&output.stderr)
+            ),
+        }
+    }
+
+    /// Backs up the project with `message` and returns the backup hash.
+    pub fn backup(&self, message: &str, extra_args: &[&str]) -> String {
+        let mut args = vec!["backup", "-s", "local", "-m", message];
+        args.extend_from_slice(extra_args);
+
+        self.gib(&self.project(), &args)["backup"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// Restores `backup` of the project into `target`.
+    pub fn restore(&self, backup: &str, target: &Path, extra_args: &[&str]) -> Value {
+        let mut args = vec![
+            "restore",
+            "-s",
+            "local",
+            "-b",
+            backup,
+            "-t",
+            target.to_str().unwrap(),
+        ];
+        args.extend_from_slice(extra_args);
+
+        self.gib(&self.project(), &args)
+    }
+
+    /// Reads an unencrypted msgpack object of the project repository, like a manifest
+    /// (`backups/<hash>`) or the chunk index (`indexes/chunks`).
+    pub fn read_object(&self, object: &str) -> Value {
+        self.read_repository_object("project", object)
+    }
+
+    /// Reads an unencrypted msgpack object of the repository `key`.
+    pub fn read_repository_object(&self, key: &str, object: &str) -> Value {
+        let bytes = std::fs::read(self.store().join(key).join(object)).unwrap();
+        let bytes = zstd::decode_all(bytes.as_slice()).unwrap();
+        rmp_serde::from_slice(&bytes).unwrap()
+    }
+
+    /// Replaces an unencrypted msgpack object of the project repository, for example to
+    /// turn a manifest into what an older gib would have written.
+    pub fn write_object(&self, object: &str, value: &Value) {
+        let bytes = rmp_serde::to_vec_named(value).unwrap();
+        let bytes = zstd::encode_all(bytes.as_slice(), 3).unwrap();
+        std::fs::write(self.store().join("project").join(object), bytes).unwrap();
+    }
+
+    /// The repository keys that have something in the storage.
+    pub fn repositories(&self) -> Vec<String> {
+        let mut keys = std::fs::read_dir(self.store())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        keys.sort();
+        keys
+    }
+
+    /// The hashes of the chunks in the storage, whatever folder depth they are stored at.
+    pub fn stored_chunks(&self) -> Vec<String> {
+        let chunks_dir = self.store().join("project").join("chunks");
+        let mut chunks = walkdir::WalkDir::new(&chunks_dir)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(&chunks_dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('/', "")
+            })
+            .collect::<Vec<String>>();
+        chunks.sort();
+        chunks
+    }
+}
@@ -0,0 +1,117 @@
+//! `gib diff` against the working directory leaves out what the backup's ignore rules left
+//! out, instead of listing those files as added.
+
+mod common;
+
+use common::Sandbox;
+use serde_json::Value;
+use std::fs;
+
+fn changes(diff: &Value) -> Vec<(String, String)> {
+    diff["changes"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| {
+            (
+                entry["path"].as_str().unwrap().to_string(),
+                entry["change"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn worktree_diff_applies_the_ignore_rules() {
+    let sandbox = Sandbox::new();
+    let project = sandbox.project();
+    fs::write(project.join(".gibignore"), "*.log\n").unwrap();
+    fs::create_dir_all(project.join("cache")).unwrap();
+    fs::create_dir_all(project.join(".git")).unwrap();
+    fs::write(project.join("kept.txt"), b"kept").unwrap();
+    fs::write(project.join("debug.log"), b"log").unwrap();
+    fs::write(project.join("cache/blob"), b"cached").unwrap();
+    fs::write(project.join(".git/HEAD"), b"ref").unwrap();
+    fs::write(project.join("notes.tmp"), b"tmp").unwrap();
+
+    let backup = sandbox.backup(
+        "first",
+        &[
+            "--ignore",
+            "*.tmp",
+            "--exclude-path",
+            "cache",
+            "--exclude-vcs",
+        ],
+    );
+
+    fs::write(project.join("added.txt"), b"new").unwrap();
+    fs::write(project.join("other.log"), b"more log").unwrap();
+    fs::write(project.join("cache/other"), b"more cache").unwrap();
+
+    let diff = sandbox.gib(
+        &project,
+        &[
+            "diff",
+            "-s",
+            "local",
+            &backup,
+            "--ignore",
+            "*.tmp",
+            "--exclude-path",
+            "cache",
+            "--exclude-vcs",
+        ],
+    );
+
+    assert_eq!(
+        changes(&diff),
+        vec![("added.txt".to_string(), "added".to_string())]
+    );
+    assert_eq!(diff["unchanged"], 2, "{}", diff);
+
+    // Without the flags only the .gibignore file applies.
+    let diff = sandbox.gib(&project, &["diff", "-s", "local", &backup]);
+    let added = changes(&diff)
+        .into_iter()
+        .filter(|(_, change)| change == "added")
+        .map(|(path, _)| path)
+        .collect::<Vec<String>>();
+    assert_eq!(
+        added,
+        vec![
+            ".git/HEAD",
+            "added.txt",
+            "cache/blob",
+            "cache/other",
+            "notes.tmp"
+        ]
+    );
+}
+
+#[test]
+fn worktree_diff_matches_ignore_patterns_case_insensitively() {
+    let sandbox = Sandbox::new();
+    let project = sandbox.project();
+    fs::create_dir_all(project.join("Vendor")).unwrap();
+    fs::write(project.join("Vendor/lib.txt"), b"lib").unwrap();
+    fs::write(project.join("kept.txt"), b"kept").unwrap();
+
+    let backup = sandbox.backup("first", &["--exclude-path", "Vendor"]);
+
+    let diff = sandbox.gib(
+        &project,
+        &[
+            "diff",
+            "-s",
+            "local",
+            &backup,
+            "--ignore-case",
+            "--ignore",
+            "vendor/",
+        ],
+    );
+
+    assert!(changes(&diff).is_empty(), "{}", diff);
+    assert_eq!(diff["unchanged"], 1);
+}
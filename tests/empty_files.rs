@@ -0,0 +1,141 @@
+//! Empty files are stored with no chunks, so they must never create a chunk or change a
+//! refcount, and still come back as empty files.
+
+mod common;
+
+use common::{EMPTY_HASH, Sandbox};
+use std::fs;
+
+fn write_project(sandbox: &Sandbox) {
+    let project = sandbox.project();
+    fs::create_dir_all(project.join("nested/deeper")).unwrap();
+    fs::write(project.join("empty.txt"), b"").unwrap();
+    fs::write(project.join("nested/deeper/empty.log"), b"").unwrap();
+    fs::write(project.join("content.txt"), b"some content").unwrap();
+}
+
+#[test]
+fn empty_files_round_trip_without_chunks() {
+    let sandbox = Sandbox::new();
+    write_project(&sandbox);
+
+    let backup = sandbox.backup("first", &[]);
+
+    let manifest = sandbox.read_object(&format!("backups/{}", backup));
+    for path in ["empty.txt", "nested/deeper/empty.log"] {
+        let object = &manifest["tree"][path];
+        assert_eq!(object["size"], 0, "{}", path);
+        assert_eq!(object["hash"], EMPTY_HASH, "{}", path);
+        assert_eq!(object["chunks"].as_array().unwrap().len(), 0, "{}", path);
+    }
+
+    // Only the non-empty file produced a chunk.
+    let content_chunks = manifest["tree"]["content.txt"]["chunks"].clone();
+    assert_eq!(sandbox.stored_chunks().len(), 1);
+    assert_eq!(
+        serde_json::Value::from(sandbox.stored_chunks()),
+        content_chunks
+    );
+
+    let target = sandbox.path("restored");
+    let restored = sandbox.restore(&backup, &target, &[]);
+    assert_eq!(restored["restored"], 3);
+    assert_eq!(fs::read(target.join("empty.txt")).unwrap(), b"");
+    assert_eq!(
+        fs::read(target.join("nested/deeper/empty.log")).unwrap(),
+        b""
+    );
+    assert_eq!(
+        fs::read(target.join("content.txt")).unwrap(),
+        b"some content"
+    );
+
+    let verified = sandbox.gib(&sandbox.project(), &["verify", "-s", "local"]);
+    assert_eq!(verified["failures"].as_array().unwrap().len(), 0);
+    assert_eq!(verified["chunks_verified"], 1);
+}
+
+#[test]
+fn restoring_an_empty_file_truncates_an_existing_one() {
+    let sandbox = Sandbox::new();
+    write_project(&sandbox);
+
+    let backup = sandbox.backup("first", &[]);
+
+    let target = sandbox.path("restored");
+    fs::create_dir_all(&target).unwrap();
+    fs::write(target.join("empty.txt"), b"left over from before").unwrap();
+
+    sandbox.restore(&backup, &target, &[]);
+
+    assert_eq!(fs::read(target.join("empty.txt")).unwrap(), b"");
+}
+
+#[test]
+fn empty_files_never_count_towards_refcounts() {
+    let sandbox = Sandbox::new();
+    write_project(&sandbox);
+
+    let first = sandbox.backup("first", &[]);
+    let chunk_indexes = sandbox.read_object("indexes/chunks");
+    let refcounts = chunk_indexes.as_object().unwrap();
+    assert_eq!(refcounts.len(), 1);
+    assert!(refcounts.values().all(|index| index["refcount"] == 1));
+
+    // More empty files deduplicate to nothing: no new chunk, no refcount.
+    for index in 0..5 {
+        fs::write(sandbox.project().join(format!("more-{}.txt", index)), b"").unwrap();
+    }
+    let second = sandbox.backup("second", &[]);
+
+    assert_eq!(sandbox.stored_chunks().len(), 1);
+    let chunk_indexes = sandbox.read_object("indexes/chunks");
+    let refcounts = chunk_indexes.as_object().unwrap();
+    assert_eq!(refcounts.len(), 1);
+    assert!(refcounts.values().all(|index| index["refcount"] == 2));
+
+    let check = sandbox.gib(
+        &sandbox.project(),
+        &["check", "-s", "local", "--repair-refcounts", "--dry-run"],
+    );
+    assert_eq!(check["problems"].as_array().unwrap().len(), 0);
+    assert_eq!(
+        check["refcount_repair"]["drifted"]
+            .as_array()
+            .unwrap()
+            .len(),
+        0
+    );
+
+    // Deleting a backup and pruning keeps the chunk the other backup still uses.
+    let deleted = sandbox.gib(
+        &sandbox.project(),
+        &["backup", "delete", "-s", "local", "-b", &first, "-y"],
+    );
+    assert_eq!(deleted["deleted_chunks"], 0);
+    sandbox.gib(
+        &sandbox.project(),
+        &["storage", "prune", "-s", "local", "-y"],
+    );
+
+    assert_eq!(sandbox.stored_chunks().len(), 1);
+    let chunk_indexes = sandbox.read_object("indexes/chunks");
+    assert!(
+        chunk_indexes
+            .as_object()
+            .unwrap()
+            .values()
+            .all(|index| index["refcount"] == 1)
+    );
+
+    let target = sandbox.path("restored");
+    assert_eq!(sandbox.restore(&second, &target, &[])["restored"], 8);
+    assert_eq!(fs::read(target.join("more-4.txt")).unwrap(), b"");
+
+    // Once the last backup is gone, nothing is left behind for the empty files.
+    sandbox.gib(
+        &sandbox.project(),
+        &["backup", "delete", "-s", "local", "-b", &second, "-y"],
+    );
+    assert!(sandbox.stored_chunks().is_empty());
+}
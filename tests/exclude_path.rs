@@ -0,0 +1,43 @@
+//! `--exclude-path` with `--ignore-case` matches paths case-insensitively, but the dry-run
+//! report still has to walk the folder as it is named on disk.
+
+mod common;
+
+use common::Sandbox;
+use std::fs;
+
+#[test]
+fn dry_run_counts_a_mixed_case_excluded_path_with_ignore_case() {
+    let sandbox = Sandbox::new();
+    let project = sandbox.project();
+    fs::create_dir_all(project.join("Vendor/Lib")).unwrap();
+    fs::write(project.join("Vendor/Lib/a.txt"), b"hello").unwrap();
+    fs::write(project.join("Vendor/b.txt"), b"hi").unwrap();
+    fs::write(project.join("kept.txt"), b"kept").unwrap();
+
+    let report = sandbox.gib(
+        &project,
+        &[
+            "backup",
+            "-s",
+            "local",
+            "-m",
+            "dry",
+            "--dry-run",
+            "--ignore-case",
+            "--exclude-path",
+            "Vendor",
+        ],
+    );
+
+    assert_eq!(report["files_total"], 1);
+    let excluded = report["excluded"].as_array().unwrap();
+    assert_eq!(excluded.len(), 1);
+    assert!(
+        excluded[0]["path"].as_str().unwrap().ends_with("Vendor"),
+        "{}",
+        excluded[0]["path"]
+    );
+    assert_eq!(excluded[0]["files"], 2);
+    assert_eq!(excluded[0]["bytes"], 7);
+}
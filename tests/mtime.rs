@@ -0,0 +1,81 @@
+//! Modification times are recorded in nanoseconds and put back by restore, while backups
+//! made before they were recorded still restore.
+
+mod common;
+
+use common::Sandbox;
+use std::fs::{self, File};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// 2023-11-14 22:13:20.123456789 UTC, with every sub-second digit set.
+const MTIME_NS: u64 = 1_700_000_000_123_456_789;
+
+fn mtime_ns(path: &std::path::Path) -> u64 {
+    fs::metadata(path)
+        .unwrap()
+        .modified()
+        .unwrap()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+fn write_project(sandbox: &Sandbox) {
+    let path = sandbox.project().join("dated.txt");
+    fs::write(&path, b"dated content").unwrap();
+    File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(UNIX_EPOCH + Duration::from_nanos(MTIME_NS))
+        .unwrap();
+
+    // The test is only meaningful where the filesystem keeps nanoseconds.
+    assert_eq!(mtime_ns(&path), MTIME_NS);
+}
+
+#[test]
+fn nanosecond_mtime_round_trips_through_manifest_and_restore() {
+    let sandbox = Sandbox::new();
+    write_project(&sandbox);
+
+    let backup = sandbox.backup("dated", &[]);
+
+    let manifest = sandbox.read_object(&format!("backups/{}", backup));
+    assert_eq!(manifest["tree"]["dated.txt"]["mtime_ns"], MTIME_NS);
+
+    let target = sandbox.path("restored");
+    sandbox.restore(&backup, &target, &[]);
+
+    assert_eq!(mtime_ns(&target.join("dated.txt")), MTIME_NS);
+}
+
+#[test]
+fn manifests_without_mtime_still_restore() {
+    let sandbox = Sandbox::new();
+    write_project(&sandbox);
+
+    let backup = sandbox.backup("dated", &[]);
+
+    // What a gib from before modification times were recorded wrote.
+    let manifest_object = format!("backups/{}", backup);
+    let mut manifest = sandbox.read_object(&manifest_object);
+    for object in manifest["tree"].as_object_mut().unwrap().values_mut() {
+        object.as_object_mut().unwrap().remove("mtime_ns");
+    }
+    sandbox.write_object(&manifest_object, &manifest);
+
+    let restore_started = SystemTime::now();
+    let target = sandbox.path("restored");
+    let restored = sandbox.restore(&backup, &target, &[]);
+
+    assert_eq!(restored["restored"], 1);
+    let restored_path = target.join("dated.txt");
+    assert_eq!(fs::read(&restored_path).unwrap(), b"dated content");
+    // Without a recorded time the file keeps the time it was restored at (give or take
+    // the coarse clock the kernel stamps files with).
+    assert!(
+        fs::metadata(&restored_path).unwrap().modified().unwrap()
+            >= restore_started - Duration::from_secs(1)
+    );
+}
@@ -0,0 +1,138 @@
+//! A `--root-path` that is a symlink to a directory: by default the link is kept as the
+//! root, with `--follow-root-symlink` its target is backed up instead.
+
+#![cfg(unix)]
+
+mod common;
+
+use common::Sandbox;
+use serde_json::Value;
+use std::fs;
+use std::os::unix::fs::symlink;
+
+/// `real-files` with two files, and `linked-files` pointing to it.
+fn write_linked_root(sandbox: &Sandbox) {
+    let target = sandbox.path("real-files");
+    fs::create_dir_all(target.join("sub")).unwrap();
+    fs::write(target.join("a.txt"), b"alpha").unwrap();
+    fs::write(target.join("sub/b.txt"), b"beta").unwrap();
+
+    symlink(&target, sandbox.path("linked-files")).unwrap();
+}
+
+fn backup_linked_root(sandbox: &Sandbox, extra_args: &[&str]) -> String {
+    let mut args = vec![
+        "backup",
+        "-s",
+        "local",
+        "-m",
+        "linked",
+        "-r",
+        "linked-files",
+    ];
+    args.extend_from_slice(extra_args);
+
+    sandbox.gib(&sandbox.path(""), &args)["backup"]
+        .as_str()
+        .unwrap()
+        .to_string()
+}
+
+fn tree_paths(manifest: &Value) -> Vec<String> {
+    let mut paths = manifest["tree"]
+        .as_object()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect::<Vec<String>>();
+    paths.sort();
+    paths
+}
+
+fn assert_restores_files(sandbox: &Sandbox, key: &str, backup: &str) {
+    let target = sandbox.path(&format!("restored-{}", key));
+    sandbox.gib(
+        &sandbox.path(""),
+        &[
+            "restore",
+            "-s",
+            "local",
+            "-k",
+            key,
+            "-b",
+            backup,
+            "-t",
+            target.to_str().unwrap(),
+        ],
+    );
+
+    assert_eq!(fs::read(target.join("a.txt")).unwrap(), b"alpha");
+    assert_eq!(fs::read(target.join("sub/b.txt")).unwrap(), b"beta");
+    assert!(
+        !fs::symlink_metadata(&target)
+            .unwrap()
+            .file_type()
+            .is_symlink()
+    );
+}
+
+#[test]
+fn a_symlinked_root_is_kept_as_the_link_by_default() {
+    let sandbox = Sandbox::new();
+    write_linked_root(&sandbox);
+
+    let backup = backup_linked_root(&sandbox, &[]);
+
+    // Named after the link, and the files are walked through it.
+    assert_eq!(sandbox.repositories(), vec!["linked-files"]);
+    let manifest = sandbox.read_repository_object("linked-files", &format!("backups/{}", backup));
+    assert_eq!(tree_paths(&manifest), vec!["a.txt", "sub/b.txt"]);
+    assert!(
+        manifest["tree"]
+            .as_object()
+            .unwrap()
+            .values()
+            .all(|object| object["link_target"].is_null())
+    );
+
+    assert_restores_files(&sandbox, "linked-files", &backup);
+
+    // Comparing with the files through the link does not see the link itself either.
+    let diff = sandbox.gib(
+        &sandbox.path(""),
+        &[
+            "diff",
+            "-s",
+            "local",
+            "-k",
+            "linked-files",
+            &backup,
+            "--root",
+            "linked-files",
+        ],
+    );
+    assert_eq!(diff["changes"].as_array().unwrap().len(), 0);
+    assert_eq!(diff["unchanged"], 2);
+}
+
+#[test]
+fn follow_root_symlink_backs_up_the_target() {
+    let sandbox = Sandbox::new();
+    write_linked_root(&sandbox);
+
+    let backup = backup_linked_root(&sandbox, &["--follow-root-symlink"]);
+
+    assert_eq!(sandbox.repositories(), vec!["real-files"]);
+    let manifest = sandbox.read_repository_object("real-files", &format!("backups/{}", backup));
+    assert_eq!(tree_paths(&manifest), vec!["a.txt", "sub/b.txt"]);
+
+    assert_restores_files(&sandbox, "real-files", &backup);
+
+    // Backing up the folder itself lands in the same repository and deduplicates fully.
+    let data = sandbox.gib(
+        &sandbox.path(""),
+        &["backup", "-s", "local", "-m", "direct", "-r", "real-files"],
+    );
+    assert_eq!(sandbox.repositories(), vec!["real-files"]);
+    assert_eq!(data["stats"]["new_chunks"], 0);
+}